@@ -0,0 +1,63 @@
+//! Build script that records the git commit Ruma is built from and the versions of the
+//! migrations embedded into it.
+
+use std::fs::{File, read_dir};
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(&["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .and_then(|output| if output.status.success() {
+            String::from_utf8(output.stdout).ok()
+        } else {
+            None
+        })
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=RUMA_GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+
+    // Commits change the branch HEAD points to, not HEAD itself. The branch is a file of its own
+    // unless it was packed. Paths that do not exist would make Cargo run this script every time.
+    let head = read_to_string(".git/HEAD");
+
+    if let Some(branch) = head.trim().splitn(2, "ref: ").nth(1) {
+        for path in &[format!(".git/{}", branch), ".git/packed-refs".to_string()] {
+            if Path::new(path).exists() {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+
+    // Diesel's version of a migration is the part of its directory name before the first
+    // underscore.
+    let mut migrations: Vec<String> = read_dir("migrations")
+        .expect("Failed to read the migrations directory")
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .map(|name| name.split('_').next().unwrap_or("").to_string())
+        .filter(|version| !version.is_empty())
+        .collect();
+
+    migrations.sort();
+
+    println!("cargo:rustc-env=RUMA_MIGRATIONS={}", migrations.join(","));
+    println!("cargo:rerun-if-changed=migrations");
+}
+
+/// Read a file into a string, which is empty if the file cannot be read.
+fn read_to_string(path: &str) -> String {
+    let mut contents = String::new();
+
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+        contents.clear();
+    }
+
+    contents
+}
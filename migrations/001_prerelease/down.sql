@@ -1,15 +1,37 @@
 DROP TABLE access_tokens;
 DROP TABLE account_data;
+DROP TABLE admin_audit_log;
+DROP TABLE devices;
+DROP TABLE email_digests;
+DROP TABLE email_notifications;
+DROP TABLE event_lookup;
+DROP TABLE event_purges;
+DROP TABLE event_relations;
+DROP TABLE event_transactions;
 DROP TABLE events;
+DROP FUNCTION maintain_event_lookup();
+DROP FUNCTION record_replaced_state();
+DROP TABLE failed_logins;
+DROP TABLE federation_queue;
 DROP TABLE filters;
+DROP TABLE key_backup_keys;
+DROP TABLE key_backups;
+DROP TABLE mail_queue;
+DROP TABLE monthly_active_users;
 DROP TABLE presence_list;
 DROP TABLE presence_status;
 DROP TABLE profiles;
+DROP TABLE push_queue;
 DROP TABLE pushers;
+DROP TABLE registration_nonces;
 DROP TABLE room_account_data;
 DROP TABLE room_aliases;
 DROP TABLE room_memberships;
 DROP TABLE room_tags;
 DROP TABLE rooms;
+DROP TABLE threepid_sessions;
+DROP TABLE threepids;
+DROP TABLE to_device_messages;
 DROP TABLE transactions;
+DROP TABLE user_erasures;
 DROP TABLE users;
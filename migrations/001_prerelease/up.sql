@@ -2,11 +2,18 @@ CREATE TABLE access_tokens (
     id BIGSERIAL PRIMARY KEY,
     user_id TEXT NOT NULL,
     value TEXT NOT NULL,
+    device_id TEXT,
     revoked BOOLEAN NOT NULL DEFAULT FALSE,
     created_at TIMESTAMP NOT NULL DEFAULT now(),
-    updated_at TIMESTAMP NOT NULL DEFAULT now()
+    updated_at TIMESTAMP NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMP NOT NULL,
+    last_used_ip TEXT,
+    soft_logout BOOLEAN NOT NULL DEFAULT FALSE,
+    expiry_warned_at TIMESTAMP
 );
 
+CREATE INDEX access_tokens_last_used_at_idx ON access_tokens (last_used_at) WHERE NOT revoked;
+
 CREATE TABLE account_data (
     id BIGSERIAL PRIMARY KEY,
     user_id TEXT NOT NULL,
@@ -15,6 +22,78 @@ CREATE TABLE account_data (
     UNIQUE (user_id, data_type)
 );
 
+CREATE TABLE admin_audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    action TEXT NOT NULL,
+    target TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE TABLE devices (
+    id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    display_name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, id)
+);
+
+CREATE TABLE email_digests (
+    user_id TEXT PRIMARY KEY,
+    sent_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE email_notifications (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX email_notifications_user_id_idx ON email_notifications (user_id, id);
+
+CREATE TABLE event_relations (
+    event_id TEXT NOT NULL PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    relates_to_id TEXT NOT NULL,
+    rel_type TEXT NOT NULL,
+    aggregation_key TEXT,
+    ordering BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX event_relations_aggregation_idx ON event_relations (relates_to_id, rel_type, aggregation_key);
+CREATE INDEX event_relations_ordering_idx ON event_relations (relates_to_id, ordering);
+
+-- The room, position in the stream and sender of every event, so that endpoints given only an event
+-- ID can find out which room it belongs to without loading the event. Maintained by the
+-- events_lookup trigger below.
+CREATE TABLE event_lookup (
+    event_id TEXT NOT NULL PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    ordering BIGINT NOT NULL,
+    sender TEXT NOT NULL
+);
+
+CREATE TABLE event_purges (
+    id BIGSERIAL PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    purged_up_to BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX event_purges_room_id_idx ON event_purges (room_id, purged_up_to);
+
+-- The transaction IDs that events were sent with, echoed in the events' unsigned data to the
+-- access token, or the application service token, that sent them.
+CREATE TABLE event_transactions (
+    event_id TEXT NOT NULL PRIMARY KEY,
+    access_token TEXT NOT NULL,
+    transaction_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
 CREATE TABLE events (
     id TEXT NOT NULL PRIMARY KEY,
     ordering BIGSERIAL NOT NULL,
@@ -25,9 +104,76 @@ CREATE TABLE events (
     content TEXT NOT NULL,
     extra_content TEXT,
     created_at TIMESTAMP NOT NULL DEFAULT now(),
+    replaces_state TEXT,
+    prev_content TEXT,
+    redacted BOOLEAN NOT NULL DEFAULT FALSE,
+    content_hash TEXT NOT NULL,
     UNIQUE (ordering)
 );
 
+CREATE INDEX events_state_idx ON events (room_id, event_type, state_key, ordering);
+
+-- For exporting and erasing the events a user sent.
+CREATE INDEX events_user_id_idx ON events (user_id, ordering);
+
+-- Record the state event a new state event replaces, and its content, on the new row, so that
+-- events can be serialized with their previous content without joining the table with itself.
+-- The content of redacted events is left out.
+CREATE FUNCTION record_replaced_state() RETURNS trigger AS $$
+BEGIN
+    IF NEW.state_key IS NOT NULL THEN
+        SELECT id, CASE WHEN redacted THEN NULL ELSE content END
+        INTO NEW.replaces_state, NEW.prev_content
+        FROM events
+        WHERE room_id = NEW.room_id
+            AND event_type = NEW.event_type
+            AND state_key = NEW.state_key
+        ORDER BY ordering DESC
+        LIMIT 1;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER events_replaced_state BEFORE INSERT ON events
+    FOR EACH ROW EXECUTE PROCEDURE record_replaced_state();
+
+-- Keep event_lookup in step with events, in the transaction that inserts or deletes the event.
+CREATE FUNCTION maintain_event_lookup() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO event_lookup (event_id, room_id, ordering, sender)
+        VALUES (NEW.id, NEW.room_id, NEW.ordering, NEW.user_id);
+    ELSE
+        DELETE FROM event_lookup WHERE event_id = OLD.id;
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER events_lookup AFTER INSERT OR DELETE ON events
+    FOR EACH ROW EXECUTE PROCEDURE maintain_event_lookup();
+
+CREATE TABLE failed_logins (
+    user_id TEXT PRIMARY KEY,
+    attempts INTEGER NOT NULL,
+    first_failed_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE federation_queue (
+    id BIGSERIAL PRIMARY KEY,
+    destination TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    edu_type TEXT,
+    coalesce_key TEXT,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX federation_queue_destination_idx ON federation_queue (destination, id);
+
 CREATE TABLE filters (
     id BIGSERIAL PRIMARY KEY,
     user_id TEXT NOT NULL,
@@ -35,11 +181,52 @@ CREATE TABLE filters (
     UNIQUE (id, user_id)
 );
 
+CREATE TABLE key_backup_keys (
+    user_id TEXT NOT NULL,
+    version BIGINT NOT NULL,
+    room_id TEXT NOT NULL,
+    session_id TEXT NOT NULL,
+    first_message_index BIGINT NOT NULL,
+    forwarded_count BIGINT NOT NULL,
+    is_verified BOOLEAN NOT NULL,
+    session_data TEXT NOT NULL,
+    PRIMARY KEY (user_id, version, room_id, session_id)
+);
+
+CREATE TABLE key_backups (
+    version BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    algorithm TEXT NOT NULL,
+    auth_data TEXT NOT NULL,
+    etag BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE INDEX key_backups_user_id_idx ON key_backups (user_id, version);
+
+CREATE TABLE mail_queue (
+    id BIGSERIAL PRIMARY KEY,
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX mail_queue_next_attempt_at_idx ON mail_queue (next_attempt_at);
+
+CREATE TABLE monthly_active_users (
+    user_id TEXT PRIMARY KEY,
+    last_active_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
 CREATE TABLE presence_status (
     user_id TEXT PRIMARY KEY,
     event_id TEXT NOT NULL,
     presence TEXT NOT NULL,
     status_msg TEXT,
+    sticky BOOLEAN NOT NULL DEFAULT FALSE,
     updated_at TIMESTAMP NOT NULL DEFAULT now()
 );
 
@@ -56,6 +243,17 @@ CREATE TABLE profiles (
     UNIQUE(id)
 );
 
+CREATE TABLE push_queue (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    app_id TEXT NOT NULL,
+    body TEXT NOT NULL,
+    next_attempt_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX push_queue_next_attempt_at_idx ON push_queue (next_attempt_at);
+
 CREATE TABLE pushers (
     user_id TEXT NOT NULL,
     lang TEXT NOT NULL,
@@ -66,9 +264,20 @@ CREATE TABLE pushers (
     profile_tag TEXT,
     pushkey TEXT NOT NULL,
     app_display_name TEXT NOT NULL,
+    last_success_at TIMESTAMP,
+    last_failure_at TIMESTAMP,
+    last_failure_reason TEXT,
+    last_failure_status INTEGER,
+    consecutive_failures INTEGER NOT NULL DEFAULT 0,
+    backoff_until TIMESTAMP,
     PRIMARY KEY (user_id, app_id)
 );
 
+CREATE TABLE registration_nonces (
+    nonce TEXT PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
 CREATE TABLE room_account_data (
     id BIGSERIAL PRIMARY KEY,
     user_id TEXT NOT NULL,
@@ -110,14 +319,61 @@ CREATE TABLE rooms (
     id TEXT NOT NULL PRIMARY KEY,
     user_id TEXT NOT NULL,
     public BOOLEAN NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    room_type TEXT,
+    joined_member_count BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE threepid_sessions (
+    id TEXT NOT NULL PRIMARY KEY,
+    client_secret TEXT NOT NULL,
+    medium TEXT NOT NULL,
+    address TEXT NOT NULL,
+    token TEXT NOT NULL,
+    send_attempt BIGINT NOT NULL,
+    validated BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    purpose TEXT NOT NULL,
+    token_attempts INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX threepid_sessions_client_secret_idx ON threepid_sessions (client_secret, medium, address);
+
+CREATE TABLE threepids (
+    medium TEXT NOT NULL,
+    address TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (medium, address)
+);
+
+CREATE TABLE to_device_messages (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    content TEXT NOT NULL,
     created_at TIMESTAMP NOT NULL DEFAULT now()
 );
 
+CREATE INDEX to_device_messages_device_idx ON to_device_messages (user_id, device_id, id);
+
 CREATE TABLE transactions (
     path TEXT NOT NULL,
     access_token TEXT NOT NULL,
+    user_id TEXT NOT NULL,
     response TEXT NOT NULL,
-    PRIMARY KEY (path, access_token)
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (path, access_token, user_id)
+);
+
+CREATE TABLE user_erasures (
+    user_id TEXT NOT NULL PRIMARY KEY,
+    redacted_up_to BIGINT NOT NULL DEFAULT 0,
+    redacted_events BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    completed_at TIMESTAMP
 );
 
 CREATE TABLE users (
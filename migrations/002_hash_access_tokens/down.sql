@@ -0,0 +1,4 @@
+-- The plaintext tokens cannot be recovered from their hashes, so all sessions end.
+DROP INDEX access_tokens_token_hash_idx;
+
+ALTER TABLE access_tokens RENAME COLUMN token_hash TO value;
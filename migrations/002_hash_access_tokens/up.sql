@@ -0,0 +1,9 @@
+-- Access tokens were stored in plaintext. Only their SHA-256 hashes are stored from now on, and
+-- the stored tokens are hashed in place, so their sessions keep working.
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE access_tokens RENAME COLUMN value TO token_hash;
+
+UPDATE access_tokens SET token_hash = encode(digest(token_hash, 'sha256'), 'hex');
+
+CREATE UNIQUE INDEX access_tokens_token_hash_idx ON access_tokens (token_hash);
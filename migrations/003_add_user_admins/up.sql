@@ -0,0 +1,3 @@
+-- Server administrators can be registered with the registration shared secret or `ruma user
+-- create --admin`, in addition to the `server_admins` of the configuration.
+ALTER TABLE users ADD COLUMN admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,5 @@
+//! API endpoints for the media repository of the r0 version of the Matrix spec.
+
+pub use self::preview_url::PreviewUrl;
+
+mod preview_url;
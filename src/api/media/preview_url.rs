@@ -0,0 +1,139 @@
+//! Endpoints for previews of web pages.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use url::Url;
+
+use config::Config;
+use error::ApiError;
+use middleware::{AccessTokenAuth, MiddlewareChain};
+use modifier::SerializableResponse;
+use url_preview::preview;
+
+/// The GET `/preview_url` endpoint.
+///
+/// Returns the OpenGraph properties of the page at the `url` query parameter. Previews are
+/// disabled unless `url_preview` is configured, since the server fetches every page a user asks
+/// for.
+pub struct PreviewUrl;
+
+middleware_chain!(PreviewUrl, [AccessTokenAuth]);
+
+impl Handler for PreviewUrl {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let preview_config = match config.url_preview {
+            Some(ref preview_config) => preview_config,
+            None => Err(ApiError::unimplemented("URL previews are disabled.".to_string()))?,
+        };
+
+        let url: Url = request.url.clone().into();
+
+        let mut target = None;
+
+        for (key, value) in url.query_pairs().into_owned() {
+            if key == "url" {
+                target = Some(value);
+            }
+        }
+
+        let target = match target {
+            Some(target) => target,
+            None => Err(ApiError::missing_param("url"))?,
+        };
+
+        let target = Url::parse(&target).map_err(|_| {
+            ApiError::invalid_param("url", "Must be an absolute HTTP or HTTPS URL.")
+        })?;
+
+        let response = preview(&target, preview_config)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use config::UrlPreviewConfig;
+    use test::Test;
+    use url_preview::serve_page;
+
+    /// A `Test` with URL previews of any host enabled.
+    fn test_with_previews() -> Test {
+        Test::with_config(|config| {
+            config.url_preview = Some(UrlPreviewConfig {
+                allowed_hosts: Vec::new(),
+                denied_hosts: vec!["intranet.example.com".to_string()],
+                max_size: 1024,
+            });
+        })
+    }
+
+    fn preview_path(url: &str, access_token: &str) -> String {
+        format!("/_matrix/media/r0/preview_url?url={}&access_token={}", url, access_token)
+    }
+
+    #[test]
+    fn preview_url() {
+        let test = test_with_previews();
+        let carl = test.create_user();
+
+        serve_page(
+            "https://example.com/article",
+            r#"<meta property="og:title" content="Ruma">
+            <meta property="og:description" content="A Matrix homeserver">"#,
+        );
+
+        let response = test.get(&preview_path("https://example.com/article", &carl.token));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("og:title").unwrap().as_str().unwrap(), "Ruma");
+        assert_eq!(
+            response.json().get("og:description").unwrap().as_str().unwrap(),
+            "A Matrix homeserver"
+        );
+    }
+
+    #[test]
+    fn previews_are_disabled_by_default() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        serve_page("https://example.com/article", "<title>Ruma</title>");
+
+        let response = test.get(&preview_path("https://example.com/article", &carl.token));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn preview_of_denied_host() {
+        let test = test_with_previews();
+        let carl = test.create_user();
+
+        serve_page("https://intranet.example.com/", "<title>Secrets</title>");
+
+        let response = test.get(&preview_path("https://intranet.example.com/", &carl.token));
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn preview_without_url() {
+        let test = test_with_previews();
+        let carl = test.create_user();
+
+        let response = test.get(
+            &format!("/_matrix/media/r0/preview_url?access_token={}", carl.token)
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
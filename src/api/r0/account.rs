@@ -1,10 +1,9 @@
 //! Endpoints for accounts.
 use bodyparser;
-use diesel::SaveChangesDsl;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use diesel::pg::PgConnection;
+use iron::{BeforeMiddleware, Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use iron::status::Status;
 
-use crypto::hash_password;
 use db::DB;
 use error::ApiError;
 use middleware::{
@@ -23,19 +22,38 @@ use models::account_data::{
     NewRoomAccountData,
 };
 use models::room_membership::RoomMembership;
+use models::threepid::Threepid;
+use models::threepid_session::{
+    EMAIL_MEDIUM,
+    PASSWORD_RESET_PURPOSE,
+    ThreepidCredentials,
+    ThreepidSession,
+};
 use models::user::User;
 use modifier::EmptyResponse;
 
 /// The `/account/password` endpoint.
+///
+/// Users who have forgotten their password authenticate with a validated email address bound to
+/// their account instead of an access token. Resetting the password this way uses up the
+/// validation session and logs out all of the user's devices.
 #[derive(Debug)]
 pub struct AccountPassword;
 
 #[derive(Clone, Debug, Deserialize)]
 struct AccountPasswordRequest {
     pub new_password: String,
+    /// Proof that the user owns an email address bound to the account.
+    pub auth: Option<PasswordResetAuth>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PasswordResetAuth {
+    /// The credentials of the session in which the email address was validated.
+    pub threepid_creds: ThreepidCredentials,
 }
 
-middleware_chain!(AccountPassword, [AccessTokenAuth]);
+middleware_chain!(AccountPassword);
 
 impl Handler for AccountPassword {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
@@ -46,46 +64,97 @@ impl Handler for AccountPassword {
                 Ok(None) | Err(_) => Err(ApiError::not_json(None))?,
             };
 
-        let mut user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let new_password = account_password_request.new_password;
 
-        user.password_hash = hash_password(&account_password_request.new_password)?;
+        match account_password_request.auth {
+            Some(auth) => {
+                DB::with_transaction(request, |connection| {
+                    let (mut user, session) =
+                        find_user_by_validated_email(connection, &auth.threepid_creds)?;
 
-        let connection = DB::from_request(request)?;
+                    user.set_password(connection, &new_password)?;
+                    AccessToken::revoke_all(connection, &user.id)?;
+                    session.delete(connection)?;
+
+                    Ok(())
+                })?;
+            }
+            None => {
+                AccessTokenAuth.before(request)?;
 
-        user.save_changes::<User>(&*connection)
-            .map_err(|_| ApiError::unauthorized(None))?;
+                let connection = DB::from_request(request)?;
+
+                let mut user = request.extensions.get::<User>()
+                    .expect("AccessTokenAuth should ensure a user")
+                    .clone();
+
+                user.set_password(&connection, &new_password)?;
+            }
+        }
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
 }
 
+/// Find the user that the email address of a session validated for a password reset is bound to.
+fn find_user_by_validated_email(connection: &PgConnection, credentials: &ThreepidCredentials)
+-> Result<(User, ThreepidSession), ApiError> {
+    let session = ThreepidSession::find_validated(
+        connection,
+        credentials,
+        PASSWORD_RESET_PURPOSE,
+    )?;
+
+    let session = match session {
+        Some(session) => session,
+        None => return Err(ApiError::threepid_auth_failed(None)),
+    };
+
+    if session.medium != EMAIL_MEDIUM {
+        return Err(ApiError::threepid_auth_failed(None));
+    }
+
+    let threepid = match Threepid::find_by_address(connection, EMAIL_MEDIUM, &session.address)? {
+        Some(threepid) => threepid,
+        None => return Err(ApiError::threepid_not_found(None)),
+    };
+
+    match User::find_active_user(connection, &threepid.user_id)? {
+        Some(user) => Ok((user, session)),
+        None => Err(ApiError::threepid_not_found(None)),
+    }
+}
+
 /// The `/account/deactivate` endpoint.
+///
+/// With `erase` set to true, the user's personal data is erased as well: the events they sent are
+/// redacted and their profile and third party identifiers are deleted in the background.
 #[derive(Debug)]
 pub struct DeactivateAccount;
 
+#[derive(Clone, Debug, Deserialize)]
+struct DeactivateAccountRequest {
+    /// Whether the user's personal data should be erased.
+    #[serde(default)]
+    pub erase: bool,
+}
+
 middleware_chain!(DeactivateAccount, [AccessTokenAuth]);
 
 impl Handler for DeactivateAccount {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let connection = DB::from_request(request)?;
-
-        {
-            let token = request.extensions.get_mut::<AccessToken>()
-                .expect("AccessTokenAuth should ensure an access token");
+        let erase = match request.get::<bodyparser::Struct<DeactivateAccountRequest>>() {
+            Ok(Some(deactivate_request)) => deactivate_request.erase,
+            Ok(None) => false,
+            Err(_) => Err(ApiError::bad_json(None))?,
+        };
 
-            token.revoke(&connection)?;
-        }
+        let connection = DB::from_request(request)?;
 
         let user = request.extensions.get_mut::<User>()
             .expect("AccessTokenAuth should ensure a user");
 
-        user.deactivate(&connection)?;
-
-        // Delete all the account data associated with the user.
-        AccountData::delete_by_uid(&connection, &user.id)?;
-        RoomAccountData::delete_by_uid(&connection, &user.id)?;
+        user.deactivate(&connection, erase)?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -202,9 +271,17 @@ impl Handler for PutRoomAccountData {
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use test::{Test, email_token};
     use iron::status::Status;
 
+    use models::presence_status::advance_clock;
+    use models::threepid_session::{
+        BINDING_PURPOSE,
+        EMAIL_MEDIUM,
+        ThreepidCredentials,
+        ThreepidSession,
+    };
+
     #[test]
     fn change_password() {
         let test = Test::new();
@@ -223,6 +300,161 @@ mod tests {
         assert_eq!(response.status, Status::Ok);
     }
 
+    #[test]
+    fn reset_password_by_email() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        test.bind_email(&carl, "carl@example.com");
+
+        let body = r#"{"client_secret": "reset", "email": "carl@example.com", "send_attempt": 1}"#;
+        let response = test.post("/_matrix/client/r0/account/password/email/requestToken", body);
+        let sid = response.json().get("sid").unwrap().as_str().unwrap().to_string();
+
+        let emails = test.deliver_emails();
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, "carl@example.com");
+        assert_eq!(emails[0].subject, "Reset your password on ruma.test");
+
+        let reset = format!(
+            r#"{{
+                "new_password": "hidden",
+                "auth": {{
+                    "type": "m.login.email.identity",
+                    "threepid_creds": {{"sid": "{}", "client_secret": "reset"}}
+                }}
+            }}"#,
+            sid
+        );
+
+        // The token has not been submitted yet.
+        let response = test.post("/_matrix/client/r0/account/password", &reset);
+
+        assert_eq!(response.status, Status::Unauthorized);
+
+        let body = format!(
+            r#"{{"sid": "{}", "client_secret": "reset", "token": "{}"}}"#,
+            sid,
+            email_token(&emails[0])
+        );
+        test.post("/_matrix/client/r0/account/password/email/submitToken", &body);
+
+        let response = test.post("/_matrix/client/r0/account/password", &reset);
+        test.check_empty_response(response);
+
+        let login = format!(
+            r#"{{"type": "m.login.password", "user": "{}", "password": "hidden"}}"#,
+            carl.name
+        );
+
+        assert_eq!(test.post("/_matrix/client/r0/login", &login).status, Status::Ok);
+
+        // The reset logged out Carl's devices.
+        let response = test.get(
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token)
+        );
+
+        assert_eq!(response.status, Status::Unauthorized);
+
+        // The session was used up.
+        let response = test.post("/_matrix/client/r0/account/password", &reset);
+
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn reset_password_with_expired_session() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        test.bind_email(&carl, "carl@example.com");
+
+        let body = r#"{"client_secret": "reset", "email": "carl@example.com", "send_attempt": 1}"#;
+        let response = test.post("/_matrix/client/r0/account/password/email/requestToken", body);
+        let sid = response.json().get("sid").unwrap().as_str().unwrap().to_string();
+        let emails = test.deliver_emails();
+
+        let body = format!(
+            r#"{{"sid": "{}", "client_secret": "reset", "token": "{}"}}"#,
+            sid,
+            email_token(&emails[0])
+        );
+        test.post("/_matrix/client/r0/account/password/email/submitToken", &body);
+
+        advance_clock(2 * 60 * 60 * 1000);
+
+        let reset = format!(
+            r#"{{
+                "new_password": "hidden",
+                "auth": {{
+                    "type": "m.login.email.identity",
+                    "threepid_creds": {{"sid": "{}", "client_secret": "reset"}}
+                }}
+            }}"#,
+            sid
+        );
+        let response = test.post("/_matrix/client/r0/account/password", &reset);
+
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn reset_password_with_binding_session() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        test.bind_email(&carl, "carl@example.com");
+
+        // The email address is validated again, but to be bound rather than for a reset.
+        let sid = test.with_connection(|connection| {
+            let (session, _) = ThreepidSession::request_token(
+                connection,
+                EMAIL_MEDIUM,
+                "carl@example.com",
+                "bind",
+                1,
+                BINDING_PURPOSE,
+            ).unwrap();
+            let credentials = ThreepidCredentials {
+                sid: session.id.clone(),
+                client_secret: "bind".to_string(),
+            };
+
+            let validated = ThreepidSession::submit_token(connection, &credentials, &session.token);
+
+            assert!(validated.unwrap());
+
+            session.id
+        });
+
+        let reset = format!(
+            r#"{{
+                "new_password": "hidden",
+                "auth": {{
+                    "type": "m.login.email.identity",
+                    "threepid_creds": {{"sid": "{}", "client_secret": "bind"}}
+                }}
+            }}"#,
+            sid
+        );
+        let response = test.post("/_matrix/client/r0/account/password", &reset);
+
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn change_password_without_authentication() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/account/password",
+            r#"{"new_password": "hidden"}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
     #[test]
     fn deactivate_account() {
         let test = Test::new();
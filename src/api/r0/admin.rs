@@ -0,0 +1,2076 @@
+//! Endpoints for server administration.
+
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind, Write};
+
+use bodyparser;
+use diesel::{Connection, LoadDsl, insert};
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::headers::{ContentType, Encoding, TransferEncoding};
+use iron::response::WriteBody;
+use iron::status::Status;
+use r2d2::PooledConnection;
+use r2d2_diesel::ConnectionManager;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use serde_json::{Value, to_writer};
+use url::Url;
+
+use config::Config;
+use crypto::{hash_password, sha256_hex, verify_hmac_sha1_hex};
+use db::DB;
+use error::{ApiError, MapApiError};
+use event_validation::{new_room_event, verify_event_type_allowed};
+use middleware::{JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam};
+use models::admin_audit_log::AuditLogEntry;
+use models::event::{Event, NewImportedEvent};
+use models::event_relation::EventRelation;
+use models::monthly_active_user::MonthlyActiveUser;
+use models::pusher::{DeliveryStats, Pusher, PusherOptions};
+use models::registration_nonce::RegistrationNonce;
+use models::room::Room;
+use models::room_alias::RoomAlias;
+use models::room_export::RoomExport;
+use models::room_membership::{MembershipTransition, RoomMembership};
+use models::user::{NewUser, User};
+use models::user_erasure::UserErasure;
+use modifier::SerializableResponse;
+use schema::events;
+use util::user_id::local_user_id;
+
+/// The POST `/admin/rooms/:room_id/batch_send` endpoint.
+///
+/// Saves several room events in a single transaction, keeping the `origin_server_ts` of each
+/// event, e.g. to import the history of a room from another system. Nothing is saved if any event
+/// is invalid. The request is authenticated with the registration shared secret and a nonce from
+/// GET `/admin/register` in the `nonce` query parameter: the `mac` query parameter must be the
+/// hex encoded HMAC-SHA1 of the nonce, *batch_send*, the room ID and the hex encoded SHA-256 hash
+/// of the request body, separated by NUL bytes, keyed with the secret.
+pub struct BatchSendEvents;
+
+#[derive(Clone, Debug, Deserialize)]
+struct BatchSendEventsRequest {
+    /// The events to save, oldest first.
+    events: Vec<BatchEvent>,
+}
+
+/// A room event to save as part of a batch.
+#[derive(Clone, Debug, Deserialize)]
+struct BatchEvent {
+    /// The type of the event, e.g. *m.room.message*.
+    #[serde(rename = "type")]
+    event_type: String,
+    /// The content of the event.
+    content: Value,
+    /// The user who sent the event.
+    sender: UserId,
+    /// The time the event was originally sent, in milliseconds since the Unix epoch.
+    origin_server_ts: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchSendEventsResponse {
+    /// The IDs of the saved events, in the order they were given.
+    event_ids: Vec<String>,
+}
+
+middleware_chain!(BatchSendEvents, [JsonRequest, RoomIdParam]);
+
+impl Handler for BatchSendEvents {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let body = match request.get::<bodyparser::Raw>() {
+            Ok(Some(body)) => body,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let message = format!("batch_send\0{}\0{}", room_id, sha256_hex(body.as_bytes()));
+
+        verify_request_nonce_mac(request, message.as_bytes())?;
+
+        let batch_request = match request.get::<bodyparser::Struct<BatchSendEventsRequest>>() {
+            Ok(Some(batch_request)) => batch_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        if Room::find(&connection, &room_id)?.is_none() {
+            Err(ApiError::not_found("The room was not found on this server".to_string()))?;
+        }
+
+        let mut new_events = Vec::with_capacity(batch_request.events.len());
+
+        for event in batch_request.events {
+            let event_id = EventId::new(&config.domain).map_api_err(|_| {
+                ApiError::unknown("Failed to generated event ID for the new event.".to_string())
+            })?;
+
+            let event_type = EventType::from(event.event_type.as_ref());
+
+            verify_event_type_allowed(&config, &event_type)?;
+
+            let new_event = new_room_event(
+                &event_type,
+                event.content,
+                &event_id,
+                &room_id,
+                &event.sender,
+            )?;
+
+            new_events.push(NewImportedEvent::new(new_event, event.origin_server_ts)?);
+        }
+
+        // A single insert gives the events consecutive orderings.
+        let events = connection.transaction::<Vec<Event>, ApiError, _>(|| {
+            let events: Vec<Event> = insert(&new_events)
+                .into(events::table)
+                .get_results(&*connection)
+                .map_err(ApiError::from)?;
+
+            EventRelation::create_for_events(&connection, &events)?;
+
+            Ok(events)
+        }).map_err(ApiError::from)?;
+
+        let response = BatchSendEventsResponse {
+            event_ids: events.iter().map(|event| event.id.to_string()).collect(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/admin/directory/bulk_resolve` endpoint.
+///
+/// Resolves many room aliases at once, e.g. for bridges that manage a lot of rooms. Aliases that
+/// are unknown to this server resolve to `null` instead of failing the request. At most
+/// `max_bulk_resolve_aliases` aliases can be resolved per request. The request is authenticated
+/// with the registration shared secret: the `mac` query parameter must be the hex encoded
+/// HMAC-SHA1 of the string *bulk_resolve*, keyed with the secret.
+pub struct BulkResolveRoomAliases;
+
+#[derive(Clone, Debug, Deserialize)]
+struct BulkResolveRoomAliasesRequest {
+    /// The aliases to resolve.
+    aliases: Vec<RoomAliasId>,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkResolveRoomAliasesResponse {
+    /// The room each of the requested aliases points to, or `None` for unknown aliases.
+    aliases: HashMap<String, Option<ResolvedRoomAlias>>,
+}
+
+/// The room a room alias points to.
+#[derive(Debug, Serialize)]
+struct ResolvedRoomAlias {
+    /// The ID of the room.
+    room_id: RoomId,
+    /// A list of servers that are aware of this room ID.
+    servers: Vec<String>,
+}
+
+middleware_chain!(BulkResolveRoomAliases, [JsonRequest]);
+
+impl Handler for BulkResolveRoomAliases {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        verify_request_mac(request, b"bulk_resolve")?;
+
+        let resolve_request =
+            match request.get::<bodyparser::Struct<BulkResolveRoomAliasesRequest>>() {
+                Ok(Some(resolve_request)) => resolve_request,
+                Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+            };
+
+        let config = Config::from_request(request)?;
+
+        if resolve_request.aliases.len() > config.max_bulk_resolve_aliases {
+            Err(ApiError::invalid_param(
+                "aliases",
+                &format!(
+                    "At most {} aliases can be resolved at once.",
+                    config.max_bulk_resolve_aliases
+                ),
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let mut aliases: HashMap<String, Option<ResolvedRoomAlias>> = resolve_request.aliases
+            .iter()
+            .map(|alias| (alias.to_string(), None))
+            .collect();
+
+        for room_alias in RoomAlias::find_by_aliases(&connection, &resolve_request.aliases)? {
+            let resolved = ResolvedRoomAlias {
+                room_id: room_alias.room_id,
+                servers: room_alias.servers,
+            };
+
+            aliases.insert(room_alias.alias.to_string(), Some(resolved));
+        }
+
+        let response = BulkResolveRoomAliasesResponse {
+            aliases: aliases,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/register` endpoint.
+pub struct GetRegistrationNonce;
+
+#[derive(Debug, Serialize)]
+struct GetRegistrationNonceResponse {
+    /// A single-use nonce to include in the shared-secret registration request.
+    nonce: String,
+}
+
+middleware_chain!(GetRegistrationNonce);
+
+impl Handler for GetRegistrationNonce {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        if config.registration_shared_secret.is_none() {
+            Err(ApiError::unimplemented(
+                "Shared-secret registration is disabled.".to_string()
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let registration_nonce = RegistrationNonce::create(&connection)?;
+
+        let response = GetRegistrationNonceResponse {
+            nonce: registration_nonce.nonce,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/monthly_active_users` endpoint.
+///
+/// The request is authenticated with the registration shared secret: the `mac` query parameter
+/// must be the hex encoded HMAC-SHA1 of the string *monthly_active_users*, keyed with the secret.
+pub struct GetMonthlyActiveUsers;
+
+#[derive(Debug, Serialize)]
+struct GetMonthlyActiveUsersResponse {
+    /// The number of users that have made a request within the last 30 days.
+    count: i64,
+    /// The maximum number of monthly active users, if there is one.
+    max_mau: Option<u64>,
+}
+
+middleware_chain!(GetMonthlyActiveUsers);
+
+impl Handler for GetMonthlyActiveUsers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        verify_request_mac(request, b"monthly_active_users")?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let response = GetMonthlyActiveUsersResponse {
+            count: MonthlyActiveUser::count(&connection)?,
+            max_mau: config.max_mau,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/rooms/:room_id/export` endpoint.
+///
+/// The request is authenticated with the registration shared secret and a nonce from GET
+/// `/admin/register` in the `nonce` query parameter: the `mac` query parameter must be the hex
+/// encoded HMAC-SHA1 of the nonce, *room_export* and the room ID, separated by NUL bytes, keyed
+/// with the secret.
+pub struct GetRoomExport;
+
+/// A response body that serializes a `RoomExport` while it is being written.
+struct RoomExportBody(RoomExport);
+
+impl WriteBody for RoomExportBody {
+    fn write_body(&mut self, body: &mut Write) -> Result<(), IoError> {
+        to_writer(body, &self.0).map_err(|err| IoError::new(ErrorKind::Other, err))
+    }
+}
+
+middleware_chain!(GetRoomExport, [RoomIdParam]);
+
+impl Handler for GetRoomExport {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        verify_request_nonce_mac(request, format!("room_export\0{}", room_id).as_bytes())?;
+
+        let connection = DB::from_request(request)?;
+
+        let export = Room::export(&connection, &room_id)?;
+
+        let mut response = Response::with(Status::Ok);
+
+        // The export is serialized while it is sent, so it must not be collected in memory to be
+        // compressed.
+        response.headers.set(ContentType::json());
+        response.headers.set(TransferEncoding(vec![Encoding::Chunked]));
+        response.body = Some(Box::new(RoomExportBody(export)));
+
+        Ok(response)
+    }
+}
+
+/// The GET `/admin/server_version` endpoint.
+///
+/// The exact version tells attackers which vulnerabilities the server has, so the request is
+/// authenticated with the registration shared secret: the `mac` query parameter must be the hex
+/// encoded HMAC-SHA1 of the string *server_version*, keyed with the secret.
+pub struct GetServerVersion;
+
+#[derive(Debug, Serialize)]
+struct GetServerVersionResponse {
+    /// The version of Ruma.
+    server_version: &'static str,
+    /// The git commit Ruma was built from, or *unknown* if it was built outside of a git checkout.
+    git_commit: &'static str,
+}
+
+middleware_chain!(GetServerVersion);
+
+impl Handler for GetServerVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        verify_request_mac(request, b"server_version")?;
+
+        let response = GetServerVersionResponse {
+            server_version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("RUMA_GIT_COMMIT"),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/users/:user_id/data_export` endpoint.
+///
+/// Exports everything stored about a user, e.g. to answer a data subject access request. The
+/// contents of the events the user sent are only included if the `include_contents` query
+/// parameter is *true*. Every export is recorded in the admin audit log.
+///
+/// The export is read from a single snapshot of the database while it is sent, so that the
+/// account and the sent events are consistent and a prolific user's export does not have to fit
+/// into memory. The database connection is held until the download is complete. The request is
+/// authenticated with the registration shared secret and a nonce from GET `/admin/register` in
+/// the `nonce` query parameter: the `mac` query parameter must be the hex encoded HMAC-SHA1 of
+/// the nonce, *data_export* and the user ID, separated by NUL bytes, keyed with the secret.
+pub struct GetUserDataExport;
+
+/// A response body that writes the export of a user while the sent events are loaded.
+struct UserExportBody {
+    /// The user to export.
+    user_id: UserId,
+    /// The connection the export is read with.
+    connection: PooledConnection<ConnectionManager<PgConnection>>,
+    /// Whether the contents of the sent events are included.
+    include_contents: bool,
+}
+
+impl WriteBody for UserExportBody {
+    fn write_body(&mut self, body: &mut Write) -> Result<(), IoError> {
+        let connection = &*self.connection;
+        let user_id = &self.user_id;
+        let include_contents = self.include_contents;
+
+        DB::with_snapshot(connection, || {
+            let export = User::export(connection, user_id)?;
+
+            export.write(connection, include_contents, body).map_err(|error| {
+                ApiError::unknown(format!("Failed to export the user: {}", error))
+            })
+        }).map_err(|error| IoError::new(ErrorKind::Other, error))
+    }
+}
+
+middleware_chain!(GetUserDataExport, [UserIdParam]);
+
+impl Handler for GetUserDataExport {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("Should have been required by UserIdParam.")
+            .clone();
+
+        verify_request_nonce_mac(request, format!("data_export\0{}", user_id).as_bytes())?;
+
+        let url: Url = request.url.clone().into();
+        let mut include_contents = false;
+
+        for (key, value) in url.query_pairs().into_owned() {
+            match (key.as_ref(), value.as_ref()) {
+                ("include_contents", "true") => include_contents = true,
+                ("include_contents", "false") => include_contents = false,
+                ("include_contents", _) => {
+                    Err(ApiError::invalid_param("include_contents", "No boolean!"))?;
+                }
+                _ => {}
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        if User::find_registered_user(&connection, &user_id)?.is_none() {
+            Err(ApiError::not_found(format!("The user {} was not found", user_id)))?;
+        }
+
+        AuditLogEntry::record(&connection, "user_data_export", &user_id.to_string())?;
+
+        let mut response = Response::with(Status::Ok);
+
+        // The export is written while it is sent, so it must not be collected in memory to be
+        // compressed.
+        response.headers.set(ContentType::json());
+        response.headers.set(TransferEncoding(vec![Encoding::Chunked]));
+        response.body = Some(Box::new(UserExportBody {
+            user_id: user_id,
+            connection: connection,
+            include_contents: include_contents,
+        }));
+
+        Ok(response)
+    }
+}
+
+/// The GET `/admin/users/:user_id/erasure` endpoint.
+///
+/// Returns the progress of the erasure of a deactivated user's personal data, which reports
+/// *done* once all of it is gone. The request is authenticated with the registration shared
+/// secret and a nonce from GET `/admin/register` in the `nonce` query parameter: the `mac` query
+/// parameter must be the hex encoded HMAC-SHA1 of the nonce, *erasure* and the user ID, separated
+/// by NUL bytes, keyed with the secret.
+pub struct GetUserErasure;
+
+middleware_chain!(GetUserErasure, [UserIdParam]);
+
+impl Handler for GetUserErasure {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("Should have been required by UserIdParam.")
+            .clone();
+
+        verify_request_nonce_mac(request, format!("erasure\0{}", user_id).as_bytes())?;
+
+        let connection = DB::from_request(request)?;
+
+        let erasure = match UserErasure::find(&connection, &user_id)? {
+            Some(erasure) => erasure,
+            None => Err(ApiError::not_found(format!("No erasure of {} was requested", user_id)))?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(erasure.status()))))
+    }
+}
+
+/// The GET `/admin/rooms/:room_id/memberships/:user_id` endpoint.
+///
+/// Returns every change of a user's membership in a room with its reason and time, for
+/// moderation tools. The request is authenticated with the registration shared secret: the `mac`
+/// query parameter must be the hex encoded HMAC-SHA1 of *memberships:* followed by the room ID, a
+/// colon and the user ID, keyed with the secret.
+pub struct GetMembershipTransitions;
+
+#[derive(Debug, Serialize)]
+struct GetMembershipTransitionsResponse {
+    /// The changes of the membership, oldest first.
+    transitions: Vec<MembershipTransition>,
+}
+
+middleware_chain!(GetMembershipTransitions, [RoomIdParam, UserIdParam]);
+
+impl Handler for GetMembershipTransitions {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("Should have been required by UserIdParam.")
+            .clone();
+
+        verify_request_mac(request, format!("memberships:{}:{}", room_id, user_id).as_bytes())?;
+
+        let connection = DB::from_request(request)?;
+
+        if Room::find(&connection, &room_id)?.is_none() {
+            Err(ApiError::not_found(format!("The room {} was not found on this server", room_id)))?;
+        }
+
+        let response = GetMembershipTransitionsResponse {
+            transitions: RoomMembership::find_transitions(&connection, &room_id, &user_id)?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/pushers/:user_id` endpoint.
+///
+/// Returns the pushers of a user with their delivery statistics, to find out why notifications
+/// do not arrive. The request is authenticated with the registration shared secret: the `mac`
+/// query parameter must be the hex encoded HMAC-SHA1 of *pushers:* followed by the user ID, keyed
+/// with the secret.
+pub struct GetUserPushers;
+
+#[derive(Debug, Serialize)]
+struct GetUserPushersResponse {
+    /// The pushers of the user.
+    pushers: Vec<UserPusher>,
+}
+
+/// A pusher with its delivery statistics.
+#[derive(Debug, Serialize)]
+struct UserPusher {
+    /// The pusher, as returned to the user by GET `/pushers`.
+    pusher: PusherOptions,
+    /// The delivery statistics of the pusher.
+    delivery: DeliveryStats,
+}
+
+middleware_chain!(GetUserPushers, [UserIdParam]);
+
+impl Handler for GetUserPushers {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user_id = request.extensions.get::<UserIdParam>()
+            .expect("Should have been required by UserIdParam.")
+            .clone();
+
+        verify_request_mac(request, format!("pushers:{}", user_id).as_bytes())?;
+
+        let connection = DB::from_request(request)?;
+
+        let pushers = Pusher::find_by_uid(&connection, &user_id)?
+            .into_iter()
+            .map(|pusher| {
+                UserPusher {
+                    delivery: pusher.delivery_stats(),
+                    pusher: PusherOptions::from(pusher),
+                }
+            })
+            .collect();
+
+        let response = GetUserPushersResponse {
+            pushers: pushers,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/admin/register` endpoint.
+pub struct SharedSecretRegister;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SharedSecretRegisterRequest {
+    /// The nonce retrieved from GET `/admin/register`.
+    pub nonce: String,
+    /// The local part of the Matrix ID to register.
+    pub username: String,
+    /// The password for the account.
+    pub password: String,
+    /// Whether the account should be a server administrator.
+    #[serde(default)]
+    pub admin: bool,
+    /// The hex encoded HMAC-SHA1 of the nonce, username, password and admin flag, separated by
+    /// NUL bytes, keyed with the registration shared secret.
+    pub mac: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SharedSecretRegisterResponse {
+    /// An access token for the account.
+    pub access_token: String,
+    /// The hostname of the homeserver on which the account has been registered.
+    pub home_server: String,
+    /// The fully-qualified Matrix ID that has been registered.
+    pub user_id: UserId,
+}
+
+middleware_chain!(SharedSecretRegister, [JsonRequest]);
+
+impl Handler for SharedSecretRegister {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let register_request = match request.get::<bodyparser::Struct<SharedSecretRegisterRequest>>() {
+            Ok(Some(register_request)) => register_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let config = Config::from_request(request)?;
+
+        let shared_secret = match config.registration_shared_secret {
+            Some(ref shared_secret) => shared_secret.clone(),
+            None => Err(ApiError::unimplemented(
+                "Shared-secret registration is disabled.".to_string()
+            ))?,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        if !RegistrationNonce::consume(&connection, &register_request.nonce)? {
+            let error = ApiError::unauthorized("Unrecognised nonce".to_string());
+
+            return Err(IronError::from(error));
+        }
+
+        let message = registration_mac_message(&register_request);
+
+        if !verify_hmac_sha1_hex(shared_secret.as_bytes(), &message, &register_request.mac) {
+            let error = ApiError::unauthorized("HMAC incorrect".to_string());
+
+            return Err(IronError::from(error));
+        }
+
+        let new_user = NewUser {
+            id: local_user_id(&register_request.username, &config.domain, "username")?,
+            password_hash: hash_password(&register_request.password)?,
+            admin: register_request.admin,
+        };
+
+        if User::find_registered_user(&connection, &new_user.id)?.is_some() {
+            Err(ApiError::user_in_use(format!("The user {} already exists.", new_user.id)))?;
+        }
+
+        let (user, access_token) = User::create(
+            &connection,
+            &new_user,
+            &config,
+        )?;
+
+        let response = SharedSecretRegisterResponse {
+            access_token: access_token,
+            home_server: config.domain.clone(),
+            user_id: user.id,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Check that the `mac` query parameter of an administrative request is the HMAC of `message`,
+/// keyed with the registration shared secret.
+fn verify_request_mac(request: &mut Request, message: &[u8]) -> Result<(), ApiError> {
+    let config = Config::from_request(request)?;
+
+    let shared_secret = match config.registration_shared_secret {
+        Some(ref shared_secret) => shared_secret.clone(),
+        None => Err(ApiError::unimplemented(
+            "Shared-secret authentication is disabled.".to_string()
+        ))?,
+    };
+
+    let mac = match query_param(request, "mac") {
+        Some(mac) => mac,
+        None => Err(ApiError::missing_param("mac"))?,
+    };
+
+    if !verify_hmac_sha1_hex(shared_secret.as_bytes(), message, &mac) {
+        Err(ApiError::unauthorized("HMAC incorrect".to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// Like `verify_request_mac`, but the HMAC covers the `nonce` query parameter followed by a NUL
+/// byte and `message`, so the request cannot be replayed.
+///
+/// The nonce must have been retrieved from GET `/admin/register` and is used up.
+fn verify_request_nonce_mac(request: &mut Request, message: &[u8]) -> Result<(), ApiError> {
+    let nonce = match query_param(request, "nonce") {
+        Some(nonce) => nonce,
+        None => Err(ApiError::missing_param("nonce"))?,
+    };
+
+    let mut nonce_message = nonce.clone().into_bytes();
+
+    nonce_message.push(0);
+    nonce_message.extend_from_slice(message);
+
+    verify_request_mac(request, &nonce_message)?;
+
+    let connection = DB::from_request(request)?;
+
+    if !RegistrationNonce::consume(&connection, &nonce)? {
+        Err(ApiError::unauthorized("Unrecognised nonce".to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// The value of the query parameter `name` of the request, if any.
+fn query_param(request: &Request, name: &str) -> Option<String> {
+    let url: Url = request.url.clone().into();
+
+    url.query_pairs().into_owned().find(|&(ref key, _)| key == name).map(|(_, value)| value)
+}
+
+/// Build the message covered by the HMAC of a shared-secret registration request.
+fn registration_mac_message(register_request: &SharedSecretRegisterRequest) -> Vec<u8> {
+    let admin = if register_request.admin { "admin" } else { "notadmin" };
+
+    [
+        register_request.nonce.as_str(),
+        register_request.username.as_str(),
+        register_request.password.as_str(),
+        admin,
+    ].join("\0").into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{
+        Connection,
+        ExecuteDsl,
+        ExpressionMethods,
+        FilterDsl,
+        LoadDsl,
+        SelectDsl,
+        insert,
+        select,
+    };
+    use diesel::expression::dsl::sql;
+    use diesel::types::BigInt;
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::{Value, from_str, from_value};
+
+    use crypto::{hmac_sha1_hex, sha256_hex};
+    use models::admin_audit_log::AuditLogEntry;
+    use models::event::{Event, NewEvent, content_hash};
+    use models::federation_queue::{FederationQueueEntry, PRESENCE_EDU_TYPE};
+    use models::presence_status::advance_clock;
+    use models::push_queue::QueuedPush;
+    use models::pusher::{PusherData, PusherOptions};
+    use models::registration_nonce::RegistrationNonce;
+    use models::room::Room;
+    use models::room_export::RoomExport;
+    use models::threepid::Threepid;
+    use models::to_device_message::ToDeviceMessage;
+    use models::user::User;
+    use push::{sent_notifications, set_gateway_status};
+    use query::SyncOptions;
+    use schema::{
+        devices,
+        event_transactions,
+        events,
+        federation_queue,
+        push_queue,
+        threepid_sessions,
+        to_device_messages,
+    };
+    use test::{REGISTRATION_SHARED_SECRET, Test, TestUser};
+
+    fn get_nonce(test: &Test) -> String {
+        let response = test.get("/_matrix/client/r0/admin/register");
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().get("nonce").unwrap().as_str().unwrap().to_string()
+    }
+
+    fn register_body(nonce: &str, username: &str, password: &str, secret: &str) -> String {
+        admin_register_body(nonce, username, password, secret, false)
+    }
+
+    fn admin_register_body(nonce: &str, username: &str, password: &str, secret: &str, admin: bool)
+    -> String {
+        let admin_flag = if admin { "admin" } else { "notadmin" };
+        let message = format!("{}\0{}\0{}\0{}", nonce, username, password, admin_flag);
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!(
+            r#"{{"nonce": "{}", "username": "{}", "password": "{}", "admin": {}, "mac": "{}"}}"#,
+            nonce,
+            username,
+            password,
+            admin,
+            mac
+        )
+    }
+
+    fn export_path(test: &Test, room_id: &str, secret: &str) -> String {
+        let nonce = get_nonce(test);
+        let message = format!("{}\0room_export\0{}", nonce, room_id);
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!("/_matrix/client/r0/admin/rooms/{}/export?nonce={}&mac={}", room_id, nonce, mac)
+    }
+
+    fn data_export_path(test: &Test, user_id: &str, secret: &str, query: &str) -> String {
+        let nonce = get_nonce(test);
+        let message = format!("{}\0data_export\0{}", nonce, user_id);
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!(
+            "/_matrix/client/r0/admin/users/{}/data_export?nonce={}&mac={}{}",
+            user_id,
+            nonce,
+            mac,
+            query
+        )
+    }
+
+    fn erasure_path(test: &Test, user_id: &str, secret: &str) -> String {
+        let nonce = get_nonce(test);
+        let message = format!("{}\0erasure\0{}", nonce, user_id);
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!("/_matrix/client/r0/admin/users/{}/erasure?nonce={}&mac={}", user_id, nonce, mac)
+    }
+
+    fn memberships_path(room_id: &str, user_id: &str, secret: &str) -> String {
+        let message = format!("memberships:{}:{}", room_id, user_id);
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!(
+            "/_matrix/client/r0/admin/rooms/{}/memberships/{}?mac={}",
+            room_id,
+            user_id,
+            mac
+        )
+    }
+
+    fn pushers_path(user_id: &str, secret: &str) -> String {
+        let message = format!("pushers:{}", user_id);
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!("/_matrix/client/r0/admin/pushers/{}?mac={}", user_id, mac)
+    }
+
+    /// The delivery statistics of the only pusher of the user.
+    fn delivery_stats(test: &Test, user: &TestUser) -> Value {
+        let response = test.get(&pushers_path(&user.id, REGISTRATION_SHARED_SECRET));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let pushers = response.json().get("pushers").unwrap().as_array().unwrap().clone();
+
+        assert_eq!(pushers.len(), 1);
+        assert_eq!(
+            pushers[0].get("pusher").unwrap().get("app_id").unwrap().as_str().unwrap(),
+            "io.ruma.test"
+        );
+
+        pushers[0].get("delivery").unwrap().clone()
+    }
+
+    fn batch_send_path(test: &Test, room_id: &str, body: &str, secret: &str) -> String {
+        let nonce = get_nonce(test);
+        let message = format!(
+            "{}\0batch_send\0{}\0{}",
+            nonce,
+            room_id,
+            sha256_hex(body.as_bytes())
+        );
+        let mac = hmac_sha1_hex(secret.as_bytes(), message.as_bytes());
+
+        format!(
+            "/_matrix/client/r0/admin/rooms/{}/batch_send?nonce={}&mac={}",
+            room_id,
+            nonce,
+            mac
+        )
+    }
+
+    /// A batch of messages sent by the user at the given times.
+    fn message_batch(user: &TestUser, timestamps: &[i64]) -> String {
+        let events: Vec<String> = timestamps.iter().map(|timestamp| {
+            format!(
+                r#"{{
+                    "type": "m.room.message",
+                    "content": {{"body": "Imported", "msgtype": "m.text"}},
+                    "sender": "{}",
+                    "origin_server_ts": {}
+                }}"#,
+                user.id,
+                timestamp
+            )
+        }).collect();
+
+        format!(r#"{{"events": [{}]}}"#, events.join(", "))
+    }
+
+    /// The number of message events in the room.
+    fn count_messages(test: &Test, room_id: &str) -> usize {
+        let room_id = RoomId::try_from(room_id).unwrap();
+
+        test.with_connection(|connection| {
+            Event::find_room_events(connection, &room_id, 0).unwrap()
+                .iter()
+                .filter(|event| event.event_type == "m.room.message")
+                .count()
+        })
+    }
+
+    fn event_id(event: &Value) -> String {
+        event.get("event_id").unwrap().as_str().unwrap().to_string()
+    }
+
+    /// The current state and the timeline of a room as seen by the user, sorted by event ID and
+    /// without the fields that depend on the request, see `stable_fields`.
+    fn room_contents(test: &Test, user: &TestUser, room_id: &str) -> (Vec<Value>, Vec<Value>) {
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            user.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let mut state = response.json().as_array().unwrap().clone();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&user.token, options);
+        let mut timeline = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+
+        state.sort_by_key(event_id);
+        timeline.sort_by_key(event_id);
+
+        (stable_fields(state), stable_fields(timeline))
+    }
+
+    /// Remove the fields of events that depend on the time or the token of the request, so
+    /// events can be compared across servers: the *age*, and the *transaction_id* that is only
+    /// shown to the token that sent the event.
+    fn stable_fields(events: Vec<Value>) -> Vec<Value> {
+        events.into_iter().map(|mut event| {
+            let is_empty = match event.get_mut("unsigned").and_then(Value::as_object_mut) {
+                Some(unsigned) => {
+                    unsigned.remove("age");
+                    unsigned.remove("transaction_id");
+                    unsigned.is_empty()
+                }
+                None => false,
+            };
+
+            if is_empty {
+                event.as_object_mut().unwrap().remove("unsigned");
+            }
+
+            event
+        }).collect()
+    }
+
+    #[test]
+    fn export_and_import_room() {
+        let (username, room_id, export, contents) = {
+            let test = Test::new();
+            let alice = test.create_user();
+            let room_id = test.create_room_with_params(
+                &alice.token,
+                r#"{"room_alias_name": "exported"}"#,
+            );
+
+            test.send_message(&alice.token, &room_id, "Hello", 1);
+            test.send_state_event(
+                &alice.token,
+                &room_id,
+                "m.room.topic",
+                r#"{"topic": "Export"}"#,
+                None,
+            );
+            test.send_message(&alice.token, &room_id, "Goodbye", 2);
+
+            let account_data_path = format!(
+                "/_matrix/client/r0/user/{}/rooms/{}/account_data/org.example?access_token={}",
+                alice.id, room_id, alice.token
+            );
+            let response = test.put(&account_data_path, r#"{"color": "yellow"}"#);
+            test.check_empty_response(response);
+
+            let response = test.get(&export_path(&test, &room_id, REGISTRATION_SHARED_SECRET));
+
+            assert_eq!(response.status, Status::Ok);
+
+            let contents = room_contents(&test, &alice, &room_id);
+
+            (alice.name.clone(), room_id, response.json().clone(), contents)
+        };
+
+        // The first test server has been dropped, so this one starts with an empty database.
+        let test = Test::new();
+        let response = test.register_user(
+            &format!(r#"{{"username": "{}", "password": "secret"}}"#, username)
+        );
+        let token = response.json().get("access_token").unwrap().as_str().unwrap().to_string();
+        let user_id = response.json().get("user_id").unwrap().as_str().unwrap().to_string();
+        let alice = TestUser { id: user_id, token: token, name: username };
+
+        let room_export: RoomExport = from_value(export.clone()).unwrap();
+
+        test.with_connection(|connection| {
+            Room::import(connection, &room_export).unwrap();
+        });
+
+        assert_eq!(room_contents(&test, &alice, &room_id), contents);
+
+        let response = test.get(&export_path(&test, &room_id, REGISTRATION_SHARED_SECRET));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json(), &export);
+
+        let response = test.get_room_by_alias("#exported:ruma.test");
+
+        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+
+        test.with_connection(|connection| {
+            assert!(Room::import(connection, &room_export).is_err());
+        });
+    }
+
+    #[test]
+    fn import_room_with_floats_in_event_content() {
+        let (username, mut room_export) = {
+            let test = Test::new();
+            let alice = test.create_user();
+            let room_id = test.create_room(&alice.token);
+
+            test.send_message(&alice.token, &room_id, "Legacy", 1);
+
+            let response = test.get(&export_path(&test, &room_id, REGISTRATION_SHARED_SECRET));
+
+            assert_eq!(response.status, Status::Ok);
+
+            (alice.name.clone(), from_value::<RoomExport>(response.json().clone()).unwrap())
+        };
+
+        // Events from before canonical JSON was enforced can have floats in their content.
+        let event_id = {
+            let event = room_export.events.iter_mut()
+                .find(|event| event.event_type == "m.room.message")
+                .unwrap();
+
+            event.content = r#"{"body": "Legacy", "msgtype": "m.text", "ratio": 1.5}"#.to_string();
+            event.content_hash = None;
+            event.id.clone()
+        };
+
+        let test = Test::new();
+        test.register_user(&format!(r#"{{"username": "{}", "password": "secret"}}"#, username));
+
+        test.with_connection(|connection| {
+            Room::import(connection, &room_export).unwrap();
+
+            let event = Event::find(connection, &event_id).unwrap().unwrap();
+
+            assert!(event.has_intact_content().unwrap());
+        });
+    }
+
+    #[test]
+    fn import_room_keeps_timestamps_and_redactions() {
+        let (username, mut room_export) = {
+            let test = Test::new();
+            let alice = test.create_user();
+            let room_id = test.create_room(&alice.token);
+
+            test.send_message(&alice.token, &room_id, "Erased", 1);
+
+            let response = test.get(&export_path(&test, &room_id, REGISTRATION_SHARED_SECRET));
+
+            assert_eq!(response.status, Status::Ok);
+
+            (alice.name.clone(), from_value::<RoomExport>(response.json().clone()).unwrap())
+        };
+
+        // The message was erased in 2017, long before the import.
+        let event_id = {
+            let event = room_export.events.iter_mut()
+                .find(|event| event.event_type == "m.room.message")
+                .unwrap();
+
+            event.content = "{}".to_string();
+            event.content_hash = Some(content_hash("{}").unwrap());
+            event.redacted = true;
+            event.origin_server_ts = Some(1_500_000_000_000);
+            event.id.clone()
+        };
+
+        let test = Test::new();
+        test.register_user(&format!(r#"{{"username": "{}", "password": "secret"}}"#, username));
+
+        test.with_connection(|connection| {
+            Room::import(connection, &room_export).unwrap();
+
+            let event = Event::find(connection, &event_id).unwrap().unwrap();
+
+            assert!(event.redacted);
+            assert!(event.has_intact_content().unwrap());
+            assert_eq!(event.origin_server_ts(), 1_500_000_000_000);
+        });
+    }
+
+    #[test]
+    fn get_monthly_active_users() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), b"monthly_active_users");
+        let path = format!("/_matrix/client/r0/admin/monthly_active_users?mac={}", mac);
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("count").unwrap().as_i64().unwrap(), 0);
+
+        test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", alice.token));
+
+        let response = test.get(&path);
+
+        assert_eq!(response.json().get("count").unwrap().as_i64().unwrap(), 1);
+    }
+
+    #[test]
+    fn export_room_with_invalid_mac() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&export_path(&test, &room_id, "not_the_shared_secret"));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn export_room_cannot_be_replayed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let path = export_path(&test, &room_id, REGISTRATION_SHARED_SECRET);
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn register_with_shared_secret() {
+        let test = Test::new();
+        let nonce = get_nonce(&test);
+
+        let body = register_body(&nonce, "carl", "secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("access_token").is_some());
+        assert_eq!(response.json().get("home_server").unwrap().as_str().unwrap(), "ruma.test");
+        assert_eq!(response.json().get("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+
+        let login_body = r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#;
+        let response = test.post("/_matrix/client/r0/login", login_body);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn register_server_admin_with_shared_secret() {
+        let test = Test::new();
+        let nonce = get_nonce(&test);
+
+        let body = admin_register_body(&nonce, "carl", "secret", REGISTRATION_SHARED_SECRET, true);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let user = test.with_connection(|connection| {
+            User::find_registered_user(connection, &user_id).unwrap().unwrap()
+        });
+
+        assert!(user.admin);
+    }
+
+    #[test]
+    fn register_existing_user_with_shared_secret() {
+        let test = Test::new();
+        let nonce = get_nonce(&test);
+
+        let body = register_body(&nonce, "carl", "secret", REGISTRATION_SHARED_SECRET);
+
+        assert_eq!(test.post("/_matrix/client/r0/admin/register", &body).status, Status::Ok);
+
+        let nonce = get_nonce(&test);
+        let body = register_body(&nonce, "carl", "other_secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
+    }
+
+    #[test]
+    fn register_with_invalid_mac() {
+        let test = Test::new();
+        let nonce = get_nonce(&test);
+
+        let body = register_body(&nonce, "carl", "secret", "not_the_shared_secret");
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn nonce_cannot_be_reused() {
+        let test = Test::new();
+        let nonce = get_nonce(&test);
+
+        let body = register_body(&nonce, "carl", "secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let body = register_body(&nonce, "alice", "secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn expired_nonces_are_rejected_and_deleted() {
+        let test = Test::new();
+        let expired_nonce = get_nonce(&test);
+
+        advance_clock(60_001);
+
+        let nonce = get_nonce(&test);
+
+        test.with_connection(|connection| {
+            assert_eq!(RegistrationNonce::delete_expired(connection).unwrap(), 1);
+        });
+
+        let body = register_body(&expired_nonce, "carl", "secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let body = register_body(&nonce, "carl", "secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn unknown_nonce() {
+        let test = Test::new();
+
+        let body = register_body("deadbeef", "carl", "secret", REGISTRATION_SHARED_SECRET);
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn get_server_version() {
+        let test = Test::new();
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), b"server_version");
+
+        let response = test.get(&format!("/_matrix/client/r0/admin/server_version?mac={}", mac));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("server_version").unwrap().as_str().unwrap(),
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(!response.json().get("git_commit").unwrap().as_str().unwrap().is_empty());
+
+        let mac = hmac_sha1_hex(b"not_the_shared_secret", b"server_version");
+        let response = test.get(&format!("/_matrix/client/r0/admin/server_version?mac={}", mac));
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(test.get("/_matrix/client/r0/admin/server_version").status, Status::BadRequest);
+    }
+
+    #[test]
+    fn batch_send_keeps_timestamps() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        // 2010-01-01 and one second later.
+        let body = message_batch(&alice, &[1_262_304_000_000, 1_262_304_001_000]);
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let event_ids: Vec<EventId> = response.json().get("event_ids").unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event_id| EventId::try_from(event_id.as_str().unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(event_ids.len(), 2);
+
+        let (first, second) = test.with_connection(|connection| {
+            (
+                Event::find(connection, &event_ids[0]).unwrap().unwrap(),
+                Event::find(connection, &event_ids[1]).unwrap().unwrap(),
+            )
+        });
+
+        assert_eq!(second.ordering, first.ordering + 1);
+        assert_eq!(first.user_id.to_string(), alice.id);
+        // Microseconds since 2000-01-01.
+        assert_eq!(first.created_at.0, 315_619_200_000_000);
+        assert_eq!(second.created_at.0, 315_619_201_000_000);
+    }
+
+    #[test]
+    fn invalid_event_rolls_back_batch() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let body = format!(
+            r#"{{"events": [
+                {{
+                    "type": "m.room.message",
+                    "content": {{"body": "Imported", "msgtype": "m.text"}},
+                    "sender": "{}",
+                    "origin_server_ts": 1262304000000
+                }},
+                {{
+                    "type": "m.room.topic",
+                    "content": {{"topic": "Not a message event"}},
+                    "sender": "{}",
+                    "origin_server_ts": 1262304001000
+                }}
+            ]}}"#,
+            alice.id,
+            alice.id
+        );
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(count_messages(&test, &room_id), 0);
+
+        // Events must not be dated in the future.
+        let body = message_batch(&alice, &[1_262_304_000_000, 32_503_680_000_000]);
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(count_messages(&test, &room_id), 0);
+    }
+
+    #[test]
+    fn batch_send_rejects_denied_event_types() {
+        let test = Test::with_config(|config| {
+            config.denied_event_types = vec!["m.room.message".to_string()];
+        });
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let body = message_batch(&alice, &[1_262_304_000_000]);
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+        assert_eq!(count_messages(&test, &room_id), 0);
+    }
+
+    #[test]
+    fn batch_send_with_invalid_mac() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let body = message_batch(&alice, &[1_262_304_000_000]);
+
+        let path = batch_send_path(&test, &room_id, &body, "not_the_shared_secret");
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        // The MAC of a room export does not authorize sending events.
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), room_id.as_bytes());
+        let path = format!("/_matrix/client/r0/admin/rooms/{}/batch_send?mac={}", room_id, mac);
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::BadRequest);
+
+        // The MAC covers the body.
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+        let response = test.post(&path, &message_batch(&alice, &[1_262_304_001_000]));
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(count_messages(&test, &room_id), 0);
+    }
+
+    #[test]
+    fn batch_send_cannot_be_replayed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let body = message_batch(&alice, &[1_262_304_000_000]);
+
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+
+        assert_eq!(test.post(&path, &body).status, Status::Ok);
+        assert_eq!(test.post(&path, &body).status, Status::Forbidden);
+        assert_eq!(count_messages(&test, &room_id), 1);
+    }
+
+    #[test]
+    fn batch_send_with_timestamp_out_of_range() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let body = message_batch(&alice, &[9_223_372_036_854_775_807]);
+
+        let path = batch_send_path(&test, &room_id, &body, REGISTRATION_SHARED_SECRET);
+        let response = test.post(&path, &body);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(count_messages(&test, &room_id), 0);
+    }
+
+    #[test]
+    fn bulk_resolve_room_aliases() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "books"}"#);
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), b"bulk_resolve");
+        let path = format!("/_matrix/client/r0/admin/directory/bulk_resolve?mac={}", mac);
+
+        let response = test.post(
+            &path,
+            r##"{"aliases": ["#books:ruma.test", "#films:ruma.test", "#books:example.com"]}"##,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let aliases = response.json().get("aliases").unwrap();
+        let books = aliases.get("#books:ruma.test").unwrap();
+
+        assert_eq!(aliases.as_object().unwrap().len(), 3);
+        assert_eq!(books.get("room_id").unwrap().as_str().unwrap(), room_id);
+        assert!(books.get("servers").unwrap().is_array());
+        assert!(aliases.get("#films:ruma.test").unwrap().is_null());
+        assert!(aliases.get("#books:example.com").unwrap().is_null());
+    }
+
+    #[test]
+    fn bulk_resolve_room_aliases_with_one_query() {
+        let test = Test::new();
+        let alice = test.create_user();
+        test.create_room_with_params(&alice.token, r#"{"room_alias_name": "books"}"#);
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), b"bulk_resolve");
+        let path = format!("/_matrix/client/r0/admin/directory/bulk_resolve?mac={}", mac);
+
+        // The pool of the test server has a single connection in a single transaction, so the
+        // statistics of the transaction count the scans of the request. Without index scans, every
+        // query of the table scans it exactly once.
+        let room_alias_scans = || test.with_connection(|connection| {
+            select(sql::<BigInt>(
+                "(SELECT seq_scan FROM pg_stat_xact_user_tables WHERE relname = 'room_aliases')"
+            )).get_result::<i64>(connection).unwrap()
+        });
+
+        test.with_connection(|connection| {
+            connection.execute("SET enable_indexscan = off").unwrap();
+            connection.execute("SET enable_bitmapscan = off").unwrap();
+        });
+
+        let scans_before = room_alias_scans();
+        let response = test.post(
+            &path,
+            r##"{"aliases": ["#books:ruma.test", "#films:ruma.test", "#music:ruma.test"]}"##,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(room_alias_scans() - scans_before, 1);
+
+        let aliases = response.json().get("aliases").unwrap();
+
+        assert!(aliases.get("#books:ruma.test").unwrap().is_object());
+        assert!(aliases.get("#films:ruma.test").unwrap().is_null());
+        assert!(aliases.get("#music:ruma.test").unwrap().is_null());
+    }
+
+    #[test]
+    fn bulk_resolve_too_many_room_aliases() {
+        let test = Test::with_config(|config| config.max_bulk_resolve_aliases = 1);
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), b"bulk_resolve");
+        let path = format!("/_matrix/client/r0/admin/directory/bulk_resolve?mac={}", mac);
+
+        let body = r##"{"aliases": ["#books:ruma.test", "#films:ruma.test"]}"##;
+        let response = test.post(&path, body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn bulk_resolve_room_aliases_with_invalid_mac() {
+        let test = Test::new();
+        let mac = hmac_sha1_hex(b"not_the_shared_secret", b"bulk_resolve");
+        let path = format!("/_matrix/client/r0/admin/directory/bulk_resolve?mac={}", mac);
+
+        let response = test.post(&path, r##"{"aliases": ["#books:ruma.test"]}"##);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn pusher_delivery_stats_record_failures_and_recovery() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.ruma.test/_matrix/push/v1/notify".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "io.ruma.test".to_string(),
+            profile_tag: None,
+            pushkey: "pushkey".to_string(),
+            app_display_name: "Ruma Test".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&bob.token, options).status, Status::Ok);
+
+        let stats = delivery_stats(&test, &bob);
+
+        assert_eq!(stats.get("consecutive_failures").unwrap().as_i64().unwrap(), 0);
+        assert!(stats.get("last_failure_ts").unwrap().is_null());
+
+        // The gateway fails, so the pusher backs off.
+        set_gateway_status(500);
+        test.send_call_invite(&alice.token, &room_id, 1);
+        test.deliver_push_notifications();
+
+        let stats = delivery_stats(&test, &bob);
+        let failed_at = stats.get("last_failure_ts").unwrap().as_i64().unwrap();
+
+        assert_eq!(stats.get("consecutive_failures").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(stats.get("last_failure_status").unwrap().as_i64().unwrap(), 500);
+        assert!(stats.get("last_failure_reason").unwrap().is_string());
+        assert_eq!(stats.get("backoff_until_ts").unwrap().as_i64().unwrap(), failed_at + 30_000);
+        assert!(stats.get("last_success_ts").unwrap().is_null());
+
+        // Nothing is sent during the backoff, but the notification stays queued.
+        test.send_call_invite(&alice.token, &room_id, 2);
+        test.deliver_push_notifications();
+
+        assert_eq!(sent_notifications().len(), 1);
+        assert_eq!(test.deliver_push_notifications(), 0);
+        let stats = delivery_stats(&test, &bob);
+
+        assert_eq!(stats.get("consecutive_failures").unwrap().as_i64().unwrap(), 1);
+
+        // The queued notification is sent after the backoff, and another failure doubles it.
+        advance_clock(31_000);
+        test.send_call_invite(&alice.token, &room_id, 3);
+        test.deliver_push_notifications();
+
+        let stats = delivery_stats(&test, &bob);
+        let failed_at = stats.get("last_failure_ts").unwrap().as_i64().unwrap();
+
+        assert_eq!(sent_notifications().len(), 2);
+        assert_eq!(stats.get("consecutive_failures").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(stats.get("backoff_until_ts").unwrap().as_i64().unwrap(), failed_at + 60_000);
+
+        // The gateway recovers, which resets the failures but keeps the last one for debugging.
+        set_gateway_status(200);
+        advance_clock(61_000);
+        test.send_call_invite(&alice.token, &room_id, 4);
+        test.deliver_push_notifications();
+
+        let stats = delivery_stats(&test, &bob);
+        let notifications = sent_notifications();
+        let mut event_ids: Vec<&str> = notifications.iter()
+            .map(|&(_, ref body)| body.pointer("/notification/event_id").unwrap().as_str().unwrap())
+            .collect();
+
+        event_ids.sort();
+        event_ids.dedup();
+
+        // Every invite was attempted once, including the ones sent during a backoff.
+        assert_eq!(event_ids.len(), 4);
+        assert_eq!(stats.get("consecutive_failures").unwrap().as_i64().unwrap(), 0);
+        assert!(stats.get("last_success_ts").unwrap().as_i64().unwrap() > failed_at);
+        assert!(stats.get("backoff_until_ts").unwrap().is_null());
+        assert_eq!(stats.get("last_failure_status").unwrap().as_i64().unwrap(), 500);
+    }
+
+    #[test]
+    fn pusher_delivery_stats_survive_updates() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.ruma.test/_matrix/push/v1/notify".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "io.ruma.test".to_string(),
+            profile_tag: None,
+            pushkey: "pushkey".to_string(),
+            app_display_name: "Ruma Test".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&bob.token, options.clone()).status, Status::Ok);
+
+        set_gateway_status(500);
+        test.send_call_invite(&alice.token, &room_id, 1);
+        test.deliver_push_notifications();
+
+        let stats = delivery_stats(&test, &bob);
+        let failed_at = stats.get("last_failure_ts").unwrap().as_i64().unwrap();
+
+        // Clients register their pushers again on every start.
+        let mut renamed = options.clone();
+        renamed.device_display_name = "tablet".to_string();
+
+        assert_eq!(test.set_pusher(&bob.token, renamed).status, Status::Ok);
+
+        let stats = delivery_stats(&test, &bob);
+
+        assert_eq!(stats.get("consecutive_failures").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(stats.get("last_failure_ts").unwrap().as_i64().unwrap(), failed_at);
+        assert_eq!(stats.get("backoff_until_ts").unwrap().as_i64().unwrap(), failed_at + 30_000);
+    }
+
+    #[test]
+    fn get_user_pushers_with_invalid_mac() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&pushers_path(&carl.id, "not_the_shared_secret"));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn get_user_data_export() {
+        let test = Test::new();
+        let (bob, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        test.send_message(&bob.token, &room_id, "Hi", 1);
+        test.send_message(&bob.token, &room_id, "Anyone?", 2);
+        test.bind_email(&bob, "bob@ruma.test");
+        test.update_presence(&bob.token, &bob.id, r#"{"presence": "online"}"#);
+
+        let displayname_path =
+            format!("/_matrix/client/r0/profile/{}/displayname?access_token={}", bob.id, bob.token);
+
+        assert_eq!(test.put(&displayname_path, r#"{"displayname": "Bob"}"#).status, Status::Ok);
+
+        let account_data_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/org.example.settings?access_token={}",
+            bob.id,
+            bob.token
+        );
+
+        assert_eq!(test.put(&account_data_path, r#"{"theme": "dark"}"#).status, Status::Ok);
+
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.ruma.test/_matrix/push/v1/notify".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "io.ruma.test".to_string(),
+            profile_tag: None,
+            pushkey: "pushkey".to_string(),
+            app_display_name: "Ruma Test".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&bob.token, options).status, Status::Ok);
+
+        let response = test.get(&data_export_path(&test, &bob.id, REGISTRATION_SHARED_SECRET, ""));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let account = response.json().get("account").unwrap();
+
+        assert_eq!(account.get("user_id").unwrap().as_str().unwrap(), bob.id);
+        assert_eq!(
+            account.get("profile").unwrap().get("displayname").unwrap().as_str().unwrap(),
+            "Bob"
+        );
+        assert_eq!(
+            account.get("presence").unwrap().get("presence").unwrap().as_str().unwrap(),
+            "online"
+        );
+
+        for section in &[
+            "threepids",
+            "devices",
+            "access_tokens",
+            "account_data",
+            "room_memberships",
+            "pushers",
+        ] {
+            assert!(
+                !account.get(*section).unwrap().as_array().unwrap().is_empty(),
+                "The section {} should not be empty",
+                section
+            );
+        }
+
+        assert!(account.get("access_tokens").unwrap()[0].get("token_hash").is_none());
+
+        let sent_events = response.json().get("sent_events").unwrap().as_array().unwrap();
+        let messages: Vec<&Value> = sent_events.iter()
+            .filter(|event| event.get("event_type").unwrap().as_str().unwrap() == "m.room.message")
+            .collect();
+
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].get("content").is_none());
+
+        let response = test.get(
+            &data_export_path(&test, &bob.id, REGISTRATION_SHARED_SECRET, "&include_contents=true")
+        );
+        let sent_events = response.json().get("sent_events").unwrap().as_array().unwrap();
+
+        assert!(sent_events.iter().all(|event| event.get("content").is_some()));
+
+        let audit_log = test.with_connection(|connection| {
+            AuditLogEntry::find_by_target(connection, &bob.id).unwrap()
+        });
+
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].action, "user_data_export");
+    }
+
+    #[test]
+    fn get_user_data_export_cannot_be_replayed() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let path = data_export_path(&test, &carl.id, REGISTRATION_SHARED_SECRET, "");
+
+        assert_eq!(test.get(&path).status, Status::Ok);
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn get_user_data_export_with_invalid_mac() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&data_export_path(&test, &carl.id, "not_the_shared_secret", ""));
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let audit_log = test.with_connection(|connection| {
+            AuditLogEntry::find_by_target(connection, &carl.id).unwrap()
+        });
+
+        assert!(audit_log.is_empty());
+    }
+
+    #[test]
+    fn erase_deactivated_user() {
+        let test = Test::new();
+        let (bob, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let alice = test.create_user();
+
+        assert_eq!(test.join_room(&alice.token, &room_id).status, Status::Ok);
+
+        let avatar_url_path =
+            format!("/_matrix/client/r0/profile/{}/avatar_url?access_token={}", bob.id, bob.token);
+
+        assert_eq!(
+            test.put(&avatar_url_path, r#"{"avatar_url": "mxc://ruma.test/bob"}"#).status,
+            Status::Ok
+        );
+        test.send_message(&bob.token, &room_id, "My secret", 1);
+        test.bind_email(&bob, "bob@ruma.test");
+
+        // Queued copies of Bob's messages and presence, and some of Alice's that only quote him.
+        let alice_id = UserId::try_from(alice.id.as_str()).unwrap();
+        let bob_id = UserId::try_from(bob.id.as_str()).unwrap();
+        test.with_connection(|connection| {
+            let secret = from_str::<Value>(r#"{"body": "My secret"}"#).unwrap();
+            let quote = from_str::<Value>(&format!(r#"{{"body": "{} said"}}"#, bob.id)).unwrap();
+
+            ToDeviceMessage::create(connection, &alice_id, "A", &bob_id, "m.secret", &secret)
+                .unwrap();
+            ToDeviceMessage::create(connection, &alice_id, "A", &alice_id, "m.quote", &quote)
+                .unwrap();
+
+            for &(sender, content) in &[(&bob.id, &secret), (&alice.id, &quote)] {
+                let body = format!(
+                    r#"{{"notification": {{"sender": "{}", "content": {}}}}}"#,
+                    sender,
+                    content
+                );
+                QueuedPush::enqueue(connection, &alice_id, "ruma.test", &body).unwrap();
+
+                let presence = from_str::<Value>(
+                    &format!(r#"{{"push": [{{"user_id": "{}", "presence": "online"}}]}}"#, sender)
+                ).unwrap();
+                let destinations = vec!["remote.test".to_string()];
+                FederationQueueEntry::enqueue_edus(
+                    connection,
+                    &destinations,
+                    PRESENCE_EDU_TYPE,
+                    sender,
+                    &presence
+                ).unwrap();
+            }
+        });
+
+        let deactivate_path =
+            format!("/_matrix/client/r0/account/deactivate?access_token={}", bob.token);
+
+        test.check_empty_response(test.post(&deactivate_path, r#"{"erase": true}"#));
+
+        let path = erasure_path(&test, &bob.id, REGISTRATION_SHARED_SECRET);
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("status").unwrap().as_str().unwrap(), "pending");
+
+        // The status cannot be requested again with the same nonce.
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+
+        test.erase_users();
+
+        let response = test.get(&erasure_path(&test, &bob.id, REGISTRATION_SHARED_SECRET));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("status").unwrap().as_str().unwrap(), "done");
+        assert!(response.json().get("redacted_events").unwrap().as_i64().unwrap() > 0);
+        assert!(response.json().get("completed_ts").unwrap().as_i64().is_some());
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let timeline = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+        let bobs_events = |event_type: &str| -> Vec<Value> {
+            timeline.iter()
+                .filter(|event| event.get("sender").unwrap().as_str().unwrap() == bob.id)
+                .filter(|event| event.get("type").unwrap().as_str().unwrap() == event_type)
+                .map(|event| event.get("content").unwrap().clone())
+                .collect()
+        };
+        let messages = bobs_events("m.room.message");
+        let memberships = bobs_events("m.room.member");
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0], from_str::<Value>("{}").unwrap());
+        assert!(!memberships.is_empty());
+
+        for content in memberships {
+            assert_eq!(content, from_str::<Value>(r#"{"membership": "join"}"#).unwrap());
+        }
+
+        let profile_path =
+            format!("/_matrix/client/r0/profile/{}?access_token={}", bob.id, alice.token);
+
+        assert_eq!(test.get(&profile_path).status, Status::NotFound);
+
+        let (threepids, sessions, transactions, device_names) = test.with_connection(|connection| {
+            let sessions: Vec<String> = threepid_sessions::table
+                .select(threepid_sessions::id)
+                .filter(threepid_sessions::address.eq("bob@ruma.test"))
+                .get_results(connection)
+                .unwrap();
+            let transactions: Vec<String> = event_transactions::table
+                .select(event_transactions::transaction_id)
+                .get_results(connection)
+                .unwrap();
+            let device_names: Vec<String> = devices::table
+                .select(devices::display_name)
+                .filter(devices::user_id.eq(&bob_id))
+                .get_results(connection)
+                .unwrap();
+
+            (
+                Threepid::find_by_user(connection, &bob_id).unwrap(),
+                sessions,
+                transactions,
+                device_names,
+            )
+        });
+
+        assert!(threepids.is_empty());
+        assert!(sessions.is_empty());
+        assert!(transactions.is_empty());
+        assert!(device_names.iter().all(|display_name| display_name.is_empty()));
+
+        let queued = test.with_connection(|connection| {
+            let to_device_senders: Vec<String> = to_device_messages::table
+                .select(to_device_messages::sender)
+                .get_results(connection)
+                .unwrap();
+            let push_bodies: Vec<String> = push_queue::table
+                .select(push_queue::body)
+                .get_results(connection)
+                .unwrap();
+            let federation_payloads: Vec<String> = federation_queue::table
+                .select(federation_queue::payload)
+                .get_results(connection)
+                .unwrap();
+
+            (to_device_senders, push_bodies, federation_payloads)
+        });
+        let (to_device_senders, push_bodies, federation_payloads) = queued;
+
+        assert_eq!(to_device_senders, vec![alice.id.clone()]);
+        assert_eq!(push_bodies.len(), 1);
+        assert!(push_bodies[0].contains(&format!(r#""sender":"{}""#, alice.id)));
+        assert_eq!(federation_payloads.len(), 1);
+        assert!(federation_payloads[0].contains(&format!(r#""user_id":"{}""#, alice.id)));
+    }
+
+    #[test]
+    fn get_user_erasure_that_was_not_requested() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&erasure_path(&test, &carl.id, REGISTRATION_SHARED_SECRET));
+
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.get(&erasure_path(&test, &carl.id, "not_the_shared_secret"));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn get_membership_transitions() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.kick_from_room(&alice.token, &room_id, &bob.id, Some("Spamming")).status,
+            Status::Ok
+        );
+
+        let response = test.get(&memberships_path(&room_id, &bob.id, REGISTRATION_SHARED_SECRET));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let transitions = response.json().get("transitions").unwrap().as_array().unwrap().clone();
+        let memberships: Vec<&str> = transitions.iter()
+            .map(|transition| transition.get("membership").unwrap().as_str().unwrap())
+            .collect();
+        let reasons: Vec<Option<&str>> = transitions.iter()
+            .map(|transition| transition.get("reason").unwrap().as_str())
+            .collect();
+
+        assert_eq!(memberships, vec!["invite", "join", "leave"]);
+        assert_eq!(reasons, vec![None, None, Some("Spamming")]);
+        assert!(transitions.iter().all(|transition| {
+            transition.get("origin_server_ts").unwrap().as_i64().is_some()
+        }));
+
+        let response = test.get(&memberships_path(&room_id, &bob.id, "not_the_shared_secret"));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn membership_transitions_are_found_by_state_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        // A ban sent by Alice, like one received over federation.
+        let content = r#"{"membership": "ban", "reason": "Spamming"}"#.to_string();
+        let ban = NewEvent {
+            content_hash: content_hash(&content).unwrap(),
+            content: content,
+            event_type: "m.room.member".to_string(),
+            extra_content: None,
+            id: EventId::new("ruma.test").unwrap(),
+            room_id: RoomId::try_from(room_id.as_str()).unwrap(),
+            state_key: Some(bob.id.clone()),
+            user_id: UserId::try_from(alice.id.as_str()).unwrap(),
+        };
+
+        test.with_connection(|connection| {
+            insert(&ban).into(events::table).execute(connection).unwrap();
+        });
+
+        let memberships = |user_id: &str| -> Vec<String> {
+            let path = memberships_path(&room_id, user_id, REGISTRATION_SHARED_SECRET);
+
+            test.get(&path).json().get("transitions").unwrap().as_array().unwrap().iter()
+                .map(|transition| transition["membership"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(memberships(&bob.id), vec!["join", "ban"]);
+        assert_eq!(memberships(&alice.id), vec!["join"]);
+    }
+}
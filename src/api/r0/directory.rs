@@ -1,17 +1,30 @@
-//! Endpoints for managing room aliases.
+//! Endpoints for managing room aliases and the rooms published in the room directory.
+
+use std::error::Error;
 
 use bodyparser;
+use diesel::pg::PgConnection;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use iron::status::Status;
-use ruma_identifiers::RoomId;
+use router::Router;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomAliasId, RoomId};
+use url::percent_encoding::percent_decode;
 
+use appservice::AppServiceRegistration;
 use config::Config;
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam};
+use history_visibility::is_world_readable;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam, RoomIdParam};
+use models::room::{Room, RoomVisibility};
 use models::room_alias::{RoomAlias, NewRoomAlias};
+use models::room_membership::RoomMembership;
 use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use spam::{CompositeSpamChecker, SpamChecker};
+use util::appservice::verify_alias_allowed;
+use util::room_alias::parse_local_room_alias;
 
 /// The GET `/directory/room/:room_alias` endpoint.
 pub struct GetRoomAlias;
@@ -44,6 +57,58 @@ impl Handler for GetRoomAlias {
     }
 }
 
+/// The GET `/rooms/:room_id/aliases` endpoint.
+///
+/// Lists all aliases on this server that point to the room, unlike the *m.room.canonical_alias*
+/// state event. Users who are not members of the room can only list the aliases of public and
+/// world-readable rooms.
+pub struct GetRoomAliases;
+
+#[derive(Debug, Serialize)]
+struct GetRoomAliasesResponse {
+    /// The aliases pointing to the room.
+    aliases: Vec<RoomAliasId>,
+}
+
+middleware_chain!(GetRoomAliases, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRoomAliases {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found("The room was not found on this server".to_string()))?,
+        };
+
+        let is_member = RoomMembership::find(&connection, &room.id, &user.id)?
+            .map_or(false, |membership| membership.membership == "join");
+
+        let is_visible = room.public || is_world_readable(&connection, &room.id)?;
+
+        if !is_member && !is_visible {
+            Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?;
+        }
+
+        let aliases = RoomAlias::find_by_room_id(&connection, &room.id)?
+            .into_iter()
+            .map(|room_alias| room_alias.alias)
+            .collect();
+
+        let response = GetRoomAliasesResponse {
+            aliases: aliases,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// The DELETE `/directory/room/:room_alias` endpoint.
 pub struct DeleteRoomAlias;
 
@@ -72,6 +137,11 @@ impl Handler for DeleteRoomAlias {
 }
 
 /// The PUT `/directory/room/:room_alias` endpoint.
+///
+/// Unlike the other endpoints, it requires a full room alias on this server, so no aliases are
+/// stored that could never be resolved. Aliases in the exclusive namespaces of application
+/// services can only be created by them, and aliases matching `reserved_alias_patterns` only by
+/// the server administrators.
 pub struct PutRoomAlias;
 
 #[derive(Clone, Debug, Deserialize)]
@@ -80,23 +150,40 @@ struct PutRoomAliasRequest {
     pub room_id: RoomId,
 }
 
-middleware_chain!(PutRoomAlias, [JsonRequest, RoomAliasIdParam, AccessTokenAuth]);
+middleware_chain!(PutRoomAlias, [JsonRequest, AccessTokenAuth]);
 
 impl Handler for PutRoomAlias {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
         let config = Config::from_request(request)?;
 
-        let room_alias_id = request.extensions.get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId").clone();
+        let room_alias = request.extensions.get::<Router>()
+            .expect("Params object is missing")
+            .find("room_alias")
+            .ok_or_else(|| ApiError::missing_param("room_alias"))?
+            .to_string();
+
+        let decoded_room_alias = percent_decode(room_alias.as_bytes())
+            .decode_utf8()
+            .map_err(|err| ApiError::invalid_param("room_alias", err.description()))?;
+
+        let room_alias_id =
+            parse_local_room_alias(&decoded_room_alias, &config.domain, "room_alias")?;
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        verify_alias_allowed(
+            &config,
+            &room_alias_id.to_string(),
+            &user,
+            request.extensions.get::<AppServiceRegistration>(),
+        )?;
 
         let room_id = match request.get::<bodyparser::Struct<PutRoomAliasRequest>>() {
             Ok(Some(req)) => req.room_id,
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
-
         let connection = DB::from_request(request)?;
 
         let new_room_alias = NewRoomAlias {
@@ -112,8 +199,120 @@ impl Handler for PutRoomAlias {
     }
 }
 
+/// The GET `/directory/list/room/:room_id` endpoint.
+pub struct GetRoomVisibility;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RoomVisibilityBody {
+    /// Whether the room is published in the room directory.
+    visibility: RoomVisibility,
+}
+
+middleware_chain!(GetRoomVisibility, [RoomIdParam]);
+
+impl Handler for GetRoomVisibility {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found("The room was not found on this server".to_string()))?,
+        };
+
+        let response = RoomVisibilityBody {
+            visibility: if room.public { RoomVisibility::Public } else { RoomVisibility::Private },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/directory/list/room/:room_id` endpoint.
+///
+/// Publishes a room in the room directory or removes it from there. Only members who may set the
+/// room's *m.room.canonical_alias* and the server administrators can do that, and publishing is
+/// subject to the spam checkers like creating a public room.
+pub struct PutRoomVisibility;
+
+middleware_chain!(PutRoomVisibility, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for PutRoomVisibility {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let visibility = match request.get::<bodyparser::Struct<RoomVisibilityBody>>() {
+            Ok(Some(body)) => body.visibility,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId").clone();
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found("The room was not found on this server".to_string()))?,
+        };
+
+        if !user.is_server_admin(&config) {
+            verify_may_publish(&connection, &room, &user)?;
+        }
+
+        if visibility == RoomVisibility::Public {
+            let spam_checker = CompositeSpamChecker::from_request(request)?;
+
+            spam_checker.user_may_publish_room(&user.id, &room.id).ensure_allowed(&config)?;
+        }
+
+        Room::set_public(&connection, &room.id, visibility == RoomVisibility::Public)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// Check that a user may change whether a room is published: the user must have joined it and
+/// have the power level needed to set its *m.room.canonical_alias*.
+fn verify_may_publish(connection: &PgConnection, room: &Room, user: &User)
+-> Result<(), ApiError> {
+    let is_member = RoomMembership::find(connection, &room.id, &user.id)?
+        .map_or(false, |membership| membership.membership == "join");
+
+    if !is_member {
+        return Err(ApiError::unauthorized("The user is not a member of the room".to_string()));
+    }
+
+    let power_levels = room.current_power_levels(connection)?;
+    let user_power_level = power_levels
+        .users
+        .get(&user.id)
+        .unwrap_or(&power_levels.users_default);
+    let required_power_level = power_levels
+        .events
+        .get(&EventType::RoomCanonicalAlias)
+        .unwrap_or(&power_levels.state_default);
+
+    if required_power_level > user_power_level {
+        return Err(ApiError::unauthorized(
+            "Insufficient power level to publish the room in the directory.".to_string()
+        ));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::UserId;
+    use serde_json::from_str;
+
     use test::Test;
     use iron::status::Status;
 
@@ -207,7 +406,7 @@ mod tests {
         let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
 
         let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}", carl.token
+            "/_matrix/client/r0/directory/room/%23my_room:ruma.test?access_token={}", carl.token
         );
         let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
         let response = test.put(&put_room_alias_path, &put_room_alias_body);
@@ -226,7 +425,8 @@ mod tests {
         let user = test.create_user();
 
         let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}", user.token
+            "/_matrix/client/r0/directory/room/%23my_room:ruma.test?access_token={}",
+            user.token
         );
         let put_room_alias_body = r#"{"room_id": "!nonexistent:ruma.test"}"#;
         let response = test.put(&put_room_alias_path, &put_room_alias_body);
@@ -245,7 +445,8 @@ mod tests {
         let room_id = response.json().get("room_id").unwrap().as_str().unwrap();
 
         let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/my_room?access_token={}", user.token
+            "/_matrix/client/r0/directory/room/%23my_room:ruma.test?access_token={}",
+            user.token
         );
         let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
         let response = test.put(&put_room_alias_path, &put_room_alias_body);
@@ -256,4 +457,247 @@ mod tests {
             "IO_RUMA_ALIAS_TAKEN"
         );
     }
+
+    #[test]
+    fn put_room_alias_without_hash() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/my_room:ruma.test?access_token={}", carl.token
+        );
+        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn put_room_alias_on_foreign_domain() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23my_room:example.com?access_token={}",
+            carl.token
+        );
+        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+
+        let response = test.get("/_matrix/client/r0/directory/room/%23my_room:example.com");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn put_room_alias_in_exclusive_namespace() {
+        let test = Test::with_config(|config| {
+            config.app_services.push(from_str(r#"{
+                "id": "irc",
+                "as_token": "as_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {"aliases": [{"exclusive": true, "regex": "#irc_.*:ruma\\.test"}]}
+            }"#).unwrap());
+        });
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23irc_ruma:ruma.test?access_token={}",
+            carl.token
+        );
+        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_bot", "password": "secret"}"#
+        ).status.is_success());
+
+        let put_room_alias_path =
+            "/_matrix/client/r0/directory/room/%23irc_ruma:ruma.test?access_token=as_secret";
+        let response = test.put(put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn put_room_alias_matching_reserved_pattern() {
+        let test = Test::with_config(|config| {
+            config.reserved_alias_patterns.push("#admin-.*:ruma\\.test".to_string());
+            config.server_admins.push(UserId::try_from("@alice:ruma.test").unwrap());
+        });
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23admin-help:ruma.test?access_token={}",
+            carl.token
+        );
+        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+
+        let response = test.register_user(r#"{"username": "alice", "password": "secret"}"#);
+        let alice_token = response.json().get("access_token").unwrap().as_str().unwrap();
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23admin-help:ruma.test?access_token={}",
+            alice_token
+        );
+        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn put_room_alias_with_illegal_characters() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23my%20room:ruma.test?access_token={}",
+            carl.token
+        );
+        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+        let response = test.put(&put_room_alias_path, &put_room_alias_body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn get_room_aliases() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_room_with_params(
+            &carl.token,
+            r#"{"room_alias_name": "my_room", "visibility": "private"}"#,
+        );
+
+        let put_room_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23other_room:ruma.test?access_token={}",
+            carl.token
+        );
+        let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
+
+        assert_eq!(test.put(&put_room_alias_path, &put_room_alias_body).status, Status::Ok);
+
+        let aliases_path = format!(
+            "/_matrix/client/r0/rooms/{}/aliases?access_token={}",
+            room_id,
+            carl.token
+        );
+        let response = test.get(&aliases_path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let mut aliases: Vec<&str> = response.json().get("aliases").unwrap()
+            .as_array().unwrap()
+            .iter()
+            .map(|alias| alias.as_str().unwrap())
+            .collect();
+        aliases.sort();
+
+        assert_eq!(aliases, vec!["#my_room:ruma.test", "#other_room:ruma.test"]);
+    }
+
+    #[test]
+    fn get_room_aliases_of_private_room_as_non_member() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let henry = test.create_user();
+        let room_id = test.create_room_with_params(
+            &carl.token,
+            r#"{"room_alias_name": "my_room", "visibility": "private"}"#,
+        );
+
+        let aliases_path = format!(
+            "/_matrix/client/r0/rooms/{}/aliases?access_token={}",
+            room_id,
+            henry.token
+        );
+        let response = test.get(&aliases_path);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn publish_room_in_directory() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let visibility_path = format!("/_matrix/client/r0/directory/list/room/{}", room_id);
+        let response = test.get(&visibility_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("visibility").unwrap().as_str().unwrap(), "private");
+
+        let response = test.put(
+            &format!("{}?access_token={}", visibility_path, carl.token),
+            r#"{"visibility": "public"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&visibility_path);
+
+        assert_eq!(response.json().get("visibility").unwrap().as_str().unwrap(), "public");
+    }
+
+    #[test]
+    fn publishing_requires_the_power_level_of_canonical_aliases() {
+        let test = Test::with_config(|config| config.default_power_levels.state_default = 50);
+        let carl = test.create_user();
+        let henry = test.create_user();
+        let outsider = test.create_user();
+        let room_id = test.create_public_room(&carl.token);
+
+        assert_eq!(test.join_room(&henry.token, &room_id).status, Status::Ok);
+
+        let visibility_path = format!("/_matrix/client/r0/directory/list/room/{}", room_id);
+
+        for user in &[&henry, &outsider] {
+            let response = test.put(
+                &format!("{}?access_token={}", visibility_path, user.token),
+                r#"{"visibility": "private"}"#,
+            );
+
+            assert_eq!(response.status, Status::Forbidden);
+        }
+
+        let response = test.get(&visibility_path);
+
+        assert_eq!(response.json().get("visibility").unwrap().as_str().unwrap(), "public");
+    }
+
+    #[test]
+    fn visibility_of_unknown_room() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/directory/list/room/!unknown:ruma.test");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
 }
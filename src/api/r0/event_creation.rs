@@ -3,32 +3,27 @@
 use std::convert::TryInto;
 
 use bodyparser;
-use diesel::{Connection, ExecuteDsl, insert};
+use diesel::{LoadDsl, insert};
 use diesel::pg::PgConnection;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response, status};
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response, status};
 use router::Router;
-use ruma_events::call::answer::AnswerEvent;
-use ruma_events::call::candidates::CandidatesEvent;
-use ruma_events::call::hangup::HangupEvent;
-use ruma_events::call::invite::InviteEvent;
-use ruma_events::room::avatar::AvatarEvent;
-use ruma_events::room::canonical_alias::CanonicalAliasEvent;
-use ruma_events::room::guest_access::GuestAccessEvent;
-use ruma_events::room::history_visibility::HistoryVisibilityEvent;
-use ruma_events::room::join_rules::JoinRulesEvent;
-use ruma_events::room::message::MessageEvent;
-use ruma_events::room::name::NameEvent;
-use ruma_events::room::power_levels::PowerLevelsEvent;
-use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
-use ruma_events::room::topic::TopicEvent;
-use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
-use ruma_identifiers::{RoomId, EventId};
-use serde::Deserialize;
+use ruma_events::{CustomStateEvent, EventType};
+use ruma_events::room::power_levels::PowerLevelsEventContent;
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
 use serde_json::{Value, from_str, from_value, to_string};
+use url::Url;
 
+use appservice::AppServiceRegistration;
 use db::DB;
 use config::Config;
 use error::{ApiError, MapApiError};
+use event_validation::{
+    ensure_empty_state_key,
+    extract_event_content,
+    new_room_event,
+    new_state_event,
+    verify_event_type_allowed,
+};
 use middleware::{
     AccessTokenAuth,
     EventTypeParam,
@@ -37,56 +32,44 @@ use middleware::{
     RoomIdParam,
     TransactionIdParam,
 };
-use models::access_token::AccessToken;
-use models::event::NewEvent;
-use models::room::Room;
-use models::room_membership::RoomMembership;
+use models::event::{Event, NewEvent, NewImportedEvent, is_storable_timestamp};
+use models::event_relation::{EventRelation, NewEventRelation};
+use models::event_transaction::EventTransaction;
+use models::room::{PINNED_EVENTS_EVENT_TYPE, Room};
+use models::room_alias::RoomAlias;
 use models::transaction::Transaction;
 use models::user::User;
 use modifier::SerializableResponse;
+use persister::EventPersister;
+use push;
 use schema::events;
-
-macro_rules! room_event {
-    (
-        $ty:ident,
-        $event_content:ident,
-        $event_type:ident,
-        $event_id:ident,
-        $room_id:ident,
-        $user:ident
-    ) => {
-        $ty {
-            content: extract_event_content($event_content, &$event_type)?,
-            event_id: $event_id.clone(),
-            event_type: $event_type.clone(),
-            room_id: $room_id.clone(),
-            unsigned: None,
-            user_id: $user.id.clone(),
-        }.try_into().map_err(ApiError::from)?
-    };
+use server_acl::{SERVER_ACL_EVENT_TYPE, ServerAcl};
+use spam::{CompositeSpamChecker, SpamChecker};
+use util::room_state::state_key;
+
+/// The keys of an event that are always set by the server.
+const SERVER_SET_EVENT_KEYS: [&'static str; 4] = [
+    "event_id",
+    "origin_server_ts",
+    "room_id",
+    "sender",
+];
+
+/// The content of an `m.room.pinned_events` event.
+#[derive(Debug, Deserialize)]
+struct PinnedEventsContent {
+    /// The IDs of the pinned events.
+    pinned: Vec<EventId>,
 }
 
-macro_rules! state_event {
-    (
-        $ty:ident,
-        $event_content:ident,
-        $event_type:ident,
-        $event_id:ident,
-        $room_id:ident,
-        $state_key:ident,
-        $user:ident
-    ) => {
-        $ty {
-            content: extract_event_content($event_content, &$event_type)?,
-            event_id: $event_id.clone(),
-            event_type: $event_type.clone(),
-            prev_content: None,
-            room_id: $room_id.clone(),
-            state_key: $state_key.to_string(),
-            unsigned: None,
-            user_id: $user.id.clone(),
-        }.try_into().map_err(ApiError::from)?
-    };
+/// The content of an `m.room.canonical_alias` event.
+#[derive(Debug, Deserialize)]
+struct CanonicalAliasContent {
+    /// The canonical alias of the room.
+    alias: RoomAliasId,
+    /// Alternative aliases the room advertises.
+    #[serde(default)]
+    alt_aliases: Vec<RoomAliasId>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -96,6 +79,9 @@ struct EventResponse {
 }
 
 /// The `/rooms/:room_id/send/:event_type/:transaction_id` endpoint.
+///
+/// Application services can set the `origin_server_ts` of the event with the `ts` query
+/// parameter, e.g. to bridge history. The event is still appended to the end of the timeline.
 pub struct SendMessageEvent;
 
 middleware_chain!(SendMessageEvent, [JsonRequest, RoomIdParam, EventTypeParam, TransactionIdParam, AccessTokenAuth]);
@@ -108,88 +94,108 @@ impl Handler for SendMessageEvent {
         let event_type = request.extensions.get::<EventTypeParam>()
             .expect("EventTypeParam should ensure an EventType").clone();
 
-        request.extensions.get::<TransactionIdParam>()
+        let transaction_id = request.extensions.get::<TransactionIdParam>()
             .expect("TransactionIdParam should ensure a TransactionId").clone();
 
         let user = request.extensions.get::<User>()
             .expect("AccessTokenAuth should ensure a user").clone();
 
-        let event_content = request
-            .get::<bodyparser::Json>()
-            .expect("JsonRequest verifies the Result is Ok")
-            .expect("JsonRequest verifies the Option is Some");
+        let event_content = strip_event_metadata(
+            request
+                .get::<bodyparser::Json>()
+                .expect("JsonRequest verifies the Result is Ok")
+                .expect("JsonRequest verifies the Option is Some")
+        );
         let config = Config::from_request(request)?;
+
+        verify_event_type_allowed(&config, &event_type)?;
+
         let event_id = EventId::new(&config.domain).map_api_err(|_| {
             ApiError::unknown("Failed to generated event ID for the new event.".to_string())
         })?;
 
-        let room_event: NewEvent = match event_type {
-            EventType::CallAnswer => {
-                room_event!(AnswerEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallCandidates => {
-                room_event!(CandidatesEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallHangup => {
-                room_event!(HangupEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::CallInvite => {
-                room_event!(InviteEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::RoomMessage => {
-                room_event!(MessageEvent, event_content, event_type, event_id, room_id, user)
-            }
-            EventType::Custom(ref custom_event_type) => {
-                CustomRoomEvent {
-                    content: event_content,
-                    event_id: event_id.clone(),
-                    event_type: EventType::Custom(custom_event_type.clone()),
-                    room_id: room_id.clone(),
-                    unsigned: None,
-                    user_id: user.id.clone(),
-                }.try_into().map_err(ApiError::from)?
-            }
-            _ => {
-                let error = ApiError::bad_event(
-                    format!("Events of type {} cannot be created with this API.", event_type)
-                );
-
-                return Err(IronError::from(error));
-            }
+        let room_event = new_room_event(&event_type, event_content, &event_id, &room_id, &user.id)?;
+        let imported_event = match timestamp_override(request)? {
+            Some(ts) => Some(NewImportedEvent::new(room_event.clone(), ts)?),
+            None => None,
         };
 
-        let connection = DB::from_request(request)?;
+        let spam_checker = CompositeSpamChecker::from_request(request)?;
+        let persister = EventPersister::from_request(request)?;
 
         let path = request.url.path().join("/").to_string();
-        let token = (*request.extensions.get::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token")).clone();
+        let token_hash = Transaction::token_hash(request);
+
+        // The connection is returned to the pool before the event is persisted, which writes
+        // with a connection of its own.
+        {
+            let connection = DB::from_request(request)?;
 
-        if let Some(transaction) = Transaction::find(&connection, &path, &token.value)? {
-            let response: EventResponse = from_str(&transaction.response).map_err(ApiError::from)?;
-            return Ok(Response::with((status::Ok, SerializableResponse(response))));
+            let response = find_event_response(&connection, &path, &token_hash, &user.id)?;
+
+            if let Some(response) = response {
+                return Ok(Response::with((status::Ok, SerializableResponse(response))));
+            }
         }
 
         let response = EventResponse {
             event_id: event_id.opaque_id().to_string(),
         };
+        let serialized_response = to_string(&response).map_err(ApiError::from)?;
+        let user_id = user.id.clone();
+        let write_path = path.clone();
+        let write_token_hash = token_hash.clone();
+
+        let written = persister.persist(&room_id, Box::new(move |connection| {
+            // A concurrent request with the same transaction ID waits here until the request that
+            // created the transaction is done, and fails without writing anything if it succeeded.
+            let is_created = Transaction::create(
+                connection,
+                write_path.clone(),
+                write_token_hash.clone(),
+                user.id.clone(),
+                serialized_response.clone(),
+            )?;
+
+            if !is_created {
+                return Err(ApiError::unknown("The transaction was already completed.".to_string()));
+            }
 
-        connection.transaction(|| {
-            verify_permissions(&connection, &room_id, &user, &event_type)?;
+            verify_permissions(connection, &room_event.room_id, &user, &event_type)?;
 
-            insert(&room_event)
-                .into(events::table)
-                .execute(&*connection)
-                .map_err(ApiError::from)?;
+            let event: Event = match imported_event {
+                Some(ref imported_event) => {
+                    insert(imported_event).into(events::table).get_result(connection)
+                }
+                None => insert(&room_event).into(events::table).get_result(connection),
+            }.map_err(ApiError::from)?;
 
-            let serialized_response = to_string(&response).map_err(ApiError::from)?;
+            // Rejecting the event rolls back its insertion.
+            spam_checker.check_event_for_spam(&event).ensure_allowed(&config)?;
 
-            Transaction::create(
-                &connection,
-                path.clone(),
-                token.value.clone(),
-                serialized_response,
-            )
-        }).map_err(ApiError::from)?;
+            verify_relation(connection, &event)?;
+            EventRelation::create_for_events(connection, &[event.clone()])?;
+            EventTransaction::create(connection, &event.id, &write_token_hash, &transaction_id)?;
+
+            Ok(event)
+        }));
+
+        let connection = DB::from_request(request)?;
+
+        let event = match written {
+            Ok(event) => event,
+            Err(error) => {
+                // A request with the same transaction ID that finished first has the response.
+                match find_event_response(&connection, &path, &token_hash, &user_id)? {
+                    Some(response) => {
+                        return Ok(Response::with((status::Ok, SerializableResponse(response))));
+                    }
+                    None => Err(error)?,
+                }
+            }
+        };
+
+        push::notify_room_members(&connection, &event)?;
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
     }
@@ -197,6 +203,9 @@ impl Handler for SendMessageEvent {
 
 /// The `/rooms/:room_id/state/:event_type/:state_key and /rooms/:room_id/state/:event_type`
 /// endpoints.
+///
+/// Like with `SendMessageEvent`, application services can set the `origin_server_ts` of the event
+/// with the `ts` query parameter.
 pub struct StateMessageEvent;
 
 middleware_chain!(StateMessageEvent, [JsonRequest, RoomIdParam, EventTypeParam, AccessTokenAuth]);
@@ -219,162 +228,112 @@ impl Handler for StateMessageEvent {
         let user = request.extensions.get::<User>()
             .expect("AccessTokenAuth should ensure a user").clone();
 
-        let event_content = request
-            .get::<bodyparser::Json>()
-            .expect("JsonRequest verifies the Result is Ok")
-            .expect("JsonRequest verifies the Option is Some");
+        let event_content = strip_event_metadata(
+            request
+                .get::<bodyparser::Json>()
+                .expect("JsonRequest verifies the Result is Ok")
+                .expect("JsonRequest verifies the Option is Some")
+        );
         let config = Config::from_request(request)?;
+
+        verify_event_type_allowed(&config, &event_type)?;
+
         let event_id = EventId::new(&config.domain).map_api_err(|_| {
             ApiError::unknown("Failed to generated event ID for the new event.".to_string())
         })?;
+        let ts = timestamp_override(request)?;
+        let persister = EventPersister::from_request(request)?;
 
-        let state_event: NewEvent = match event_type {
-            EventType::RoomAvatar => {
-                ensure_empty_state_key(state_key, &event_type)?;
+        // The connection is returned to the pool before the event is persisted, which writes
+        // with a connection of its own.
+        let (state_event, canonical_aliases) = {
+            let connection = DB::from_request(request)?;
 
-                state_event!(
-                    AvatarEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomCanonicalAlias => {
-                ensure_empty_state_key(state_key, &event_type)?;
-
-                state_event!(
-                    CanonicalAliasEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomGuestAccess => {
+            if event_type.to_string() == SERVER_ACL_EVENT_TYPE {
                 ensure_empty_state_key(state_key, &event_type)?;
 
-                state_event!(
-                    GuestAccessEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
+                // An ACL denying this server would lock all local users out of the room.
+                ServerAcl::from_content(event_content.clone())?
+                    .verify_allows_own_server(&config.domain)?;
             }
-            EventType::RoomHistoryVisibility => {
-                ensure_empty_state_key(state_key, &event_type)?;
 
-                state_event!(
-                    HistoryVisibilityEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomJoinRules => {
+            let event_content = if event_type.to_string() == PINNED_EVENTS_EVENT_TYPE {
                 ensure_empty_state_key(state_key, &event_type)?;
 
-                state_event!(
-                    JoinRulesEvent,
+                verify_pinned_events(
+                    &connection,
+                    &room_id,
+                    &event_type,
                     event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomName => {
-                ensure_empty_state_key(state_key, &event_type)?;
+                    config.drop_invalid_pinned_events,
+                )?
+            } else {
+                event_content
+            };
+
+            let mut canonical_aliases = None;
+
+            let state_event: NewEvent = match event_type {
+                EventType::RoomCanonicalAlias => {
+                    ensure_empty_state_key(state_key, &event_type)?;
+
+                    // The aliases are checked when the event is written, after the permissions,
+                    // so users who may not set them learn nothing about the room's aliases.
+                    canonical_aliases = Some(extract_event_content::<CanonicalAliasContent>(
+                        event_content.clone(),
+                        &event_type,
+                    )?);
+
+                    // `CanonicalAliasEvent` does not know about `alt_aliases`, so the content is
+                    // saved as is.
+                    CustomStateEvent {
+                        content: event_content,
+                        event_id: event_id.clone(),
+                        event_type: event_type.clone(),
+                        prev_content: None,
+                        room_id: room_id.clone(),
+                        state_key: state_key.to_string(),
+                        unsigned: None,
+                        user_id: user.id.clone(),
+                    }.try_into().map_err(ApiError::from)?
+                }
+                _ => {
+                    new_state_event(
+                        &event_type,
+                        event_content,
+                        state_key,
+                        &event_id,
+                        &room_id,
+                        &user.id,
+                    )?
+                }
+            };
+
+            (state_event, canonical_aliases)
+        };
 
-                state_event!(
-                    NameEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomPowerLevels => {
-                ensure_empty_state_key(state_key, &event_type)?;
+        let imported_event = match ts {
+            Some(ts) => Some(NewImportedEvent::new(state_event.clone(), ts)?),
+            None => None,
+        };
 
-                state_event!(
-                    PowerLevelsEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomThirdPartyInvite => {
-                state_event!(
-                    ThirdPartyInviteEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::RoomTopic => {
-                ensure_empty_state_key(state_key, &event_type)?;
+        let write_room_id = room_id.clone();
+        let homeserver_domain = config.domain.clone();
 
-                state_event!(
-                    TopicEvent,
-                    event_content,
-                    event_type,
-                    event_id,
-                    room_id,
-                    state_key,
-                    user
-                )
-            }
-            EventType::Custom(ref custom_event_type) => {
-                CustomStateEvent {
-                    content: event_content,
-                    event_id: event_id.clone(),
-                    event_type: EventType::Custom(custom_event_type.clone()),
-                    prev_content: None,
-                    room_id: room_id.clone(),
-                    state_key: state_key.to_string(),
-                    unsigned: None,
-                    user_id: user.id.clone(),
-                }.try_into().map_err(ApiError::from)?
-            }
-            _ => {
-                let error = ApiError::bad_event(
-                    format!("Events of type {} cannot be created with this API.", event_type)
-                );
+        persister.persist(&room_id, Box::new(move |connection| {
+            verify_permissions(connection, &write_room_id, &user, &event_type)?;
 
-                return Err(IronError::from(error));
+            if let Some(ref content) = canonical_aliases {
+                verify_canonical_aliases(connection, &write_room_id, &homeserver_domain, content)?;
             }
-        };
 
-        let connection = DB::from_request(request)?;
-
-        connection.transaction(|| {
-            verify_permissions(&connection, &room_id, &user, &event_type)?;
-
-            insert(&state_event)
-                .into(events::table)
-                .execute(&*connection)
-                .map_err(ApiError::from)
-        }).map_err(ApiError::from)?;
+            match imported_event {
+                Some(ref imported_event) => {
+                    insert(imported_event).into(events::table).get_result(connection)
+                }
+                None => insert(&state_event).into(events::table).get_result(connection),
+            }.map_err(ApiError::from)
+        }))?;
 
         let response = EventResponse {
             event_id: event_id.opaque_id().to_string(),
@@ -384,17 +343,165 @@ impl Handler for StateMessageEvent {
     }
 }
 
+/// The response saved for the transaction of a request to send an event, if there is one.
+fn find_event_response(connection: &PgConnection, path: &str, token_hash: &str, user_id: &UserId)
+-> Result<Option<EventResponse>, ApiError> {
+    match Transaction::find(connection, path, token_hash, user_id)? {
+        Some(transaction) => from_str(&transaction.response).map(Some).map_err(ApiError::from),
+        None => Ok(None),
+    }
+}
+
+/// The `origin_server_ts` an application service set with the `ts` query parameter, if any.
+///
+/// It must be a number of milliseconds since the Unix epoch that can be stored, failing with 400
+/// otherwise. `NewImportedEvent::new` rejects timestamps in the future. Other users cannot set the
+/// timestamp of their events.
+fn timestamp_override(request: &Request) -> Result<Option<i64>, ApiError> {
+    let url: Url = request.url.clone().into();
+    let ts = match url.query_pairs().find(|&(ref key, _)| key == "ts") {
+        Some((_, ts)) => ts.into_owned(),
+        None => return Ok(None),
+    };
+
+    if request.extensions.get::<AppServiceRegistration>().is_none() {
+        return Err(ApiError::unauthorized(
+            "Only application services can set the timestamp of events.".to_string()
+        ));
+    }
+
+    match ts.parse() {
+        Ok(ts) if is_storable_timestamp(ts) => Ok(Some(ts)),
+        Ok(_) | Err(_) => Err(ApiError::invalid_param(
+            "ts",
+            "Must be a number of milliseconds since the Unix epoch.",
+        )),
+    }
+}
+
+/// Remove the keys describing the event itself from content sent by a client.
+///
+/// The server sets these keys, so clients must not be able to forge them by including them in
+/// the content.
+fn strip_event_metadata(mut event_content: Value) -> Value {
+    if let Some(object) = event_content.as_object_mut() {
+        for key in SERVER_SET_EVENT_KEYS.iter() {
+            object.remove(*key);
+        }
+    }
+
+    event_content
+}
+
+/// Check that the event an event relates to, if any, was sent in the same room.
+fn verify_relation(connection: &PgConnection, event: &Event) -> Result<(), ApiError> {
+    let relation = match NewEventRelation::from_event(event)? {
+        Some(relation) => relation,
+        None => return Ok(()),
+    };
+
+    match Event::locate(connection, &relation.relates_to_id)? {
+        Some(ref relates_to) if relates_to.room_id == event.room_id => Ok(()),
+        _ => Err(ApiError::bad_event(
+            format!("The event {} is not in this room.", relation.relates_to_id)
+        )),
+    }
+}
+
+/// Check that the events pinned by an `m.room.pinned_events` event were sent in the room.
+///
+/// Other events are left out of the returned content if `drop_invalid` is set, and fail with
+/// `IO_RUMA_INVALID_PARAM` otherwise.
+fn verify_pinned_events(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    event_type: &EventType,
+    mut event_content: Value,
+    drop_invalid: bool,
+) -> Result<Value, ApiError> {
+    let content: PinnedEventsContent = extract_event_content(event_content.clone(), event_type)?;
+
+    let events_in_room: Vec<EventId> =
+        Event::find_many_in_room(connection, room_id, &content.pinned)?
+            .into_iter()
+            .map(|event| event.id)
+            .collect();
+
+    let mut pinned = Vec::with_capacity(content.pinned.len());
+
+    for event_id in content.pinned {
+        if events_in_room.contains(&event_id) {
+            pinned.push(Value::String(event_id.to_string()));
+        } else if !drop_invalid {
+            return Err(ApiError::invalid_param(
+                "pinned",
+                &format!("The event {} is not in this room.", event_id),
+            ));
+        }
+    }
+
+    event_content["pinned"] = Value::Array(pinned);
+
+    Ok(event_content)
+}
+
+/// Check that the canonical alias and all alternative aliases are local aliases of the room.
+fn verify_canonical_aliases(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    homeserver_domain: &str,
+    content: &CanonicalAliasContent,
+) -> Result<(), ApiError> {
+    let aliases: Vec<RoomAliasId> = Some(&content.alias).into_iter()
+        .chain(content.alt_aliases.iter())
+        .cloned()
+        .collect();
+
+    for alias in &aliases {
+        if alias.hostname().to_string() != homeserver_domain {
+            return Err(ApiError::bad_alias(format!("The alias {} is not local to this server.", alias)));
+        }
+    }
+
+    let room_aliases = RoomAlias::find_by_aliases(connection, &aliases)?;
+
+    for alias in &aliases {
+        let points_to_room = room_aliases.iter()
+            .any(|room_alias| room_alias.alias == *alias && room_alias.room_id == *room_id);
+
+        if !points_to_room {
+            return Err(ApiError::bad_alias(
+                format!("The alias {} does not point to this room.", alias)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
 /// Check if a `User` has permission to create an event in a given `Room`.
+///
+/// The user's membership and the room's power levels are looked up with a single query.
 fn verify_permissions(connection: &PgConnection, room_id: &RoomId, user: &User, event_type: &EventType)
 -> Result<(), ApiError> {
-    let room = match Room::find(connection, room_id)? {
-        Some(room) => room,
-        None => Err(ApiError::unauthorized("The room was not found on this server".to_string()))?,
-    };
+    if Room::find(connection, room_id)?.is_none() {
+        Err(ApiError::unauthorized("The room was not found on this server".to_string()))?
+    }
+
+    let member_key = user.id.to_string();
+    let mut contents = Room::get_state_contents(
+        connection,
+        room_id,
+        &[(EventType::RoomPowerLevels, ""), (EventType::RoomMember, &member_key)],
+    )?;
 
-    match RoomMembership::find(connection, room_id, &user.id)? {
+    let membership = contents
+        .remove(&state_key(&EventType::RoomMember, &member_key))
+        .and_then(|content| content.get("membership").and_then(Value::as_str).map(String::from));
+
+    match membership {
         Some(membership) => {
-            if membership.membership != "join" {
+            if membership != "join" {
                 Err(ApiError::unauthorized(
                     format!("The user {} has not joined the room", user.id)
                 ))?
@@ -407,7 +514,10 @@ fn verify_permissions(connection: &PgConnection, room_id: &RoomId, user: &User,
         }
     }
 
-    let power_levels = room.current_power_levels(&*connection)?;
+    let power_levels = match contents.remove(&state_key(&EventType::RoomPowerLevels, "")) {
+        Some(content) => from_value::<PowerLevelsEventContent>(content).map_err(ApiError::from)?,
+        None => Room::default_power_levels(),
+    };
     let user_power_level = power_levels
         .users
         .get(&user.id)
@@ -426,33 +536,63 @@ fn verify_permissions(connection: &PgConnection, room_id: &RoomId, user: &User,
     Ok(())
 }
 
-/// Enforces an empty state key for an event type that requires it.
-fn ensure_empty_state_key(state_key: &str, event_type: &EventType) -> Result<(), IronError> {
-    if state_key == "" {
-        Ok(())
-    } else {
-        Err(ApiError::bad_event(format!("Events of type {} must have an empty state key.", event_type)))?
-    }
-}
-
-/// Convert the JSON from the request into the correct type for the event's `content` field.
-fn extract_event_content<T>(event_content: Value, event_type: &EventType) -> Result<T, ApiError>
-where T: for<'de> Deserialize<'de> {
-    from_value(event_content).map_api_err(|_| {
-        ApiError::bad_event(
-            format!(
-                "Event content did not match expected structure for event of type {}.",
-                event_type
-            )
-        )
-    })
-}
-
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::convert::TryFrom;
+
+    use diesel::{ExecuteDsl, ExpressionMethods, FindDsl, update};
+    use diesel::pg::data_types::PgTimestamp;
+    use ruma_identifiers::{EventId, RoomId};
+    use serde_json::{Value, from_str};
+
+    use models::event::Event;
+    use models::pusher::{PusherData, PusherOptions};
+    use push::{deliver_queued, notify_room_members, sent_notifications};
+    use query::SyncOptions;
+    use schema::events;
+    use test::{Response, Test, TestUser};
     use iron::status::Status;
 
+    /// The content of a valid call invite with a lifetime of one minute.
+    const CALL_INVITE: &'static str = r#"{
+        "call_id": "12345",
+        "lifetime": 60000,
+        "offer": {"type": "offer", "sdp": "v=0"},
+        "version": 0
+    }"#;
+
+    /// Register an HTTP pusher for the user.
+    fn set_http_pusher(test: &Test, user: &TestUser) {
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.ruma.test/_matrix/push/v1/notify".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "io.ruma.test".to_string(),
+            profile_tag: None,
+            pushkey: "pushkey".to_string(),
+            app_display_name: "Ruma Test".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&user.token, options).status, Status::Ok);
+    }
+
+    /// Send a call event with the given content.
+    fn send_call_event(test: &Test, user: &TestUser, room_id: &str, event_type: &str, body: &str)
+    -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/{}/1?access_token={}",
+            room_id,
+            event_type,
+            user.token
+        );
+
+        test.put(&path, body)
+    }
+
     #[test]
     fn create_message_event() {
         let test = Test::new();
@@ -470,6 +610,147 @@ mod tests {
         assert!(response.json().get("event_id").unwrap().as_str().is_some());
     }
 
+    #[test]
+    fn sent_events_are_stored_with_a_content_hash() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let response = test.send_message(&user.token, &room_id, "Hi", 1);
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let event_id = EventId::try_from(&format!("${}:ruma.test", opaque_id)).unwrap();
+
+        test.with_connection(|connection| {
+            let event = Event::find(connection, &event_id).unwrap().unwrap();
+
+            assert_eq!(event.content_hash.len(), 64);
+            assert!(event.has_intact_content().unwrap());
+
+            update(events::table.find(&event_id))
+                .set(events::content.eq(r#"{"body":"Bye","msgtype":"m.text"}"#))
+                .execute(connection)
+                .unwrap();
+
+            let event = Event::find(connection, &event_id).unwrap().unwrap();
+
+            assert!(!event.has_intact_content().unwrap());
+        });
+    }
+
+    #[test]
+    fn events_with_fractional_numbers_are_rejected() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/org.example.score/1?access_token={}",
+            room_id,
+            user.token
+        );
+        let response = test.put(&path, r#"{"score": 1.5}"#);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_JSON");
+
+        let response = test.put(&path.replace("/1?", "/2?"), r#"{"score": 2}"#);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn sent_events_can_be_located() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let response = test.send_message(&user.token, &room_id, "Hi", 1);
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let event_id = EventId::try_from(&format!("${}:ruma.test", opaque_id)).unwrap();
+
+        test.with_connection(|connection| {
+            let event = Event::find(connection, &event_id).unwrap().unwrap();
+            let location = Event::locate(connection, &event_id).unwrap().unwrap();
+
+            assert_eq!(location.room_id.to_string(), room_id);
+            assert_eq!(location.sender.to_string(), user.id);
+            assert_eq!(location.ordering, event.ordering);
+
+            let other_room_id = RoomId::try_from("!other:ruma.test").unwrap();
+
+            assert!(Event::verify_in_room(connection, &event_id, &event.room_id).is_ok());
+            assert!(Event::verify_in_room(connection, &event_id, &other_room_id).is_err());
+        });
+    }
+
+    #[test]
+    fn client_cannot_forge_event_metadata() {
+        let test = Test::new();
+        let user = test.create_user();
+        let mallory = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let create_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/org.example.note/1?access_token={}",
+            room_id,
+            user.token
+        );
+        let body = format!(
+            r#"{{
+                "body": "Hi",
+                "event_id": "$forged:ruma.test",
+                "origin_server_ts": 1,
+                "room_id": "!forged:ruma.test",
+                "sender": "{}"
+            }}"#,
+            mallory.id
+        );
+
+        let response = test.put(&create_event_path, &body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let event_id = EventId::try_from(&format!("${}:ruma.test", opaque_id)).unwrap();
+
+        assert_ne!(event_id.to_string(), "$forged:ruma.test");
+
+        let event = test.with_connection(|connection| {
+            Event::find(connection, &event_id).unwrap().unwrap()
+        });
+        let content: Value = from_str(&event.content).unwrap();
+
+        assert_eq!(event.user_id.to_string(), user.id);
+        assert_eq!(event.room_id.to_string(), room_id);
+        assert_eq!(content.get("body").unwrap().as_str().unwrap(), "Hi");
+
+        for key in &["event_id", "origin_server_ts", "room_id", "sender"] {
+            assert!(content.get(key).is_none());
+        }
+    }
+
+    #[test]
+    fn blocklisted_message_is_rejected() {
+        let test = Test::with_config(|config| {
+            config.spam_blocklist = vec![r"buy cheap \w+".to_string()];
+        });
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+
+        let response = test.send_message(&user.token, &room_id, "BUY CHEAP watches here", 1);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The request was rejected as spam."
+        );
+
+        let response = test.send_message(&user.token, &room_id, "Buy me a coffee?", 2);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
     #[test]
     fn event_content_does_not_match_event_type() {
         let test = Test::new();
@@ -657,4 +938,503 @@ mod tests {
         let third_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
         assert_ne!(third_event_id, second_event_id);
     }
+
+    #[test]
+    fn set_canonical_alias_with_alt_aliases() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "main"}"#);
+
+        let put_alias_path = format!(
+            "/_matrix/client/r0/directory/room/%23other:ruma.test?access_token={}",
+            alice.token
+        );
+        let response = test.put(&put_alias_path, &format!(r#"{{"room_id": "{}"}}"#, room_id));
+        assert_eq!(response.status, Status::Ok);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.canonical_alias?access_token={}",
+            room_id,
+            alice.token
+        );
+        let event_content = r#"{"alias": "#main:ruma.test", "alt_aliases": ["#other:ruma.test"]}"#;
+
+        let response = test.put(&state_event_path, event_content);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn canonical_alias_must_point_to_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let _ = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "elsewhere"}"#);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.canonical_alias?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let response = test.put(&state_event_path, r#"{"alias": "#unbound:ruma.test"}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_ALIAS");
+
+        let response = test.put(&state_event_path, r#"{"alias": "#elsewhere:ruma.test"}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_ALIAS");
+    }
+
+    #[test]
+    fn canonical_aliases_are_checked_after_permissions() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.canonical_alias?access_token={}",
+            room_id,
+            bob.token
+        );
+
+        let response = test.put(&state_event_path, r#"{"alias": "#unbound:ruma.test"}"#);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn alt_aliases_must_be_local_and_point_to_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"room_alias_name": "main"}"#);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.canonical_alias?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let event_content = r#"{"alias": "#main:ruma.test", "alt_aliases": ["#unbound:ruma.test"]}"#;
+        let response = test.put(&state_event_path, event_content);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_ALIAS");
+
+        let event_content = r#"{"alias": "#main:ruma.test", "alt_aliases": ["#main:example.com"]}"#;
+        let response = test.put(&state_event_path, event_content);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_BAD_ALIAS");
+    }
+
+    #[test]
+    fn call_invite_rings_other_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        set_http_pusher(&test, &alice);
+        set_http_pusher(&test, &bob);
+
+        let response = send_call_event(&test, &alice, &room_id, "m.call.invite", CALL_INVITE);
+
+        assert_eq!(response.status, Status::Ok);
+
+        // Notifications are only posted by the push worker.
+        assert!(sent_notifications().is_empty());
+        assert_eq!(test.deliver_push_notifications(), 1);
+
+        // The caller is not notified of their own call.
+        let notifications = sent_notifications();
+
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].0, "https://push.ruma.test/_matrix/push/v1/notify");
+
+        let notification = notifications[0].1.get("notification").unwrap();
+        let tweaks = notification.get("devices").unwrap()[0].get("tweaks").unwrap();
+
+        assert_eq!(notification.get("type").unwrap().as_str().unwrap(), "m.call.invite");
+        assert_eq!(notification.get("sender").unwrap().as_str().unwrap(), alice.id);
+        assert_eq!(tweaks.get("sound").unwrap().as_str().unwrap(), "ring");
+
+        // Messages in a room with two members notify with the default sound.
+        test.send_message(&alice.token, &room_id, "Hi", 2);
+
+        assert_eq!(test.deliver_push_notifications(), 1);
+
+        let notifications = sent_notifications();
+        let notification = notifications[1].1.get("notification").unwrap();
+        let tweaks = notification.get("devices").unwrap()[0].get("tweaks").unwrap();
+
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notification.get("type").unwrap().as_str().unwrap(), "m.room.message");
+        assert_eq!(tweaks.get("sound").unwrap().as_str().unwrap(), "default");
+    }
+
+    #[test]
+    fn expired_call_invite_is_not_pushed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = send_call_event(&test, &alice, &room_id, "m.call.invite", CALL_INVITE);
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let event_id = EventId::try_from(&format!("${}:ruma.test", opaque_id)).unwrap();
+
+        set_http_pusher(&test, &bob);
+
+        test.with_connection(|connection| {
+            let mut event = Event::find(connection, &event_id).unwrap().unwrap();
+
+            notify_room_members(connection, &event).unwrap();
+            assert_eq!(deliver_queued(connection).unwrap(), 1);
+            assert_eq!(sent_notifications().len(), 1);
+
+            // Backdate the invite by an hour, well beyond its lifetime of one minute.
+            event.created_at = PgTimestamp(event.created_at.0 - 3_600_000_000);
+
+            notify_room_members(connection, &event).unwrap();
+            assert_eq!(deliver_queued(connection).unwrap(), 0);
+            assert_eq!(sent_notifications().len(), 1);
+        });
+    }
+
+    #[test]
+    fn call_events_are_validated() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let invalid_events = [
+            (
+                "m.call.invite",
+                r#"{"lifetime": 60000, "offer": {"type": "offer", "sdp": "v=0"}, "version": 0}"#,
+            ),
+            (
+                "m.call.invite",
+                r#"{"call_id": "1", "offer": {"type": "offer", "sdp": "v=0"}, "version": 0}"#,
+            ),
+            (
+                "m.call.invite",
+                r#"{
+                    "call_id": "1",
+                    "lifetime": 60000,
+                    "offer": {"type": "answer", "sdp": "v=0"},
+                    "version": 0
+                }"#,
+            ),
+            (
+                "m.call.answer",
+                r#"{"call_id": "1", "answer": {"type": "answer", "sdp": ""}, "version": 0}"#,
+            ),
+            ("m.call.hangup", r#"{"call_id": "1"}"#),
+            ("m.call.hangup", r#"{"call_id": "", "version": 0}"#),
+        ];
+
+        for &(event_type, body) in &invalid_events {
+            let response = send_call_event(&test, &alice, &room_id, event_type, body);
+
+            assert_eq!(response.status, Status::UnprocessableEntity);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "IO_RUMA_BAD_EVENT"
+            );
+        }
+
+        let response = send_call_event(
+            &test,
+            &alice,
+            &room_id,
+            "m.call.hangup",
+            r#"{"call_id": "1", "version": 0}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn server_acl_cannot_deny_own_server() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let acl_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.server_acl?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let response = test.put(&acl_path, r#"{"allow": ["*"], "deny": ["*.test"]}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+
+        let response = test.put(&acl_path, r#"{"allow": ["ruma.test"], "allow_ip_literals": 1}"#);
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+
+        let response = test.put(&acl_path, r#"{"allow": ["*"], "deny": ["evil.test"]}"#);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    /// Pin a message of the room and a message of another room, and return the pinned events
+    /// of the room afterwards, or the response if pinning failed.
+    fn pin_events(test: &Test) -> Result<Value, Response> {
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let other_room_id = test.create_room(&alice.token);
+
+        let event_id = |room_id: &str, txn_id: u64| {
+            let response = test.send_message(&alice.token, room_id, "Pin me", txn_id);
+
+            format!("${}:ruma.test", response.json().get("event_id").unwrap().as_str().unwrap())
+        };
+        let content = format!(
+            r#"{{"pinned": ["{}", "{}"]}}"#,
+            event_id(&room_id, 1),
+            event_id(&other_room_id, 2)
+        );
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.pinned_events",
+            &content,
+            None
+        );
+
+        if response.status != Status::Ok {
+            return Err(response);
+        }
+
+        let response = test.get_state_event(&alice.token, &room_id, "m.room.pinned_events", None);
+
+        Ok(response.json().get("pinned").unwrap().clone())
+    }
+
+    #[test]
+    fn pinned_events_of_other_rooms_are_rejected() {
+        let test = Test::new();
+        let response = pin_events(&test).unwrap_err();
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn pinned_events_of_other_rooms_can_be_dropped() {
+        let test = Test::with_config(|config| config.drop_invalid_pinned_events = true);
+        let pinned = pin_events(&test).unwrap();
+        let pinned = pinned.as_array().unwrap();
+
+        assert_eq!(pinned.len(), 1);
+        assert!(pinned[0].as_str().unwrap().starts_with('$'));
+    }
+
+    #[test]
+    fn pinning_events_of_public_chats_requires_moderator_level() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        let response = test.get_state_event(&alice.token, &room_id, "m.room.power_levels", None);
+        let mut power_levels = response.json().clone();
+
+        assert_eq!(power_levels["events"]["m.room.pinned_events"].as_u64(), Some(50));
+
+        power_levels["users"][bob.id.clone()] = Value::from(50);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.power_levels",
+            &power_levels.to_string(),
+            None
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let event_ids: Vec<String> = (1..3).map(|txn_id| {
+            let response = test.send_message(&carl.token, &room_id, "Pin me", txn_id);
+
+            format!("${}:ruma.test", response.json().get("event_id").unwrap().as_str().unwrap())
+        }).collect();
+        let content = format!(r#"{{"pinned": ["{}", "{}"]}}"#, event_ids[0], event_ids[1]);
+
+        let response =
+            test.send_state_event(&carl.token, &room_id, "m.room.pinned_events", &content, None);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response =
+            test.send_state_event(&bob.token, &room_id, "m.room.pinned_events", &content, None);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get_state_event(&carl.token, &room_id, "m.room.pinned_events", None);
+
+        assert_eq!(response.json().get("pinned").unwrap().as_array().unwrap().len(), 2);
+
+        let response = test.send_state_event(
+            &bob.token,
+            &room_id,
+            "m.room.pinned_events",
+            r#"{"pinned": ["not an event ID"]}"#,
+            None
+        );
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn app_service_sets_timestamps_of_bridged_history() {
+        let test = Test::with_config(|config| {
+            config.app_services.push(from_str(r#"{
+                "id": "irc",
+                "as_token": "as_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {"users": [{"exclusive": true, "regex": "@irc_.*:ruma\\.test"}]}
+            }"#).unwrap());
+        });
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_bot", "password": "secret"}"#
+        ).status.is_success());
+        assert_eq!(test.join_room("as_secret", &room_id).status, Status::Ok);
+
+        let send_path = |token: &str, txn_id: u64, ts: &str| {
+            format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/{}?access_token={}&ts={}",
+                room_id,
+                txn_id,
+                token,
+                ts
+            )
+        };
+        let body = r#"{"body": "Bridged", "msgtype": "m.text"}"#;
+
+        let response = test.put(&send_path(&alice.token, 1, "1262304000000"), body);
+        assert_eq!(response.status, Status::Forbidden);
+
+        for ts in vec!["yesterday", "-1", "9223372036854775807", "9223372036854775808"] {
+            let response = test.put(&send_path("as_secret", 1, ts), body);
+            assert_eq!(response.status, Status::BadRequest);
+        }
+
+        for (txn_id, ts) in vec![(2, "1262304000000"), (3, "1262304001000")] {
+            assert_eq!(test.put(&send_path("as_secret", txn_id, ts), body).status, Status::Ok);
+        }
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let events = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let (first, second) = (&events[events.len() - 2], &events[events.len() - 1]);
+
+        assert_eq!(first.pointer("/origin_server_ts").unwrap().as_i64().unwrap(), 1262304000000);
+        assert_eq!(second.pointer("/origin_server_ts").unwrap().as_i64().unwrap(), 1262304001000);
+
+        let age = |event: &Value| event.pointer("/unsigned/age").unwrap().as_i64().unwrap();
+
+        // Both were sent more than ten years after their timestamps.
+        assert!(age(first) > 10 * 365 * 24 * 60 * 60 * 1000);
+        assert!(age(first) > age(second));
+
+        let creation = events.iter().find(|event| {
+            event.pointer("/type").unwrap().as_str() == Some("m.room.create")
+        }).unwrap();
+
+        assert!(creation.pointer("/origin_server_ts").unwrap().as_i64().unwrap() > 1262304001000);
+    }
+
+    fn send_event(test: &Test, user: &TestUser, room_id: &str, event_type: &str) -> Response {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/{}/1?access_token={}",
+            room_id,
+            event_type,
+            user.token
+        );
+
+        test.put(&path, r#"{"body": "Hi", "msgtype": "m.text"}"#)
+    }
+
+    #[test]
+    fn events_of_denied_types_cannot_be_sent() {
+        let test = Test::with_config(|config| {
+            config.denied_event_types = vec!["org.example.experimental.*".to_string()];
+        });
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = send_event(&test, &alice, &room_id, "org.example.experimental.poll");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "org.example.experimental.widget",
+            r#"{"url": "https://widget.example.com"}"#,
+            None,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(send_event(&test, &alice, &room_id, "m.room.message").status, Status::Ok);
+    }
+
+    #[test]
+    fn only_events_of_allowed_types_can_be_sent() {
+        let test = Test::with_config(|config| {
+            config.allowed_event_types = vec!["m.room.*".to_string()];
+            config.denied_event_types = vec!["m.room.topic".to_string()];
+        });
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        assert_eq!(send_event(&test, &alice, &room_id, "m.room.message").status, Status::Ok);
+        assert_eq!(
+            send_event(&test, &alice, &room_id, "org.example.custom").status,
+            Status::Forbidden
+        );
+
+        let name = r#"{"name": "Ruma"}"#;
+        let topic = r#"{"topic": "Ruma"}"#;
+
+        assert_eq!(
+            test.send_state_event(&alice.token, &room_id, "m.room.name", name, None).status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.send_state_event(&alice.token, &room_id, "m.room.topic", topic, None).status,
+            Status::Forbidden
+        );
+    }
 }
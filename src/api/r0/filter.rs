@@ -59,6 +59,8 @@ impl Handler for PostFilter {
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
+        filter.validate()?;
+
         let connection = DB::from_request(request)?;
 
         let id = Filter::create(&connection, user_id, to_value(&filter).map_err(ApiError::from)?.to_string())?;
@@ -110,6 +112,27 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn malformed_event_fields_are_rejected() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter?access_token={}",
+            carl.id,
+            carl.token
+        );
+
+        let response = test.post(&filter_path, r#"{"event_fields":["content.body", "content."]}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+
+        let response = test.post(&filter_path, r#"{"event_fields":["content.m\\.relates_to"]}"#);
+        assert_eq!(response.status, Status::Ok);
+    }
+
     #[test]
     fn get_not_found() {
         let test = Test::new();
@@ -18,7 +18,14 @@ use models::room_alias::RoomAlias;
 use models::room_membership::{RoomMembership, RoomMembershipOptions};
 use models::user::User;
 use modifier::{SerializableResponse, EmptyResponse};
+use spam::{CompositeSpamChecker, SpamChecker};
 
+/// The body of the requests with which a user changes their own membership.
+#[derive(Clone, Debug, Deserialize)]
+struct OwnMembershipRequest {
+    /// Why the user joins, knocks on or leaves the room.
+    pub reason: Option<String>,
+}
 
 /// The `/rooms/:room_id/join` endpoint.
 pub struct JoinRoom;
@@ -45,7 +52,9 @@ impl Handler for JoinRoom {
             .expect("Should have been required by RoomIdParam.")
             .clone();
 
-        join_room(room_id, user, &connection, &config)
+        let reason = parse_reason(request)?;
+
+        join_room(room_id, user, reason, &connection, &config)
     }
 }
 
@@ -68,25 +77,53 @@ impl Handler for JoinRoomWithIdOrAlias {
             .expect("Should have been required by RoomIdOrAliasParam.")
             .clone();
 
-        let room_id = match room_id_or_alias {
-            RoomIdOrAliasId::RoomId(id) => id,
-            RoomIdOrAliasId::RoomAliasId(alias) => {
-                let room_alias = RoomAlias::find_by_alias(&connection, &alias)?;
-                room_alias.room_id
-            }
-        };
+        let room_id = resolve_room_id(&connection, room_id_or_alias)?;
+        let reason = parse_reason(request)?;
+
+        join_room(room_id, user, reason, &connection, &config)
+    }
+}
 
-        join_room(room_id, user, &connection, &config)
+/// Return the `reason` of a request that changes the user's own membership.
+fn parse_reason(request: &mut Request) -> Result<Option<String>, ApiError> {
+    match request.get::<bodyparser::Struct<OwnMembershipRequest>>() {
+        Ok(Some(req)) => Ok(req.reason),
+        Ok(None) => Ok(None),
+        Err(err) => Err(ApiError::bad_json(err.description().to_string())),
+    }
+}
+
+/// Return the `RoomId` for a `RoomIdOrAliasId`.
+fn resolve_room_id(connection: &PgConnection, room_id_or_alias: RoomIdOrAliasId)
+-> Result<RoomId, ApiError> {
+    match room_id_or_alias {
+        RoomIdOrAliasId::RoomId(id) => Ok(id),
+        RoomIdOrAliasId::RoomAliasId(alias) => {
+            let room_alias = RoomAlias::find_by_alias(connection, &alias)?;
+            Ok(room_alias.room_id)
+        }
     }
 }
 
 /// Handles the work of actually saving the user to the room membership table
-fn join_room(room_id: RoomId, user: User, connection: &PgConnection, config: &Config) -> IronResult<Response> {
+fn join_room(
+    room_id: RoomId,
+    user: User,
+    reason: Option<String>,
+    connection: &PgConnection,
+    config: &Config,
+) -> IronResult<Response> {
+    match RoomMembership::find(connection, &room_id, &user.id)? {
+        Some(ref membership) if membership.membership == "join" => (),
+        _ => RoomMembership::verify_room_limit(connection, &user.id, config.max_rooms_per_user)?,
+    }
+
     let room_membership_options = RoomMembershipOptions {
         room_id: room_id.clone(),
         user_id: user.id.clone(),
         sender: user.id,
         membership: "join".to_string(),
+        reason: reason,
     };
 
     let room_membership = RoomMembership::upsert(
@@ -100,6 +137,71 @@ fn join_room(room_id: RoomId, user: User, connection: &PgConnection, config: &Co
     Ok(Response::with((Status::Ok, SerializableResponse(response))))
 }
 
+/// The `/knock/:room_id_or_alias` endpoint.
+pub struct KnockOnRoom;
+
+#[derive(Debug, Serialize)]
+struct KnockOnRoomResponse {
+    /// The room that was knocked on.
+    room_id: RoomId,
+}
+
+middleware_chain!(KnockOnRoom, [JsonRequest, RoomIdOrAliasParam, AccessTokenAuth]);
+
+impl Handler for KnockOnRoom {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let room_id_or_alias = request.extensions.get::<RoomIdOrAliasParam>()
+            .expect("Should have been required by RoomIdOrAliasParam.")
+            .clone();
+
+        let room_id = resolve_room_id(&connection, room_id_or_alias)?;
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id: room_id.clone(),
+            user_id: user.id.clone(),
+            sender: user.id.clone(),
+            membership: "knock".to_string(),
+            reason: parse_reason(request)?,
+        };
+
+        match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(mut room_membership) => {
+                match room_membership.membership.as_str() {
+                    "knock" => {},
+                    "leave" => {
+                        RoomMembership::verify_priviledges(&connection, &room_membership_options)?;
+
+                        room_membership.update(
+                            &connection,
+                            &config.domain,
+                            room_membership_options,
+                        )?;
+                    },
+                    "join" => Err(ApiError::unauthorized("User has already joined the room".to_string()))?,
+                    "invite" => Err(ApiError::unauthorized("User is already invited to the room".to_string()))?,
+                    "ban" => Err(ApiError::unauthorized("User is banned from the room".to_string()))?,
+                    _ => Err(ApiError::unauthorized("Invalid membership state".to_string()))?,
+                }
+            },
+            None => {
+                RoomMembership::create(&connection, &config.domain, room_membership_options)?;
+            },
+        }
+
+        let response = KnockOnRoomResponse { room_id: room_id };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// The `/rooms/:room_id/leave` endpoint.
 pub struct LeaveRoom;
 
@@ -124,6 +226,7 @@ impl Handler for LeaveRoom {
             user_id: user.id.clone(),
             sender: user.id.clone(),
             membership: "leave".to_string(),
+            reason: parse_reason(request)?,
         };
 
         if Room::find(&connection, &room_id)?.is_none() {
@@ -134,7 +237,7 @@ impl Handler for LeaveRoom {
             Some(mut room_membership) => {
                 match room_membership.membership.as_str() {
                     "leave" => Ok(Response::with(Status::Ok)),
-                    "join" | "invite" => {
+                    "join" | "invite" | "knock" => {
                         room_membership.update(
                             &connection,
                             &config.domain,
@@ -173,12 +276,14 @@ impl Handler for KickFromRoom {
         let kicker = request.extensions.get::<User>()
             .expect("AccessTokenAuth should ensure a user").clone();
 
-        let kickee_id = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
-            Ok(Some(req)) => req.user_id,
+        let kick_request = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
+            Ok(Some(req)) => req,
             Ok(None) => Err(ApiError::bad_json(None))?,
             Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
         };
 
+        let kickee_id = kick_request.user_id;
+
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
@@ -192,8 +297,11 @@ impl Handler for KickFromRoom {
             _ => Err(ApiError::unauthorized("The kicker is not currently in the room".to_string()))?,
         };
 
+        // Kicking a user who knocked rejects the knock.
         let mut kickee_membership = match RoomMembership::find(&connection, &room_id, &kickee_id)? {
-            Some(ref membership) if membership.membership == "join" => membership.clone(),
+            Some(ref membership) if membership.membership == "join" || membership.membership == "knock" => {
+                membership.clone()
+            },
             _ => Err(ApiError::unauthorized("The kickee is not currently in the room".to_string()))?,
         };
 
@@ -212,6 +320,7 @@ impl Handler for KickFromRoom {
             user_id: kickee_id,
             sender: kicker.id,
             membership: "leave".to_string(),
+            reason: kick_request.reason,
         };
 
         kickee_membership.update(&connection, &config.domain, room_membership_options)?;
@@ -226,6 +335,8 @@ pub struct InviteToRoom;
 
 #[derive(Clone, Debug, Deserialize)]
 struct InviteToRoomRequest {
+    /// Why the user is invited.
+    pub reason: Option<String>,
     /// The fully qualified user ID of the invitee.
     pub user_id: UserId,
 }
@@ -240,14 +351,17 @@ impl Handler for InviteToRoom {
         let inviter = request.extensions.get::<User>()
             .expect("AccessTokenAuth should ensure a user").clone();
 
-        let invitee_id = match request.get::<bodyparser::Struct<InviteToRoomRequest>>() {
-            Ok(Some(req)) => req.user_id,
+        let (invitee_id, reason) = match request.get::<bodyparser::Struct<InviteToRoomRequest>>() {
+            Ok(Some(req)) => (req.user_id, req.reason),
             Ok(None) => Err(ApiError::missing_param("user_id"))?,
             Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
         };
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
+        let spam_checker = CompositeSpamChecker::from_request(request)?;
+
+        spam_checker.user_may_invite(&inviter.id, &invitee_id, &room_id).ensure_allowed(&config)?;
 
         let invitee_membership = connection.transaction::<Option<RoomMembership>, ApiError, _>(|| {
             if User::find_active_user(&connection, &invitee_id)?.is_none() {
@@ -286,6 +400,7 @@ impl Handler for InviteToRoom {
             user_id: invitee_id,
             sender: inviter.id,
             membership: "invite".to_string(),
+            reason: reason,
         };
 
         match invitee_membership {
@@ -298,6 +413,8 @@ impl Handler for InviteToRoom {
                     "The invited user has already joined".to_string()
                 )),
                 _ => {
+                    RoomMembership::verify_priviledges(&connection, &new_membership_options)?;
+
                     entry.update(
                         &connection,
                         &config.domain,
@@ -325,8 +442,52 @@ impl Handler for InviteToRoom {
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Barrier};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+
+    use diesel::{ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, update};
     use iron::status::Status;
+    use ruma_identifiers::{RoomId, UserId};
+    use serde_json::Value;
+
+    use hooks::register_test_membership_hook;
+    use models::room_membership::RoomMembership;
+    use query::SyncOptions;
+    use schema::room_memberships;
+    use test::{Test, TestUser};
+
+    /// The reasons of the leave events of `user_id` in the timeline of `observer`'s sync.
+    fn leave_reasons(test: &Test, observer: &TestUser, room_id: &str, user_id: &str)
+    -> Vec<Option<String>> {
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&observer.token, options);
+        let events = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone();
+
+        events.iter()
+            .filter(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.member")
+            .filter(|event| event.get("sender").unwrap().as_str().unwrap() == user_id)
+            .filter(|event| event.pointer("/content/membership").unwrap() == "leave")
+            .map(|event| {
+                event.pointer("/content/reason")
+                    .and_then(|reason| reason.as_str())
+                    .map(|reason| reason.to_string())
+            })
+            .collect()
+    }
 
     #[test]
     fn join_own_public_room_via_join_endpoint() {
@@ -726,6 +887,56 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn kick_user_with_reason() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.kick_from_room(&alice.token, &room_id, &bob.id, Some("Spamming")).status,
+            Status::Ok
+        );
+
+        assert_eq!(
+            leave_reasons(&test, &alice, &room_id, &bob.id),
+            vec![Some("Spamming".to_string())]
+        );
+
+        let deactivate_path =
+            format!("/_matrix/client/r0/account/deactivate?access_token={}", bob.token);
+
+        test.check_empty_response(test.post(&deactivate_path, r#"{"erase": true}"#));
+        test.erase_users();
+
+        assert_eq!(leave_reasons(&test, &alice, &room_id, &bob.id), vec![None]);
+    }
+
+    #[test]
+    fn leave_room_with_reason() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let leave_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id,
+            bob.token
+        );
+
+        assert_eq!(test.post(&leave_path, r#"{"reason": "Bye"}"#).status, Status::Ok);
+        assert_eq!(
+            leave_reasons(&test, &alice, &room_id, &bob.id),
+            vec![Some("Bye".to_string())]
+        );
+    }
+
     #[test]
     fn kick_user_without_permissions() {
         let test = Test::new();
@@ -812,4 +1023,287 @@ mod tests {
             "The kickee is not currently in the room"
         );
     }
+
+    #[test]
+    fn knock_on_knockable_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_private_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.join_rules",
+            r#"{"join_rule": "knock"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let knock_path = format!("/_matrix/client/r0/knock/{}?access_token={}", room_id, bob.token);
+        let response = test.post(&knock_path, r"{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+        let knock_state = response
+            .json()
+            .pointer(&format!("/rooms/knock/{}/knock_state/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        let event_type = |event: &Value| event.get("type").unwrap().as_str().unwrap().to_string();
+        let member_state_keys: Vec<&str> = knock_state
+            .iter()
+            .filter(|event| event_type(*event) == "m.room.member")
+            .map(|event| event.get("state_key").unwrap().as_str().unwrap())
+            .collect();
+
+        // Only the knocker's own membership is shown, not those of the room's members.
+        assert_eq!(member_state_keys, vec![bob.id.as_str()]);
+        assert!(knock_state.iter().any(|event| event_type(event) == "m.room.join_rules"));
+        assert!(knock_state.iter().all(|event| {
+            event.get("event_id").is_none() && event.get("origin_server_ts").is_none()
+        }));
+
+        // The knock alone does not allow joining the room.
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.invite(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn reject_knock() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_private_room(&alice.token);
+
+        test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.join_rules",
+            r#"{"join_rule": "knock"}"#,
+            None,
+        );
+
+        let knock_path = format!("/_matrix/client/r0/knock/{}?access_token={}", room_id, bob.token);
+        assert_eq!(test.post(&knock_path, r"{}").status, Status::Ok);
+
+        let response = test.kick_from_room(&alice.token, &room_id, &bob.id, None);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&bob.token, options);
+
+        assert!(response.json().pointer(&format!("/rooms/knock/{}", room_id)).is_none());
+    }
+
+    #[test]
+    fn knock_on_invite_only_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_private_room(&alice.token);
+
+        let knock_path = format!("/_matrix/client/r0/knock/{}?access_token={}", room_id, bob.token);
+        let response = test.post(&knock_path, r"{}");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "This room does not allow knocking"
+        );
+    }
+
+    #[test]
+    fn concurrent_joins_create_one_membership() {
+        // The joins have to use connections of their own for their transactions to race.
+        let postgres_url = Test::fresh_database("ruma_test_concurrent_joins");
+        let test = Arc::new(Test::with_committing_database(&postgres_url, |config| {
+            config.db_pool_max_size = 2;
+        }));
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let room_join_path = format!("/_matrix/client/r0/rooms/{}/join?access_token={}", room_id, bob.token);
+        let barrier = Arc::new(Barrier::new(2));
+
+        let handles: Vec<_> = (0..2).map(|_| {
+            let test = test.clone();
+            let room_join_path = room_join_path.clone();
+            let barrier = barrier.clone();
+
+            thread::spawn(move || {
+                barrier.wait();
+                test.post(&room_join_path, r"{}")
+            })
+        }).collect();
+
+        for handle in handles {
+            let response = handle.join().unwrap();
+
+            assert_eq!(response.status, Status::Ok);
+            assert_eq!(response.json().get("room_id").unwrap().as_str().unwrap(), room_id);
+        }
+
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        let user_id = UserId::try_from(bob.id.as_str()).unwrap();
+
+        let memberships: Vec<RoomMembership> = test.with_connection(|connection| {
+            room_memberships::table
+                .filter(room_memberships::room_id.eq(&room_id))
+                .filter(room_memberships::user_id.eq(&user_id))
+                .get_results(connection)
+                .unwrap()
+        });
+
+        assert_eq!(memberships.len(), 1);
+        assert_eq!(memberships[0].membership, "join");
+    }
+
+    #[test]
+    fn banned_user_cannot_join() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let room_join_path = format!("/_matrix/client/r0/rooms/{}/join?access_token={}", room_id, bob.token);
+
+        assert_eq!(test.post(&room_join_path, r"{}").status, Status::Ok);
+
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        let user_id = UserId::try_from(bob.id.as_str()).unwrap();
+
+        test.with_connection(|connection| {
+            update(
+                room_memberships::table
+                    .filter(room_memberships::room_id.eq(&room_id))
+                    .filter(room_memberships::user_id.eq(&user_id))
+            )
+                .set(room_memberships::membership.eq("ban"))
+                .execute(connection)
+                .unwrap();
+        });
+
+        let response = test.post(&room_join_path, r"{}");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "User is banned from the room"
+        );
+    }
+
+    #[test]
+    fn blocklisted_invitee_cannot_be_invited() {
+        let test = Test::with_config(|config| {
+            config.spam_blocklist = vec!["^@spambot".to_string()];
+            config.spam_rejection_message = Some("No spam, please.".to_string());
+        });
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        assert!(test.register_user(
+            r#"{"username": "spambot", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = test.invite(&alice.token, &room_id, "@spambot:ruma.test");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("error").unwrap().as_str().unwrap(), "No spam, please.");
+
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        let user_id = UserId::try_from("@spambot:ruma.test").unwrap();
+        let membership = test.with_connection(|connection| {
+            RoomMembership::find(connection, &room_id, &user_id).unwrap()
+        });
+
+        assert!(membership.is_none());
+    }
+
+    #[test]
+    fn room_limit_counts_joined_rooms() {
+        let test = Test::with_config(|config| config.max_rooms_per_user = Some(2));
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let first_room_id = test.create_room(&alice.token);
+        test.create_room(&alice.token);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", alice.token),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_LIMIT_EXCEEDED");
+
+        let public_room_id = test.create_public_room(&bob.token);
+        let response = test.join_room(&alice.token, &public_room_id);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_LIMIT_EXCEEDED");
+
+        // Rejoining a room does not count as another room.
+        assert_eq!(test.join_room(&alice.token, &first_room_id).status, Status::Ok);
+
+        assert_eq!(test.leave_room(&alice.token, &first_room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&alice.token, &public_room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn membership_hook_fires_once_per_transition() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let transitions = Rc::new(RefCell::new(Vec::new()));
+        let recorded = transitions.clone();
+        let bob_id = bob.id.clone();
+
+        register_test_membership_hook(Box::new(move |_room_id, user_id, old, new| {
+            if user_id.to_string() == bob_id {
+                recorded.borrow_mut().push((old.map(|old| old.to_string()), new.to_string()));
+            }
+        }));
+
+        assert_eq!(test.invite(&alice.token, &room_id, &bob.id).status, Status::Ok);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.kick_from_room(&alice.token, &room_id, &bob.id, None).status, Status::Ok);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let expected = vec![
+            (None, "invite".to_string()),
+            (Some("invite".to_string()), "join".to_string()),
+            (Some("join".to_string()), "leave".to_string()),
+            (Some("leave".to_string()), "join".to_string()),
+        ];
+
+        assert_eq!(*transitions.borrow(), expected);
+    }
 }
@@ -1,25 +1,40 @@
-use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::{Formatter, Result as FmtResult};
 
 use bodyparser;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response, status};
+use iron::headers::UserAgent;
 use ruma_identifiers::UserId;
 use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
+use url::Url;
 
+use appservice::AppServiceRegistration;
 use authentication::{AuthParams, PasswordAuthParams};
 use config::Config;
+use crypto::generate_device_id;
 use db::DB;
 use error::ApiError;
 use middleware::{JsonRequest, MiddlewareChain};
 use models::access_token::AccessToken;
+use models::device::{Device, NewDevice};
+use models::failed_login::FailedLogin;
+use models::monthly_active_user::MonthlyActiveUser;
+use models::threepid::Threepid;
+use models::user::User;
+use models::threepid_session::MSISDN_MEDIUM;
 use modifier::SerializableResponse;
+use msisdn;
+use util::user_agent::device_display_name;
+use util::user_id;
 
 /// The `/login` endpoint.
 pub struct Login;
 
 #[derive(Clone, Debug, PartialEq)]
 enum LoginType {
+    /// The m.login.application_service type, with the token of an application service as the
+    /// access token.
+    ApplicationService,
     /// The m.login.password type.
     Password,
 }
@@ -37,8 +52,11 @@ impl<'de> Deserialize<'de> for LoginType {
 
             fn visit_str<E>(self, value: &str) -> Result<LoginType, E> where E: SerdeError {
                 match value {
+                    "m.login.application_service" => Ok(LoginType::ApplicationService),
                     "m.login.password" => Ok(LoginType::Password),
-                    _ => Err(SerdeError::custom("Currenlty only m.login.password is supported")),
+                    _ => Err(SerdeError::custom(
+                        "Only m.login.password and m.login.application_service are supported"
+                    )),
                 }
             }
         }
@@ -49,13 +67,32 @@ impl<'de> Deserialize<'de> for LoginType {
 
 #[derive(Clone, Debug, Deserialize)]
 struct LoginRequest {
-    /// The login type being used. Currently only "m.login.password" is supported.
+    /// The login type being used, either "m.login.password" or "m.login.application_service".
     #[serde(rename="type")]
     pub login_type: LoginType,
     /// The fully qualified user ID or just local part of the user ID, to log in.
-    pub user: String,
-    /// The user's password.
-    pub password: String,
+    pub user: Option<String>,
+    /// Identification information for the user, used instead of `user`.
+    pub identifier: Option<LoginIdentifier>,
+    /// The user's password, for "m.login.password".
+    pub password: Option<String>,
+    /// The ID of the device to log in with. A new device is created if not given.
+    pub device_id: Option<String>,
+    /// A display name for the device, if it is created. Derived from the `User-Agent` if not given.
+    pub initial_device_display_name: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct LoginIdentifier {
+    /// The identifier type, either "m.id.user" or "m.id.phone".
+    #[serde(rename="type")]
+    pub identifier_type: String,
+    /// The user ID or local part, for "m.id.user".
+    pub user: Option<String>,
+    /// The two-letter country code the phone number should be parsed as, for "m.id.phone".
+    pub country: Option<String>,
+    /// The phone number, for "m.id.phone".
+    pub phone: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -66,6 +103,8 @@ struct LoginResponse {
     pub home_server: String,
     /// The fully-qualified Matrix ID that has been registered.
     pub user_id: UserId,
+    /// The ID of the device the access token belongs to.
+    pub device_id: String,
 }
 
 middleware_chain!(Login, [JsonRequest]);
@@ -79,44 +118,210 @@ impl Handler for Login {
         };
 
         let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
 
-        let user_id = match UserId::try_from(&login_request.user) {
-            Ok(user_id) => {
-                if user_id.hostname().to_string() != config.domain {
-                    Err(ApiError::unauthorized("User cannot be identified by this homeserver".to_string()))?;
+        let user_id = match login_request.identifier {
+            Some(ref identifier) if identifier.identifier_type == "m.id.phone" => {
+                let country = identifier.country.as_ref()
+                    .ok_or_else(|| ApiError::missing_param("country"))?;
+                let phone = identifier.phone.as_ref()
+                    .ok_or_else(|| ApiError::missing_param("phone"))?;
+                let address = msisdn::normalize(country, phone)?;
+
+                match Threepid::find_by_address(&connection, MSISDN_MEDIUM, &address)? {
+                    Some(threepid) => threepid.user_id,
+                    None => Err(ApiError::unauthorized("Invalid credentials".to_string()))?,
                 }
-
-                user_id
+            }
+            Some(ref identifier) if identifier.identifier_type == "m.id.user" => {
+                match identifier.user {
+                    Some(ref user) => parse_user_id(user, &config.domain)?,
+                    None => Err(ApiError::missing_param("user"))?,
+                }
+            }
+            Some(_) => Err(ApiError::invalid_param("identifier", "Unsupported identifier type."))?,
+            None => match login_request.user {
+                Some(ref user) => parse_user_id(user, &config.domain)?,
+                None => Err(ApiError::missing_param("user"))?,
             },
-            Err(_) => UserId::try_from(&format!("@{}:{}", login_request.user, &config.domain))
-                        .map_err(ApiError::from)?,
         };
 
-        let auth_params = AuthParams::Password(PasswordAuthParams {
-            password: login_request.password,
-            user_id: user_id,
-        });
+        let registered_user = match login_request.login_type {
+            LoginType::ApplicationService => {
+                let url: Url = request.url.clone().into();
+                let token = url.query_pairs()
+                    .find(|&(ref key, _)| key == "access_token")
+                    .map(|(_, token)| token.into_owned())
+                    .ok_or_else(|| ApiError::unauthorized(None))?;
+
+                let registration =
+                    match AppServiceRegistration::find_by_token(&config.app_services, &token) {
+                        Some(registration) => registration,
+                        None => Err(ApiError::unauthorized("Unknown token".to_string()))?,
+                    };
+
+                if !registration.controls_user(&user_id, &config.domain)? {
+                    Err(ApiError::exclusive(None))?;
+                }
 
-        let connection = DB::from_request(request)?;
-        let registered_user = auth_params.authenticate(&connection)
-            .map_err(|_| ApiError::unauthorized("Invalid credentials".to_string()))?;
+                match User::find_active_user(&connection, &user_id)? {
+                    Some(user) => user,
+                    None => Err(ApiError::unauthorized("Invalid credentials".to_string()))?,
+                }
+            }
+            LoginType::Password => {
+                let password = login_request.password
+                    .ok_or_else(|| ApiError::missing_param("password"))?;
+
+                // Only the logins of registered users are counted, so that guessing user IDs
+                // does not fill up the failed logins.
+                let lockout = match config.login_lockout {
+                    Some(ref lockout)
+                        if User::find_registered_user(&connection, &user_id)?.is_some() => {
+                        Some(lockout)
+                    }
+                    _ => None,
+                };
+
+                if let Some(lockout) = lockout {
+                    FailedLogin::count_attempt(&connection, &user_id, lockout)?;
+                }
+
+                let auth_params = AuthParams::Password(PasswordAuthParams {
+                    password: password,
+                    user_id: user_id.clone(),
+                });
+
+                let registered_user = match auth_params.authenticate(&connection) {
+                    Ok(registered_user) => registered_user,
+                    Err(_) => Err(ApiError::unauthorized("Invalid credentials".to_string()))?,
+                };
+
+                if lockout.is_some() {
+                    FailedLogin::reset(&connection, &user_id)?;
+                }
+
+                registered_user
+            }
+        };
+
+        MonthlyActiveUser::check_limit(
+            &connection,
+            Some(&registered_user.id),
+            config.max_mau,
+            config.admin_contact.as_ref(),
+        )?;
+
+        let display_name = match login_request.initial_device_display_name {
+            Some(display_name) => display_name,
+            None => {
+                let user_agent = request.headers.get::<UserAgent>();
+
+                device_display_name(user_agent.map(|user_agent| user_agent.as_str()))
+            }
+        };
+
+        let new_device = NewDevice {
+            id: match login_request.device_id {
+                Some(device_id) => device_id,
+                None => generate_device_id()?,
+            },
+            user_id: registered_user.id.clone(),
+            display_name: display_name,
+        };
+
+        let device = Device::find_or_create(&connection, &new_device)?;
 
-        let access_token = AccessToken::create(&connection, &registered_user.id, &config.macaroon_secret_key)?;
+        let access_token = AccessToken::create(
+            &connection,
+            &registered_user.id,
+            Some(device.id.as_str()),
+            &config,
+        )?;
 
         let response = LoginResponse {
-            access_token: access_token.value,
+            access_token: access_token,
             home_server: config.domain.clone(),
             user_id: registered_user.id,
+            device_id: device.id,
         };
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
     }
 }
 
+/// Parse a fully qualified user ID or local part into a normalized user ID of this homeserver.
+fn parse_user_id(user: &str, domain: &str) -> Result<UserId, ApiError> {
+    let user_id = user_id::parse_user_id(user, domain, true, "user")?;
+
+    if user_id.hostname().to_string() != domain {
+        Err(ApiError::unauthorized("User cannot be identified by this homeserver".to_string()))?;
+    }
+
+    Ok(user_id)
+}
+
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use std::convert::TryFrom;
+
+    use diesel::LoadDsl;
+    use iron::headers::{Headers, UserAgent};
+    use iron::method::Method;
     use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use serde_json::from_str;
+
+    use config::LoginLockoutConfig;
+    use models::device::Device;
+    use models::failed_login::FailedLogin;
+    use models::presence_status::advance_clock;
+    use schema::failed_logins;
+    use test::{Response, Test};
+
+    /// A test server that locks accounts after three failed logins within a minute.
+    fn test_with_login_lockout() -> Test {
+        Test::with_config(|config| {
+            config.login_lockout = Some(LoginLockoutConfig {
+                max_failed_attempts: 3,
+                window: 60,
+            });
+        })
+    }
+
+    /// A test server with an application service controlling the users starting with *irc_*.
+    fn test_with_app_service() -> Test {
+        Test::with_config(|config| {
+            config.app_services.push(from_str(r#"{
+                "id": "irc",
+                "as_token": "as_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {"users": [{"exclusive": true, "regex": "@irc_.*:ruma\\.test"}]}
+            }"#).unwrap());
+        })
+    }
+
+    fn app_service_login(test: &Test, as_token: &str, user: &str) -> Response {
+        let body = format!(
+            r#"{{
+                "type": "m.login.application_service",
+                "identifier": {{"type": "m.id.user", "user": "{}"}}
+            }}"#,
+            user
+        );
+
+        test.post(&format!("/_matrix/client/r0/login?access_token={}", as_token), &body)
+    }
+
+    fn login(test: &Test, password: &str) -> Status {
+        let body = format!(
+            r#"{{"type": "m.login.password", "user": "carl", "password": "{}"}}"#,
+            password
+        );
+
+        test.post("/_matrix/client/r0/login", &body).status
+    }
 
     #[test]
     fn valid_credentials() {
@@ -177,4 +382,343 @@ mod tests {
 
         assert_eq!(response.status, Status::Forbidden);
     }
+
+    #[test]
+    fn login_with_phone_number() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        test.bind_msisdn(&carl, "US", "202-555-0123");
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{
+                "type": "m.login.password",
+                "identifier": {"type": "m.id.phone", "country": "US", "phone": "(202) 555-0123"},
+                "password": "secret"
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("user_id").unwrap().as_str().unwrap(), carl.id);
+    }
+
+    #[test]
+    fn login_with_unknown_phone_number() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{
+                "type": "m.login.password",
+                "identifier": {"type": "m.id.phone", "country": "US", "phone": "(202) 555-0123"},
+                "password": "secret"
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn mau_limit_blocks_inactive_users() {
+        let test = Test::with_config(|config| config.max_mau = Some(1));
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+        assert!(test.register_user(
+            r#"{"username": "alice", "password": "secret"}"#
+        ).status.is_success());
+
+        let carl_login = r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#;
+        let response = test.post("/_matrix/client/r0/login", carl_login);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let token = response.json().get("access_token").unwrap().as_str().unwrap().to_string();
+        let threepids_path = format!("/_matrix/client/r0/account/3pid?access_token={}", token);
+
+        // Carl's first authenticated request makes him a monthly active user.
+        assert_eq!(test.get(&threepids_path).status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "alice", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_RESOURCE_LIMIT_EXCEEDED"
+        );
+
+        // Carl stops counting 30 days after his request.
+        let retry_after = response.retry_after_secs();
+
+        assert!(retry_after <= 30 * 24 * 60 * 60);
+        assert!(retry_after > 30 * 24 * 60 * 60 - 60);
+        assert_eq!(
+            response.json().get("admin_contact").unwrap().as_str().unwrap(),
+            "mailto:admin@ruma.test"
+        );
+
+        assert_eq!(test.get(&threepids_path).status, Status::Ok);
+        assert_eq!(test.post("/_matrix/client/r0/login", carl_login).status, Status::Ok);
+    }
+
+    #[test]
+    fn mau_limit_blocks_requests_of_inactive_users() {
+        let test = Test::with_config(|config| config.max_mau = Some(1));
+
+        // Registering does not make a user active, so both users get an access token.
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let threepids_path = |token: &str| {
+            format!("/_matrix/client/r0/account/3pid?access_token={}", token)
+        };
+
+        assert_eq!(test.get(&threepids_path(&carl.token)).status, Status::Ok);
+
+        let response = test.get(&threepids_path(&alice.token));
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_RESOURCE_LIMIT_EXCEEDED"
+        );
+
+        assert_eq!(test.get(&threepids_path(&carl.token)).status, Status::Ok);
+    }
+
+    #[test]
+    fn device_is_named_after_user_agent() {
+        let test = Test::new();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let mut headers = Headers::new();
+
+        headers.set(UserAgent(
+            "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0".to_string()
+        ));
+
+        let response = test.request_with_headers(
+            Method::Post,
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+            headers,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let device_id = response.json().get("device_id").unwrap().as_str().unwrap().to_string();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let device = test.with_connection(|connection| {
+            Device::find(connection, &user_id, &device_id).unwrap().unwrap()
+        });
+
+        assert_eq!(device.display_name, "Firefox on Linux");
+    }
+
+    #[test]
+    fn initial_device_display_name_takes_precedence() {
+        let test = Test::new();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{
+                "type": "m.login.password",
+                "user": "carl",
+                "password": "secret",
+                "device_id": "CARLSPHONE",
+                "initial_device_display_name": "Carl's phone"
+            }"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("device_id").unwrap().as_str().unwrap(), "CARLSPHONE");
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let device = test.with_connection(|connection| {
+            Device::find(connection, &user_id, "CARLSPHONE").unwrap().unwrap()
+        });
+
+        assert_eq!(device.display_name, "Carl's phone");
+    }
+
+    #[test]
+    fn repeated_wrong_passwords_lock_the_account() {
+        let test = test_with_login_lockout();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        for _ in 0..3 {
+            assert_eq!(login(&test, "guess"), Status::Forbidden);
+        }
+
+        assert_eq!(login(&test, "guess"), Status::TooManyRequests);
+        assert_eq!(login(&test, "secret"), Status::TooManyRequests);
+    }
+
+    #[test]
+    fn failed_logins_are_counted_for_the_normalized_user_id() {
+        let test = test_with_login_lockout();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        for user in &["Carl", "@CARL:ruma.test", "@Carl:RUMA.TEST"] {
+            let body = format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "guess"}}"#,
+                user
+            );
+
+            assert_eq!(test.post("/_matrix/client/r0/login", &body).status, Status::Forbidden);
+        }
+
+        assert_eq!(login(&test, "secret"), Status::TooManyRequests);
+    }
+
+    #[test]
+    fn locked_account_is_unlocked_after_the_window() {
+        let test = test_with_login_lockout();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        for _ in 0..3 {
+            assert_eq!(login(&test, "guess"), Status::Forbidden);
+        }
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::TooManyRequests);
+
+        let retry_after = response.retry_after_secs();
+
+        assert!(retry_after <= 60);
+        assert!(retry_after > 50);
+
+        advance_clock(61_000);
+
+        assert_eq!(login(&test, "secret"), Status::Ok);
+    }
+
+    #[test]
+    fn failures_of_a_passed_window_are_not_counted() {
+        let test = test_with_login_lockout();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        assert_eq!(login(&test, "guess"), Status::Forbidden);
+        assert_eq!(login(&test, "guess"), Status::Forbidden);
+
+        advance_clock(61_000);
+
+        for _ in 0..3 {
+            assert_eq!(login(&test, "guess"), Status::Forbidden);
+        }
+
+        assert_eq!(login(&test, "secret"), Status::TooManyRequests);
+    }
+
+    #[test]
+    fn successful_login_resets_failed_attempts() {
+        let test = test_with_login_lockout();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        assert_eq!(login(&test, "guess"), Status::Forbidden);
+        assert_eq!(login(&test, "guess"), Status::Forbidden);
+        assert_eq!(login(&test, "secret"), Status::Ok);
+        assert_eq!(login(&test, "guess"), Status::Forbidden);
+        assert_eq!(login(&test, "guess"), Status::Forbidden);
+        assert_eq!(login(&test, "secret"), Status::Ok);
+    }
+
+    #[test]
+    fn failed_logins_of_unknown_users_are_not_recorded() {
+        let test = test_with_login_lockout();
+
+        for _ in 0..5 {
+            assert_eq!(login(&test, "guess"), Status::Forbidden);
+        }
+
+        let recorded: Vec<FailedLogin> = test.with_connection(|connection| {
+            failed_logins::table.load(connection).unwrap()
+        });
+
+        assert!(recorded.is_empty());
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        assert_eq!(login(&test, "secret"), Status::Ok);
+    }
+
+    #[test]
+    fn app_service_logs_in_as_user_in_its_namespace() {
+        let test = test_with_app_service();
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = app_service_login(&test, "as_secret", "irc_carl");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("access_token").is_some());
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@irc_carl:ruma.test"
+        );
+    }
+
+    #[test]
+    fn app_service_cannot_log_in_outside_its_namespace() {
+        let test = test_with_app_service();
+
+        assert!(test.register_user(
+            r#"{"username": "carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = app_service_login(&test, "as_secret", "carl");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+    }
+
+    #[test]
+    fn app_service_login_requires_its_token() {
+        let test = test_with_app_service();
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_carl", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = app_service_login(&test, "guess", "irc_carl");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
@@ -2,11 +2,15 @@ use iron::{Chain, Handler, IronResult, Request, Response};
 use iron::status::Status;
 
 use db::DB;
+use error::ApiError;
 use middleware::{AccessTokenAuth, MiddlewareChain};
 use models::access_token::AccessToken;
 use modifier::EmptyResponse;
 
 /// The `/logout` endpoint.
+///
+/// Application services authenticate with their `as_token`, which cannot be revoked, so they
+/// cannot log out.
 pub struct Logout;
 
 middleware_chain!(Logout, [AccessTokenAuth]);
@@ -15,8 +19,12 @@ impl Handler for Logout {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
 
-        let access_token = request.extensions.get_mut::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token");
+        let access_token = match request.extensions.get_mut::<AccessToken>() {
+            Some(access_token) => access_token,
+            None => Err(ApiError::unauthorized(
+                "Application services cannot log out.".to_string()
+            ))?,
+        };
 
         access_token.revoke(&connection)?;
 
@@ -27,6 +35,7 @@ impl Handler for Logout {
 #[cfg(test)]
 mod tests {
     use iron::status::Status;
+    use serde_json::from_str;
 
     use test::Test;
 
@@ -41,4 +50,25 @@ mod tests {
         assert!(test.post(&login_path, "{}").status.is_success());
         assert_eq!(test.post(&login_path, "{}").status, Status::Forbidden);
     }
+
+    #[test]
+    fn app_service_cannot_log_out() {
+        let test = Test::with_config(|config| {
+            config.app_services.push(from_str(r#"{
+                "id": "irc",
+                "as_token": "as_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {"users": [{"exclusive": true, "regex": "@irc_.*:ruma\\.test"}]}
+            }"#).unwrap());
+        });
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_bot", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = test.post("/_matrix/client/r0/logout?access_token=as_secret", "{}");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
@@ -6,24 +6,71 @@ pub use self::account::{
     PutAccountData,
     PutRoomAccountData,
 };
-pub use self::directory::{DeleteRoomAlias, GetRoomAlias, PutRoomAlias};
+pub use self::admin::{
+    BatchSendEvents,
+    BulkResolveRoomAliases,
+    GetMembershipTransitions,
+    GetMonthlyActiveUsers,
+    GetRegistrationNonce,
+    GetRoomExport,
+    GetServerVersion,
+    GetUserDataExport,
+    GetUserErasure,
+    GetUserPushers,
+    SharedSecretRegister,
+};
+pub use self::directory::{
+    DeleteRoomAlias,
+    GetRoomAlias,
+    GetRoomAliases,
+    GetRoomVisibility,
+    PutRoomAlias,
+    PutRoomVisibility,
+};
 pub use self::event_creation::{SendMessageEvent, StateMessageEvent};
 pub use self::filter::{GetFilter, PostFilter};
-pub use self::join::{InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom};
+pub use self::join::{
+    InviteToRoom,
+    JoinRoom,
+    JoinRoomWithIdOrAlias,
+    KickFromRoom,
+    KnockOnRoom,
+    LeaveRoom,
+};
 pub use self::login::Login;
 pub use self::logout::Logout;
 pub use self::members::Members;
 pub use self::presence::{GetPresenceList, GetPresenceStatus, PostPresenceList, PutPresenceStatus};
 pub use self::pushers::{GetPushers, SetPushers};
 pub use self::profile::{GetAvatarUrl, GetDisplayName, Profile, PutAvatarUrl, PutDisplayName};
-pub use self::registration::Register;
+pub use self::registration::{Register, RegisterAvailable};
 pub use self::room_creation::CreateRoom;
+pub use self::room_events::{GetEventContext, GetRoomEvent};
 pub use self::room_info::{GetStateEvent, RoomState};
-pub use self::sync::Sync;
+pub use self::room_keys::{
+    CreateKeyBackupVersion,
+    DeleteRoomKeys,
+    GetKeyBackupVersion,
+    GetRoomKeys,
+    PutRoomKeys,
+    UpdateKeyBackupVersion,
+};
+pub use self::sync::{RoomInitialSync, Sync};
 pub use self::tags::{DeleteTag, GetTags, PutTag};
+pub use self::threepid::{
+    AddThreepid,
+    GetThreepids,
+    RequestEmailAccountToken,
+    RequestEmailRegistrationToken,
+    RequestMsisdnAccountToken,
+    RequestMsisdnRegistrationToken,
+    RequestPasswordResetEmailToken,
+    SubmitThreepidToken,
+};
 pub use self::versions::Versions;
 
 mod account;
+mod admin;
 mod directory;
 mod event_creation;
 mod filter;
@@ -36,7 +83,10 @@ mod profile;
 mod pushers;
 mod registration;
 mod room_creation;
+mod room_events;
 mod room_info;
+mod room_keys;
 mod sync;
 mod tags;
+mod threepid;
 mod versions;
@@ -1,10 +1,13 @@
 //! Endpoints for presence.
 
+use std::collections::HashMap;
+
 use bodyparser;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, IronError, Plugin, Request, Response};
 use ruma_identifiers::UserId;
 use ruma_events::presence::PresenceState;
+use url::Url;
 
 use config::Config;
 use db::DB;
@@ -25,6 +28,8 @@ struct PutPresenceStatusRequest {
     status_msg: Option<String>,
     /// The new presence state. One of: ["online", "offline", "unavailable"]
     presence: PresenceState,
+    /// Whether the presence should be kept when the user is idle, e.g. for bots.
+    sticky: Option<bool>,
 }
 
 middleware_chain!(PutPresenceStatus, [UserIdParam, JsonRequest, AccessTokenAuth]);
@@ -52,12 +57,22 @@ impl Handler for PutPresenceStatus {
             return Err(IronError::from(error));
         }
 
+        if let Some(ref status_msg) = put_presence_status_request.status_msg {
+            if status_msg.chars().count() > config.max_presence_status_length {
+                Err(ApiError::invalid_param(
+                    "status_msg",
+                    &format!("Must not be longer than {} characters.", config.max_presence_status_length),
+                ))?;
+            }
+        }
+
         PresenceStatus::upsert(
             &connection,
             &config.domain,
             &user_id,
             Some(put_presence_status_request.presence),
-            put_presence_status_request.status_msg
+            put_presence_status_request.status_msg,
+            put_presence_status_request.sticky
         )?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
@@ -91,13 +106,26 @@ impl Handler for GetPresenceStatus {
             .expect("AccessTokenAuth should ensure a user").clone();
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
-        if user.id != user_id {
-            let rooms = RoomMembership::find_common_rooms(
+        // A presence list entry only counts while it is visible in the presence list and sync,
+        // e.g. with `presence_requires_consent` only if the observed user has listed the
+        // requester in turn.
+        let is_listed = user.id != user_id &&
+            PresenceList::find_visible_observed_users(
+                &connection,
+                &user.id,
+                &config.presence_suppressed_rooms,
+                config.presence_requires_consent
+            )?.contains(&user_id);
+
+        if user.id != user_id && !is_listed {
+            let rooms = RoomMembership::find_presence_sharing_rooms(
                 &connection,
                 &user.id,
                 &user_id,
-                "join"
+                config.presence_max_room_size,
+                &config.presence_suppressed_rooms
             )?;
             if rooms.is_empty() {
                 Err(ApiError::unauthorized(
@@ -113,20 +141,23 @@ impl Handler for GetPresenceStatus {
             )?,
         };
 
+        let response = GetPresenceStatusResponse::from(status);
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+impl From<PresenceStatus> for GetPresenceStatusResponse {
+    fn from(status: PresenceStatus) -> Self {
         let presence_state: PresenceState = status.presence.parse()
             .expect("Database insert should ensure a PresenceState");
 
-        let now = get_now();
-        let last_active_ago = now - status.updated_at.0;
-
-        let response = GetPresenceStatusResponse {
+        GetPresenceStatusResponse {
             status_msg: status.status_msg,
             currently_active: PresenceState::Online == presence_state,
-            last_active_ago: last_active_ago,
+            last_active_ago: get_now() - status.updated_at.0,
             presence: presence_state,
-        };
-
-        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+        }
     }
 }
 
@@ -156,6 +187,7 @@ impl Handler for PostPresenceList {
             .expect("AccessTokenAuth should ensure a user").clone();
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -169,7 +201,9 @@ impl Handler for PostPresenceList {
             &connection,
             &user_id,
             &put_presence_list_request.invite,
-            put_presence_list_request.drop
+            put_presence_list_request.drop,
+            config.max_presence_list_size,
+            &config.presence_suppressed_rooms,
         )?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
@@ -177,6 +211,9 @@ impl Handler for PostPresenceList {
 }
 
 /// The GET `/presence/list/:user_id` endpoint with response of `Vec<PresenceEvent>`.
+///
+/// With `?format=status`, the response is a map of the user IDs to their presence status in the
+/// format of the GET `/presence/:user_id/status` endpoint instead.
 pub struct GetPresenceList;
 
 middleware_chain!(GetPresenceList, [UserIdParam, AccessTokenAuth]);
@@ -186,26 +223,109 @@ impl Handler for GetPresenceList {
         let user_id = request.extensions.get::<UserIdParam>()
             .expect("UserIdParam should ensure a UserId").clone();
 
-        let connection = DB::from_request(request)?;
+        let url: Url = request.url.clone().into();
+        let format = url.query_pairs()
+            .find(|&(ref key, _)| key == "format")
+            .map(|(_, value)| value.into_owned());
 
-        let (_, events) = PresenceList::find_events_by_uid(
-            &connection,
-            &user_id,
-            None
-        )?;
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
-        Ok(Response::with((Status::Ok, SerializableResponse(events))))
+        match format.as_ref().map(String::as_str) {
+            None | Some("event") => {
+                let (_, events) = PresenceList::find_events_by_uid(
+                    &connection,
+                    &user_id,
+                    None,
+                    &config.presence_suppressed_rooms,
+                    config.presence_requires_consent
+                )?;
+
+                Ok(Response::with((Status::Ok, SerializableResponse(events))))
+            }
+            Some("status") => {
+                let statuses: HashMap<UserId, GetPresenceStatusResponse> =
+                    PresenceList::find_statuses_by_uid(
+                        &connection,
+                        &user_id,
+                        None,
+                        &config.presence_suppressed_rooms,
+                        config.presence_requires_consent
+                    )?
+                    .into_iter()
+                    .map(|status| (status.user_id.clone(), GetPresenceStatusResponse::from(status)))
+                    .collect();
+
+                Ok(Response::with((Status::Ok, SerializableResponse(statuses))))
+            }
+            Some(_) => Err(IronError::from(
+                ApiError::invalid_param("format", "Must be \"event\" or \"status\".")
+            )),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
     use std::thread;
     use std::time::Duration;
 
+    use diesel::{ExecuteDsl, ExpressionMethods, FindDsl, insert, update};
     use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::{Map, Value, from_str};
+
+    use config::DefaultPowerLevels;
+    use models::federation_queue::FederationQueueEntry;
+    use models::presence_status::{PresenceStatus, advance_clock};
+    use models::pusher::{PusherData, PusherOptions};
+    use models::room::{CreationOptions, NewRoom, Room, RoomPreset};
+    use models::room_membership::{NewRoomMembership, RoomMembership, RoomMembershipOptions};
+    use push::sent_notifications;
+    use query::SyncOptions;
+    use schema::{room_memberships, rooms};
+    use test::{MAX_PRESENCE_LIST_SIZE, MAX_PRESENCE_STATUS_LENGTH, PRESENCE_IDLE_TIMEOUT, Test, TestUser};
+
+    /// The ID of a room a test lists in the server configuration before creating it.
+    const LOBBY_ID: &'static str = "!lobby:ruma.test";
+
+    /// Create a public room with the given ID and `user` as its only member.
+    fn create_room_with_id(test: &Test, user: &TestUser, room_id: &str) {
+        let room_id = RoomId::try_from(room_id).unwrap();
+        let user_id = UserId::try_from(user.id.as_str()).unwrap();
+
+        let new_room = NewRoom {
+            id: room_id.clone(),
+            user_id: user_id.clone(),
+            public: true,
+            room_type: None,
+        };
+        let creation_options = CreationOptions {
+            alias: None,
+            default_power_levels: DefaultPowerLevels::default(),
+            default_state: Vec::new(),
+            federate: None,
+            initial_state: None,
+            invite_list: None,
+            name: None,
+            preset: RoomPreset::PublicChat,
+            creation_content: Map::new(),
+            topic: None,
+        };
+        let membership_options = RoomMembershipOptions {
+            room_id: room_id,
+            user_id: user_id.clone(),
+            sender: user_id,
+            membership: "join".to_string(),
+            reason: None,
+        };
 
-    use test::Test;
+        test.with_connection(|connection| {
+            Room::create(connection, &new_room, "ruma.test", &creation_options).unwrap();
+            RoomMembership::create(connection, "ruma.test", membership_options).unwrap();
+        });
+    }
 
     #[test]
     fn basic_presence_status() {
@@ -263,6 +383,202 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn large_rooms_do_not_share_presence() {
+        let test = Test::with_config(|config| config.presence_max_room_size = Some(100));
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        let room = test.with_connection(|connection| Room::find(connection, &room_id).unwrap());
+        assert_eq!(room.unwrap().joined_member_count, 2);
+
+        // Pretend that many more users have joined.
+        test.with_connection(|connection| {
+            update(rooms::table.find(&room_id))
+                .set(rooms::joined_member_count.eq(5000))
+                .execute(connection)
+                .unwrap();
+        });
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            bob.id,
+            alice.token
+        );
+        assert_eq!(test.get(&presence_status_path).status, Status::Forbidden);
+
+        let sync_options = || SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, sync_options());
+        let events = response.json().pointer("/presence/events").unwrap().as_array().unwrap();
+        assert!(events.is_empty());
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, bob.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.get(&presence_status_path).status, Status::Ok);
+
+        let response = test.sync(&alice.token, sync_options());
+        let events = response.json().pointer("/presence/events").unwrap().as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pointer("/content/user_id").unwrap().as_str().unwrap(), bob.id);
+    }
+
+    #[test]
+    fn presence_list_entries_need_consent_for_the_status() {
+        let test = Test::with_config(|config| {
+            config.presence_max_room_size = Some(100);
+            config.presence_requires_consent = true;
+        });
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let alice_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let response = test.post(
+            &alice_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, bob.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        // Pretend that many more users have joined, so the room does not share presence.
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        test.with_connection(|connection| {
+            update(rooms::table.find(&room_id))
+                .set(rooms::joined_member_count.eq(5000))
+                .execute(connection)
+                .unwrap();
+        });
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            bob.id,
+            alice.token
+        );
+
+        // Bob has not put Alice on his own presence list.
+        assert_eq!(test.get(&presence_status_path).status, Status::Forbidden);
+
+        let bob_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            bob.id,
+            bob.token
+        );
+        let response = test.post(
+            &bob_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, alice.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.get(&presence_status_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn presence_is_not_federated_to_servers_denied_by_the_acl() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.server_acl",
+            r#"{"allow": ["*"], "deny": ["evil.test"]}"#,
+            None
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        // Pretend that users of two remote servers have joined over federation.
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        test.with_connection(|connection| {
+            for remote_user_id in &["@carl:remote.test", "@dan:evil.test"] {
+                let remote_user_id = UserId::try_from(*remote_user_id).unwrap();
+                let membership = NewRoomMembership {
+                    event_id: EventId::new("ruma.test").unwrap(),
+                    room_id: room_id.clone(),
+                    user_id: remote_user_id.clone(),
+                    sender: remote_user_id,
+                    membership: "join".to_string(),
+                };
+
+                insert(&membership).into(room_memberships::table).execute(connection).unwrap();
+            }
+        });
+
+        test.update_presence(&alice.token, &alice.id, r#"{"presence":"online"}"#);
+
+        test.with_connection(|connection| {
+            let pending = |destination| {
+                FederationQueueEntry::find_pending(connection, destination, 0).unwrap()
+            };
+
+            assert_eq!(pending("remote.test").len(), 1);
+            assert!(pending("evil.test").is_empty());
+        });
+    }
+
+    #[test]
+    fn presence_edus_carry_the_time_since_the_last_activity() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        // Pretend that a remote user has joined over federation.
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+        test.with_connection(|connection| {
+            let remote_user_id = UserId::try_from("@carl:remote.test").unwrap();
+            let membership = NewRoomMembership {
+                event_id: EventId::new("ruma.test").unwrap(),
+                room_id: room_id.clone(),
+                user_id: remote_user_id.clone(),
+                sender: remote_user_id,
+                membership: "join".to_string(),
+            };
+
+            insert(&membership).into(room_memberships::table).execute(connection).unwrap();
+        });
+
+        test.update_presence(&alice.token, &alice.id, r#"{"presence":"online"}"#);
+
+        let idle_timeout = PRESENCE_IDLE_TIMEOUT as i64 * 1000;
+        advance_clock(idle_timeout + 500);
+
+        let edus = test.with_connection(|connection| {
+            PresenceStatus::set_idle_offline(connection, "ruma.test", idle_timeout).unwrap();
+
+            FederationQueueEntry::find_pending(connection, "remote.test", 0).unwrap()
+        });
+
+        assert_eq!(edus.len(), 1);
+
+        let edu = from_str::<Value>(&edus[0].payload).unwrap();
+        let push = edu.pointer("/content/push/0").unwrap();
+
+        assert_eq!(push.get("presence").unwrap().as_str().unwrap(), "offline");
+        assert_eq!(push.get("currently_active").unwrap().as_bool().unwrap(), false);
+        assert!(push.get("last_active_ago").unwrap().as_i64().unwrap() >= idle_timeout + 500);
+    }
+
     #[test]
     fn not_found_presence_status() {
         let test = Test::new();
@@ -277,6 +593,31 @@ mod tests {
         assert_eq!(response.status, Status::NotFound);
     }
 
+    #[test]
+    fn presence_status_message_too_long() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            alice.id,
+            alice.token
+        );
+
+        let status_msg: String = (0..MAX_PRESENCE_STATUS_LENGTH).map(|_| 'ä').collect();
+        let body = format!(r#"{{"presence":"online", "status_msg": "{}"}}"#, status_msg);
+        let response = test.put(&presence_status_path, &body);
+        assert_eq!(response.status, Status::Ok);
+
+        let body = format!(r#"{{"presence":"online", "status_msg": "{}!"}}"#, status_msg);
+        let response = test.put(&presence_status_path, &body);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
     #[test]
     fn forbidden_put_presence_status() {
         let test = Test::new();
@@ -346,6 +687,164 @@ mod tests {
         );
     }
 
+    #[test]
+    fn presence_list_in_status_format() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let alice = test.create_user();
+
+        assert_eq!(test.join_room(&alice.token, &room_id).status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, carl.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(
+            &carl.token,
+            &carl.id,
+            r#"{"presence":"online", "status_msg": "Busy"}"#
+        );
+
+        let response = test.get(&format!("{}&format=status", presence_list_path));
+        assert_eq!(response.status, Status::Ok);
+
+        let statuses = response.json().as_object().unwrap();
+        assert_eq!(statuses.len(), 1);
+
+        let status = statuses.get(&carl.id).unwrap();
+        Test::assert_json_keys(
+            status,
+            vec!["currently_active", "last_active_ago", "presence", "status_msg"]
+        );
+        assert_eq!(status.get("presence").unwrap().as_str().unwrap(), "online");
+        assert_eq!(status.get("status_msg").unwrap().as_str().unwrap(), "Busy");
+        assert_eq!(status.get("currently_active").unwrap().as_bool().unwrap(), true);
+
+        let response = test.get(&format!("{}&format=event", presence_list_path));
+        assert_eq!(response.json().as_array().unwrap().len(), 1);
+
+        let response = test.get(&format!("{}&format=flat", presence_list_path));
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn presence_list_withholds_presence_without_consent() {
+        let test = Test::with_config(|config| config.presence_requires_consent = true);
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, bob.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        // Bob has not put Alice on his own presence list.
+        let response = test.get(&presence_list_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn presence_list_shows_presence_with_consent() {
+        let test = Test::with_config(|config| config.presence_requires_consent = true);
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let alice_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let response = test.post(
+            &alice_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, bob.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let bob_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            bob.id,
+            bob.token
+        );
+        let response = test.post(
+            &bob_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, alice.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let response = test.get(&alice_list_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let events = response.json().as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pointer("/content/user_id").unwrap().as_str().unwrap(), bob.id);
+    }
+
+    #[test]
+    fn presence_list_entries_are_suspended_without_a_shared_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite": ["{}"], "drop": []}}"#, bob.id)
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let response = test.get(&presence_list_path);
+        assert_eq!(response.json().as_array().unwrap().len(), 1);
+
+        // The entry is kept, but it does not show Bob's presence while he is not in the room.
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&presence_list_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().as_array().unwrap().is_empty());
+
+        let status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            bob.id,
+            alice.token
+        );
+        assert_eq!(test.get(&status_path).status, Status::Forbidden);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&presence_list_path);
+        let events = response.json().as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pointer("/content/user_id").unwrap().as_str().unwrap(), bob.id);
+        assert_eq!(test.get(&status_path).status, Status::Ok);
+    }
+
     #[test]
     fn forbidden_presence_list_no_shared_room() {
         let test = Test::new();
@@ -362,6 +861,57 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn suppressed_rooms_do_not_allow_presence_list_invites() {
+        let test = Test::with_config(|config| {
+            config.presence_suppressed_rooms = vec![RoomId::try_from(LOBBY_ID).unwrap()];
+        });
+        let alice = test.create_user();
+        let bob = test.create_user();
+        create_room_with_id(&test, &alice, LOBBY_ID);
+        assert_eq!(test.join_room(&bob.token, LOBBY_ID).status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let body = format!(r#"{{"invite": ["{}"], "drop": []}}"#, bob.id);
+
+        assert_eq!(test.post(&presence_list_path, &body).status, Status::Forbidden);
+
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        assert_eq!(test.post(&presence_list_path, &body).status, Status::Ok);
+    }
+
+    #[test]
+    fn suppressed_rooms_do_not_share_presence_status() {
+        let test = Test::with_config(|config| {
+            config.presence_suppressed_rooms = vec![RoomId::try_from(LOBBY_ID).unwrap()];
+        });
+        let alice = test.create_user();
+        let bob = test.create_user();
+        create_room_with_id(&test, &alice, LOBBY_ID);
+        assert_eq!(test.join_room(&bob.token, LOBBY_ID).status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            bob.id,
+            alice.token
+        );
+
+        assert_eq!(test.get(&presence_status_path).status, Status::Forbidden);
+
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        assert_eq!(test.get(&presence_status_path).status, Status::Ok);
+    }
+
     #[test]
     fn invitee_does_not_exist_presence_list() {
         let test = Test::new();
@@ -481,4 +1031,173 @@ mod tests {
         assert!(last_active_ago > 4_000);
         assert!(last_active_ago < 4_500);
     }
+
+    #[test]
+    fn presence_list_limit() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let users: Vec<TestUser> = (0..MAX_PRESENCE_LIST_SIZE + 1).map(|_| {
+            let user = test.create_user();
+            assert_eq!(test.join_room(&user.token, &room_id).status, Status::Ok);
+            user
+        }).collect();
+        let user_ids: Vec<String> = users.iter().map(|user| format!(r#""{}""#, user.id)).collect();
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id,
+            alice.token
+        );
+
+        let body = format!(r#"{{"invite":[{}], "drop": []}}"#, user_ids.join(", "));
+        let response = test.post(&presence_list_path, &body);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+
+        let body = format!(
+            r#"{{"invite":[{}], "drop": []}}"#,
+            user_ids[..MAX_PRESENCE_LIST_SIZE].join(", ")
+        );
+        let response = test.post(&presence_list_path, &body);
+        assert_eq!(response.status, Status::Ok);
+
+        // Dropping a user at the same time makes room for a new one.
+        let body = format!(
+            r#"{{"invite":[{}], "drop": [{}]}}"#,
+            user_ids[MAX_PRESENCE_LIST_SIZE],
+            user_ids[0]
+        );
+        let response = test.post(&presence_list_path, &body);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn sticky_presence_stays_online_when_idle() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        test.update_presence(&alice.token, &alice.id, r#"{"presence":"online", "sticky": true}"#);
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let idle_timeout = PRESENCE_IDLE_TIMEOUT as i64 * 1000;
+        advance_clock(idle_timeout + 500);
+
+        let marked_offline = test.with_connection(|connection| {
+            PresenceStatus::set_idle_offline(connection, "ruma.test", idle_timeout).unwrap()
+        });
+
+        assert_eq!(marked_offline, 1);
+
+        let alice_presence_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            alice.id,
+            bob.token
+        );
+        let response = test.get(&alice_presence_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("presence").unwrap().as_str().unwrap(), "online");
+        assert!(response.json().get("sticky").is_none());
+
+        let bob_presence_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            bob.id,
+            alice.token
+        );
+        let response = test.get(&bob_presence_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("presence").unwrap().as_str().unwrap(), "offline");
+    }
+
+    #[test]
+    fn user_id_param_is_case_insensitive() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let token = response.json().get("access_token").unwrap().as_str().unwrap().to_string();
+
+        test.update_presence(&token, "@CARL:ruma.test", r#"{"presence":"online"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/presence/@carl:ruma.test/status?access_token={}",
+            token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("presence").unwrap().as_str().unwrap(), "online");
+    }
+
+    #[test]
+    fn user_id_param_with_space_is_rejected() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/presence/@car%20l:ruma.test/status?access_token={}",
+                alice.token
+            ),
+            r#"{"presence":"online"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn user_id_param_accepts_localpart_if_enabled() {
+        let test = Test::with_config(|config| config.localpart_user_id_params = true);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let token = response.json().get("access_token").unwrap().as_str().unwrap().to_string();
+
+        // Fails the test unless the request succeeds.
+        test.update_presence(&token, "Carl", r#"{"presence":"online"}"#);
+    }
+
+    #[test]
+    fn presence_updates_are_never_pushed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.ruma.test/_matrix/push/v1/notify".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "io.ruma.test".to_string(),
+            profile_tag: None,
+            pushkey: "pushkey".to_string(),
+            app_display_name: "Ruma Test".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&bob.token, options).status, Status::Ok);
+
+        test.update_presence(&alice.token, &alice.id, r#"{"presence":"online"}"#);
+        test.update_presence(&alice.token, &alice.id, r#"{"presence":"unavailable"}"#);
+
+        assert_eq!(test.deliver_push_notifications(), 0);
+        assert!(sent_notifications().is_empty());
+    }
 }
@@ -220,6 +220,18 @@ impl Handler for PutDisplayName {
             return Err(IronError::from(error));
         }
 
+        if let Some(ref displayname) = displayname_request.displayname {
+            if displayname.chars().count() > config.max_displayname_length {
+                Err(ApiError::invalid_param(
+                    "displayname",
+                    &format!(
+                        "Must not be longer than {} characters.",
+                        config.max_displayname_length
+                    ),
+                ))?;
+            }
+        }
+
         DataProfile::update_displayname(
             &connection,
             &config.domain,
@@ -235,7 +247,7 @@ impl Handler for PutDisplayName {
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use test::{MAX_DISPLAYNAME_LENGTH, Test};
     use iron::status::Status;
     use query::SyncOptions;
 
@@ -349,6 +361,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn put_too_long_displayname() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let put_displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id,
+            carl.token
+        );
+
+        let displayname: String = (0..MAX_DISPLAYNAME_LENGTH).map(|_| 'ä').collect();
+        let body = format!(r#"{{"displayname": "{}"}}"#, displayname);
+        let response = test.put(&put_displayname_path, &body);
+        assert_eq!(response.status, Status::Ok);
+
+        let body = format!(r#"{{"displayname": "{}!"}}"#, displayname);
+        let response = test.put(&put_displayname_path, &body);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
     #[test]
     fn put_displayname_unauthorized() {
         let test = Test::new();
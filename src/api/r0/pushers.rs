@@ -2,7 +2,7 @@
 use bodyparser;
 use iron::{Chain, Handler, IronError, IronResult, Request, Response, Plugin};
 use iron::status::Status;
-use serde_json::{Value, from_value};
+use serde_json::{Value, from_value, to_value};
 
 use db::DB;
 use error::{ApiError, MapApiError};
@@ -16,7 +16,9 @@ pub struct GetPushers;
 
 #[derive(Clone, Debug, Serialize)]
 struct GetPushersResponse {
-    pushers: Vec<PusherOptions>,
+    /// The pushers, each with the non-standard `io.ruma.failing` key that tells whether the last
+    /// delivery of a notification to it failed.
+    pushers: Vec<Value>,
 }
 
 middleware_chain!(GetPushers, [AccessTokenAuth]);
@@ -30,8 +32,21 @@ impl Handler for GetPushers {
 
         let pushers = Pusher::find_by_uid(&connection, &user.id)?;
 
+        let pushers = pushers.into_iter()
+            .map(|pusher| {
+                let failing = pusher.is_failing();
+                let mut pusher = to_value(PusherOptions::from(pusher)).map_err(ApiError::from)?;
+
+                if let Value::Object(ref mut pusher) = pusher {
+                    pusher.insert("io.ruma.failing".to_string(), Value::Bool(failing));
+                }
+
+                Ok(pusher)
+            })
+            .collect::<Result<Vec<Value>, ApiError>>()?;
+
         let response = GetPushersResponse {
-            pushers: pushers.into_iter().map(PusherOptions::from).collect()
+            pushers: pushers,
         };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
@@ -77,12 +92,27 @@ impl Handler for SetPushers {
 
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use test::{Test, TestUser};
     use iron::status::Status;
+    use models::presence_status::advance_clock;
     use models::pusher::PusherOptions;
     use models::pusher::PusherData;
+    use push::set_gateway_status;
     use serde_json::from_value;
 
+    /// Whether the only pusher of the user is marked as failing in GET `/pushers`.
+    fn is_failing(test: &Test, user: &TestUser) -> bool {
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", user.token));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let pushers = response.json().get("pushers").unwrap().as_array().unwrap().clone();
+
+        assert_eq!(pushers.len(), 1);
+
+        pushers[0].get("io.ruma.failing").unwrap().as_bool().unwrap()
+    }
+
     #[test]
     fn add_pusher() {
         let test = Test::new();
@@ -293,4 +323,73 @@ mod tests {
         let json = response.json();
         assert_eq!(json.get("pushers").unwrap().as_array().unwrap().len(), 1);
     }
+
+    #[test]
+    fn email_pusher_requires_bound_email_address() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "email".to_string(),
+            data: PusherData {
+                url: None,
+            },
+            device_display_name: "carl@example.com".to_string(),
+            app_id: "m.email".to_string(),
+            profile_tag: None,
+            pushkey: "carl@example.com".to_string(),
+            app_display_name: "Email Notifications".to_string(),
+            append: false,
+        };
+
+        let response = test.set_pusher(&carl.token, options.clone());
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_NOT_FOUND"
+        );
+
+        test.bind_email(&carl, "carl@example.com");
+
+        let response = test.set_pusher(&carl.token, options);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn failing_pushers_are_marked() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.ruma.test/_matrix/push/v1/notify".to_string()),
+            },
+            device_display_name: "device".to_string(),
+            app_id: "device".to_string(),
+            profile_tag: None,
+            pushkey: "device".to_string(),
+            app_display_name: "device".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.set_pusher(&bob.token, options).status, Status::Ok);
+        assert!(!is_failing(&test, &bob));
+
+        set_gateway_status(502);
+        test.send_call_invite(&alice.token, &room_id, 1);
+        test.deliver_push_notifications();
+
+        assert!(is_failing(&test, &bob));
+
+        set_gateway_status(200);
+        advance_clock(31_000);
+        test.send_call_invite(&alice.token, &room_id, 2);
+        test.deliver_push_notifications();
+
+        assert!(!is_failing(&test, &bob));
+    }
 }
@@ -1,27 +1,43 @@
 //! Endpoints for user account registration.
 
-use std::convert::TryFrom;
 use std::fmt::{Formatter, Result as FmtResult};
 
 use bodyparser;
 use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response, status};
 use ruma_identifiers::UserId;
 use serde::de::{Deserialize, Deserializer, Visitor, Error as SerdeError};
+use url::Url;
 
+use appservice::AppServiceRegistration;
 use config::Config;
 use crypto::hash_password;
 use db::DB;
 use error::ApiError;
 use middleware::{JsonRequest, MiddlewareChain};
-use models::profile::Profile;
+use models::monthly_active_user::MonthlyActiveUser;
+use models::threepid::Threepid;
+use models::threepid_session::{
+    BINDING_PURPOSE,
+    MSISDN_MEDIUM,
+    ThreepidCredentials,
+    ThreepidSession,
+};
 use models::user::{NewUser, User};
 use modifier::SerializableResponse;
+use util::appservice::{NamespaceKind, verify_not_reserved};
+use util::user_id::{generate_user_id, local_user_id};
 
 /// The `/register` endpoint.
+///
+/// Application services register the users in their namespaces by passing their `as_token` as
+/// the access token. User IDs in exclusive namespaces can only be registered that way.
 pub struct Register;
 
 #[derive(Clone, Debug, Deserialize)]
 struct RegistrationRequest {
+    /// Additional authentication information, e.g. the validation session of a phone number to
+    /// bind to the account.
+    pub auth: Option<RegistrationAuth>,
     /// If true, the server binds the email used for authentication to the Matrix ID with the ID Server.
     pub bind_email: Option<bool>,
     /// The kind of account to register. Defaults to user. One of: ["guest", "user"]
@@ -33,12 +49,33 @@ struct RegistrationRequest {
     pub username: Option<String>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct RegistrationAuth {
+    /// The authentication type. Currently only "m.login.msisdn" is supported.
+    #[serde(rename="type")]
+    pub auth_type: String,
+    /// The credentials of the validation session of the third party identifier.
+    pub threepid_creds: ThreepidCredentials,
+}
+
 #[derive(Copy, Clone, Debug)]
 enum RegistrationKind {
     Guest,
     User,
 }
 
+/// The GET `/register/available` endpoint.
+///
+/// Clients check whether a username can be registered before asking for the other details. No
+/// username is available while registration is disabled.
+pub struct RegisterAvailable;
+
+#[derive(Debug, Serialize)]
+struct RegisterAvailableResponse {
+    /// Always true, since unavailable usernames fail with an error.
+    pub available: bool,
+}
+
 #[derive(Debug, Serialize)]
 struct RegistrationResponse {
     /// An access token for the account. This access token can then be used to authorize other requests.
@@ -51,6 +88,8 @@ struct RegistrationResponse {
 
 middleware_chain!(Register, [JsonRequest]);
 
+middleware_chain!(RegisterAvailable);
+
 impl<'de> Deserialize<'de> for RegistrationKind {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
         struct RegistrationKindVisitor;
@@ -92,52 +131,160 @@ impl Handler for Register {
 
         let config = Config::from_request(request)?;
 
+        let url: Url = request.url.clone().into();
+        let app_service = url.query_pairs()
+            .find(|&(ref key, _)| key == "access_token")
+            .and_then(|(_, token)| {
+                AppServiceRegistration::find_by_token(&config.app_services, &token).cloned()
+            });
+
+        if app_service.is_none() {
+            verify_registration_enabled(&config)?;
+        }
+
         let new_user = NewUser {
             id: match registration_request.username {
-                Some(username) => {
-                    UserId::try_from(&format!("@{}:{}", username, &config.domain))
-                        .map_err(ApiError::from)?
-                }
-                None => UserId::new(&config.domain).map_err(ApiError::from)?,
+                Some(username) => local_user_id(&username, &config.domain, "username")?,
+                None => generate_user_id(&config.domain)?,
             },
             password_hash: hash_password(&registration_request.password)?,
+            admin: false,
         };
 
-        let connection = DB::from_request(request)?;
-
-        if User::find_registered_user(&connection, &new_user.id)?.is_some() {
-            let error = ApiError::unauthorized("This user_id already exists".to_string());
-
-            return Err(IronError::from(error));
+        if let Some(ref registration) = app_service {
+            if !registration.controls_user(&new_user.id, &config.domain)? {
+                Err(ApiError::exclusive(format!(
+                    "The application service cannot register {}.",
+                    new_user.id
+                )))?;
+            }
         }
 
-        let (user, access_token) = User::create(
-            &connection,
-            &new_user,
-            &config.macaroon_secret_key,
+        verify_not_reserved(
+            &config.app_services,
+            NamespaceKind::Users,
+            &new_user.id.to_string(),
+            app_service.as_ref(),
         )?;
 
-        let new_profile = Profile {
-            id: user.id.clone(),
-            avatar_url: None,
-            displayname: None,
+        // Registration is undone if binding the phone number or creating the profile fails, so
+        // the user ID is not taken by an account nobody can use.
+        let response = DB::with_transaction(request, |connection| {
+            if User::find_registered_user(connection, &new_user.id)?.is_some() {
+                let error = ApiError::unauthorized("This user_id already exists".to_string());
+
+                return Err(IronError::from(error));
+            }
+
+            MonthlyActiveUser::check_limit(
+                connection,
+                None,
+                config.max_mau,
+                config.admin_contact.as_ref(),
+            )?;
+
+            let threepid_session = match registration_request.auth {
+                Some(ref auth) if auth.auth_type == "m.login.msisdn" => {
+                    let session = ThreepidSession::find_validated(
+                        connection,
+                        &auth.threepid_creds,
+                        BINDING_PURPOSE,
+                    )?;
+
+                    let session = match session {
+                        Some(ref session) if session.medium == MSISDN_MEDIUM => session.clone(),
+                        Some(_) | None => Err(ApiError::threepid_auth_failed(None))?,
+                    };
+
+                    let bound_threepid =
+                        Threepid::find_by_address(connection, &session.medium, &session.address)?;
+
+                    if bound_threepid.is_some() {
+                        Err(ApiError::threepid_in_use(None))?;
+                    }
+
+                    Some(session)
+                }
+                Some(_) => {
+                    Err(ApiError::invalid_param("auth", "Unsupported authentication type."))?
+                }
+                None => None,
+            };
+
+            let (user, access_token) = User::create(
+                connection,
+                &new_user,
+                &config,
+            )?;
+
+            if let Some(session) = threepid_session {
+                Threepid::bind(connection, &session, &user.id, &config.allowed_email_domains)?;
+            }
+
+            let response = RegistrationResponse {
+                access_token: access_token,
+                home_server: config.domain.clone(),
+                user_id: user.id,
+            };
+
+            Ok(response)
+        })?;
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
+
+impl Handler for RegisterAvailable {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        verify_registration_enabled(&config)?;
+
+        let url: Url = request.url.clone().into();
+        let username = url.query_pairs()
+            .find(|&(ref key, _)| key == "username")
+            .map(|(_, username)| username.into_owned());
+
+        let user_id = match username {
+            Some(username) => local_user_id(&username, &config.domain, "username")?,
+            None => Err(ApiError::missing_param("username"))?,
         };
 
-        Profile::create(&connection, &new_profile)?;
+        let user_id_string = user_id.to_string();
+
+        verify_not_reserved(&config.app_services, NamespaceKind::Users, &user_id_string, None)?;
 
-        let response = RegistrationResponse {
-            access_token: access_token.value,
-            home_server: config.domain.clone(),
-            user_id: user.id,
+        let connection = DB::from_request(request)?;
+
+        if User::find_registered_user(&connection, &user_id)?.is_some() {
+            Err(ApiError::user_in_use(format!("The user ID {} is already taken.", user_id)))?;
+        }
+
+        let response = RegisterAvailableResponse {
+            available: true,
         };
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
     }
 }
 
+/// Check that users can register accounts themselves.
+///
+/// Fails with `M_FORBIDDEN` unless `registration_enabled` is set.
+fn verify_registration_enabled(config: &Config) -> Result<(), ApiError> {
+    if config.registration_enabled {
+        Ok(())
+    } else {
+        Err(ApiError::unauthorized("Registration is disabled".to_string()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use test::Test;
+    use serde_json::from_str;
+
+    use crypto::hmac_sha1_hex;
+    use test::{REGISTRATION_SHARED_SECRET, Test};
     use iron::status::Status;
 
     #[test]
@@ -200,4 +347,156 @@ mod tests {
             "This user_id already exists"
         );
     }
+
+    #[test]
+    fn exclusive_namespace_is_reserved_for_its_app_service() {
+        let test = Test::with_config(|config| {
+            config.app_services.push(from_str(r#"{
+                "id": "irc",
+                "as_token": "as_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {"users": [
+                    {"exclusive": true, "regex": "@irc_.*:ruma\\.test"},
+                    {"exclusive": false, "regex": "@.*_log:ruma\\.test"}
+                ]}
+            }"#).unwrap());
+        });
+
+        let response = test.register_user(r#"{"username": "irc_carl", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+
+        let response = test.register_user(r#"{"username": "carl_log", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let as_register_path = "/_matrix/client/r0/register?access_token=as_secret";
+        let response = test.post(
+            as_register_path,
+            r#"{"username": "irc_carl", "password": "secret"}"#
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@irc_carl:ruma.test"
+        );
+
+        let response = test.post(
+            as_register_path,
+            r#"{"username": "alice", "password": "secret"}"#
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+    }
+
+    #[test]
+    fn register_with_phone_number() {
+        let test = Test::new();
+        let sid = test.validate_msisdn("GB", "07700 900123", "secret");
+
+        let body = format!(
+            r#"{{
+                "username": "carl",
+                "password": "secret",
+                "auth": {{
+                    "type": "m.login.msisdn",
+                    "threepid_creds": {{"sid": "{}", "client_secret": "secret"}}
+                }}
+            }}"#,
+            sid
+        );
+        let response = test.register_user(&body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let login_body = r#"{
+            "type": "m.login.password",
+            "identifier": {"type": "m.id.phone", "country": "GB", "phone": "+44 7700 900123"},
+            "password": "secret"
+        }"#;
+        let response = test.post("/_matrix/client/r0/login", login_body);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+    }
+
+    #[test]
+    fn register_with_unvalidated_phone_number() {
+        let test = Test::new();
+
+        let body = r#"{
+            "username": "carl",
+            "password": "secret",
+            "auth": {
+                "type": "m.login.msisdn",
+                "threepid_creds": {"sid": "unknown", "client_secret": "secret"}
+            }
+        }"#;
+        let response = test.register_user(body);
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_AUTH_FAILED"
+        );
+    }
+
+    #[test]
+    fn registration_can_be_disabled() {
+        let test = Test::with_config(|config| config.registration_enabled = false);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Registration is disabled"
+        );
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get("/_matrix/client/r0/admin/register");
+        let nonce = response.json().get("nonce").unwrap().as_str().unwrap().to_string();
+        let message = format!("{}\0carl\0secret\0notadmin", nonce);
+        let mac = hmac_sha1_hex(REGISTRATION_SHARED_SECRET.as_bytes(), message.as_bytes());
+        let body = format!(
+            r#"{{"nonce": "{}", "username": "carl", "password": "secret", "mac": "{}"}}"#,
+            nonce,
+            mac
+        );
+        let response = test.post("/_matrix/client/r0/admin/register", &body);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+    }
+
+    #[test]
+    fn username_availability() {
+        let test = Test::new();
+        let path = "/_matrix/client/r0/register/available?username=carl";
+
+        let response = test.get(path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("available").unwrap().as_bool(), Some(true));
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(path);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_USER_IN_USE");
+
+        let response = test.get("/_matrix/client/r0/register/available");
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
 }
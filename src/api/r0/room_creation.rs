@@ -4,27 +4,43 @@ use std::convert::From;
 
 use bodyparser;
 use diesel::Connection;
+use diesel::pg::PgConnection;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use iron::status::Status;
+use ruma_events::EventType;
 use ruma_events::stripped::StrippedState;
 use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Map, Value, from_str, to_string};
 
-use config::Config;
+use appservice::AppServiceRegistration;
+use config::{Config, StateTemplate};
 use db::DB;
 use error::ApiError;
+use event_validation::verify_event_type_allowed;
 use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
 use models::room::{CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility};
 use models::room_membership::{RoomMembership, RoomMembershipOptions};
+use models::transaction::Transaction;
 use models::user::User;
 use modifier::SerializableResponse;
+use spam::{CompositeSpamChecker, SpamChecker};
+use util::appservice::verify_alias_allowed;
+use util::room_alias::parse_local_room_alias;
+
+/// The keys of the content of m.room.create events that only the server sets.
+const SERVER_CREATION_CONTENT_KEYS: [&'static str; 2] = ["creator", "room_version"];
 
 /// The `/createRoom` endpoint.
+///
+/// Clients can make retries safe by setting `txn_id`: a request with the same `txn_id` and access
+/// token as an earlier one returns the room that was created the first time instead of creating
+/// another one.
 pub struct CreateRoom;
 
 #[derive(Clone, Debug, Deserialize)]
 struct CreateRoomRequest {
     /// Extra keys to be added to the content of the m.room.create.
-    pub creation_content: Option<CreationContent>,
+    pub creation_content: Option<Map<String, Value>>,
     /// A list of state events to set in the new room. This allows the
     /// user to override the default state events set in the new room.
     pub initial_state: Option<Vec<Box<StrippedState>>>,
@@ -38,17 +54,13 @@ struct CreateRoomRequest {
     pub room_alias_name: Option<String>,
     /// Indicates the room's topic.
     pub topic: Option<String>,
+    /// A client-generated ID that makes retries of the request create only one room.
+    pub txn_id: Option<String>,
     /// Indicates whether or not that the room will be shown in the published room list.
     pub visibility: Option<RoomVisibility>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-struct CreationContent {
-    #[serde(rename="m.federate")]
-    pub federate: Option<bool>,
-}
-
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CreateRoomResponse {
     /// The fully qualified ID of the room that was created.
     room_id: RoomId,
@@ -60,24 +72,74 @@ impl Handler for CreateRoom {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
         let user = request.extensions.get::<User>()
             .expect("AccessTokenAuth should ensure a user").clone();
+        let config = Config::from_request(request)?;
+
+        // `initial_state` is checked before parsing it, since it can contain event types that
+        // templates are defined for but `StrippedState` does not know about.
+        if let Ok(Some(body)) = request.get::<bodyparser::Json>() {
+            verify_locked_state(&body, &config.default_room_state)?;
+            verify_initial_state_allowed(&body, &config)?;
+        }
+
         let create_room_request = match request.get::<bodyparser::Struct<CreateRoomRequest>>() {
             Ok(Some(create_room_request)) => create_room_request,
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
         let connection = DB::from_request(request)?;
-        let config = Config::from_request(request)?;
+
+        let transaction_path = create_room_request.txn_id.as_ref().map(|txn_id| {
+            format!("{}/{}", request.url.path().join("/"), txn_id)
+        });
+        let token_hash = Transaction::token_hash(request);
+
+        if let Some(ref path) = transaction_path {
+            if let Some(response) = find_room_response(&connection, path, &token_hash, &user.id)? {
+                return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+            }
+        }
+
+        let spam_checker = CompositeSpamChecker::from_request(request)?;
+
+        spam_checker.user_may_create_room(&user.id).ensure_allowed(&config)?;
+        RoomMembership::verify_room_limit(&connection, &user.id, config.max_rooms_per_user)?;
+
+        if let Some(ref alias_name) = create_room_request.room_alias_name {
+            let alias = parse_local_room_alias(
+                &format!("#{}:{}", alias_name, config.domain),
+                &config.domain,
+                "room_alias_name",
+            )?;
+
+            verify_alias_allowed(
+                &config,
+                &alias.to_string(),
+                &user,
+                request.extensions.get::<AppServiceRegistration>(),
+            )?;
+        }
+
+        let creation_content = create_room_request.creation_content.unwrap_or_else(Map::new);
+        let (federate, room_type) = parse_creation_content(&creation_content)?;
 
         let new_room = NewRoom {
             id: RoomId::new(&config.domain).map_err(ApiError::from)?,
             user_id: user.id,
             public: create_room_request.visibility.map_or(false, |v| v == RoomVisibility::Public),
+            room_type: room_type,
         };
 
-        let federate = match create_room_request.creation_content {
-            Some(creation_content) => creation_content.federate.unwrap_or(true),
-            None => true,
-        };
+        if new_room.public {
+            spam_checker.user_may_publish_room(&new_room.user_id, &new_room.id)
+                .ensure_allowed(&config)?;
+        }
+
+        if let Some(ref invitees) = create_room_request.invite {
+            for invitee in invitees {
+                spam_checker.user_may_invite(&new_room.user_id, invitee, &new_room.id)
+                    .ensure_allowed(&config)?;
+            }
+        }
 
         let preset = match create_room_request.preset {
             Some(preset) => preset,
@@ -90,6 +152,9 @@ impl Handler for CreateRoom {
 
         let creation_options = CreationOptions {
             alias: create_room_request.room_alias_name,
+            creation_content: creation_content,
+            default_power_levels: config.default_power_levels,
+            default_state: config.default_room_state.clone(),
             federate: Some(federate),
             initial_state: create_room_request.initial_state,
             invite_list: create_room_request.invite,
@@ -98,7 +163,27 @@ impl Handler for CreateRoom {
             topic: create_room_request.topic,
         };
 
-        let room: Room = connection.transaction::<Room, ApiError, _>(|| {
+        let room = connection.transaction::<Option<Room>, ApiError, _>(|| {
+            // The transaction is created first, so that a concurrent request with the same
+            // `txn_id` waits for this one and then creates nothing if it succeeded.
+            if let Some(ref path) = transaction_path {
+                let response = CreateRoomResponse {
+                    room_id: new_room.id.clone(),
+                };
+                let serialized_response = to_string(&response).map_err(ApiError::from)?;
+                let is_created = Transaction::create(
+                    &connection,
+                    path.clone(),
+                    token_hash.clone(),
+                    new_room.user_id.clone(),
+                    serialized_response,
+                )?;
+
+                if !is_created {
+                    return Ok(None);
+                }
+            }
+
             let room = Room::create(&connection, &new_room, &config.domain, &creation_options)?;
 
             let options = RoomMembershipOptions {
@@ -106,14 +191,27 @@ impl Handler for CreateRoom {
                 user_id: room.user_id.clone(),
                 sender: room.user_id.clone(),
                 membership: "join".to_string(),
+                reason: None,
             };
 
             RoomMembership::create(&connection, &config.domain, options)?;
 
-            Ok(room)
+            Ok(Some(room))
         })
         .map_err(ApiError::from)?;
 
+        let room = match room {
+            Some(room) => room,
+            None => {
+                let path = transaction_path.expect("Rooms are only skipped for transactions");
+                let user_id = &new_room.user_id;
+                let response = find_room_response(&connection, &path, &token_hash, user_id)?
+                    .ok_or_else(|| ApiError::unknown("The transaction was deleted.".to_string()))?;
+
+                return Ok(Response::with((Status::Ok, SerializableResponse(response))));
+            }
+        };
+
         let response = CreateRoomResponse {
             room_id: room.id,
         };
@@ -122,11 +220,128 @@ impl Handler for CreateRoom {
     }
 }
 
+/// The response saved for the transaction of a request to create a room, if there is one.
+fn find_room_response(connection: &PgConnection, path: &str, token_hash: &str, user_id: &UserId)
+-> Result<Option<CreateRoomResponse>, ApiError> {
+    match Transaction::find(connection, path, token_hash, user_id)? {
+        Some(transaction) => from_str(&transaction.response).map(Some).map_err(ApiError::from),
+        None => Ok(None),
+    }
+}
+
+/// Check the `creation_content` of a request to create a room and return whether the room is
+/// federated and its type.
+///
+/// Keys the server sets itself cannot be set by the user.
+fn parse_creation_content(creation_content: &Map<String, Value>)
+-> Result<(bool, Option<String>), ApiError> {
+    let server_key = SERVER_CREATION_CONTENT_KEYS.iter()
+        .find(|key| creation_content.contains_key(**key));
+
+    if let Some(key) = server_key {
+        return Err(ApiError::invalid_param(
+            "creation_content",
+            &format!("{} is set by the server.", key),
+        ));
+    }
+
+    let federate = match creation_content.get("m.federate") {
+        Some(&Value::Bool(federate)) => federate,
+        Some(_) => {
+            Err(ApiError::invalid_param("creation_content", "m.federate must be a boolean."))?
+        }
+        None => true,
+    };
+
+    let room_type = match creation_content.get("type") {
+        Some(&Value::String(ref room_type)) => Some(room_type.clone()),
+        Some(_) => Err(ApiError::invalid_param("creation_content", "type must be a string."))?,
+        None => None,
+    };
+
+    Ok((federate, room_type))
+}
+
+/// Check that a request to create a room does not override any locked state template.
+fn verify_locked_state(body: &Value, templates: &[StateTemplate]) -> Result<(), ApiError> {
+    let initial_state = body.get("initial_state").and_then(Value::as_array);
+
+    for template in templates.iter().filter(|template| template.locked) {
+        let overridden_by_param = match template.event_type.as_ref() {
+            "m.room.name" => body.get("name").is_some(),
+            "m.room.topic" => body.get("topic").is_some(),
+            _ => false,
+        } && template.state_key == "";
+
+        let overridden_by_initial_state = initial_state.map_or(false, |events| {
+            events.iter().any(|event| {
+                let event_type = event.get("type").and_then(Value::as_str).unwrap_or("");
+                let state_key = event.get("state_key").and_then(Value::as_str).unwrap_or("");
+
+                template.matches(event_type, state_key)
+            })
+        });
+
+        if overridden_by_param || overridden_by_initial_state {
+            return Err(ApiError::unauthorized(format!(
+                "The state event {} of new rooms is set by the server and cannot be changed.",
+                template.event_type
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that the state events a request to create a room sets through `initial_state`, `name`
+/// and `topic` are of types users may send.
+fn verify_initial_state_allowed(body: &Value, config: &Config) -> Result<(), ApiError> {
+    if body.get("name").is_some() {
+        verify_event_type_allowed(config, &EventType::RoomName)?;
+    }
+
+    if body.get("topic").is_some() {
+        verify_event_type_allowed(config, &EventType::RoomTopic)?;
+    }
+
+    let initial_state = body.get("initial_state").and_then(Value::as_array);
+
+    for event in initial_state.into_iter().flat_map(|events| events) {
+        if let Some(event_type) = event.get("type").and_then(Value::as_str) {
+            verify_event_type_allowed(config, &EventType::from(event_type))?;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::{RoomId, UserId};
+    use serde_json::from_str;
+
+    use config::StateTemplate;
+    use models::presence_status::advance_clock;
+    use models::room::Room;
+    use models::room_membership::RoomMembership;
+    use models::transaction::Transaction;
     use test::Test;
     use iron::status::Status;
 
+    /// A test server that sets a locked server ACL in every new room.
+    fn test_with_locked_server_acl() -> Test {
+        Test::with_config(|config| {
+            config.default_room_state.push(StateTemplate {
+                event_type: "m.room.server_acl".to_string(),
+                state_key: "".to_string(),
+                content: from_str(r#"{"allow": ["*"], "deny": ["evil.test"]}"#).unwrap(),
+                locked: true,
+            });
+        })
+    }
+
     #[test]
     fn no_parameters() {
         let test = Test::new();
@@ -161,6 +376,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn with_invalid_room_alias_name() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       user.token);
+
+        for alias_name in &["My_Room", "my room", "my:room"] {
+            let body = format!(r#"{{"room_alias_name": "{}"}}"#, alias_name);
+            let response = test.post(&create_room_path, &body);
+
+            assert_eq!(response.status, Status::BadRequest);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "IO_RUMA_INVALID_PARAM"
+            );
+        }
+    }
+
     #[test]
     fn with_public_visibility() {
         let test = Test::new();
@@ -316,6 +551,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn power_levels_default_to_the_configured_levels() {
+        let test = Test::with_config(|config| {
+            config.default_power_levels.kick = 75;
+            config.default_power_levels.state_default = 60;
+            config.default_power_levels.users_default = 10;
+        });
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let power_levels_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let response = test.get(&power_levels_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let power_levels = response.json();
+        assert_eq!(power_levels.get("ban").unwrap().as_u64().unwrap(), 50);
+        assert_eq!(power_levels.get("kick").unwrap().as_u64().unwrap(), 75);
+        assert_eq!(power_levels.get("state_default").unwrap().as_u64().unwrap(), 60);
+        assert_eq!(power_levels.get("users_default").unwrap().as_u64().unwrap(), 10);
+        assert_eq!(
+            power_levels.get("users").unwrap().get(alice.id.as_str()).unwrap().as_u64().unwrap(),
+            100
+        );
+    }
+
     #[test]
     fn with_power_levels_in_initial_state() {
         let test = Test::new();
@@ -473,4 +738,270 @@ mod tests {
         assert_eq!(test.send_message(&bob.token, &room_id, "Hi", 1).status, Status::Ok);
         assert_eq!(test.send_message(&alice.token, &room_id, "Hi", 1).status, Status::Ok);
     }
+
+    #[test]
+    fn with_default_room_state() {
+        let test = test_with_locked_server_acl();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let state_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.server_acl?access_token={}",
+            room_id,
+            alice.token
+        );
+
+        let response = test.get(&state_path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("deny").unwrap()[0].as_str().unwrap(), "evil.test");
+    }
+
+    #[test]
+    fn initial_state_cannot_override_locked_default_room_state() {
+        let test = test_with_locked_server_acl();
+        let alice = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       alice.token);
+
+        let response = test.post(&create_room_path, r#"{
+            "initial_state": [{
+                "state_key": "",
+                "type": "m.room.server_acl",
+                "content": {"allow": ["*"], "deny": []}
+            }]
+        }"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+    }
+
+    #[test]
+    fn name_cannot_override_locked_default_room_name() {
+        let test = Test::with_config(|config| {
+            config.default_room_state.push(StateTemplate {
+                event_type: "m.room.name".to_string(),
+                state_key: "".to_string(),
+                content: from_str(r#"{"name": "Company room"}"#).unwrap(),
+                locked: true,
+            });
+        });
+        let alice = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       alice.token);
+
+        let response = test.post(&create_room_path, r#"{"name": "My room"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn initial_state_of_denied_types_is_rejected() {
+        let test = Test::with_config(|config| {
+            config.denied_event_types = vec![
+                "org.example.experimental.*".to_string(),
+                "m.room.topic".to_string(),
+            ];
+        });
+        let alice = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       alice.token);
+
+        let response = test.post(&create_room_path, r#"{
+            "initial_state": [{
+                "state_key": "",
+                "type": "org.example.experimental.widget",
+                "content": {"url": "https://widget.example.com"}
+            }]
+        }"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_FORBIDDEN");
+
+        let response = test.post(&create_room_path, r#"{"topic": "Experiments"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.post(&create_room_path, r#"{"name": "Experiments"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn with_room_type_in_creation_content() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"creation_content": {"type": "m.space", "m.federate": false}}"#,
+        );
+
+        let response = test.get_state_event(&alice.token, &room_id, "m.room.create", None);
+
+        assert_eq!(response.status, Status::Ok);
+        let content = response.json();
+        assert_eq!(content.get("type").unwrap().as_str().unwrap(), "m.space");
+        assert_eq!(content.get("m.federate").unwrap().as_bool().unwrap(), false);
+        assert_eq!(content.get("creator").unwrap().as_str().unwrap(), alice.id);
+
+        let room = test.with_connection(|connection| {
+            Room::find(connection, &RoomId::try_from(room_id.as_str()).unwrap()).unwrap().unwrap()
+        });
+
+        assert_eq!(room.room_type, Some("m.space".to_string()));
+    }
+
+    #[test]
+    fn room_type_is_in_stripped_invite_state() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"creation_content": {"type": "m.space"}}"#,
+        );
+
+        assert_eq!(test.invite(&alice.token, &room_id, &bob.id).status, Status::Ok);
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", bob.token);
+        let response = test.get(&sync_path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let pointer = format!("/rooms/invite/{}/invite_state/events", room_id);
+        let events = response.json().pointer(&pointer).unwrap().as_array().unwrap().clone();
+        let create_event = events.iter()
+            .find(|event| event["type"].as_str() == Some("m.room.create"))
+            .unwrap();
+
+        assert_eq!(create_event["content"]["type"].as_str().unwrap(), "m.space");
+    }
+
+    #[test]
+    fn creation_content_cannot_set_server_keys() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}",
+                                       alice.token);
+
+        let bodies = vec![
+            format!(r#"{{"creation_content": {{"creator": "{}"}}}}"#, bob.id),
+            r#"{"creation_content": {"room_version": "5"}}"#.to_string(),
+            r#"{"creation_content": {"type": 1}}"#.to_string(),
+        ];
+
+        for body in bodies {
+            let response = test.post(&create_room_path, &body);
+
+            assert_eq!(response.status, Status::BadRequest);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "IO_RUMA_INVALID_PARAM"
+            );
+        }
+    }
+
+    #[test]
+    fn retried_room_creation_creates_one_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let body = r#"{"name": "Retried", "txn_id": "create-1"}"#;
+
+        let first = test.create_room_with_params(&alice.token, body);
+        let retry = test.create_room_with_params(&alice.token, body);
+        let other = test.create_room_with_params(&alice.token, r#"{"txn_id": "create-2"}"#);
+        let bobs = test.create_room_with_params(&bob.token, body);
+
+        assert_eq!(first, retry);
+        assert!(other != first);
+        assert!(bobs != first);
+
+        let memberships = test.with_connection(|connection| {
+            let user_id = UserId::try_from(alice.id.as_str()).unwrap();
+
+            RoomMembership::find_by_uid(connection, user_id).unwrap()
+        });
+
+        assert_eq!(memberships.len(), 2);
+    }
+
+    #[test]
+    fn with_room_alias_in_exclusive_namespace() {
+        let test = Test::with_config(|config| {
+            config.app_services.push(from_str(r#"{
+                "id": "irc",
+                "as_token": "as_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {"aliases": [{"exclusive": true, "regex": "#irc_.*:ruma\\.test"}]}
+            }"#).unwrap());
+        });
+        let carl = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}", carl.token);
+        let response = test.post(&create_room_path, r#"{"room_alias_name": "irc_ruma"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_bot", "password": "secret"}"#
+        ).status.is_success());
+
+        let response = test.post(
+            "/_matrix/client/r0/createRoom?access_token=as_secret",
+            r#"{"room_alias_name": "irc_ruma"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn with_room_alias_matching_reserved_pattern() {
+        let test = Test::with_config(|config| {
+            config.reserved_alias_patterns.push("#admin-.*:ruma\\.test".to_string());
+            config.server_admins.push(UserId::try_from("@alice:ruma.test").unwrap());
+        });
+        let carl = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}", carl.token);
+        let response = test.post(&create_room_path, r#"{"room_alias_name": "admin-help"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_EXCLUSIVE");
+
+        let response = test.register_user(r#"{"username": "alice", "password": "secret"}"#);
+        let alice_token = response.json().get("access_token").unwrap().as_str().unwrap();
+
+        let create_room_path =
+            format!("/_matrix/client/r0/createRoom?access_token={}", alice_token);
+        let response = test.post(&create_room_path, r#"{"room_alias_name": "admin-help"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn room_creation_transactions_expire_after_a_day() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let body = r#"{"txn_id": "create-1"}"#;
+
+        let first = test.create_room_with_params(&alice.token, body);
+
+        advance_clock(24 * 60 * 60 * 1000 + 1);
+
+        test.with_connection(|connection| {
+            assert_eq!(Transaction::delete_expired(connection).unwrap(), 1);
+        });
+
+        assert!(test.create_room_with_params(&alice.token, body) != first);
+    }
 }
@@ -0,0 +1,381 @@
+//! Endpoints for retrieving events of a room by their ID.
+
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::error::Error;
+
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use router::Router;
+use ruma_identifiers::{EventId, RoomId};
+use serde_json::Value;
+use url::percent_encoding::percent_decode;
+
+use db::DB;
+use error::{ApiError, MapApiError};
+use history_visibility::{HistoryTimeline, ReadableUntil};
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::event::Event;
+use models::transaction::Transaction;
+use models::user::User;
+use modifier::SerializableResponse;
+use util::pagination::{Stream, Token, parse_limit};
+use views::event::{RenderOptions, render_events};
+
+/// The number of events around the event returned by `/context` when no limit is given.
+const DEFAULT_CONTEXT_LIMIT: usize = 10;
+
+/// The maximum number of events around the event returned by `/context`.
+const MAX_CONTEXT_LIMIT: usize = 100;
+
+/// The GET `/rooms/:room_id/event/:event_id` endpoint.
+///
+/// Clients fetch the pinned events of a room one by one with it, so the event is looked up with
+/// `Event::find_many_in_room` instead of checking its room separately.
+pub struct GetRoomEvent;
+
+middleware_chain!(GetRoomEvent, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRoomEvent {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+        let event_id = event_id_param(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let history = HistoryTimeline::load(&connection, &room_id, &user.id)?;
+        let event = find_readable_event(&connection, &room_id, &history, &event_id)?;
+
+        let token_hash = Transaction::token_hash(request);
+        let render_options = RenderOptions {
+            user_id: Some(&user.id),
+            token_hash: Some(&token_hash),
+            ignored_user_ids: &[],
+            bundle_relations: true,
+        };
+        let event = render_events(&connection, vec![event], &render_options)?
+            .pop()
+            .expect("Every event should be rendered");
+
+        Ok(Response::with((Status::Ok, SerializableResponse(event))))
+    }
+}
+
+/// The GET `/rooms/:room_id/context/:event_id` endpoint.
+pub struct GetEventContext;
+
+#[derive(Debug, Serialize)]
+struct GetEventContextResponse {
+    /// A token to paginate backwards from the oldest event returned.
+    pub start: String,
+    /// A token to paginate forwards from the newest event returned.
+    pub end: String,
+    /// The events before the event, newest first.
+    pub events_before: Vec<Value>,
+    /// The event itself.
+    pub event: Value,
+    /// The events after the event, oldest first.
+    pub events_after: Vec<Value>,
+    /// The state of the room at the newest event returned.
+    pub state: Vec<Value>,
+}
+
+middleware_chain!(GetEventContext, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetEventContext {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+        let event_id = event_id_param(request)?;
+        let limit = parse_limit(request, DEFAULT_CONTEXT_LIMIT, MAX_CONTEXT_LIMIT)?;
+
+        let connection = DB::from_request(request)?;
+
+        let history = HistoryTimeline::load(&connection, &room_id, &user.id)?;
+        let event = find_readable_event(&connection, &room_id, &history, &event_id)?;
+
+        // Members who left only see the events from before they left.
+        let readable_until = match history.readable_until() {
+            Some(ReadableUntil::Before(until)) => until,
+            _ => i64::max_value(),
+        };
+
+        let before_limit = limit / 2;
+        let after_limit = limit - before_limit;
+
+        let mut events_before = history.filter(Event::find_latest_room_events(
+            &connection,
+            &room_id,
+            -1,
+            Some(event.ordering),
+            Some(before_limit),
+        )?);
+        events_before.reverse();
+
+        let events_after = Event::find_next_room_events(
+            &connection,
+            &room_id,
+            event.ordering,
+            after_limit,
+        )?;
+        let events_after: Vec<Event> = history.filter(events_after).into_iter()
+            .filter(|event| event.ordering < readable_until)
+            .collect();
+
+        let start = events_before.last().map_or(event.ordering, |event| event.ordering);
+        let end = events_after.last().map_or(event.ordering, |event| event.ordering);
+
+        let state = Event::get_room_state_events_until(
+            &connection,
+            &room_id,
+            min(end + 1, readable_until),
+        )?;
+
+        let token_hash = Transaction::token_hash(request);
+        let render_options = RenderOptions {
+            user_id: Some(&user.id),
+            token_hash: Some(&token_hash),
+            ignored_user_ids: &[],
+            bundle_relations: true,
+        };
+
+        let response = GetEventContextResponse {
+            start: Token::new(Stream::Messages, start).encode(),
+            end: Token::new(Stream::Messages, end).encode(),
+            events_before: render_events(&connection, events_before, &render_options)?,
+            event: render_events(&connection, vec![event], &render_options)?
+                .pop()
+                .expect("Every event should be rendered"),
+            events_after: render_events(&connection, events_after, &render_options)?,
+            state: render_events(&connection, state, &render_options)?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Parse the `event_id` path parameter.
+fn event_id_param(request: &Request) -> Result<EventId, ApiError> {
+    let params = request.extensions.get::<Router>().expect("Params object is missing");
+
+    match params.find("event_id") {
+        Some(event_id) => {
+            let decoded_event_id = percent_decode(event_id.as_bytes())
+                .decode_utf8()
+                .map_err(|err| ApiError::invalid_param("event_id", err.description()))?;
+
+            EventId::try_from(&decoded_event_id).map_api_err(|err| {
+                ApiError::invalid_param("event_id", err.description())
+            })
+        }
+        None => Err(ApiError::missing_param("event_id")),
+    }
+}
+
+/// Look up an event of the room the user may read according to the room's `history`.
+///
+/// Fails with `M_NOT_FOUND` for events the user may not read, like for events of other rooms and
+/// events that do not exist.
+fn find_readable_event(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    history: &HistoryTimeline,
+    event_id: &EventId,
+) -> Result<Event, ApiError> {
+    let events = Event::find_many_in_room(connection, room_id, &[event_id.clone()])?;
+
+    match history.filter(events).pop() {
+        Some(event) => Ok(event),
+        None => Err(ApiError::not_found(
+            format!("The event {} was not found in this room.", event_id)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use test::{Test, TestUser};
+
+    /// Send a message and return its event ID.
+    fn send_message(test: &Test, user: &TestUser, room_id: &str, message: &str, txn_id: u64)
+    -> String {
+        let response = test.send_message(&user.token, room_id, message, txn_id);
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        format!("${}:ruma.test", opaque_id)
+    }
+
+    /// The bodies of a list of rendered messages.
+    fn bodies(events: &Value) -> Vec<&str> {
+        events.as_array().unwrap().iter()
+            .map(|event| event.pointer("/content/body").unwrap().as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn events_are_retrieved_by_id() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let event_id = send_message(&test, &alice, &room_id, "Hello", 1);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/event/{}?access_token={}",
+            room_id,
+            event_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("event_id").unwrap().as_str(), Some(event_id.as_str()));
+        assert_eq!(response.json().pointer("/content/body").unwrap().as_str(), Some("Hello"));
+        assert_eq!(
+            response.json().pointer("/unsigned/transaction_id").unwrap().as_str(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn events_of_other_rooms_are_not_found() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let other_room_id = test.create_public_room(&alice.token);
+        let event_id = send_message(&test, &alice, &other_room_id, "Elsewhere", 1);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/event/{}?access_token={}",
+            room_id,
+            event_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn events_are_not_found_for_non_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let event_id = send_message(&test, &alice, &room_id, "Members only", 1);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/event/{}?access_token={}",
+            room_id,
+            event_id,
+            bob.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn pinned_events_are_retrieved_by_id() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let first = send_message(&test, &alice, &room_id, "First", 1);
+        let second = send_message(&test, &alice, &room_id, "Second", 2);
+
+        let content = format!(r#"{{"pinned": ["{}", "{}"]}}"#, second, first);
+        let response =
+            test.send_state_event(&alice.token, &room_id, "m.room.pinned_events", &content, None);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get_state_event(&alice.token, &room_id, "m.room.pinned_events", None);
+        let pinned: Vec<String> = response.json().get("pinned").unwrap().as_array().unwrap().iter()
+            .map(|event_id| event_id.as_str().unwrap().to_string())
+            .collect();
+
+        let bodies: Vec<String> = pinned.iter().map(|event_id| {
+            let response = test.get(&format!(
+                "/_matrix/client/r0/rooms/{}/event/{}?access_token={}",
+                room_id,
+                event_id,
+                alice.token
+            ));
+
+            assert_eq!(response.status, Status::Ok);
+
+            response.json().pointer("/content/body").unwrap().as_str().unwrap().to_string()
+        }).collect();
+
+        assert_eq!(bodies, vec!["Second", "First"]);
+    }
+
+    #[test]
+    fn context_contains_the_events_around_the_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let event_ids: Vec<String> = (1..6).map(|txn_id| {
+            send_message(&test, &alice, &room_id, &format!("Message {}", txn_id), txn_id)
+        }).collect();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?limit=4&access_token={}",
+            room_id,
+            event_ids[2],
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+
+        assert_eq!(json.pointer("/event/event_id").unwrap().as_str(), Some(event_ids[2].as_str()));
+        assert_eq!(bodies(json.get("events_before").unwrap()), vec!["Message 2", "Message 1"]);
+        assert_eq!(bodies(json.get("events_after").unwrap()), vec!["Message 4", "Message 5"]);
+        assert!(json.get("start").unwrap().is_string());
+        assert!(json.get("end").unwrap().is_string());
+
+        let state = json.get("state").unwrap().as_array().unwrap();
+
+        assert!(state.iter().any(|event| event["type"].as_str() == Some("m.room.create")));
+    }
+
+    #[test]
+    fn context_stops_where_a_member_left() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_id = send_message(&test, &alice, &room_id, "Before", 1);
+
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        send_message(&test, &alice, &room_id, "After", 2);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/context/{}?access_token={}",
+            room_id,
+            event_id,
+            bob.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let events_after = response.json().get("events_after").unwrap().as_array().unwrap();
+
+        assert!(!events_after.iter().any(|event| {
+            event.pointer("/content/body").and_then(Value::as_str) == Some("After")
+        }));
+    }
+}
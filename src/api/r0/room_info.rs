@@ -1,34 +1,32 @@
 //! Endpoint for retrieving the state of a room.
 
-use std::convert::TryInto;
-
+use diesel::pg::PgConnection;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 use router::Router;
 use ruma_events::EventType;
-use ruma_events::collections::all::StateEvent;
 use ruma_events::room::aliases::AliasesEventContent;
 use ruma_events::room::avatar::AvatarEventContent;
 use ruma_events::room::canonical_alias::CanonicalAliasEventContent;
-use ruma_events::room::create::CreateEventContent;
 use ruma_events::room::guest_access::GuestAccessEventContent;
 use ruma_events::room::history_visibility::HistoryVisibilityEventContent;
 use ruma_events::room::join_rules::JoinRulesEventContent;
-use ruma_events::room::member::MemberEventContent;
 use ruma_events::room::name::NameEventContent;
 use ruma_events::room::power_levels::PowerLevelsEventContent;
 use ruma_events::room::third_party_invite::ThirdPartyInviteEventContent;
 use ruma_events::room::topic::TopicEventContent;
-use serde_json::from_str;
+use serde_json::{Value, from_str};
 
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, EventTypeParam, MiddlewareChain, RoomIdParam};
+use history_visibility::{HistoryTimeline, ReadableUntil};
+use middleware::{EventTypeParam, MiddlewareChain, OptionalAccessTokenAuth, RoomIdParam};
 use models::event::Event;
 use models::room::Room;
-use models::room_membership::RoomMembership;
+use models::transaction::Transaction;
 use models::user::User;
 use modifier::SerializableResponse;
+use views::event::{RenderOptions, render_events};
 
 /// Deserialize event's content with the given `EventType` and send it as the response.
 macro_rules! send_content {
@@ -43,12 +41,11 @@ macro_rules! send_content {
 /// The `/rooms/:room_id/state` endpoint.
 pub struct RoomState;
 
-middleware_chain!(RoomState, [RoomIdParam, AccessTokenAuth]);
+middleware_chain!(RoomState, [RoomIdParam, OptionalAccessTokenAuth]);
 
 impl Handler for RoomState {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = request.extensions.get::<User>().cloned();
 
         let room_id = request.extensions.get::<RoomIdParam>()
             .expect("RoomIdParam should ensure a room_id").clone();
@@ -62,29 +59,18 @@ impl Handler for RoomState {
             }
         };
 
-        let membership = match RoomMembership::find(&connection, &room.id, &user.id)? {
-            Some(membership) => membership,
-            None => Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?
-        };
-
-        let state_events: Vec<StateEvent> = match membership.membership.as_ref() {
-            "join" => {
-                Event::get_room_full_state(&connection, &room_id)?.iter()
-                    .cloned()
-                    .map(|e| e.try_into())
-                    .collect::<Result<Vec<StateEvent>, ApiError>>()?
-            },
-            "ban" | "leave" => {
-                let last_event = Event::find(&connection, &membership.event_id)?
-                    .expect("A room membership should be associated with an event");
-
-                Event::get_room_state_events_until(&connection, &room_id, &last_event)?.iter()
-                    .cloned()
-                    .map(|e| e.try_into())
-                    .collect::<Result<Vec<StateEvent>, ApiError>>()?
-            },
-            _ => Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?
+        let token_hash = user.as_ref().map(|_| Transaction::token_hash(request));
+        let render_options = RenderOptions {
+            user_id: user.as_ref().map(|user| &user.id),
+            token_hash: token_hash.as_ref().map(String::as_str),
+            ignored_user_ids: &[],
+            bundle_relations: false,
         };
+        let state_events = render_events(
+            &connection,
+            readable_state(&connection, &room, user.as_ref())?,
+            &render_options,
+        )?;
 
         Ok(Response::with((Status::Ok, SerializableResponse(state_events))))
     }
@@ -93,7 +79,7 @@ impl Handler for RoomState {
 /// The `/rooms/:room_id/state/:event_type` and `/rooms/:room_id/state/:event_type/:state_key` endpoints.
 pub struct GetStateEvent;
 
-middleware_chain!(GetStateEvent, [RoomIdParam, EventTypeParam, AccessTokenAuth]);
+middleware_chain!(GetStateEvent, [RoomIdParam, EventTypeParam, OptionalAccessTokenAuth]);
 
 impl Handler for GetStateEvent {
     fn handle(&self, request: &mut Request) -> IronResult<Response> {
@@ -108,8 +94,7 @@ impl Handler for GetStateEvent {
 
         let state_key = params.find("state_key").unwrap_or("");
 
-        let user = request.extensions.get::<User>()
-            .expect("AccessTokenAuth should ensure a user").clone();
+        let user = request.extensions.get::<User>().cloned();
 
         let connection = DB::from_request(request)?;
 
@@ -120,35 +105,12 @@ impl Handler for GetStateEvent {
             }
         };
 
-        let membership = match RoomMembership::find(&connection, &room.id, &user.id)? {
-            Some(membership) => membership,
-            None => Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?
-        };
-
-        let state_event = match membership.membership.as_ref() {
-            "join" => {
-                Event::get_room_full_state(&connection, &room.id)?.iter()
-                    .filter(|e| {
-                        e.event_type == event_type.to_string() &&
-                        e.state_key.clone().unwrap_or("".to_string()) == state_key
-                    })
-                    .next()
-                    .cloned()
-            },
-            "ban" | "leave" => {
-                let last_event = Event::find(&connection, &membership.event_id)?
-                    .expect("A room membership should be associated with an event");
-
-                Event::get_room_state_events_until(&connection, &room_id, &last_event)?.iter()
-                    .filter(|e| {
-                        e.event_type == event_type.to_string() &&
-                        e.state_key.clone().unwrap_or("".to_string()) == state_key
-                    })
-                    .next()
-                    .cloned()
-            },
-            _ => Err(ApiError::unauthorized("The user is not a member of the room".to_string()))?
-        };
+        let state_event = readable_state(&connection, &room, user.as_ref())?.into_iter()
+            .filter(|e| {
+                e.event_type == event_type.to_string() &&
+                e.state_key.clone().unwrap_or("".to_string()) == state_key
+            })
+            .next();
 
         if state_event.is_none() {
             Err(ApiError::not_found("The requested state event was not found".to_string()))?
@@ -160,11 +122,13 @@ impl Handler for GetStateEvent {
             EventType::RoomAliases => send_content!(AliasesEventContent, content),
             EventType::RoomAvatar => send_content!(AvatarEventContent, content),
             EventType::RoomCanonicalAlias => send_content!(CanonicalAliasEventContent, content),
-            EventType::RoomCreate => send_content!(CreateEventContent, content),
+            // Sent as it is stored, since `CreateEventContent` drops keys like the room's type.
+            EventType::RoomCreate => send_content!(Value, content),
             EventType::RoomGuestAccess => send_content!(GuestAccessEventContent, content),
             EventType::RoomHistoryVisibility => send_content!(HistoryVisibilityEventContent, content),
             EventType::RoomJoinRules => send_content!(JoinRulesEventContent, content),
-            EventType::RoomMember => send_content!(MemberEventContent, content),
+            // Sent as it is stored, since `MemberEventContent` drops the reason.
+            EventType::RoomMember => send_content!(Value, content),
             EventType::RoomName => send_content!(NameEventContent, content),
             EventType::RoomPowerLevels => send_content!(PowerLevelsEventContent, content),
             EventType::RoomThirdPartyInvite => send_content!(ThirdPartyInviteEventContent, content),
@@ -174,6 +138,27 @@ impl Handler for GetStateEvent {
     }
 }
 
+/// The state of the room the user may read, according to the history visibility, or that users
+/// who are not logged in may read if `user` is `None`.
+///
+/// Members who left or were banned see the state from before they left.
+fn readable_state(connection: &PgConnection, room: &Room, user: Option<&User>)
+-> Result<Vec<Event>, ApiError> {
+    let history = match user {
+        Some(user) => HistoryTimeline::load(connection, &room.id, &user.id)?,
+        None => HistoryTimeline::load_anonymous(connection, &room.id)?,
+    };
+
+    match history.readable_until() {
+        Some(ReadableUntil::Now) => Event::get_room_full_state(connection, &room.id),
+        Some(ReadableUntil::Before(until)) => {
+            Event::get_room_state_events_until(connection, &room.id, until)
+        }
+        None if user.is_none() => Err(ApiError::unauthorized(None)),
+        None => Err(ApiError::unauthorized("The user is not a member of the room".to_string())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use test::Test;
@@ -324,7 +309,7 @@ mod tests {
         let room_id = test.create_room_with_params(&alice.token, &room_options);
 
         let put_room_alias_path = format!(
-            "/_matrix/client/r0/directory/room/alias_2?access_token={}",
+            "/_matrix/client/r0/directory/room/%23alias_2:ruma.test?access_token={}",
             alice.token
         );
 
@@ -577,4 +562,81 @@ mod tests {
             "The user is not a member of the room"
         );
     }
+
+    #[test]
+    fn world_readable_state_is_visible_to_non_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "world_readable"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get_state_event(&bob.token, &room_id, "m.room.create", None);
+        assert_eq!(response.status, Status::Ok);
+
+        let room_state_path = format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id,
+            bob.token
+        );
+
+        assert_eq!(test.get(&room_state_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn world_readable_state_is_visible_without_access_token() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let room_state_path = format!("/_matrix/client/r0/rooms/{}/state", room_id);
+        let create_event_path = format!("/_matrix/client/r0/rooms/{}/state/m.room.create", room_id);
+
+        assert_eq!(test.get(&room_state_path).status, Status::Forbidden);
+        assert_eq!(test.get(&create_event_path).status, Status::Forbidden);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "world_readable"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&room_state_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(!response.json().as_array().unwrap().is_empty());
+
+        let response = test.get(&create_event_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("creator").unwrap().as_str().unwrap(), alice.id);
+    }
+
+    #[test]
+    fn invalid_access_token_is_rejected_for_world_readable_state() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "world_readable"}"#,
+            None,
+        );
+
+        let room_state_path =
+            format!("/_matrix/client/r0/rooms/{}/state?access_token=invalid", room_id);
+
+        assert_eq!(test.get(&room_state_path).status, Status::Forbidden);
+    }
 }
@@ -0,0 +1,497 @@
+//! Endpoints for server-side backups of room keys.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use bodyparser;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+use router::Router;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_str};
+use url::Url;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use models::key_backup::{BackedUpKey, KeyBackup, NewKeyBackup};
+use models::user::User;
+use modifier::{EmptyResponse, SerializableResponse};
+
+/// The body of requests creating or updating a version of a key backup.
+#[derive(Clone, Debug, Deserialize)]
+struct KeyBackupVersionRequest {
+    /// The algorithm the keys in the backup are encrypted with.
+    algorithm: String,
+    /// The algorithm-specific data needed to verify and decrypt the backup.
+    auth_data: Value,
+}
+
+/// The response of the GET `/room_keys/version` endpoint.
+#[derive(Clone, Debug, Serialize)]
+struct GetKeyBackupVersionResponse {
+    /// The algorithm the keys in the backup are encrypted with.
+    algorithm: String,
+    /// The algorithm-specific data needed to verify and decrypt the backup.
+    auth_data: Value,
+    /// The number of keys in the backup.
+    count: i64,
+    /// A value that changes whenever the keys in the backup change.
+    etag: String,
+    /// The version of the backup.
+    version: String,
+}
+
+/// The response of the POST `/room_keys/version` endpoint.
+#[derive(Clone, Debug, Serialize)]
+struct CreateKeyBackupVersionResponse {
+    /// The version of the new backup.
+    version: String,
+}
+
+/// The backed up keys of a user, by room.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RoomKeys {
+    /// The backed up keys of each room.
+    rooms: BTreeMap<String, RoomKeyBackup>,
+}
+
+/// The backed up keys of one room.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RoomKeyBackup {
+    /// The backed up keys of each session in the room.
+    sessions: BTreeMap<String, KeyBackupData>,
+}
+
+/// The backed up key of one session.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct KeyBackupData {
+    /// The index of the first message in the session that the key can decrypt.
+    first_message_index: u64,
+    /// The number of times the key has been forwarded between devices.
+    forwarded_count: u64,
+    /// Whether the device the key came from has been verified.
+    is_verified: bool,
+    /// The encrypted key.
+    session_data: Value,
+}
+
+/// The response of the endpoints that change the keys in a backup.
+#[derive(Clone, Debug, Serialize)]
+struct RoomKeysUpdateResponse {
+    /// The number of keys in the backup.
+    count: i64,
+    /// The new etag of the backup.
+    etag: String,
+}
+
+/// The POST `/room_keys/version` endpoint.
+pub struct CreateKeyBackupVersion;
+
+middleware_chain!(CreateKeyBackupVersion, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for CreateKeyBackupVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version_request = match request.get::<bodyparser::Struct<KeyBackupVersionRequest>>() {
+            Ok(Some(version_request)) => version_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let new_key_backup = NewKeyBackup {
+            user_id: user.id,
+            algorithm: version_request.algorithm,
+            auth_data: version_request.auth_data.to_string(),
+        };
+
+        let key_backup = DB::with_transaction(request, |connection| {
+            Ok(KeyBackup::create(connection, &new_key_backup)?)
+        })?;
+
+        let response = CreateKeyBackupVersionResponse {
+            version: key_backup.version.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/room_keys/version` and `/room_keys/version/:version` endpoints.
+///
+/// Without a version, the current version of the backup is returned.
+pub struct GetKeyBackupVersion;
+
+middleware_chain!(GetKeyBackupVersion, [AccessTokenAuth]);
+
+impl Handler for GetKeyBackupVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version = request.extensions.get::<Router>()
+            .expect("Params object is missing")
+            .find("version")
+            .map(ToString::to_string);
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let key_backup = match version {
+            Some(ref version) => KeyBackup::find(&connection, &user.id, version)?,
+            None => KeyBackup::find_current(&connection, &user.id)?,
+        };
+
+        let key_backup = match key_backup {
+            Some(key_backup) => key_backup,
+            None => Err(ApiError::not_found("No key backup exists.".to_string()))?,
+        };
+
+        let response = GetKeyBackupVersionResponse {
+            algorithm: key_backup.algorithm.clone(),
+            auth_data: from_str(&key_backup.auth_data).map_err(ApiError::from)?,
+            count: key_backup.count_keys(&connection)?,
+            etag: key_backup.etag.to_string(),
+            version: key_backup.version.to_string(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The PUT `/room_keys/version/:version` endpoint.
+///
+/// Only the `auth_data` of a backup can be changed.
+pub struct UpdateKeyBackupVersion;
+
+middleware_chain!(UpdateKeyBackupVersion, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for UpdateKeyBackupVersion {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version = request.extensions.get::<Router>()
+            .expect("Params object is missing")
+            .find("version")
+            .expect("The route should ensure a version")
+            .to_string();
+
+        let version_request = match request.get::<bodyparser::Struct<KeyBackupVersionRequest>>() {
+            Ok(Some(version_request)) => version_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let mut key_backup = match KeyBackup::find(&connection, &user.id, &version)? {
+            Some(key_backup) => key_backup,
+            None => Err(ApiError::not_found("No key backup exists.".to_string()))?,
+        };
+
+        if version_request.algorithm != key_backup.algorithm {
+            Err(ApiError::invalid_param(
+                "algorithm",
+                "The algorithm of a key backup cannot be changed.",
+            ))?;
+        }
+
+        key_backup.update_auth_data(&connection, version_request.auth_data.to_string())?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The PUT `/room_keys/keys` endpoint.
+pub struct PutRoomKeys;
+
+middleware_chain!(PutRoomKeys, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PutRoomKeys {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version = version_param(request)?;
+
+        let room_keys = match request.get::<bodyparser::Struct<RoomKeys>>() {
+            Ok(Some(room_keys)) => room_keys,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        // The version is checked and the keys are written in one transaction, so they cannot end
+        // up in a version that stopped being the current one in the meantime.
+        let response = DB::with_transaction(request, |connection| {
+            let mut key_backup =
+                KeyBackup::find_current_for_write(connection, &user.id, &version)?;
+
+            let mut keys = Vec::new();
+
+            for (room_id, room_key_backup) in room_keys.rooms {
+                let room_id = RoomId::try_from(room_id.as_str())
+                    .map_err(|_| ApiError::invalid_param("rooms", "Invalid room ID."))?;
+
+                for (session_id, key) in room_key_backup.sessions {
+                    keys.push(BackedUpKey {
+                        user_id: user.id.clone(),
+                        version: key_backup.version,
+                        room_id: room_id.clone(),
+                        session_id: session_id,
+                        first_message_index: key.first_message_index as i64,
+                        forwarded_count: key.forwarded_count as i64,
+                        is_verified: key.is_verified,
+                        session_data: key.session_data.to_string(),
+                    });
+                }
+            }
+
+            key_backup.put_keys(connection, keys)?;
+
+            Ok(RoomKeysUpdateResponse {
+                count: key_backup.count_keys(connection)?,
+                etag: key_backup.etag.to_string(),
+            })
+        })?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/room_keys/keys` endpoint.
+///
+/// Keys of any version of the backup can be read, not only of the current one.
+pub struct GetRoomKeys;
+
+middleware_chain!(GetRoomKeys, [AccessTokenAuth]);
+
+impl Handler for GetRoomKeys {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version = version_param(request)?;
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let key_backup = match KeyBackup::find(&connection, &user.id, &version)? {
+            Some(key_backup) => key_backup,
+            None => Err(ApiError::not_found("No key backup exists.".to_string()))?,
+        };
+
+        let mut rooms = BTreeMap::new();
+
+        for key in key_backup.find_keys(&connection)? {
+            let room_key_backup = rooms.entry(key.room_id.to_string()).or_insert(RoomKeyBackup {
+                sessions: BTreeMap::new(),
+            });
+
+            room_key_backup.sessions.insert(key.session_id, KeyBackupData {
+                first_message_index: key.first_message_index as u64,
+                forwarded_count: key.forwarded_count as u64,
+                is_verified: key.is_verified,
+                session_data: from_str(&key.session_data).map_err(ApiError::from)?,
+            });
+        }
+
+        let response = RoomKeys {
+            rooms: rooms,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The DELETE `/room_keys/keys` endpoint.
+pub struct DeleteRoomKeys;
+
+middleware_chain!(DeleteRoomKeys, [AccessTokenAuth]);
+
+impl Handler for DeleteRoomKeys {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let version = version_param(request)?;
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let response = DB::with_transaction(request, |connection| {
+            let mut key_backup =
+                KeyBackup::find_current_for_write(connection, &user.id, &version)?;
+
+            key_backup.delete_keys(connection)?;
+
+            Ok(RoomKeysUpdateResponse {
+                count: key_backup.count_keys(connection)?,
+                etag: key_backup.etag.to_string(),
+            })
+        })?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Return the required `version` query parameter of a request.
+fn version_param(request: &Request) -> Result<String, ApiError> {
+    let url: Url = request.url.clone().into();
+
+    for (key, value) in url.query_pairs().into_owned() {
+        if key == "version" {
+            return Ok(value);
+        }
+    }
+
+    Err(ApiError::missing_param("version"))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use test::Test;
+
+    /// The content of a backed up key for a session in `!room:ruma.test`.
+    fn room_keys(session_id: &str, first_message_index: u64, is_verified: bool) -> String {
+        format!(
+            r#"{{"rooms": {{"!room:ruma.test": {{"sessions": {{"{}": {{
+                "first_message_index": {},
+                "forwarded_count": 0,
+                "is_verified": {},
+                "session_data": {{"ciphertext": "{}"}}
+            }}}}}}}}}}"#,
+            session_id,
+            first_message_index,
+            is_verified,
+            first_message_index,
+        )
+    }
+
+    /// Create a key backup version and return it.
+    fn create_version(test: &Test, access_token: &str) -> String {
+        let response = test.post(
+            &format!("/_matrix/client/r0/room_keys/version?access_token={}", access_token),
+            r#"{"algorithm": "m.megolm_backup.v1", "auth_data": {"public_key": "abc"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().get("version").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn create_and_get_version() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let version_path = format!(
+            "/_matrix/client/r0/room_keys/version?access_token={}",
+            carl.token,
+        );
+
+        assert_eq!(test.get(&version_path).status, Status::NotFound);
+
+        let version = create_version(&test, &carl.token);
+
+        let response = test.get(&version_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        assert_eq!(json.pointer("/version").unwrap().as_str().unwrap(), version);
+        assert_eq!(json.pointer("/algorithm").unwrap().as_str().unwrap(), "m.megolm_backup.v1");
+        assert_eq!(json.pointer("/auth_data/public_key").unwrap().as_str().unwrap(), "abc");
+        assert_eq!(json.pointer("/count").unwrap().as_i64().unwrap(), 0);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/room_keys/version/{}?access_token={}",
+                version,
+                carl.token,
+            ),
+            r#"{"algorithm": "m.megolm_backup.v1", "auth_data": {"public_key": "def"}}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&version_path);
+        let public_key = response.json().pointer("/auth_data/public_key").unwrap();
+        assert_eq!(public_key.as_str().unwrap(), "def");
+    }
+
+    #[test]
+    fn put_and_get_keys() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let version = create_version(&test, &carl.token);
+
+        let keys_path = format!(
+            "/_matrix/client/r0/room_keys/keys?version={}&access_token={}",
+            version,
+            carl.token,
+        );
+
+        let response = test.put(&keys_path, &room_keys("session", 5, false));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().pointer("/count").unwrap().as_i64().unwrap(), 1);
+        let etag = response.json().pointer("/etag").unwrap().as_str().unwrap().to_string();
+
+        // A key that can decrypt fewer messages does not replace the backed up key.
+        let response = test.put(&keys_path, &room_keys("session", 10, false));
+        assert_eq!(response.json().pointer("/etag").unwrap().as_str().unwrap(), etag);
+
+        // A key from a verified device does.
+        let response = test.put(&keys_path, &room_keys("session", 10, true));
+        assert_eq!(response.json().pointer("/count").unwrap().as_i64().unwrap(), 1);
+        assert!(response.json().pointer("/etag").unwrap().as_str().unwrap() != etag);
+
+        let response = test.get(&keys_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let session = response.json().pointer("/rooms/!room:ruma.test/sessions/session").unwrap();
+        assert_eq!(session.pointer("/first_message_index").unwrap().as_u64().unwrap(), 10);
+        assert_eq!(session.pointer("/is_verified").unwrap().as_bool().unwrap(), true);
+        assert_eq!(session.pointer("/session_data/ciphertext").unwrap().as_str().unwrap(), "10");
+
+        let response = test.delete(&keys_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().pointer("/count").unwrap().as_i64().unwrap(), 0);
+    }
+
+    #[test]
+    fn only_the_current_version_can_be_written() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let old_version = create_version(&test, &carl.token);
+        let current_version = create_version(&test, &carl.token);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/room_keys/keys?version={}&access_token={}",
+                old_version,
+                carl.token,
+            ),
+            &room_keys("session", 0, false),
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_WRONG_ROOM_KEYS_VERSION"
+        );
+        assert_eq!(
+            response.json().get("current_version").unwrap().as_str().unwrap(),
+            current_version
+        );
+    }
+
+    #[test]
+    fn keys_of_other_users_are_not_found() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+        let version = create_version(&test, &carl.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/room_keys/keys?version={}&access_token={}",
+            version,
+            alice.token,
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
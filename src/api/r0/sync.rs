@@ -3,8 +3,9 @@ use std::u64;
 use std::error::Error;
 use std::str::FromStr;
 
-use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use iron::status::Status;
+use persistent::Read;
 use ruma_events::presence::PresenceState;
 use serde_json::from_str;
 use url::Url;
@@ -12,10 +13,20 @@ use url::Url;
 use config::Config;
 use db::DB;
 use error::ApiError;
-use middleware::{AccessTokenAuth, MiddlewareChain};
+use middleware::{AccessTokenAuth, MiddlewareChain, OptionalAccessTokenAuth, RoomIdParam};
+use models::access_token::AccessToken;
+use models::filter::ContentFilter;
+use models::room::Room;
+use models::to_device_message::ToDeviceMessage;
+use models::transaction::Transaction;
 use models::user::User;
 use modifier::SerializableResponse;
-use query::{self, Batch, SyncOptions};
+use query::{self, Batch, RoomWorkers, SyncOptions, DEFAULT_ROOM_INITIAL_SYNC_LIMIT};
+use util::pagination::parse_limit;
+use util::worker_pool::WorkerLimit;
+
+/// The maximum number of timeline events returned by `/rooms/:room_id/initialSync`.
+const MAX_ROOM_INITIAL_SYNC_LIMIT: usize = 100;
 
 /// The `/sync` endpoint.
 pub struct Sync;
@@ -41,8 +52,9 @@ impl Handler for Sync {
         for tuple in query_pairs {
             match (tuple.0.as_ref(), tuple.1.as_ref()) {
                 ("filter", value) => {
-                    let content = from_str(value)
+                    let content: ContentFilter = from_str(value)
                         .map_err(|err| ApiError::invalid_param("filter", err.description()))?;
+                    content.validate()?;
                     filter = Some(content);
                 },
                 ("since", value) => {
@@ -78,15 +90,98 @@ impl Handler for Sync {
             }
         }
 
+        // Only the rooms of initial syncs are assembled concurrently. A single worker would only
+        // take another connection, so such syncs are serial, too.
+        let wanted_workers = if since.is_none() { config.initial_sync_worker_count() } else { 0 };
+        let workers = WorkerLimit::from_request(request)?.reserve(wanted_workers);
+
+        let room_workers = match workers.count() {
+            0 | 1 => None,
+            _ => Some(RoomWorkers {
+                database: request.get::<Read<DB>>().map_err(ApiError::from)?,
+                workers: workers,
+            }),
+        };
+
+        let acknowledged = since.as_ref().map_or(0, |since| since.to_device_key);
         let options = SyncOptions {
-            filter: filter,
+            filter: filter.clone(),
             since: since,
             full_state: full_state,
             set_presence: set_presence,
             timeout: timeout,
         };
 
-        let response = query::Sync::sync(&connection, &config.domain, &user, options)?;
+        let mut response = query::Sync::sync(
+            &connection,
+            &config,
+            &user,
+            &Transaction::token_hash(request),
+            options,
+            room_workers,
+        )?;
+
+        // Messages are only deleted once the device syncs with a batch acknowledging them, so
+        // they are delivered again if a response does not reach the device. Application services
+        // have no devices.
+        let device_id = request.extensions.get::<AccessToken>()
+            .and_then(|access_token| access_token.device_id.clone());
+
+        if let Some(device_id) = device_id {
+            ToDeviceMessage::acknowledge(&connection, &user.id, &device_id, acknowledged)?;
+
+            let (events, last_id) =
+                ToDeviceMessage::find_for_device(&connection, &user.id, &device_id)?;
+
+            response.add_to_device_events(events, last_id);
+        }
+
+        match filter {
+            Some(ref filter) => {
+                let response = response.to_filtered_value(filter)?;
+
+                Ok(Response::with((Status::Ok, SerializableResponse(response))))
+            }
+            None => Ok(Response::with((Status::Ok, SerializableResponse(response)))),
+        }
+    }
+}
+
+/// The deprecated `/rooms/:room_id/initialSync` endpoint, for older clients.
+///
+/// It is composed of the same pieces as `/sync`, so both show the same events.
+pub struct RoomInitialSync;
+
+middleware_chain!(RoomInitialSync, [RoomIdParam, OptionalAccessTokenAuth]);
+
+impl Handler for RoomInitialSync {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>().cloned();
+
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id").clone();
+
+        let limit = parse_limit(
+            request,
+            DEFAULT_ROOM_INITIAL_SYNC_LIMIT,
+            MAX_ROOM_INITIAL_SYNC_LIMIT,
+        )?;
+
+        let connection = DB::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found("The room was not found on this server".to_string()))?,
+        };
+
+        let token_hash = user.as_ref().map(|_| Transaction::token_hash(request));
+        let response = query::RoomInitialSync::initial_sync(
+            &connection,
+            user.as_ref(),
+            token_hash.as_ref().map(String::as_str),
+            &room,
+            limit,
+        )?;
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
@@ -99,11 +194,13 @@ mod tests {
     use test::Test;
     use iron::status::Status;
     use ruma_events::presence::PresenceState;
-    use ruma_identifiers::EventId;
-    use serde_json::from_str;
+    use ruma_identifiers::{EventId, RoomId};
+    use serde_json::{Value, from_str};
 
+    use models::event_purge::EventPurge;
     use models::filter::ContentFilter;
     use query::{SyncOptions};
+    use util::pagination::{Stream, Token};
 
     #[test]
     fn sync_without_new_events() {
@@ -305,6 +402,153 @@ mod tests {
         assert_eq!(timeline.get("limited").unwrap().as_bool().unwrap(), false);
     }
 
+    #[test]
+    fn timelines_are_capped_by_the_server_maximum() {
+        let test = Test::with_config(|config| config.max_sync_timeline_limit = Some(3));
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        for txn_id in 1..6 {
+            let body = format!("Message {}", txn_id);
+
+            assert_eq!(test.send_message(&carl.token, &room_id, &body, txn_id).status, Status::Ok);
+        }
+
+        for filter in &[None, Some(r#"{"room":{"timeline":{"limit":10}}}"#)] {
+            let options = SyncOptions {
+                filter: filter.map(|filter| from_str(filter).unwrap()),
+                since: None,
+                full_state: false,
+                set_presence: None,
+                timeout: 0
+            };
+            let response = test.sync(&carl.token, options);
+            let timeline = response
+                .json()
+                .pointer(&format!("/rooms/join/{}/timeline", room_id))
+                .unwrap();
+
+            assert_eq!(timeline.get("limited").unwrap().as_bool().unwrap(), true);
+
+            let bodies: Vec<&str> = timeline.get("events").unwrap().as_array().unwrap().iter()
+                .map(|event| event.pointer("/content/body").unwrap().as_str().unwrap())
+                .collect();
+
+            assert_eq!(bodies, vec!["Message 3", "Message 4", "Message 5"]);
+
+            let prev_batch = timeline.get("prev_batch").unwrap().as_str().unwrap();
+
+            assert!(Token::decode(Stream::Messages, "from", prev_batch).is_ok());
+        }
+    }
+
+    /// A copy of `value` without the `unsigned.age` of its events, which grows between syncs.
+    fn without_ages(value: &Value) -> Value {
+        match *value {
+            Value::Array(ref values) => Value::Array(values.iter().map(without_ages).collect()),
+            Value::Object(ref map) => Value::Object(map.iter().map(|(key, value)| {
+                let mut value = without_ages(value);
+
+                if key == "unsigned" {
+                    if let Some(unsigned) = value.as_object_mut() {
+                        unsigned.remove("age");
+                    }
+                }
+
+                (key.clone(), value)
+            }).collect()),
+            ref value => value.clone(),
+        }
+    }
+
+    #[test]
+    fn concurrent_initial_syncs_match_serial_ones() {
+        let postgres_url = Test::fresh_database("ruma_test_concurrent_sync");
+        let concurrent = Test::with_committing_database(&postgres_url, |config| {
+            config.db_pool_max_size = 8;
+        });
+        let serial = Test::with_committing_database(&postgres_url, |config| {
+            config.db_pool_max_size = 8;
+            config.serial_initial_sync = true;
+        });
+
+        let carl = concurrent.create_user();
+        let alice = concurrent.create_user();
+
+        for txn_id in 0..6 {
+            let room_id = concurrent.create_public_room(&alice.token);
+
+            assert_eq!(concurrent.join_room(&carl.token, &room_id).status, Status::Ok);
+
+            let body = format!("Message {}", txn_id);
+            let response = concurrent.send_message(&alice.token, &room_id, &body, txn_id);
+
+            assert_eq!(response.status, Status::Ok);
+
+            if txn_id % 3 == 0 {
+                assert_eq!(concurrent.leave_room(&carl.token, &room_id).status, Status::Ok);
+            }
+        }
+
+        let invited_room_id = concurrent.create_room(&alice.token);
+        let response = concurrent.invite(&alice.token, &invited_room_id, &carl.id);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"include_leave":true}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let concurrent_response = concurrent.sync(&carl.token, options.clone());
+        let serial_response = serial.sync(&carl.token, options);
+
+        assert_eq!(concurrent_response.status, Status::Ok);
+        assert_eq!(
+            without_ages(concurrent_response.json().get("rooms").unwrap()),
+            without_ages(serial_response.json().get("rooms").unwrap())
+        );
+        assert_eq!(
+            concurrent_response.json().pointer("/rooms/join").unwrap().as_object().unwrap().len(),
+            4
+        );
+    }
+
+    #[test]
+    fn timelines_with_as_many_events_as_the_limit_are_not_limited() {
+        let test = Test::with_config(|config| config.max_sync_timeline_limit = Some(3));
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let since = Test::get_next_batch(&test.sync(&carl.token, options.clone()));
+
+        for txn_id in 1..4 {
+            let body = format!("Message {}", txn_id);
+
+            assert_eq!(test.send_message(&carl.token, &room_id, &body, txn_id).status, Status::Ok);
+        }
+
+        let options = SyncOptions {
+            since: Some(since),
+            ..options
+        };
+        let response = test.sync(&carl.token, options);
+        let timeline = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline", room_id))
+            .unwrap();
+
+        assert_eq!(timeline.get("limited").unwrap().as_bool().unwrap(), false);
+        assert_eq!(timeline.get("events").unwrap().as_array().unwrap().len(), 3);
+    }
+
     #[test]
     fn sync_joined_room_state() {
         let test = Test::new();
@@ -885,6 +1129,45 @@ mod tests {
         assert_eq!(events.as_array().unwrap().len(), 6);
     }
 
+    #[test]
+    fn initial_sync_of_many_rooms() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_ids: Vec<String> = (0..30).map(|index| {
+            let room_id = test.create_room(&carl.token);
+            let body = format!("Message in room {}", index);
+
+            assert_eq!(test.send_message(&carl.token, &room_id, &body, index).status, Status::Ok);
+
+            room_id
+        }).collect();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().pointer("/rooms/join").unwrap().as_object().unwrap().len(), 30);
+
+        for (index, room_id) in room_ids.iter().enumerate() {
+            let last_event = response.json()
+                .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+                .and_then(Value::as_array)
+                .and_then(|events| events.last())
+                .unwrap();
+
+            assert_eq!(
+                last_event.pointer("/content/body").unwrap(),
+                &format!("Message in room {}", index)
+            );
+        }
+    }
+
     #[test]
     fn basic_since_state() {
         let test = Test::new();
@@ -919,6 +1202,57 @@ mod tests {
         assert_eq!(events.as_array().unwrap().len(), 1);
     }
 
+    #[test]
+    fn member_events_carry_their_previous_content() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id,
+            carl.token
+        );
+
+        assert_eq!(test.put(&displayname_path, r#"{"displayname": "Carl"}"#).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&carl.token, options);
+        let next_batch = Test::get_next_batch(&response);
+
+        assert_eq!(test.put(&displayname_path, r#"{"displayname": "Carlos"}"#).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&carl.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/state/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let member_event = events
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.member")
+            .unwrap();
+
+        let displayname = |pointer: &str| member_event.pointer(pointer).unwrap().as_str().unwrap();
+
+        assert_eq!(displayname("/content/displayname"), "Carlos");
+        assert_eq!(displayname("/prev_content/displayname"), "Carl");
+        assert_eq!(displayname("/unsigned/prev_content/displayname"), "Carl");
+        assert!(member_event.pointer("/unsigned/replaces_state").unwrap().as_str().is_some());
+    }
+
     #[test]
     fn set_presence() {
         let test = Test::new();
@@ -1015,6 +1349,87 @@ mod tests {
         assert_eq!(array.len(), 0);
     }
 
+    #[test]
+    fn account_data_is_filtered_by_type_globs() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        for data_type in &["m.direct", "org.example.cache", "org.example.settings", "com.other"] {
+            let account_data_path = format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                carl.id,
+                data_type,
+                carl.token
+            );
+
+            assert_eq!(test.put(&account_data_path, r#"{"size": 1}"#).status, Status::Ok);
+        }
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{
+                "account_data": {
+                    "limit": 10,
+                    "types": ["m.*", "org.example.*"],
+                    "not_types": ["org.example.cache"]
+                }
+            }"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&carl.token, options);
+        let mut types: Vec<&str> = response
+            .json()
+            .pointer("/account_data/events")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("type").unwrap().as_str().unwrap())
+            .collect();
+        types.sort();
+
+        assert_eq!(types, vec!["m.direct", "org.example.settings"]);
+    }
+
+    #[test]
+    fn account_data_is_limited_by_the_filter() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        for data_type in &["org.example.first", "org.example.second", "org.example.third"] {
+            let account_data_path = format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                carl.id,
+                data_type,
+                carl.token
+            );
+
+            assert_eq!(test.put(&account_data_path, r#"{"size": 1}"#).status, Status::Ok);
+        }
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"account_data": {"limit": 2}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&carl.token, options);
+        let types: Vec<&str> = response
+            .json()
+            .pointer("/account_data/events")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("type").unwrap().as_str().unwrap())
+            .collect();
+
+        assert_eq!(types, vec!["org.example.first", "org.example.second"]);
+    }
+
     #[test]
     fn invalid_since() {
         let test = Test::new();
@@ -1059,4 +1474,398 @@ mod tests {
         let response = test.get(&format!("/_matrix/client/r0/sync?full_state={}&access_token={}", "{10s_234", carl.token));
         assert_eq!(response.status, Status::BadRequest);
     }
+
+    #[test]
+    fn room_initial_sync_shows_the_same_messages_as_sync() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        for txn_id in 1..4 {
+            let response = test.send_message(&alice.token, &room_id, &txn_id.to_string(), txn_id);
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/initialSync?limit=2&access_token={}",
+            room_id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let initial_sync = response.json();
+        assert_eq!(initial_sync.pointer("/room_id").unwrap().as_str().unwrap(), room_id);
+        assert_eq!(initial_sync.pointer("/membership").unwrap().as_str().unwrap(), "join");
+        assert_eq!(initial_sync.pointer("/visibility").unwrap().as_str().unwrap(), "private");
+
+        let state = initial_sync.pointer("/state").unwrap().as_array().unwrap();
+        assert!(state.iter().any(|event| event.get("type").unwrap() == "m.room.create"));
+
+        let chunk = initial_sync.pointer("/messages/chunk").unwrap().as_array().unwrap();
+        let bodies: Vec<&str> = chunk.iter()
+            .map(|event| event.pointer("/content/body").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(bodies, vec!["2", "3"]);
+
+        let filter: ContentFilter = from_str(r#"{"room":{"timeline":{"limit":2}}}"#).unwrap();
+        let options = SyncOptions {
+            filter: Some(filter),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        let timeline = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline", room_id))
+            .unwrap();
+        assert_eq!(timeline.get("events").unwrap().as_array().unwrap(), chunk);
+        assert_eq!(
+            initial_sync.pointer("/messages/start").unwrap(),
+            timeline.get("prev_batch").unwrap()
+        );
+    }
+
+    #[test]
+    fn room_initial_sync_requires_membership_or_world_readable_history() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let initial_sync_path = format!(
+            "/_matrix/client/r0/rooms/{}/initialSync?access_token={}",
+            room_id,
+            bob.token
+        );
+
+        assert_eq!(test.get(&initial_sync_path).status, Status::Forbidden);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "world_readable"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&initial_sync_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("membership").is_none());
+    }
+
+    #[test]
+    fn room_initial_sync_of_world_readable_room_without_access_token() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let initial_sync_path = format!("/_matrix/client/r0/rooms/{}/initialSync", room_id);
+
+        test.send_message(&alice.token, &room_id, "Hidden", 1);
+
+        assert_eq!(test.get(&initial_sync_path).status, Status::Forbidden);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "world_readable"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.send_message(&alice.token, &room_id, "Public", 2);
+
+        let response = test.get(&initial_sync_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("membership").is_none());
+
+        let bodies: Vec<&str> = response.json()
+            .pointer("/messages/chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|event| event.pointer("/content/body"))
+            .map(|body| body.as_str().unwrap())
+            .collect();
+
+        assert_eq!(bodies, vec!["Public"]);
+    }
+
+    /// The bodies of the messages in the timeline of a joined room in the initial sync of a user.
+    fn synced_message_bodies(test: &Test, access_token: &str, room_id: &str) -> Vec<String> {
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(access_token, options);
+
+        response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event.get("type").unwrap() == "m.room.message")
+            .map(|event| event.pointer("/content/body").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sync_shows_shared_history_to_new_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "before", 1).status, Status::Ok);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.send_message(&alice.token, &room_id, "after", 2).status, Status::Ok);
+
+        assert_eq!(synced_message_bodies(&test, &bob.token, &room_id), vec!["before", "after"]);
+    }
+
+    #[test]
+    fn sync_hides_events_before_joining_with_joined_history() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "joined"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "before", 1).status, Status::Ok);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.send_message(&alice.token, &room_id, "after", 2).status, Status::Ok);
+
+        assert_eq!(synced_message_bodies(&test, &bob.token, &room_id), vec!["after"]);
+        assert_eq!(synced_message_bodies(&test, &alice.token, &room_id), vec!["before", "after"]);
+
+        let initial_sync = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/initialSync?access_token={}",
+            room_id,
+            bob.token
+        ));
+        let chunk = initial_sync.json().pointer("/messages/chunk").unwrap().as_array().unwrap();
+
+        assert!(!chunk.iter().any(|event| {
+            event.pointer("/content/body").map_or(false, |body| body == "before")
+        }));
+    }
+
+    #[test]
+    fn timeline_limit_counts_only_visible_events() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.history_visibility",
+            r#"{"history_visibility": "joined"}"#,
+            None,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.send_message(&alice.token, &room_id, "first", 1).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        for txn_id in 2..5 {
+            let body = format!("Hidden {}", txn_id);
+
+            assert_eq!(test.send_message(&alice.token, &room_id, &body, txn_id).status, Status::Ok);
+        }
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.send_message(&alice.token, &room_id, "second", 5).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"timeline":{"limit":3}}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let response = test.sync(&bob.token, options);
+        let timeline = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline", room_id))
+            .unwrap()
+            .clone();
+        let events = timeline.get("events").unwrap().as_array().unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].pointer("/content/body").unwrap().as_str().unwrap(), "first");
+        assert_eq!(events[1].pointer("/content/membership").unwrap().as_str().unwrap(), "join");
+        assert_eq!(events[2].pointer("/content/body").unwrap().as_str().unwrap(), "second");
+        assert_eq!(timeline.get("limited").unwrap().as_bool().unwrap(), true);
+
+        let prev_batch = timeline.get("prev_batch").unwrap().as_str().unwrap();
+
+        assert!(Token::decode(Stream::Messages, "from", prev_batch).is_ok());
+    }
+
+    #[test]
+    fn sync_after_purge_is_limited_with_full_state() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+        let since = Test::get_next_batch(&test.sync(&alice.token, options.clone()));
+
+        assert_eq!(test.send_message(&alice.token, &room_id, "kept", 1).status, Status::Ok);
+
+        let incremental_options = SyncOptions {
+            since: Some(since.clone()),
+            ..options
+        };
+        let response = test.sync(&alice.token, incremental_options.clone());
+        let room = response.json().pointer(&format!("/rooms/join/{}", room_id)).unwrap().clone();
+
+        assert_eq!(room.pointer("/timeline/limited").unwrap().as_bool().unwrap(), false);
+        assert_eq!(room.pointer("/state/events").unwrap().as_array().unwrap().len(), 0);
+
+        test.with_connection(|connection| {
+            let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+
+            EventPurge::record(connection, &room_id, since.room_key + 1).unwrap();
+        });
+
+        let response = test.sync(&alice.token, incremental_options);
+        let room = response.json().pointer(&format!("/rooms/join/{}", room_id)).unwrap().clone();
+        let state_events = room.pointer("/state/events").unwrap().as_array().unwrap();
+
+        assert_eq!(room.pointer("/timeline/limited").unwrap().as_bool().unwrap(), true);
+        assert!(state_events.iter().any(|event| {
+            event.get("type").unwrap().as_str().unwrap() == "m.room.create"
+        }));
+    }
+
+    /// Sync with a filter and return the events of the timeline and state of a joined room.
+    fn filtered_room_events(test: &Test, access_token: &str, room_id: &str, filter: &str)
+    -> (Vec<Value>, Vec<Value>) {
+        let options = SyncOptions {
+            filter: Some(from_str(filter).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0
+        };
+
+        let response = test.sync(access_token, options);
+        let room = response.json().pointer(&format!("/rooms/join/{}", room_id)).unwrap().clone();
+        let events = |pointer: &str| room.pointer(pointer).unwrap().as_array().unwrap().clone();
+
+        (events("/timeline/events"), events("/state/events"))
+    }
+
+    #[test]
+    fn sync_with_event_fields() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        assert_eq!(test.send_message(&carl.token, &room_id, "Hello", 1).status, Status::Ok);
+
+        let (timeline, state) = filtered_room_events(
+            &test,
+            &carl.token,
+            &room_id,
+            r#"{"event_fields":["type","content.body","sender"]}"#,
+        );
+
+        let message = timeline.iter()
+            .find(|event| event.get("type").unwrap().as_str() == Some("m.room.message"))
+            .unwrap();
+        let expected: Value = from_str(&format!(
+            r#"{{"type":"m.room.message","content":{{"body":"Hello"}},"sender":"{}"}}"#,
+            carl.id
+        )).unwrap();
+
+        assert_eq!(message, &expected);
+
+        // Create events have no body, so their content is left out entirely.
+        let create = state.iter()
+            .find(|event| event.get("type").unwrap().as_str() == Some("m.room.create"))
+            .unwrap();
+        let expected: Value = from_str(&format!(
+            r#"{{"type":"m.room.create","sender":"{}"}}"#,
+            carl.id
+        )).unwrap();
+
+        assert_eq!(create, &expected);
+    }
+
+    #[test]
+    fn sync_with_federation_event_format() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let (timeline, state) = filtered_room_events(
+            &test,
+            &carl.token,
+            &room_id,
+            r#"{"event_format":"federation","event_fields":["type","origin"]}"#,
+        );
+
+        for event in timeline.iter().chain(state.iter()) {
+            assert_eq!(event.get("origin").unwrap().as_str().unwrap(), "ruma.test");
+            assert_eq!(event.as_object().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn sync_with_malformed_event_fields_is_rejected() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let filter = r#"{"event_fields":["content..body"]}"#;
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            filter,
+            carl.token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn room_initial_sync_rejects_invalid_limits() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/initialSync?limit=0&access_token={}",
+            room_id,
+            alice.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+    }
 }
@@ -0,0 +1,828 @@
+//! Endpoints for third party identifiers.
+
+use bodyparser;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::status::Status;
+
+use config::Config;
+use db::DB;
+use error::ApiError;
+use locale::Locale;
+use mailer::{Email, PasswordResetMail, ThreepidValidationMail};
+use middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use models::threepid::Threepid;
+use models::threepid_session::{
+    BINDING_PURPOSE,
+    EMAIL_MEDIUM,
+    MSISDN_MEDIUM,
+    PASSWORD_RESET_PURPOSE,
+    ThreepidCredentials,
+    ThreepidSession,
+};
+use models::user::User;
+use modifier::{EmptyResponse, SerializableResponse};
+use msisdn;
+use sms;
+
+/// The POST `/register/email/requestToken` endpoint.
+pub struct RequestEmailRegistrationToken;
+
+/// The POST `/account/3pid/email/requestToken` endpoint.
+pub struct RequestEmailAccountToken;
+
+/// The POST `/account/password/email/requestToken` endpoint.
+pub struct RequestPasswordResetEmailToken;
+
+#[derive(Clone, Debug, Deserialize)]
+struct RequestEmailTokenRequest {
+    /// A secret chosen by the client to identify this validation attempt.
+    pub client_secret: String,
+    /// The email address to validate.
+    pub email: String,
+    /// The client's counter for (re)sending the token.
+    pub send_attempt: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestEmailTokenResponse {
+    /// The session ID to use when submitting the token or binding the email address.
+    pub sid: String,
+}
+
+/// Why an email address is validated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum EmailTokenPurpose {
+    /// To bind an email address that is not bound to any user yet.
+    Binding,
+    /// To reset the password of the user the email address is bound to.
+    PasswordReset,
+}
+
+middleware_chain!(RequestEmailRegistrationToken, [JsonRequest]);
+
+impl Handler for RequestEmailRegistrationToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        request_email_token(request, EmailTokenPurpose::Binding)
+    }
+}
+
+middleware_chain!(RequestEmailAccountToken, [JsonRequest]);
+
+impl Handler for RequestEmailAccountToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        request_email_token(request, EmailTokenPurpose::Binding)
+    }
+}
+
+middleware_chain!(RequestPasswordResetEmailToken, [JsonRequest]);
+
+impl Handler for RequestPasswordResetEmailToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        request_email_token(request, EmailTokenPurpose::PasswordReset)
+    }
+}
+
+/// Start validating an email address and queue an email with the token.
+fn request_email_token(request: &mut Request, purpose: EmailTokenPurpose)
+-> IronResult<Response> {
+    let token_request = match request.get::<bodyparser::Struct<RequestEmailTokenRequest>>() {
+        Ok(Some(token_request)) => token_request,
+        Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+    };
+
+    let address = token_request.email.trim().to_string();
+
+    if !is_email_address(&address) {
+        Err(ApiError::invalid_param("email", "Invalid email address."))?;
+    }
+
+    let config = Config::from_request(request)?;
+    let connection = DB::from_request(request)?;
+
+    let is_bound = Threepid::find_by_address(&connection, EMAIL_MEDIUM, &address)?.is_some();
+
+    match purpose {
+        EmailTokenPurpose::Binding => {
+            Threepid::verify_allowed(EMAIL_MEDIUM, &address, &config.allowed_email_domains)?;
+
+            if is_bound {
+                Err(ApiError::threepid_in_use("Email address is already in use.".to_string()))?;
+            }
+        }
+        EmailTokenPurpose::PasswordReset => {
+            if !is_bound {
+                Err(ApiError::threepid_not_found(None))?;
+            }
+        }
+    }
+
+    let session_purpose = match purpose {
+        EmailTokenPurpose::Binding => BINDING_PURPOSE,
+        EmailTokenPurpose::PasswordReset => PASSWORD_RESET_PURPOSE,
+    };
+
+    let (session, needs_sending) = ThreepidSession::request_token(
+        &connection,
+        EMAIL_MEDIUM,
+        &address,
+        &token_request.client_secret,
+        token_request.send_attempt,
+        session_purpose,
+    )?;
+
+    if needs_sending {
+        let locale = Locale::from_request(request);
+        let domain = config.domain.clone();
+        let token = session.token.clone();
+
+        let email = match purpose {
+            EmailTokenPurpose::Binding => Email::from_template(
+                &address,
+                &ThreepidValidationMail { domain: domain, token: token },
+                locale,
+            ),
+            EmailTokenPurpose::PasswordReset => Email::from_template(
+                &address,
+                &PasswordResetMail { domain: domain, token: token },
+                locale,
+            ),
+        };
+
+        email.queue(&connection)?;
+    }
+
+    let response = RequestEmailTokenResponse {
+        sid: session.id,
+    };
+
+    Ok(Response::with((Status::Ok, SerializableResponse(response))))
+}
+
+/// Check that an address has a non-empty local part and domain and contains no whitespace.
+fn is_email_address(address: &str) -> bool {
+    match address.rfind('@') {
+        Some(index) => {
+            index > 0 && index < address.len() - 1 && !address.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+/// The POST `/register/msisdn/requestToken` endpoint.
+pub struct RequestMsisdnRegistrationToken;
+
+/// The POST `/account/3pid/msisdn/requestToken` endpoint.
+pub struct RequestMsisdnAccountToken;
+
+#[derive(Clone, Debug, Deserialize)]
+struct RequestMsisdnTokenRequest {
+    /// A secret chosen by the client to identify this validation attempt.
+    pub client_secret: String,
+    /// The two-letter country code the phone number should be parsed as.
+    pub country: String,
+    /// The phone number to validate.
+    pub phone_number: String,
+    /// The client's counter for (re)sending the token.
+    pub send_attempt: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMsisdnTokenResponse {
+    /// The session ID to use when submitting the token or binding the phone number.
+    pub sid: String,
+    /// The normalized phone number.
+    pub msisdn: String,
+    /// The phone number in international format.
+    pub intl_fmt: String,
+}
+
+middleware_chain!(RequestMsisdnRegistrationToken, [JsonRequest]);
+
+impl Handler for RequestMsisdnRegistrationToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        request_msisdn_token(request)
+    }
+}
+
+middleware_chain!(RequestMsisdnAccountToken, [JsonRequest]);
+
+impl Handler for RequestMsisdnAccountToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        request_msisdn_token(request)
+    }
+}
+
+/// Start validating a phone number that is not bound to any user yet and text it the token.
+fn request_msisdn_token(request: &mut Request) -> IronResult<Response> {
+    let token_request = match request.get::<bodyparser::Struct<RequestMsisdnTokenRequest>>() {
+        Ok(Some(token_request)) => token_request,
+        Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+    };
+
+    let address = msisdn::normalize(&token_request.country, &token_request.phone_number)?;
+
+    let config = Config::from_request(request)?;
+    let connection = DB::from_request(request)?;
+
+    if Threepid::find_by_address(&connection, MSISDN_MEDIUM, &address)?.is_some() {
+        Err(ApiError::threepid_in_use("Phone number is already in use.".to_string()))?;
+    }
+
+    let (session, needs_sending) = ThreepidSession::request_token(
+        &connection,
+        MSISDN_MEDIUM,
+        &address,
+        &token_request.client_secret,
+        token_request.send_attempt,
+        BINDING_PURPOSE,
+    )?;
+
+    if needs_sending {
+        let body = Locale::from_request(request).format(
+            "sms.validation_code",
+            &[("domain", config.domain.as_str()), ("token", session.token.as_str())],
+        );
+
+        sms::sender(&config).send(&address, &body)?;
+    }
+
+    let response = RequestMsisdnTokenResponse {
+        sid: session.id,
+        intl_fmt: format!("+{}", address),
+        msisdn: address,
+    };
+
+    Ok(Response::with((Status::Ok, SerializableResponse(response))))
+}
+
+/// The POST `/submitToken` endpoints of all media, e.g. `/register/msisdn/submitToken` and
+/// `/account/password/email/submitToken`.
+pub struct SubmitThreepidToken;
+
+#[derive(Clone, Debug, Deserialize)]
+struct SubmitThreepidTokenRequest {
+    /// The session ID returned when requesting the token.
+    pub sid: String,
+    /// The client secret used when requesting the token.
+    pub client_secret: String,
+    /// The token that was sent to the third party identifier.
+    pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SubmitThreepidTokenResponse {
+    /// Whether the third party identifier was validated.
+    pub success: bool,
+}
+
+middleware_chain!(SubmitThreepidToken, [JsonRequest]);
+
+impl Handler for SubmitThreepidToken {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let submit_request = request.get::<bodyparser::Struct<SubmitThreepidTokenRequest>>();
+
+        let submit_request = match submit_request {
+            Ok(Some(submit_request)) => submit_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let credentials = ThreepidCredentials {
+            sid: submit_request.sid,
+            client_secret: submit_request.client_secret,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        let response = SubmitThreepidTokenResponse {
+            success: ThreepidSession::submit_token(
+                &connection,
+                &credentials,
+                &submit_request.token,
+            )?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/account/3pid` endpoint.
+pub struct GetThreepids;
+
+#[derive(Debug, Serialize)]
+struct GetThreepidsResponse {
+    /// The third party identifiers bound to the user.
+    pub threepids: Vec<ThreepidInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct ThreepidInfo {
+    /// The medium of the third party identifier.
+    pub medium: String,
+    /// The third party identifier.
+    pub address: String,
+}
+
+middleware_chain!(GetThreepids, [AccessTokenAuth]);
+
+impl Handler for GetThreepids {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let connection = DB::from_request(request)?;
+
+        let threepids = Threepid::find_by_user(&connection, &user.id)?
+            .into_iter()
+            .map(|threepid| ThreepidInfo {
+                medium: threepid.medium,
+                address: threepid.address,
+            })
+            .collect();
+
+        let response = GetThreepidsResponse {
+            threepids: threepids,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/account/3pid` endpoint.
+pub struct AddThreepid;
+
+#[derive(Clone, Debug, Deserialize)]
+struct AddThreepidRequest {
+    /// The credentials of the validation session of the third party identifier.
+    pub three_pid_creds: ThreepidCredentials,
+}
+
+middleware_chain!(AddThreepid, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for AddThreepid {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let add_request = match request.get::<bodyparser::Struct<AddThreepidRequest>>() {
+            Ok(Some(add_request)) => add_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+
+        let config = Config::from_request(request)?;
+
+        DB::with_transaction(request, |connection| {
+            let session = ThreepidSession::find_validated(
+                connection,
+                &add_request.three_pid_creds,
+                BINDING_PURPOSE,
+            )?;
+
+            let session = match session {
+                Some(session) => session,
+                None => Err(ApiError::threepid_auth_failed(None))?,
+            };
+
+            Threepid::bind(connection, &session, &user.id, &config.allowed_email_domains)?;
+
+            Ok(())
+        })?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::Headers;
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use models::threepid_session::{
+        BINDING_PURPOSE,
+        EMAIL_MEDIUM,
+        ThreepidCredentials,
+        ThreepidSession,
+    };
+    use sms::sent_messages;
+    use test::{Response, Test, TestUser, email_token};
+
+    /// Validate an email address directly in the database and bind it to the user.
+    fn bind_email(test: &Test, user: &TestUser, address: &str) -> Response {
+        let sid = test.with_connection(|connection| {
+            let (session, _) = ThreepidSession::request_token(
+                connection,
+                EMAIL_MEDIUM,
+                address,
+                "email_secret",
+                1,
+                BINDING_PURPOSE,
+            ).unwrap();
+            let credentials = ThreepidCredentials {
+                sid: session.id.clone(),
+                client_secret: "email_secret".to_string(),
+            };
+
+            let validated = ThreepidSession::submit_token(connection, &credentials, &session.token);
+
+            assert!(validated.unwrap());
+
+            session.id
+        });
+
+        let body = format!(
+            r#"{{"three_pid_creds": {{"sid": "{}", "client_secret": "email_secret"}}}}"#,
+            sid
+        );
+
+        test.post(&format!("/_matrix/client/r0/account/3pid?access_token={}", user.token), &body)
+    }
+
+    #[test]
+    fn request_registration_token() {
+        let test = Test::new();
+
+        let body = r#"{
+            "client_secret": "secret",
+            "country": "GB",
+            "phone_number": "07700 900123",
+            "send_attempt": 1
+        }"#;
+        let response = test.post("/_matrix/client/r0/register/msisdn/requestToken", body);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("sid").is_some());
+        assert_eq!(response.json().get("msisdn").unwrap().as_str().unwrap(), "447700900123");
+        assert_eq!(response.json().get("intl_fmt").unwrap().as_str().unwrap(), "+447700900123");
+
+        let messages = sent_messages();
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, "447700900123");
+
+        // The same send attempt must not text the phone number again.
+        test.post("/_matrix/client/r0/register/msisdn/requestToken", body);
+
+        assert_eq!(sent_messages().len(), 1);
+    }
+
+    #[test]
+    fn request_token_in_german() {
+        let test = Test::new();
+
+        let body = r#"{
+            "client_secret": "secret",
+            "country": "GB",
+            "phone_number": "07700 900123",
+            "send_attempt": 1
+        }"#;
+        let mut headers = Headers::new();
+
+        headers.set_raw("Accept-Language", vec![b"de".to_vec()]);
+
+        let response = test.request_with_headers(
+            Method::Post,
+            "/_matrix/client/r0/register/msisdn/requestToken",
+            body,
+            headers,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let messages = sent_messages();
+
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].1.starts_with("Dein Bestätigungscode für ruma.test lautet "));
+    }
+
+    #[test]
+    fn request_token_for_invalid_number() {
+        let test = Test::new();
+
+        let body = r#"{
+            "client_secret": "secret",
+            "country": "GB",
+            "phone_number": "not a number",
+            "send_attempt": 1
+        }"#;
+        let response = test.post("/_matrix/client/r0/register/msisdn/requestToken", body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn bind_phone_number_to_account() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        test.bind_msisdn(&carl, "US", "202-555-0123");
+
+        let response = test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token));
+        let threepids = response.json().get("threepids").unwrap().as_array().unwrap();
+
+        assert_eq!(threepids.len(), 1);
+        assert_eq!(threepids[0].get("medium").unwrap().as_str().unwrap(), "msisdn");
+        assert_eq!(threepids[0].get("address").unwrap().as_str().unwrap(), "12025550123");
+    }
+
+    #[test]
+    fn request_token_for_number_in_use() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        test.bind_msisdn(&carl, "US", "202-555-0123");
+
+        let body = r#"{
+            "client_secret": "other_secret",
+            "country": "US",
+            "phone_number": "+1 202 555 0123",
+            "send_attempt": 1
+        }"#;
+        let response = test.post("/_matrix/client/r0/account/3pid/msisdn/requestToken", body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_IN_USE"
+        );
+    }
+
+    #[test]
+    fn bind_phone_number_with_wrong_token() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let body = r#"{
+            "client_secret": "secret",
+            "country": "US",
+            "phone_number": "202-555-0123",
+            "send_attempt": 1
+        }"#;
+        let response = test.post("/_matrix/client/r0/account/3pid/msisdn/requestToken", body);
+        let sid = response.json().get("sid").unwrap().as_str().unwrap().to_string();
+
+        let body = format!(r#"{{"sid": "{}", "client_secret": "secret", "token": "wrong"}}"#, sid);
+        let response = test.post("/_matrix/client/r0/account/3pid/msisdn/submitToken", &body);
+
+        assert_eq!(response.json().get("success").unwrap().as_bool().unwrap(), false);
+
+        let body = format!(
+            r#"{{"three_pid_creds": {{"sid": "{}", "client_secret": "secret"}}}}"#,
+            sid
+        );
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token),
+            &body,
+        );
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_AUTH_FAILED"
+        );
+    }
+
+    #[test]
+    fn msisdn_token_attempts_are_limited() {
+        let test = Test::new();
+        let request_token = |send_attempt: u64| {
+            let body = format!(
+                r#"{{
+                    "client_secret": "secret",
+                    "country": "US",
+                    "phone_number": "202-555-0123",
+                    "send_attempt": {}
+                }}"#,
+                send_attempt
+            );
+            let response = test.post("/_matrix/client/r0/account/3pid/msisdn/requestToken", &body);
+            let sid = response.json().get("sid").unwrap().as_str().unwrap().to_string();
+            let (_, message) = sent_messages().pop().unwrap();
+
+            (sid, message.split_whitespace().last().unwrap().to_string())
+        };
+        let submit_token = |sid: &str, token: &str| {
+            let body = format!(
+                r#"{{"sid": "{}", "client_secret": "secret", "token": "{}"}}"#,
+                sid,
+                token
+            );
+            let response = test.post("/_matrix/client/r0/account/3pid/msisdn/submitToken", &body);
+
+            response.json().get("success").unwrap().as_bool().unwrap()
+        };
+
+        let (sid, token) = request_token(1);
+
+        for _ in 0..5 {
+            assert!(!submit_token(&sid, "000000x"));
+        }
+
+        // Even the right token fails once the attempts are used up.
+        assert!(!submit_token(&sid, &token));
+
+        // Requesting a token again starts a new session.
+        let (new_sid, new_token) = request_token(2);
+
+        assert!(new_sid != sid);
+        assert!(submit_token(&new_sid, &new_token));
+    }
+
+    #[test]
+    fn validated_sessions_bind_only_once() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let dave = test.create_user();
+        let sid = test.validate_msisdn("US", "202-555-0123", "bind_secret");
+        let body = format!(
+            r#"{{"three_pid_creds": {{"sid": "{}", "client_secret": "bind_secret"}}}}"#,
+            sid
+        );
+        let bind = |user: &TestUser| {
+            let path = format!("/_matrix/client/r0/account/3pid?access_token={}", user.token);
+
+            test.post(&path, &body)
+        };
+
+        assert_eq!(bind(&carl).status, Status::Ok);
+
+        let response = bind(&dave);
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_AUTH_FAILED"
+        );
+    }
+
+    #[test]
+    fn bind_email_of_allowed_domain() {
+        let test = Test::with_config(|config| {
+            config.allowed_email_domains = vec!["Example.com".to_string()];
+        });
+        let carl = test.create_user();
+
+        assert_eq!(bind_email(&test, &carl, "carl@example.COM").status, Status::Ok);
+
+        let response = test.get(&format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token));
+        let threepids = response.json().get("threepids").unwrap().as_array().unwrap();
+
+        assert_eq!(threepids.len(), 1);
+        assert_eq!(threepids[0].get("medium").unwrap().as_str().unwrap(), "email");
+    }
+
+    #[test]
+    fn bind_email_of_denied_domain() {
+        let test = Test::with_config(|config| {
+            config.allowed_email_domains = vec!["example.com".to_string()];
+        });
+        let carl = test.create_user();
+
+        let response = bind_email(&test, &carl, "carl@mail.example.com");
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_DENIED"
+        );
+
+        // Phone numbers are not restricted.
+        test.bind_msisdn(&carl, "US", "202-555-0123");
+    }
+
+    #[test]
+    fn bind_email_without_restriction() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        assert_eq!(bind_email(&test, &carl, "carl@example.org").status, Status::Ok);
+    }
+
+    #[test]
+    fn bind_email_validated_by_mail() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let body = r#"{
+            "client_secret": "secret",
+            "email": "carl@example.com",
+            "send_attempt": 1
+        }"#;
+        let response = test.post("/_matrix/client/r0/account/3pid/email/requestToken", body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let sid = response.json().get("sid").unwrap().as_str().unwrap().to_string();
+
+        // The email is sent once the mail worker runs.
+        let emails = test.deliver_emails();
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, "carl@example.com");
+        assert_eq!(emails[0].subject, "Validate your email address on ruma.test");
+        assert!(emails[0].html.contains(&email_token(&emails[0])));
+
+        // The same send attempt must not send another email.
+        test.post("/_matrix/client/r0/account/3pid/email/requestToken", body);
+
+        assert!(test.deliver_emails().is_empty());
+
+        let body = format!(
+            r#"{{"sid": "{}", "client_secret": "secret", "token": "{}"}}"#,
+            sid,
+            email_token(&emails[0])
+        );
+        let response = test.post("/_matrix/client/r0/account/3pid/email/submitToken", &body);
+
+        assert_eq!(response.json().get("success").unwrap().as_bool().unwrap(), true);
+
+        let body = format!(
+            r#"{{"three_pid_creds": {{"sid": "{}", "client_secret": "secret"}}}}"#,
+            sid
+        );
+        let response = test.post(
+            &format!("/_matrix/client/r0/account/3pid?access_token={}", carl.token),
+            &body,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn request_email_token_in_german() {
+        let test = Test::new();
+
+        let body = r#"{
+            "client_secret": "secret",
+            "email": "carl@example.com",
+            "send_attempt": 1
+        }"#;
+        let mut headers = Headers::new();
+
+        headers.set_raw("Accept-Language", vec![b"de".to_vec()]);
+
+        let response = test.request_with_headers(
+            Method::Post,
+            "/_matrix/client/r0/register/email/requestToken",
+            body,
+            headers,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let emails = test.deliver_emails();
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].subject, "E-Mail-Adresse für ruma.test bestätigen");
+    }
+
+    #[test]
+    fn request_email_token_for_invalid_address() {
+        let test = Test::new();
+
+        let body = r#"{"client_secret": "secret", "email": "carl", "send_attempt": 1}"#;
+        let response = test.post("/_matrix/client/r0/register/email/requestToken", body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+        assert!(test.deliver_emails().is_empty());
+    }
+
+    #[test]
+    fn request_email_token_for_address_in_use() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        assert_eq!(bind_email(&test, &carl, "carl@example.com").status, Status::Ok);
+
+        let body = r#"{"client_secret": "other", "email": "carl@example.com", "send_attempt": 1}"#;
+        let response = test.post("/_matrix/client/r0/account/3pid/email/requestToken", body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_IN_USE"
+        );
+    }
+
+    #[test]
+    fn request_password_reset_token_for_unknown_address() {
+        let test = Test::new();
+
+        let body = r#"{"client_secret": "secret", "email": "carl@example.com", "send_attempt": 1}"#;
+        let response = test.post("/_matrix/client/r0/account/password/email/requestToken", body);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_THREEPID_NOT_FOUND"
+        );
+        assert!(test.deliver_emails().is_empty());
+    }
+}
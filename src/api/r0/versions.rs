@@ -1,7 +1,10 @@
 //! Endpoints for information about supported versions of the Matrix spec.
 
+use std::collections::BTreeMap;
+
 use iron::{Chain, Handler, IronResult, Request, Response, status};
 
+use features::UnstableFeatures;
 use middleware::MiddlewareChain;
 use modifier::SerializableResponse;
 
@@ -12,6 +15,7 @@ pub struct Versions;
 #[derive(Serialize)]
 struct VersionsResponse {
     versions: Vec<&'static str>,
+    unstable_features: BTreeMap<&'static str, bool>,
 }
 
 middleware_chain!(Versions);
@@ -19,10 +23,17 @@ middleware_chain!(Versions);
 impl VersionsResponse {
     /// Returns the list of supported `Versions` of the Matrix spec.
     pub fn supported() -> Self {
+        VersionsResponse::with_unstable_features(&UnstableFeatures::supported())
+    }
+
+    /// Returns the list of supported `Versions` of the Matrix spec along with the given unstable
+    /// features.
+    fn with_unstable_features(unstable_features: &UnstableFeatures) -> Self {
         VersionsResponse {
             versions: vec![
                 "r0.2.0"
-            ]
+            ],
+            unstable_features: unstable_features.features().clone(),
         }
     }
 }
@@ -32,3 +43,34 @@ impl Handler for Versions {
         Ok(Response::with((status::Ok, SerializableResponse(VersionsResponse::supported()))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::{Value, to_value};
+
+    use features::UnstableFeatures;
+    use test::Test;
+    use super::VersionsResponse;
+
+    #[test]
+    fn versions_include_unstable_features() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("versions").unwrap().is_array());
+        assert!(response.json().get("unstable_features").unwrap().is_object());
+    }
+
+    #[test]
+    fn registered_unstable_feature_is_listed() {
+        let mut unstable_features = UnstableFeatures::supported();
+        unstable_features.register("io.ruma.example", true);
+
+        let json = to_value(&VersionsResponse::with_unstable_features(&unstable_features)).unwrap();
+
+        assert_eq!(json.pointer("/unstable_features/io.ruma.example"), Some(&Value::Bool(true)));
+    }
+}
@@ -0,0 +1,7 @@
+//! API endpoints for the 1.x.x version of the Matrix spec.
+
+pub use self::relations::GetRelations;
+pub use self::space::GetHierarchy;
+
+mod relations;
+mod space;
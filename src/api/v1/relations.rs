@@ -0,0 +1,537 @@
+//! Endpoints for event relations.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use router::Router;
+use ruma_identifiers::EventId;
+use serde_json::Value;
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use db::DB;
+use error::{ApiError, MapApiError};
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::event::Event;
+use models::event_relation::EventRelation;
+use models::room_membership::RoomMembership;
+use models::transaction::Transaction;
+use models::user::User;
+use modifier::SerializableResponse;
+use util::pagination::{Stream, Token, parse_limit};
+use views::event::{RenderOptions, render_events};
+
+/// The maximum number of events returned per page, also used when no limit is given.
+const MAX_LIMIT: usize = 50;
+
+/// The GET `/rooms/:room_id/relations/:event_id` and
+/// `/rooms/:room_id/relations/:event_id/:rel_type` endpoints.
+pub struct GetRelations;
+
+#[derive(Debug, Serialize)]
+struct GetRelationsResponse {
+    /// The events relating to the event, newest first.
+    pub chunk: Vec<Value>,
+    /// A token to fetch the next page of older events with, if there are more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+}
+
+middleware_chain!(GetRelations, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetRelations {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let params = request.extensions.get::<Router>().expect("Params object is missing").clone();
+
+        let event_id = match params.find("event_id") {
+            Some(event_id) => {
+                let decoded_event_id = percent_decode(event_id.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("event_id", err.description()))?;
+
+                EventId::try_from(&decoded_event_id).map_api_err(|err| {
+                    ApiError::invalid_param("event_id", err.description())
+                })?
+            }
+            None => Err(ApiError::missing_param("event_id"))?,
+        };
+
+        let rel_type = match params.find("rel_type") {
+            Some(rel_type) => Some(
+                percent_decode(rel_type.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("rel_type", err.description()))?
+                    .to_string()
+            ),
+            None => None,
+        };
+
+        let url: Url = request.url.clone().into();
+
+        let mut before = None;
+        let limit = parse_limit(request, MAX_LIMIT, MAX_LIMIT)? as i64;
+
+        for (key, value) in url.query_pairs().into_owned() {
+            if key == "from" {
+                before = Some(Token::decode(Stream::Relations, "from", &value)?.position());
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(ref membership) if membership.membership == "join" => (),
+            _ => Err(ApiError::unauthorized(
+                format!("The user {} has not joined the room", user.id)
+            ))?,
+        }
+
+        Event::verify_in_room(&connection, &event_id, &room_id)?;
+
+        // Fetch one more relation than requested to find out whether there is another page.
+        let mut relations = EventRelation::find_page(
+            &connection,
+            &event_id,
+            rel_type.as_ref().map(String::as_str),
+            before,
+            limit + 1,
+        )?;
+
+        let next_batch = if relations.len() as i64 > limit {
+            relations.truncate(limit as usize);
+
+            relations.last().map(|relation| Token::new(Stream::Relations, relation.ordering).encode())
+        } else {
+            None
+        };
+
+        let event_ids: Vec<EventId> = relations.into_iter().map(|relation| relation.event_id).collect();
+
+        let token_hash = Transaction::token_hash(request);
+        let render_options = RenderOptions {
+            user_id: Some(&user.id),
+            token_hash: Some(&token_hash),
+            ignored_user_ids: &[],
+            bundle_relations: false,
+        };
+        let chunk = render_events(
+            &connection,
+            Event::find_many(&connection, &event_ids)?,
+            &render_options,
+        )?;
+
+        let response = GetRelationsResponse {
+            chunk: chunk,
+            next_batch: next_batch,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+
+    use diesel::{LoadDsl, insert};
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::Value;
+
+    use models::event::{Event, NewEvent, content_hash};
+    use models::event_relation::EventRelation;
+    use query::SyncOptions;
+    use schema::events;
+    use test::{Response as TestResponse, Test, TestUser};
+
+    const KEYS: [&'static str; 5] = ["👍", "👎", "😄", "🎉", "❤️"];
+
+    /// Send a message and return its event ID.
+    fn send_message(test: &Test, user: &TestUser, room_id: &str) -> String {
+        let response = test.send_message(&user.token, room_id, "Hot take", 1);
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        format!("${}:ruma.test", opaque_id)
+    }
+
+    /// Insert `count` reactions to an event directly into the database.
+    fn seed_reactions(test: &Test, user: &TestUser, room_id: &str, event_id: &str, count: usize) {
+        let room_id = RoomId::try_from(room_id).unwrap();
+        let user_id = UserId::try_from(user.id.as_str()).unwrap();
+
+        let new_events: Vec<NewEvent> = (0..count).map(|i| {
+            let content = format!(
+                r#"{{"m.relates_to": {{"rel_type": "m.annotation", "event_id": "{}", "key": "{}"}}}}"#,
+                event_id,
+                KEYS[i % KEYS.len()]
+            );
+
+            NewEvent {
+                content_hash: content_hash(&content).unwrap(),
+                content: content,
+                event_type: "m.reaction".to_string(),
+                extra_content: None,
+                id: EventId::new("ruma.test").unwrap(),
+                room_id: room_id.clone(),
+                state_key: None,
+                user_id: user_id.clone(),
+            }
+        }).collect();
+
+        test.with_connection(|connection| {
+            let events: Vec<Event> = insert(&new_events)
+                .into(events::table)
+                .get_results(connection)
+                .unwrap();
+
+            EventRelation::create_for_events(connection, &events).unwrap();
+        });
+    }
+
+    /// React to an event with `key`. Each user can only react once per test.
+    fn react(test: &Test, user: &TestUser, room_id: &str, event_id: &str, key: &str) {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.reaction/1?access_token={}",
+            room_id,
+            user.token
+        );
+        let body = format!(
+            r#"{{"m.relates_to": {{"rel_type": "m.annotation", "event_id": "{}", "key": "{}"}}}}"#,
+            event_id,
+            key
+        );
+
+        assert_eq!(test.put(&path, &body).status, Status::Ok);
+    }
+
+    /// Edit a message, returning the response.
+    fn edit(test: &Test, user: &TestUser, room_id: &str, event_id: &str, body: &str, txn_id: u64)
+    -> TestResponse {
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/edit{}?access_token={}",
+            room_id,
+            txn_id,
+            user.token
+        );
+        let body = format!(
+            r#"{{
+                "msgtype": "m.text",
+                "body": "* {0}",
+                "m.new_content": {{"msgtype": "m.text", "body": "{0}"}},
+                "m.relates_to": {{"rel_type": "m.replace", "event_id": "{1}"}}
+            }}"#,
+            body,
+            event_id
+        );
+
+        test.put(&path, &body)
+    }
+
+    /// Return the bundled latest edit of an event in the sync response of a user, if any.
+    fn synced_edit(test: &Test, user: &TestUser, room_id: &str, event_id: &str) -> Option<Value> {
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&user.token, options);
+
+        let timeline = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let message = timeline.iter()
+            .find(|event| event.get("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap();
+
+        message.pointer("/unsigned/m.relations/m.replace").cloned()
+    }
+
+    /// Return the bundled annotations of an event in the sync response of a user.
+    fn synced_annotations(test: &Test, user: &TestUser, room_id: &str, event_id: &str)
+    -> Vec<Value> {
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&user.token, options);
+
+        let timeline = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let message = timeline.iter()
+            .find(|event| event.get("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap();
+
+        message.pointer("/unsigned/m.relations/m.annotation/chunk")
+            .map_or_else(Vec::new, |chunk| chunk.as_array().unwrap().clone())
+    }
+
+    fn relations_page(test: &Test, user: &TestUser, room_id: &str, event_id: &str, from: Option<&str>)
+    -> (Vec<String>, Option<String>) {
+        let mut path = format!(
+            "/_matrix/client/v1/rooms/{}/relations/{}/m.annotation?limit=40&access_token={}",
+            room_id,
+            event_id,
+            user.token
+        );
+
+        if let Some(from) = from {
+            path = format!("{}&from={}", path, from);
+        }
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let event_ids = response.json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("event_id").unwrap().as_str().unwrap().to_string())
+            .collect();
+        let next_batch = response.json()
+            .get("next_batch")
+            .map(|next_batch| next_batch.as_str().unwrap().to_string());
+
+        (event_ids, next_batch)
+    }
+
+    #[test]
+    fn bundled_annotations_are_counts_only() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let event_id = send_message(&test, &alice, &room_id);
+
+        seed_reactions(&test, &alice, &room_id, &event_id, 3000);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+
+        // Bundling 3000 event IDs alone would take well over 100 KB.
+        assert!(response.body.len() < 10_000);
+
+        let timeline = response.json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        let message = timeline.iter()
+            .find(|event| event.get("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap();
+        let chunk = message.pointer("/unsigned/m.relations/m.annotation/chunk")
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        assert_eq!(chunk.len(), KEYS.len());
+
+        for annotation in chunk {
+            let annotation = annotation.as_object().unwrap();
+
+            assert_eq!(annotation.len(), 4);
+            assert_eq!(annotation.get("type").unwrap(), &Value::String("m.reaction".to_string()));
+            assert_eq!(annotation.get("count").unwrap().as_i64().unwrap(), 600);
+            assert_eq!(annotation.get("me").unwrap(), &Value::Bool(true));
+        }
+    }
+
+    #[test]
+    fn annotations_are_aggregated_for_the_viewer() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carol = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carol.token, &room_id).status, Status::Ok);
+
+        let event_id = send_message(&test, &alice, &room_id);
+
+        react(&test, &bob, &room_id, &event_id, "👍");
+        react(&test, &carol, &room_id, &event_id, "👍");
+
+        let annotations = synced_annotations(&test, &alice, &room_id, &event_id);
+
+        assert_eq!(annotations.len(), 1);
+        assert_eq!(annotations[0].get("key").unwrap().as_str().unwrap(), "👍");
+        assert_eq!(annotations[0].get("count").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(annotations[0].get("me").unwrap(), &Value::Bool(false));
+
+        let annotations = synced_annotations(&test, &bob, &room_id, &event_id);
+
+        assert_eq!(annotations[0].get("me").unwrap(), &Value::Bool(true));
+
+        let ignore_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/m.ignored_user_list?access_token={}",
+            alice.id,
+            alice.token
+        );
+        let ignore_body = format!(r#"{{"ignored_users": {{"{}": {{}}}}}}"#, carol.id);
+
+        assert_eq!(test.put(&ignore_path, &ignore_body).status, Status::Ok);
+
+        let annotations = synced_annotations(&test, &alice, &room_id, &event_id);
+
+        assert_eq!(annotations[0].get("count").unwrap().as_i64().unwrap(), 1);
+    }
+
+    #[test]
+    fn relations_paginate_stably() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let event_id = send_message(&test, &alice, &room_id);
+
+        seed_reactions(&test, &alice, &room_id, &event_id, 100);
+
+        let (first_page, mut next_batch) = relations_page(&test, &alice, &room_id, &event_id, None);
+
+        // Reactions arriving mid-pagination are newer than the first page and must not shift the
+        // following pages.
+        seed_reactions(&test, &alice, &room_id, &event_id, 30);
+
+        let mut seen: HashSet<String> = first_page.into_iter().collect();
+
+        while let Some(from) = next_batch {
+            let (page, batch) = relations_page(&test, &alice, &room_id, &event_id, Some(&from));
+
+            for event_id in page {
+                assert!(seen.insert(event_id));
+            }
+
+            next_batch = batch;
+        }
+
+        assert_eq!(seen.len(), 100);
+
+        let (newest_page, _) = relations_page(&test, &alice, &room_id, &event_id, None);
+
+        assert_eq!(newest_page.iter().filter(|event_id| !seen.contains(*event_id)).count(), 30);
+    }
+
+    #[test]
+    fn relations_require_membership() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let event_id = send_message(&test, &alice, &room_id);
+
+        let response = test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/relations/{}?access_token={}",
+            room_id,
+            event_id,
+            bob.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn relations_of_events_of_other_rooms_are_not_found() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let other_room_id = test.create_room(&alice.token);
+        let event_id = send_message(&test, &alice, &other_room_id);
+
+        let response = test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/relations/{}?access_token={}",
+            room_id,
+            event_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn latest_edits_by_the_sender_are_bundled() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_id = send_message(&test, &alice, &room_id);
+
+        assert!(synced_edit(&test, &alice, &room_id, &event_id).is_none());
+
+        assert_eq!(edit(&test, &alice, &room_id, &event_id, "Lukewarm take", 1).status, Status::Ok);
+        let response = edit(&test, &alice, &room_id, &event_id, "Cold take", 2);
+        assert_eq!(response.status, Status::Ok);
+        let opaque_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        let edit_id = format!("${}:ruma.test", opaque_id);
+
+        // Edits of other users are ignored, even if they are newer.
+        assert_eq!(edit(&test, &bob, &room_id, &event_id, "Bob was here", 3).status, Status::Ok);
+
+        for user in &[&alice, &bob] {
+            let bundled_edit = synced_edit(&test, user, &room_id, &event_id).unwrap();
+
+            assert_eq!(bundled_edit.get("event_id").unwrap().as_str().unwrap(), edit_id);
+            assert_eq!(bundled_edit.get("sender").unwrap().as_str().unwrap(), alice.id);
+            assert!(bundled_edit.get("origin_server_ts").unwrap().is_i64());
+            assert_eq!(
+                bundled_edit.pointer("/content/m.new_content/body").unwrap().as_str().unwrap(),
+                "Cold take"
+            );
+        }
+    }
+
+    #[test]
+    fn edits_require_new_content() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let event_id = send_message(&test, &alice, &room_id);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/send/m.room.message/edit?access_token={}",
+                room_id,
+                alice.token
+            ),
+            &format!(
+                r#"{{
+                    "msgtype": "m.text",
+                    "body": "* Cold take",
+                    "m.relates_to": {{"rel_type": "m.replace", "event_id": "{}"}}
+                }}"#,
+                event_id
+            ),
+        );
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "IO_RUMA_BAD_EVENT");
+    }
+}
@@ -0,0 +1,585 @@
+//! Endpoints for spaces.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::error::Error;
+
+use diesel::pg::PgConnection;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+use url::Url;
+
+use db::DB;
+use error::ApiError;
+use middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use models::event::Event;
+use models::room::Room;
+use models::room_membership::RoomMembership;
+use models::user::User;
+use modifier::SerializableResponse;
+use util::pagination::{Stream, Token, parse_limit};
+
+/// The event type linking a space to one of its children.
+const SPACE_CHILD_EVENT_TYPE: &'static str = "m.space.child";
+
+/// The maximum number of rooms returned per page, also used when no limit is given.
+const MAX_LIMIT: usize = 50;
+
+/// The maximum number of levels of children followed, also used when no depth is given.
+const MAX_DEPTH: usize = 10;
+
+/// The maximum number of rooms visited while walking a space, for all pages together.
+const MAX_ROOMS: usize = 250;
+
+/// The state event types that make up a room summary.
+const SUMMARY_EVENT_TYPES: [&'static str; 8] = [
+    "m.room.avatar",
+    "m.room.canonical_alias",
+    "m.room.guest_access",
+    "m.room.history_visibility",
+    "m.room.join_rules",
+    "m.room.name",
+    "m.room.topic",
+    SPACE_CHILD_EVENT_TYPE,
+];
+
+/// The GET `/rooms/:room_id/hierarchy` endpoint.
+pub struct GetHierarchy;
+
+#[derive(Debug, Serialize)]
+struct GetHierarchyResponse {
+    /// The summaries of the rooms in the space, in breadth-first order.
+    pub rooms: Vec<RoomSummary>,
+    /// A token to fetch the next page of rooms with, if there are more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_batch: Option<String>,
+}
+
+/// The summary of a room in a space hierarchy.
+#[derive(Debug, Serialize)]
+struct RoomSummary {
+    /// The ID of the room.
+    pub room_id: RoomId,
+    /// The name of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The topic of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// The URL of the room's avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// The canonical alias of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonical_alias: Option<String>,
+    /// The join rule of the room, e.g. *public*.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_rule: Option<String>,
+    /// The type of the room as set in its m.room.create event, e.g. *m.space*.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_type: Option<String>,
+    /// The number of users that have joined the room.
+    pub num_joined_members: i64,
+    /// Whether the room's history can be read without joining it.
+    pub world_readable: bool,
+    /// Whether guests can join the room.
+    pub guest_can_join: bool,
+    /// The m.space.child events of the room.
+    pub children_state: Vec<ChildState>,
+}
+
+/// A stripped m.space.child state event.
+#[derive(Debug, Serialize)]
+struct ChildState {
+    /// The content of the event.
+    pub content: Value,
+    /// The user who sent the event.
+    pub sender: UserId,
+    /// The ID of the child room.
+    pub state_key: String,
+    /// The type of the event.
+    #[serde(rename="type")]
+    pub event_type: String,
+}
+
+/// The options of a hierarchy request.
+struct HierarchyOptions {
+    /// Only follow children that are marked as suggested.
+    suggested_only: bool,
+    /// How many levels of children to follow, at most `MAX_DEPTH`.
+    max_depth: usize,
+    /// The number of rooms to skip, from a pagination token.
+    skip: usize,
+    /// The maximum number of rooms to return.
+    limit: usize,
+}
+
+middleware_chain!(GetHierarchy, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for GetHierarchy {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let user = request.extensions.get::<User>()
+            .expect("AccessTokenAuth should ensure a user").clone();
+        let room_id = request.extensions.get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let url: Url = request.url.clone().into();
+
+        let mut options = HierarchyOptions {
+            suggested_only: false,
+            max_depth: MAX_DEPTH,
+            skip: 0,
+            limit: parse_limit(request, MAX_LIMIT, MAX_LIMIT)?,
+        };
+
+        for (key, value) in url.query_pairs().into_owned() {
+            match (key.as_ref(), value.as_ref()) {
+                ("suggested_only", "true") => {
+                    options.suggested_only = true;
+                }
+                ("suggested_only", "false") => {
+                    options.suggested_only = false;
+                }
+                ("suggested_only", _) => {
+                    Err(ApiError::invalid_param("suggested_only", "No boolean!"))?;
+                }
+                ("max_depth", value) => {
+                    let max_depth = usize::from_str_radix(value, 10)
+                        .map_err(|err| ApiError::invalid_param("max_depth", err.description()))?;
+
+                    options.max_depth = cmp::min(max_depth, MAX_DEPTH);
+                }
+                ("from", value) => {
+                    let token = Token::decode_for_user_and_room(
+                        Stream::Hierarchy,
+                        "from",
+                        value,
+                        &user.id,
+                        &room_id,
+                    )?;
+
+                    if token.position() < 0 {
+                        Err(ApiError::invalid_param("from", "Not a valid pagination token."))?;
+                    }
+
+                    options.skip = token.position() as usize;
+                }
+                _ => (),
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        if Room::find(&connection, &room_id)?.is_none() {
+            Err(ApiError::not_found(format!("The room {} was not found on this server", room_id)))?;
+        }
+
+        let joined_room_ids: HashSet<RoomId> =
+            RoomMembership::find_room_ids_by_uid_and_state(&connection, &user.id, "join")?
+                .into_iter()
+                .collect();
+
+        if summarize_rooms(&connection, &joined_room_ids, &[room_id.clone()])?.is_empty() {
+            Err(ApiError::unauthorized(format!("The user {} cannot see the room {}", user.id, room_id)))?;
+        }
+
+        let (rooms, has_more) = traverse_space(&connection, &joined_room_ids, &room_id, &options)?;
+
+        let next_batch = if has_more {
+            let position = (options.skip + rooms.len()) as i64;
+
+            Some(Token::for_user_and_room(Stream::Hierarchy, position, &user.id, &room_id).encode())
+        } else {
+            None
+        };
+
+        let response = GetHierarchyResponse {
+            rooms: rooms,
+            next_batch: next_batch,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// Walk the children of a space breadth-first, starting with the space itself.
+///
+/// Every room is visited at most once, so cycles in the space graph are harmless, and the walk
+/// stops after `MAX_ROOMS` rooms. The rooms of each level are summarized together, with a fixed
+/// number of queries. Rooms the user cannot see are left out and their children are not followed.
+/// Returns the requested page of rooms and whether there are more rooms after it.
+fn traverse_space(
+    connection: &PgConnection,
+    joined_room_ids: &HashSet<RoomId>,
+    root_id: &RoomId,
+    options: &HierarchyOptions,
+) -> Result<(Vec<RoomSummary>, bool), ApiError> {
+    let mut visited = HashSet::new();
+    let mut level = vec![root_id.clone()];
+    let mut depth = 0;
+    let mut rooms = Vec::new();
+    let mut position = 0;
+
+    visited.insert(root_id.clone());
+
+    while !level.is_empty() {
+        let mut next_level = Vec::new();
+
+        for summary in summarize_rooms(connection, joined_room_ids, &level)? {
+            if depth < options.max_depth {
+                for child in &summary.children_state {
+                    if options.suggested_only && !is_suggested(child) {
+                        continue;
+                    }
+
+                    let child_id = match RoomId::try_from(child.state_key.as_str()) {
+                        Ok(child_id) => child_id,
+                        Err(_) => continue,
+                    };
+
+                    if visited.len() < MAX_ROOMS && visited.insert(child_id.clone()) {
+                        next_level.push(child_id);
+                    }
+                }
+            }
+
+            if position >= options.skip {
+                if rooms.len() == options.limit {
+                    return Ok((rooms, true));
+                }
+
+                rooms.push(summary);
+            }
+
+            position += 1;
+        }
+
+        level = next_level;
+        depth += 1;
+    }
+
+    Ok((rooms, false))
+}
+
+/// Summarize the given rooms on this server, in the same order, leaving out unknown rooms and
+/// rooms the user cannot see.
+///
+/// A room is visible if its history is world readable, the user has joined it or anyone can join
+/// it.
+fn summarize_rooms(
+    connection: &PgConnection,
+    joined_room_ids: &HashSet<RoomId>,
+    room_ids: &[RoomId],
+) -> Result<Vec<RoomSummary>, ApiError> {
+    let mut summaries: HashMap<RoomId, RoomSummary> = Room::find_many(connection, room_ids)?
+        .into_iter()
+        .map(|room| (room.id.clone(), RoomSummary {
+            room_id: room.id,
+            name: None,
+            topic: None,
+            avatar_url: None,
+            canonical_alias: None,
+            join_rule: None,
+            room_type: room.room_type,
+            num_joined_members: room.joined_member_count,
+            world_readable: false,
+            guest_can_join: false,
+            children_state: Vec::new(),
+        }))
+        .collect();
+
+    let event_types: Vec<EventType> = SUMMARY_EVENT_TYPES.iter()
+        .map(|event_type| EventType::from(*event_type))
+        .collect();
+
+    for event in Event::get_rooms_state_events_by_types(connection, room_ids, &event_types)? {
+        let summary = match summaries.get_mut(&event.room_id) {
+            Some(summary) => summary,
+            None => continue,
+        };
+        let content: Value = from_str(&event.content)?;
+        let event_type = EventType::from(event.event_type.as_ref());
+
+        match event_type {
+            EventType::RoomAvatar => summary.avatar_url = string_field(&content, "url"),
+            EventType::RoomCanonicalAlias => summary.canonical_alias = string_field(&content, "alias"),
+            EventType::RoomGuestAccess => {
+                summary.guest_can_join = string_field(&content, "guest_access")
+                    .map_or(false, |guest_access| guest_access == "can_join");
+            }
+            EventType::RoomHistoryVisibility => {
+                summary.world_readable = string_field(&content, "history_visibility")
+                    .map_or(false, |history_visibility| history_visibility == "world_readable");
+            }
+            EventType::RoomJoinRules => summary.join_rule = string_field(&content, "join_rule"),
+            EventType::RoomName => summary.name = string_field(&content, "name"),
+            EventType::RoomTopic => summary.topic = string_field(&content, "topic"),
+            EventType::Custom(ref event_type) if event_type == SPACE_CHILD_EVENT_TYPE => {
+                // Children are removed from a space by replacing the event with one without `via`.
+                let has_via = content.get("via")
+                    .and_then(Value::as_array)
+                    .map_or(false, |via| !via.is_empty());
+
+                if has_via {
+                    summary.children_state.push(ChildState {
+                        content: content,
+                        sender: event.user_id,
+                        state_key: event.state_key.unwrap_or_default(),
+                        event_type: event.event_type,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(room_ids.iter()
+        .filter_map(|room_id| summaries.remove(room_id))
+        .filter(|summary| {
+            let is_public = summary.join_rule.as_ref()
+                .map_or(false, |join_rule| join_rule == "public");
+
+            summary.world_readable || joined_room_ids.contains(&summary.room_id) || is_public
+        })
+        .collect())
+}
+
+/// Return whether the m.space.child event marks the child as suggested.
+fn is_suggested(child: &ChildState) -> bool {
+    child.content.get("suggested").and_then(Value::as_bool).unwrap_or(false)
+}
+
+/// Return the string value of `key` in an event's content.
+fn string_field(content: &Value, key: &str) -> Option<String> {
+    content.get(key).and_then(Value::as_str).map(ToString::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use iron::status::Status;
+
+    use test::{Response, Test, TestUser};
+
+    use super::MAX_DEPTH;
+
+    /// Create a space with a child room, which links to a grandchild room, which links back to
+    /// the space. Returns the IDs of the space, the child and the grandchild.
+    fn create_space_with_cycle(test: &Test, access_token: &str) -> (String, String, String) {
+        let space_id = test.create_room_with_params(
+            access_token,
+            r#"{"visibility": "public", "name": "Space", "creation_content": {"type": "m.space"}}"#,
+        );
+        let child_id = test.create_public_room(access_token);
+        let grandchild_id = test.create_public_room(access_token);
+
+        let links = [(&space_id, &child_id), (&child_id, &grandchild_id), (&grandchild_id, &space_id)];
+
+        for &(parent_id, child_id) in links.iter() {
+            let response = test.send_state_event(
+                access_token,
+                parent_id,
+                "m.space.child",
+                r#"{"via": ["ruma.test"]}"#,
+                Some(child_id.as_str()),
+            );
+
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        (space_id, child_id, grandchild_id)
+    }
+
+    fn room_ids(response: &Response) -> Vec<String> {
+        response.json()
+            .get("rooms")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| room.get("room_id").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn hierarchy_with_cycle() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let (space_id, child_id, grandchild_id) = create_space_with_cycle(&test, &alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?access_token={}",
+            space_id,
+            bob.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(room_ids(&response), vec![space_id.clone(), child_id, grandchild_id]);
+        assert!(response.json().get("next_batch").is_none());
+
+        let rooms = response.json().get("rooms").unwrap().as_array().unwrap().clone();
+        let space = &rooms[0];
+
+        assert_eq!(space.get("name").unwrap().as_str().unwrap(), "Space");
+        assert_eq!(space.get("room_type").unwrap().as_str().unwrap(), "m.space");
+        assert_eq!(space.get("join_rule").unwrap().as_str().unwrap(), "public");
+        assert_eq!(space.get("num_joined_members").unwrap().as_i64().unwrap(), 1);
+        assert_eq!(space.get("children_state").unwrap().as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn hierarchy_max_depth() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let (space_id, child_id, _) = create_space_with_cycle(&test, &alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?max_depth=1&access_token={}",
+            space_id,
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(room_ids(&response), vec![space_id, child_id]);
+    }
+
+    #[test]
+    fn hierarchy_depth_is_bounded() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let mut chain = vec![test.create_public_room(&alice.token)];
+
+        for _ in 0..MAX_DEPTH + 1 {
+            let child_id = test.create_public_room(&alice.token);
+            let response = test.send_state_event(
+                &alice.token,
+                chain.last().unwrap(),
+                "m.space.child",
+                r#"{"via": ["ruma.test"]}"#,
+                Some(child_id.as_str()),
+            );
+
+            assert_eq!(response.status, Status::Ok);
+
+            chain.push(child_id);
+        }
+
+        let response = test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?max_depth=1000&access_token={}",
+            chain[0],
+            alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(room_ids(&response), &chain[..MAX_DEPTH + 1]);
+    }
+
+    #[test]
+    fn hierarchy_pagination() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let (space_id, _, _) = create_space_with_cycle(&test, &alice.token);
+
+        let mut seen = HashSet::new();
+        let mut from: Option<String> = None;
+
+        loop {
+            let path = match from {
+                Some(ref from) => format!(
+                    "/_matrix/client/v1/rooms/{}/hierarchy?limit=2&from={}&access_token={}",
+                    space_id,
+                    from,
+                    alice.token
+                ),
+                None => format!(
+                    "/_matrix/client/v1/rooms/{}/hierarchy?limit=2&access_token={}",
+                    space_id,
+                    alice.token
+                ),
+            };
+            let response = test.get(&path);
+
+            assert_eq!(response.status, Status::Ok);
+
+            for room_id in room_ids(&response) {
+                assert!(seen.insert(room_id));
+            }
+
+            match response.json().get("next_batch") {
+                Some(next_batch) => from = Some(next_batch.as_str().unwrap().to_string()),
+                None => break,
+            }
+        }
+
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[test]
+    fn hierarchy_pagination_tokens_are_bound_to_the_user_and_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let (space_id, child_id, _) = create_space_with_cycle(&test, &alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &space_id).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?limit=1&access_token={}",
+            space_id,
+            alice.token
+        ));
+        let from = response.json().get("next_batch").unwrap().as_str().unwrap().to_string();
+
+        let hierarchy_page = |room_id: &str, user: &TestUser| test.get(&format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?limit=1&from={}&access_token={}",
+            room_id,
+            from,
+            user.token
+        ));
+
+        assert_eq!(hierarchy_page(&space_id, &alice).status, Status::Ok);
+        assert_eq!(hierarchy_page(&space_id, &bob).status, Status::BadRequest);
+        assert_eq!(hierarchy_page(&child_id, &alice).status, Status::BadRequest);
+    }
+
+    #[test]
+    fn hierarchy_skips_invisible_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let space_id = test.create_public_room(&alice.token);
+        let private_room_id = test.create_private_room(&alice.token);
+
+        test.send_state_event(
+            &alice.token,
+            &space_id,
+            "m.space.child",
+            r#"{"via": ["ruma.test"]}"#,
+            Some(private_room_id.as_str()),
+        );
+
+        let path = format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?access_token={}",
+            space_id,
+            bob.token
+        );
+
+        assert_eq!(room_ids(&test.get(&path)), vec![space_id.clone()]);
+
+        let path = format!(
+            "/_matrix/client/v1/rooms/{}/hierarchy?access_token={}",
+            private_room_id,
+            bob.token
+        );
+
+        assert_eq!(test.get(&path).status, Status::Forbidden);
+    }
+}
@@ -0,0 +1,231 @@
+//! Application services, e.g. bridges, registered in the server configuration.
+//!
+//! An application service authenticates with the `as_token` of its registration instead of an
+//! access token. It acts as its sender user, or as any other user in its user namespaces, which it
+//! names with the `user_id` query parameter, and it can log in as those users with
+//! *m.login.application_service*. User IDs and room aliases in its exclusive namespaces are
+//! reserved for it, see `util::appservice`.
+
+use std::convert::TryFrom;
+
+use iron::typemap::Key;
+use ruma_identifiers::UserId;
+
+use crypto::secrets_are_equal;
+use error::{ApiError, CliError};
+use util::appservice::{compile_namespace, in_namespaces};
+
+/// The registration of an application service, as in its registration file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AppServiceRegistration {
+    /// A unique ID of the application service.
+    pub id: String,
+    /// The token the application service authenticates with.
+    pub as_token: String,
+    /// The localpart of the user the application service acts as by default.
+    pub sender_localpart: String,
+    /// The IDs the application service is interested in.
+    #[serde(default)]
+    pub namespaces: Namespaces,
+}
+
+/// The namespaces of an application service.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Namespaces {
+    /// The user IDs the application service controls.
+    #[serde(default)]
+    pub users: Vec<Namespace>,
+    /// The room aliases the application service manages.
+    #[serde(default)]
+    pub aliases: Vec<Namespace>,
+}
+
+/// IDs matching a regular expression.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Namespace {
+    /// Whether only the application service may use the IDs.
+    #[serde(default)]
+    pub exclusive: bool,
+    /// A regular expression the whole ID must match, e.g. *@irc_.*:example.com*.
+    pub regex: String,
+}
+
+impl AppServiceRegistration {
+    /// Find the registration of the application service with the given token.
+    ///
+    /// The tokens are compared in constant time.
+    pub fn find_by_token<'a>(registrations: &'a [AppServiceRegistration], token: &str)
+    -> Option<&'a AppServiceRegistration> {
+        registrations.iter().find(|registration| secrets_are_equal(&registration.as_token, token))
+    }
+
+    /// The ID of the user the application service acts as by default.
+    pub fn sender(&self, domain: &str) -> Result<UserId, ApiError> {
+        UserId::try_from(&format!("@{}:{}", self.sender_localpart, domain))
+            .map_err(ApiError::from)
+    }
+
+    /// Check whether the application service controls the user, i.e. the user is its sender or
+    /// in one of its user namespaces.
+    pub fn controls_user(&self, user_id: &UserId, domain: &str) -> Result<bool, ApiError> {
+        if *user_id == self.sender(domain)? {
+            return Ok(true);
+        }
+
+        Ok(in_namespaces(&self.namespaces.users, &user_id.to_string()))
+    }
+
+    /// Check that the IDs and tokens of the registrations are unique and their namespaces are
+    /// valid regular expressions.
+    pub fn validate_all(registrations: &[AppServiceRegistration]) -> Result<(), CliError> {
+        for (index, registration) in registrations.iter().enumerate() {
+            for other in &registrations[..index] {
+                if other.id == registration.id || other.as_token == registration.as_token {
+                    return Err(CliError::new(format!(
+                        "app_services {} and {} must have different IDs and tokens.",
+                        other.id,
+                        registration.id
+                    )));
+                }
+            }
+
+            let namespaces = registration.namespaces.users.iter()
+                .chain(registration.namespaces.aliases.iter());
+
+            for namespace in namespaces {
+                if let Err(error) = compile_namespace(&namespace.regex) {
+                    return Err(CliError::new(format!(
+                        "Invalid namespace of app_service {}: {}",
+                        registration.id,
+                        error
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Key for AppServiceRegistration {
+    type Value = AppServiceRegistration;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+    use serde_json::from_str;
+
+    use test::Test;
+    use super::AppServiceRegistration;
+
+    fn registration() -> AppServiceRegistration {
+        from_str(r#"{
+            "id": "irc",
+            "as_token": "as_secret",
+            "sender_localpart": "irc_bot",
+            "namespaces": {"users": [{"exclusive": true, "regex": "@irc_.*:ruma\\.test"}]}
+        }"#).unwrap()
+    }
+
+    fn controls(user_id: &str) -> bool {
+        registration().controls_user(&UserId::try_from(user_id).unwrap(), "ruma.test").unwrap()
+    }
+
+    #[test]
+    fn users_in_the_namespace_are_controlled() {
+        assert!(controls("@irc_bot:ruma.test"));
+        assert!(controls("@irc_carl:ruma.test"));
+        assert!(!controls("@carl:ruma.test"));
+        assert!(!controls("@irc_carl:ruma.test.evil"));
+    }
+
+    #[test]
+    fn invalid_namespaces_are_rejected() {
+        let mut invalid = registration();
+        invalid.id = "broken".to_string();
+        invalid.as_token = "other_secret".to_string();
+        invalid.namespaces.users[0].regex = "@irc_(:ruma.test".to_string();
+
+        assert!(AppServiceRegistration::validate_all(&[registration()]).is_ok());
+        assert!(AppServiceRegistration::validate_all(&[registration(), invalid]).is_err());
+        assert!(AppServiceRegistration::validate_all(&[registration(), registration()]).is_err());
+    }
+
+    #[test]
+    fn app_service_acts_as_users_in_its_namespace() {
+        let test = Test::with_config(|config| config.app_services.push(registration()));
+
+        assert!(test.post(
+            "/_matrix/client/r0/register?access_token=as_secret",
+            r#"{"username": "irc_carl", "password": "secret"}"#
+        ).status.is_success());
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let join_path = format!(
+            "/_matrix/client/r0/rooms/{}/join?access_token=as_secret&user_id=@irc_carl:ruma.test",
+            room_id
+        );
+        assert_eq!(test.post(&join_path, "{}").status, Status::Ok);
+
+        let message_path = format!(
+            "/_matrix/client/r0/rooms/{}/send/m.room.message/1\
+            ?access_token=as_secret&user_id=@irc_carl:ruma.test",
+            room_id
+        );
+        let response = test.put(&message_path, r#"{"msgtype": "m.text", "body": "Hi"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+
+        let outsider_path = format!(
+            "/_matrix/client/r0/pushers?access_token=as_secret&user_id={}",
+            alice.id
+        );
+
+        assert_eq!(test.get(&outsider_path).status, Status::Forbidden);
+        let unknown_token_path = "/_matrix/client/r0/pushers?access_token=guess";
+
+        assert_eq!(test.get(unknown_token_path).status, Status::Forbidden);
+    }
+
+    #[test]
+    fn transactions_are_scoped_to_the_user_acted_as() {
+        let test = Test::with_config(|config| config.app_services.push(registration()));
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let user_path = |path: String, localpart: &str| {
+            format!("{}?access_token=as_secret&user_id=@{}:ruma.test", path, localpart)
+        };
+
+        for localpart in vec!["irc_carl", "irc_dave"] {
+            let body = format!(r#"{{"username": "{}", "password": "secret"}}"#, localpart);
+
+            assert!(test.post("/_matrix/client/r0/register?access_token=as_secret", &body)
+                .status.is_success());
+
+            let join_path = format!("/_matrix/client/r0/rooms/{}/join", room_id);
+
+            assert_eq!(test.post(&user_path(join_path, localpart), "{}").status, Status::Ok);
+        }
+
+        let send = |localpart: &str| {
+            let path = format!("/_matrix/client/r0/rooms/{}/send/m.room.message/1", room_id);
+            let body = r#"{"msgtype": "m.text", "body": "Hi"}"#;
+            let response = test.put(&user_path(path, localpart), body);
+
+            assert_eq!(response.status, Status::Ok);
+
+            response.json().get("event_id").unwrap().as_str().unwrap().to_string()
+        };
+
+        let carls = send("irc_carl");
+        let daves = send("irc_dave");
+
+        assert!(carls != daves);
+        assert_eq!(send("irc_carl"), carls);
+    }
+}
@@ -1,18 +1,14 @@
 extern crate clap;
-extern crate env_logger;
 extern crate ruma;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
 use ruma::config::Config;
+use ruma::logging;
 use ruma::crypto::generate_macaroon_secret_key;
 use ruma::server::Server;
 
 fn main() {
-    if let Err(error) = env_logger::init() {
-        eprintln!("Failed to initialize logger: {}", error);
-    }
-
     let matches = App::new("ruma-client-server")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Client-server APIs for Ruma.")
@@ -45,6 +41,10 @@ fn main() {
                 }
             };
 
+            if let Err(error) = logging::init(config.log_format) {
+                eprintln!("Failed to initialize logger: {}", error);
+            }
+
             match Server::new(&config).mount_client() {
                 Ok(server) => {
                     if let Err(error) = server.run() {
@@ -1,17 +1,13 @@
 extern crate clap;
-extern crate env_logger;
 extern crate ruma;
 
 use clap::{App, AppSettings, Arg, SubCommand};
 
 use ruma::config::Config;
+use ruma::logging;
 use ruma::server::Server;
 
 fn main() {
-    if let Err(error) = env_logger::init() {
-        eprintln!("Failed to initialize logger: {}", error);
-    }
-
     let matches = App::new("ruma-extra-server")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Extra APIs for Ruma.")
@@ -40,6 +36,10 @@ fn main() {
                 }
             };
 
+            if let Err(error) = logging::init(config.log_format) {
+                eprintln!("Failed to initialize logger: {}", error);
+            }
+
             let server = Server::new(&config).mount_extra();
 
             if let Err(error) = server.run() {
@@ -1,17 +1,38 @@
 extern crate clap;
-extern crate env_logger;
 extern crate ruma;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use std::io::{BufRead, stdin};
+use std::process::exit;
 
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use ruma::cli::{Command, run};
 use ruma::config::Config;
+use ruma::logging;
 use ruma::crypto::generate_macaroon_secret_key;
+use ruma::migrations::{SchemaStatus, connect, migrate};
 use ruma::server::Server;
 
 fn main() {
-    if let Err(error) = env_logger::init() {
-        eprintln!("Failed to initialize logger: {}", error);
-    }
+    let config_arg = Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("PATH")
+        .help("Path to a configuration file")
+        .takes_value(true);
+
+    let json_arg = Arg::with_name("json")
+        .long("json")
+        .help("Prints the output as JSON");
+
+    let password_stdin_arg = Arg::with_name("password-stdin")
+        .long("password-stdin")
+        .help("Reads the password from the first line of stdin instead of generating one");
+
+    let user_arg = Arg::with_name("user")
+        .value_name("USER")
+        .help("The ID or localpart of the user")
+        .required(true);
 
     let matches = App::new("ruma")
         .version(env!("CARGO_PKG_VERSION"))
@@ -21,12 +42,72 @@ fn main() {
         .subcommand(
             SubCommand::with_name("run")
                 .about("Runs the server")
-                .arg(Arg::with_name("config")
-                     .short("c")
-                     .long("config")
-                     .value_name("PATH")
-                     .help("Path to a configuration file")
-                     .takes_value(true))
+                .arg(config_arg.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Runs the pending database migrations")
+                .arg(config_arg.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("check-schema")
+                .about("Checks that the database schema matches this version of Ruma")
+                .arg(config_arg.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("user")
+                .about("Manages user accounts without going through the HTTP API")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Creates a user")
+                        .arg(config_arg.clone())
+                        .arg(json_arg.clone())
+                        .arg(password_stdin_arg.clone())
+                        .arg(
+                            Arg::with_name("localpart")
+                                .value_name("LOCALPART")
+                                .help("The localpart of the new user's ID")
+                                .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("admin")
+                                .long("admin")
+                                .help("Makes the user a server administrator")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("set-password")
+                        .about("Replaces the password of a user")
+                        .arg(config_arg.clone())
+                        .arg(json_arg.clone())
+                        .arg(password_stdin_arg)
+                        .arg(user_arg.clone())
+                )
+                .subcommand(
+                    SubCommand::with_name("deactivate")
+                        .about("Deactivates a user and revokes all of their access tokens")
+                        .arg(config_arg.clone())
+                        .arg(json_arg.clone())
+                        .arg(user_arg.clone())
+                        .arg(
+                            Arg::with_name("erase")
+                                .long("erase")
+                                .help("Also redacts the user's events and deletes their profile")
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("token")
+                .about("Manages access tokens without going through the HTTP API")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("revoke-all")
+                        .about("Revokes all access tokens of a user")
+                        .arg(config_arg)
+                        .arg(json_arg)
+                        .arg(user_arg)
+                )
         )
         .subcommand(
             SubCommand::with_name("secret")
@@ -36,13 +117,9 @@ fn main() {
 
     match matches.subcommand() {
         ("run", Some(submatches)) => {
-            let config = match Config::from_file(submatches.value_of("config")) {
-                Ok(config) => config,
-                Err(error) => {
-                    eprintln!("Failed to load configuration file: {}", error);
-
-                    return;
-                }
+            let config = match load_config(submatches) {
+                Some(config) => config,
+                None => return,
             };
 
             match Server::new(&config).mount_all() {
@@ -58,6 +135,83 @@ fn main() {
                 }
             }
         }
+        ("migrate", Some(submatches)) => {
+            let config = match load_config(submatches) {
+                Some(config) => config,
+                None => exit(1),
+            };
+
+            match connect(&config.postgres_url).and_then(|connection| migrate(&connection)) {
+                Ok(0) => println!("The database schema is up to date."),
+                Ok(count) => println!("Ran {} database migrations.", count),
+                Err(error) => {
+                    eprintln!("Failed to migrate the database: {}", error);
+
+                    exit(1);
+                }
+            }
+        }
+        ("check-schema", Some(submatches)) => {
+            let config = match load_config(submatches) {
+                Some(config) => config,
+                None => exit(1),
+            };
+
+            let status = connect(&config.postgres_url)
+                .and_then(|connection| SchemaStatus::check(&connection));
+
+            match status {
+                Ok(ref status) if status.is_current() => println!("{}", status),
+                Ok(status) => {
+                    eprintln!("{}", status);
+
+                    exit(1);
+                }
+                Err(error) => {
+                    eprintln!("Failed to check the database schema: {}", error);
+
+                    exit(1);
+                }
+            }
+        }
+        ("user", Some(submatches)) => match submatches.subcommand() {
+            ("create", Some(command_matches)) => {
+                let command = Command::CreateUser {
+                    localpart: value(command_matches, "localpart"),
+                    password: read_password(command_matches),
+                    admin: command_matches.is_present("admin"),
+                };
+
+                run_command(command_matches, command);
+            }
+            ("set-password", Some(command_matches)) => {
+                let command = Command::SetPassword {
+                    user: value(command_matches, "user"),
+                    password: read_password(command_matches),
+                };
+
+                run_command(command_matches, command);
+            }
+            ("deactivate", Some(command_matches)) => {
+                let command = Command::DeactivateUser {
+                    user: value(command_matches, "user"),
+                    erase: command_matches.is_present("erase"),
+                };
+
+                run_command(command_matches, command);
+            }
+            _ => println!("{}", submatches.usage()),
+        },
+        ("token", Some(submatches)) => match submatches.subcommand() {
+            ("revoke-all", Some(command_matches)) => {
+                let command = Command::RevokeAllTokens {
+                    user: value(command_matches, "user"),
+                };
+
+                run_command(command_matches, command);
+            }
+            _ => println!("{}", submatches.usage()),
+        },
         ("secret", Some(_)) => match generate_macaroon_secret_key() {
             Ok(key) => println!("{}", key),
             Err(error) => eprintln!("Failed to generate macaroon secret key: {}", error),
@@ -65,3 +219,68 @@ fn main() {
         _ => println!("{}", matches.usage()),
     };
 }
+
+/// Load the configuration file given with `--config` and initialize the logger.
+fn load_config(matches: &ArgMatches) -> Option<Config> {
+    let config = match Config::from_file(matches.value_of("config")) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Failed to load configuration file: {}", error);
+
+            return None;
+        }
+    };
+
+    if let Err(error) = logging::init(config.log_format) {
+        eprintln!("Failed to initialize logger: {}", error);
+    }
+
+    Some(config)
+}
+
+/// Run a command on the database and print its output, exiting with an error if it fails.
+fn run_command(matches: &ArgMatches, command: Command) {
+    let config = match load_config(matches) {
+        Some(config) => config,
+        None => exit(1),
+    };
+
+    match run(&config, command, matches.is_present("json")) {
+        Ok(output) => println!("{}", output),
+        Err(error) => {
+            eprintln!("{}", error);
+
+            exit(1);
+        }
+    }
+}
+
+/// The value of a required argument.
+fn value(matches: &ArgMatches, name: &str) -> String {
+    matches.value_of(name).expect("clap should ensure required arguments").to_string()
+}
+
+/// Read the password from the first line of stdin if `--password-stdin` is given.
+fn read_password(matches: &ArgMatches) -> Option<String> {
+    if !matches.is_present("password-stdin") {
+        return None;
+    }
+
+    let mut password = String::new();
+
+    if let Err(error) = stdin().lock().read_line(&mut password) {
+        eprintln!("Failed to read the password from stdin: {}", error);
+
+        exit(1);
+    }
+
+    let password = password.trim_right_matches(|c: char| c == '\r' || c == '\n');
+
+    if password.is_empty() {
+        eprintln!("No password was given on stdin.");
+
+        exit(1);
+    }
+
+    Some(password.to_string())
+}
@@ -0,0 +1,423 @@
+//! Administration of user accounts from the command line.
+//!
+//! Operators need accounts before there is anybody to make requests with, so these commands of
+//! the `ruma` binary work on the database directly. They use the same model functions as the
+//! endpoints, so that e.g. a deactivation has the same effects either way. The output of every
+//! command can be printed as `key: value` lines or as JSON.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use diesel::pg::PgConnection;
+use ruma_identifiers::UserId;
+use serde::Serialize;
+use serde_json::to_string;
+
+use config::Config;
+use crypto::{generate_password, hash_password};
+use error::CliError;
+use migrations::{connect, prepare};
+use models::access_token::AccessToken;
+use models::user::{NewUser, User};
+use util::user_id::{local_user_id, parse_user_id};
+
+/// A command of the `ruma` binary that works on the database.
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// `ruma user create`.
+    CreateUser {
+        /// The localpart of the new user's ID.
+        localpart: String,
+        /// The password, which is generated if not given.
+        password: Option<String>,
+        /// Whether the user should be a server administrator.
+        admin: bool,
+    },
+    /// `ruma user set-password`.
+    SetPassword {
+        /// The ID or localpart of the user.
+        user: String,
+        /// The new password, which is generated if not given.
+        password: Option<String>,
+    },
+    /// `ruma user deactivate`.
+    DeactivateUser {
+        /// The ID or localpart of the user.
+        user: String,
+        /// Whether the user's personal data should be erased as well.
+        erase: bool,
+    },
+    /// `ruma token revoke-all`.
+    RevokeAllTokens {
+        /// The ID or localpart of the user.
+        user: String,
+    },
+}
+
+/// The output of `ruma user create`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CreatedUser {
+    /// The ID of the new user.
+    pub user_id: UserId,
+    /// An access token for the new user.
+    pub access_token: String,
+    /// The generated password, if none was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// The output of `ruma user set-password`.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChangedPassword {
+    /// The ID of the user.
+    pub user_id: UserId,
+    /// The generated password, if none was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// The output of `ruma user deactivate`.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeactivatedUser {
+    /// The ID of the deactivated user.
+    pub user_id: UserId,
+    /// Whether the erasure of the user's personal data was requested.
+    pub erased: bool,
+}
+
+/// The output of `ruma token revoke-all`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RevokedTokens {
+    /// The ID of the user.
+    pub user_id: UserId,
+    /// The number of access tokens that were revoked.
+    pub revoked_tokens: usize,
+}
+
+/// Run a command on the configured database and return its output, formatted as JSON if `json`
+/// is true.
+///
+/// The database schema must be up to date, since the server would refuse to start otherwise.
+pub fn run(config: &Config, command: Command, json: bool) -> Result<String, CliError> {
+    let connection = connect(&config.postgres_url)?;
+
+    prepare(&connection, false)?;
+
+    match command {
+        Command::CreateUser { localpart, password, admin } => {
+            let password = password.as_ref().map(String::as_str);
+            let output = create_user(&connection, config, &localpart, password, admin)?;
+
+            format_output(&output, json)
+        }
+        Command::SetPassword { user, password } => {
+            let password = password.as_ref().map(String::as_str);
+            let output = set_password(&connection, config, &user, password)?;
+
+            format_output(&output, json)
+        }
+        Command::DeactivateUser { user, erase } => {
+            format_output(&deactivate_user(&connection, config, &user, erase)?, json)
+        }
+        Command::RevokeAllTokens { user } => {
+            format_output(&revoke_all_tokens(&connection, config, &user)?, json)
+        }
+    }
+}
+
+/// Create a user on this server with the given localpart.
+///
+/// A password is generated if none is given. The user is a server administrator if `admin` is
+/// true.
+pub fn create_user(
+    connection: &PgConnection,
+    config: &Config,
+    localpart: &str,
+    password: Option<&str>,
+    admin: bool,
+) -> Result<CreatedUser, CliError> {
+    let (password, generated_password) = password_or_generated(password)?;
+
+    let new_user = NewUser {
+        id: local_user_id(localpart, &config.domain, "localpart")?,
+        password_hash: hash_password(&password)?,
+        admin: admin,
+    };
+
+    if User::find_registered_user(connection, &new_user.id)?.is_some() {
+        return Err(CliError::new(format!("The user {} already exists.", new_user.id)));
+    }
+
+    let (user, access_token) = User::create(connection, &new_user, config)?;
+
+    Ok(CreatedUser {
+        user_id: user.id,
+        access_token: access_token,
+        password: generated_password,
+    })
+}
+
+/// Replace the password of an active user, given by ID or localpart.
+///
+/// A password is generated if none is given. Existing access tokens stay valid, like when users
+/// change their password themselves.
+pub fn set_password(
+    connection: &PgConnection,
+    config: &Config,
+    user: &str,
+    password: Option<&str>,
+) -> Result<ChangedPassword, CliError> {
+    let mut user = find_active_user(connection, config, user)?;
+    let (password, generated_password) = password_or_generated(password)?;
+
+    user.set_password(connection, &password)?;
+
+    Ok(ChangedPassword {
+        user_id: user.id,
+        password: generated_password,
+    })
+}
+
+/// Deactivate an active user, given by ID or localpart, and request the erasure of their
+/// personal data if `erase` is true.
+pub fn deactivate_user(connection: &PgConnection, config: &Config, user: &str, erase: bool)
+-> Result<DeactivatedUser, CliError> {
+    let mut user = find_active_user(connection, config, user)?;
+
+    user.deactivate(connection, erase)?;
+
+    Ok(DeactivatedUser {
+        user_id: user.id,
+        erased: erase,
+    })
+}
+
+/// Revoke all access tokens of an active user, given by ID or localpart.
+pub fn revoke_all_tokens(connection: &PgConnection, config: &Config, user: &str)
+-> Result<RevokedTokens, CliError> {
+    let user = find_active_user(connection, config, user)?;
+    let revoked_tokens = AccessToken::revoke_all(connection, &user.id)?;
+
+    Ok(RevokedTokens {
+        user_id: user.id,
+        revoked_tokens: revoked_tokens,
+    })
+}
+
+/// Format the output of a command as `key: value` lines, or as JSON if `json` is true.
+pub fn format_output<T>(output: &T, json: bool) -> Result<String, CliError>
+where T: Display + Serialize {
+    if json {
+        to_string(output).map_err(CliError::from)
+    } else {
+        Ok(output.to_string())
+    }
+}
+
+/// Find an active user on this server by ID or localpart.
+fn find_active_user(connection: &PgConnection, config: &Config, user: &str)
+-> Result<User, CliError> {
+    let user_id = parse_user_id(user, &config.domain, true, "user")?;
+
+    match User::find_active_user(connection, &user_id)? {
+        Some(user) => Ok(user),
+        None => {
+            Err(CliError::new(format!("The user {} does not exist or is deactivated.", user_id)))
+        }
+    }
+}
+
+/// The given password, or a generated one. The generated password is returned a second time, so
+/// that it can be shown to the operator.
+fn password_or_generated(password: Option<&str>) -> Result<(String, Option<String>), CliError> {
+    match password {
+        Some(password) => Ok((password.to_string(), None)),
+        None => {
+            let password = generate_password()?;
+
+            Ok((password.clone(), Some(password)))
+        }
+    }
+}
+
+impl Display for CreatedUser {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "user_id: {}\naccess_token: {}", self.user_id, self.access_token)?;
+
+        if let Some(ref password) = self.password {
+            write!(f, "\npassword: {}", password)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ChangedPassword {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "user_id: {}", self.user_id)?;
+
+        if let Some(ref password) = self.password {
+            write!(f, "\npassword: {}", password)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for DeactivatedUser {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "user_id: {}\nerased: {}", self.user_id, self.erased)
+    }
+}
+
+impl Display for RevokedTokens {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "user_id: {}\nrevoked_tokens: {}", self.user_id, self.revoked_tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use models::user::User;
+    use test::Test;
+    use super::{
+        create_user,
+        deactivate_user,
+        format_output,
+        revoke_all_tokens,
+        set_password,
+    };
+
+    fn login(test: &Test, user: &str, password: &str) -> Status {
+        let body = format!(
+            r#"{{"type": "m.login.password", "user": "{}", "password": "{}"}}"#,
+            user,
+            password
+        );
+
+        test.post("/_matrix/client/r0/login", &body).status
+    }
+
+    fn pushers_status(test: &Test, access_token: &str) -> Status {
+        test.get(&format!("/_matrix/client/r0/pushers?access_token={}", access_token)).status
+    }
+
+    #[test]
+    fn created_user_can_log_in() {
+        let test = Test::new();
+        let config = Test::default_config();
+
+        let created = test.with_connection(|connection| {
+            create_user(connection, &config, "Carl", Some("secret"), false).unwrap()
+        });
+
+        assert_eq!(created.user_id.to_string(), "@carl:ruma.test");
+        assert!(created.password.is_none());
+        assert_eq!(login(&test, "carl", "secret"), Status::Ok);
+        assert_eq!(pushers_status(&test, &created.access_token), Status::Ok);
+
+        let profile = test.get("/_matrix/client/r0/profile/@carl:ruma.test");
+
+        assert_eq!(profile.status, Status::Ok);
+    }
+
+    #[test]
+    fn created_user_without_password_gets_a_generated_one() {
+        let test = Test::new();
+        let config = Test::default_config();
+
+        let created = test.with_connection(|connection| {
+            create_user(connection, &config, "carl", None, false).unwrap()
+        });
+
+        let password = created.password.clone().unwrap();
+
+        assert_eq!(password.len(), 24);
+        assert_eq!(login(&test, "carl", &password), Status::Ok);
+
+        let json = format_output(&created, true).unwrap();
+
+        assert!(json.contains(r#""user_id":"@carl:ruma.test""#));
+        assert!(json.contains(&format!(r#""password":"{}""#, password)));
+    }
+
+    #[test]
+    fn existing_users_are_not_created() {
+        let test = Test::new();
+        let config = Test::default_config();
+
+        test.with_connection(|connection| {
+            assert!(create_user(connection, &config, "carl", Some("secret"), false).is_ok());
+            assert!(create_user(connection, &config, "carl", Some("secret"), false).is_err());
+            assert!(create_user(connection, &config, "carl", Some("secret"), true).is_err());
+        });
+    }
+
+    #[test]
+    fn created_admins_are_server_administrators() {
+        let test = Test::new();
+        let config = Test::default_config();
+
+        let user = test.with_connection(|connection| {
+            let created = create_user(connection, &config, "carl", Some("secret"), true).unwrap();
+
+            User::find_registered_user(connection, &created.user_id).unwrap().unwrap()
+        });
+
+        assert!(user.admin);
+        assert!(user.is_server_admin(&config));
+    }
+
+    #[test]
+    fn set_password_of_user() {
+        let test = Test::new();
+        let user = test.create_user();
+        let config = Test::default_config();
+
+        let changed = test.with_connection(|connection| {
+            set_password(connection, &config, &user.id, Some("new secret")).unwrap()
+        });
+
+        assert_eq!(format_output(&changed, false).unwrap(), format!("user_id: {}", user.id));
+        assert_eq!(login(&test, &user.id, "secret"), Status::Forbidden);
+        assert_eq!(login(&test, &user.id, "new secret"), Status::Ok);
+    }
+
+    #[test]
+    fn deactivated_user_cannot_log_in() {
+        let test = Test::new();
+        let user = test.create_user();
+        let config = Test::default_config();
+
+        test.with_connection(|connection| {
+            deactivate_user(connection, &config, &user.id, false).unwrap();
+
+            assert!(deactivate_user(connection, &config, &user.id, false).is_err());
+        });
+
+        assert_eq!(pushers_status(&test, &user.token), Status::Forbidden);
+        assert_eq!(login(&test, &user.id, "secret"), Status::Forbidden);
+    }
+
+    #[test]
+    fn revoke_all_tokens_of_user() {
+        let test = Test::new();
+        let user = test.create_user();
+        let config = Test::default_config();
+
+        assert_eq!(login(&test, &user.id, "secret"), Status::Ok);
+
+        let revoked = test.with_connection(|connection| {
+            revoke_all_tokens(connection, &config, &user.id).unwrap()
+        });
+
+        assert_eq!(revoked.revoked_tokens, 2);
+        assert_eq!(
+            format_output(&revoked, false).unwrap(),
+            format!("user_id: {}\nrevoked_tokens: 2", user.id)
+        );
+        assert_eq!(pushers_status(&test, &user.token), Status::Forbidden);
+        assert_eq!(login(&test, &user.id, "secret"), Status::Ok);
+    }
+}
@@ -1,8 +1,13 @@
 //! Cryptographic operations.
 
+use std::str::from_utf8;
+
 use argon2rs::verifier::Encoded;
 use base64::encode;
 use rand::{OsRng, Rng};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{SHA1, SHA256, digest};
+use ring::hmac::{SigningKey, sign, verify_with_own_key};
 
 use error::{ApiError, CliError};
 
@@ -32,6 +37,100 @@ pub fn verify_password(encoded_hash: &[u8], plaintext_password: &str)
     Ok(encoded.verify(plaintext_password.as_bytes()))
 }
 
+/// Generates a random nonce, encoded as a hex string.
+pub fn generate_nonce() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+    let mut nonce = [0u8; 16];
+
+    rng.fill_bytes(&mut nonce);
+
+    Ok(encode_hex(&nonce))
+}
+
+/// Generates a random password of 24 Base64 characters, e.g. for accounts created by operators.
+pub fn generate_password() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+    let mut password = [0u8; 18];
+
+    rng.fill_bytes(&mut password);
+
+    Ok(encode(&password))
+}
+
+/// Generates a random access token of `length` bytes, encoded as a hex string.
+pub fn generate_access_token(length: usize) -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+    let mut token = vec![0u8; length];
+
+    rng.fill_bytes(&mut token);
+
+    Ok(encode_hex(&token))
+}
+
+/// Generates a random device ID of ten upper case letters.
+pub fn generate_device_id() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+
+    Ok((0..10).map(|_| (b'A' + rng.gen_range(0, 26)) as char).collect())
+}
+
+/// Generates a random six digit token that is easy to type in, e.g. from an SMS.
+pub fn generate_numeric_token() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+
+    Ok(format!("{:06}", rng.gen_range(0, 1_000_000)))
+}
+
+/// Computes the SHA-256 hash of a value, encoded as a hex string.
+pub fn sha256_hex(value: &[u8]) -> String {
+    encode_hex(digest(&SHA256, value).as_ref())
+}
+
+/// Computes the HMAC-SHA1 of a message, encoded as a hex string.
+pub fn hmac_sha1_hex(key: &[u8], message: &[u8]) -> String {
+    let signing_key = SigningKey::new(&SHA1, key);
+
+    encode_hex(sign(&signing_key, message).as_ref())
+}
+
+/// Compares two secrets in constant time, so the time taken does not reveal how much of a guessed
+/// secret was right. Their hashes are compared, which does not reveal their length either.
+pub fn secrets_are_equal(secret: &str, other: &str) -> bool {
+    verify_slices_are_equal(
+        digest(&SHA256, secret.as_bytes()).as_ref(),
+        digest(&SHA256, other.as_bytes()).as_ref(),
+    ).is_ok()
+}
+
+/// Verifies a hex encoded HMAC-SHA1 of a message in constant time.
+pub fn verify_hmac_sha1_hex(key: &[u8], message: &[u8], hex_mac: &str) -> bool {
+    let mac = match decode_hex(hex_mac) {
+        Some(mac) => mac,
+        None => return false,
+    };
+    let signing_key = SigningKey::new(&SHA1, key);
+
+    verify_with_own_key(&signing_key, message, &mac).is_ok()
+}
+
+/// Encodes bytes as a lowercase hex string.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes a hex string into bytes.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.as_bytes().chunks(2)
+        .map(|pair| {
+            from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
 /// Generates a random salt for Argon2.
 fn generate_salt() -> Result<[u8; 16], ApiError> {
     let mut rng = OsRng::new()?;
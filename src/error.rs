@@ -1,12 +1,13 @@
 //! Error types and conversions.
 
+use std::cmp::max;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::fmt::Error as FmtError;
 use std::io::Error as IoError;
 use std::string::FromUtf8Error;
 use std::sync::PoisonError;
-use std::time::SystemTimeError;
+use std::time::{Duration, SystemTimeError};
 
 use argon2rs::verifier::DecodeError;
 use base64::Base64Error;
@@ -22,11 +23,30 @@ use ruma_identifiers::Error as RumaIdentifiersError;
 use serde::ser::{Serialize, Serializer};
 use serde_json::{Error as SerdeJsonError, to_string};
 
+use locale::Locale;
+
 /// A client-facing error.
 #[derive(Clone, Debug, Serialize)]
 pub struct ApiError {
     errcode: ApiErrorCode,
     error: String,
+    /// The contact of the server administrator, for errors the user cannot resolve themselves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    admin_contact: Option<String>,
+    /// The current version of the user's key backup, for requests to another version.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_version: Option<String>,
+    /// The time in milliseconds the client should wait before retrying the request, which is also
+    /// sent in the `Retry-After` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+    /// Whether the access token was revoked without logging out the device, so the client can
+    /// log in again and keep its encryption keys.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    soft_logout: Option<bool>,
+    /// The catalog key of the message, if the default message of the error code is used.
+    #[serde(skip_serializing)]
+    message_key: Option<&'static str>,
 }
 
 /// The error code for a client-facing error.
@@ -34,11 +54,16 @@ pub struct ApiError {
 pub enum ApiErrorCode {
     /// The requested room alias is already taken.
     AliasTaken,
+    /// A room alias is not valid for the requested operation, e.g. it does not point to the room.
+    BadAlias,
     /// Request contained an event that was not valid input for the requested API.
     BadEvent,
     /// The request contained valid JSON, but it was malformed in some way,
     /// e.g. missing required keys, invalid values for keys.
     BadJson,
+    /// A user ID or room alias is in the exclusive namespace of an application service, or
+    /// outside the namespaces of the application service using it.
+    Exclusive,
     /// Forbidden access, e.g. joining a room without permission, failed login.
     Forbidden,
     /// Guests are not allowed to perform the requested operation.
@@ -46,6 +71,8 @@ pub enum ApiErrorCode {
     /// An input parameter didn't have a valid format.
     InvalidParam,
     /// Too many requests have been sent in a short period of time. Wait a while then try again.
+    /// Also used for requests that would grow a user's list, e.g. the presence list, beyond its
+    /// maximum size.
     LimitExceeded,
     /// A required input parameter was not supplied, e.g. query string or URL path-based parameter.
     MissingParam,
@@ -53,12 +80,36 @@ pub enum ApiErrorCode {
     NotFound,
     /// Request did not contain valid JSON.
     NotJson,
+    /// The server has exceeded one of its limits, e.g. the maximum number of monthly active users.
+    ResourceLimitExceeded,
+    /// The room ID is already in use by another room.
+    RoomInUse,
+    /// A third party identifier could not be validated with the given credentials.
+    ThreepidAuthFailed,
+    /// A third party identifier is not allowed on this server, e.g. an email address of a domain
+    /// that is not allowed.
+    ThreepidDenied,
+    /// A third party identifier is already bound to another user.
+    ThreepidInUse,
+    /// No user is bound to the third party identifier.
+    ThreepidNotFound,
+    /// The server cannot handle the request at the moment, e.g. because the database is
+    /// unavailable.
+    Unavailable,
     /// Ruma does not implement the requested API.
     Unimplemented,
     /// Errors not fitting into another category.
     Unknown,
     /// The access token specified was not recognised.
     UnknownToken,
+    /// The server does not have an endpoint at the requested path.
+    Unrecognized,
+    /// The endpoint at the requested path does not support the request's method.
+    UnrecognizedMethod,
+    /// The user ID is already taken.
+    UserInUse,
+    /// A request to a key backup was not for the current version of the backup.
+    WrongRoomKeysVersion,
 }
 
 /// An operator-facing error.
@@ -82,42 +133,86 @@ pub trait MapApiError {
 }
 
 impl ApiError {
+    /// Create an error with the given message, or with the English message for `message_key` if
+    /// there is none. Only the latter can be translated by `localize`.
+    fn with_default_message(errcode: ApiErrorCode, message: Option<String>, message_key: &'static str)
+    -> ApiError {
+        match message {
+            Some(message) => ApiError {
+                errcode: errcode,
+                error: message,
+                admin_contact: None,
+                current_version: None,
+                retry_after_ms: None,
+                soft_logout: None,
+                message_key: None,
+            },
+            None => ApiError {
+                errcode: errcode,
+                error: Locale::English.message(message_key),
+                admin_contact: None,
+                current_version: None,
+                retry_after_ms: None,
+                soft_logout: None,
+                message_key: Some(message_key),
+            },
+        }
+    }
+
+    /// Translate the message of the error into the given locale, if it is a default message.
+    ///
+    /// The error code is never translated.
+    pub fn localize(&self, locale: Locale) -> ApiError {
+        match self.message_key {
+            Some(message_key) => ApiError {
+                errcode: self.errcode.clone(),
+                error: locale.message(message_key),
+                admin_contact: self.admin_contact.clone(),
+                current_version: self.current_version.clone(),
+                retry_after_ms: self.retry_after_ms,
+                soft_logout: self.soft_logout,
+                message_key: Some(message_key),
+            },
+            None => self.clone(),
+        }
+    }
+
     /// Create an error for requests that try to create a room alias that is already taken.
     pub fn alias_taken<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::AliasTaken,
-            error: message.unwrap_or_else(|| "Alias already taken.".to_string()),
-        }
+        ApiError::with_default_message(
+            ApiErrorCode::AliasTaken,
+            message.into(),
+            "error.alias_taken",
+        )
+    }
+
+    /// Create an error for room aliases that cannot be used for the requested operation.
+    pub fn bad_alias<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(ApiErrorCode::BadAlias, message.into(), "error.bad_alias")
     }
 
     /// Create an error for invalid or incomplete input to event creation API endpoints.
     pub fn bad_event<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::BadEvent,
-            error: message.unwrap_or_else(|| "Invalid event data.".to_string()),
-        }
+        ApiError::with_default_message(ApiErrorCode::BadEvent, message.into(), "error.bad_event")
     }
 
     /// Create an error for invalid or incomplete JSON in request bodies.
     pub fn bad_json<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::BadJson,
-            error: message.unwrap_or_else(|| {
-                "Invalid or missing key-value pairs in JSON.".to_string()
-            }),
-        }
+        ApiError::with_default_message(ApiErrorCode::BadJson, message.into(), "error.bad_json")
+    }
+
+    /// Create an error for application services using user IDs outside their namespaces.
+    pub fn exclusive<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(ApiErrorCode::Exclusive, message.into(), "error.exclusive")
     }
 
     /// Create an error for endpoints where guest accounts are not supported.
     pub fn guest_forbidden<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::GuestAccessForbidden,
-            error: message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
-        }
+        ApiError::with_default_message(
+            ApiErrorCode::GuestAccessForbidden,
+            message.into(),
+            "error.guest_forbidden",
+        )
     }
 
     /// Create an error for invalid input parameters.
@@ -125,83 +220,231 @@ impl ApiError {
         ApiError {
             errcode: ApiErrorCode::InvalidParam,
             error: format!("Parameter '{}' is not valid: {}", param_name, msg),
+            admin_contact: None,
+            current_version: None,
+            retry_after_ms: None,
+            soft_logout: None,
+            message_key: None,
         }
     }
 
+    /// Create an error for requests that would grow a user's list beyond its maximum size.
+    ///
+    /// Unlike rate limits, these errors have no time to retry after and are sent as 403.
+    pub fn list_limit_exceeded<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::LimitExceeded,
+            message.into(),
+            "error.list_limit_exceeded",
+        )
+    }
+
     /// Create an error for requests missing a value for a required parameter.
     pub fn missing_param(param_name: &str) -> ApiError {
         ApiError {
             errcode: ApiErrorCode::MissingParam,
             error: format!("Missing value for required parameter: {}.", param_name),
+            admin_contact: None,
+            current_version: None,
+            retry_after_ms: None,
+            soft_logout: None,
+            message_key: None,
         }
     }
 
     /// Create an error for requests that do not map to a resource.
     pub fn not_found<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::NotFound,
-            error: message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
-        }
+        ApiError::with_default_message(ApiErrorCode::NotFound, message.into(), "error.not_found")
     }
 
     /// Create an error for requests without JSON bodies.
     pub fn not_json<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
+        ApiError::with_default_message(ApiErrorCode::NotJson, message.into(), "error.not_json")
+    }
+
+    /// Create an error for requests that would exceed a limit of the server, e.g. the maximum
+    /// number of monthly active users.
+    pub fn resource_limit_exceeded(admin_contact: Option<String>) -> ApiError {
         ApiError {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
+            admin_contact: admin_contact,
+            ..ApiError::with_default_message(
+                ApiErrorCode::ResourceLimitExceeded,
+                None,
+                "error.resource_limit_exceeded",
+            )
         }
     }
 
+    /// Create an error for requests that would create a room with an ID that is already in use.
+    pub fn room_in_use<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::RoomInUse,
+            message.into(),
+            "error.room_in_use",
+        )
+    }
+
+    /// Create an error for requests that a spam checker rejected.
+    pub fn spam<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(ApiErrorCode::Forbidden, message.into(), "error.spam")
+    }
+
     /// Create an error for requests that are not marked as containing JSON.
     pub fn wrong_content_type<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| {
-                "Request's Content-Type header must be application/json.".to_string()
-            }),
-        }
+        ApiError::with_default_message(
+            ApiErrorCode::NotJson,
+            message.into(),
+            "error.wrong_content_type",
+        )
+    }
+
+    /// Create an error for third party identifiers that could not be validated.
+    pub fn threepid_auth_failed<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::ThreepidAuthFailed,
+            message.into(),
+            "error.threepid_auth_failed",
+        )
+    }
+
+    /// Create an error for third party identifiers that are not allowed on this server.
+    pub fn threepid_denied<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::ThreepidDenied,
+            message.into(),
+            "error.threepid_denied",
+        )
+    }
+
+    /// Create an error for third party identifiers that are already bound to a user.
+    pub fn threepid_in_use<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::ThreepidInUse,
+            message.into(),
+            "error.threepid_in_use",
+        )
+    }
+
+    /// Create an error for third party identifiers that are not bound to any user.
+    pub fn threepid_not_found<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::ThreepidNotFound,
+            message.into(),
+            "error.threepid_not_found",
+        )
     }
 
     /// Create an error for requests that did not provide required authentication parameters.
     pub fn unauthorized<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
-        ApiError {
-            errcode: ApiErrorCode::Forbidden,
-            error: message.unwrap_or_else(|| "Authentication is required.".to_string()),
-        }
+        ApiError::with_default_message(
+            ApiErrorCode::Forbidden,
+            message.into(),
+            "error.unauthorized",
+        )
     }
 
     /// Create an error for Matrix APIs that Ruma intentionally does not implement.
     pub fn unimplemented<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
+        ApiError::with_default_message(
+            ApiErrorCode::Unimplemented,
+            message.into(),
+            "error.unimplemented",
+        )
+    }
+
+    /// Create an error for requests that cannot be handled because the database is unavailable.
+    pub fn unavailable(retry_after: Duration) -> ApiError {
+        ApiError::with_default_message(ApiErrorCode::Unavailable, None, "error.unavailable")
+            .with_retry_after(retry_after)
+    }
+
+    /// Create an error for requests with an access token that is not valid (anymore).
+    ///
+    /// If the token was revoked by a `soft_logout`, the client is told that it can log in again
+    /// with the same device.
+    pub fn unknown_token(soft_logout: bool) -> ApiError {
         ApiError {
-            errcode: ApiErrorCode::Unimplemented,
-            error: message.unwrap_or_else(|| {
-                "The homeserver does not implement this API.".to_string()
-            }),
+            soft_logout: if soft_logout { Some(true) } else { None },
+            ..ApiError::with_default_message(
+                ApiErrorCode::UnknownToken,
+                None,
+                "error.unknown_token",
+            )
         }
     }
 
-    /// Create an error for Matrix APIs that Ruma intentionally does not implement.
-    pub fn limited_rate<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
+    /// Create an error for requests to paths without an endpoint.
+    pub fn unrecognized<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::Unrecognized,
+            message.into(),
+            "error.unrecognized",
+        )
+    }
+
+    /// Create an error for requests with a method the endpoint at their path does not support.
+    pub fn unrecognized_method<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::UnrecognizedMethod,
+            message.into(),
+            "error.unrecognized_method",
+        )
+    }
+
+    /// Create an error for user IDs that are already taken.
+    pub fn user_in_use<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(ApiErrorCode::UserInUse, message.into(), "error.user_in_use")
+    }
+
+    /// Create an error for requests beyond a rate limit, which can be retried after `retry_after`.
+    pub fn limited_rate<T: Into<Option<String>>>(message: T, retry_after: Duration) -> ApiError {
+        ApiError::with_default_message(
+            ApiErrorCode::LimitExceeded,
+            message.into(),
+            "error.limited_rate",
+        ).with_retry_after(retry_after)
+    }
+
+    /// The HTTP status code that should be used to represent the error.
+    fn status_code(&self) -> Status {
+        match self.errcode {
+            // List limits share the error code of rate limits, but retrying does not help.
+            ApiErrorCode::LimitExceeded if self.retry_after_ms.is_none() => Status::Forbidden,
+            ref errcode => errcode.status_code(),
+        }
+    }
+
+    /// Tell the client to wait `retry_after` before retrying the request.
+    ///
+    /// The time is sent in milliseconds in the `retry_after_ms` field, and in whole seconds,
+    /// rounded up, in the `Retry-After` header. Limits that know when they let requests through
+    /// again, e.g. from the age of their oldest entry, should compute it rather than use a
+    /// constant.
+    pub fn with_retry_after(self, retry_after: Duration) -> ApiError {
+        let millis = retry_after.as_secs() * 1000 + retry_after.subsec_nanos() as u64 / 1_000_000;
+
         ApiError {
-            errcode: ApiErrorCode::LimitExceeded,
-            error: message.unwrap_or_else(|| "Too many retry!".to_string()),
+            retry_after_ms: Some(millis),
+            ..self
         }
     }
 
-    /// Create a generic error for anything not specifically covered by the Matrix spec.
-    pub fn unknown<T: Into<Option<String>>>(message: T) -> ApiError {
-        let message = message.into();
+    /// Create an error for requests to a version of a key backup that is not the current one.
+    pub fn wrong_room_keys_version(current_version: Option<String>) -> ApiError {
         ApiError {
-            errcode: ApiErrorCode::Unknown,
-            error: message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
+            current_version: current_version,
+            ..ApiError::with_default_message(
+                ApiErrorCode::WrongRoomKeysVersion,
+                None,
+                "error.wrong_room_keys_version",
+            )
         }
     }
+
+    /// Create a generic error for anything not specifically covered by the Matrix spec.
+    pub fn unknown<T: Into<Option<String>>>(message: T) -> ApiError {
+        ApiError::with_default_message(ApiErrorCode::Unknown, message.into(), "error.unknown")
+    }
 }
 
 impl Display for ApiError {
@@ -322,7 +565,14 @@ impl From<ApiError> for IronError {
 impl Modifier<Response> for ApiError {
     fn modify(self, response: &mut Response) {
         response.headers.set(ContentType::json());
-        response.status = Some(self.errcode.status_code());
+        response.status = Some(self.status_code());
+
+        if let Some(retry_after_ms) = self.retry_after_ms {
+            let seconds = max((retry_after_ms + 999) / 1000, 1);
+
+            response.headers.set_raw("Retry-After", vec![seconds.to_string().into_bytes()]);
+        }
+
         response.body = Some(Box::new(to_string(&self).expect("ApiError should always serialize")));
     }
 }
@@ -334,15 +584,28 @@ impl ApiErrorCode {
             ApiErrorCode::AliasTaken => Status::Conflict,
             ApiErrorCode::BadEvent |
             ApiErrorCode::BadJson => Status::UnprocessableEntity,
+            ApiErrorCode::Exclusive |
             ApiErrorCode::Forbidden |
-            ApiErrorCode::GuestAccessForbidden => Status::Forbidden,
+            ApiErrorCode::GuestAccessForbidden |
+            ApiErrorCode::ResourceLimitExceeded |
+            ApiErrorCode::ThreepidDenied |
+            ApiErrorCode::WrongRoomKeysVersion => Status::Forbidden,
+            ApiErrorCode::BadAlias |
             ApiErrorCode::InvalidParam |
             ApiErrorCode::MissingParam |
-            ApiErrorCode::NotJson => Status::BadRequest,
+            ApiErrorCode::NotJson |
+            ApiErrorCode::RoomInUse |
+            ApiErrorCode::ThreepidInUse |
+            ApiErrorCode::ThreepidNotFound |
+            ApiErrorCode::Unrecognized |
+            ApiErrorCode::UserInUse => Status::BadRequest,
             ApiErrorCode::LimitExceeded => Status::TooManyRequests,
+            ApiErrorCode::UnrecognizedMethod => Status::MethodNotAllowed,
             ApiErrorCode::NotFound |
             ApiErrorCode::Unimplemented => Status::NotFound,
+            ApiErrorCode::Unavailable => Status::ServiceUnavailable,
             ApiErrorCode::Unknown => Status::InternalServerError,
+            ApiErrorCode::ThreepidAuthFailed |
             ApiErrorCode::UnknownToken => Status::Unauthorized,
         }
     }
@@ -352,8 +615,10 @@ impl Serialize for ApiErrorCode {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
         let value = match *self {
             ApiErrorCode::AliasTaken => "IO_RUMA_ALIAS_TAKEN",
+            ApiErrorCode::BadAlias => "M_BAD_ALIAS",
             ApiErrorCode::BadEvent => "IO_RUMA_BAD_EVENT",
             ApiErrorCode::BadJson => "M_BAD_JSON",
+            ApiErrorCode::Exclusive => "M_EXCLUSIVE",
             ApiErrorCode::Forbidden => "M_FORBIDDEN",
             ApiErrorCode::GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
             ApiErrorCode::InvalidParam => "IO_RUMA_INVALID_PARAM",
@@ -361,9 +626,20 @@ impl Serialize for ApiErrorCode {
             ApiErrorCode::MissingParam => "M_MISSING_PARAM",
             ApiErrorCode::NotFound => "M_NOT_FOUND",
             ApiErrorCode::NotJson => "M_NOT_JSON",
+            ApiErrorCode::ResourceLimitExceeded => "M_RESOURCE_LIMIT_EXCEEDED",
+            ApiErrorCode::RoomInUse => "M_ROOM_IN_USE",
+            ApiErrorCode::ThreepidAuthFailed => "M_THREEPID_AUTH_FAILED",
+            ApiErrorCode::ThreepidDenied => "M_THREEPID_DENIED",
+            ApiErrorCode::ThreepidInUse => "M_THREEPID_IN_USE",
+            ApiErrorCode::ThreepidNotFound => "M_THREEPID_NOT_FOUND",
+            ApiErrorCode::Unavailable => "M_UNKNOWN",
             ApiErrorCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
             ApiErrorCode::Unknown => "M_UNKNOWN",
             ApiErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ApiErrorCode::Unrecognized => "M_UNRECOGNIZED",
+            ApiErrorCode::UnrecognizedMethod => "M_UNRECOGNIZED",
+            ApiErrorCode::UserInUse => "M_USER_IN_USE",
+            ApiErrorCode::WrongRoomKeysVersion => "M_WRONG_ROOM_KEYS_VERSION",
         };
 
         serializer.serialize_str(value)
@@ -411,10 +687,14 @@ impl<T, E> MapApiError for Result<T, E> where E: Debug {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use iron::Response;
     use iron::headers::ContentType;
     use iron::modifier::Modifier;
     use iron::status::Status;
+    use serde_json::to_value;
+
     use error::ApiError;
 
     #[test]
@@ -426,4 +706,38 @@ mod tests {
         assert_eq!(response.headers.get::<ContentType>().unwrap(), &ContentType::json());
         assert_eq!(response.status.unwrap(), Status::Forbidden);
     }
+
+    #[test]
+    fn unavailable_errors_tell_when_to_retry() {
+        let mut response = Response::new();
+        let error = ApiError::unavailable(Duration::from_millis(1500));
+        error.modify(&mut response);
+
+        assert_eq!(response.status.unwrap(), Status::ServiceUnavailable);
+        assert_eq!(response.headers.get_raw("Retry-After").unwrap(), &[b"2".to_vec()]);
+    }
+
+    #[test]
+    fn retry_after_is_rounded_up_to_whole_seconds() {
+        for &(millis, seconds) in &[(0, b"1"), (999, b"1"), (1000, b"1"), (1001, b"2")] {
+            let mut response = Response::new();
+            let error = ApiError::limited_rate(None, Duration::from_millis(millis));
+            error.modify(&mut response);
+
+            assert_eq!(response.status.unwrap(), Status::TooManyRequests);
+            assert_eq!(response.headers.get_raw("Retry-After").unwrap(), &[seconds.to_vec()]);
+        }
+    }
+
+    #[test]
+    fn list_limits_share_the_code_of_rate_limits() {
+        let mut response = Response::new();
+        let error = ApiError::list_limit_exceeded(None);
+        let json = to_value(&error).unwrap();
+        error.modify(&mut response);
+
+        assert_eq!(json["errcode"].as_str(), Some("M_LIMIT_EXCEEDED"));
+        assert_eq!(response.status.unwrap(), Status::Forbidden);
+        assert!(response.headers.get_raw("Retry-After").is_none());
+    }
 }
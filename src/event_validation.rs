@@ -0,0 +1,349 @@
+//! Validation of events created by clients.
+//!
+//! Events are built from their JSON content through the types of ruma-events, which rejects
+//! content that does not match the structure of the event type.
+
+use std::convert::TryInto;
+
+use ruma_events::call::answer::AnswerEvent;
+use ruma_events::call::candidates::CandidatesEvent;
+use ruma_events::call::hangup::HangupEvent;
+use ruma_events::call::invite::InviteEvent;
+use ruma_events::room::avatar::AvatarEvent;
+use ruma_events::room::guest_access::GuestAccessEvent;
+use ruma_events::room::history_visibility::HistoryVisibilityEvent;
+use ruma_events::room::join_rules::JoinRulesEvent;
+use ruma_events::room::message::MessageEvent;
+use ruma_events::room::name::NameEvent;
+use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
+use ruma_events::room::topic::TopicEvent;
+use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde::Deserialize;
+use serde_json::{Value, from_str, from_value};
+
+use config::Config;
+use error::{ApiError, MapApiError};
+use models::event::NewEvent;
+use util::glob::glob;
+
+macro_rules! room_event {
+    (
+        $ty:ident,
+        $event_content:ident,
+        $event_type:ident,
+        $event_id:ident,
+        $room_id:ident,
+        $user_id:ident
+    ) => {
+        $ty {
+            content: extract_event_content($event_content, &$event_type)?,
+            event_id: $event_id.clone(),
+            event_type: $event_type.clone(),
+            room_id: $room_id.clone(),
+            unsigned: None,
+            user_id: $user_id.clone(),
+        }.try_into().map_err(ApiError::from)?
+    };
+}
+
+macro_rules! state_event {
+    (
+        $ty:ident,
+        $event_content:ident,
+        $event_type:ident,
+        $event_id:ident,
+        $room_id:ident,
+        $state_key:ident,
+        $user_id:ident
+    ) => {
+        $ty {
+            content: extract_event_content($event_content, &$event_type)?,
+            event_id: $event_id.clone(),
+            event_type: $event_type.clone(),
+            prev_content: None,
+            room_id: $room_id.clone(),
+            state_key: $state_key.to_string(),
+            unsigned: None,
+            user_id: $user_id.clone(),
+        }.try_into().map_err(ApiError::from)?
+    };
+}
+
+/// Build a new room event of a type that can be sent with `/rooms/:room_id/send`, validating its
+/// content.
+pub fn new_room_event(
+    event_type: &EventType,
+    event_content: Value,
+    event_id: &EventId,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<NewEvent, ApiError> {
+    let room_event: NewEvent = match *event_type {
+        EventType::CallAnswer => {
+            room_event!(AnswerEvent, event_content, event_type, event_id, room_id, user_id)
+        }
+        EventType::CallCandidates => {
+            room_event!(CandidatesEvent, event_content, event_type, event_id, room_id, user_id)
+        }
+        EventType::CallHangup => {
+            room_event!(HangupEvent, event_content, event_type, event_id, room_id, user_id)
+        }
+        EventType::CallInvite => {
+            room_event!(InviteEvent, event_content, event_type, event_id, room_id, user_id)
+        }
+        EventType::RoomMessage => {
+            room_event!(MessageEvent, event_content, event_type, event_id, room_id, user_id)
+        }
+        EventType::Custom(ref custom_event_type) => {
+            CustomRoomEvent {
+                content: event_content,
+                event_id: event_id.clone(),
+                event_type: EventType::Custom(custom_event_type.clone()),
+                room_id: room_id.clone(),
+                unsigned: None,
+                user_id: user_id.clone(),
+            }.try_into().map_err(ApiError::from)?
+        }
+        _ => {
+            return Err(ApiError::bad_event(
+                format!("Events of type {} cannot be created with this API.", event_type)
+            ));
+        }
+    };
+
+    verify_call_content(event_type, &room_event.content)?;
+
+    Ok(room_event)
+}
+
+/// Check the parts of call events that their content types leave open.
+///
+/// The content types already require a `call_id`, a `version` and, for invites, a `lifetime`,
+/// but allow an empty `call_id`, an invite with an answer or an empty SDP.
+fn verify_call_content(event_type: &EventType, content: &str) -> Result<(), ApiError> {
+    let description_type = match *event_type {
+        EventType::CallInvite => Some("offer"),
+        EventType::CallAnswer => Some("answer"),
+        EventType::CallCandidates | EventType::CallHangup => None,
+        _ => return Ok(()),
+    };
+
+    let content: Value = from_str(content).map_err(ApiError::from)?;
+
+    match content.get("call_id").and_then(Value::as_str) {
+        Some(call_id) if !call_id.trim().is_empty() => {}
+        _ => {
+            let message = format!("Events of type {} need a call_id.", event_type);
+
+            return Err(ApiError::bad_event(message));
+        }
+    }
+
+    if let Some(description_type) = description_type {
+        let description = content.get(description_type);
+        let session_type = description.and_then(|description| description.get("type"));
+        let sdp = description.and_then(|description| description.get("sdp"));
+
+        match (session_type.and_then(Value::as_str), sdp.and_then(Value::as_str)) {
+            (Some(session_type), Some(sdp))
+                if session_type == description_type && !sdp.trim().is_empty() => {}
+            _ => {
+                return Err(ApiError::bad_event(format!(
+                    "The {} of events of type {} must be a session description of type {} with \
+                    an SDP.",
+                    description_type,
+                    event_type,
+                    description_type
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Build a new state event of a type that can be sent with `/rooms/:room_id/state`, validating
+/// its content.
+///
+/// `m.room.canonical_alias` events are rejected, as their aliases have to be checked against the
+/// room.
+pub fn new_state_event(
+    event_type: &EventType,
+    event_content: Value,
+    state_key: &str,
+    event_id: &EventId,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<NewEvent, ApiError> {
+    let state_event: NewEvent = match *event_type {
+        EventType::RoomAvatar => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                AvatarEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomGuestAccess => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                GuestAccessEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomHistoryVisibility => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                HistoryVisibilityEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomJoinRules => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                JoinRulesEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomName => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                NameEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomPowerLevels => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                PowerLevelsEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomThirdPartyInvite => {
+            state_event!(
+                ThirdPartyInviteEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::RoomTopic => {
+            ensure_empty_state_key(state_key, event_type)?;
+
+            state_event!(
+                TopicEvent,
+                event_content,
+                event_type,
+                event_id,
+                room_id,
+                state_key,
+                user_id
+            )
+        }
+        EventType::Custom(ref custom_event_type) => {
+            CustomStateEvent {
+                content: event_content,
+                event_id: event_id.clone(),
+                event_type: EventType::Custom(custom_event_type.clone()),
+                prev_content: None,
+                room_id: room_id.clone(),
+                state_key: state_key.to_string(),
+                unsigned: None,
+                user_id: user_id.clone(),
+            }.try_into().map_err(ApiError::from)?
+        }
+        _ => {
+            return Err(ApiError::bad_event(
+                format!("Events of type {} cannot be created with this API.", event_type)
+            ));
+        }
+    };
+
+    Ok(state_event)
+}
+
+/// Check that users may send events of the type, according to `allowed_event_types` and
+/// `denied_event_types`.
+///
+/// This applies to every event whose type users choose: events sent to a room, the initial state
+/// of new rooms and imported events. Events the server creates itself are not checked.
+pub fn verify_event_type_allowed(config: &Config, event_type: &EventType) -> Result<(), ApiError> {
+    let event_type = event_type.to_string();
+    let matches_any = |patterns: &[String]| {
+        patterns.iter().any(|pattern| glob(pattern, &event_type))
+    };
+
+    if matches_any(&config.denied_event_types) ||
+        !(config.allowed_event_types.is_empty() || matches_any(&config.allowed_event_types)) {
+        return Err(ApiError::unauthorized(format!(
+            "Events of the type {} are not allowed on this server.",
+            event_type
+        )));
+    }
+
+    Ok(())
+}
+
+/// Enforces an empty state key for an event type that requires it.
+pub fn ensure_empty_state_key(state_key: &str, event_type: &EventType) -> Result<(), ApiError> {
+    if state_key == "" {
+        Ok(())
+    } else {
+        Err(ApiError::bad_event(
+            format!("Events of type {} must have an empty state key.", event_type)
+        ))
+    }
+}
+
+/// Convert the JSON from the request into the correct type for the event's `content` field.
+pub fn extract_event_content<T>(event_content: Value, event_type: &EventType) -> Result<T, ApiError>
+where T: for<'de> Deserialize<'de> {
+    from_value(event_content).map_api_err(|_| {
+        ApiError::bad_event(
+            format!(
+                "Event content did not match expected structure for event of type {}.",
+                event_type
+            )
+        )
+    })
+}
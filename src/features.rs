@@ -0,0 +1,35 @@
+//! Unstable features of the Matrix spec and whether Ruma supports them.
+
+use std::collections::BTreeMap;
+
+/// The unstable features advertised to clients by the `/versions` endpoint.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnstableFeatures {
+    /// Whether each feature is enabled, by the feature's name, e.g. *org.matrix.lazy_loading*.
+    features: BTreeMap<&'static str, bool>,
+}
+
+impl UnstableFeatures {
+    /// The unstable features of this build of Ruma.
+    ///
+    /// This is the single place features are registered in, e.g. with
+    /// `features.register("org.matrix.lazy_loading", true)` once lazy loading is implemented.
+    pub fn supported() -> Self {
+        UnstableFeatures::default()
+    }
+
+    /// Register a feature, replacing any earlier registration with the same name.
+    pub fn register(&mut self, name: &'static str, enabled: bool) {
+        self.features.insert(name, enabled);
+    }
+
+    /// Whether the given feature is registered and enabled.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.features.get(name).cloned().unwrap_or(false)
+    }
+
+    /// Whether each feature is enabled, by the feature's name.
+    pub fn features(&self) -> &BTreeMap<&'static str, bool> {
+        &self.features
+    }
+}
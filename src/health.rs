@@ -0,0 +1,42 @@
+//! An endpoint for load balancers and monitoring to check whether the server can handle requests.
+
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response, status};
+use persistent::Read;
+
+use db::DB;
+use error::ApiError;
+use middleware::{MiddlewareChain, ResponseHeaders};
+use modifier::SerializableResponse;
+
+/// The `/ruma/health` endpoint.
+///
+/// Responds with the `Health` of the database, with 200 if it is available and 503 otherwise.
+pub struct GetHealth;
+
+impl Handler for GetHealth {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let database = request.get::<Read<DB>>().map_err(ApiError::from)?;
+        let health = database.health();
+
+        let status = if health.healthy {
+            status::Ok
+        } else {
+            status::ServiceUnavailable
+        };
+
+        Ok(Response::with((status, SerializableResponse(health))))
+    }
+}
+
+impl MiddlewareChain for GetHealth {
+    /// Create a `GetHealth` with all necessary middleware.
+    ///
+    /// The database is linked when the client APIs are mounted.
+    fn chain() -> Chain {
+        let mut chain = Chain::new(GetHealth);
+
+        chain.link_after(ResponseHeaders::new());
+
+        chain
+    }
+}
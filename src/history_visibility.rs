@@ -0,0 +1,380 @@
+//! Which events of a room a user may read, according to its *m.room.history_visibility*.
+//!
+//! Every endpoint that returns events of a room asks a `HistoryTimeline` of the room and the user
+//! which of them are visible, so that `/sync`, `/initialSync` and `/state` all agree. An event is
+//! judged by the history visibility before it and the user's membership at the time it was sent:
+//!
+//! * *world_readable* events are visible to everybody.
+//! * *shared* events are visible to users who have joined the room at any point, up to the point
+//!   where they last left it or were banned.
+//! * *invited* events are visible to users who were invited or joined when they were sent.
+//! * *joined* events are visible to users who were joined when they were sent.
+//!
+//! A user's own membership event counts as sent after the membership changed, so users see the
+//! event that made them join. A change of the history visibility is judged by the more permissive
+//! of the old and the new visibility, so that users see the change that hides later events.
+
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use error::ApiError;
+use models::event::Event;
+use models::room::Room;
+use util::room_state::state_key;
+
+/// A value of the `history_visibility` field, from the most to the least permissive.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum HistoryVisibility {
+    /// Anybody can read the events, even without joining the room.
+    WorldReadable,
+    /// Members can read the events, including those sent before they joined.
+    Shared,
+    /// Members can read the events sent since they were invited.
+    Invited,
+    /// Members can read the events sent since they joined.
+    Joined,
+}
+
+/// How much of a room a user may read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadableUntil {
+    /// The room up to its current state.
+    Now,
+    /// The room up to the event with this ordering, exclusively, i.e. the membership event with
+    /// which the user left or was banned.
+    Before(i64),
+}
+
+/// The changes of a room's history visibility and of a user's membership in it.
+#[derive(Clone, Debug)]
+pub struct HistoryTimeline {
+    /// The orderings of the history visibility events and their values, oldest first.
+    visibility_changes: Vec<(i64, HistoryVisibility)>,
+    /// The orderings of the user's membership events and their memberships, oldest first.
+    membership_changes: Vec<(i64, String)>,
+}
+
+impl HistoryVisibility {
+    /// Parse the content of an *m.room.history_visibility* event.
+    ///
+    /// Unknown values are treated as *joined*, the least permissive value.
+    pub fn from_content(content: &str) -> HistoryVisibility {
+        let content: Option<Value> = from_str(content).ok();
+
+        match content.as_ref().and_then(|content| content.get("history_visibility")) {
+            Some(&Value::String(ref value)) => match value.as_str() {
+                "world_readable" => HistoryVisibility::WorldReadable,
+                "shared" => HistoryVisibility::Shared,
+                "invited" => HistoryVisibility::Invited,
+                _ => HistoryVisibility::Joined,
+            },
+            _ => HistoryVisibility::Joined,
+        }
+    }
+}
+
+impl HistoryTimeline {
+    /// Create a timeline from the changes of the history visibility and the user's membership,
+    /// each given by the ordering of its event, oldest first.
+    pub fn new(
+        visibility_changes: Vec<(i64, HistoryVisibility)>,
+        membership_changes: Vec<(i64, String)>,
+    ) -> HistoryTimeline {
+        HistoryTimeline {
+            visibility_changes: visibility_changes,
+            membership_changes: membership_changes,
+        }
+    }
+
+    /// Load the timeline of a room and a user from the database.
+    pub fn load(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<HistoryTimeline, ApiError> {
+        let visibility_changes = HistoryTimeline::load_anonymous(connection, room_id)?
+            .visibility_changes;
+
+        let membership_changes = Event::find_state_changes(
+            connection,
+            room_id,
+            &EventType::RoomMember,
+            &user_id.to_string(),
+        )?.into_iter()
+            .map(|event| {
+                let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+                let membership = content.get("membership")
+                    .and_then(Value::as_str)
+                    .unwrap_or("leave")
+                    .to_string();
+
+                Ok((event.ordering, membership))
+            })
+            .collect::<Result<Vec<(i64, String)>, ApiError>>()?;
+
+        Ok(HistoryTimeline::new(visibility_changes, membership_changes))
+    }
+
+    /// Load the timeline of a room for a user who is not logged in, and so has no membership.
+    pub fn load_anonymous(connection: &PgConnection, room_id: &RoomId)
+    -> Result<HistoryTimeline, ApiError> {
+        let visibility_changes = Event::find_state_changes(
+            connection,
+            room_id,
+            &EventType::RoomHistoryVisibility,
+            "",
+        )?.into_iter()
+            .map(|event| (event.ordering, HistoryVisibility::from_content(&event.content)))
+            .collect();
+
+        Ok(HistoryTimeline::new(visibility_changes, Vec::new()))
+    }
+
+    /// Whether the event with the given ordering is visible to the user.
+    pub fn is_visible(&self, ordering: i64) -> bool {
+        let membership = self.membership_at(ordering);
+
+        match self.visibility_at(ordering) {
+            HistoryVisibility::WorldReadable => true,
+            HistoryVisibility::Shared => {
+                self.has_joined() && self.left_at().map_or(true, |left_at| ordering < left_at)
+            }
+            HistoryVisibility::Invited => {
+                membership == Some("join") || membership == Some("invite")
+            }
+            HistoryVisibility::Joined => membership == Some("join"),
+        }
+    }
+
+    /// Keep the events that are visible to the user.
+    pub fn filter(&self, events: Vec<Event>) -> Vec<Event> {
+        events.into_iter().filter(|event| self.is_visible(event.ordering)).collect()
+    }
+
+    /// How much of the room the user may read, or `None` if the user may not read it at all.
+    ///
+    /// Joined members read the room up to now, and users who left or were banned up to that
+    /// point. Everybody else may only read rooms whose history is currently world-readable.
+    pub fn readable_until(&self) -> Option<ReadableUntil> {
+        if let Some(left_at) = self.left_at() {
+            return Some(ReadableUntil::Before(left_at));
+        }
+
+        match self.membership_changes.last() {
+            Some(&(_, ref membership)) if membership == "join" => Some(ReadableUntil::Now),
+            _ if self.current_visibility() == HistoryVisibility::WorldReadable => {
+                Some(ReadableUntil::Now)
+            }
+            _ => None,
+        }
+    }
+
+    /// The current history visibility of the room.
+    pub fn current_visibility(&self) -> HistoryVisibility {
+        self.visibility_changes.last().map_or(HistoryVisibility::Shared, |&(_, visibility)| {
+            visibility
+        })
+    }
+
+    /// The history visibility the event with the given ordering is judged by.
+    ///
+    /// Rooms without a history visibility event are *shared*.
+    fn visibility_at(&self, ordering: i64) -> HistoryVisibility {
+        let mut visibility = HistoryVisibility::Shared;
+
+        for &(change_ordering, change) in &self.visibility_changes {
+            if change_ordering < ordering {
+                visibility = change;
+            } else {
+                if change_ordering == ordering {
+                    visibility = visibility.min(change);
+                }
+
+                break;
+            }
+        }
+
+        visibility
+    }
+
+    /// The membership of the user at the event with the given ordering, including the changes
+    /// made by that event.
+    fn membership_at(&self, ordering: i64) -> Option<&str> {
+        self.membership_changes.iter()
+            .take_while(|&&(change_ordering, _)| change_ordering <= ordering)
+            .last()
+            .map(|&(_, ref membership)| membership.as_str())
+    }
+
+    /// Whether the user has joined the room at any point.
+    fn has_joined(&self) -> bool {
+        self.membership_changes.iter().any(|&(_, ref membership)| membership == "join")
+    }
+
+    /// The ordering of the membership event with which the user left the room or was banned, if
+    /// that is the user's current membership.
+    fn left_at(&self) -> Option<i64> {
+        match self.membership_changes.last() {
+            Some(&(ordering, ref membership)) if membership == "leave" || membership == "ban" => {
+                Some(ordering)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether the history of a room is currently visible to users who are not members.
+pub fn is_world_readable(connection: &PgConnection, room_id: &RoomId) -> Result<bool, ApiError> {
+    let event_type = EventType::RoomHistoryVisibility;
+    let contents = Room::get_state_contents(connection, room_id, &[(event_type.clone(), "")])?;
+
+    let visibility = contents.get(&state_key(&event_type, ""))
+        .and_then(|content| content.get("history_visibility"))
+        .and_then(Value::as_str);
+
+    Ok(visibility == Some("world_readable"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HistoryTimeline, HistoryVisibility, ReadableUntil};
+    use super::HistoryVisibility::*;
+
+    /// A timeline in which the history visibility is set at ordering 5 and the user is invited at
+    /// 10, joins at 20 and leaves at 30.
+    fn timeline(visibility: HistoryVisibility) -> HistoryTimeline {
+        HistoryTimeline::new(
+            vec![(5, visibility)],
+            vec![
+                (10, "invite".to_string()),
+                (20, "join".to_string()),
+                (30, "leave".to_string()),
+            ],
+        )
+    }
+
+    /// The orderings at which events are visible in `timeline`, out of one event before the
+    /// invite, during the invite, while joined and after leaving.
+    fn visible_orderings(timeline: &HistoryTimeline) -> Vec<i64> {
+        vec![7, 15, 25, 35].into_iter().filter(|&ordering| timeline.is_visible(ordering)).collect()
+    }
+
+    #[test]
+    fn parse_history_visibility() {
+        assert_eq!(
+            HistoryVisibility::from_content(r#"{"history_visibility": "world_readable"}"#),
+            WorldReadable
+        );
+        assert_eq!(HistoryVisibility::from_content(r#"{"history_visibility": "shared"}"#), Shared);
+        assert_eq!(
+            HistoryVisibility::from_content(r#"{"history_visibility": "invited"}"#),
+            Invited
+        );
+        assert_eq!(HistoryVisibility::from_content(r#"{"history_visibility": "joined"}"#), Joined);
+        assert_eq!(HistoryVisibility::from_content(r#"{"history_visibility": "secret"}"#), Joined);
+        assert_eq!(HistoryVisibility::from_content("{}"), Joined);
+    }
+
+    #[test]
+    fn world_readable_events_are_always_visible() {
+        assert_eq!(visible_orderings(&timeline(WorldReadable)), vec![7, 15, 25, 35]);
+
+        let stranger = HistoryTimeline::new(vec![(5, WorldReadable)], Vec::new());
+
+        assert!(stranger.is_visible(7));
+        assert_eq!(stranger.readable_until(), Some(ReadableUntil::Now));
+    }
+
+    #[test]
+    fn shared_events_are_visible_to_users_who_joined() {
+        assert_eq!(visible_orderings(&timeline(Shared)), vec![7, 15, 25]);
+        assert!(!timeline(Shared).is_visible(30), "the user's leave event is not visible");
+
+        let invitee = HistoryTimeline::new(vec![(5, Shared)], vec![(10, "invite".to_string())]);
+
+        assert!(!invitee.is_visible(7));
+        assert!(!invitee.is_visible(15));
+        assert_eq!(invitee.readable_until(), None);
+    }
+
+    #[test]
+    fn shared_events_are_visible_until_the_final_leave() {
+        let timeline = HistoryTimeline::new(
+            vec![(5, Shared)],
+            vec![
+                (20, "join".to_string()),
+                (30, "leave".to_string()),
+                (40, "join".to_string()),
+                (50, "leave".to_string()),
+            ],
+        );
+
+        assert!(timeline.is_visible(35), "left, but joined again later");
+        assert!(timeline.is_visible(45));
+        assert!(!timeline.is_visible(55), "after the final leave");
+
+        let rejoined = HistoryTimeline::new(
+            vec![(5, Shared)],
+            vec![(20, "join".to_string()), (30, "leave".to_string()), (40, "join".to_string())],
+        );
+
+        assert!(rejoined.is_visible(35));
+        assert!(rejoined.is_visible(45));
+    }
+
+    #[test]
+    fn invited_events_are_visible_from_the_invite() {
+        assert_eq!(visible_orderings(&timeline(Invited)), vec![15, 25]);
+    }
+
+    #[test]
+    fn joined_events_are_visible_from_the_join() {
+        assert_eq!(visible_orderings(&timeline(Joined)), vec![25]);
+
+        let timeline = timeline(Joined);
+
+        assert!(timeline.is_visible(20), "the user's join event is visible");
+        assert!(!timeline.is_visible(30), "the user's leave event is not visible");
+    }
+
+    #[test]
+    fn rooms_without_history_visibility_are_shared() {
+        let timeline = HistoryTimeline::new(Vec::new(), vec![(20, "join".to_string())]);
+
+        assert!(timeline.is_visible(1));
+        assert_eq!(timeline.current_visibility(), Shared);
+    }
+
+    #[test]
+    fn visibility_changes_apply_to_later_events() {
+        let timeline = HistoryTimeline::new(
+            vec![(5, Joined), (40, WorldReadable), (50, Joined)],
+            vec![(20, "join".to_string()), (30, "leave".to_string())],
+        );
+
+        assert!(!timeline.is_visible(35), "joined before the change");
+        assert!(timeline.is_visible(40), "the change to world_readable itself");
+        assert!(timeline.is_visible(45), "world_readable after the change");
+        assert!(timeline.is_visible(50), "the change away from world_readable");
+        assert!(!timeline.is_visible(55), "joined after the change");
+    }
+
+    #[test]
+    fn readable_until_depends_on_the_current_membership() {
+        assert_eq!(timeline(Joined).readable_until(), Some(ReadableUntil::Before(30)));
+
+        let member = HistoryTimeline::new(vec![(5, Joined)], vec![(20, "join".to_string())]);
+
+        assert_eq!(member.readable_until(), Some(ReadableUntil::Now));
+
+        let banned = HistoryTimeline::new(
+            vec![(5, WorldReadable)],
+            vec![(20, "join".to_string()), (30, "ban".to_string())],
+        );
+
+        assert_eq!(banned.readable_until(), Some(ReadableUntil::Before(30)));
+
+        let stranger = HistoryTimeline::new(vec![(5, Shared)], Vec::new());
+
+        assert_eq!(stranger.readable_until(), None);
+    }
+}
@@ -0,0 +1,84 @@
+//! Hooks run when the state of the server changes.
+//!
+//! Anything that caches data derived from room memberships, e.g. which users may see each other's
+//! presence, should be kept up to date from `membership_changed` instead of from the individual
+//! endpoints, so that no way of changing a membership is missed. The number of joined members of
+//! each room is kept this way.
+//!
+//! Presence lists do not need a consumer: their entries are authorized whenever they are read, so
+//! an entry is suspended as soon as the users stop sharing a room and takes effect again when they
+//! share one, without any state to keep up to date.
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+use diesel::pg::PgConnection;
+use ruma_identifiers::{RoomId, UserId};
+
+use error::ApiError;
+use models::room::Room;
+
+/// A hook called by tests whenever a membership changes.
+#[cfg(test)]
+pub type TestMembershipHook = Box<Fn(&RoomId, &UserId, Option<&str>, &str)>;
+
+#[cfg(test)]
+thread_local! {
+    /// The hooks registered by tests on the current thread.
+    static TEST_MEMBERSHIP_HOOKS: RefCell<Vec<TestMembershipHook>> = RefCell::new(Vec::new());
+}
+
+/// Notify all consumers that the membership of a user in a room changed from `old` to `new`.
+///
+/// `old` is `None` if the user had no membership in the room before. This must be called within
+/// the transaction that persists the membership, after its row has been written, so that the
+/// consumers see the new state and their changes are rolled back along with it.
+pub fn membership_changed(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    user_id: &UserId,
+    old: Option<&str>,
+    new: &str,
+) -> Result<(), ApiError> {
+    debug!(
+        "Membership of {} in {} changed from {} to {}.",
+        user_id,
+        room_id,
+        old.unwrap_or("none"),
+        new
+    );
+
+    let delta = match (old == Some("join"), new == "join") {
+        (false, true) => 1,
+        (true, false) => -1,
+        _ => 0,
+    };
+
+    if delta != 0 {
+        Room::change_joined_member_count(connection, room_id, delta)?;
+    }
+
+    run_test_hooks(room_id, user_id, old, new);
+
+    Ok(())
+}
+
+/// Register a hook that is called on the current thread whenever a membership changes.
+#[cfg(test)]
+pub fn register_test_membership_hook(hook: TestMembershipHook) {
+    TEST_MEMBERSHIP_HOOKS.with(|hooks| hooks.borrow_mut().push(hook));
+}
+
+/// Call the hooks registered by tests on the current thread.
+#[cfg(test)]
+fn run_test_hooks(room_id: &RoomId, user_id: &UserId, old: Option<&str>, new: &str) {
+    TEST_MEMBERSHIP_HOOKS.with(|hooks| {
+        for hook in hooks.borrow().iter() {
+            hook(room_id, user_id, old, new);
+        }
+    });
+}
+
+/// Call the hooks registered by tests on the current thread.
+#[cfg(not(test))]
+fn run_test_hooks(_room_id: &RoomId, _user_id: &UserId, _old: Option<&str>, _new: &str) {}
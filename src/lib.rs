@@ -10,9 +10,14 @@ extern crate chrono;
 extern crate clap;
 #[macro_use] extern crate diesel;
 #[macro_use] extern crate diesel_codegen;
-#[cfg(test)] extern crate env_logger;
+extern crate env_logger;
+extern crate flate2;
+extern crate hyper;
+extern crate hyper_openssl;
 extern crate iron;
 #[cfg(test)] extern crate iron_test;
+#[macro_use] extern crate lazy_static;
+extern crate lettre;
 #[macro_use] extern crate log;
 extern crate macaroons;
 extern crate mount;
@@ -21,6 +26,8 @@ extern crate persistent;
 extern crate r2d2;
 extern crate r2d2_diesel;
 extern crate rand;
+extern crate regex;
+extern crate ring;
 extern crate router;
 extern crate ruma_events;
 extern crate ruma_identifiers;
@@ -36,20 +43,43 @@ extern crate url;
 pub mod middleware;
 /// API endpoints as Iron handlers.
 pub mod api {
+    pub mod media;
     pub mod r0;
+    pub mod v1;
 }
+pub mod appservice;
 pub mod authentication;
+pub mod cli;
 pub mod config;
 pub mod crypto;
 pub mod db;
 pub mod error;
+pub mod event_validation;
+pub mod features;
+pub mod health;
+pub mod history_visibility;
+pub mod hooks;
+pub mod listener;
+pub mod locale;
+pub mod logging;
+pub mod mailer;
+pub mod migrations;
 /// Models for the API's domain objects.
 pub mod models;
 pub mod modifier;
+pub mod msisdn;
+pub mod persister;
+pub mod push;
 pub mod schema;
 pub mod server;
+pub mod server_acl;
+pub mod sms;
+pub mod spam;
 pub mod query;
 pub mod swagger;
+pub mod url_preview;
+pub mod util;
+pub mod views;
 #[cfg(test)] pub mod test;
 
 embed_migrations!();
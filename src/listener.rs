@@ -0,0 +1,673 @@
+//! The HTTP listener, which limits how long clients may take to send their requests.
+//!
+//! hyper's read timeout only applies to single reads on the socket, so a client that sends a
+//! byte of its request every few seconds can tie up a worker thread indefinitely. The listener
+//! wraps every connection in a `DeadlineStream`, which gives each request a deadline for its
+//! headers, counted from their first byte, and a deadline for its body, counted from the end of
+//! the headers. The time a handler takes to build its response, e.g. while it waits for new
+//! events, does not count against either.
+//!
+//! A few clients could still tie up all worker threads by opening many connections, so the
+//! listener can also limit the number of connections per IP address with a `ConnectionLimit`.
+//! Connections beyond the limit are rejected when they are accepted, before a worker thread reads
+//! any of their requests.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use hyper::net::{HttpListener, HttpStream, NetworkListener, NetworkStream};
+use serde_json::to_string;
+
+use error::ApiError;
+use util::ip_network::{IpNetwork, canonical};
+
+/// The bytes that end the headers of a request.
+const HEADER_TERMINATOR: &'static [u8] = b"\r\n\r\n";
+
+/// The time, in seconds, rejected clients are told to wait before opening another connection.
+///
+/// The limit does not know when the open connections will be closed, but most clients close
+/// them soon after their requests are done.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// The time, in seconds, the listener tries to write the rejection of a connection for.
+const REJECTION_WRITE_TIMEOUT_SECS: u64 = 1;
+
+/// The deadlines of the requests on a connection.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestDeadlines {
+    /// The time a client may take to send the headers of a request.
+    pub headers: Duration,
+    /// The time a client may take to send the body of a request.
+    pub body: Duration,
+}
+
+/// Limits the number of connections from one IP address that are open at the same time.
+///
+/// Connections from the `trusted_proxies` are not limited, since a reverse proxy opens the
+/// connections of all clients behind it. Connections beyond the limit are answered with
+/// `M_LIMIT_EXCEEDED` and closed, and are logged as warnings.
+///
+/// Clones share their counts, so the listeners hyper clones for its threads share one limit.
+#[derive(Clone, Debug)]
+pub struct ConnectionLimit {
+    /// The maximum number of connections per IP address open at the same time.
+    limit: usize,
+    /// The networks of the reverse proxies, whose connections are not limited.
+    trusted_proxies: Vec<IpNetwork>,
+    /// The counts shared by all clones.
+    counts: Arc<Counts>,
+}
+
+/// The counts of a `ConnectionLimit`.
+#[derive(Debug, Default)]
+struct Counts {
+    /// The number of open connections, by IP address. Addresses without connections are removed.
+    open: Mutex<HashMap<IpAddr, usize>>,
+    /// The number of connections rejected since the server started.
+    rejected: AtomicUsize,
+}
+
+/// A connection counted against the limit of its IP address until it is dropped.
+struct Permit {
+    /// The address of the client.
+    ip: IpAddr,
+    /// The counts the connection is counted in.
+    counts: Arc<Counts>,
+}
+
+/// An `HttpListener` whose connections enforce `RequestDeadlines` and an optional
+/// `ConnectionLimit`.
+#[derive(Clone)]
+pub struct DeadlineListener {
+    /// The listener accepting the connections.
+    listener: HttpListener,
+    /// The deadlines of the requests.
+    deadlines: RequestDeadlines,
+    /// The number of connections dropped for missing a deadline, shared by all clones.
+    timed_out: Arc<AtomicUsize>,
+    /// The limit of the connections per IP address, if any.
+    connection_limit: Option<ConnectionLimit>,
+}
+
+/// A connection whose requests must be sent before their `RequestDeadlines`.
+///
+/// hyper reads and writes the connection through clones, so the clones share their state.
+#[derive(Clone)]
+pub struct DeadlineStream {
+    /// The connection.
+    stream: HttpStream,
+    /// The deadlines of the requests.
+    deadlines: RequestDeadlines,
+    /// The state of the current request, shared by all clones.
+    state: Arc<Mutex<RequestState>>,
+    /// The number of connections dropped for missing a deadline.
+    timed_out: Arc<AtomicUsize>,
+    /// The permit of the connection, which is released once hyper drops all clones.
+    _permit: Option<Arc<Permit>>,
+}
+
+/// The part of a request a connection is reading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    /// Waiting for the first byte of a request.
+    Idle,
+    /// Reading the headers, which must be complete before the given time.
+    Headers(Instant),
+    /// Reading the body, which must be complete before the given time.
+    Body(Instant),
+}
+
+/// The state of the request a connection is reading.
+#[derive(Debug)]
+struct RequestState {
+    /// The part of the request being read.
+    phase: Phase,
+    /// The number of bytes of `HEADER_TERMINATOR` at the end of the headers read so far.
+    terminator_matched: usize,
+    /// The timeout of single reads on the socket, as set by hyper.
+    read_timeout: Option<Duration>,
+}
+
+impl ConnectionLimit {
+    /// Create a `ConnectionLimit` allowing `limit` connections per IP address at the same time,
+    /// except from the `trusted_proxies`.
+    pub fn new(limit: usize, trusted_proxies: Vec<IpNetwork>) -> Self {
+        ConnectionLimit {
+            limit: limit,
+            trusted_proxies: trusted_proxies,
+            counts: Arc::new(Counts::default()),
+        }
+    }
+
+    /// The number of connections rejected since the limit was created.
+    pub fn rejected_count(&self) -> usize {
+        self.counts.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Check whether connections from `ip` are limited.
+    fn is_limited(&self, ip: IpAddr) -> bool {
+        !self.trusted_proxies.iter().any(|network| network.contains(ip))
+    }
+
+    /// Count a connection from `ip`, unless it already has the maximum number of connections.
+    fn acquire(&self, ip: IpAddr) -> Option<Permit> {
+        let mut open = self.counts.lock_open();
+        let count = open.entry(ip).or_insert(0);
+
+        if *count >= self.limit {
+            return None;
+        }
+
+        *count += 1;
+
+        Some(Permit {
+            ip: ip,
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// Answer a connection from `ip` beyond the limit with `M_LIMIT_EXCEEDED` and close it.
+    ///
+    /// The response is written before the request is read. Writing it times out quickly, so a
+    /// client that does not read it cannot hold up the accepting thread.
+    fn reject(&self, mut stream: HttpStream, ip: IpAddr) {
+        let rejected = self.counts.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+
+        warn!(
+            "Rejected a connection from {}, which has {} connections open already. \
+            {} connections were rejected so far.",
+            ip,
+            self.limit,
+            rejected
+        );
+
+        let error = ApiError::limited_rate(None, Duration::from_secs(RETRY_AFTER_SECS));
+        let body = to_string(&error).expect("ApiError should always serialize");
+        let response = format!(
+            "HTTP/1.1 429 Too Many Requests\r\nContent-Type: application/json\r\n\
+            Retry-After: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            RETRY_AFTER_SECS,
+            body.len(),
+            body
+        );
+        let timeout = Some(Duration::from_secs(REJECTION_WRITE_TIMEOUT_SECS));
+
+        if let Err(error) = stream.set_write_timeout(timeout)
+            .and_then(|_| stream.write_all(response.as_bytes()))
+            .and_then(|_| stream.close(Shutdown::Both))
+        {
+            debug!("Failed to reject a connection from {}: {}", ip, error);
+        }
+    }
+}
+
+impl Counts {
+    /// Lock the open counts.
+    ///
+    /// The counts are only changed while the lock is held, so they stay consistent even if a
+    /// thread panicked while holding it.
+    fn lock_open(&self) -> MutexGuard<HashMap<IpAddr, usize>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut open = self.counts.lock_open();
+
+        let is_last = match open.get_mut(&self.ip) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if is_last {
+            open.remove(&self.ip);
+        }
+    }
+}
+
+impl DeadlineListener {
+    /// Listen on `address`, enforcing `deadlines` on every request and the `connection_limit`, if
+    /// any, on every connection.
+    pub fn new(
+        address: &str,
+        deadlines: RequestDeadlines,
+        connection_limit: Option<ConnectionLimit>,
+    ) -> ::hyper::Result<Self> {
+        Ok(DeadlineListener {
+            listener: HttpListener::new(address)?,
+            deadlines: deadlines,
+            timed_out: Arc::new(AtomicUsize::new(0)),
+            connection_limit: connection_limit,
+        })
+    }
+
+    /// The number of connections dropped since the listener was created because a request was
+    /// not sent in time.
+    pub fn timed_out_count(&self) -> usize {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Wrap an accepted connection in a `DeadlineStream`, or reject it if its IP address has
+    /// reached the connection limit.
+    fn admit(&self, mut stream: HttpStream) -> io::Result<Option<DeadlineStream>> {
+        let mut permit = None;
+
+        if let Some(ref connection_limit) = self.connection_limit {
+            let ip = canonical(stream.peer_addr()?.ip());
+
+            if connection_limit.is_limited(ip) {
+                match connection_limit.acquire(ip) {
+                    Some(acquired) => permit = Some(Arc::new(acquired)),
+                    None => {
+                        connection_limit.reject(stream, ip);
+
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        Ok(Some(DeadlineStream::new(stream, self.deadlines, self.timed_out.clone(), permit)))
+    }
+}
+
+impl NetworkListener for DeadlineListener {
+    type Stream = DeadlineStream;
+
+    fn accept(&mut self) -> ::hyper::Result<DeadlineStream> {
+        loop {
+            let stream = self.listener.accept()?;
+
+            if let Some(stream) = self.admit(stream)? {
+                return Ok(stream);
+            }
+        }
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl DeadlineStream {
+    /// Wrap a connection, enforcing `deadlines` on its requests and holding its `permit` until it
+    /// is dropped.
+    fn new(
+        stream: HttpStream,
+        deadlines: RequestDeadlines,
+        timed_out: Arc<AtomicUsize>,
+        permit: Option<Arc<Permit>>,
+    ) -> Self {
+        DeadlineStream {
+            stream: stream,
+            deadlines: deadlines,
+            state: Arc::new(Mutex::new(RequestState {
+                phase: Phase::Idle,
+                terminator_matched: 0,
+                read_timeout: None,
+            })),
+            timed_out: timed_out,
+            _permit: permit,
+        }
+    }
+
+    /// Lock the state of the current request.
+    fn state(&self) -> MutexGuard<RequestState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Read from the connection and advance the phase of the request by what was read.
+    fn read_and_record(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        let deadlines = self.deadlines;
+        self.state().record_read(&buf[..read], deadlines);
+
+        Ok(read)
+    }
+
+    /// Count and log a connection dropped for missing a deadline, and return the error to fail
+    /// the read with.
+    fn time_out(&mut self, part: &str, limit: Duration) -> io::Error {
+        let count = self.timed_out.fetch_add(1, Ordering::Relaxed) + 1;
+        let peer = self.stream.peer_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_else(|_| "an unknown address".to_string());
+
+        warn!(
+            "Dropped a connection from {} that did not send the {} of its request within {} \
+            seconds ({} connections dropped so far).",
+            peer,
+            part,
+            limit.as_secs(),
+            count
+        );
+
+        io::Error::new(io::ErrorKind::TimedOut, format!("request {} timed out", part))
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (phase, read_timeout) = {
+            let state = self.state();
+            (state.phase, state.read_timeout)
+        };
+
+        let (part, deadline, limit) = match phase {
+            Phase::Idle => {
+                self.stream.set_read_timeout(read_timeout)?;
+
+                return self.read_and_record(buf);
+            }
+            Phase::Headers(deadline) => ("headers", deadline, self.deadlines.headers),
+            Phase::Body(deadline) => ("body", deadline, self.deadlines.body),
+        };
+
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(self.time_out(part, limit));
+        }
+
+        let remaining = deadline.duration_since(now);
+
+        // The socket times out at the deadline unless hyper's read timeout is shorter.
+        let (timeout, times_out_at_deadline) = match read_timeout {
+            Some(read_timeout) if read_timeout < remaining => (read_timeout, false),
+            _ => (remaining, true),
+        };
+
+        self.stream.set_read_timeout(Some(timeout))?;
+
+        match self.read_and_record(buf) {
+            Err(ref error) if times_out_at_deadline && is_timeout(error) => {
+                Err(self.time_out(part, limit))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state().record_write(buf);
+
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl NetworkStream for DeadlineStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.state().read_timeout = timeout;
+
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.stream.close(how)
+    }
+}
+
+impl RequestState {
+    /// Advance the phase of the request after `bytes` were read.
+    fn record_read(&mut self, bytes: &[u8], deadlines: RequestDeadlines) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        if self.phase == Phase::Idle {
+            self.phase = Phase::Headers(Instant::now() + deadlines.headers);
+            self.terminator_matched = 0;
+        }
+
+        if let Phase::Headers(_) = self.phase {
+            for &byte in bytes {
+                if byte == HEADER_TERMINATOR[self.terminator_matched] {
+                    self.terminator_matched += 1;
+                } else if byte == HEADER_TERMINATOR[0] {
+                    self.terminator_matched = 1;
+                } else {
+                    self.terminator_matched = 0;
+                }
+
+                if self.terminator_matched == HEADER_TERMINATOR.len() {
+                    self.phase = Phase::Body(Instant::now() + deadlines.body);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Finish the request once its response is written, so that the time until the next request
+    /// is only limited by hyper's keep-alive timeout.
+    ///
+    /// Interim responses do not finish the request. hyper sends `100 Continue` to requests that
+    /// expect it before reading their body, which must still be sent before the deadline that
+    /// started at the end of the headers.
+    fn record_write(&mut self, bytes: &[u8]) {
+        if !is_interim_response(bytes) {
+            self.phase = Phase::Idle;
+        }
+    }
+}
+
+/// Check whether `bytes` start an interim response, i.e. one with a 1xx status code.
+fn is_interim_response(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"HTTP/1.1 1") || bytes.starts_with(b"HTTP/1.0 1")
+}
+
+/// Check whether a read failed because the socket timed out.
+fn is_timeout(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use hyper::net::{HttpStream, NetworkStream};
+
+    use util::ip_network::IpNetwork;
+
+    use super::{ConnectionLimit, DeadlineListener, DeadlineStream, RequestDeadlines};
+
+    /// The short deadlines of the test connections.
+    fn deadlines() -> RequestDeadlines {
+        RequestDeadlines {
+            headers: Duration::from_millis(300),
+            body: Duration::from_millis(300),
+        }
+    }
+
+    /// The response written to the test requests.
+    const RESPONSE: &'static [u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+
+    /// Connect a client to a `DeadlineStream` with short deadlines and a long read timeout.
+    fn connect() -> (TcpStream, DeadlineStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let stream = DeadlineStream::new(
+            HttpStream(stream),
+            deadlines(),
+            Arc::new(AtomicUsize::new(0)),
+            None
+        );
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        (client, stream)
+    }
+
+    /// Connect a client to `listener`, returning the client and the connection it admitted.
+    fn connect_to(listener: &DeadlineListener) -> (TcpStream, Option<DeadlineStream>) {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(socket.local_addr().unwrap()).unwrap();
+        let (stream, _) = socket.accept().unwrap();
+
+        (client, listener.admit(HttpStream(stream)).unwrap())
+    }
+
+    /// A `DeadlineListener` allowing one connection per IP address, except from the
+    /// `trusted_proxies`.
+    fn limited_listener(trusted_proxies: Vec<IpNetwork>) -> DeadlineListener {
+        let limit = ConnectionLimit::new(1, trusted_proxies);
+
+        DeadlineListener::new("127.0.0.1:0", deadlines(), Some(limit)).unwrap()
+    }
+
+    /// Read from `stream` until it fails, returning the error kind and the time it took.
+    fn read_until_error(stream: &mut DeadlineStream) -> (ErrorKind, Duration) {
+        let start = Instant::now();
+        let mut buf = [0; 1024];
+
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => panic!("The client closed the connection."),
+                Ok(_) => continue,
+                Err(error) => return (error.kind(), start.elapsed()),
+            }
+        }
+    }
+
+    #[test]
+    fn trickled_headers_time_out() {
+        let (mut client, mut stream) = connect();
+
+        let trickle = thread::spawn(move || {
+            for byte in b"GET /_matrix/client/versions HTTP/1.1\r\nHost: ruma.test\r\n".iter() {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let (kind, elapsed) = read_until_error(&mut stream);
+
+        assert_eq!(kind, ErrorKind::TimedOut);
+        assert!(elapsed < Duration::from_secs(1));
+        assert_eq!(stream.timed_out.load(Ordering::Relaxed), 1);
+
+        stream.close(Shutdown::Both).unwrap();
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn stalled_body_times_out() {
+        let (mut client, mut stream) = connect();
+
+        client.write_all(b"PUT /upload HTTP/1.1\r\nHost: ruma.test\r\nContent-Length: 10\r\n\r\na")
+            .unwrap();
+
+        let (kind, elapsed) = read_until_error(&mut stream);
+
+        assert_eq!(kind, ErrorKind::TimedOut);
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn time_spent_handling_a_request_does_not_count() {
+        let (mut client, mut stream) = connect();
+        let request = b"GET /_matrix/client/r0/sync?timeout=25000 HTTP/1.1\r\n\r\n";
+        let mut buf = [0; 1024];
+
+        client.write_all(request).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), request.len());
+
+        // Handle the request for longer than both deadlines, like a long-polling sync.
+        thread::sleep(Duration::from_millis(700));
+
+        stream.write_all(RESPONSE).unwrap();
+
+        let mut response = vec![0; RESPONSE.len()];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response[..], RESPONSE);
+
+        // The next request on the connection gets deadlines of its own.
+        thread::sleep(Duration::from_millis(400));
+        client.write_all(request).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), request.len());
+    }
+
+    #[test]
+    fn continue_responses_keep_the_body_deadline() {
+        let (mut client, mut stream) = connect();
+        let request = b"PUT /upload HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 10\r\n\r\n";
+        let mut buf = [0; 1024];
+
+        client.write_all(request).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), request.len());
+
+        // The client is told to send the body, but never does.
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+
+        let (kind, elapsed) = read_until_error(&mut stream);
+
+        assert_eq!(kind, ErrorKind::TimedOut);
+        assert!(elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn connections_beyond_the_limit_are_rejected() {
+        let listener = limited_listener(Vec::new());
+        let (_first_client, first) = connect_to(&listener);
+        let (mut second_client, second) = connect_to(&listener);
+
+        assert!(first.is_some());
+        assert!(second.is_none());
+
+        let mut response = String::new();
+        second_client.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(response.contains("\r\nRetry-After: 1\r\n"));
+        assert!(response.contains(r#""errcode":"M_LIMIT_EXCEEDED""#));
+        assert!(response.contains(r#""retry_after_ms":1000"#));
+        assert_eq!(listener.connection_limit.as_ref().unwrap().rejected_count(), 1);
+
+        // Closing the first connection makes room for another one.
+        drop(first);
+
+        assert!(connect_to(&listener).1.is_some());
+    }
+
+    #[test]
+    fn connections_from_trusted_proxies_are_not_limited() {
+        let listener = limited_listener(vec![IpNetwork::parse("127.0.0.0/8").unwrap()]);
+        let (_first_client, first) = connect_to(&listener);
+        let (_second_client, second) = connect_to(&listener);
+
+        assert!(first.is_some());
+        assert!(second.is_some());
+    }
+}
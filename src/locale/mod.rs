@@ -0,0 +1,151 @@
+//! Translations of the messages Ruma shows to users.
+//!
+//! Messages are looked up by key in catalogs bundled into the binary. A message missing from a
+//! catalog falls back to English.
+
+use std::collections::HashMap;
+
+use iron::Request;
+use iron::headers::{AcceptLanguage, q};
+use iron::typemap::Key;
+use toml;
+
+/// The English catalog, which contains every message.
+static ENGLISH_CATALOG: &'static str = include_str!("en.toml");
+/// The German catalog.
+static GERMAN_CATALOG: &'static str = include_str!("de.toml");
+
+lazy_static! {
+    /// The parsed catalogs of all locales.
+    static ref CATALOGS: HashMap<Locale, HashMap<String, String>> = {
+        let mut catalogs = HashMap::new();
+
+        catalogs.insert(Locale::English, parse_catalog(ENGLISH_CATALOG));
+        catalogs.insert(Locale::German, parse_catalog(GERMAN_CATALOG));
+
+        catalogs
+    };
+}
+
+/// A language messages can be shown in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+pub enum Locale {
+    /// English, the fallback for messages missing from other catalogs.
+    #[serde(rename="en")]
+    English,
+    /// German.
+    #[serde(rename="de")]
+    German,
+}
+
+impl Locale {
+    /// Find the locale of a language tag like *de* or *de-AT*.
+    pub fn from_language_tag(tag: &str) -> Option<Locale> {
+        let language = tag.split('-').next().unwrap_or("").to_lowercase();
+
+        match language.as_ref() {
+            "de" => Some(Locale::German),
+            "en" => Some(Locale::English),
+            _ => None,
+        }
+    }
+
+    /// Pick the supported locale the client prefers most.
+    ///
+    /// Languages with a quality of 0 are not acceptable to the client and never picked.
+    pub fn from_accept_language(accept_language: &AcceptLanguage) -> Option<Locale> {
+        let mut languages = accept_language.0.clone();
+
+        // Sorting is stable, so languages of equal quality stay in the client's order.
+        languages.sort_by(|a, b| b.quality.cmp(&a.quality));
+
+        languages.iter()
+            .filter(|language| language.quality > q(0))
+            .filter_map(|language| language.item.language.as_ref())
+            .filter_map(|language| Locale::from_language_tag(language))
+            .next()
+    }
+
+    /// The locale chosen for a request by the `Localization` middleware, English if there is
+    /// none.
+    pub fn from_request(request: &Request) -> Locale {
+        request.extensions.get::<Locale>().cloned().unwrap_or(Locale::English)
+    }
+
+    /// Look up a message in this locale's catalog, falling back to English.
+    pub fn message(&self, key: &str) -> String {
+        CATALOGS.get(self)
+            .and_then(|catalog| catalog.get(key))
+            .or_else(|| CATALOGS[&Locale::English].get(key))
+            .cloned()
+            .unwrap_or_else(|| {
+                warn!("The message {} is missing from the English catalog.", key);
+
+                key.to_string()
+            })
+    }
+
+    /// Look up a message like `message` and replace its `{placeholders}` with the given values.
+    pub fn format(&self, key: &str, args: &[(&str, &str)]) -> String {
+        args.iter().fold(self.message(key), |message, &(name, value)| {
+            message.replace(&format!("{{{}}}", name), value)
+        })
+    }
+}
+
+impl Key for Locale {
+    type Value = Locale;
+}
+
+/// Parse a bundled catalog.
+fn parse_catalog(catalog: &str) -> HashMap<String, String> {
+    toml::from_str(catalog).expect("Bundled message catalogs should be valid TOML")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use iron::headers::{AcceptLanguage, LanguageTag, QualityItem, q};
+
+    use super::{CATALOGS, Locale};
+
+    #[test]
+    fn catalogs_have_the_same_keys() {
+        let english: HashSet<&String> = CATALOGS[&Locale::English].keys().collect();
+        let german: HashSet<&String> = CATALOGS[&Locale::German].keys().collect();
+
+        assert_eq!(english, german);
+    }
+
+    #[test]
+    fn format_message() {
+        let message = Locale::German.format(
+            "sms.validation_code",
+            &[("domain", "ruma.test"), ("token", "123456")],
+        );
+
+        assert_eq!(message, "Dein Bestätigungscode für ruma.test lautet 123456");
+    }
+
+    #[test]
+    fn prefer_locale_with_highest_quality() {
+        let accept_language = AcceptLanguage(vec![
+            QualityItem::new("fr".parse::<LanguageTag>().unwrap(), q(1000)),
+            QualityItem::new("en-GB".parse::<LanguageTag>().unwrap(), q(500)),
+            QualityItem::new("de-AT".parse::<LanguageTag>().unwrap(), q(800)),
+        ]);
+
+        assert_eq!(Locale::from_accept_language(&accept_language), Some(Locale::German));
+    }
+
+    #[test]
+    fn never_pick_unacceptable_locales() {
+        let accept_language = AcceptLanguage(vec![
+            QualityItem::new("fr".parse::<LanguageTag>().unwrap(), q(1000)),
+            QualityItem::new("de".parse::<LanguageTag>().unwrap(), q(0)),
+        ]);
+
+        assert_eq!(Locale::from_accept_language(&accept_language), None);
+    }
+}
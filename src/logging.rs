@@ -0,0 +1,92 @@
+//! Log output, either as plain text or as one JSON object per line.
+
+use std::env;
+
+use env_logger::LogBuilder;
+use log::{LogLevel, LogRecord, SetLoggerError};
+use serde_json::to_string;
+
+/// The log target used for the access log written by `RequestLogger`.
+///
+/// When logging JSON, messages for this target are expected to be JSON objects already and are
+/// written as is.
+pub const REQUEST_LOG_TARGET: &'static str = "ruma::request";
+
+/// The format log messages are written in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum LogFormat {
+    /// One JSON object per line, for consumption by log aggregators.
+    #[serde(rename="json")]
+    Json,
+    /// Human readable lines of text.
+    #[serde(rename="text")]
+    Text,
+}
+
+/// A log message in the JSON format.
+#[derive(Debug, Serialize)]
+struct JsonLogLine<'a> {
+    /// The level of the message, e.g. *INFO*.
+    level: String,
+    /// The module the message was logged from.
+    target: &'a str,
+    /// The message itself.
+    msg: String,
+}
+
+/// Initialize the global logger.
+///
+/// Like `env_logger::init`, which levels are logged is controlled by the `RUST_LOG` environment
+/// variable.
+pub fn init(format: LogFormat) -> Result<(), SetLoggerError> {
+    let mut builder = LogBuilder::new();
+
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+
+    if format == LogFormat::Json {
+        builder.format(format_json);
+    }
+
+    builder.init()
+}
+
+/// Format a log record as a JSON object.
+fn format_json(record: &LogRecord) -> String {
+    if record.target() == REQUEST_LOG_TARGET {
+        return record.args().to_string();
+    }
+
+    json_line(record.level(), record.target(), record.args().to_string())
+}
+
+/// Serialize a log message in the JSON format.
+fn json_line(level: LogLevel, target: &str, msg: String) -> String {
+    let line = JsonLogLine {
+        level: level.to_string(),
+        target: target,
+        msg: msg,
+    };
+
+    to_string(&line).expect("JsonLogLine should always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+    use serde_json::{Value, from_str};
+
+    use super::json_line;
+
+    #[test]
+    fn json_log_line() {
+        let line = json_line(LogLevel::Warn, "ruma::server", "Something \"odd\"\nhappened".to_string());
+        let json: Value = from_str(&line).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(json.get("level").unwrap().as_str().unwrap(), "WARN");
+        assert_eq!(json.get("target").unwrap().as_str().unwrap(), "ruma::server");
+        assert_eq!(json.get("msg").unwrap().as_str().unwrap(), "Something \"odd\"\nhappened");
+    }
+}
@@ -0,0 +1,336 @@
+//! Digest emails about notifications users missed while they were offline.
+//!
+//! Notifications for users with an email pusher are collected by `push::notify_room_members`. The
+//! digest worker regularly sends them to the users who are offline, grouped by room.
+
+use std::collections::HashMap;
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_events::presence::PresenceState;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use config::Config;
+use error::ApiError;
+use locale::Locale;
+use mailer::{DigestContent, DigestNotification, DigestRoom, Email, NotificationDigestMail};
+use models::access_token::AccessToken;
+use models::email_notification::{EmailDigest, EmailNotification};
+use models::event::Event;
+use models::presence_status::{PresenceStatus, get_now};
+use models::profile::Profile;
+use models::pusher::{EMAIL_PUSHER_KIND, Pusher};
+use models::room::Room;
+use util::room_state::{StateKey, state_key};
+
+/// The maximum number of characters of a message shown in a digest.
+const MAX_SNIPPET_LENGTH: usize = 100;
+
+/// The type of the state event that enables encryption in a room.
+const ENCRYPTION_EVENT_TYPE: &'static str = "m.room.encryption";
+
+/// Queue digest emails for the users with pending notifications who are offline, which is the
+/// work of the digest worker.
+///
+/// Users are offline if their presence is not *online* and they have not used an access token for
+/// `email_digest_delay` seconds. Users get at most one digest every `email_digest_interval`
+/// seconds, later notifications wait for the next one, so the users who got a digest recently
+/// are left out by the query. Only the notifications of the users who are offline are loaded.
+/// Returns the number of digests queued.
+pub fn send_digests(connection: &PgConnection, config: &Config) -> Result<usize, ApiError> {
+    let now = get_now();
+    let recent_user_ids = EmailDigest::find_user_ids_sent_since(
+        connection,
+        now - config.email_digest_interval as i64 * 1000,
+    )?;
+
+    let mut sent = 0;
+
+    for user_id in EmailNotification::find_pending_user_ids(connection, &recent_user_ids)? {
+        if send_digest(connection, config, &user_id, now)? {
+            sent += 1;
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Queue a digest of the pending notifications of the user, if the user is offline at `now`.
+/// The notifications are deleted once the digest was queued. Returns whether a digest was
+/// queued.
+fn send_digest(connection: &PgConnection, config: &Config, user_id: &UserId, now: i64)
+-> Result<bool, ApiError> {
+    if !is_offline(connection, user_id, now, config.email_digest_delay as i64 * 1000)? {
+        return Ok(false);
+    }
+
+    let notifications = EmailNotification::find_by_uid(connection, user_id)?;
+
+    let last_id = match notifications.last() {
+        Some(notification) => notification.id,
+        None => return Ok(false),
+    };
+
+    let pushers: Vec<Pusher> = Pusher::find_by_uid(connection, user_id)?
+        .into_iter()
+        .filter(|pusher| pusher.kind == EMAIL_PUSHER_KIND)
+        .collect();
+
+    // Notifications of users who removed their email pushers are dropped.
+    if pushers.is_empty() {
+        EmailNotification::delete_until(connection, user_id, last_id)?;
+
+        return Ok(false);
+    }
+
+    let mail = NotificationDigestMail {
+        domain: config.domain.clone(),
+        rooms: digest_rooms(connection, &notifications)?,
+    };
+
+    // Notifications of events that no longer exist are dropped as well.
+    if mail.rooms.is_empty() {
+        EmailNotification::delete_until(connection, user_id, last_id)?;
+
+        return Ok(false);
+    }
+
+    connection.transaction::<bool, ApiError, _>(|| {
+        for pusher in &pushers {
+            let locale = Locale::from_language_tag(&pusher.lang).unwrap_or(config.default_locale);
+
+            Email::from_template(&pusher.pushkey, &mail, locale).queue(connection)?;
+        }
+
+        EmailDigest::record(connection, user_id)?;
+        EmailNotification::delete_until(connection, user_id, last_id)?;
+
+        Ok(true)
+    }).map_err(ApiError::from)
+}
+
+/// Check whether the user's presence is not *online* and the user has not used an access token
+/// for `delay` milliseconds.
+fn is_offline(connection: &PgConnection, user_id: &UserId, now: i64, delay: i64)
+-> Result<bool, ApiError> {
+    if let Some(status) = PresenceStatus::find_by_uid(connection, user_id)? {
+        if status.presence == PresenceState::Online.to_string() {
+            return Ok(false);
+        }
+    }
+
+    match AccessToken::last_used_by_user(connection, user_id)? {
+        Some(last_used_at) => Ok(now - last_used_at >= delay),
+        None => Ok(true),
+    }
+}
+
+/// Group the notifications by room, in the order the rooms were first notified of.
+///
+/// Notifications of events that no longer exist are left out.
+fn digest_rooms(connection: &PgConnection, notifications: &[EmailNotification])
+-> Result<Vec<DigestRoom>, ApiError> {
+    let mut room_ids: Vec<RoomId> = Vec::new();
+    let mut rooms: Vec<DigestRoom> = Vec::new();
+    let mut encrypted_rooms: Vec<bool> = Vec::new();
+
+    for notification in notifications {
+        let event = match Event::find(connection, &notification.event_id)? {
+            Some(event) => event,
+            None => continue,
+        };
+
+        let index = match room_ids.iter().position(|room_id| *room_id == notification.room_id) {
+            Some(index) => index,
+            None => {
+                let encryption_event_type = EventType::Custom(ENCRYPTION_EVENT_TYPE.to_string());
+                let state = Room::get_state_contents(connection, &notification.room_id, &[
+                    (EventType::RoomName, ""),
+                    (EventType::RoomCanonicalAlias, ""),
+                    (encryption_event_type.clone(), ""),
+                ])?;
+
+                room_ids.push(notification.room_id.clone());
+                rooms.push(DigestRoom {
+                    name: room_name(&state, &notification.room_id),
+                    notifications: Vec::new(),
+                });
+                encrypted_rooms.push(state.contains_key(&state_key(&encryption_event_type, "")));
+
+                rooms.len() - 1
+            }
+        };
+
+        let digest_notification = DigestNotification {
+            sender: sender_name(connection, &event.user_id)?,
+            content: digest_content(&event, encrypted_rooms[index]),
+        };
+
+        rooms[index].notifications.push(digest_notification);
+    }
+
+    Ok(rooms)
+}
+
+/// What the digest shows for an event. Messages in encrypted rooms are never shown, even if a
+/// client sent them unencrypted.
+fn digest_content(event: &Event, is_encrypted_room: bool) -> DigestContent {
+    if event.event_type == EventType::CallInvite.to_string() {
+        return DigestContent::Call;
+    }
+
+    if is_encrypted_room || event.event_type != EventType::RoomMessage.to_string() {
+        return DigestContent::Encrypted;
+    }
+
+    let body = from_str::<Value>(&event.content)
+        .ok()
+        .and_then(|content| content.get("body").and_then(Value::as_str).map(str::to_string));
+
+    match body {
+        Some(body) => DigestContent::Message(snippet(&body)),
+        None => DigestContent::Encrypted,
+    }
+}
+
+/// Shorten a message body to `MAX_SNIPPET_LENGTH` characters.
+fn snippet(body: &str) -> String {
+    if body.chars().count() <= MAX_SNIPPET_LENGTH {
+        return body.to_string();
+    }
+
+    let mut snippet: String = body.chars().take(MAX_SNIPPET_LENGTH - 1).collect();
+    snippet.push('…');
+
+    snippet
+}
+
+/// The name of a room, its canonical alias, or its ID, given the contents of its name and
+/// canonical alias events.
+fn room_name(state: &HashMap<StateKey, Value>, room_id: &RoomId) -> String {
+    state_content_string(state, &EventType::RoomName, "name")
+        .or_else(|| state_content_string(state, &EventType::RoomCanonicalAlias, "alias"))
+        .unwrap_or_else(|| room_id.to_string())
+}
+
+/// Return a non-empty string field of the content of a state event with an empty state key.
+fn state_content_string(state: &HashMap<StateKey, Value>, event_type: &EventType, field: &str)
+-> Option<String> {
+    state.get(&state_key(event_type, ""))
+        .and_then(|content| content.get(field).and_then(Value::as_str))
+        .and_then(|value| if value.is_empty() { None } else { Some(value.to_string()) })
+}
+
+/// The display name of a user, or their ID if they have none.
+fn sender_name(connection: &PgConnection, user_id: &UserId) -> Result<String, ApiError> {
+    let displayname = Profile::find_by_uid(connection, user_id)?
+        .and_then(|profile| profile.displayname);
+
+    Ok(displayname.unwrap_or_else(|| user_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use models::presence_status::advance_clock;
+    use models::pusher::{PusherData, PusherOptions};
+    use test::{Test, TestUser};
+    use super::{MAX_SNIPPET_LENGTH, snippet};
+
+    /// Bind an email address to the user and set an email pusher for it.
+    fn set_email_pusher(test: &Test, user: &TestUser, address: &str) {
+        test.bind_email(user, address);
+
+        let options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "email".to_string(),
+            data: PusherData {
+                url: None,
+            },
+            device_display_name: address.to_string(),
+            app_id: "m.email".to_string(),
+            profile_tag: None,
+            pushkey: address.to_string(),
+            app_display_name: "Email Notifications".to_string(),
+            append: false,
+        };
+
+        assert_eq!(test.set_pusher(&user.token, options).status, Status::Ok);
+    }
+
+    #[test]
+    fn offline_users_get_one_digest_per_interval() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"name": "Book Club", "visibility": "public"}"#,
+        );
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        set_email_pusher(&test, &bob, "bob@example.com");
+        test.update_presence(&bob.token, &bob.id, r#"{"presence": "offline"}"#);
+
+        assert_eq!(test.send_call_invite(&alice.token, &room_id, 1).status, Status::Ok);
+
+        // Bob used his access token just now.
+        assert!(test.send_email_digests().is_empty());
+
+        advance_clock(601 * 1000);
+
+        let emails = test.send_email_digests();
+
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, "bob@example.com");
+        assert_eq!(emails[0].subject, "Notifications you missed on ruma.test");
+        assert!(emails[0].text.contains("Book Club"));
+        assert!(emails[0].text.contains(&format!("{} called you.", alice.id)));
+        assert!(emails[0].html.contains("<h2>Book Club</h2>"));
+
+        assert_eq!(test.send_call_invite(&alice.token, &room_id, 2).status, Status::Ok);
+
+        advance_clock(601 * 1000);
+
+        assert!(test.send_email_digests().is_empty());
+
+        // The notification waits for the next digest, and is only sent once.
+        advance_clock(3000 * 1000);
+
+        assert_eq!(test.send_email_digests().len(), 1);
+
+        advance_clock(3601 * 1000);
+
+        assert!(test.send_email_digests().is_empty());
+    }
+
+    #[test]
+    fn online_users_get_no_digest() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        set_email_pusher(&test, &bob, "bob@example.com");
+        test.update_presence(&bob.token, &bob.id, r#"{"presence": "online"}"#);
+
+        assert_eq!(test.send_call_invite(&alice.token, &room_id, 1).status, Status::Ok);
+
+        advance_clock(601 * 1000);
+
+        assert!(test.send_email_digests().is_empty());
+    }
+
+    #[test]
+    fn long_messages_are_shortened() {
+        let body = "a".repeat(MAX_SNIPPET_LENGTH + 1);
+
+        assert_eq!(snippet("Hi"), "Hi");
+        assert_eq!(snippet(&body).chars().count(), MAX_SNIPPET_LENGTH);
+        assert!(snippet(&body).ends_with('…'));
+    }
+}
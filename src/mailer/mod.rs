@@ -0,0 +1,316 @@
+//! Delivery of emails, e.g. for validating email addresses.
+//!
+//! Handlers render emails and put them into the mail queue in the database. A background worker
+//! started with the server delivers them through a `Mailer` and retries failed deliveries with
+//! increasing delays. Another worker queues digests of missed notifications for users with an
+//! email pusher.
+
+use std::cmp::min;
+#[cfg(test)]
+use std::sync::Mutex;
+
+use diesel::pg::PgConnection;
+use lettre::email::EmailBuilder;
+use lettre::transport::EmailTransport;
+use lettre::transport::smtp::{SecurityLevel, SmtpTransportBuilder};
+
+use config::{Config, SmtpConfig, SmtpTls};
+use error::ApiError;
+use locale::Locale;
+use models::mail_queue::QueuedMail;
+
+pub use self::digest::send_digests;
+pub use self::template::{
+    DigestContent,
+    DigestNotification,
+    DigestRoom,
+    MailTemplate,
+    NotificationDigestMail,
+    PasswordResetMail,
+    RenderedMail,
+    ThreepidValidationMail,
+    render,
+};
+
+mod digest;
+mod template;
+
+/// The maximum number of queued emails delivered in one run of the worker.
+const DELIVERY_BATCH_SIZE: i64 = 50;
+
+/// The number of delivery attempts after which an email is dropped from the queue.
+///
+/// With the delays of `retry_delay`, this gives up after about a day.
+const MAX_DELIVERY_ATTEMPTS: i32 = 30;
+
+/// The number of milliseconds before the first retry of a failed delivery.
+const INITIAL_RETRY_DELAY: i64 = 30 * 1000;
+
+/// The maximum number of milliseconds between two delivery attempts.
+const MAX_RETRY_DELAY: i64 = 60 * 60 * 1000;
+
+/// An email ready to be sent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Email {
+    /// The email address of the recipient.
+    pub to: String,
+    /// The subject.
+    pub subject: String,
+    /// The HTML body.
+    pub html: String,
+    /// The plain text body, shown by clients that do not display HTML.
+    pub text: String,
+}
+
+/// Something that can deliver an email.
+pub trait Mailer {
+    /// Send `email` to its recipient.
+    fn send(&self, email: &Email) -> Result<(), ApiError>;
+}
+
+/// A `Mailer` that only writes emails to the log.
+///
+/// Used when no SMTP server is configured.
+pub struct LoggingMailer;
+
+/// A `Mailer` that sends emails through an SMTP server.
+pub struct SmtpMailer {
+    /// The SMTP server and the sender of the emails.
+    config: SmtpConfig,
+}
+
+/// A `Mailer` that keeps the emails it "sends", so tests can inspect them.
+#[cfg(test)]
+pub struct CapturingMailer {
+    /// The emails sent so far, oldest first.
+    sent: Mutex<Vec<Email>>,
+}
+
+/// Create the `Mailer` for the given configuration.
+pub fn mailer(config: &Config) -> Box<Mailer + Send + Sync> {
+    match config.smtp {
+        Some(ref smtp) => Box::new(SmtpMailer::new(smtp.clone())),
+        None => Box::new(LoggingMailer),
+    }
+}
+
+impl Email {
+    /// Render a template in `locale` into an email to `to`.
+    pub fn from_template(to: &str, template: &MailTemplate, locale: Locale) -> Self {
+        let rendered = render(template, locale);
+
+        Email {
+            to: to.to_string(),
+            subject: rendered.subject,
+            html: rendered.html,
+            text: rendered.text,
+        }
+    }
+
+    /// Put the email into the mail queue, to be delivered by the worker.
+    pub fn queue(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        QueuedMail::enqueue(connection, &self.to, &self.subject, &self.html, &self.text)?;
+
+        Ok(())
+    }
+}
+
+impl Mailer for LoggingMailer {
+    fn send(&self, email: &Email) -> Result<(), ApiError> {
+        info!("Email to {} with subject \"{}\":\n{}", email.to, email.subject, email.text);
+
+        Ok(())
+    }
+}
+
+impl SmtpMailer {
+    /// Create an `SmtpMailer` for the given SMTP server.
+    pub fn new(config: SmtpConfig) -> Self {
+        SmtpMailer {
+            config: config,
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), ApiError> {
+        let message = EmailBuilder::new()
+            .to(email.to.as_str())
+            .from(self.config.from.as_str())
+            .subject(&email.subject)
+            .alternative(&email.html, &email.text)
+            .build()
+            .map_err(|error| ApiError::unknown(format!("Failed to build the email: {}", error)))?;
+
+        let security_level = match self.config.tls {
+            SmtpTls::None => SecurityLevel::NeverEncrypt,
+            SmtpTls::StartTls => SecurityLevel::AlwaysEncrypt,
+            SmtpTls::Tls => SecurityLevel::EncryptedWrapper,
+        };
+
+        let mut builder = SmtpTransportBuilder::new((self.config.host.as_str(), self.config.port()))
+            .map_err(|error| {
+                ApiError::unknown(format!("Failed to reach the SMTP server: {}", error))
+            })?
+            .security_level(security_level)
+            .smtp_utf8(true);
+
+        if let (Some(username), Some(password)) =
+            (self.config.username.as_ref(), self.config.password.as_ref()) {
+            builder = builder.credentials(username, password);
+        }
+
+        let mut transport = builder.build();
+        let result = transport.send(message);
+
+        transport.close();
+
+        result.map(|_| ()).map_err(|error| {
+            ApiError::unknown(format!("The SMTP server did not accept the email: {}", error))
+        })
+    }
+}
+
+#[cfg(test)]
+impl CapturingMailer {
+    /// Create a `CapturingMailer` that has not sent any emails.
+    pub fn new() -> Self {
+        CapturingMailer {
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The emails sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Mailer for CapturingMailer {
+    fn send(&self, email: &Email) -> Result<(), ApiError> {
+        self.sent.lock().unwrap().push(email.clone());
+
+        Ok(())
+    }
+}
+
+/// Deliver the queued emails that are due, which is the work of the mail worker.
+///
+/// Delivered emails are removed from the queue. Failed deliveries are retried later, until the
+/// email is given up on after `MAX_DELIVERY_ATTEMPTS`. Returns the number of delivered emails.
+pub fn deliver_queued(connection: &PgConnection, mailer: &Mailer) -> Result<usize, ApiError> {
+    let mut delivered = 0;
+
+    for queued_mail in QueuedMail::find_due(connection, DELIVERY_BATCH_SIZE)? {
+        let email = Email {
+            to: queued_mail.recipient.clone(),
+            subject: queued_mail.subject.clone(),
+            html: queued_mail.html_body.clone(),
+            text: queued_mail.text_body.clone(),
+        };
+
+        match mailer.send(&email) {
+            Ok(()) => {
+                queued_mail.delete(connection)?;
+
+                delivered += 1;
+            }
+            Err(error) => {
+                if queued_mail.attempts + 1 >= MAX_DELIVERY_ATTEMPTS {
+                    error!(
+                        "Giving up on the email to {} after {} attempts: {}",
+                        email.to,
+                        MAX_DELIVERY_ATTEMPTS,
+                        error
+                    );
+
+                    queued_mail.delete(connection)?;
+                } else {
+                    warn!("Failed to send the email to {}, retrying later: {}", email.to, error);
+
+                    queued_mail.retry_later(connection, retry_delay(queued_mail.attempts))?;
+                }
+            }
+        }
+    }
+
+    Ok(delivered)
+}
+
+/// The number of milliseconds to wait before retrying an email that failed `attempts` times
+/// before the current failure.
+fn retry_delay(attempts: i32) -> i64 {
+    (0..attempts).fold(INITIAL_RETRY_DELAY, |delay, _| min(delay * 2, MAX_RETRY_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use error::ApiError;
+    use super::{CapturingMailer, Email, Mailer, deliver_queued, retry_delay};
+    use test::Test;
+
+    /// A `Mailer` whose SMTP server is always down.
+    struct FailingMailer;
+
+    impl Mailer for FailingMailer {
+        fn send(&self, _: &Email) -> Result<(), ApiError> {
+            Err(ApiError::unknown(None))
+        }
+    }
+
+    #[test]
+    fn retry_delays_grow_up_to_the_maximum() {
+        assert_eq!(retry_delay(0), 30 * 1000);
+        assert_eq!(retry_delay(1), 60 * 1000);
+        assert_eq!(retry_delay(3), 240 * 1000);
+        assert_eq!(retry_delay(7), 60 * 60 * 1000);
+        assert_eq!(retry_delay(29), 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn queued_emails_are_delivered_once() {
+        let test = Test::new();
+        let mailer = CapturingMailer::new();
+
+        let email = Email {
+            to: "carl@example.com".to_string(),
+            subject: "Subject".to_string(),
+            html: "<p>Body</p>".to_string(),
+            text: "Body".to_string(),
+        };
+
+        test.with_connection(|connection| {
+            email.queue(connection).unwrap();
+
+            assert_eq!(deliver_queued(connection, &mailer).unwrap(), 1);
+            assert_eq!(deliver_queued(connection, &mailer).unwrap(), 0);
+        });
+
+        assert_eq!(mailer.sent(), vec![email]);
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_later() {
+        let test = Test::new();
+        let mailer = CapturingMailer::new();
+
+        let email = Email {
+            to: "carl@example.com".to_string(),
+            subject: "Subject".to_string(),
+            html: "<p>Body</p>".to_string(),
+            text: "Body".to_string(),
+        };
+
+        test.with_connection(|connection| {
+            email.queue(connection).unwrap();
+
+            assert_eq!(deliver_queued(connection, &FailingMailer).unwrap(), 0);
+
+            // The email is not due again until the retry delay has passed.
+            assert_eq!(deliver_queued(connection, &mailer).unwrap(), 0);
+        });
+
+        assert!(mailer.sent().is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+//! Templates of the emails Ruma sends.
+//!
+//! Every email has an HTML and a plain text template, bundled into the binary. Templates contain
+//! placeholders in double braces: `{{name}}` is replaced with a variable of the email, and
+//! `{{t:key}}` with the message `mail.<template>.<key>` from the catalog of the recipient's
+//! locale, whose own `{placeholders}` are filled with the same variables. The subject is the
+//! message `mail.<template>.subject`. Values are HTML-escaped in the HTML template, except for
+//! variables in triple braces like `{{{name}}}`, which templates escape themselves. Emails that
+//! only differ in their messages, like those sending a token, share their templates.
+
+use locale::Locale;
+
+/// The variables and templates of one kind of email.
+///
+/// Implementations only describe their content, so new kinds of emails, e.g. notifications,
+/// need nothing but a new type, its templates and its messages.
+pub trait MailTemplate {
+    /// The name of the template, used as the prefix of its messages in the catalogs.
+    fn name(&self) -> &'static str;
+
+    /// The HTML template.
+    fn html(&self) -> &'static str;
+
+    /// The plain text template.
+    fn text(&self) -> &'static str;
+
+    /// The values of the template's variables in the given locale.
+    fn variables(&self, locale: Locale) -> Vec<(&'static str, String)>;
+}
+
+/// The email sent to validate an email address before it is bound to an account.
+#[derive(Clone, Debug)]
+pub struct ThreepidValidationMail {
+    /// The domain of this server.
+    pub domain: String,
+    /// The token the user has to submit.
+    pub token: String,
+}
+
+/// The email sent to reset the password of the account an email address is bound to.
+#[derive(Clone, Debug)]
+pub struct PasswordResetMail {
+    /// The domain of this server.
+    pub domain: String,
+    /// The token the user has to submit.
+    pub token: String,
+}
+
+/// The email sent to users with an email pusher about notifications they missed while
+/// offline.
+#[derive(Clone, Debug)]
+pub struct NotificationDigestMail {
+    /// The domain of this server.
+    pub domain: String,
+    /// The rooms with notifications, in the order they are listed.
+    pub rooms: Vec<DigestRoom>,
+}
+
+/// The notifications of one room in a digest.
+#[derive(Clone, Debug)]
+pub struct DigestRoom {
+    /// The name of the room.
+    pub name: String,
+    /// The notifications, oldest first.
+    pub notifications: Vec<DigestNotification>,
+}
+
+/// A notification in a digest.
+#[derive(Clone, Debug)]
+pub struct DigestNotification {
+    /// The display name or ID of the user who sent the event.
+    pub sender: String,
+    /// What the notification is about.
+    pub content: DigestContent,
+}
+
+/// What a notification in a digest is about.
+#[derive(Clone, Debug)]
+pub enum DigestContent {
+    /// A message in an unencrypted room, with its body.
+    Message(String),
+    /// A message the server cannot read, shown with a generic line.
+    Encrypted,
+    /// An incoming call.
+    Call,
+}
+
+/// The subject and bodies of an email in one locale.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RenderedMail {
+    /// The subject.
+    pub subject: String,
+    /// The HTML body.
+    pub html: String,
+    /// The plain text body.
+    pub text: String,
+}
+
+impl MailTemplate for ThreepidValidationMail {
+    fn name(&self) -> &'static str {
+        "threepid_validation"
+    }
+
+    fn html(&self) -> &'static str {
+        include_str!("templates/token.html")
+    }
+
+    fn text(&self) -> &'static str {
+        include_str!("templates/token.txt")
+    }
+
+    fn variables(&self, _: Locale) -> Vec<(&'static str, String)> {
+        vec![("domain", self.domain.clone()), ("token", self.token.clone())]
+    }
+}
+
+impl MailTemplate for PasswordResetMail {
+    fn name(&self) -> &'static str {
+        "password_reset"
+    }
+
+    fn html(&self) -> &'static str {
+        include_str!("templates/token.html")
+    }
+
+    fn text(&self) -> &'static str {
+        include_str!("templates/token.txt")
+    }
+
+    fn variables(&self, _: Locale) -> Vec<(&'static str, String)> {
+        vec![("domain", self.domain.clone()), ("token", self.token.clone())]
+    }
+}
+
+impl NotificationDigestMail {
+    /// The line shown for a notification, without escaping.
+    fn line(notification: &DigestNotification, locale: Locale) -> String {
+        let sender = [("sender", notification.sender.as_str())];
+
+        match notification.content {
+            DigestContent::Message(ref body) => format!("{}: {}", notification.sender, body),
+            DigestContent::Encrypted => {
+                locale.format("mail.notification_digest.encrypted", &sender)
+            }
+            DigestContent::Call => locale.format("mail.notification_digest.call", &sender),
+        }
+    }
+}
+
+impl MailTemplate for NotificationDigestMail {
+    fn name(&self) -> &'static str {
+        "notification_digest"
+    }
+
+    fn html(&self) -> &'static str {
+        include_str!("templates/notification_digest.html")
+    }
+
+    fn text(&self) -> &'static str {
+        include_str!("templates/notification_digest.txt")
+    }
+
+    fn variables(&self, locale: Locale) -> Vec<(&'static str, String)> {
+        let mut rooms_text = String::new();
+        let mut rooms_html = String::new();
+
+        for room in &self.rooms {
+            rooms_text.push_str(&room.name);
+            rooms_text.push('\n');
+            rooms_html.push_str(&format!("<h2>{}</h2>\n<ul>\n", escape_html(&room.name)));
+
+            for notification in &room.notifications {
+                let line = NotificationDigestMail::line(notification, locale);
+
+                rooms_text.push_str(&format!("  {}\n", line));
+                rooms_html.push_str(&format!("<li>{}</li>\n", escape_html(&line)));
+            }
+
+            rooms_text.push('\n');
+            rooms_html.push_str("</ul>\n");
+        }
+
+        vec![
+            ("domain", self.domain.clone()),
+            ("rooms", rooms_text.trim_right().to_string()),
+            ("rooms_html", rooms_html.trim_right().to_string()),
+        ]
+    }
+}
+
+/// Render the subject and both bodies of an email in the given locale.
+pub fn render(template: &MailTemplate, locale: Locale) -> RenderedMail {
+    let variables = template.variables(locale);
+
+    RenderedMail {
+        subject: translate(template, locale, "subject", &variables),
+        html: render_source(template, locale, template.html(), &variables, true),
+        text: render_source(template, locale, template.text(), &variables, false),
+    }
+}
+
+/// Replace the placeholders of one of the templates.
+fn render_source(
+    template: &MailTemplate,
+    locale: Locale,
+    source: &str,
+    variables: &[(&'static str, String)],
+    escape: bool,
+) -> String {
+    let mut rendered = String::with_capacity(source.len());
+    let mut rest = source;
+
+    while let Some(start) = rest.find("{{") {
+        let (open, close) = if rest[start..].starts_with("{{{") {
+            ("{{{", "}}}")
+        } else {
+            ("{{", "}}")
+        };
+
+        let end = match rest[start..].find(close) {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        rendered.push_str(&rest[..start]);
+
+        let placeholder = rest[start + open.len()..end].trim();
+
+        let value = if placeholder.starts_with("t:") {
+            translate(template, locale, &placeholder[2..], variables)
+        } else {
+            match variables.iter().find(|&&(name, _)| name == placeholder) {
+                Some(&(_, ref value)) => value.clone(),
+                None => {
+                    warn!("The mail template {} has no variable {}.", template.name(), placeholder);
+
+                    String::new()
+                }
+            }
+        };
+
+        if escape && open == "{{" {
+            rendered.push_str(&escape_html(&value));
+        } else {
+            rendered.push_str(&value);
+        }
+
+        rest = &rest[end + close.len()..];
+    }
+
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// Look up a message of the template and fill in the variables.
+fn translate(
+    template: &MailTemplate,
+    locale: Locale,
+    key: &str,
+    variables: &[(&'static str, String)],
+) -> String {
+    let args: Vec<(&str, &str)> = variables.iter()
+        .map(|&(name, ref value)| (name, value.as_str()))
+        .collect();
+
+    locale.format(&format!("mail.{}.{}", template.name(), key), &args)
+}
+
+/// Escape the characters that have a meaning in HTML.
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use locale::Locale;
+    use super::{
+        DigestContent,
+        DigestNotification,
+        DigestRoom,
+        NotificationDigestMail,
+        PasswordResetMail,
+        ThreepidValidationMail,
+        render,
+    };
+
+    #[test]
+    fn render_threepid_validation_mail() {
+        let mail = ThreepidValidationMail {
+            domain: "ruma.test".to_string(),
+            token: "123456".to_string(),
+        };
+
+        let rendered = render(&mail, Locale::English);
+
+        assert_eq!(rendered.subject, "Validate your email address on ruma.test");
+        assert!(rendered.text.contains("Your validation code is: 123456"));
+        assert!(rendered.text.contains("an account on ruma.test"));
+        assert!(!rendered.text.contains("{{"));
+        assert!(rendered.html.contains("<strong>123456</strong>"));
+        assert!(
+            rendered.html.contains("<title>Validate your email address on ruma.test</title>")
+        );
+        assert!(!rendered.html.contains("{{"));
+    }
+
+    #[test]
+    fn render_password_reset_mail_in_german() {
+        let mail = PasswordResetMail {
+            domain: "ruma.test".to_string(),
+            token: "123456".to_string(),
+        };
+
+        let rendered = render(&mail, Locale::German);
+
+        assert_eq!(rendered.subject, "Passwort für ruma.test zurücksetzen");
+        assert!(rendered.text.contains("Dein Bestätigungscode lautet: 123456"));
+        assert!(rendered.html.contains("<strong>123456</strong>"));
+        assert!(!rendered.html.contains("{{"));
+    }
+
+    #[test]
+    fn variables_are_escaped_in_html() {
+        let mail = ThreepidValidationMail {
+            domain: "<ruma.test>".to_string(),
+            token: "1 & 2".to_string(),
+        };
+
+        let rendered = render(&mail, Locale::English);
+
+        assert!(rendered.html.contains("<strong>1 &amp; 2</strong>"));
+        assert!(rendered.html.contains("&lt;ruma.test&gt;"));
+        assert!(rendered.text.contains("1 & 2"));
+    }
+
+    #[test]
+    fn render_notification_digest_mail() {
+        let mail = NotificationDigestMail {
+            domain: "ruma.test".to_string(),
+            rooms: vec![DigestRoom {
+                name: "Tom & Jerry".to_string(),
+                notifications: vec![
+                    DigestNotification {
+                        sender: "Alice".to_string(),
+                        content: DigestContent::Message("<b>Hi</b>".to_string()),
+                    },
+                    DigestNotification {
+                        sender: "Bob".to_string(),
+                        content: DigestContent::Encrypted,
+                    },
+                ],
+            }],
+        };
+
+        let rendered = render(&mail, Locale::English);
+
+        assert_eq!(rendered.subject, "Notifications you missed on ruma.test");
+        assert!(rendered.text.contains("Tom & Jerry\n  Alice: <b>Hi</b>\n"));
+        assert!(rendered.text.contains("Bob sent an encrypted message."));
+        assert!(rendered.html.contains("<h2>Tom &amp; Jerry</h2>"));
+        assert!(rendered.html.contains("<li>Alice: &lt;b&gt;Hi&lt;/b&gt;</li>"));
+        assert!(!rendered.html.contains("{{"));
+    }
+}
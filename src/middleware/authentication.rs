@@ -6,17 +6,36 @@ use ruma_identifiers::UserId;
 use serde_json::Value;
 use url::Url;
 
+use appservice::AppServiceRegistration;
 use authentication::{AuthParams, InteractiveAuth, PasswordAuthParams};
 use config::Config;
 use db::DB;
 use error::ApiError;
+use middleware::ClientIp;
 use models::access_token::AccessToken;
+use models::monthly_active_user::MonthlyActiveUser;
 use models::user::User;
 
 /// Handles access token authentication for all API endpoints that require it.
+///
+/// Once the server has `max_mau` monthly active users, requests of users who are not currently
+/// active fail with `M_RESOURCE_LIMIT_EXCEEDED`, even with a valid access token.
+///
+/// Application services authenticate with their `as_token` instead of an access token. They act
+/// as the user named by the `user_id` query parameter, which must be in their namespaces, or as
+/// their sender user. Their requests have an `AppServiceRegistration` instead of an
+/// `AccessToken`.
 #[derive(Debug)]
 pub struct AccessTokenAuth;
 
+/// Handles access token authentication for API endpoints that can also be used without one,
+/// e.g. to read world-readable rooms.
+///
+/// Requests with an access token are authenticated like with `AccessTokenAuth`. Requests without
+/// one are passed on without a `User`.
+#[derive(Debug)]
+pub struct OptionalAccessTokenAuth;
+
 /// Handles Matrix's interactive authentication protocol for all API endpoints that require it.
 #[derive(Debug)]
 pub struct UIAuth {
@@ -39,13 +58,36 @@ impl BeforeMiddleware for AccessTokenAuth {
         let mut query_pairs = url.query_pairs();
 
         if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
+            let config = Config::from_request(request)?;
+
+            if let Some(registration) =
+                AppServiceRegistration::find_by_token(&config.app_services, token) {
+                let user_id = url.query_pairs()
+                    .find(|&(ref key, _)| key == "user_id")
+                    .map(|(_, user_id)| user_id.into_owned());
+
+                return authenticate_app_service(request, registration, user_id, &config.domain);
+            }
+
             let access_token = match AccessToken::find_valid_by_token(&connection, token)? {
                 Some(access_token) => access_token,
+                None if AccessToken::is_soft_logged_out(&connection, token)? => {
+                    Err(ApiError::unknown_token(true))?
+                }
                 None => Err(ApiError::unauthorized("Unknown token".to_string()))?,
             };
 
             match User::find_active_user(&connection, &access_token.user_id)? {
                 Some(user) => {
+                    MonthlyActiveUser::check_limit(
+                        &connection,
+                        Some(&user.id),
+                        config.max_mau,
+                        config.admin_contact.as_ref(),
+                    )?;
+                    MonthlyActiveUser::record_activity(&connection, &user.id)?;
+                    access_token.record_use(&connection, ClientIp::from_request(request))?;
+
                     request.extensions.insert::<AccessToken>(access_token);
                     request.extensions.insert::<User>(user);
 
@@ -61,6 +103,50 @@ impl BeforeMiddleware for AccessTokenAuth {
     }
 }
 
+/// Authenticate a request of an application service as the user it names, or its sender.
+fn authenticate_app_service(
+    request: &mut Request,
+    registration: &AppServiceRegistration,
+    user_id: Option<String>,
+    domain: &str,
+) -> IronResult<()> {
+    let user_id = match user_id {
+        Some(user_id) => UserId::try_from(user_id.as_str()).map_err(ApiError::from)?,
+        None => registration.sender(domain)?,
+    };
+
+    if !registration.controls_user(&user_id, domain)? {
+        Err(ApiError::unauthorized(format!(
+            "The application service cannot act as {}.",
+            user_id
+        )))?;
+    }
+
+    let connection = DB::from_request(request)?;
+
+    match User::find_active_user(&connection, &user_id)? {
+        Some(user) => {
+            request.extensions.insert::<AppServiceRegistration>(registration.clone());
+            request.extensions.insert::<User>(user);
+
+            Ok(())
+        }
+        None => Err(ApiError::unauthorized(format!("The user {} does not exist.", user_id)))?,
+    }
+}
+
+impl BeforeMiddleware for OptionalAccessTokenAuth {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let url: Url = request.url.clone().into();
+
+        if url.query_pairs().any(|(key, _)| key == "access_token") {
+            AccessTokenAuth.before(request)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl BeforeMiddleware for UIAuth {
     fn before(&self, request: &mut Request) -> IronResult<()> {
         let json = request
@@ -0,0 +1,271 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use iron::{BeforeMiddleware, IronResult, Request};
+use iron::typemap::Key;
+
+use util::ip_network::{IpNetwork, canonical};
+
+/// Determines the IP address of the client a request comes from.
+///
+/// Behind a reverse proxy, every request seems to come from the proxy. Each proxy appends the
+/// address it received the request from to the `X-Forwarded-For` header, or to `Forwarded` if
+/// there is no `X-Forwarded-For`, so starting from the peer and going left, the first address that
+/// is not one of the `trusted_proxies` is the client's. Addresses further left were sent by the
+/// client and could be made up. The headers are ignored unless the peer is a trusted proxy.
+///
+/// It should be linked before anything that needs the address, which it gets with
+/// `ClientIp::from_request`.
+#[derive(Clone, Debug)]
+pub struct ClientIp {
+    /// The networks of the reverse proxies whose forwarding headers are believed.
+    trusted_proxies: Vec<IpNetwork>,
+}
+
+impl ClientIp {
+    /// Create a `ClientIp` believing the forwarding headers of the given proxies.
+    pub fn new(trusted_proxies: Vec<IpNetwork>) -> Self {
+        ClientIp {
+            trusted_proxies: trusted_proxies,
+        }
+    }
+
+    /// The IP address of the client a request comes from, or the address of the peer if the
+    /// request was not passed through `ClientIp`.
+    pub fn from_request(request: &Request) -> IpAddr {
+        request.extensions.get::<ClientIp>().cloned().unwrap_or_else(|| {
+            canonical(request.remote_addr.ip())
+        })
+    }
+
+    /// Check whether `ip` belongs to a trusted proxy.
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|network| network.contains(ip))
+    }
+
+    /// Follow the chain of trusted proxies back to the client.
+    ///
+    /// An entry that is not an address, like the *unknown* allowed by `Forwarded`, ends the chain
+    /// at the proxy that added it.
+    fn resolve(&self, request: &Request) -> IpAddr {
+        let mut client = canonical(request.remote_addr.ip());
+
+        if !self.is_trusted(client) {
+            return client;
+        }
+
+        for entry in forwarded_addresses(request).into_iter().rev() {
+            match entry {
+                Some(ip) => client = ip,
+                None => break,
+            }
+
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+impl BeforeMiddleware for ClientIp {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let ip = self.resolve(request);
+
+        request.extensions.insert::<ClientIp>(ip);
+
+        Ok(())
+    }
+}
+
+impl Key for ClientIp {
+    type Value = IpAddr;
+}
+
+/// The addresses of the forwarding headers of a request, in the order they were added, or `None`
+/// for entries without a valid address.
+fn forwarded_addresses(request: &Request) -> Vec<Option<IpAddr>> {
+    if let Some(entries) = header_entries(request, "X-Forwarded-For") {
+        return entries.iter().map(|entry| parse_address(entry)).collect();
+    }
+
+    if let Some(elements) = header_entries(request, "Forwarded") {
+        return elements.iter().map(|element| {
+            element.split(';')
+                .filter_map(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+
+                    match (parts.next(), parts.next()) {
+                        (Some(key), Some(value)) if key.trim().to_lowercase() == "for" => {
+                            Some(value)
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+                .and_then(parse_address)
+        }).collect();
+    }
+
+    Vec::new()
+}
+
+/// The comma-separated entries of all values of a header, or `None` if the request does not have
+/// the header.
+fn header_entries(request: &Request, name: &str) -> Option<Vec<String>> {
+    request.headers.get_raw(name).map(|values| {
+        values.iter()
+            .flat_map(|value| {
+                String::from_utf8_lossy(value)
+                    .split(',')
+                    .map(|entry| entry.trim().to_string())
+                    .collect::<Vec<String>>()
+            })
+            .collect()
+    })
+}
+
+/// Parse an address of a forwarding header, which may be quoted and have a port, like
+/// *"[2001:db8::1]:4711"* or *192.0.2.1:4711*.
+fn parse_address(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim().trim_matches('"');
+
+    let address = if entry.starts_with('[') {
+        match entry.find(']') {
+            Some(end) => &entry[1..end],
+            None => return None,
+        }
+    } else if entry.matches(':').count() == 1 {
+        &entry[..entry.find(':').expect("The entry should contain a colon")]
+    } else {
+        entry
+    };
+
+    IpAddr::from_str(address).ok().map(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{ExpressionMethods, FilterDsl, LoadDsl};
+    use iron::{Chain, IronResult, Request, Response};
+    use iron::headers::Headers;
+    use iron::method::Method;
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use ruma_identifiers::UserId;
+
+    use models::access_token::AccessToken;
+    use schema::access_tokens;
+    use test::Test;
+    use util::ip_network::IpNetwork;
+    use super::{ClientIp, parse_address};
+
+    /// A handler that responds with the client's address.
+    fn handler(request: &mut Request) -> IronResult<Response> {
+        Ok(Response::with((Status::Ok, ClientIp::from_request(request).to_string())))
+    }
+
+    /// Resolve the address of a test request, which comes from the loopback address, with the
+    /// given raw headers.
+    fn resolve(trusted_proxies: &[&str], headers: &[(&str, &str)]) -> String {
+        let trusted_proxies = trusted_proxies.iter()
+            .map(|network| IpNetwork::parse(network).unwrap())
+            .collect();
+
+        let mut chain = Chain::new(handler);
+        chain.link_before(ClientIp::new(trusted_proxies));
+
+        let mut raw_headers = Headers::new();
+
+        for &(name, value) in headers {
+            raw_headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+        }
+
+        let response = request::get("http://ruma.test/", raw_headers, &chain).unwrap();
+
+        response::extract_body_to_string(response)
+    }
+
+    /// The address the access tokens of a user were last used from.
+    fn last_used_ip(test: &Test, user_id: &str) -> Option<String> {
+        let user_id = UserId::try_from(user_id).unwrap();
+
+        test.with_connection(|connection| {
+            access_tokens::table
+                .filter(access_tokens::user_id.eq(&user_id))
+                .first::<AccessToken>(connection)
+                .unwrap()
+                .last_used_ip
+        })
+    }
+
+    #[test]
+    fn forwarding_headers_of_trusted_proxies_are_used() {
+        let trusted = ["127.0.0.1", "10.0.0.0/8"];
+
+        assert_eq!(resolve(&trusted, &[("X-Forwarded-For", "203.0.113.7")]), "203.0.113.7");
+        assert_eq!(
+            resolve(&trusted, &[("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.1.2.3")]),
+            "203.0.113.7"
+        );
+        assert_eq!(
+            resolve(&trusted, &[("Forwarded", "for=\"[2001:db8::1]:4711\";proto=https")]),
+            "2001:db8::1"
+        );
+        assert_eq!(resolve(&trusted, &[("X-Forwarded-For", "10.0.0.1")]), "10.0.0.1");
+        assert_eq!(resolve(&trusted, &[("X-Forwarded-For", "unknown, 10.0.0.1")]), "10.0.0.1");
+        assert_eq!(resolve(&trusted, &[]), "127.0.0.1");
+    }
+
+    #[test]
+    fn forwarding_headers_of_other_peers_are_ignored() {
+        assert_eq!(resolve(&[], &[("X-Forwarded-For", "203.0.113.7")]), "127.0.0.1");
+        assert_eq!(
+            resolve(&["10.0.0.0/8"], &[("Forwarded", "for=203.0.113.7")]),
+            "127.0.0.1"
+        );
+    }
+
+    #[test]
+    fn addresses_with_ports() {
+        assert_eq!(parse_address("192.0.2.1:4711").unwrap().to_string(), "192.0.2.1");
+        assert_eq!(parse_address("\"[2001:db8::1]\"").unwrap().to_string(), "2001:db8::1");
+        assert_eq!(parse_address("2001:db8::1").unwrap().to_string(), "2001:db8::1");
+        assert!(parse_address("_hidden").is_none());
+    }
+
+    #[test]
+    fn address_forwarded_by_trusted_proxy_is_recorded() {
+        let test = Test::with_config(|config| {
+            config.trusted_proxies = vec![IpNetwork::parse("127.0.0.1/32").unwrap()];
+        });
+        let user = test.create_user();
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", vec![b"203.0.113.7".to_vec()]);
+
+        let path = format!("/_matrix/client/r0/pushers?access_token={}", user.token);
+        let response = test.request_with_headers(Method::Get, &path, "", headers);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(last_used_ip(&test, &user.id), Some("203.0.113.7".to_string()));
+    }
+
+    #[test]
+    fn address_of_untrusted_peer_is_recorded() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Forwarded-For", vec![b"203.0.113.7".to_vec()]);
+
+        let path = format!("/_matrix/client/r0/pushers?access_token={}", user.token);
+        let response = test.request_with_headers(Method::Get, &path, "", headers);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(last_used_ip(&test, &user.id), Some("127.0.0.1".to_string()));
+    }
+}
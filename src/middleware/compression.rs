@@ -0,0 +1,225 @@
+use std::io::{Error as IoError, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use iron::{AfterMiddleware, IronResult, Request, Response};
+use iron::headers::{
+    AcceptEncoding,
+    ContentEncoding,
+    ContentLength,
+    ContentType,
+    Encoding,
+    TransferEncoding,
+    Vary,
+    q,
+};
+use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::response::WriteBody;
+use unicase::UniCase;
+
+use error::ApiError;
+
+/// Compresses JSON responses with gzip for clients that accept it.
+///
+/// Responses smaller than the threshold are sent as they are, since compressing them saves
+/// little. Bodies are collected in memory to measure them and are sent with the Content-Length of
+/// the compressed body, except for streamed bodies, which are sent with chunked transfer encoding
+/// and are compressed while they are written. Other content, like media, is never compressed.
+#[derive(Clone, Copy, Debug)]
+pub struct ResponseCompression {
+    /// The minimum size in bytes of the bodies that are compressed.
+    min_size: usize,
+}
+
+/// A body that is compressed with gzip while it is written.
+struct GzipBody(Box<WriteBody>);
+
+impl ResponseCompression {
+    /// Create a `ResponseCompression` compressing bodies of at least `min_size` bytes.
+    pub fn new(min_size: usize) -> Self {
+        ResponseCompression {
+            min_size: min_size,
+        }
+    }
+}
+
+impl AfterMiddleware for ResponseCompression {
+    fn after(&self, request: &mut Request, mut response: Response) -> IronResult<Response> {
+        if !accepts_gzip(request) || !is_json(&response) ||
+            response.headers.has::<ContentEncoding>() {
+            return Ok(response);
+        }
+
+        let mut body = match response.body.take() {
+            Some(body) => body,
+            None => return Ok(response),
+        };
+
+        response.headers.set(Vary::Items(vec![UniCase("Accept-Encoding".to_string())]));
+
+        if response.headers.has::<TransferEncoding>() {
+            response.headers.remove::<ContentLength>();
+            response.headers.set(ContentEncoding(vec![Encoding::Gzip]));
+            response.body = Some(Box::new(GzipBody(body)));
+
+            return Ok(response);
+        }
+
+        let mut buffer = Vec::new();
+
+        body.write_body(&mut buffer).map_err(ApiError::from)?;
+
+        if buffer.len() < self.min_size {
+            response.body = Some(Box::new(buffer));
+        } else {
+            // A Content-Length set by the handler is that of the uncompressed body, which clients
+            // would wait for or cut the compressed body at.
+            let mut compressed = Vec::new();
+
+            GzipBody(Box::new(buffer)).write_body(&mut compressed).map_err(ApiError::from)?;
+
+            response.headers.set(ContentEncoding(vec![Encoding::Gzip]));
+            response.headers.set(ContentLength(compressed.len() as u64));
+            response.body = Some(Box::new(compressed));
+        }
+
+        Ok(response)
+    }
+}
+
+impl WriteBody for GzipBody {
+    fn write_body(&mut self, body: &mut Write) -> Result<(), IoError> {
+        let mut encoder = GzEncoder::new(body, Compression::Default);
+
+        self.0.write_body(&mut encoder)?;
+
+        encoder.finish().map(|_| ())
+    }
+}
+
+/// Check whether the client accepts gzip-compressed responses.
+fn accepts_gzip(request: &Request) -> bool {
+    request.headers.get::<AcceptEncoding>().map_or(false, |accept_encoding| {
+        accept_encoding.iter().any(|item| item.item == Encoding::Gzip && item.quality > q(0))
+    })
+}
+
+/// Check whether the response's Content-Type is JSON.
+fn is_json(response: &Response) -> bool {
+    match response.headers.get::<ContentType>() {
+        Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use iron::{Chain, IronResult, Request, Response};
+    use iron::headers::{ContentEncoding, ContentLength, Encoding, Headers};
+    use iron::response::WriteBody;
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use serde_json::{Value, from_str};
+
+    use modifier::SerializableResponse;
+    use super::ResponseCompression;
+
+    /// A handler that responds with a JSON array of 100 events.
+    fn handler(_: &mut Request) -> IronResult<Response> {
+        let events: Vec<Value> = (0..100).map(|index| {
+            from_str(&format!(r#"{{"type":"m.room.message","content":{{"body":"{}"}}}}"#, index))
+                .unwrap()
+        }).collect();
+
+        Ok(Response::with((Status::Ok, SerializableResponse(events))))
+    }
+
+    fn get(chain: &Chain, accept_encoding: Option<&str>) -> Response {
+        let mut headers = Headers::new();
+
+        if let Some(accept_encoding) = accept_encoding {
+            headers.set_raw("Accept-Encoding", vec![accept_encoding.as_bytes().to_vec()]);
+        }
+
+        request::get("http://ruma.test/", headers, chain).unwrap()
+    }
+
+    fn chain(min_size: usize) -> Chain {
+        let mut chain = Chain::new(handler);
+        chain.link_after(ResponseCompression::new(min_size));
+
+        chain
+    }
+
+    #[test]
+    fn large_json_responses_are_compressed() {
+        let response = get(&chain(1024), Some("deflate, gzip;q=0.8"));
+
+        assert_eq!(
+            response.headers.get::<ContentEncoding>(),
+            Some(&ContentEncoding(vec![Encoding::Gzip]))
+        );
+
+        let compressed = response::extract_body_to_bytes(response);
+        let mut body = String::new();
+
+        GzDecoder::new(&compressed[..]).unwrap().read_to_string(&mut body).unwrap();
+
+        let events: Vec<Value> = from_str(&body).unwrap();
+
+        assert_eq!(events.len(), 100);
+        assert!(compressed.len() < body.len());
+    }
+
+    #[test]
+    fn content_length_is_that_of_the_compressed_body() {
+        fn handler_with_content_length(request: &mut Request) -> IronResult<Response> {
+            let mut response = handler(request)?;
+            let mut body = Vec::new();
+
+            response.body.take().unwrap().write_body(&mut body).unwrap();
+            response.headers.set(ContentLength(body.len() as u64));
+            response.body = Some(Box::new(body));
+
+            Ok(response)
+        }
+
+        let mut chain = Chain::new(handler_with_content_length);
+        chain.link_after(ResponseCompression::new(0));
+
+        let response = get(&chain, Some("gzip"));
+        let content_length = response.headers.get::<ContentLength>().unwrap().0;
+        let compressed = response::extract_body_to_bytes(response);
+
+        assert_eq!(content_length, compressed.len() as u64);
+
+        let mut body = String::new();
+
+        GzDecoder::new(&compressed[..]).unwrap().read_to_string(&mut body).unwrap();
+
+        assert!(body.len() as u64 > content_length);
+    }
+
+    #[test]
+    fn small_responses_are_not_compressed() {
+        let response = get(&chain(1024 * 1024), Some("gzip"));
+
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+
+        let events: Vec<Value> = from_str(&response::extract_body_to_string(response)).unwrap();
+
+        assert_eq!(events.len(), 100);
+    }
+
+    #[test]
+    fn responses_are_not_compressed_unless_accepted() {
+        for accept_encoding in &[None, Some("identity"), Some("gzip;q=0")] {
+            let response = get(&chain(0), *accept_encoding);
+
+            assert!(response.headers.get::<ContentEncoding>().is_none());
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response, Set};
+use iron::headers::AcceptLanguage;
+
+use error::ApiError;
+use locale::Locale;
+
+/// Picks the locale of a request from its Accept-Language header and translates the messages of
+/// the errors it results in.
+#[derive(Clone, Copy, Debug)]
+pub struct Localization {
+    /// The locale used if the client accepts none of the supported locales.
+    default_locale: Locale,
+}
+
+impl Localization {
+    /// Create a `Localization` falling back to the given locale.
+    pub fn new(default_locale: Locale) -> Self {
+        Localization {
+            default_locale: default_locale,
+        }
+    }
+}
+
+impl BeforeMiddleware for Localization {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let locale = request.headers.get::<AcceptLanguage>()
+            .and_then(Locale::from_accept_language)
+            .unwrap_or(self.default_locale);
+
+        request.extensions.insert::<Locale>(locale);
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for Localization {
+    fn catch(&self, request: &mut Request, mut error: IronError) -> IronResult<Response> {
+        let locale = request.extensions.get::<Locale>().cloned().unwrap_or(self.default_locale);
+
+        let localized_error = error.error.downcast_ref::<ApiError>().map(|api_error| api_error.localize(locale));
+
+        if let Some(localized_error) = localized_error {
+            error.response.set_mut(localized_error);
+        }
+
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::Headers;
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::Test;
+
+    fn accept_language(value: &str) -> Headers {
+        let mut headers = Headers::new();
+
+        headers.set_raw("Accept-Language", vec![value.as_bytes().to_vec()]);
+
+        headers
+    }
+
+    #[test]
+    fn translate_error_message() {
+        let test = Test::new();
+
+        let english = test.get("/_matrix/client/r0/account/3pid");
+        let german = test.request_with_headers(
+            Method::Get,
+            "/_matrix/client/r0/account/3pid",
+            "",
+            accept_language("de-DE,de;q=0.9,en;q=0.8"),
+        );
+
+        assert_eq!(german.status, Status::Forbidden);
+        assert_eq!(
+            german.json().get("errcode").unwrap().as_str().unwrap(),
+            english.json().get("errcode").unwrap().as_str().unwrap()
+        );
+        assert_eq!(english.json().get("error").unwrap().as_str().unwrap(), "Authentication is required.");
+        assert_eq!(
+            german.json().get("error").unwrap().as_str().unwrap(),
+            "Eine Authentifizierung ist erforderlich."
+        );
+    }
+
+    #[test]
+    fn unsupported_language_falls_back_to_default_locale() {
+        let test = Test::new();
+
+        let response = test.request_with_headers(
+            Method::Get,
+            "/_matrix/client/r0/account/3pid",
+            "",
+            accept_language("fr-FR"),
+        );
+
+        assert_eq!(response.json().get("error").unwrap().as_str().unwrap(), "Authentication is required.");
+    }
+}
@@ -2,12 +2,24 @@
 use iron::Chain;
 
 mod authentication;
+mod client_ip;
+mod compression;
 mod json;
+mod locale;
+mod panic_recovery;
 mod path_params;
+mod request_log;
 mod response_headers;
+mod routing;
 
-pub use self::authentication::{AccessTokenAuth, UIAuth};
+pub use self::authentication::{AccessTokenAuth, OptionalAccessTokenAuth, UIAuth};
+pub use self::client_ip::ClientIp;
+pub use self::compression::ResponseCompression;
+pub use self::locale::Localization;
+pub use self::panic_recovery::PanicRecovery;
+pub use self::request_log::{RequestId, RequestLogger};
 pub use self::response_headers::ResponseHeaders;
+pub use self::routing::Routing;
 pub use self::json::JsonRequest;
 pub use self::path_params::{
     DataTypeParam,
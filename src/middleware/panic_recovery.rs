@@ -0,0 +1,100 @@
+use std::any::Any;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use iron::{AroundMiddleware, Handler, IronResult, Request, Response};
+
+use error::ApiError;
+use middleware::RequestId;
+
+/// Turns panics in handlers into `M_UNKNOWN` errors, so clients get a JSON response instead of a
+/// reset connection.
+///
+/// It should be linked before `RequestLogger`, so panics are logged with the ID of the request
+/// and the access log shows the error response.
+#[derive(Clone, Copy, Debug)]
+pub struct PanicRecovery;
+
+/// The `Handler` wrapped by `PanicRecovery`.
+struct RecoveringHandler {
+    /// The handler that serves the requests.
+    handler: Box<Handler>,
+}
+
+impl AroundMiddleware for PanicRecovery {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(RecoveringHandler {
+            handler: handler,
+        })
+    }
+}
+
+impl Handler for RecoveringHandler {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        // Handlers only share state through the database connection pool, which stays usable
+        // after a panic, so nothing can be observed in a broken state afterwards.
+        let result = catch_unwind(AssertUnwindSafe(|| self.handler.handle(request)));
+
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let request_id = request.extensions.get::<RequestId>()
+                    .map(|request_id| format!("#{}", request_id))
+                    .unwrap_or_else(|| "without ID".to_string());
+
+                error!("Request {} panicked: {}", request_id, panic_message(&*payload));
+
+                Err(ApiError::unknown(None).into())
+            }
+        }
+    }
+}
+
+/// Extract the message of a panic, which is either a `&str` or a `String`.
+fn panic_message(payload: &(Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::{Chain, IronResult, Request, Response};
+    use iron::headers::{ContentType, Headers};
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use serde_json::{Value, from_str};
+
+    use logging::LogFormat;
+    use middleware::RequestLogger;
+    use super::PanicRecovery;
+
+    /// A handler that always panics.
+    fn panicking_handler(_: &mut Request) -> IronResult<Response> {
+        panic!("Deliberate panic in a test handler");
+    }
+
+    #[test]
+    fn panics_become_json_errors() {
+        let mut chain = Chain::new(panicking_handler);
+        chain.link_around(PanicRecovery);
+        chain.link_around(RequestLogger::new(LogFormat::Text));
+
+        let error = match request::get("http://ruma.test/panic", Headers::new(), &chain) {
+            Ok(_) => panic!("The panicking handler should fail"),
+            Err(error) => error,
+        };
+
+        assert_eq!(error.response.status, Some(Status::InternalServerError));
+        assert_eq!(error.response.headers.get::<ContentType>(), Some(&ContentType::json()));
+
+        let body: Value = from_str(&response::extract_body_to_string(error.response)).unwrap();
+        assert_eq!(body.get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN");
+
+        // The chain keeps serving requests after a panic.
+        assert!(request::get("http://ruma.test/panic", Headers::new(), &chain).is_err());
+    }
+}
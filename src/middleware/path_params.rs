@@ -16,6 +16,7 @@ use ruma_identifiers::{
 use config::Config;
 use error::{ApiError, MapApiError};
 use url::percent_encoding::percent_decode;
+use util::user_id::parse_user_id;
 
 /// Extracts a `RoomId` from the URL path parameter `room_id`.
 pub struct RoomIdParam;
@@ -76,6 +77,9 @@ impl BeforeMiddleware for RoomIdOrAliasParam {
 }
 
 /// Extracts a `UserId` from the URL path parameter `user_id`.
+///
+/// The localparts of users on this server are lower-cased. Bare localparts are accepted if
+/// `localpart_user_id_params` is enabled.
 pub struct UserIdParam;
 
 impl Key for UserIdParam {
@@ -87,6 +91,8 @@ impl BeforeMiddleware for UserIdParam {
         let params = request.extensions.get::<Router>()
             .expect("Params object is missing").clone();
 
+        let config = Config::from_request(request)?;
+
         let user_id = match params.find("user_id") {
             Some(user_id) => {
                 let decoded_user_id = percent_decode(user_id.as_bytes())
@@ -95,9 +101,12 @@ impl BeforeMiddleware for UserIdParam {
                         ApiError::invalid_param("user_id", err.description())
                     })?;
 
-                UserId::try_from(&decoded_user_id).map_api_err(|err| {
-                    ApiError::invalid_param("user_id", err.description())
-                })
+                parse_user_id(
+                    &decoded_user_id,
+                    &config.domain,
+                    config.localpart_user_id_params,
+                    "user_id",
+                )
             },
             None => Err(ApiError::missing_param("user_id"))
         }?;
@@ -154,6 +163,8 @@ impl BeforeMiddleware for FilterIdParam {
 }
 
 /// Extracts `RoomAliasId` from the URL path parameter `room_alias`.
+///
+/// The parameter is either a full room alias or the localpart of a room alias on this server.
 pub struct RoomAliasIdParam;
 
 impl Key for RoomAliasIdParam {
@@ -171,9 +182,19 @@ impl BeforeMiddleware for RoomAliasIdParam {
             Some(room_alias) => {
                 debug!("room_alias param: {}", room_alias);
 
-                RoomAliasId::try_from(
-                    &format!("#{}:{}", room_alias, config.domain)
-                ).map_api_err(|err| {
+                let decoded_room_alias = percent_decode(room_alias.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| {
+                        ApiError::invalid_param("room_alias", err.description())
+                    })?;
+
+                let room_alias = if decoded_room_alias.starts_with('#') {
+                    decoded_room_alias.into_owned()
+                } else {
+                    format!("#{}:{}", decoded_room_alias, config.domain)
+                };
+
+                RoomAliasId::try_from(&room_alias).map_api_err(|err| {
                     ApiError::invalid_param("room_alias", err.description())
                 })?
             }
@@ -0,0 +1,202 @@
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::time::Instant;
+
+use iron::{AroundMiddleware, Handler, IronResult, Request, Response};
+use iron::status::Status;
+use iron::typemap::Key;
+use mount::OriginalUrl;
+use serde_json::to_string;
+
+use logging::{LogFormat, REQUEST_LOG_TARGET};
+use models::user::User;
+
+/// The ID of the next request, shared by all `RequestLogger`s.
+static NEXT_REQUEST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The ID `RequestLogger` assigned to a request, to correlate other log messages with the line in
+/// the access log. IDs are unique until the server is restarted.
+pub struct RequestId;
+
+impl Key for RequestId {
+    type Value = usize;
+}
+
+/// Writes a line to the access log for every request.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLogger {
+    /// The format the log lines are written in.
+    format: LogFormat,
+}
+
+/// The `Handler` wrapped by `RequestLogger`.
+struct LoggedHandler {
+    /// The format the log lines are written in.
+    format: LogFormat,
+    /// The handler that serves the requests.
+    handler: Box<Handler>,
+}
+
+/// An entry in the access log.
+#[derive(Debug, Serialize)]
+struct RequestLogEntry {
+    /// The level of the log message, always *INFO*.
+    level: &'static str,
+    /// A short description of the log message.
+    msg: &'static str,
+    /// The ID of the request.
+    request_id: usize,
+    /// The HTTP method of the request.
+    method: String,
+    /// The path of the request, without the query string.
+    path: String,
+    /// The HTTP status code of the response.
+    status: u16,
+    /// The time it took to handle the request in milliseconds.
+    duration_ms: u64,
+    /// The user that made the request, if it was authenticated.
+    user_id: Option<String>,
+}
+
+impl RequestLogger {
+    /// Create a `RequestLogger` writing lines in the given format.
+    pub fn new(format: LogFormat) -> Self {
+        RequestLogger {
+            format: format,
+        }
+    }
+}
+
+impl RequestLogEntry {
+    /// The line written to the access log for this entry, in the given format.
+    fn line(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => to_string(self).expect("RequestLogEntry should always serialize"),
+            LogFormat::Text => {
+                format!(
+                    "#{} {} {} {} {}ms{}",
+                    self.request_id,
+                    self.method,
+                    self.path,
+                    self.status,
+                    self.duration_ms,
+                    self.user_id.as_ref().map(|user_id| format!(" {}", user_id)).unwrap_or_default()
+                )
+            }
+        }
+    }
+}
+
+impl AroundMiddleware for RequestLogger {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(LoggedHandler {
+            format: self.format,
+            handler: handler,
+        })
+    }
+}
+
+impl Handler for LoggedHandler {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        request.extensions.insert::<RequestId>(request_id);
+
+        let start = Instant::now();
+        let result = self.handler.handle(request);
+        let elapsed = start.elapsed();
+
+        let status = match result {
+            Ok(ref response) => response.status,
+            Err(ref error) => error.response.status,
+        };
+
+        // Mount strips its prefix from the URL, so prefer the URL as the client sent it.
+        let url = request.extensions.get::<OriginalUrl>().unwrap_or(&request.url);
+
+        let entry = RequestLogEntry {
+            level: "INFO",
+            msg: "request",
+            request_id: request_id,
+            method: request.method.to_string(),
+            path: format!("/{}", url.path().join("/")),
+            status: status.unwrap_or(Status::NotFound).to_u16(),
+            duration_ms: elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64,
+            user_id: request.extensions.get::<User>().map(|user| user.id.to_string()),
+        };
+
+        info!(target: REQUEST_LOG_TARGET, "{}", entry.line(self.format));
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::{Chain, IronResult, Request, Response};
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron_test::{request, response};
+    use serde_json::{Value, from_str};
+
+    use logging::LogFormat;
+    use super::{RequestId, RequestLogEntry, RequestLogger};
+
+    /// A handler that responds with the ID the `RequestLogger` assigned to the request.
+    fn request_id_handler(request: &mut Request) -> IronResult<Response> {
+        let request_id = request.extensions.get::<RequestId>().expect("RequestLogger sets the ID");
+
+        Ok(Response::with((Status::Ok, request_id.to_string())))
+    }
+
+    fn entry(user_id: Option<&str>) -> RequestLogEntry {
+        RequestLogEntry {
+            level: "INFO",
+            msg: "request",
+            request_id: 7,
+            method: "GET".to_string(),
+            path: "/_matrix/client/r0/sync".to_string(),
+            status: 200,
+            duration_ms: 12,
+            user_id: user_id.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn every_request_gets_its_own_id() {
+        let mut chain = Chain::new(request_id_handler);
+        chain.link_around(RequestLogger::new(LogFormat::Json));
+
+        let request_id = || {
+            let response = request::get("http://ruma.test/", Headers::new(), &chain).unwrap();
+
+            assert_eq!(response.status, Some(Status::Ok));
+
+            response::extract_body_to_string(response).parse::<usize>().unwrap()
+        };
+
+        assert!(request_id() != request_id());
+    }
+
+    #[test]
+    fn json_access_log_lines() {
+        let line = entry(Some("@carl:ruma.test")).line(LogFormat::Json);
+        let json: Value = from_str(&line).unwrap();
+
+        assert_eq!(json.get("level").unwrap().as_str().unwrap(), "INFO");
+        assert_eq!(json.get("request_id").unwrap().as_u64().unwrap(), 7);
+        assert_eq!(json.get("method").unwrap().as_str().unwrap(), "GET");
+        assert_eq!(json.get("path").unwrap().as_str().unwrap(), "/_matrix/client/r0/sync");
+        assert_eq!(json.get("status").unwrap().as_u64().unwrap(), 200);
+        assert_eq!(json.get("duration_ms").unwrap().as_u64().unwrap(), 12);
+        assert_eq!(json.get("user_id").unwrap().as_str().unwrap(), "@carl:ruma.test");
+        assert!(entry(None).line(LogFormat::Json).contains(r#""user_id":null"#));
+    }
+
+    #[test]
+    fn text_access_log_lines() {
+        assert_eq!(
+            entry(Some("@carl:ruma.test")).line(LogFormat::Text),
+            "#7 GET /_matrix/client/r0/sync 200 12ms @carl:ruma.test"
+        );
+        assert_eq!(entry(None).line(LogFormat::Text), "#7 GET /_matrix/client/r0/sync 200 12ms");
+    }
+}
@@ -8,8 +8,63 @@ use iron::headers::{
 use iron::method::Method;
 use unicase::UniCase;
 
+use config::Config;
+
 /// Adds a number of response headers to Ruma HTTP responses.
-pub struct ResponseHeaders;
+#[derive(Clone, Debug, Default)]
+pub struct ResponseHeaders {
+    /// The value of the Content-Security-Policy header, if it should be set.
+    content_security_policy: Option<String>,
+    /// Whether to set the X-Content-Type-Options and X-Frame-Options headers.
+    security_headers: bool,
+}
+
+impl ResponseHeaders {
+    /// Create a `ResponseHeaders` middleware adding the default headers only.
+    pub fn new() -> Self {
+        ResponseHeaders::default()
+    }
+
+    /// Create a `ResponseHeaders` middleware for the media API.
+    ///
+    /// Media can be uploaded by anyone, so the responses are always sandboxed by the
+    /// Content-Security-Policy header to prevent stored cross-site scripting.
+    pub fn for_media(config: &Config) -> Self {
+        ResponseHeaders {
+            content_security_policy: Some(sandboxed_policy(&config.media_content_security_policy)),
+            security_headers: config.media_security_headers,
+        }
+    }
+
+    /// Adds the configured security headers to HTTP responses.
+    fn add_security_headers(&self, response: &mut Response) {
+        if let Some(ref content_security_policy) = self.content_security_policy {
+            response.headers.set_raw(
+                "Content-Security-Policy",
+                vec![content_security_policy.clone().into_bytes()],
+            );
+        }
+
+        if self.security_headers {
+            response.headers.set_raw("X-Content-Type-Options", vec![b"nosniff".to_vec()]);
+            response.headers.set_raw("X-Frame-Options", vec![b"DENY".to_vec()]);
+        }
+    }
+}
+
+/// Add the `sandbox` directive to a Content-Security-Policy unless it already has one.
+fn sandboxed_policy(policy: &str) -> String {
+    let sandboxed = policy.split(';')
+        .any(|directive| directive.split_whitespace().next() == Some("sandbox"));
+
+    if sandboxed {
+        policy.to_string()
+    } else if policy.trim().is_empty() {
+        "sandbox".to_string()
+    } else {
+        format!("sandbox; {}", policy)
+    }
+}
 
 /// Adds a Server header to HTTP responses
 fn add_server_header(response: &mut Response) {
@@ -36,6 +91,7 @@ impl AfterMiddleware for ResponseHeaders {
         }
         add_server_header(&mut response);
         add_cors_headers(&mut response);
+        self.add_security_headers(&mut response);
 
         Ok(response)
     }
@@ -43,6 +99,7 @@ impl AfterMiddleware for ResponseHeaders {
     fn catch(&self, _: &mut Request, mut error: IronError) -> IronResult<Response> {
         add_server_header(&mut error.response);
         add_cors_headers(&mut error.response);
+        self.add_security_headers(&mut error.response);
 
         Err(error)
     }
@@ -60,6 +117,8 @@ mod tests {
     use test::{Response, Test};
     use unicase::UniCase;
 
+    use super::sandboxed_policy;
+
     fn check_for_modified_headers(response: &Response) {
         assert_eq!(
             response.headers.get::<Server>().unwrap(),
@@ -109,4 +168,36 @@ mod tests {
         // Check to see if the expected headers have been added to the response.
         check_for_modified_headers(&response);
     }
+
+    #[test]
+    fn media_response_headers() {
+        let test = Test::new();
+        let response = test.get("/_matrix/media/r0/download/ruma.test/abcdef");
+
+        check_for_modified_headers(&response);
+
+        let content_security_policy = response.headers.get_raw("Content-Security-Policy").unwrap();
+
+        assert!(String::from_utf8_lossy(&content_security_policy[0]).starts_with("sandbox"));
+        assert_eq!(
+            response.headers.get_raw("X-Content-Type-Options").unwrap(),
+            &[b"nosniff".to_vec()]
+        );
+        assert_eq!(response.headers.get_raw("X-Frame-Options").unwrap(), &[b"DENY".to_vec()]);
+    }
+
+    #[test]
+    fn client_responses_are_not_sandboxed() {
+        let test = Test::new();
+        let response = test.get("/_matrix/client/versions");
+
+        assert!(response.headers.get_raw("Content-Security-Policy").is_none());
+    }
+
+    #[test]
+    fn media_policy_is_always_sandboxed() {
+        assert_eq!(sandboxed_policy("default-src 'none'"), "sandbox; default-src 'none'");
+        assert_eq!(sandboxed_policy("default-src 'none'; sandbox"), "default-src 'none'; sandbox");
+        assert_eq!(sandboxed_policy(""), "sandbox");
+    }
 }
@@ -0,0 +1,146 @@
+use std::mem::replace;
+
+use iron::{AroundMiddleware, Handler, IronError, IronResult, Request, Response};
+use iron::Url as RequestUrl;
+use iron::headers::Allow;
+use iron::method::Method;
+use router::NoRoute;
+use url::Url;
+
+use error::ApiError;
+
+/// Prepares requests for a `Router` and answers those it has no route for.
+///
+/// Trailing slashes are removed from the path before it is matched, so `/sync/` and `/sync` reach
+/// the same endpoint. Requests to paths without an endpoint fail with `M_UNRECOGNIZED` and status
+/// 400, and requests to an endpoint with a method it does not support with `M_UNRECOGNIZED` and
+/// status 405.
+///
+/// It should be linked around the `Router` before any other around middleware.
+#[derive(Clone, Copy, Debug)]
+pub struct Routing;
+
+/// The `Handler` wrapped by `Routing`.
+struct RoutedHandler {
+    /// The router that dispatches the requests.
+    handler: Box<Handler>,
+}
+
+impl AroundMiddleware for Routing {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(RoutedHandler {
+            handler: handler,
+        })
+    }
+}
+
+impl Handler for RoutedHandler {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        strip_trailing_slashes(request);
+
+        match self.handler.handle(request) {
+            Err(ref error) if error.error.is::<NoRoute>() => {}
+            result => return result,
+        }
+
+        let allowed_methods = self.allowed_methods(request);
+
+        if allowed_methods.is_empty() {
+            return Err(IronError::from(ApiError::unrecognized(None)));
+        }
+
+        let mut error = IronError::from(ApiError::unrecognized_method(None));
+
+        error.response.headers.set(Allow(allowed_methods));
+
+        Err(error)
+    }
+}
+
+impl RoutedHandler {
+    /// The methods the router has a route for at the path of the request.
+    ///
+    /// The router answers OPTIONS requests without a route of their own with these methods.
+    fn allowed_methods(&self, request: &mut Request) -> Vec<Method> {
+        let method = replace(&mut request.method, Method::Options);
+
+        let allowed_methods = self.handler.handle(request).ok().and_then(|response| {
+            response.headers.get::<Allow>().map(|allow| allow.0.clone())
+        });
+
+        request.method = method;
+
+        allowed_methods.unwrap_or_default()
+    }
+}
+
+/// Remove trailing slashes from the path of a request, except for the root path.
+fn strip_trailing_slashes(request: &mut Request) {
+    let mut url: Url = request.url.clone().into();
+    let path = url.path().trim_right_matches('/').to_string();
+
+    if path.is_empty() || path.len() == url.path().len() {
+        return;
+    }
+
+    url.set_path(&path);
+
+    if let Ok(url) = RequestUrl::from_generic_url(url) {
+        request.url = url;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{AccessControlAllowOrigin, Allow};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use test::Test;
+
+    #[test]
+    fn trailing_slashes_are_ignored() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let versions = test.get("/_matrix/client/versions");
+        let versions_with_slash = test.get("/_matrix/client/versions/");
+
+        assert_eq!(versions_with_slash.status, Status::Ok);
+        assert_eq!(versions_with_slash.body, versions.body);
+
+        let pushers_path = format!("/_matrix/client/r0/pushers/?access_token={}", user.token);
+
+        assert_eq!(test.get(&pushers_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn unknown_endpoints_are_unrecognized() {
+        let test = Test::new();
+
+        for path in &["/_matrix/client/r0/unknown", "/_matrix/federation/v1/version"] {
+            let response = test.get(path);
+
+            assert_eq!(response.status, Status::BadRequest);
+            assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+            assert_eq!(
+                response.headers.get::<AccessControlAllowOrigin>().unwrap(),
+                &AccessControlAllowOrigin::Any
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_method_is_unrecognized() {
+        let test = Test::new();
+        let response = test.delete("/_matrix/client/r0/sync");
+
+        assert_eq!(response.status, Status::MethodNotAllowed);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNRECOGNIZED");
+        assert!(response.headers.get::<Allow>().unwrap().0.contains(&Method::Get));
+        assert_eq!(
+            response.headers.get::<AccessControlAllowOrigin>().unwrap(),
+            &AccessControlAllowOrigin::Any
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Checks that the database schema matches the migrations embedded in the binary.
+//!
+//! The migrations in the `migrations` directory are embedded into the binary when it is built.
+//! Before the client APIs are mounted, the migrations recorded in the database are compared with
+//! them. Pending migrations are run if `auto_migrate` is enabled, and otherwise the server refuses
+//! to start with a list of what is missing. A database with migrations this binary does not know,
+//! e.g. after a downgrade, is always refused.
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use diesel::{Connection, LoadDsl, SelectDsl};
+use diesel::migrations::setup_database;
+use diesel::pg::PgConnection;
+
+use embedded_migrations::run as run_pending_migrations;
+use error::CliError;
+use schema::__diesel_schema_migrations;
+
+/// The versions of the embedded migrations, separated by commas, as found by the build script.
+const EMBEDDED_MIGRATIONS: &'static str = env!("RUMA_MIGRATIONS");
+
+/// How the migrations applied to a database differ from the embedded ones.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SchemaStatus {
+    /// The embedded migrations that have not been applied to the database, oldest first.
+    pub missing: Vec<String>,
+    /// The migrations applied to the database that are not embedded in this binary.
+    pub unknown: Vec<String>,
+}
+
+impl SchemaStatus {
+    /// Compare the migrations applied to the database with the embedded ones.
+    ///
+    /// Creates the table Diesel records applied migrations in if it does not exist yet.
+    pub fn check(connection: &PgConnection) -> Result<SchemaStatus, CliError> {
+        setup_database(connection).map_err(CliError::from)?;
+
+        let mut applied: Vec<String> = __diesel_schema_migrations::table
+            .select(__diesel_schema_migrations::version)
+            .load(connection)
+            .map_err(CliError::from)?;
+
+        applied.sort();
+
+        let embedded = embedded_versions();
+
+        let missing = embedded.iter()
+            .filter(|version| !applied.iter().any(|applied| applied == *version))
+            .map(|version| version.to_string())
+            .collect();
+
+        let unknown = applied.into_iter()
+            .filter(|version| !embedded.iter().any(|embedded| *embedded == version.as_str()))
+            .collect();
+
+        Ok(SchemaStatus {
+            missing: missing,
+            unknown: unknown,
+        })
+    }
+
+    /// Whether the database has exactly the embedded migrations.
+    pub fn is_current(&self) -> bool {
+        self.missing.is_empty() && self.unknown.is_empty()
+    }
+}
+
+impl Display for SchemaStatus {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        if self.is_current() {
+            return write!(f, "The database schema is up to date.");
+        }
+
+        if !self.missing.is_empty() {
+            write!(f, "Missing migrations: {}.", self.missing.join(", "))?;
+        }
+
+        if !self.unknown.is_empty() {
+            if !self.missing.is_empty() {
+                write!(f, " ")?;
+            }
+
+            write!(f, "Unknown migrations: {}.", self.unknown.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Connect to the PostgreSQL database at the given URL, e.g. to run the migrations outside of
+/// the server.
+pub fn connect(postgres_url: &str) -> Result<PgConnection, CliError> {
+    PgConnection::establish(postgres_url).map_err(CliError::from)
+}
+
+/// Run the pending migrations. Returns the number of migrations that were run.
+///
+/// Fails without changing the database if it has migrations this binary does not know.
+pub fn migrate(connection: &PgConnection) -> Result<usize, CliError> {
+    let status = SchemaStatus::check(connection)?;
+
+    if !status.unknown.is_empty() {
+        return Err(unknown_migrations_error(&status));
+    }
+
+    if !status.missing.is_empty() {
+        info!("Running the database migrations {}.", status.missing.join(", "));
+
+        run_pending_migrations(connection).map_err(CliError::from)?;
+    }
+
+    Ok(status.missing.len())
+}
+
+/// Make sure the database schema matches the embedded migrations before the server starts.
+///
+/// Pending migrations are run if `auto_migrate` is set. Otherwise, and if the database has
+/// migrations this binary does not know, this fails with a message listing them.
+pub fn prepare(connection: &PgConnection, auto_migrate: bool) -> Result<(), CliError> {
+    let status = SchemaStatus::check(connection)?;
+
+    if !status.unknown.is_empty() {
+        return Err(unknown_migrations_error(&status));
+    }
+
+    if status.missing.is_empty() {
+        return Ok(());
+    }
+
+    if !auto_migrate {
+        return Err(CliError::new(format!(
+            "The database schema is out of date. {} Run `ruma migrate` or set auto_migrate to \
+            true in the configuration.",
+            status
+        )));
+    }
+
+    migrate(connection).map(|_| ())
+}
+
+/// The error about a database that is newer than this binary.
+fn unknown_migrations_error(status: &SchemaStatus) -> CliError {
+    CliError::new(format!(
+        "The database was migrated by a newer version of Ruma. {} Upgrade Ruma to start it with \
+        this database.",
+        status
+    ))
+}
+
+/// The versions of the embedded migrations, oldest first.
+fn embedded_versions() -> Vec<&'static str> {
+    let mut versions: Vec<&'static str> = EMBEDDED_MIGRATIONS.split(',')
+        .filter(|version| !version.is_empty())
+        .collect();
+
+    versions.sort();
+
+    versions
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron_test::request;
+
+    use server::Server;
+    use test::Test;
+    use super::{SchemaStatus, connect, embedded_versions, migrate, prepare};
+
+    #[test]
+    fn fresh_database_is_refused_without_auto_migrate() {
+        let postgres_url = Test::fresh_database("ruma_test_no_auto_migrate");
+        let connection = connect(&postgres_url).unwrap();
+
+        let status = SchemaStatus::check(&connection).unwrap();
+
+        assert_eq!(status.missing, embedded_versions());
+        assert!(status.unknown.is_empty());
+        assert!(prepare(&connection, false).is_err());
+
+        assert_eq!(migrate(&connection).unwrap(), embedded_versions().len());
+        assert!(SchemaStatus::check(&connection).unwrap().is_current());
+        assert!(prepare(&connection, false).is_ok());
+    }
+
+    #[test]
+    fn server_starts_on_fresh_database_with_auto_migrate() {
+        let mut config = Test::default_config();
+
+        config.auto_migrate = true;
+        config.postgres_url = Test::fresh_database("ruma_test_auto_migrate");
+
+        let server = Server::new(&config).mount_client().expect("The server should start");
+        let mount = server.into_mount();
+
+        let response = request::get(
+            "http://ruma.test/_matrix/client/versions",
+            Headers::new(),
+            &mount,
+        ).unwrap();
+
+        assert_eq!(response.status, Some(Status::Ok));
+
+        let connection = connect(&config.postgres_url).unwrap();
+
+        assert!(SchemaStatus::check(&connection).unwrap().is_current());
+    }
+}
@@ -1,20 +1,46 @@
 //! User access tokens.
+//!
+//! Only the SHA-256 hashes of access tokens are stored, so the tokens cannot be taken from a copy
+//! of the database. The plaintext value is returned once, when the token is created.
+
+use std::net::IpAddr;
 
 use base64::encode;
 use chrono::{Duration, UTC};
-use diesel::{ExpressionMethods, FilterDsl, LoadDsl, SaveChangesDsl, insert};
+use diesel::{
+    insert,
+    update,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+    SaveChangesDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::{all, any, sql};
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
+use diesel::types::Bool;
 use iron::typemap::Key;
 use macaroons::caveat::Caveat;
 use macaroons::token::Token;
 use macaroons::v1::V1Token;
 use ruma_identifiers::UserId;
 
+use config::Config;
+use crypto::{generate_access_token, sha256_hex};
 use error::ApiError;
+use models::presence_status::get_now;
 use schema::access_tokens;
 
+/// The minimum number of milliseconds between two updates of the time an access token was last
+/// used.
+const USE_UPDATE_INTERVAL: i64 = 60 * 1000;
+
 /// A User access token.
 #[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
 #[table_name = "access_tokens"]
@@ -23,14 +49,28 @@ pub struct AccessToken {
     pub id: i64,
     /// The ID of the user who owns the access token.
     pub user_id: UserId,
-    /// The value of the access token. This is a Base64-encoded macaroon.
-    pub value: String,
+    /// The SHA-256 hash of the access token, encoded as a hex string. The access token itself is
+    /// a Base64-encoded macaroon or random bytes encoded as hex, depending on the configuration
+    /// at the time it was issued.
+    pub token_hash: String,
+    /// The ID of the device the access token was issued to, if any.
+    pub device_id: Option<String>,
     /// Whether or not the access token has been revoked.
     pub revoked: bool,
     /// The time the access token was created.
     pub created_at: PgTimestamp,
     /// The time the access token was last modified.
     pub updated_at: PgTimestamp,
+    /// The time the access token was last used, updated at most once every minute unless the
+    /// address it is used from changes.
+    pub last_used_at: PgTimestamp,
+    /// The IP address of the client that last used the access token, if it was used yet.
+    pub last_used_ip: Option<String>,
+    /// Whether the access token was revoked because it was idle for too long, which clients are
+    /// told so they can log in again on the same device.
+    pub soft_logout: bool,
+    /// The time the device of the access token was last warned that the token will expire.
+    pub expiry_warned_at: Option<PgTimestamp>,
 }
 
 /// A new access token, not yet saved.
@@ -39,26 +79,50 @@ pub struct AccessToken {
 pub struct NewAccessToken {
     /// The ID of the user who owns the access token.
     pub user_id: UserId,
-    /// The value of the access token. This is a Base64-encoded macaroon.
-    pub value: String,
+    /// The SHA-256 hash of the access token, encoded as a hex string.
+    pub token_hash: String,
+    /// The ID of the device the access token is issued to, if any.
+    pub device_id: Option<String>,
+    /// The time the access token was issued, which counts as its first use.
+    pub last_used_at: PgTimestamp,
 }
 
 impl AccessToken {
-    /// Create a new `AccessToken` for the given user.
+    /// Create a new `AccessToken` for the given user and, optionally, one of the user's devices.
+    ///
+    /// The token has `access_token_length` random bytes, or is a macaroon if that is not set, and
+    /// starts with the `access_token_prefix`. Tokens are only looked up by their hash, so those
+    /// issued in another format stay valid.
+    ///
+    /// Returns the plaintext value of the access token, which cannot be retrieved again.
     pub fn create(
         connection: &PgConnection,
         user_id: &UserId,
-        macaroon_secret_key: &[u8],
-    ) -> Result<Self, ApiError> {
+        device_id: Option<&str>,
+        config: &Config,
+    ) -> Result<String, ApiError> {
+        let token = match config.access_token_length {
+            Some(length) => generate_access_token(length)?,
+            None => create_macaroon(&config.macaroon_secret_key, user_id)?,
+        };
+        let token = match config.access_token_prefix {
+            Some(ref prefix) => format!("{}{}", prefix, token),
+            None => token,
+        };
+
         let new_access_token = NewAccessToken {
             user_id: user_id.clone(),
-            value: create_macaroon(macaroon_secret_key, user_id)?,
+            token_hash: sha256_hex(token.as_bytes()),
+            device_id: device_id.map(str::to_string),
+            last_used_at: PgTimestamp(get_now()),
         };
 
         insert(&new_access_token)
             .into(access_tokens::table)
-            .get_result(connection)
-            .map_err(ApiError::from)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(token)
     }
 
     /// Creates an `AccessToken` from an access token string value.
@@ -67,7 +131,7 @@ impl AccessToken {
     pub fn find_valid_by_token(connection: &PgConnection, token: &str)
     -> Result<Option<AccessToken>, ApiError> {
         let token = access_tokens::table
-            .filter(access_tokens::value.eq(token))
+            .filter(access_tokens::token_hash.eq(sha256_hex(token.as_bytes())))
             .filter(access_tokens::revoked.eq(false))
             .first(connection)
             .map(AccessToken::from);
@@ -79,6 +143,125 @@ impl AccessToken {
         }
     }
 
+    /// Whether the access token was revoked by a soft logout, i.e. because it was idle for too
+    /// long rather than because the user logged out.
+    pub fn is_soft_logged_out(connection: &PgConnection, token: &str) -> Result<bool, ApiError> {
+        let soft_logout = access_tokens::table
+            .select(access_tokens::soft_logout)
+            .filter(access_tokens::token_hash.eq(sha256_hex(token.as_bytes())))
+            .filter(access_tokens::revoked.eq(true))
+            .first::<bool>(connection);
+
+        match soft_logout {
+            Ok(soft_logout) => Ok(soft_logout),
+            Err(DieselError::NotFound) => Ok(false),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return at most `limit` valid access tokens that were last used before `idle_since` and
+    /// whose devices were warned about their expiry before `warned_since`, and not used since,
+    /// except for the tokens of `exempt_user_ids`, oldest first.
+    pub fn find_idle(
+        connection: &PgConnection,
+        idle_since: i64,
+        warned_since: i64,
+        exempt_user_ids: &[UserId],
+        limit: i64,
+    ) -> Result<Vec<AccessToken>, ApiError> {
+        access_tokens::table
+            .filter(access_tokens::revoked.eq(false))
+            .filter(access_tokens::last_used_at.lt(PgTimestamp(idle_since)))
+            .filter(access_tokens::expiry_warned_at.lt(Some(PgTimestamp(warned_since))))
+            .filter(sql::<Bool>("expiry_warned_at >= last_used_at"))
+            .filter(access_tokens::user_id.ne(all(exempt_user_ids)))
+            .order(access_tokens::id.asc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Like `find_idle`, but only returns access tokens of devices that were not warned about
+    /// the expiry of the token since it was last used.
+    pub fn find_idle_unwarned(
+        connection: &PgConnection,
+        idle_since: i64,
+        exempt_user_ids: &[UserId],
+        limit: i64,
+    ) -> Result<Vec<AccessToken>, ApiError> {
+        access_tokens::table
+            .filter(access_tokens::revoked.eq(false))
+            .filter(access_tokens::device_id.is_not_null())
+            .filter(access_tokens::last_used_at.lt(PgTimestamp(idle_since)))
+            .filter(access_tokens::user_id.ne(all(exempt_user_ids)))
+            .filter(sql::<Bool>(
+                "(expiry_warned_at IS NULL OR expiry_warned_at < last_used_at)"
+            ))
+            .order(access_tokens::id.asc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Record that the devices of the access tokens with the given IDs were warned about the
+    /// expiry of the tokens just now.
+    pub fn record_expiry_warnings(connection: &PgConnection, ids: &[i64]) -> Result<(), ApiError> {
+        update(access_tokens::table.filter(access_tokens::id.eq(any(ids))))
+            .set(access_tokens::expiry_warned_at.eq(Some(PgTimestamp(get_now()))))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Revoke the access tokens with the given IDs by a soft logout, keeping their devices.
+    /// Returns the number of access tokens revoked.
+    pub fn soft_logout_all(connection: &PgConnection, ids: &[i64]) -> Result<usize, ApiError> {
+        update(access_tokens::table.filter(access_tokens::id.eq(any(ids))))
+            .set((access_tokens::revoked.eq(true), access_tokens::soft_logout.eq(true)))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Record that the access token was used for a request from the client at `ip`.
+    pub fn record_use(&self, connection: &PgConnection, ip: IpAddr) -> Result<(), ApiError> {
+        let now = get_now();
+        let ip = ip.to_string();
+
+        let is_same_ip = self.last_used_ip.as_ref() == Some(&ip);
+
+        if is_same_ip && now - self.last_used_at.0 < USE_UPDATE_INTERVAL {
+            return Ok(());
+        }
+
+        update(access_tokens::table.find(self.id))
+            .set((
+                access_tokens::last_used_at.eq(PgTimestamp(now)),
+                access_tokens::last_used_ip.eq(Some(ip)),
+            ))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return the time any valid access token of the user was last used, if the user has one.
+    pub fn last_used_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<i64>, ApiError> {
+        let last_used_at = access_tokens::table
+            .select(access_tokens::last_used_at)
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::revoked.eq(false))
+            .order(access_tokens::last_used_at.desc())
+            .first::<PgTimestamp>(connection);
+
+        match last_used_at {
+            Ok(last_used_at) => Ok(Some(last_used_at.0)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
     /// Revoke the access token so it cannot be used again.
     pub fn revoke(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
         self.revoked = true;
@@ -88,6 +271,18 @@ impl AccessToken {
             Err(error) => Err(ApiError::from(error)),
         }
     }
+
+    /// Revoke all valid access tokens of the user. Returns the number of access tokens revoked.
+    pub fn revoke_all(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let valid_tokens = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::revoked.eq(false));
+
+        update(valid_tokens)
+            .set(access_tokens::revoked.eq(true))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
 }
 
 impl Key for AccessToken {
@@ -115,3 +310,96 @@ fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<Strin
 
     Ok(encode(&serialized))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, LoadDsl, SelectDsl, delete};
+    use diesel::connection::SimpleConnection;
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use crypto::sha256_hex;
+    use schema::{__diesel_schema_migrations, access_tokens};
+    use test::Test;
+    use super::AccessToken;
+
+    /// The stored hashes of the user's access tokens.
+    fn stored_hashes(test: &Test, user_id: &str) -> Vec<String> {
+        test.with_connection(|connection| {
+            access_tokens::table
+                .select(access_tokens::token_hash)
+                .filter(access_tokens::user_id.eq(user_id))
+                .get_results(connection)
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn access_tokens_are_stored_hashed() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", carl.token));
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(stored_hashes(&test, &carl.id), vec![sha256_hex(carl.token.as_bytes())]);
+    }
+
+    #[test]
+    fn access_tokens_have_the_configured_prefix_and_length() {
+        let test = Test::with_config(|config| {
+            config.access_token_length = Some(32);
+            config.access_token_prefix = Some("syt_".to_string());
+        });
+        let carl = test.create_user();
+
+        assert!(carl.token.starts_with("syt_"));
+        assert_eq!(carl.token.len(), 4 + 64);
+        assert!(carl.token[4..].chars().all(|c| c.is_digit(16)));
+
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", carl.token));
+        assert_eq!(response.status, Status::Ok);
+
+        // Tokens issued in the default format before the configuration changed stay valid.
+        let macaroon = test.with_connection(|connection| {
+            let user_id = UserId::try_from(carl.id.as_str()).unwrap();
+
+            AccessToken::create(connection, &user_id, None, &Test::default_config()).unwrap()
+        });
+
+        assert!(!macaroon.starts_with("syt_"));
+
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", macaroon));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn plaintext_access_tokens_are_hashed_by_the_migration() {
+        let postgres_url = Test::fresh_database("ruma_test_token_migration");
+        let test = Test::with_committing_database(&postgres_url, |_| {});
+        let carl = test.create_user();
+
+        // Undo the migration and store the token the way it was stored before tokens were hashed.
+        test.with_connection(|connection| {
+            connection.batch_execute(
+                include_str!("../../migrations/002_hash_access_tokens/down.sql")
+            ).unwrap();
+            connection.execute(
+                &format!("UPDATE access_tokens SET value = '{}'", carl.token)
+            ).unwrap();
+            delete(
+                __diesel_schema_migrations::table
+                    .filter(__diesel_schema_migrations::version.eq("002"))
+            ).execute(connection).unwrap();
+        });
+
+        // A server started on the database runs the migration again.
+        let migrated = Test::with_committing_database(&postgres_url, |_| {});
+        let pushers_path = format!("/_matrix/client/r0/pushers?access_token={}", carl.token);
+
+        assert_eq!(stored_hashes(&migrated, &carl.id), vec![sha256_hex(carl.token.as_bytes())]);
+        assert_eq!(migrated.get(&pushers_path).status, Status::Ok);
+    }
+}
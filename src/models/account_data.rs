@@ -1,10 +1,13 @@
 //! Account information stored for a user.
 
+use std::collections::HashMap;
+
 use diesel::{
     ExecuteDsl,
     ExpressionMethods,
     FilterDsl,
     LoadDsl,
+    OrderDsl,
     SaveChangesDsl,
     delete,
     insert,
@@ -13,6 +16,7 @@ use diesel::result::Error as DieselError;
 use diesel::pg::PgConnection;
 use iron::typemap::Key;
 use ruma_identifiers::{UserId, RoomId};
+use serde_json::{Value, from_str};
 
 use error::ApiError;
 use schema::{account_data, room_account_data};
@@ -31,6 +35,13 @@ pub struct AccountData {
     pub content: String,
 }
 
+/// The content of *m.ignored_user_list* account data.
+#[derive(Debug, Deserialize)]
+struct IgnoredUserList {
+    /// The ignored users, mapped to an empty object.
+    ignored_users: HashMap<UserId, Value>,
+}
+
 /// New account data, not yet saved.
 #[derive(Debug, Insertable)]
 #[table_name = "account_data"]
@@ -83,15 +94,33 @@ impl AccountData {
             .map_err(ApiError::from)
     }
 
-    /// Get all account data given a `UserId`.
+    /// Get all account data given a `UserId`, in the order it was first stored.
     pub fn get_by_uid(connection: &PgConnection, uid: &UserId)
     -> Result<Vec<AccountData>, ApiError> {
         account_data::table
             .filter(account_data::user_id.eq(uid))
+            .order(account_data::id.asc())
             .load::<AccountData>(connection)
             .map_err(ApiError::from)
     }
 
+    /// Return the users on the *m.ignored_user_list* of a user.
+    ///
+    /// Ignores malformed lists, since clients can store any content as account data.
+    pub fn find_ignored_user_ids(connection: &PgConnection, uid: &UserId)
+    -> Result<Vec<UserId>, ApiError> {
+        let data = match AccountData::find_by_uid_and_type(connection, uid, "m.ignored_user_list") {
+            Ok(data) => data,
+            Err(DieselError::NotFound) => return Ok(Vec::new()),
+            Err(err) => return Err(ApiError::from(err)),
+        };
+
+        match from_str::<IgnoredUserList>(&data.content) {
+            Ok(list) => Ok(list.ignored_users.into_iter().map(|(user_id, _)| user_id).collect()),
+            Err(_) => Ok(Vec::new()),
+        }
+    }
+
     /// Update an existing entry or create a new one.
     pub fn upsert(connection: &PgConnection, new_data: &NewAccountData)
     -> Result<AccountData, ApiError> {
@@ -166,6 +195,16 @@ impl RoomAccountData {
             .map(RoomAccountData::from)
     }
 
+    /// Return all account data a user has attached to a room.
+    pub fn find_by_uid_and_room(connection: &PgConnection, uid: &UserId, rid: &RoomId)
+    -> Result<Vec<RoomAccountData>, ApiError> {
+        room_account_data::table
+            .filter(room_account_data::user_id.eq(uid))
+            .filter(room_account_data::room_id.eq(rid))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Update an `RoomAccountData` entry with new content.
     pub fn update(&mut self, connection: &PgConnection, content: String)
     -> Result<RoomAccountData, ApiError> {
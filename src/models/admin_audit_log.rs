@@ -0,0 +1,60 @@
+//! A record of administrative actions on user data.
+//!
+//! Actions like exporting everything stored about a user are authenticated with the shared
+//! secret, so the record says what was done and to whom, but not by which administrator.
+
+use diesel::{ExpressionMethods, FilterDsl, LoadDsl, OrderDsl, insert};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+
+use error::ApiError;
+use schema::admin_audit_log;
+
+/// An entry of the audit log, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "admin_audit_log"]
+pub struct NewAuditLogEntry {
+    /// The action, e.g. *user_data_export*.
+    pub action: String,
+    /// What the action was applied to, e.g. a user ID.
+    pub target: String,
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Clone, Queryable)]
+pub struct AuditLogEntry {
+    /// The ID of the entry.
+    pub id: i64,
+    /// The action, e.g. *user_data_export*.
+    pub action: String,
+    /// What the action was applied to, e.g. a user ID.
+    pub target: String,
+    /// The time the action was taken.
+    pub created_at: PgTimestamp,
+}
+
+impl AuditLogEntry {
+    /// Record that `action` was applied to `target`.
+    pub fn record(connection: &PgConnection, action: &str, target: &str)
+    -> Result<AuditLogEntry, ApiError> {
+        let new_entry = NewAuditLogEntry {
+            action: action.to_string(),
+            target: target.to_string(),
+        };
+
+        insert(&new_entry)
+            .into(admin_audit_log::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the entries of the actions applied to `target`, oldest first.
+    pub fn find_by_target(connection: &PgConnection, target: &str)
+    -> Result<Vec<AuditLogEntry>, ApiError> {
+        admin_audit_log::table
+            .filter(admin_audit_log::target.eq(target))
+            .order(admin_audit_log::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,64 @@
+//! Devices users are logged in with.
+
+use diesel::{ExecuteDsl, FindDsl, LoadDsl, insert};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use schema::devices;
+
+/// A new device, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "devices"]
+pub struct NewDevice {
+    /// The device's ID, unique per user.
+    pub id: String,
+    /// The ID of the user owning the device.
+    pub user_id: UserId,
+    /// A human-readable name for the device, e.g. *Firefox on Linux*.
+    pub display_name: String,
+}
+
+/// A device a user is logged in with.
+#[derive(Clone, Debug, Queryable)]
+pub struct Device {
+    /// The device's ID, unique per user.
+    pub id: String,
+    /// The ID of the user owning the device.
+    pub user_id: UserId,
+    /// A human-readable name for the device, e.g. *Firefox on Linux*.
+    pub display_name: String,
+    /// The time the device was created.
+    pub created_at: PgTimestamp,
+}
+
+impl Device {
+    /// Create a device unless the user already has a device with the same ID.
+    ///
+    /// An existing device keeps its display name.
+    pub fn find_or_create(connection: &PgConnection, new_device: &NewDevice)
+    -> Result<Device, ApiError> {
+        insert(&new_device.on_conflict_do_nothing())
+            .into(devices::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        match Device::find(connection, &new_device.user_id, &new_device.id)? {
+            Some(device) => Ok(device),
+            None => Err(ApiError::unknown("The device could not be saved.".to_string())),
+        }
+    }
+
+    /// Look up a device of a user.
+    pub fn find(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<Option<Device>, ApiError> {
+        match devices::table.find((user_id, device_id)).first(connection) {
+            Ok(device) => Ok(Some(device)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+//! Expiry of devices that have not been used for a long time.
+//!
+//! Operators can have devices expire after `device_idle_expiry_days` without a request. Their
+//! clients are warned with a to-device message `device_expiry_warning_days` before, and the
+//! access tokens are then revoked by a soft logout. The devices themselves are kept, so clients
+//! logging in again with the same device ID keep their encryption state.
+//!
+//! Only warned tokens expire, at the earliest `device_expiry_warning_days` after the warning, so
+//! tokens that are already idle for longer when expiry is enabled still get the full warning.
+//! Access tokens without a device cannot be warned, so they never expire.
+
+use std::cmp;
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use serde_json::to_value;
+
+use config::Config;
+use error::ApiError;
+use models::access_token::AccessToken;
+use models::admin_audit_log::AuditLogEntry;
+use models::presence_status::get_now;
+use models::to_device_message::ToDeviceMessage;
+
+/// The maximum number of access tokens the expiry worker warns about or revokes in one go.
+pub const DEVICE_EXPIRY_BATCH_SIZE: i64 = 100;
+
+/// The type of the to-device message warning a device that it will expire.
+pub const SESSION_EXPIRY_WARNING_TYPE: &'static str = "m.ruma.session_expiry_warning";
+
+/// The action recorded in the audit log for every expired access token.
+const DEVICE_EXPIRY_ACTION: &'static str = "device_expiry";
+
+/// The number of milliseconds in a day.
+const DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// The content of a session expiry warning.
+#[derive(Debug, Serialize)]
+struct SessionExpiryWarning<'a> {
+    /// The device that will expire.
+    device_id: &'a str,
+    /// The number of milliseconds until the device expires unless it makes a request.
+    expires_in_ms: i64,
+}
+
+/// The work done in a run of the expiry worker.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceExpiry {
+    /// The number of devices warned that they will expire.
+    pub warned: usize,
+    /// The number of access tokens revoked.
+    pub expired: usize,
+}
+
+/// Revoke the access tokens that were idle for `device_idle_expiry_days` and warned long enough
+/// ago, and warn the devices of the ones that will expire soon, which is the work of the expiry
+/// worker.
+///
+/// Tokens are handled in batches of at most `batch_size`, each in its own transaction, and every
+/// revoked token is recorded in the audit log. Tokens of `device_expiry_exempt_users` never
+/// expire. Does nothing if `device_idle_expiry_days` is not set.
+pub fn expire_idle_devices(connection: &PgConnection, config: &Config, batch_size: i64)
+-> Result<DeviceExpiry, ApiError> {
+    let mut expiry = DeviceExpiry::default();
+
+    let idle_expiry = match config.device_idle_expiry_days {
+        Some(days) => days as i64 * DAY,
+        None => return Ok(expiry),
+    };
+    let warning = config.device_expiry_warning_days as i64 * DAY;
+    let exempt_user_ids = &config.device_expiry_exempt_users;
+    let now = get_now();
+
+    loop {
+        let expired = connection.transaction::<usize, ApiError, _>(|| {
+            let tokens = AccessToken::find_idle(
+                connection,
+                now - idle_expiry,
+                now - warning,
+                exempt_user_ids,
+                batch_size,
+            )?;
+            let ids: Vec<i64> = tokens.iter().map(|token| token.id).collect();
+
+            AccessToken::soft_logout_all(connection, &ids)?;
+
+            for token in &tokens {
+                AuditLogEntry::record(connection, DEVICE_EXPIRY_ACTION, &audit_target(token))?;
+            }
+
+            Ok(tokens.len())
+        }).map_err(ApiError::from)?;
+
+        expiry.expired += expired;
+
+        if (expired as i64) < batch_size {
+            break;
+        }
+    }
+
+    loop {
+        let warned = connection.transaction::<usize, ApiError, _>(|| {
+            let tokens = AccessToken::find_idle_unwarned(
+                connection,
+                now - idle_expiry + warning,
+                exempt_user_ids,
+                batch_size,
+            )?;
+            let ids: Vec<i64> = tokens.iter().map(|token| token.id).collect();
+
+            for token in &tokens {
+                if let Some(ref device_id) = token.device_id {
+                    // Tokens expire no earlier than a full warning period after the warning.
+                    let expires_at = cmp::max(token.last_used_at.0 + idle_expiry, now + warning);
+                    let content = to_value(&SessionExpiryWarning {
+                        device_id: device_id,
+                        expires_in_ms: expires_at - now,
+                    }).map_err(ApiError::from)?;
+
+                    // The warning is about the user's own session, so it is sent by the user.
+                    ToDeviceMessage::create(
+                        connection,
+                        &token.user_id,
+                        device_id,
+                        &token.user_id,
+                        SESSION_EXPIRY_WARNING_TYPE,
+                        &content,
+                    )?;
+                }
+            }
+
+            AccessToken::record_expiry_warnings(connection, &ids)?;
+
+            Ok(tokens.len())
+        }).map_err(ApiError::from)?;
+
+        expiry.warned += warned;
+
+        if (warned as i64) < batch_size {
+            break;
+        }
+    }
+
+    Ok(expiry)
+}
+
+/// The target of the audit log entry of an expired access token: the user, followed by the
+/// device if the token has one, e.g. *@carl:example.com/ABCDEF*.
+fn audit_target(token: &AccessToken) -> String {
+    match token.device_id {
+        Some(ref device_id) => format!("{}/{}", token.user_id, device_id),
+        None => token.user_id.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use models::admin_audit_log::AuditLogEntry;
+    use models::device::Device;
+    use models::presence_status::advance_clock;
+    use query::SyncOptions;
+    use test::Test;
+    use super::{DAY, DeviceExpiry};
+
+    /// Register a user and log in with a device. Returns the access token of the device.
+    fn log_in(test: &Test, username: &str, device_id: &str) -> String {
+        let body = format!(r#"{{"username": "{}", "password": "secret"}}"#, username);
+
+        assert_eq!(test.register_user(&body).status, Status::Ok);
+
+        let body = format!(
+            r#"{{
+                "type": "m.login.password",
+                "user": "{}",
+                "password": "secret",
+                "device_id": "{}"
+            }}"#,
+            username,
+            device_id
+        );
+        let response = test.post("/_matrix/client/r0/login", &body);
+
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().get("access_token").unwrap().as_str().unwrap().to_string()
+    }
+
+    fn pushers_status(test: &Test, access_token: &str) -> Status {
+        test.get(&format!("/_matrix/client/r0/pushers?access_token={}", access_token)).status
+    }
+
+    #[test]
+    fn idle_devices_are_warned_and_then_expired() {
+        let test = Test::with_config(|config| {
+            config.device_idle_expiry_days = Some(90);
+            config.device_expiry_warning_days = 7;
+            config.device_expiry_exempt_users = vec![
+                UserId::try_from("@bridge:ruma.test").unwrap(),
+            ];
+        });
+        let alice = log_in(&test, "alice", "PHONE");
+        let carl = log_in(&test, "carl", "LAPTOP");
+        let bridge = log_in(&test, "bridge", "BRIDGE");
+
+        advance_clock(84 * DAY);
+
+        assert_eq!(pushers_status(&test, &carl), Status::Ok);
+        assert_eq!(test.expire_idle_devices(), DeviceExpiry { warned: 1, expired: 0 });
+
+        // Devices are warned only once.
+        assert_eq!(test.expire_idle_devices().warned, 0);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice, options.clone());
+        let warnings = response.json().pointer("/to_device/events").unwrap().as_array().unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].get("type").unwrap().as_str().unwrap(),
+            "m.ruma.session_expiry_warning"
+        );
+        assert_eq!(warnings[0].pointer("/content/device_id").unwrap().as_str().unwrap(), "PHONE");
+        let expires_in_ms = warnings[0].pointer("/content/expires_in_ms")
+            .unwrap()
+            .as_i64()
+            .unwrap();
+        // The device would be idle for 90 days in 6 days, but it gets a full week of warning.
+        assert!(6 * DAY < expires_in_ms && expires_in_ms <= 7 * DAY);
+
+        // Messages are delivered again until the device acknowledges them with the next batch.
+        let response = test.sync(&alice, options.clone());
+        let warnings = response.json().pointer("/to_device/events").unwrap().as_array().unwrap();
+
+        assert_eq!(warnings.len(), 1);
+
+        let options = SyncOptions {
+            since: Some(Test::get_next_batch(&response)),
+            ..options
+        };
+        let response = test.sync(&alice, options);
+        let warnings = response.json().pointer("/to_device/events").unwrap().as_array().unwrap();
+
+        assert!(warnings.is_empty());
+
+        advance_clock(91 * DAY);
+
+        assert_eq!(pushers_status(&test, &carl), Status::Ok);
+        assert!(test.expire_idle_devices().expired > 0);
+
+        let response = test.get(&format!("/_matrix/client/r0/pushers?access_token={}", alice));
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(response.json().get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN_TOKEN");
+        assert_eq!(response.json().get("soft_logout").unwrap().as_bool().unwrap(), true);
+
+        assert_eq!(pushers_status(&test, &carl), Status::Ok);
+        assert_eq!(pushers_status(&test, &bridge), Status::Ok);
+
+        test.with_connection(|connection| {
+            let alice_id = UserId::try_from("@alice:ruma.test").unwrap();
+            let entries = AuditLogEntry::find_by_target(connection, "@alice:ruma.test/PHONE")
+                .unwrap();
+
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].action, "device_expiry");
+
+            // The device is kept for when the user logs in again.
+            assert!(Device::find(connection, &alice_id, "PHONE").unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn devices_are_warned_a_full_period_before_they_expire() {
+        let test = Test::with_config(|config| {
+            config.device_idle_expiry_days = Some(90);
+            config.device_expiry_warning_days = 7;
+        });
+        let alice = log_in(&test, "alice", "PHONE");
+
+        // The device is already idle for longer than the expiry when it is first looked at.
+        advance_clock(200 * DAY);
+
+        assert_eq!(test.expire_idle_devices(), DeviceExpiry { warned: 1, expired: 0 });
+        assert_eq!(test.expire_idle_devices(), DeviceExpiry { warned: 0, expired: 0 });
+
+        advance_clock(6 * DAY);
+
+        assert_eq!(test.expire_idle_devices().expired, 0);
+        assert_eq!(pushers_status(&test, &alice), Status::Ok);
+
+        // The request reset the idle time, so the warning no longer counts.
+        advance_clock(91 * DAY);
+
+        assert_eq!(test.expire_idle_devices(), DeviceExpiry { warned: 1, expired: 0 });
+
+        advance_clock(8 * DAY);
+
+        assert_eq!(test.expire_idle_devices(), DeviceExpiry { warned: 0, expired: 1 });
+        assert_eq!(pushers_status(&test, &alice), Status::Unauthorized);
+    }
+}
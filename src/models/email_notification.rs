@@ -0,0 +1,170 @@
+//! Notifications waiting to be sent in a digest email.
+//!
+//! Events matching the push rules of users with an email pusher are collected here, until the
+//! digest worker sends them to the users who are offline.
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    GroupByDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::all;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use error::ApiError;
+use models::event::Event;
+use models::presence_status::get_now;
+use schema::{email_digests, email_notifications};
+
+/// A notification for the next digest, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "email_notifications"]
+pub struct NewEmailNotification {
+    /// The user to notify.
+    pub user_id: UserId,
+    /// The room of the event.
+    pub room_id: RoomId,
+    /// The event the user is notified of.
+    pub event_id: EventId,
+    /// The time of the notification.
+    pub created_at: PgTimestamp,
+}
+
+/// A notification for the next digest.
+#[derive(Debug, Clone, Queryable)]
+pub struct EmailNotification {
+    /// The ID of the notification.
+    pub id: i64,
+    /// The user to notify.
+    pub user_id: UserId,
+    /// The room of the event.
+    pub room_id: RoomId,
+    /// The event the user is notified of.
+    pub event_id: EventId,
+    /// The time of the notification.
+    pub created_at: PgTimestamp,
+}
+
+/// The last digest email sent to a user.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "email_digests"]
+pub struct EmailDigest {
+    /// The user the digest was sent to.
+    pub user_id: UserId,
+    /// The time the digest was sent.
+    pub sent_at: PgTimestamp,
+}
+
+impl EmailNotification {
+    /// Add a notification about `event` to the next digest of the user.
+    pub fn create(connection: &PgConnection, user_id: &UserId, event: &Event)
+    -> Result<(), ApiError> {
+        let new_notification = NewEmailNotification {
+            user_id: user_id.clone(),
+            room_id: event.room_id.clone(),
+            event_id: event.id.clone(),
+            created_at: PgTimestamp(get_now()),
+        };
+
+        insert(&new_notification)
+            .into(email_notifications::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return the users with pending notifications, except for `excluded_user_ids`.
+    pub fn find_pending_user_ids(connection: &PgConnection, excluded_user_ids: &[UserId])
+    -> Result<Vec<UserId>, ApiError> {
+        email_notifications::table
+            .select(email_notifications::user_id)
+            .filter(email_notifications::user_id.ne(all(excluded_user_ids)))
+            .group_by(email_notifications::user_id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the pending notifications of a user, oldest first.
+    pub fn find_by_uid(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<EmailNotification>, ApiError> {
+        email_notifications::table
+            .filter(email_notifications::user_id.eq(user_id))
+            .order(email_notifications::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Remove the notifications of a user up to the one with the ID `last_id`, once they were
+    /// sent.
+    pub fn delete_until(connection: &PgConnection, user_id: &UserId, last_id: i64)
+    -> Result<(), ApiError> {
+        let notifications = email_notifications::table
+            .filter(email_notifications::user_id.eq(user_id))
+            .filter(email_notifications::id.le(last_id));
+
+        delete(notifications).execute(connection).map_err(ApiError::from)?;
+
+        Ok(())
+    }
+}
+
+impl EmailDigest {
+    /// Return the users who were sent a digest after `since`.
+    pub fn find_user_ids_sent_since(connection: &PgConnection, since: i64)
+    -> Result<Vec<UserId>, ApiError> {
+        email_digests::table
+            .select(email_digests::user_id)
+            .filter(email_digests::sent_at.gt(PgTimestamp(since)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the time the last digest was sent to the user, if any.
+    pub fn last_sent_at(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<i64>, ApiError> {
+        match email_digests::table.find(user_id).first::<EmailDigest>(connection) {
+            Ok(digest) => Ok(Some(digest.sent_at.0)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Record that a digest was sent to the user just now.
+    pub fn record(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        let now = PgTimestamp(get_now());
+
+        match EmailDigest::last_sent_at(connection, user_id)? {
+            Some(_) => {
+                update(email_digests::table.find(user_id))
+                    .set(email_digests::sent_at.eq(now))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+            }
+            None => {
+                let digest = EmailDigest {
+                    user_id: user_id.clone(),
+                    sent_at: now,
+                };
+
+                insert(&digest)
+                    .into(email_digests::table)
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+            }
+        }
+
+        Ok(())
+    }
+}
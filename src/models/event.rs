@@ -1,5 +1,6 @@
 //! Matrix events.
 
+use std::cmp;
 use std::convert::{TryInto, TryFrom};
 
 use diesel::{
@@ -7,6 +8,7 @@ use diesel::{
     FilterDsl,
     FindDsl,
     GroupByDsl,
+    LimitDsl,
     LoadDsl,
     OrderDsl,
     SelectDsl,
@@ -58,10 +60,18 @@ use ruma_events::stripped::{
     StrippedState,
 };
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{Value, from_str, from_value, to_string};
+use serde::Deserialize;
+use serde_json::{Map, Value, from_str, from_value, to_string};
 
+use crypto::sha256_hex;
 use error::ApiError;
-use schema::events;
+use models::event_lookup::EventLocation;
+use models::presence_status::get_now;
+use schema::{event_lookup, events};
+use util::canonical_json::{to_canonical, to_canonical_allowing_floats};
+
+/// The milliseconds between the Unix epoch and the PostgreSQL epoch, 2000-01-01.
+const POSTGRES_EPOCH_MILLISECONDS: i64 = 946_684_800_000;
 
 const STATE_EVENTS: [EventType; 12] = [
     EventType::RoomAliases,
@@ -90,15 +100,43 @@ pub struct NewEvent {
     pub id: EventId,
     /// JSON of the event's content.
     pub content: String,
+    /// The hash of the event's content, see `content_hash`.
+    pub content_hash: String,
+    /// The room the event was sent in.
+    pub room_id: RoomId,
+    /// An event subtype that determines whether or not the event will overwrite a previous one.
+    pub state_key: Option<String>,
+    /// The user who sent the event.
+    pub user_id: UserId,
+}
+
+/// A new event that keeps the time it was originally created, e.g. by a system it is imported
+/// from. Not yet saved.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "events"]
+pub struct NewImportedEvent {
+    /// The type of the event, e.g. *m.room.create*.
+    pub event_type: String,
+    /// Extra key-value pairs to be mixed into the top-level JSON representation of the event.
+    pub extra_content: Option<String>,
+    /// The unique event ID.
+    pub id: EventId,
+    /// JSON of the event's content.
+    pub content: String,
+    /// The hash of the event's content, see `content_hash`.
+    pub content_hash: String,
     /// The room the event was sent in.
     pub room_id: RoomId,
     /// An event subtype that determines whether or not the event will overwrite a previous one.
     pub state_key: Option<String>,
     /// The user who sent the event.
     pub user_id: UserId,
+    /// The time the event was originally created.
+    pub created_at: PgTimestamp,
+    /// Whether the content was redacted before the event was imported.
+    pub redacted: bool,
 }
 
-/// A Matrix event.
 #[derive(Clone, Debug, Queryable)]
 pub struct Event {
     /// The unique event ID.
@@ -119,9 +157,161 @@ pub struct Event {
     pub extra_content: Option<String>,
     /// The time the event was created.
     pub created_at: PgTimestamp,
+    /// The ID of the state event with the same type and state key this event replaced, if any.
+    /// Set by the database when the event is saved.
+    pub replaces_state: Option<EventId>,
+    /// JSON of the content of the state event this event replaced, if any.
+    pub prev_content: Option<String>,
+    /// Whether the content was redacted, e.g. because the sender's data was erased.
+    pub redacted: bool,
+    /// The hash of the event's content when it was saved or redacted, see `content_hash`.
+    pub content_hash: String,
+}
+
+/// The SHA-256 hash of the canonical JSON of an event's content, encoded as a hex string.
+///
+/// Fails with `M_BAD_JSON` if the content has numbers canonical JSON does not allow.
+pub fn content_hash(content: &str) -> Result<String, ApiError> {
+    let content: Value = from_str(content).map_err(ApiError::from)?;
+
+    Ok(sha256_hex(&to_canonical(&content)?))
+}
+
+/// The content hash of an event imported from elsewhere, whose content may have floats.
+///
+/// For content without floats this is the same as `content_hash`, so it also checks the hashes of
+/// events that were not imported.
+pub fn imported_content_hash(content: &str) -> Result<String, ApiError> {
+    let content: Value = from_str(content).map_err(ApiError::from)?;
+
+    Ok(sha256_hex(&to_canonical_allowing_floats(&content)?))
+}
+
+impl NewEvent {
+    /// Replace the content of the event, updating its hash.
+    pub fn set_content(&mut self, content: &Value) -> Result<(), ApiError> {
+        self.content = to_string(content).map_err(ApiError::from)?;
+        self.content_hash = content_hash(&self.content)?;
+
+        Ok(())
+    }
+}
+
+/// Whether `origin_server_ts`, in milliseconds since the Unix epoch, can be stored as the time an
+/// imported event was created: it must not be before the Unix epoch and must fit a `TIMESTAMP`.
+pub fn is_storable_timestamp(origin_server_ts: i64) -> bool {
+    imported_timestamp(origin_server_ts).is_some()
+}
+
+/// Convert `origin_server_ts`, in milliseconds since the Unix epoch, to a `TIMESTAMP`, which is
+/// stored in microseconds since 2000-01-01.
+fn imported_timestamp(origin_server_ts: i64) -> Option<PgTimestamp> {
+    if origin_server_ts < 0 {
+        return None;
+    }
+
+    origin_server_ts.checked_sub(POSTGRES_EPOCH_MILLISECONDS)
+        .and_then(|milliseconds| milliseconds.checked_mul(1000))
+        .map(PgTimestamp)
+}
+
+impl NewImportedEvent {
+    /// Create a `NewImportedEvent` from a `NewEvent` and the time it was originally created, in
+    /// milliseconds since the Unix epoch.
+    ///
+    /// Timestamps in the future and timestamps that cannot be stored, see `is_storable_timestamp`,
+    /// are rejected.
+    pub fn new(event: NewEvent, origin_server_ts: i64) -> Result<NewImportedEvent, ApiError> {
+        let created_at = match imported_timestamp(origin_server_ts) {
+            Some(created_at) => created_at,
+            None => return Err(ApiError::bad_event(
+                format!("The event {} has an origin_server_ts out of range.", event.id)
+            )),
+        };
+
+        if created_at.0 / 1000 > get_now() {
+            return Err(ApiError::bad_event(
+                format!("The event {} has an origin_server_ts in the future.", event.id)
+            ));
+        }
+
+        Ok(NewImportedEvent {
+            event_type: event.event_type,
+            extra_content: event.extra_content,
+            id: event.id,
+            content: event.content,
+            content_hash: event.content_hash,
+            room_id: event.room_id,
+            state_key: event.state_key,
+            user_id: event.user_id,
+            created_at: created_at,
+            redacted: false,
+        })
+    }
 }
 
 impl Event {
+    /// Whether the event has to be served as a custom event, because its content does not fit the
+    /// type of its content in ruma-events.
+    ///
+    /// Redacting an event removes keys that this type requires, except for member events, which
+    /// keep their *membership*. Member events have no type for their *reason* instead, so those
+    /// with a reason are served as custom events, too.
+    pub fn is_served_as_custom(&self) -> bool {
+        if self.event_type != EventType::RoomMember.to_string() {
+            return self.redacted;
+        }
+
+        from_str::<Value>(&self.content)
+            .map(|content| content.get("reason").is_some())
+            .unwrap_or(false)
+    }
+
+    /// Whether the content of the event still has the hash it was saved with, i.e. it was not
+    /// changed in the database since.
+    pub fn has_intact_content(&self) -> Result<bool, ApiError> {
+        Ok(imported_content_hash(&self.content)? == self.content_hash)
+    }
+
+    /// The time the event was created, in milliseconds since the Unix epoch.
+    pub fn origin_server_ts(&self) -> i64 {
+        // `TIMESTAMP` columns are stored in microseconds.
+        self.created_at.0 / 1000 + POSTGRES_EPOCH_MILLISECONDS
+    }
+
+    /// The number of milliseconds since the event was created.
+    pub fn age(&self) -> i64 {
+        cmp::max(get_now() - self.created_at.0 / 1000, 0)
+    }
+
+    /// Parse the content of the state event this event replaced, if any.
+    fn parsed_prev_content<T>(&self) -> Result<Option<T>, ApiError>
+    where T: for<'de> Deserialize<'de> {
+        match self.prev_content {
+            Some(ref prev_content) => Ok(Some(from_str(prev_content).map_err(ApiError::from)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The `unsigned` data of a state event, with the ID and content of the state event it
+    /// replaced, if any.
+    fn state_unsigned(&self) -> Result<Option<Value>, ApiError> {
+        let replaces_state = match self.replaces_state {
+            Some(ref replaces_state) => replaces_state,
+            None => return Ok(None),
+        };
+
+        let mut unsigned = Map::new();
+
+        unsigned.insert("replaces_state".to_string(), Value::String(replaces_state.to_string()));
+
+        if let Some(prev_content) = self.parsed_prev_content::<Value>()? {
+            unsigned.insert("prev_content".to_string(), prev_content);
+        }
+
+        Ok(Some(Value::Object(unsigned)))
+    }
+
     /// Return room join rules for given `room_id`.
     pub fn find_room_join_rules_by_room_id(connection: &PgConnection, room_id: RoomId)
         -> Result<JoinRulesEvent, ApiError>
@@ -152,22 +342,49 @@ impl Event {
             })
     }
 
-    /// Return all `RoomEvent`'s for a `RoomId` up to a specific point in time.
-    pub fn find_room_events_until(
+    /// Return the `limit` most recent `RoomEvent`s for a `RoomId` with an ordering greater than
+    /// `after` and lower than `before`, oldest first. Without a `limit` all of them are returned.
+    pub fn find_latest_room_events(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        after: i64,
+        before: Option<i64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Event>, ApiError> {
+        let before = before.unwrap_or(i64::max_value());
+        let limit = limit.map_or(i64::max_value(), |limit| limit as i64);
+
+        let mut events: Vec<Event> = events::table
+            .filter(events::event_type.like("m.room.%"))
+            .filter(events::ordering.gt(after))
+            .filter(events::ordering.lt(before))
+            .filter(events::room_id.eq(room_id))
+            .order(events::ordering.desc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        events.reverse();
+
+        Ok(events)
+    }
+
+    /// Return the `limit` oldest `RoomEvent`s for a `RoomId` with an ordering greater than
+    /// `after`, oldest first.
+    pub fn find_next_room_events(
         connection: &PgConnection,
         room_id: &RoomId,
-        until: &i64
+        after: i64,
+        limit: usize,
     ) -> Result<Vec<Event>, ApiError> {
         events::table
             .filter(events::event_type.like("m.room.%"))
-            .filter(events::ordering.lt(until))
+            .filter(events::ordering.gt(after))
             .filter(events::room_id.eq(room_id))
             .order(events::ordering.asc())
+            .limit(limit as i64)
             .get_results(connection)
-            .map_err(|err| match err {
-                DieselError::NotFound => ApiError::not_found(None),
-                _ => ApiError::from(err),
-            })
+            .map_err(ApiError::from)
     }
 
     /// Look up an event given its `EventId`.
@@ -179,11 +396,60 @@ impl Event {
         }
     }
 
-    /// Return the room's state before a specified event.
+    /// Look up the room, stream position and sender of an event given its `EventId`, without
+    /// loading the event.
+    pub fn locate(connection: &PgConnection, event_id: &EventId)
+    -> Result<Option<EventLocation>, ApiError> {
+        match event_lookup::table.find(event_id).first(connection) {
+            Ok(location) => Ok(Some(location)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Check that an event was sent in the room a client claims it was.
+    ///
+    /// Fails with `M_NOT_FOUND` for events of other rooms, like for events that do not exist.
+    pub fn verify_in_room(connection: &PgConnection, event_id: &EventId, room_id: &RoomId)
+    -> Result<EventLocation, ApiError> {
+        match Event::locate(connection, event_id)? {
+            Some(location) if location.room_id == *room_id => Ok(location),
+            _ => Err(ApiError::not_found(
+                format!("The event {} was not found in this room.", event_id)
+            )),
+        }
+    }
+
+    /// Look up the events of a room with the given `EventId`s, newest first, in a single query.
+    ///
+    /// Events of other rooms are left out, like events that do not exist.
+    pub fn find_many_in_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_ids: &[EventId],
+    ) -> Result<Vec<Event>, ApiError> {
+        events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::id.eq(any(event_ids)))
+            .order(events::ordering.desc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Look up the events with the given `EventId`s, newest first.
+    pub fn find_many(connection: &PgConnection, event_ids: &[EventId]) -> Result<Vec<Event>, ApiError> {
+        events::table
+            .filter(events::id.eq(any(event_ids)))
+            .order(events::ordering.desc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the room's state before the event with the given ordering.
     pub fn get_room_state_events_until(
         connection: &PgConnection,
         room_id: &RoomId,
-        until: &Event,
+        until: i64,
     ) -> Result<Vec<Event>, ApiError> {
         let state_events: Vec<String> = STATE_EVENTS.iter()
             .map(EventType::to_string)
@@ -193,7 +459,7 @@ impl Event {
             .select(max(events::ordering))
             .filter(events::room_id.eq(room_id))
             .filter(events::event_type.eq(any(state_events)))
-            .filter(events::ordering.lt(until.ordering))
+            .filter(events::ordering.lt(until))
             .group_by(events::event_type);
 
         events::table
@@ -207,6 +473,65 @@ impl Event {
         Event::get_room_state_events_since(connection, room_id, -1)
     }
 
+    /// Returns every state event of the given type and state key the room has had, oldest first.
+    pub fn find_state_changes(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &EventType,
+        state_key: &str,
+    ) -> Result<Vec<Event>, ApiError> {
+        events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type.to_string()))
+            .filter(events::state_key.eq(state_key))
+            .order(events::ordering.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the room's current state events of the given type, one for each state key.
+    pub fn get_room_state_events_by_type(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &EventType,
+    ) -> Result<Vec<Event>, ApiError> {
+        let ordering = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type.to_string()))
+            .group_by(events::state_key);
+
+        events::table
+            .filter(events::ordering.nullable().eq(any(&ordering)))
+            .order(events::state_key.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Returns the current state events of the given types of several rooms in a single query, one
+    /// for each room, type and state key.
+    pub fn get_rooms_state_events_by_types(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+        event_types: &[EventType],
+    ) -> Result<Vec<Event>, ApiError> {
+        let event_types: Vec<String> = event_types.iter()
+            .map(EventType::to_string)
+            .collect();
+
+        let ordering = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::event_type.eq(any(event_types)))
+            .group_by((events::room_id, events::event_type, events::state_key));
+
+        events::table
+            .filter(events::ordering.nullable().eq(any(&ordering)))
+            .order(events::state_key.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return the state changes in a room after a specific point in time.
     pub fn get_room_state_events_since(
         connection: &PgConnection,
@@ -238,8 +563,11 @@ macro_rules! impl_try_from_room_event_for_new_event {
             type Error = ApiError;
 
             fn try_from(event: $ty) -> Result<Self, Self::Error> {
+                let content = to_string(event.content()).map_err(ApiError::from)?;
+
                 Ok(NewEvent {
-                    content: to_string(event.content()).map_err(ApiError::from)?,
+                    content_hash: content_hash(&content)?,
+                    content: content,
                     event_type: event.event_type().to_string(),
                     extra_content: None,
                     id: event.event_id().clone(),
@@ -258,8 +586,11 @@ macro_rules! impl_try_from_state_event_for_new_event {
             type Error = ApiError;
 
             fn try_from(event: $ty) -> Result<Self, Self::Error> {
+                let content = to_string(event.content()).map_err(ApiError::from)?;
+
                 Ok(NewEvent {
-                    content: to_string(event.content()).map_err(ApiError::from)?,
+                    content_hash: content_hash(&content)?,
+                    content: content,
                     event_type: event.event_type().to_string(),
                     extra_content: match event.extra_content() {
                         Some(extra_content) => Some(
@@ -302,14 +633,17 @@ macro_rules! impl_try_into_state_event_for_event {
             type Error = ApiError;
 
             fn try_into(self) -> Result<$ty, Self::Error> {
+                let prev_content = self.parsed_prev_content()?;
+                let unsigned = self.state_unsigned()?;
+
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
-                    prev_content: None,
+                    prev_content: prev_content,
                     event_id: self.id,
                     state_key: "".to_string(),
                     event_type: EventType::from(self.event_type.as_ref()),
                     room_id: self.room_id,
-                    unsigned: None,
+                    unsigned: unsigned,
                     user_id: self.user_id,
                 })
             }
@@ -391,6 +725,9 @@ impl TryInto<MemberEvent> for Event {
     type Error = ApiError;
 
     fn try_into(self) -> Result<MemberEvent, Self::Error> {
+        let prev_content = self.parsed_prev_content()?;
+        let unsigned = self.state_unsigned()?;
+
         Ok(MemberEvent {
             content: from_str(&self.content)?,
             event_id: self.id,
@@ -407,11 +744,11 @@ impl TryInto<MemberEvent> for Event {
                 },
                 None => None,
             },
-            prev_content: None,
+            prev_content: prev_content,
             state_key: "".to_string(),
             event_type: EventType::RoomMember,
             room_id: self.room_id,
-            unsigned: None,
+            unsigned: unsigned,
             user_id: self.user_id,
         })
     }
@@ -421,6 +758,10 @@ impl TryInto<StateEvent> for Event {
     type Error = ApiError;
 
     fn try_into(self) -> Result<StateEvent, Self::Error> {
+        if self.is_served_as_custom() {
+            return Ok(StateEvent::CustomState(self.try_into()?));
+        }
+
         let state_event = match EventType::from(self.event_type.as_ref()) {
             EventType::RoomAliases => StateEvent::RoomAliases(self.try_into()?),
             EventType::RoomAvatar => StateEvent::RoomAvatar(self.try_into()?),
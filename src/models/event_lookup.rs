@@ -0,0 +1,20 @@
+//! Where events belong, maintained by the database whenever events are inserted or deleted.
+//!
+//! Endpoints that are given an event ID together with a room ID must not trust the client about
+//! the room, or they would act on events of rooms the user may know nothing about. They check the
+//! room of the event here instead of loading the whole event.
+
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+/// The room, stream position and sender of an event.
+#[derive(Debug, Clone, Queryable)]
+pub struct EventLocation {
+    /// The ID of the event.
+    pub event_id: EventId,
+    /// The room the event was sent in.
+    pub room_id: RoomId,
+    /// The position of the event in the event stream.
+    pub ordering: i64,
+    /// The user who sent the event.
+    pub sender: UserId,
+}
@@ -0,0 +1,86 @@
+//! Gaps in the event stream of a room, left by purging events.
+//!
+//! Sync tokens are positions in the event stream, so a client that synced before events were
+//! purged would continue after them without noticing. Every purge is recorded here, and sync
+//! sends clients whose token predates a purge a limited timeline with the full state of the room.
+
+use diesel::{
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use ruma_identifiers::RoomId;
+
+use error::ApiError;
+use models::event_transaction::EventTransaction;
+use models::presence_status::get_now;
+use schema::event_purges;
+
+/// A purge of events, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "event_purges"]
+pub struct NewEventPurge {
+    /// The room the events were purged from.
+    pub room_id: RoomId,
+    /// The ordering of the newest purged event.
+    pub purged_up_to: i64,
+    /// The time of the purge.
+    pub created_at: PgTimestamp,
+}
+
+/// A purge of events.
+#[derive(Debug, Clone, Queryable)]
+pub struct EventPurge {
+    /// The ID of the purge.
+    pub id: i64,
+    /// The room the events were purged from.
+    pub room_id: RoomId,
+    /// The ordering of the newest purged event.
+    pub purged_up_to: i64,
+    /// The time of the purge.
+    pub created_at: PgTimestamp,
+}
+
+impl EventPurge {
+    /// Record that events of a room up to the ordering `purged_up_to` are purged, deleting their
+    /// transaction IDs.
+    ///
+    /// Anything that deletes events from the `events` table must call this, before deleting them.
+    pub fn record(connection: &PgConnection, room_id: &RoomId, purged_up_to: i64)
+    -> Result<(), ApiError> {
+        EventTransaction::delete_for_room_until(connection, room_id, purged_up_to)?;
+
+        let new_purge = NewEventPurge {
+            room_id: room_id.clone(),
+            purged_up_to: purged_up_to,
+            created_at: PgTimestamp(get_now()),
+        };
+
+        insert(&new_purge)
+            .into(event_purges::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+}
+
+/// Check whether events of a room after the ordering `since` were purged, so that a client that
+/// synced up to `since` may have missed events.
+pub fn stream_has_gap_since(connection: &PgConnection, room_id: &RoomId, since: i64)
+-> Result<bool, ApiError> {
+    let purges: i64 = event_purges::table
+        .select(count_star())
+        .filter(event_purges::room_id.eq(room_id))
+        .filter(event_purges::purged_up_to.gt(since))
+        .first(connection)
+        .map_err(ApiError::from)?;
+
+    Ok(purges > 0)
+}
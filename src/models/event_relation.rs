@@ -0,0 +1,439 @@
+//! Relations between events, e.g. reactions to a message or edits of it.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use diesel::{
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    GroupByDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::{all, any, count_star, sql};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::types::{Bool, Text};
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{Map, Value, from_str, to_value};
+
+use error::ApiError;
+use models::event::Event;
+use schema::event_relations;
+
+/// The relation type of annotations, e.g. reactions.
+pub const ANNOTATION_REL_TYPE: &'static str = "m.annotation";
+
+/// The relation type of edits, which replace the content of an event with their `m.new_content`.
+pub const REPLACE_REL_TYPE: &'static str = "m.replace";
+
+/// The maximum number of distinct annotation keys bundled with an event.
+///
+/// Only the most common keys are bundled, so clients get a useful summary of hot events without
+/// the payload growing with every new emoji.
+const MAX_BUNDLED_ANNOTATION_KEYS: usize = 20;
+
+/// A relation between events, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "event_relations"]
+pub struct NewEventRelation {
+    /// The event that relates to another event.
+    pub event_id: EventId,
+    /// The room both events were sent in.
+    pub room_id: RoomId,
+    /// The user who sent the relating event.
+    pub user_id: UserId,
+    /// The event being related to.
+    pub relates_to_id: EventId,
+    /// The type of the relation, e.g. *m.annotation*.
+    pub rel_type: String,
+    /// The key annotations are aggregated by, e.g. the emoji of a reaction.
+    pub aggregation_key: Option<String>,
+    /// The ordering of the relating event.
+    pub ordering: i64,
+}
+
+/// A relation between events.
+#[derive(Clone, Debug, Queryable)]
+pub struct EventRelation {
+    /// The event that relates to another event.
+    pub event_id: EventId,
+    /// The room both events were sent in.
+    pub room_id: RoomId,
+    /// The user who sent the relating event.
+    pub user_id: UserId,
+    /// The event being related to.
+    pub relates_to_id: EventId,
+    /// The type of the relation, e.g. *m.annotation*.
+    pub rel_type: String,
+    /// The key annotations are aggregated by, e.g. the emoji of a reaction.
+    pub aggregation_key: Option<String>,
+    /// The ordering of the relating event.
+    pub ordering: i64,
+    /// The time the relation was created.
+    pub created_at: PgTimestamp,
+}
+
+/// The number of annotations of an event with the same key.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AnnotationCount {
+    /// The type of the annotating events.
+    #[serde(rename="type")]
+    pub event_type: String,
+    /// The key of the annotations, e.g. the emoji of a reaction.
+    pub key: String,
+    /// The number of annotations with this key.
+    pub count: i64,
+    /// Whether the user the counts are shown to annotated the event with this key.
+    pub me: bool,
+}
+
+/// The aggregated relations of an event by relation type.
+#[derive(Debug, Serialize)]
+struct BundledRelationTypes {
+    /// The annotations of the event.
+    #[serde(rename="m.annotation", skip_serializing_if = "Option::is_none")]
+    annotation: Option<BundledAnnotations>,
+    /// The latest edit of the event.
+    #[serde(rename="m.replace", skip_serializing_if = "Option::is_none")]
+    replace: Option<BundledEdit>,
+}
+
+/// The aggregated annotations of an event.
+#[derive(Debug, Serialize)]
+struct BundledAnnotations {
+    /// The number of annotations per key.
+    chunk: Vec<AnnotationCount>,
+}
+
+/// The latest edit of an event, with the content clients should render instead of the original.
+#[derive(Debug, Serialize)]
+struct BundledEdit {
+    /// The ID of the editing event.
+    event_id: EventId,
+    /// The time the edit was sent, in milliseconds since the Unix epoch.
+    origin_server_ts: i64,
+    /// The user who sent the edit, always the sender of the original event.
+    sender: UserId,
+    /// The content of the editing event, including `m.new_content`.
+    content: Value,
+}
+
+/// Bundle the annotation counts and the latest edit of an event into its `unsigned` data.
+///
+/// They are added to the `m.relations` the `unsigned` data may already have, and nothing is added
+/// if there is neither. Only counts are bundled, never the IDs of the annotating events, so the
+/// size of the bundle does not depend on how popular the event is.
+pub fn bundle_relations(
+    unsigned: &mut Map<String, Value>,
+    counts: Option<Vec<AnnotationCount>>,
+    edit: Option<Event>,
+) -> Result<(), ApiError> {
+    if counts.is_none() && edit.is_none() {
+        return Ok(());
+    }
+
+    let replace = match edit {
+        Some(edit) => Some(BundledEdit {
+            content: from_str(&edit.content)?,
+            event_id: edit.id.clone(),
+            origin_server_ts: edit.origin_server_ts(),
+            sender: edit.user_id.clone(),
+        }),
+        None => None,
+    };
+
+    let bundle = BundledRelationTypes {
+        annotation: counts.map(|counts| BundledAnnotations { chunk: counts }),
+        replace: replace,
+    };
+
+    let mut relations = match unsigned.remove("m.relations") {
+        Some(Value::Object(relations)) => relations,
+        _ => Map::new(),
+    };
+
+    if let Value::Object(bundle) = to_value(&bundle).map_err(ApiError::from)? {
+        for (rel_type, aggregation) in bundle {
+            relations.insert(rel_type, aggregation);
+        }
+    }
+
+    unsigned.insert("m.relations".to_string(), Value::Object(relations));
+
+    Ok(())
+}
+
+impl NewEventRelation {
+    /// Extract the relation from the `m.relates_to` field of an event's content, if it has one.
+    pub fn from_event(event: &Event) -> Result<Option<NewEventRelation>, ApiError> {
+        let content: Value = from_str(&event.content)?;
+
+        let relates_to = match content.get("m.relates_to") {
+            Some(relates_to) => relates_to,
+            None => return Ok(None),
+        };
+
+        let rel_type = match relates_to.get("rel_type").and_then(Value::as_str) {
+            Some(rel_type) => rel_type,
+            None => return Ok(None),
+        };
+
+        let relates_to_id = match relates_to.get("event_id").and_then(Value::as_str) {
+            Some(event_id) => EventId::try_from(event_id).map_err(|_| {
+                ApiError::bad_event("m.relates_to contains an invalid event ID.".to_string())
+            })?,
+            None => Err(ApiError::bad_event("m.relates_to is missing the event ID.".to_string()))?,
+        };
+
+        let has_new_content = content.get("m.new_content").map_or(false, Value::is_object);
+
+        if rel_type == REPLACE_REL_TYPE && !has_new_content {
+            Err(ApiError::bad_event("Edits must have an m.new_content object.".to_string()))?;
+        }
+
+        let aggregation_key = if rel_type == ANNOTATION_REL_TYPE {
+            match relates_to.get("key").and_then(Value::as_str) {
+                Some(key) => Some(key.to_string()),
+                None => Err(ApiError::bad_event("Annotations must have a key.".to_string()))?,
+            }
+        } else {
+            None
+        };
+
+        Ok(Some(NewEventRelation {
+            event_id: event.id.clone(),
+            room_id: event.room_id.clone(),
+            user_id: event.user_id.clone(),
+            relates_to_id: relates_to_id,
+            rel_type: rel_type.to_string(),
+            aggregation_key: aggregation_key,
+            ordering: event.ordering,
+        }))
+    }
+}
+
+impl EventRelation {
+    /// Record the relations of the given events, skipping events without one.
+    pub fn create_for_events(connection: &PgConnection, events: &[Event]) -> Result<(), ApiError> {
+        let mut new_relations = Vec::new();
+
+        for event in events {
+            if let Some(new_relation) = NewEventRelation::from_event(event)? {
+                new_relations.push(new_relation);
+            }
+        }
+
+        if new_relations.is_empty() {
+            return Ok(());
+        }
+
+        insert(&new_relations)
+            .into(event_relations::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Count the annotations of each of the given events by key, as seen by `user_id`, or by a
+    /// user who is not logged in if `user_id` is `None`.
+    ///
+    /// Annotations sent by `ignored_user_ids` are not counted. The counting is done by the
+    /// database, so this stays cheap for events with many thousands of annotations. For each
+    /// event, only the most common keys are returned.
+    pub fn annotation_counts(
+        connection: &PgConnection,
+        event_ids: &[EventId],
+        user_id: Option<&UserId>,
+        ignored_user_ids: &[UserId],
+    ) -> Result<HashMap<EventId, Vec<AnnotationCount>>, ApiError> {
+        let mut annotation_counts: HashMap<EventId, Vec<AnnotationCount>> = HashMap::new();
+
+        if event_ids.is_empty() {
+            return Ok(annotation_counts);
+        }
+
+        let rows: Vec<(EventId, Option<String>, i64)> = event_relations::table
+            .select((
+                event_relations::relates_to_id,
+                event_relations::aggregation_key,
+                count_star(),
+            ))
+            .filter(event_relations::relates_to_id.eq(any(event_ids)))
+            .filter(event_relations::rel_type.eq(ANNOTATION_REL_TYPE))
+            .filter(event_relations::aggregation_key.is_not_null())
+            .filter(event_relations::user_id.ne(all(ignored_user_ids)))
+            .group_by((event_relations::relates_to_id, event_relations::aggregation_key))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let own_annotations: HashSet<(EventId, Option<String>)> = match user_id {
+            Some(user_id) => {
+                event_relations::table
+                    .select((event_relations::relates_to_id, event_relations::aggregation_key))
+                    .filter(event_relations::relates_to_id.eq(any(event_ids)))
+                    .filter(event_relations::rel_type.eq(ANNOTATION_REL_TYPE))
+                    .filter(event_relations::user_id.eq(user_id))
+                    .get_results::<(EventId, Option<String>)>(connection)
+                    .map_err(ApiError::from)?
+                    .into_iter()
+                    .collect()
+            }
+            None => HashSet::new(),
+        };
+
+        for (relates_to_id, aggregation_key, count) in rows {
+            let me = own_annotations.contains(&(relates_to_id.clone(), aggregation_key.clone()));
+
+            if let Some(key) = aggregation_key {
+                annotation_counts.entry(relates_to_id).or_insert_with(Vec::new).push(AnnotationCount {
+                    event_type: "m.reaction".to_string(),
+                    key: key,
+                    count: count,
+                    me: me,
+                });
+            }
+        }
+
+        for counts in annotation_counts.values_mut() {
+            counts.sort_by(|a, b| (b.count, &a.key).cmp(&(a.count, &b.key)));
+            counts.truncate(MAX_BUNDLED_ANNOTATION_KEYS);
+        }
+
+        Ok(annotation_counts)
+    }
+
+    /// Look up the latest edit of each of the given events, by the IDs of the edited events.
+    ///
+    /// Only edits sent by the sender of the edited event count, so other users cannot change what
+    /// a message says. Events without such an edit are left out.
+    pub fn latest_edits(connection: &PgConnection, event_ids: &[EventId])
+    -> Result<HashMap<EventId, Event>, ApiError> {
+        let mut latest_edits = HashMap::new();
+
+        if event_ids.is_empty() {
+            return Ok(latest_edits);
+        }
+
+        // Ordered newest first within each edited event, so DISTINCT ON keeps the latest edit.
+        let edits: Vec<(EventId, EventId)> = event_relations::table
+            .select(sql::<(Text, Text)>(
+                "DISTINCT ON (event_relations.relates_to_id) \
+                event_relations.relates_to_id, event_relations.event_id"
+            ))
+            .filter(event_relations::relates_to_id.eq(any(event_ids)))
+            .filter(event_relations::rel_type.eq(REPLACE_REL_TYPE))
+            .filter(sql::<Bool>(
+                "event_relations.user_id = \
+                (SELECT events.user_id FROM events WHERE events.id = event_relations.relates_to_id)"
+            ))
+            .order((event_relations::relates_to_id, event_relations::ordering.desc()))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        // The edited event of each latest edit, by the ID of the edit.
+        let mut edited_ids: HashMap<EventId, EventId> = edits.into_iter()
+            .map(|(relates_to_id, event_id)| (event_id, relates_to_id))
+            .collect();
+
+        let edit_ids: Vec<EventId> = edited_ids.keys().cloned().collect();
+
+        for edit in Event::find_many(connection, &edit_ids)? {
+            if let Some(relates_to_id) = edited_ids.remove(&edit.id) {
+                latest_edits.insert(relates_to_id, edit);
+            }
+        }
+
+        Ok(latest_edits)
+    }
+
+    /// Return the relations to an event, newest first.
+    ///
+    /// Pagination is keyset-based: only relations with an ordering lower than `before` are
+    /// returned, so pages stay stable and fast no matter how deep they are or how many
+    /// relations are added in the meantime.
+    pub fn find_page(
+        connection: &PgConnection,
+        relates_to_id: &EventId,
+        rel_type: Option<&str>,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<EventRelation>, ApiError> {
+        let before = before.unwrap_or(i64::max_value());
+
+        match rel_type {
+            Some(rel_type) => {
+                event_relations::table
+                    .filter(event_relations::relates_to_id.eq(relates_to_id))
+                    .filter(event_relations::rel_type.eq(rel_type))
+                    .filter(event_relations::ordering.lt(before))
+                    .order(event_relations::ordering.desc())
+                    .limit(limit)
+                    .get_results(connection)
+                    .map_err(ApiError::from)
+            }
+            None => {
+                event_relations::table
+                    .filter(event_relations::relates_to_id.eq(relates_to_id))
+                    .filter(event_relations::ordering.lt(before))
+                    .order(event_relations::ordering.desc())
+                    .limit(limit)
+                    .get_results(connection)
+                    .map_err(ApiError::from)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Map, Value, from_str};
+
+    use super::{AnnotationCount, bundle_relations};
+
+    #[test]
+    fn bundled_relations_are_merged_into_the_unsigned_data() {
+        let mut unsigned: Map<String, Value> = from_str(r#"{
+            "age": 10,
+            "m.relations": {"m.reference": {"chunk": [{"event_id": "$ref:ruma.test"}]}}
+        }"#).unwrap();
+        let counts = vec![AnnotationCount {
+            event_type: "m.reaction".to_string(),
+            key: "+1".to_string(),
+            count: 2,
+            me: false,
+        }];
+
+        bundle_relations(&mut unsigned, Some(counts), None).unwrap();
+
+        let unsigned = Value::Object(unsigned);
+
+        assert_eq!(unsigned.get("age").unwrap().as_i64().unwrap(), 10);
+        assert_eq!(
+            unsigned.pointer("/m.relations/m.reference/chunk/0/event_id").unwrap()
+                .as_str()
+                .unwrap(),
+            "$ref:ruma.test"
+        );
+        assert_eq!(
+            unsigned.pointer("/m.relations/m.annotation/chunk/0/count").unwrap()
+                .as_i64()
+                .unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn nothing_is_bundled_without_relations() {
+        let mut unsigned = Map::new();
+
+        bundle_relations(&mut unsigned, None, None).unwrap();
+
+        assert!(unsigned.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+//! The transaction IDs events were sent with.
+//!
+//! Clients match their local echo of a message to the event in their sync by its transaction ID,
+//! which is only shown to the token that sent the event. Clients do that right after sending, so
+//! transaction IDs are deleted after a day.
+
+use std::collections::HashMap;
+
+use diesel::{
+    delete,
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use ruma_identifiers::{EventId, RoomId};
+
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::{event_transactions, events};
+
+/// The time, in milliseconds, after which transaction IDs are deleted.
+const TRANSACTION_ID_LIFETIME: i64 = 24 * 60 * 60 * 1000;
+
+/// The transaction ID of an event.
+#[derive(Clone, Debug, Insertable, Queryable)]
+#[table_name = "event_transactions"]
+pub struct EventTransaction {
+    /// The event that was sent.
+    pub event_id: EventId,
+    /// The SHA-256 hash of the access token, or of the application service token, the event was
+    /// sent with.
+    pub access_token: String,
+    /// The transaction ID the client chose for the event.
+    pub transaction_id: String,
+    /// The time the event was sent.
+    pub created_at: PgTimestamp,
+}
+
+impl EventTransaction {
+    /// Record the transaction ID an event was sent with by the token with the hash `token_hash`.
+    pub fn create(
+        connection: &PgConnection,
+        event_id: &EventId,
+        token_hash: &str,
+        transaction_id: &str,
+    ) -> Result<(), ApiError> {
+        let event_transaction = EventTransaction {
+            event_id: event_id.clone(),
+            access_token: token_hash.to_string(),
+            transaction_id: transaction_id.to_string(),
+            created_at: PgTimestamp(get_now()),
+        };
+
+        insert(&event_transaction)
+            .into(event_transactions::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// The transaction IDs of those of `event_ids` that were sent by the token with the hash
+    /// `token_hash`.
+    pub fn find_transaction_ids(
+        connection: &PgConnection,
+        token_hash: &str,
+        event_ids: &[EventId],
+    ) -> Result<HashMap<EventId, String>, ApiError> {
+        if event_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let rows: Vec<(EventId, String)> = event_transactions::table
+            .select((event_transactions::event_id, event_transactions::transaction_id))
+            .filter(event_transactions::event_id.eq(any(event_ids)))
+            .filter(event_transactions::access_token.eq(token_hash))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Delete the transaction IDs of the events of a room up to the ordering `until`, which are
+    /// about to be purged. Returns the number of transaction IDs deleted.
+    pub fn delete_for_room_until(connection: &PgConnection, room_id: &RoomId, until: i64)
+    -> Result<usize, ApiError> {
+        let event_ids = events::table
+            .select(events::id)
+            .filter(events::room_id.eq(room_id))
+            .filter(events::ordering.le(until));
+
+        delete(event_transactions::table.filter(event_transactions::event_id.eq(any(&event_ids))))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Delete the transaction IDs older than `TRANSACTION_ID_LIFETIME`. Returns the number of
+    /// transaction IDs deleted.
+    pub fn delete_expired(connection: &PgConnection) -> Result<usize, ApiError> {
+        let expired_before = PgTimestamp(get_now() - TRANSACTION_ID_LIFETIME);
+
+        delete(event_transactions::table.filter(event_transactions::created_at.le(expired_before)))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,139 @@
+//! Tracking of failed logins, to lock accounts whose passwords are being guessed.
+
+use std::cmp;
+use std::time::Duration;
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use config::LoginLockoutConfig;
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::failed_logins;
+
+/// The failed logins of a user since the first one in the current window.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "failed_logins"]
+pub struct FailedLogin {
+    /// The ID of the user whose password was wrong.
+    pub user_id: UserId,
+    /// The number of failed logins in the window.
+    pub attempts: i32,
+    /// The time of the first failed login in the window.
+    pub first_failed_at: PgTimestamp,
+}
+
+impl FailedLogin {
+    /// Count a login attempt of the user, failing if the user is locked out.
+    ///
+    /// A user is locked out once `max_failed_attempts` logins failed within `window` seconds of
+    /// the first one, until the window has passed. Logins fail with `M_LIMIT_EXCEEDED` meanwhile,
+    /// even with the right password, telling the client when the window passes.
+    ///
+    /// Attempts are counted before their password is checked, and a successful login removes the
+    /// count again with `reset`, so only the failures stay counted. Within a window the check and
+    /// the increment are a single update, so concurrent attempts cannot get past the limit
+    /// together.
+    pub fn count_attempt(
+        connection: &PgConnection,
+        user_id: &UserId,
+        lockout: &LoginLockoutConfig,
+    ) -> Result<(), ApiError> {
+        let now = get_now();
+        let window_start = PgTimestamp(now - lockout.window as i64 * 1000);
+
+        let incremented = update(
+            failed_logins::table
+                .filter(failed_logins::user_id.eq(user_id))
+                .filter(failed_logins::first_failed_at.gt(window_start))
+                .filter(failed_logins::attempts.lt(lockout.max_failed_attempts as i32))
+        )
+            .set(failed_logins::attempts.eq(failed_logins::attempts + 1))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        if incremented == 1 {
+            return Ok(());
+        }
+
+        let new_failed_login = FailedLogin {
+            user_id: user_id.clone(),
+            attempts: 1,
+            first_failed_at: PgTimestamp(now),
+        };
+
+        let inserted = insert(&new_failed_login.on_conflict_do_nothing())
+            .into(failed_logins::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        if inserted == 1 {
+            return Ok(());
+        }
+
+        // The attempts are from a window that has passed, unless the user is locked out.
+        let restarted = update(
+            failed_logins::table
+                .filter(failed_logins::user_id.eq(user_id))
+                .filter(failed_logins::first_failed_at.le(window_start))
+        )
+            .set((
+                failed_logins::attempts.eq(1),
+                failed_logins::first_failed_at.eq(new_failed_login.first_failed_at),
+            ))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        if restarted == 1 {
+            return Ok(());
+        }
+
+        match FailedLogin::find(connection, user_id)? {
+            Some(failed_login) => Err(ApiError::limited_rate(
+                "Too many failed login attempts. Try again later.".to_string(),
+                failed_login.remaining_window(lockout),
+            )),
+            // A concurrent successful login has just reset the count.
+            None => Ok(()),
+        }
+    }
+
+    /// Forget the failed logins of the user after a successful login.
+    pub fn reset(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        delete(failed_logins::table.find(user_id))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Look up the failed logins of a user.
+    fn find(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<FailedLogin>, ApiError> {
+        match failed_logins::table.find(user_id).first(connection) {
+            Ok(failed_login) => Ok(Some(failed_login)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// The time until the window that started with the first failed login has passed.
+    fn remaining_window(&self, lockout: &LoginLockoutConfig) -> Duration {
+        let ends_at = self.first_failed_at.0 + lockout.window as i64 * 1000;
+
+        Duration::from_millis(cmp::max(ends_at - get_now(), 0) as u64)
+    }
+}
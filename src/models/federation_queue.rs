@@ -0,0 +1,278 @@
+//! Queue of EDUs and PDUs waiting to be sent to remote homeservers.
+//!
+//! Ruma does not speak the server-server API yet, so nothing sends the queue: it is filled, and
+//! `find_pending` and `acknowledge` are what a per-destination sender will use once there is a
+//! federation transport to send transactions with. Until then, coalescing keeps the queue at no
+//! more than one presence EDU per user and destination.
+
+use std::collections::HashMap;
+
+use diesel::{
+    delete,
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+    OrderDsl,
+};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use serde_json::{Value, to_string};
+
+use error::ApiError;
+use schema::federation_queue;
+
+/// The EDU type used for presence updates.
+pub const PRESENCE_EDU_TYPE: &'static str = "m.presence";
+
+/// An EDU as it is sent in a federation transaction.
+#[derive(Debug, Serialize)]
+struct Edu<'a> {
+    /// The type of the EDU.
+    edu_type: &'a str,
+    /// The EDU's content.
+    content: &'a Value,
+}
+
+/// A queued federation payload, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "federation_queue"]
+pub struct NewFederationQueueEntry {
+    /// The server name of the remote homeserver.
+    pub destination: String,
+    /// Either "edu" or "pdu".
+    pub kind: String,
+    /// The type of the EDU, e.g. *m.presence*.
+    pub edu_type: Option<String>,
+    /// Entries with the same destination, EDU type and key replace each other.
+    pub coalesce_key: Option<String>,
+    /// JSON of the EDU or PDU.
+    pub payload: String,
+}
+
+/// A queued federation payload.
+#[derive(Debug, Clone, Queryable)]
+pub struct FederationQueueEntry {
+    /// The stream position of the entry.
+    pub id: i64,
+    /// The server name of the remote homeserver.
+    pub destination: String,
+    /// Either "edu" or "pdu".
+    pub kind: String,
+    /// The type of the EDU, e.g. *m.presence*.
+    pub edu_type: Option<String>,
+    /// Entries with the same destination, EDU type and key replace each other.
+    pub coalesce_key: Option<String>,
+    /// JSON of the EDU or PDU.
+    pub payload: String,
+    /// The time the entry was queued.
+    pub created_at: PgTimestamp,
+}
+
+impl FederationQueueEntry {
+    /// Queue an EDU for every destination.
+    ///
+    /// Pending EDUs of the same type and coalescing key are replaced, so a destination that is
+    /// unreachable for a while only receives the latest state once it comes back. They are
+    /// deleted for all destinations at once, and the new EDUs inserted at once, so the number of
+    /// queries does not grow with the number of destinations.
+    pub fn enqueue_edus(
+        connection: &PgConnection,
+        destinations: &[String],
+        edu_type: &str,
+        coalesce_key: &str,
+        content: &Value,
+    ) -> Result<(), ApiError> {
+        if destinations.is_empty() {
+            return Ok(());
+        }
+
+        let payload = to_string(&Edu {
+            edu_type: edu_type,
+            content: content,
+        }).map_err(ApiError::from)?;
+
+        let outdated = federation_queue::table
+            .filter(federation_queue::destination.eq(any(destinations)))
+            .filter(federation_queue::edu_type.eq(edu_type))
+            .filter(federation_queue::coalesce_key.eq(coalesce_key));
+
+        delete(outdated)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        let new_entries: Vec<NewFederationQueueEntry> = destinations.iter()
+            .map(|destination| NewFederationQueueEntry {
+                destination: destination.clone(),
+                kind: "edu".to_string(),
+                edu_type: Some(edu_type.to_string()),
+                coalesce_key: Some(coalesce_key.to_string()),
+                payload: payload.clone(),
+            })
+            .collect();
+
+        insert(&new_entries)
+            .into(federation_queue::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return the pending entries for a destination after a stream position, oldest first.
+    pub fn find_pending(connection: &PgConnection, destination: &str, since: i64)
+    -> Result<Vec<FederationQueueEntry>, ApiError> {
+        let entries = federation_queue::table
+            .filter(federation_queue::destination.eq(destination))
+            .filter(federation_queue::id.gt(since))
+            .order(federation_queue::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(coalesce(entries))
+    }
+
+    /// Remove the entries of a destination up to and including a stream position once they have
+    /// been delivered.
+    pub fn acknowledge(connection: &PgConnection, destination: &str, up_to: i64)
+    -> Result<usize, ApiError> {
+        let delivered = federation_queue::table
+            .filter(federation_queue::destination.eq(destination))
+            .filter(federation_queue::id.le(up_to));
+
+        delete(delivered)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
+
+/// Keep only the latest entry per destination, EDU type and coalescing key.
+///
+/// Entries without a coalescing key are always kept. The relative order of the remaining
+/// entries is preserved.
+pub fn coalesce(entries: Vec<FederationQueueEntry>) -> Vec<FederationQueueEntry> {
+    let mut latest: HashMap<(String, String, String), i64> = HashMap::new();
+
+    for entry in &entries {
+        if let Some(key) = coalescing_key(entry) {
+            let position = latest.entry(key).or_insert(entry.id);
+
+            if entry.id > *position {
+                *position = entry.id;
+            }
+        }
+    }
+
+    entries.into_iter().filter(|entry| {
+        match coalescing_key(entry) {
+            Some(key) => latest.get(&key) == Some(&entry.id),
+            None => true,
+        }
+    }).collect()
+}
+
+/// The destination, EDU type and coalescing key of an entry, if it can be coalesced.
+fn coalescing_key(entry: &FederationQueueEntry) -> Option<(String, String, String)> {
+    match (&entry.edu_type, &entry.coalesce_key) {
+        (&Some(ref edu_type), &Some(ref key)) => {
+            Some((entry.destination.clone(), edu_type.clone(), key.clone()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::pg::data_types::PgTimestamp;
+    use serde_json::Value;
+
+    use test::Test;
+    use super::{FederationQueueEntry, PRESENCE_EDU_TYPE, coalesce};
+
+    fn presence_entry(id: i64, destination: &str, user_id: &str, presence: &str) -> FederationQueueEntry {
+        FederationQueueEntry {
+            id: id,
+            destination: destination.to_string(),
+            kind: "edu".to_string(),
+            edu_type: Some(PRESENCE_EDU_TYPE.to_string()),
+            coalesce_key: Some(user_id.to_string()),
+            payload: presence.to_string(),
+            created_at: PgTimestamp(0),
+        }
+    }
+
+    #[test]
+    fn rapid_presence_changes_are_coalesced_per_destination() {
+        let entries = vec![
+            presence_entry(1, "a.test", "@alice:ruma.test", "online"),
+            presence_entry(2, "b.test", "@alice:ruma.test", "online"),
+            presence_entry(3, "a.test", "@alice:ruma.test", "unavailable"),
+            presence_entry(4, "b.test", "@alice:ruma.test", "unavailable"),
+            presence_entry(5, "a.test", "@alice:ruma.test", "offline"),
+            presence_entry(6, "b.test", "@alice:ruma.test", "offline"),
+        ];
+
+        let coalesced = coalesce(entries);
+
+        assert_eq!(coalesced.len(), 2);
+        assert_eq!(coalesced[0].destination, "a.test");
+        assert_eq!(coalesced[0].payload, "offline");
+        assert_eq!(coalesced[1].destination, "b.test");
+        assert_eq!(coalesced[1].payload, "offline");
+    }
+
+    #[test]
+    fn enqueued_edus_replace_the_pending_ones_of_every_destination() {
+        let test = Test::new();
+        let destinations = vec!["a.test".to_string(), "b.test".to_string()];
+
+        test.with_connection(|connection| {
+            for presence in &["online", "unavailable", "offline"] {
+                FederationQueueEntry::enqueue_edus(
+                    connection,
+                    &destinations,
+                    PRESENCE_EDU_TYPE,
+                    "@alice:ruma.test",
+                    &Value::from(*presence),
+                ).unwrap();
+            }
+
+            FederationQueueEntry::enqueue_edus(
+                connection,
+                &destinations[..1],
+                PRESENCE_EDU_TYPE,
+                "@bob:ruma.test",
+                &Value::from("online"),
+            ).unwrap();
+
+            let pending = |destination| {
+                FederationQueueEntry::find_pending(connection, destination, 0).unwrap()
+            };
+
+            let payloads: Vec<String> = pending("a.test").into_iter()
+                .map(|entry| entry.payload)
+                .collect();
+            assert_eq!(payloads.len(), 2);
+            assert!(payloads[0].contains("offline"));
+            assert!(payloads[1].contains("online"));
+
+            let payloads: Vec<String> = pending("b.test").into_iter()
+                .map(|entry| entry.payload)
+                .collect();
+            assert_eq!(payloads.len(), 1);
+            assert!(payloads[0].contains("offline"));
+        });
+    }
+
+    #[test]
+    fn different_users_are_not_coalesced() {
+        let entries = vec![
+            presence_entry(1, "a.test", "@alice:ruma.test", "online"),
+            presence_entry(2, "a.test", "@bob:ruma.test", "online"),
+        ];
+
+        assert_eq!(coalesce(entries).len(), 2);
+    }
+}
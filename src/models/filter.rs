@@ -16,6 +16,8 @@ use serde::de::{Error as SerdeError, Unexpected, Visitor};
 
 use error::ApiError;
 use schema::filters;
+use util::event_fields::parse_field_path;
+use util::glob::glob;
 
 /// Defines the default format of `Filter` for `account_data` and `presence`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,6 +73,19 @@ pub struct RoomEventFilter {
     pub senders: Vec<UserId>,
 }
 
+impl EventFilter {
+    /// Whether events of the given type pass the `types` and `not_types` of the filter.
+    ///
+    /// Both lists may contain globs such as `m.*`. Exclusions take precedence over inclusions.
+    pub fn allows_type(&self, event_type: &str) -> bool {
+        if self.not_types.iter().any(|pattern| glob(pattern, event_type)) {
+            return false;
+        }
+
+        self.types.is_empty() || self.types.iter().any(|pattern| glob(pattern, event_type))
+    }
+}
+
 fn default_include_leave() -> bool {
     false
 }
@@ -179,6 +194,31 @@ pub struct ContentFilter {
     pub event_fields: Vec<String>,
 }
 
+impl ContentFilter {
+    /// Check that the `event_fields` of the filter are well-formed, failing with
+    /// `IO_RUMA_INVALID_PARAM` otherwise.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        match self.event_fields.iter().find(|field| parse_field_path(field).is_none()) {
+            Some(field) => Err(ApiError::invalid_param(
+                "event_fields",
+                &format!("`{}` is not a valid field.", field),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The paths of the `event_fields` of the filter, or `None` if all fields are included.
+    ///
+    /// Malformed fields are skipped, since they are rejected by `validate`.
+    pub fn event_field_paths(&self) -> Option<Vec<Vec<String>>> {
+        if self.event_fields.is_empty() {
+            return None;
+        }
+
+        Some(self.event_fields.iter().filter_map(|field| parse_field_path(field)).collect())
+    }
+}
+
 /// A new Matrix filter, not yet saved.
 #[derive(Debug, Insertable)]
 #[table_name = "filters"]
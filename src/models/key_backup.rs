@@ -0,0 +1,288 @@
+//! Server-side backups of the keys of end-to-end encrypted rooms.
+//!
+//! The keys are encrypted by the client, so the server stores them opaquely. Each user can have
+//! several versions of their backup, of which only the newest one, the current version, can be
+//! written to.
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    OrderDsl,
+    SaveChangesDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::count_star;
+use diesel::pg::PgConnection;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{RoomId, UserId};
+
+use error::ApiError;
+use schema::{key_backup_keys, key_backups};
+
+/// A new version of a user's key backup, not yet saved.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "key_backups"]
+pub struct NewKeyBackup {
+    /// The user who owns the backup.
+    pub user_id: UserId,
+    /// The algorithm the keys in the backup are encrypted with.
+    pub algorithm: String,
+    /// The algorithm-specific data needed to verify and decrypt the backup, as JSON.
+    pub auth_data: String,
+}
+
+/// A version of a user's key backup.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[table_name = "key_backups"]
+#[primary_key(version)]
+pub struct KeyBackup {
+    /// The version of the backup.
+    pub version: i64,
+    /// The user who owns the backup.
+    pub user_id: UserId,
+    /// The algorithm the keys in the backup are encrypted with.
+    pub algorithm: String,
+    /// The algorithm-specific data needed to verify and decrypt the backup, as JSON.
+    pub auth_data: String,
+    /// A counter that changes whenever the keys in the backup change.
+    pub etag: i64,
+}
+
+/// The backup of the key of one session of an end-to-end encrypted room.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "key_backup_keys"]
+pub struct BackedUpKey {
+    /// The user who owns the backup.
+    pub user_id: UserId,
+    /// The version of the backup the key belongs to.
+    pub version: i64,
+    /// The room the session is used in.
+    pub room_id: RoomId,
+    /// The ID of the session.
+    pub session_id: String,
+    /// The index of the first message in the session that the key can decrypt.
+    pub first_message_index: i64,
+    /// The number of times the key has been forwarded between devices.
+    pub forwarded_count: i64,
+    /// Whether the device the key came from has been verified.
+    pub is_verified: bool,
+    /// The encrypted key, as JSON.
+    pub session_data: String,
+}
+
+impl KeyBackup {
+    /// Create a new version of a user's key backup, which becomes the current version.
+    ///
+    /// The previous current version is locked until the end of the transaction, so keys that are
+    /// being written to it are written before it stops being the current version.
+    pub fn create(connection: &PgConnection, new_key_backup: &NewKeyBackup)
+    -> Result<KeyBackup, ApiError> {
+        if let Some(key_backup) = KeyBackup::find_current(connection, &new_key_backup.user_id)? {
+            KeyBackup::lock(connection, key_backup.version)?;
+        }
+
+        insert(new_key_backup)
+            .into(key_backups::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the current version of a user's key backup, if the user has a backup.
+    pub fn find_current(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<KeyBackup>, ApiError> {
+        let key_backup = key_backups::table
+            .filter(key_backups::user_id.eq(user_id))
+            .order(key_backups::version.desc())
+            .first(connection);
+
+        match key_backup {
+            Ok(key_backup) => Ok(Some(key_backup)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return the given version of a user's key backup, if it exists.
+    ///
+    /// Versions are passed as given by the client, so versions that are not numbers are not
+    /// found.
+    pub fn find(connection: &PgConnection, user_id: &UserId, version: &str)
+    -> Result<Option<KeyBackup>, ApiError> {
+        let version: i64 = match version.parse() {
+            Ok(version) => version,
+            Err(_) => return Ok(None),
+        };
+
+        let key_backup = key_backups::table
+            .find(version)
+            .filter(key_backups::user_id.eq(user_id))
+            .first(connection);
+
+        match key_backup {
+            Ok(key_backup) => Ok(Some(key_backup)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return the given version of a user's key backup for writing to it, locking it until the end
+    /// of the current transaction so that no new version is created in the meantime.
+    ///
+    /// Fails with `M_WRONG_ROOM_KEYS_VERSION` if it is not the current version.
+    pub fn find_current_for_write(connection: &PgConnection, user_id: &UserId, version: &str)
+    -> Result<KeyBackup, ApiError> {
+        let key_backup = match KeyBackup::find_current(connection, user_id)? {
+            Some(key_backup) => key_backup,
+            None => return Err(ApiError::not_found("No key backup exists.".to_string())),
+        };
+
+        if key_backup.version.to_string() != version {
+            return Err(ApiError::wrong_room_keys_version(Some(key_backup.version.to_string())));
+        }
+
+        KeyBackup::lock(connection, key_backup.version)?;
+
+        // A new version may have been created while waiting for the lock.
+        match KeyBackup::find_current(connection, user_id)? {
+            Some(ref current) if current.version == key_backup.version => {}
+            current => {
+                let current_version = current.map(|current| current.version.to_string());
+
+                return Err(ApiError::wrong_room_keys_version(current_version));
+            }
+        }
+
+        Ok(key_backup)
+    }
+
+    /// Lock the given version of a key backup until the end of the current transaction.
+    fn lock(connection: &PgConnection, version: i64) -> Result<(), ApiError> {
+        // Diesel cannot express `SELECT ... FOR UPDATE`, but a no-op update takes the same lock.
+        update(key_backups::table.find(version))
+            .set(key_backups::etag.eq(key_backups::etag))
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    /// Replace the algorithm-specific data of the backup.
+    pub fn update_auth_data(&mut self, connection: &PgConnection, auth_data: String)
+    -> Result<(), ApiError> {
+        self.auth_data = auth_data;
+
+        self.save_changes::<KeyBackup>(connection)?;
+
+        Ok(())
+    }
+
+    /// Return the number of keys in the backup.
+    pub fn count_keys(&self, connection: &PgConnection) -> Result<i64, ApiError> {
+        key_backup_keys::table
+            .filter(key_backup_keys::user_id.eq(&self.user_id))
+            .filter(key_backup_keys::version.eq(self.version))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return all keys in the backup.
+    pub fn find_keys(&self, connection: &PgConnection) -> Result<Vec<BackedUpKey>, ApiError> {
+        key_backup_keys::table
+            .filter(key_backup_keys::user_id.eq(&self.user_id))
+            .filter(key_backup_keys::version.eq(self.version))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Store keys in the backup.
+    ///
+    /// A key that is already backed up is only replaced by a better one: a key from a verified
+    /// device, then a key that can decrypt more messages, then a key that was forwarded less
+    /// often. The etag changes if any key was stored.
+    pub fn put_keys(&mut self, connection: &PgConnection, keys: Vec<BackedUpKey>)
+    -> Result<(), ApiError> {
+        connection.transaction::<(), ApiError, _>(|| {
+            let mut changed = false;
+
+            for key in keys {
+                let existing_key = key_backup_keys::table
+                    .find((&self.user_id, self.version, &key.room_id, &key.session_id))
+                    .first::<BackedUpKey>(connection);
+
+                match existing_key {
+                    Ok(existing_key) => {
+                        if !key.replaces(&existing_key) {
+                            continue;
+                        }
+
+                        delete(key_backup_keys::table.find(
+                            (&self.user_id, self.version, &key.room_id, &key.session_id)
+                        )).execute(connection)?;
+                    }
+                    Err(DieselError::NotFound) => {}
+                    Err(err) => return Err(ApiError::from(err)),
+                }
+
+                let key = BackedUpKey {
+                    user_id: self.user_id.clone(),
+                    version: self.version,
+                    ..key
+                };
+
+                insert(&key).into(key_backup_keys::table).execute(connection)?;
+
+                changed = true;
+            }
+
+            if changed {
+                self.touch(connection)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Delete all keys in the backup. The etag changes if there were any keys.
+    pub fn delete_keys(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
+        let keys = key_backup_keys::table
+            .filter(key_backup_keys::user_id.eq(&self.user_id))
+            .filter(key_backup_keys::version.eq(self.version));
+
+        if delete(keys).execute(connection)? > 0 {
+            self.touch(connection)?;
+        }
+
+        Ok(())
+    }
+
+    /// Change the etag after the keys in the backup have changed.
+    fn touch(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
+        self.etag += 1;
+
+        self.save_changes::<KeyBackup>(connection)?;
+
+        Ok(())
+    }
+}
+
+impl BackedUpKey {
+    /// Whether this key should replace the backed up key of the same session.
+    fn replaces(&self, existing_key: &BackedUpKey) -> bool {
+        if self.is_verified != existing_key.is_verified {
+            return self.is_verified;
+        }
+
+        if self.first_message_index != existing_key.first_message_index {
+            return self.first_message_index < existing_key.first_message_index;
+        }
+
+        self.forwarded_count < existing_key.forwarded_count
+    }
+}
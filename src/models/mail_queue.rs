@@ -0,0 +1,117 @@
+//! Queue of emails waiting to be delivered.
+//!
+//! Emails are rendered when they are queued and delivered by a background worker, so requests do
+//! not wait for the mail server and failed deliveries can be retried.
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::mail_queue;
+
+/// A queued email, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "mail_queue"]
+pub struct NewQueuedMail {
+    /// The email address of the recipient.
+    pub recipient: String,
+    /// The subject of the email.
+    pub subject: String,
+    /// The HTML version of the body.
+    pub html_body: String,
+    /// The plain text version of the body.
+    pub text_body: String,
+    /// The time of the first delivery attempt.
+    pub next_attempt_at: PgTimestamp,
+}
+
+/// A queued email.
+#[derive(Debug, Clone, Queryable)]
+pub struct QueuedMail {
+    /// The ID of the entry.
+    pub id: i64,
+    /// The email address of the recipient.
+    pub recipient: String,
+    /// The subject of the email.
+    pub subject: String,
+    /// The HTML version of the body.
+    pub html_body: String,
+    /// The plain text version of the body.
+    pub text_body: String,
+    /// The number of failed delivery attempts.
+    pub attempts: i32,
+    /// The earliest time of the next delivery attempt.
+    pub next_attempt_at: PgTimestamp,
+    /// The time the email was queued.
+    pub created_at: PgTimestamp,
+}
+
+impl QueuedMail {
+    /// Queue an email for immediate delivery.
+    pub fn enqueue(
+        connection: &PgConnection,
+        recipient: &str,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+    ) -> Result<QueuedMail, ApiError> {
+        let new_mail = NewQueuedMail {
+            recipient: recipient.to_string(),
+            subject: subject.to_string(),
+            html_body: html_body.to_string(),
+            text_body: text_body.to_string(),
+            next_attempt_at: PgTimestamp(get_now()),
+        };
+
+        insert(&new_mail)
+            .into(mail_queue::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return up to `limit` emails that are due for a delivery attempt, oldest first.
+    pub fn find_due(connection: &PgConnection, limit: i64)
+    -> Result<Vec<QueuedMail>, ApiError> {
+        mail_queue::table
+            .filter(mail_queue::next_attempt_at.le(PgTimestamp(get_now())))
+            .order(mail_queue::id.asc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Remove the email from the queue, once it is delivered or given up on.
+    pub fn delete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(mail_queue::table.find(self.id))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Record a failed delivery attempt and try again after `delay` milliseconds.
+    pub fn retry_later(&self, connection: &PgConnection, delay: i64) -> Result<(), ApiError> {
+        update(mail_queue::table.find(self.id))
+            .set((
+                mail_queue::attempts.eq(self.attempts + 1),
+                mail_queue::next_attempt_at.eq(PgTimestamp(get_now() + delay)),
+            ))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+}
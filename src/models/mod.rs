@@ -1,14 +1,35 @@
 pub mod access_token;
 pub mod account_data;
+pub mod admin_audit_log;
+pub mod device;
+pub mod device_expiry;
+pub mod email_notification;
 pub mod event;
+pub mod event_lookup;
+pub mod event_purge;
+pub mod event_relation;
+pub mod event_transaction;
+pub mod failed_login;
+pub mod federation_queue;
 pub mod filter;
+pub mod key_backup;
+pub mod mail_queue;
+pub mod monthly_active_user;
 pub mod presence_list;
 pub mod presence_status;
 pub mod profile;
+pub mod push_queue;
 pub mod pusher;
+pub mod registration_nonce;
 pub mod room;
 pub mod room_alias;
+pub mod room_export;
 pub mod room_membership;
 pub mod tags;
+pub mod threepid;
+pub mod threepid_session;
+pub mod to_device_message;
 pub mod transaction;
 pub mod user;
+pub mod user_erasure;
+pub mod user_export;
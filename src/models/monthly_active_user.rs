@@ -0,0 +1,173 @@
+//! Tracking of monthly active users.
+
+use std::cmp;
+use std::time::Duration;
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SelectDsl,
+};
+use diesel::expression::dsl::{count_star, min};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::monthly_active_users;
+
+/// The number of milliseconds for which a user counts as active after their last request.
+pub const MONTHLY_ACTIVE_PERIOD: i64 = 30 * 24 * 60 * 60 * 1000;
+
+/// The minimum number of milliseconds between two updates of a user's last activity.
+///
+/// The activity is recorded for every authenticated request, so the database is only written to
+/// once in a while.
+const ACTIVITY_UPDATE_INTERVAL: i64 = 60 * 60 * 1000;
+
+/// A user that has made a request within the last 30 days.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "monthly_active_users"]
+pub struct MonthlyActiveUser {
+    /// The user's ID.
+    pub user_id: UserId,
+    /// The time of the user's last request, updated at most once every hour.
+    pub last_active_at: PgTimestamp,
+}
+
+impl MonthlyActiveUser {
+    /// Record that the user has made a request.
+    pub fn record_activity(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        let now = get_now();
+
+        match MonthlyActiveUser::find(connection, user_id)? {
+            Some(ref active_user)
+                if now - active_user.last_active_at.0 < ACTIVITY_UPDATE_INTERVAL => Ok(()),
+            Some(_) => {
+                update(monthly_active_users::table.find(user_id))
+                    .set(monthly_active_users::last_active_at.eq(PgTimestamp(now)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok(())
+            }
+            None => {
+                let new_active_user = MonthlyActiveUser {
+                    user_id: user_id.clone(),
+                    last_active_at: PgTimestamp(now),
+                };
+
+                insert(&new_active_user.on_conflict_do_nothing())
+                    .into(monthly_active_users::table)
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Check whether the user has made a request within the last 30 days.
+    pub fn is_active(connection: &PgConnection, user_id: &UserId) -> Result<bool, ApiError> {
+        match MonthlyActiveUser::find(connection, user_id)? {
+            Some(active_user) => {
+                Ok(get_now() - active_user.last_active_at.0 < MONTHLY_ACTIVE_PERIOD)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Return the number of users that have made a request within the last 30 days.
+    pub fn count(connection: &PgConnection) -> Result<i64, ApiError> {
+        let active_since = PgTimestamp(get_now() - MONTHLY_ACTIVE_PERIOD);
+
+        monthly_active_users::table
+            .select(count_star())
+            .filter(monthly_active_users::last_active_at.ge(active_since))
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Check that a user who is not currently active may become active.
+    ///
+    /// Fails with `M_RESOURCE_LIMIT_EXCEEDED` if the server already has `max_mau` monthly active
+    /// users, telling the client when the least recently active user stops counting. Users who
+    /// are already active are always allowed.
+    pub fn check_limit(
+        connection: &PgConnection,
+        user_id: Option<&UserId>,
+        max_mau: Option<u64>,
+        admin_contact: Option<&String>,
+    ) -> Result<(), ApiError> {
+        let max_mau = match max_mau {
+            Some(max_mau) => max_mau as i64,
+            None => return Ok(()),
+        };
+
+        if let Some(user_id) = user_id {
+            if MonthlyActiveUser::is_active(connection, user_id)? {
+                return Ok(());
+            }
+        }
+
+        if MonthlyActiveUser::count(connection)? >= max_mau {
+            let retry_after = MonthlyActiveUser::time_until_next_expiry(connection)?;
+
+            return Err(
+                ApiError::resource_limit_exceeded(admin_contact.cloned())
+                    .with_retry_after(retry_after)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The time until the least recently active user no longer counts as monthly active.
+    fn time_until_next_expiry(connection: &PgConnection) -> Result<Duration, ApiError> {
+        let now = get_now();
+        let active_since = PgTimestamp(now - MONTHLY_ACTIVE_PERIOD);
+
+        let oldest: Option<PgTimestamp> = monthly_active_users::table
+            .select(min(monthly_active_users::last_active_at))
+            .filter(monthly_active_users::last_active_at.ge(active_since))
+            .first(connection)
+            .map_err(ApiError::from)?;
+
+        let expires_at = oldest.map_or(now, |oldest| oldest.0 + MONTHLY_ACTIVE_PERIOD);
+
+        Ok(Duration::from_millis(cmp::max(expires_at - now, 0) as u64))
+    }
+
+    /// Delete the entries of users that have not made a request within the last 30 days.
+    ///
+    /// Returns the number of deleted entries.
+    pub fn expire(connection: &PgConnection) -> Result<usize, ApiError> {
+        let active_since = PgTimestamp(get_now() - MONTHLY_ACTIVE_PERIOD);
+
+        let expired = monthly_active_users::table
+            .filter(monthly_active_users::last_active_at.lt(active_since));
+
+        delete(expired)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Look up the entry of a user.
+    fn find(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<MonthlyActiveUser>, ApiError> {
+        match monthly_active_users::table.find(user_id).first(connection) {
+            Ok(active_user) => Ok(Some(active_user)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! Storage and querying of presence lists.
 
 use std::cmp;
+use std::collections::HashSet;
 
 use diesel::{
     delete,
@@ -16,7 +17,7 @@ use diesel::expression::dsl::any;
 use diesel::pg::PgConnection;
 use ruma_events::EventType;
 use ruma_events::presence::{PresenceEvent, PresenceEventContent, PresenceState};
-use ruma_identifiers::UserId;
+use ruma_identifiers::{RoomId, UserId};
 
 use error::ApiError;
 use models::presence_status::{PresenceStatus, get_now};
@@ -37,11 +38,16 @@ pub struct PresenceList {
 
 impl PresenceList {
     /// Combines creations and deletions of multiple presence list entries.
+    ///
+    /// Fails if the presence list would end up with more than `max_size` entries, or if an
+    /// invited user shares no joined room with the user other than the `suppressed_room_ids`.
     pub fn update(
         connection: &PgConnection,
         user_id: &UserId,
         invite: &[UserId],
-        drop: Vec<UserId>
+        drop: Vec<UserId>,
+        max_size: usize,
+        suppressed_room_ids: &[RoomId],
     ) -> Result<(), ApiError> {
         connection.transaction::<(()), ApiError, _>(|| {
             let missing_user_ids = User::find_missing_users(
@@ -78,11 +84,26 @@ impl PresenceList {
                 )
             }
 
-            let room_ids = RoomMembership::find_room_ids_by_uid_and_state(
+            let mut observed_users: HashSet<UserId> = PresenceList::find_observed_users(
+                connection,
+                user_id
+            )?.into_iter().collect();
+            observed_users.extend(invite.iter().cloned());
+            for dropped_user in &drop {
+                observed_users.remove(dropped_user);
+            }
+            if observed_users.len() > max_size {
+                return Err(ApiError::list_limit_exceeded(format!(
+                    "The presence list cannot contain more than {} users.",
+                    max_size
+                )))
+            }
+
+            let room_ids: Vec<RoomId> = RoomMembership::find_room_ids_by_uid_and_state(
                 connection,
                 user_id,
                 "join"
-            )?;
+            )?.into_iter().filter(|room_id| !suppressed_room_ids.contains(room_id)).collect();
 
             let mut invites: Vec<PresenceList> = Vec::new();
             for observed_user in invite {
@@ -131,19 +152,102 @@ impl PresenceList {
         Ok(users)
     }
 
-    /// Return `PresenceEvent`'s for given `UserId`.
+    /// Get the `UserId`'s among `user_ids` who have the given `UserId` on their presence list.
+    pub fn find_observers_among(
+        connection: &PgConnection,
+        user_id: &UserId,
+        user_ids: &[UserId],
+    ) -> Result<Vec<UserId>, ApiError> {
+        let users: Vec<UserId> = presence_list::table
+            .filter(presence_list::user_id.eq(any(user_ids)))
+            .filter(presence_list::observed_user_id.eq(user_id))
+            .select(presence_list::user_id)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(users)
+    }
+
+    /// Get the `UserId`'s on the presence list of the given `UserId` whose presence it may see.
+    ///
+    /// Entries are authorized when they are read rather than only when they are created: an entry
+    /// is suspended while the users share no joined room other than the `suppressed_room_ids`, and
+    /// takes effect again once they do. If `requires_consent` is set, users who do not have the
+    /// given `UserId` on their own presence list are left out as well.
+    pub fn find_visible_observed_users(
+        connection: &PgConnection,
+        user_id: &UserId,
+        suppressed_room_ids: &[RoomId],
+        requires_consent: bool,
+    ) -> Result<Vec<UserId>, ApiError> {
+        let mut observed_users = PresenceList::find_observed_users(connection, user_id)?;
+
+        if requires_consent {
+            let observers = PresenceList::find_observers_among(
+                connection,
+                user_id,
+                &observed_users
+            )?;
+
+            // Users always consent to seeing their own presence.
+            observed_users.retain(|observed_user| {
+                observed_user == user_id || observers.contains(observed_user)
+            });
+        }
+
+        let sharing_users = RoomMembership::find_user_ids_sharing_rooms(
+            connection,
+            user_id,
+            &observed_users,
+            suppressed_room_ids
+        )?;
+
+        observed_users.retain(|observed_user| {
+            observed_user == user_id || sharing_users.contains(observed_user)
+        });
+
+        Ok(observed_users)
+    }
+
+    /// Return the presence statuses of the users on the presence list of the given `UserId` that
+    /// changed since `since`, see `find_visible_observed_users`.
+    pub fn find_statuses_by_uid(
+        connection: &PgConnection,
+        user_id: &UserId,
+        since: Option<i64>,
+        suppressed_room_ids: &[RoomId],
+        requires_consent: bool,
+    ) -> Result<Vec<PresenceStatus>, ApiError> {
+        let observed_users = PresenceList::find_visible_observed_users(
+            connection,
+            user_id,
+            suppressed_room_ids,
+            requires_consent
+        )?;
+
+        PresenceStatus::get_users(connection, &observed_users, since)
+    }
+
+    /// Return `PresenceEvent`'s for given `UserId`, see `find_statuses_by_uid`.
     pub fn find_events_by_uid(
         connection: &PgConnection,
         user_id: &UserId,
-        since: Option<i64>
+        since: Option<i64>,
+        suppressed_room_ids: &[RoomId],
+        requires_consent: bool,
     ) -> Result<(i64, Vec<PresenceEvent>), ApiError> {
         let mut presence_key = match since {
             Some(since) => since,
             None => 0,
         };
 
-        let observed_users = PresenceList::find_observed_users(connection, user_id)?;
-        let users_status = PresenceStatus::get_users(connection, &observed_users, since)?;
+        let users_status = PresenceList::find_statuses_by_uid(
+            connection,
+            user_id,
+            since,
+            suppressed_room_ids,
+            requires_consent
+        )?;
 
         let observed_users: Vec<UserId> = users_status.iter().map(|status| {
             status.user_id.clone()
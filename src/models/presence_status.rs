@@ -1,5 +1,8 @@
 //! Storage and querying of presence status.
 
+#[cfg(test)]
+use std::cell::Cell;
+
 use chrono::{Duration, NaiveDateTime, NaiveDate, UTC};
 use diesel::{
     insert,
@@ -17,10 +20,42 @@ use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
 use ruma_events::presence::PresenceState;
 use ruma_identifiers::{UserId, EventId};
+use serde_json::to_value;
 
 use error::ApiError;
+use models::federation_queue::{FederationQueueEntry, PRESENCE_EDU_TYPE};
+use models::room_membership::RoomMembership;
 use schema::presence_status;
 
+#[cfg(test)]
+thread_local! {
+    /// The number of milliseconds tests on the current thread have advanced the clock by.
+    static CLOCK_OFFSET: Cell<i64> = Cell::new(0);
+}
+
+/// The content of an `m.presence` EDU.
+#[derive(Debug, Serialize)]
+struct PresenceEduContent<'a> {
+    /// The presence updates.
+    push: Vec<PresenceEduPush<'a>>,
+}
+
+/// A single presence update within an `m.presence` EDU.
+#[derive(Debug, Serialize)]
+struct PresenceEduPush<'a> {
+    /// The user whose presence changed.
+    user_id: &'a UserId,
+    /// The new presence state.
+    presence: &'a str,
+    /// An optional status message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_msg: Option<&'a String>,
+    /// The time in milliseconds since the last activity of the user.
+    last_active_ago: i64,
+    /// Whether the user is currently active.
+    currently_active: bool,
+}
+
 /// A Matrix presence status, not saved yet.
 #[derive(Debug, Clone, Insertable)]
 #[table_name = "presence_status"]
@@ -33,6 +68,8 @@ pub struct NewPresenceStatus {
     pub presence: String,
     /// A possible status message from the user.
     pub status_msg: Option<String>,
+    /// Whether the presence is kept as is when the user is idle.
+    pub sticky: bool,
     /// Timestamp of the last update.
     pub updated_at: PgTimestamp,
 }
@@ -50,6 +87,8 @@ pub struct PresenceStatus {
     pub presence: String,
     /// A possible status message from the user.
     pub status_msg: Option<String>,
+    /// Whether the presence is kept as is when the user is idle.
+    pub sticky: bool,
     /// Timestamp of the last update.
     pub updated_at: PgTimestamp,
 }
@@ -57,7 +96,27 @@ pub struct PresenceStatus {
 /// Return current time in milliseconds
 pub fn get_now() -> i64 {
     let now = UTC::now().naive_utc();
-    get_milliseconds(now)
+    get_milliseconds(now) + clock_offset()
+}
+
+/// Move the clock of the current thread forward, so tests do not have to wait for timeouts.
+///
+/// Only `get_now` is affected, not timestamps set by the database.
+#[cfg(test)]
+pub fn advance_clock(milliseconds: i64) {
+    CLOCK_OFFSET.with(|offset| offset.set(offset.get() + milliseconds));
+}
+
+/// The number of milliseconds the clock was advanced by.
+#[cfg(test)]
+fn clock_offset() -> i64 {
+    CLOCK_OFFSET.with(Cell::get)
+}
+
+/// The clock is never advanced outside of tests.
+#[cfg(not(test))]
+fn clock_offset() -> i64 {
+    0
 }
 
 /// Return `time` in milliseconds with a same epoch as PostgreSQL.
@@ -68,13 +127,38 @@ pub fn get_milliseconds(time: NaiveDateTime) -> i64 {
 }
 
 impl PresenceStatus {
-    /// Update or insert a presence status entry.
+    /// Update or insert a presence status entry for an action of the user.
+    ///
+    /// If `sticky` is `None`, an existing entry keeps its stickiness.
     pub fn upsert(
         connection: &PgConnection,
         homeserver_domain: &str,
         user_id: &UserId,
         presence: Option<PresenceState>,
-        status_msg: Option<String>
+        status_msg: Option<String>,
+        sticky: Option<bool>
+    ) -> Result<(), ApiError> {
+        PresenceStatus::upsert_last_active(
+            connection,
+            homeserver_domain,
+            user_id,
+            presence,
+            status_msg,
+            sticky,
+            get_now()
+        )
+    }
+
+    /// Update or insert a presence status entry of a user who was last active at `last_active`,
+    /// in milliseconds since the PostgreSQL epoch.
+    fn upsert_last_active(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        user_id: &UserId,
+        presence: Option<PresenceState>,
+        status_msg: Option<String>,
+        sticky: Option<bool>,
+        last_active: i64
     ) -> Result<(), ApiError> {
         let event_id = &EventId::new(homeserver_domain).map_err(ApiError::from)?;
 
@@ -88,23 +172,84 @@ impl PresenceStatus {
                 }
             };
 
+            let sticky = match sticky {
+                Some(sticky) => sticky,
+                None => status.as_ref().map(|status| status.sticky).unwrap_or(false),
+            };
+
+            let changed = match status {
+                Some(ref status) => status.presence != presence || status.status_msg != status_msg,
+                None => true,
+            };
+
             match status {
-                Some(mut status) => status.update(connection, presence, status_msg, event_id),
-                None => PresenceStatus::create(connection, user_id, presence, status_msg, event_id),
+                Some(mut status) => status.update(connection, presence.clone(), status_msg.clone(), sticky, event_id)?,
+                None => PresenceStatus::create(connection, user_id, presence.clone(), status_msg.clone(), sticky, event_id)?,
+            }
+
+            if changed {
+                PresenceStatus::enqueue_federation_edus(
+                    connection,
+                    homeserver_domain,
+                    user_id,
+                    &presence,
+                    status_msg.as_ref(),
+                    last_active
+                )?;
             }
+
+            Ok(())
         }).map_err(ApiError::from)
     }
 
+    /// Queue an `m.presence` EDU for every remote homeserver sharing a room with the user.
+    ///
+    /// The EDUs are only queued here, so the caller's transaction stays short. Older pending
+    /// presence EDUs of the same user are replaced.
+    fn enqueue_federation_edus(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        user_id: &UserId,
+        presence: &str,
+        status_msg: Option<&String>,
+        last_active: i64
+    ) -> Result<(), ApiError> {
+        let destinations = RoomMembership::find_remote_servers(connection, user_id, homeserver_domain)?;
+
+        if destinations.is_empty() {
+            return Ok(());
+        }
+
+        let push = PresenceEduPush {
+            user_id: user_id,
+            presence: presence,
+            status_msg: status_msg,
+            last_active_ago: get_now() - last_active,
+            currently_active: presence == "online",
+        };
+        let content = to_value(PresenceEduContent { push: vec![push] }).map_err(ApiError::from)?;
+
+        FederationQueueEntry::enqueue_edus(
+            connection,
+            &destinations,
+            PRESENCE_EDU_TYPE,
+            &user_id.to_string(),
+            &content
+        )
+    }
+
     /// Update a presence status entry.
     fn update(
         &mut self,
         connection: &PgConnection,
         presence: String,
         status_msg: Option<String>,
+        sticky: bool,
         event_id: &EventId
     ) -> Result<(), ApiError> {
         self.presence = presence;
         self.status_msg = status_msg;
+        self.sticky = sticky;
         self.event_id = event_id.clone();
         self.updated_at = PgTimestamp(get_now());
 
@@ -120,6 +265,7 @@ impl PresenceStatus {
         user_id: &UserId,
         presence: String,
         status_msg: Option<String>,
+        sticky: bool,
         event_id: &EventId
     ) -> Result<(), ApiError> {
         let new_status = NewPresenceStatus {
@@ -127,6 +273,7 @@ impl PresenceStatus {
             event_id: event_id.clone(),
             presence: presence,
             status_msg: status_msg,
+            sticky: sticky,
             updated_at: PgTimestamp(get_now()),
         };
         insert(&new_status)
@@ -136,6 +283,40 @@ impl PresenceStatus {
         Ok(())
     }
 
+    /// Mark users as offline whose presence has not been updated for `idle_timeout` milliseconds.
+    ///
+    /// Sticky presence is left alone. Being marked as offline is not an action of the users, so
+    /// they stay last active at their previous update. The idle users are looked up and marked in
+    /// one transaction, so a failure marks none of them instead of only some. Returns the number
+    /// of users marked as offline.
+    pub fn set_idle_offline(connection: &PgConnection, homeserver_domain: &str, idle_timeout: i64)
+    -> Result<usize, ApiError> {
+        let idle_since = PgTimestamp(get_now() - idle_timeout);
+
+        connection.transaction::<usize, ApiError, _>(|| {
+            let idle_statuses: Vec<PresenceStatus> = presence_status::table
+                .filter(presence_status::presence.ne("offline"))
+                .filter(presence_status::sticky.eq(false))
+                .filter(presence_status::updated_at.lt(idle_since))
+                .get_results(connection)
+                .map_err(ApiError::from)?;
+
+            for status in &idle_statuses {
+                PresenceStatus::upsert_last_active(
+                    connection,
+                    homeserver_domain,
+                    &status.user_id,
+                    Some(PresenceState::Offline),
+                    status.status_msg.clone(),
+                    None,
+                    status.updated_at.0
+                )?;
+            }
+
+            Ok(idle_statuses.len())
+        }).map_err(ApiError::from)
+    }
+
     /// Return `PresenceStatus` for given `UserId`.
     pub fn find_by_uid(
         connection: &PgConnection,
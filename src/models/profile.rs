@@ -1,11 +1,13 @@
 //! Matrix profile.
 
 use diesel::{
+    delete,
     insert,
     Connection,
     ExpressionMethods,
     FilterDsl,
     FindDsl,
+    ExecuteDsl,
     LoadDsl,
     SaveChangesDsl,
 };
@@ -54,7 +56,7 @@ impl Profile {
                 }
             };
 
-            PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
+            PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None, None)?;
             Ok(profile)
         }).map_err(ApiError::from)
     }
@@ -81,7 +83,7 @@ impl Profile {
                 }
             };
 
-            PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
+            PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None, None)?;
             Ok(profile)
         }).map_err(ApiError::from)
     }
@@ -119,6 +121,7 @@ impl Profile {
                 user_id: user_id.clone(),
                 sender: user_id.clone(),
                 membership: "join".to_string(),
+                reason: None,
             };
 
             room_membership.update(connection, homeserver_domain, options)?;
@@ -135,6 +138,15 @@ impl Profile {
             .map_err(ApiError::from)
     }
 
+    /// Delete the `Profile` of a user, if there is one.
+    pub fn delete(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        delete(profiles::table.find(user_id))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
     /// Return `Profile` for given `UserId`.
     pub fn find_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Option<Profile>, ApiError> {
         let profile = profiles::table
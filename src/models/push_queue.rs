@@ -0,0 +1,134 @@
+//! Queue of push notifications waiting to be delivered.
+//!
+//! Notifications are rendered when they are queued and posted to the push gateways by a
+//! background worker, so requests do not wait for the gateways. Notifications for a pusher that
+//! is backing off wait until the backoff has passed, and are given up on after a day.
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::push_queue;
+
+/// The time, in milliseconds, after which queued notifications are given up on.
+const PUSH_LIFETIME: i64 = 24 * 60 * 60 * 1000;
+
+/// A queued push notification, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "push_queue"]
+pub struct NewQueuedPush {
+    /// The user of the pusher.
+    pub user_id: UserId,
+    /// The ID of the application of the pusher.
+    pub app_id: String,
+    /// The JSON body to post to the push gateway.
+    pub body: String,
+    /// The time from which on the notification may be posted.
+    pub next_attempt_at: PgTimestamp,
+    /// The time the notification was queued.
+    pub created_at: PgTimestamp,
+}
+
+/// A queued push notification.
+#[derive(Debug, Clone, Queryable)]
+pub struct QueuedPush {
+    /// The ID of the entry.
+    pub id: i64,
+    /// The user of the pusher.
+    pub user_id: UserId,
+    /// The ID of the application of the pusher.
+    pub app_id: String,
+    /// The JSON body to post to the push gateway.
+    pub body: String,
+    /// The time from which on the notification may be posted.
+    pub next_attempt_at: PgTimestamp,
+    /// The time the notification was queued.
+    pub created_at: PgTimestamp,
+}
+
+impl QueuedPush {
+    /// Queue a notification for the pusher of `user_id` with the ID `app_id`.
+    pub fn enqueue(connection: &PgConnection, user_id: &UserId, app_id: &str, body: &str)
+    -> Result<(), ApiError> {
+        let now = get_now();
+        let new_push = NewQueuedPush {
+            user_id: user_id.clone(),
+            app_id: app_id.to_string(),
+            body: body.to_string(),
+            next_attempt_at: PgTimestamp(now),
+            created_at: PgTimestamp(now),
+        };
+
+        insert(&new_push)
+            .into(push_queue::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return up to `limit` queued notifications that may be posted now, oldest first.
+    pub fn find_due(connection: &PgConnection, limit: i64)
+    -> Result<Vec<QueuedPush>, ApiError> {
+        push_queue::table
+            .filter(push_queue::next_attempt_at.le(PgTimestamp(get_now())))
+            .order(push_queue::id.asc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Keep the queued notifications for the pusher of `user_id` with the ID `app_id` from being
+    /// posted before the time `until`, e.g. the end of the pusher's backoff.
+    pub fn postpone_for_pusher(
+        connection: &PgConnection,
+        user_id: &UserId,
+        app_id: &str,
+        until: i64,
+    ) -> Result<(), ApiError> {
+        let queued_pushes = push_queue::table
+            .filter(push_queue::user_id.eq(user_id))
+            .filter(push_queue::app_id.eq(app_id))
+            .filter(push_queue::next_attempt_at.lt(PgTimestamp(until)));
+
+        update(queued_pushes)
+            .set(push_queue::next_attempt_at.eq(PgTimestamp(until)))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Remove the notification from the queue, once it is delivered or dropped.
+    pub fn delete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(push_queue::table.find(self.id))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Give up on the notifications queued more than `PUSH_LIFETIME` ago, e.g. for a pusher that
+    /// has been failing for that long. Returns the number of notifications deleted.
+    pub fn delete_expired(connection: &PgConnection) -> Result<usize, ApiError> {
+        let expired_before = PgTimestamp(get_now() - PUSH_LIFETIME);
+
+        delete(push_queue::table.filter(push_queue::created_at.le(expired_before)))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -1,8 +1,11 @@
 //! Matrix pusher.
 
+use std::cmp::min;
+
 use diesel::{
     delete,
     insert,
+    update,
     Connection,
     ExecuteDsl,
     ExpressionMethods,
@@ -12,12 +15,23 @@ use diesel::{
     SaveChangesDsl,
 };
 use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
 use ruma_identifiers::UserId;
 
 use error::ApiError;
+use models::threepid::Threepid;
 use schema::pushers;
 
+/// The kind of pushers that send digest emails to the address in their push key.
+pub const EMAIL_PUSHER_KIND: &'static str = "email";
+
+/// The number of milliseconds notifications are not sent to a pusher after its first failure.
+const INITIAL_BACKOFF: i64 = 30 * 1000;
+
+/// The maximum number of milliseconds notifications are not sent to a failing pusher.
+const MAX_BACKOFF: i64 = 60 * 60 * 1000;
+
 /// Data need for kind is http.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct PusherData {
@@ -30,7 +44,8 @@ pub struct PusherData {
 pub struct PusherOptions {
     /// The preferred language for receiving notifications (e.g. 'en' or 'en-US')
     pub lang: String,
-    /// The kind of pusher. "http" is a pusher that sends HTTP pokes.
+    /// The kind of pusher. "http" is a pusher that sends HTTP pokes, "email" one that sends
+    /// digest emails to the email address in `pushkey`.
     pub kind: String,
     /// A dictionary of information for the pusher implementation itself.
     pub data: PusherData,
@@ -114,10 +129,44 @@ pub struct Pusher {
     pub pushkey: String,
     /// A string that will allow the user to identify what application owns this pusher.
     pub app_display_name: String,
+    /// When a notification was last delivered to the push gateway.
+    pub last_success_at: Option<PgTimestamp>,
+    /// When the delivery of a notification last failed.
+    pub last_failure_at: Option<PgTimestamp>,
+    /// Why the delivery of a notification last failed.
+    pub last_failure_reason: Option<String>,
+    /// The HTTP status code of the push gateway's response to the last failed delivery, if it
+    /// responded at all.
+    pub last_failure_status: Option<i32>,
+    /// The number of failed deliveries since the last successful one.
+    pub consecutive_failures: i32,
+    /// Until when no notifications are sent to the pusher, because its deliveries failed.
+    pub backoff_until: Option<PgTimestamp>,
+}
+
+/// The delivery statistics of a pusher, for inspection by administrators.
+///
+/// Times are in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct DeliveryStats {
+    /// When a notification was last delivered to the push gateway.
+    pub last_success_ts: Option<i64>,
+    /// When the delivery of a notification last failed.
+    pub last_failure_ts: Option<i64>,
+    /// Why the delivery of a notification last failed.
+    pub last_failure_reason: Option<String>,
+    /// The HTTP status code of the push gateway's response to the last failed delivery.
+    pub last_failure_status: Option<i32>,
+    /// The number of failed deliveries since the last successful one.
+    pub consecutive_failures: i32,
+    /// Until when no notifications are sent to the pusher.
+    pub backoff_until_ts: Option<i64>,
 }
 
 impl Pusher {
     /// Update or Create a `Pusher` entry based on `PusherOptions`.
+    ///
+    /// A pusher that is registered again with the same push key keeps its delivery statistics.
     pub fn upsert(
         connection: &PgConnection,
         user_id: &UserId,
@@ -127,14 +176,13 @@ impl Pusher {
             if !options.is_valid() {
                 return Err(ApiError::bad_json("If kind is http, data.url shouldn't be null.".to_string()))
             }
+            if options.kind == EMAIL_PUSHER_KIND {
+                Pusher::verify_email_address(connection, user_id, &options.pushkey)?;
+            }
+            let previous = Pusher::find(connection, &user_id, &options.app_id)?;
             match options.append {
                 true => {
-                    let pusher = Pusher::find(
-                        connection,
-                        &user_id,
-                        &options.app_id
-                    )?;
-                    match pusher {
+                    match previous.clone() {
                         Some(mut pusher) => {
                             pusher.update(connection, options.clone())?;
                             return Ok(pusher)
@@ -150,10 +198,25 @@ impl Pusher {
                     )?;
                 },
             }
-            Ok(Pusher::create(connection, user_id.clone(), options.clone())?)
+            Ok(Pusher::create(connection, user_id.clone(), options.clone(), previous.as_ref())?)
         }).map_err(ApiError::from)
     }
 
+    /// Check that the push key of an email pusher is an email address bound to the user.
+    fn verify_email_address(
+        connection: &PgConnection,
+        user_id: &UserId,
+        address: &str
+    ) -> Result<(), ApiError> {
+        match Threepid::find_by_address(connection, "email", address)? {
+            Some(ref threepid) if threepid.user_id == *user_id => Ok(()),
+            _ => Err(ApiError::threepid_not_found(
+                "The pushkey of an email pusher must be an email address bound to the account."
+                    .to_string()
+            )),
+        }
+    }
+
     /// Delete a `Pusher`
     pub fn delete(
         connection: &PgConnection,
@@ -184,12 +247,18 @@ impl Pusher {
         }
     }
 
-    /// Create a new `Pusher`.
+    /// Create a new `Pusher`, with the delivery statistics of the `previous` pusher of the user
+    /// with the same app ID if it had the same push key.
     fn create(
         connection: &PgConnection,
         user_id: UserId,
-        options: PusherOptions
+        options: PusherOptions,
+        previous: Option<&Pusher>
     ) -> Result<Pusher, ApiError> {
+        let previous = match previous {
+            Some(pusher) if pusher.pushkey == options.pushkey => Some(pusher),
+            _ => None,
+        };
         let new_pusher = Pusher {
             user_id: user_id,
             lang: options.lang,
@@ -200,6 +269,12 @@ impl Pusher {
             pushkey: options.pushkey,
             app_display_name: options.app_display_name,
             url: options.data.url,
+            last_success_at: previous.and_then(|pusher| pusher.last_success_at),
+            last_failure_at: previous.and_then(|pusher| pusher.last_failure_at),
+            last_failure_reason: previous.and_then(|pusher| pusher.last_failure_reason.clone()),
+            last_failure_status: previous.and_then(|pusher| pusher.last_failure_status),
+            consecutive_failures: previous.map_or(0, |pusher| pusher.consecutive_failures),
+            backoff_until: previous.and_then(|pusher| pusher.backoff_until),
         };
 
         insert(&new_pusher)
@@ -245,4 +320,98 @@ impl Pusher {
             .filter(pushers::user_id.eq(user_id))
             .get_results(connection).map_err(ApiError::from)
     }
+
+    /// Whether the last delivery of a notification to the pusher failed.
+    pub fn is_failing(&self) -> bool {
+        self.consecutive_failures > 0
+    }
+
+    /// Whether notifications should not be sent to the pusher at the time `now`, because its
+    /// deliveries failed recently.
+    pub fn is_backing_off(&self, now: i64) -> bool {
+        self.backoff_until.map_or(false, |backoff_until| now < backoff_until.0)
+    }
+
+    /// The delivery statistics of the pusher.
+    pub fn delivery_stats(&self) -> DeliveryStats {
+        DeliveryStats {
+            last_success_ts: self.last_success_at.map(|timestamp| timestamp.0),
+            last_failure_ts: self.last_failure_at.map(|timestamp| timestamp.0),
+            last_failure_reason: self.last_failure_reason.clone(),
+            last_failure_status: self.last_failure_status,
+            consecutive_failures: self.consecutive_failures,
+            backoff_until_ts: self.backoff_until.map(|timestamp| timestamp.0),
+        }
+    }
+
+    /// Record that a notification was delivered to the push gateway at the time `now`.
+    ///
+    /// The details of the last failure are kept for debugging.
+    pub fn record_success(&mut self, connection: &PgConnection, now: i64)
+    -> Result<(), ApiError> {
+        self.last_success_at = Some(PgTimestamp(now));
+        self.consecutive_failures = 0;
+        self.backoff_until = None;
+
+        update(pushers::table.find((&self.user_id, &self.app_id)))
+            .set((
+                pushers::last_success_at.eq(self.last_success_at),
+                pushers::consecutive_failures.eq(self.consecutive_failures),
+                pushers::backoff_until.eq(self.backoff_until),
+            ))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Record that the delivery of a notification failed at the time `now`, with the HTTP status
+    /// code of the push gateway's response if there was one.
+    ///
+    /// No notifications are sent to the pusher for a while afterwards, twice as long after every
+    /// consecutive failure. The failures are counted in the database, so concurrent failures are
+    /// all counted.
+    pub fn record_failure(
+        &mut self,
+        connection: &PgConnection,
+        now: i64,
+        status: Option<u16>,
+        reason: &str,
+    ) -> Result<(), ApiError> {
+        let result = update(pushers::table.find((&self.user_id, &self.app_id)))
+            .set((
+                pushers::last_failure_at.eq(Some(PgTimestamp(now))),
+                pushers::last_failure_reason.eq(Some(reason.to_string())),
+                pushers::last_failure_status.eq(status.map(i32::from)),
+                pushers::consecutive_failures.eq(pushers::consecutive_failures + 1),
+            ))
+            .get_result::<Pusher>(connection);
+
+        let mut pusher = match result {
+            Ok(pusher) => pusher,
+            // The pusher was deleted in the meantime.
+            Err(DieselError::NotFound) => return Ok(()),
+            Err(err) => return Err(ApiError::from(err)),
+        };
+
+        let backoff = (1..pusher.consecutive_failures)
+            .fold(INITIAL_BACKOFF, |backoff, _| min(backoff * 2, MAX_BACKOFF));
+
+        pusher.backoff_until = Some(PgTimestamp(now + backoff));
+
+        // A later failure sets a longer backoff of its own.
+        let unchanged = pushers::table
+            .filter(pushers::user_id.eq(&pusher.user_id))
+            .filter(pushers::app_id.eq(&pusher.app_id))
+            .filter(pushers::consecutive_failures.eq(pusher.consecutive_failures));
+
+        update(unchanged)
+            .set(pushers::backoff_until.eq(pusher.backoff_until))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        *self = pusher;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,71 @@
+//! Nonces for shared-secret registration.
+
+use diesel::{
+    delete,
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LoadDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+
+use crypto::generate_nonce;
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::registration_nonces;
+
+/// The number of milliseconds a nonce can be used for after it was issued.
+const NONCE_LIFETIME: i64 = 60_000;
+
+/// A nonce handed out for shared-secret registration.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "registration_nonces"]
+pub struct RegistrationNonce {
+    /// The nonce as a hex string.
+    pub nonce: String,
+    /// The time the nonce was issued.
+    pub created_at: PgTimestamp,
+}
+
+impl RegistrationNonce {
+    /// Issue a new nonce.
+    pub fn create(connection: &PgConnection) -> Result<RegistrationNonce, ApiError> {
+        let new_nonce = RegistrationNonce {
+            nonce: generate_nonce()?,
+            created_at: PgTimestamp(get_now()),
+        };
+
+        insert(&new_nonce)
+            .into(registration_nonces::table)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Use up a nonce.
+    ///
+    /// Returns `false` if the nonce was never issued, has already been used or has expired. Of
+    /// concurrent requests with the same nonce, only one deletes it and gets `true`.
+    pub fn consume(connection: &PgConnection, nonce: &str) -> Result<bool, ApiError> {
+        let issued_after = PgTimestamp(get_now() - NONCE_LIFETIME);
+        let valid_nonce = registration_nonces::table
+            .filter(registration_nonces::nonce.eq(nonce))
+            .filter(registration_nonces::created_at.ge(issued_after));
+
+        let rows = delete(valid_nonce).execute(connection).map_err(ApiError::from)?;
+
+        Ok(rows == 1)
+    }
+
+    /// Delete the nonces that expired without being used. Returns the number of nonces deleted.
+    pub fn delete_expired(connection: &PgConnection) -> Result<usize, ApiError> {
+        let expired_before = PgTimestamp(get_now() - NONCE_LIFETIME);
+        let expired_nonces = registration_nonces::table
+            .filter(registration_nonces::created_at.lt(expired_before));
+
+        delete(expired_nonces)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -3,7 +3,20 @@
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
-use diesel::{Connection, ExecuteDsl, ExpressionMethods, FilterDsl, FindDsl, LoadDsl, OrderDsl, insert};
+use diesel::{
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    GroupByDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    insert,
+    update,
+};
+use diesel::expression::dsl::{any, max};
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
@@ -26,17 +39,32 @@ use ruma_events::room::name::{NameEvent, NameEventContent};
 use ruma_events::room::power_levels::{PowerLevelsEvent, PowerLevelsEventContent};
 use ruma_events::room::topic::{TopicEvent, TopicEventContent};
 use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use serde_json::{Map, Value, from_str};
 
+use config::{DefaultPowerLevels, StateTemplate};
 use error::ApiError;
+use event_validation::new_state_event;
 use models::event::{Event, NewEvent};
 use models::room_alias::{NewRoomAlias, RoomAlias};
 use models::room_membership::RoomMembership;
 use schema::{events, rooms};
+use util::room_state::{StateKey, state_key};
+
+/// The type of the state event listing the pinned events of a room.
+pub const PINNED_EVENTS_EVENT_TYPE: &'static str = "m.room.pinned_events";
+
+/// The power level required to pin events in rooms created with the `public_chat` preset, so
+/// that only moderators decide what every member sees pinned.
+const PUBLIC_CHAT_PINNED_EVENTS_LEVEL: u64 = 50;
 
 /// Options provided by the user to customize the room upon creation.
 pub struct CreationOptions {
     /// An initial alias for the room.
     pub alias: Option<String>,
+    /// The power levels used if neither `default_state` nor `initial_state` sets them.
+    pub default_power_levels: DefaultPowerLevels,
+    /// State events from the server configuration, set after the events of the preset.
+    pub default_state: Vec<StateTemplate>,
     /// Whether or not the room should be federated.
     pub federate: Option<bool>,
     /// A list of state events to set in the new room.
@@ -47,6 +75,9 @@ pub struct CreationOptions {
     pub name: Option<String>,
     /// A convenience parameter for setting a few default state events.
     pub preset: RoomPreset,
+    /// Extra keys of the content of the m.room.create event, e.g. the room's *type*. Keys the
+    /// server sets itself, like *creator*, take precedence.
+    pub creation_content: Map<String, Value>,
     /// An initial topic for the room.
     pub topic: Option<String>,
 }
@@ -61,6 +92,8 @@ pub struct NewRoom {
     pub user_id: UserId,
     /// Whether or not the room is visible in the directory.
     pub public: bool,
+    /// The type of the room as set in its m.room.create event, e.g. *m.space*.
+    pub room_type: Option<String>,
 }
 
 /// A Matrix room.
@@ -74,6 +107,10 @@ pub struct Room {
     pub public: bool,
     /// The time the room was created.
     pub created_at: PgTimestamp,
+    /// The type of the room as set in its m.room.create event, e.g. *m.space*.
+    pub room_type: Option<String>,
+    /// The number of users who are joined to the room, kept up to date by the membership hook.
+    pub joined_member_count: i64,
 }
 
 /// A convenience parameter for setting a few default state events.
@@ -82,7 +119,8 @@ pub enum RoomPreset {
     /// `join_rules` is set to `invite` and `history_visibility` is set to `shared`.
     #[serde(rename="private_chat")]
     PrivateChat,
-    /// `join_rules` is set to `public` and `history_visibility` is set to `shared`.
+    /// `join_rules` is set to `public` and `history_visibility` is set to `shared`. Unless the
+    /// power levels are set otherwise, pinning events requires moderator level.
     #[serde(rename="public_chat")]
     PublicChat,
     /// Same as `PrivateChat`, but all initial invitees get the same power level as the creator.
@@ -91,7 +129,7 @@ pub enum RoomPreset {
 }
 
 /// Indicates whether or not that the room will be shown in the published room list.
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum RoomVisibility {
     /// The room will be private.
     #[serde(rename="private")]
@@ -106,9 +144,10 @@ impl Room {
     ///
     /// The creation order of the events should be the following:
     /// 1. Events set by presets.
-    /// 2. Events listed in initial_state, in the order that they are listed.
-    /// 3. Events implied by name and topic.
-    /// 4. Invite events implied by invite and invite_3pid.
+    /// 2. Events set by the server's default state templates.
+    /// 3. Events listed in initial_state, in the order that they are listed.
+    /// 4. Events implied by name and topic.
+    /// 5. Invite events implied by invite and invite_3pid.
     pub fn create(
         connection: &PgConnection,
         new_room: &NewRoom,
@@ -123,7 +162,7 @@ impl Room {
 
             let mut new_events = Vec::new();
 
-            let new_create_event: NewEvent = CreateEvent {
+            let mut new_create_event: NewEvent = CreateEvent {
                 content: CreateEventContent {
                     creator: new_room.user_id.clone(),
                     federate: creation_options.federate,
@@ -137,11 +176,25 @@ impl Room {
                 user_id: new_room.user_id.clone(),
             }.try_into()?;
 
+            // ruma-events only knows about some keys of the content, so add the others by hand.
+            if !creation_options.creation_content.is_empty() {
+                let mut content: Map<String, Value> = from_str(&new_create_event.content)?;
+
+                for (key, value) in &creation_options.creation_content {
+                    if !content.contains_key(key) {
+                        content.insert(key.clone(), value.clone());
+                    }
+                }
+
+                new_create_event.set_content(&Value::Object(content))?;
+            }
+
             new_events.push(new_create_event);
 
             let mut is_canonical_alias_set = false;
             let mut is_history_visibility_set = false;
             let mut is_power_levels_set = false;
+            let mut is_public_chat = false;
             let mut is_trusted_private_chat = false;
             let mut new_room_aliases = Vec::new();
 
@@ -161,6 +214,8 @@ impl Room {
                     new_events.push(new_join_rules_event);
                 },
                 RoomPreset::PublicChat => {
+                    is_public_chat = true;
+
                     let new_join_rules_event: NewEvent = JoinRulesEvent {
                         content: JoinRulesEventContent { join_rule: JoinRule::Public },
                         event_id: EventId::new(homeserver_domain)?,
@@ -192,6 +247,43 @@ impl Room {
                 }
             }
 
+            for template in &creation_options.default_state {
+                let event_type = EventType::from(template.event_type.as_ref());
+                let mut content = template.content.clone();
+
+                match event_type {
+                    EventType::RoomHistoryVisibility => is_history_visibility_set = true,
+                    EventType::RoomPowerLevels => {
+                        is_power_levels_set = true;
+
+                        let mut users = vec![room.user_id.clone()];
+
+                        if is_trusted_private_chat {
+                            if let Some(ref invite_list) = creation_options.invite_list {
+                                users.extend(invite_list.iter().cloned());
+                            }
+                        }
+
+                        // The template was validated as power levels, so it has a map of users.
+                        for user in users {
+                            content["users"][user.to_string()] = Value::from(100);
+                        }
+                    }
+                    _ => {}
+                }
+
+                let new_template_event = new_state_event(
+                    &event_type,
+                    content,
+                    &template.state_key,
+                    &EventId::new(homeserver_domain)?,
+                    &room.id,
+                    &room.user_id,
+                )?;
+
+                new_events.push(new_template_event);
+            }
+
             if let Some(ref name) = creation_options.name {
                 let new_name_event: NewEvent = NameEvent {
                     content: NameEventContent {
@@ -416,17 +508,27 @@ impl Room {
                     }
                 }
 
+                let levels = &creation_options.default_power_levels;
+                let mut event_levels = HashMap::new();
+
+                if is_public_chat {
+                    event_levels.insert(
+                        EventType::Custom(PINNED_EVENTS_EVENT_TYPE.to_string()),
+                        PUBLIC_CHAT_PINNED_EVENTS_LEVEL,
+                    );
+                }
+
                 let new_power_levels_event: NewEvent = PowerLevelsEvent {
                     content: PowerLevelsEventContent {
-                        ban: 50,
-                        events: HashMap::new(),
-                        events_default: 0,
-                        invite: 50,
-                        kick: 50,
-                        redact: 50,
-                        state_default: 0,
+                        ban: levels.ban,
+                        events: event_levels,
+                        events_default: levels.events_default,
+                        invite: levels.invite,
+                        kick: levels.kick,
+                        redact: levels.redact,
+                        state_default: levels.state_default,
                         users: user_power,
-                        users_default: 0,
+                        users_default: levels.users_default,
                     },
                     event_id: EventId::new(homeserver_domain)?,
                     event_type: EventType::RoomPowerLevels,
@@ -505,22 +607,95 @@ impl Room {
                 Ok(power_levels_event.content)
             }
             Err(error) => match error {
-                DieselError::NotFound => Ok(PowerLevelsEventContent {
-                    ban: 50,
-                    events: HashMap::new(),
-                    events_default: 0,
-                    invite: 50,
-                    kick: 50,
-                    redact: 50,
-                    state_default: 0,
-                    users: HashMap::new(),
-                    users_default: 0,
-                }),
+                DieselError::NotFound => Ok(Room::default_power_levels()),
                 _ => Err(error.into()),
             },
         }
     }
 
+    /// The power levels of a room without a power levels event, according to the specification.
+    pub fn default_power_levels() -> PowerLevelsEventContent {
+        PowerLevelsEventContent {
+            ban: 50,
+            events: HashMap::new(),
+            events_default: 0,
+            invite: 50,
+            kick: 50,
+            redact: 50,
+            state_default: 0,
+            users: HashMap::new(),
+            users_default: 0,
+        }
+    }
+
+    /// Look up the current contents of the given state events of a room with a single query.
+    ///
+    /// The contents are keyed by event type and state key. State events the room does not have
+    /// are left out.
+    pub fn get_state_contents(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        keys: &[(EventType, &str)],
+    ) -> Result<HashMap<StateKey, Value>, ApiError> {
+        let mut contents = HashMap::new();
+
+        if keys.is_empty() {
+            return Ok(contents);
+        }
+
+        let wanted: Vec<StateKey> = keys.iter()
+            .map(|&(ref event_type, key)| state_key(event_type, key))
+            .collect();
+        let event_types: Vec<String> = wanted.iter().map(|&(ref event_type, _)| event_type.clone())
+            .collect();
+        let state_keys: Vec<String> = wanted.iter().map(|&(_, ref key)| key.clone()).collect();
+
+        let ordering = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(any(event_types)))
+            .filter(events::state_key.eq(any(state_keys)))
+            .group_by((events::event_type, events::state_key));
+
+        let events: Vec<Event> = events::table
+            .filter(events::ordering.nullable().eq(any(&ordering)))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        // The query matches every combination of the types and state keys, not only the pairs.
+        for event in events {
+            let key = (event.event_type, event.state_key.unwrap_or_else(String::new));
+
+            if wanted.contains(&key) {
+                contents.insert(key, from_str(&event.content).map_err(ApiError::from)?);
+            }
+        }
+
+        Ok(contents)
+    }
+
+    /// Add `delta` to the number of joined members of a room.
+    pub fn change_joined_member_count(connection: &PgConnection, room_id: &RoomId, delta: i64)
+    -> Result<(), ApiError> {
+        update(rooms::table.find(room_id))
+            .set(rooms::joined_member_count.eq(rooms::joined_member_count + delta))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Set whether a room is visible in the directory.
+    pub fn set_public(connection: &PgConnection, room_id: &RoomId, public: bool)
+    -> Result<(), ApiError> {
+        update(rooms::table.find(room_id))
+            .set(rooms::public.eq(public))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
     /// Look up a `Room` given the `RoomId`.
     pub fn find(connection: &PgConnection, room_id: &RoomId)
     -> Result<Option<Room>, ApiError> {
@@ -534,4 +709,98 @@ impl Room {
             Err(err) => Err(ApiError::from(err)),
         }
     }
+
+    /// Look up the `Room`s with the given `RoomId`s in a single query, leaving out unknown rooms.
+    pub fn find_many(connection: &PgConnection, room_ids: &[RoomId])
+    -> Result<Vec<Room>, ApiError> {
+        rooms::table
+            .filter(rooms::id.eq(any(room_ids)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::{Connection, LoadDsl, select};
+    use diesel::expression::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::result::Error as DieselError;
+    use diesel::types::BigInt;
+    use ruma_events::EventType;
+    use ruma_identifiers::RoomId;
+
+    use test::Test;
+    use util::room_state::state_key;
+    use super::Room;
+
+    #[test]
+    fn state_contents_are_looked_up_by_type_and_state_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"name": "Book Club"}"#);
+
+        let contents = test.with_connection(|connection| {
+            Room::get_state_contents(
+                connection,
+                &RoomId::try_from(room_id.as_str()).unwrap(),
+                &[
+                    (EventType::RoomName, ""),
+                    (EventType::RoomTopic, ""),
+                    (EventType::RoomMember, alice.id.as_str()),
+                    (EventType::RoomMember, bob.id.as_str()),
+                ],
+            ).unwrap()
+        });
+
+        assert_eq!(contents.len(), 2);
+        assert_eq!(
+            contents[&state_key(&EventType::RoomName, "")]["name"].as_str(),
+            Some("Book Club")
+        );
+        assert_eq!(
+            contents[&state_key(&EventType::RoomMember, &alice.id)]["membership"].as_str(),
+            Some("join")
+        );
+    }
+
+    /// The number of scans of the `events` table in the current transaction so far.
+    fn event_scans(connection: &PgConnection) -> i64 {
+        select(sql::<BigInt>(
+            "COALESCE((SELECT seq_scan + COALESCE(idx_scan, 0) FROM pg_stat_xact_user_tables \
+             WHERE relname = 'events'), 0)"
+        )).get_result(connection).unwrap()
+    }
+
+    #[test]
+    fn state_contents_take_the_same_number_of_scans_for_any_number_of_keys() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room_with_params(&alice.token, r#"{"name": "Book Club"}"#);
+        let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+
+        let scans = |keys: &[(EventType, &str)]| test.with_connection(|connection| {
+            connection.transaction::<i64, DieselError, _>(|| {
+                let before = event_scans(connection);
+                Room::get_state_contents(connection, &room_id, keys).unwrap();
+
+                Ok(event_scans(connection) - before)
+            }).unwrap()
+        });
+
+        let one_key = scans(&[(EventType::RoomName, "")]);
+        let four_keys = scans(&[
+            (EventType::RoomName, ""),
+            (EventType::RoomPowerLevels, ""),
+            (EventType::RoomMember, alice.id.as_str()),
+            (EventType::RoomMember, bob.id.as_str()),
+        ]);
+
+        assert!(one_key > 0);
+        assert_eq!(one_key, four_keys);
+    }
 }
@@ -12,6 +12,7 @@ use diesel::{
     insert,
     delete,
 };
+use diesel::expression::dsl::any;
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::{Error as DieselError, DatabaseErrorKind};
@@ -107,8 +108,17 @@ impl RoomAlias {
             })
     }
 
+    /// Return the `RoomAlias` entries of the given `RoomAliasId`'s that exist, in one query.
+    pub fn find_by_aliases(connection: &PgConnection, aliases: &[RoomAliasId])
+    -> Result<Vec<RoomAlias>, ApiError> {
+        room_aliases::table
+            .filter(room_aliases::alias.eq(any(aliases)))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return all aliases associated with the given `RoomId`.
-    fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
+    pub fn find_by_room_id(connection: &PgConnection, room_id: &RoomId)
     -> Result<Vec<RoomAlias>, ApiError> {
         let aliases: Vec<RoomAlias> = room_aliases::table
             .filter(room_aliases::room_id.eq(room_id))
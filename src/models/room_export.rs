@@ -0,0 +1,383 @@
+//! Exporting rooms to and importing them from a single JSON document.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use diesel::{Connection, ExpressionMethods, FilterDsl, LoadDsl, OrderDsl, insert};
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+
+use error::ApiError;
+use hooks;
+use models::account_data::{NewRoomAccountData, RoomAccountData};
+use models::event::{Event, NewEvent, NewImportedEvent, imported_content_hash};
+use models::event_relation::EventRelation;
+use models::room::{NewRoom, Room};
+use models::room_alias::{NewRoomAlias, RoomAlias};
+use models::room_membership::{NewRoomMembership, RoomMembership};
+use schema::{events, room_account_data, room_aliases, room_memberships, rooms};
+
+/// A room with its complete history, as produced by `Room::export`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoomExport {
+    /// The room itself.
+    pub room: ExportedRoom,
+    /// All events of the room, oldest first. This includes the historical state.
+    pub events: Vec<ExportedEvent>,
+    /// The IDs of the events making up the current state of the room.
+    pub current_state: Vec<EventId>,
+    /// The current membership of every user that was ever a member of the room.
+    pub memberships: Vec<ExportedMembership>,
+    /// The aliases pointing to the room.
+    pub aliases: Vec<ExportedAlias>,
+    /// The room account data of the room's members.
+    pub account_data: Vec<ExportedAccountData>,
+}
+
+/// A room in a `RoomExport`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedRoom {
+    /// The room's unique ID.
+    pub id: RoomId,
+    /// The ID of the user who created the room.
+    pub user_id: UserId,
+    /// Whether or not the room is visible in the directory.
+    pub public: bool,
+    /// The type of the room, e.g. *m.space*. Missing in exports of older versions.
+    pub room_type: Option<String>,
+}
+
+/// An event in a `RoomExport`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedEvent {
+    /// The unique event ID.
+    pub id: EventId,
+    /// The user who sent the event.
+    pub user_id: UserId,
+    /// The type of the event, e.g. *m.room.create*.
+    pub event_type: String,
+    /// The state key of the event, if it is a state event.
+    pub state_key: Option<String>,
+    /// JSON of the event's content.
+    pub content: String,
+    /// Extra key-value pairs to be mixed into the top-level JSON representation of the event.
+    pub extra_content: Option<String>,
+    /// The time the event was created, in milliseconds since the Unix epoch. Missing in exports
+    /// of older versions, whose events are imported as created at the time of the import.
+    pub origin_server_ts: Option<i64>,
+    /// Whether the content was redacted.
+    #[serde(default)]
+    pub redacted: bool,
+    /// The hash of the content when the event was saved or redacted, see `content_hash`. Missing
+    /// in exports of older versions, whose content is hashed on import.
+    pub content_hash: Option<String>,
+}
+
+/// A room membership in a `RoomExport`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedMembership {
+    /// The ID of the *m.room.member* event that set the membership.
+    pub event_id: EventId,
+    /// The user's ID.
+    pub user_id: UserId,
+    /// The ID of the user who set the membership.
+    pub sender: UserId,
+    /// The membership state, e.g. *join*.
+    pub membership: String,
+}
+
+/// A room alias in a `RoomExport`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedAlias {
+    /// The human-readable alias.
+    pub alias: RoomAliasId,
+    /// The ID of the user that created the alias.
+    pub user_id: UserId,
+    /// A list of homeserver domains that know about this alias.
+    pub servers: Vec<String>,
+}
+
+/// Room account data in a `RoomExport`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportedAccountData {
+    /// The ID of the user who owns the data.
+    pub user_id: UserId,
+    /// The type of the data.
+    pub data_type: String,
+    /// The contents.
+    pub content: String,
+}
+
+impl Room {
+    /// Collect everything stored about a room into a `RoomExport`.
+    ///
+    /// Room account data only ever exists for local users, so all of it is included.
+    pub fn export(connection: &PgConnection, room_id: &RoomId) -> Result<RoomExport, ApiError> {
+        let room = match Room::find(connection, room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::not_found(format!("The room {} was not found", room_id)))?,
+        };
+
+        let events: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .order(events::ordering.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let mut current_state = BTreeMap::new();
+
+        for event in &events {
+            if let Some(ref state_key) = event.state_key {
+                let key = (event.event_type.clone(), state_key.clone());
+
+                current_state.insert(key, event.id.clone());
+            }
+        }
+
+        let memberships: Vec<RoomMembership> = room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .order(room_memberships::user_id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let aliases: Vec<RoomAlias> = room_aliases::table
+            .filter(room_aliases::room_id.eq(room_id))
+            .order(room_aliases::alias.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let account_data: Vec<RoomAccountData> = room_account_data::table
+            .filter(room_account_data::room_id.eq(room_id))
+            .order(room_account_data::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(RoomExport {
+            room: ExportedRoom {
+                id: room.id,
+                user_id: room.user_id,
+                public: room.public,
+                room_type: room.room_type,
+            },
+            events: events.into_iter().map(|event| ExportedEvent {
+                origin_server_ts: Some(event.origin_server_ts()),
+                id: event.id,
+                user_id: event.user_id,
+                event_type: event.event_type,
+                state_key: event.state_key,
+                content: event.content,
+                extra_content: event.extra_content,
+                redacted: event.redacted,
+                content_hash: Some(event.content_hash),
+            }).collect(),
+            current_state: current_state.into_iter().map(|(_, event_id)| event_id).collect(),
+            memberships: memberships.into_iter().map(|membership| ExportedMembership {
+                event_id: membership.event_id,
+                user_id: membership.user_id,
+                sender: membership.sender,
+                membership: membership.membership,
+            }).collect(),
+            aliases: aliases.into_iter().map(|alias| ExportedAlias {
+                alias: alias.alias,
+                user_id: alias.user_id,
+                servers: alias.servers,
+            }).collect(),
+            account_data: account_data.into_iter().map(|data| ExportedAccountData {
+                user_id: data.user_id,
+                data_type: data.data_type,
+                content: data.content,
+            }).collect(),
+        })
+    }
+
+    /// Load a `RoomExport` into the database, preserving all IDs.
+    ///
+    /// The export is rejected if it is not self-consistent, or if the room, any of its events or
+    /// any of its aliases already exist on this server. The events get new orderings, but keep
+    /// their order, timestamps, content hashes and redactions. The users referenced by the export
+    /// are not created.
+    pub fn import(connection: &PgConnection, export: &RoomExport) -> Result<Room, ApiError> {
+        verify_export(export)?;
+
+        connection.transaction::<Room, ApiError, _>(|| {
+            let room_id = &export.room.id;
+
+            if Room::find(connection, room_id)?.is_some() {
+                Err(ApiError::room_in_use(format!("The room {} already exists", room_id)))?;
+            }
+
+            let event_ids: Vec<EventId> = export.events.iter()
+                .map(|event| event.id.clone())
+                .collect();
+
+            if let Some(event) = Event::find_many(connection, &event_ids)?.into_iter().next() {
+                Err(ApiError::bad_json(format!("The event {} already exists", event.id)))?;
+            }
+
+            let aliases: Vec<RoomAliasId> = export.aliases.iter()
+                .map(|alias| alias.alias.clone())
+                .collect();
+
+            let taken_aliases = RoomAlias::find_by_aliases(connection, &aliases)?;
+
+            if let Some(alias) = taken_aliases.first() {
+                Err(ApiError::alias_taken(format!("The alias {} is already taken", alias.alias)))?;
+            }
+
+            let new_room = NewRoom {
+                id: room_id.clone(),
+                user_id: export.room.user_id.clone(),
+                public: export.room.public,
+                room_type: export.room.room_type.clone(),
+            };
+
+            let room: Room = insert(&new_room)
+                .into(rooms::table)
+                .get_result(connection)
+                .map_err(ApiError::from)?;
+
+            // Insert the events one at a time so their orderings follow the exported order.
+            let mut events = Vec::with_capacity(export.events.len());
+
+            for event in &export.events {
+                let content_hash = match event.content_hash {
+                    Some(ref content_hash) => content_hash.clone(),
+                    None => imported_content_hash(&event.content)?,
+                };
+
+                let new_event = NewEvent {
+                    content: event.content.clone(),
+                    content_hash: content_hash,
+                    event_type: event.event_type.clone(),
+                    extra_content: event.extra_content.clone(),
+                    id: event.id.clone(),
+                    room_id: room_id.clone(),
+                    state_key: event.state_key.clone(),
+                    user_id: event.user_id.clone(),
+                };
+
+                let inserted = match event.origin_server_ts {
+                    Some(origin_server_ts) => {
+                        let new_event = NewImportedEvent {
+                            redacted: event.redacted,
+                            ..NewImportedEvent::new(new_event, origin_server_ts)?
+                        };
+
+                        insert(&new_event).into(events::table).get_result::<Event>(connection)
+                    }
+                    None => insert(&new_event).into(events::table).get_result::<Event>(connection),
+                };
+
+                events.push(inserted.map_err(ApiError::from)?);
+            }
+
+            EventRelation::create_for_events(connection, &events)?;
+
+            for membership in &export.memberships {
+                let new_membership = NewRoomMembership {
+                    event_id: membership.event_id.clone(),
+                    room_id: room_id.clone(),
+                    user_id: membership.user_id.clone(),
+                    sender: membership.sender.clone(),
+                    membership: membership.membership.clone(),
+                };
+
+                let membership = insert(&new_membership)
+                    .into(room_memberships::table)
+                    .get_result::<RoomMembership>(connection)
+                    .map_err(ApiError::from)?;
+
+                hooks::membership_changed(
+                    connection,
+                    &membership.room_id,
+                    &membership.user_id,
+                    None,
+                    &membership.membership,
+                )?;
+            }
+
+            for alias in &export.aliases {
+                let new_alias = NewRoomAlias {
+                    alias: alias.alias.clone(),
+                    room_id: room_id.clone(),
+                    user_id: alias.user_id.clone(),
+                    servers: alias.servers.clone(),
+                };
+
+                insert(&new_alias)
+                    .into(room_aliases::table)
+                    .get_result::<RoomAlias>(connection)
+                    .map_err(ApiError::from)?;
+            }
+
+            for data in &export.account_data {
+                let new_data = NewRoomAccountData {
+                    user_id: data.user_id.clone(),
+                    room_id: room_id.clone(),
+                    data_type: data.data_type.clone(),
+                    content: data.content.clone(),
+                };
+
+                RoomAccountData::create(connection, &new_data)?;
+            }
+
+            Ok(room)
+        }).map_err(ApiError::from)
+    }
+}
+
+/// Check that all references within a `RoomExport` can be resolved.
+fn verify_export(export: &RoomExport) -> Result<(), ApiError> {
+    let mut events = HashMap::new();
+
+    for event in &export.events {
+        if events.insert(event.id.clone(), event).is_some() {
+            Err(ApiError::bad_json(format!("The event {} is exported more than once", event.id)))?;
+        }
+    }
+
+    match export.events.first() {
+        Some(event) if event.event_type == EventType::RoomCreate.to_string() => (),
+        _ => Err(ApiError::bad_json(
+            "The first event must be the m.room.create event.".to_string()
+        ))?,
+    }
+
+    for event_id in &export.current_state {
+        match events.get(event_id) {
+            Some(event) if event.state_key.is_some() => (),
+            _ => Err(ApiError::bad_json(
+                format!("The current state references the unknown state event {}", event_id)
+            ))?,
+        }
+    }
+
+    let mut members = HashSet::new();
+
+    for membership in &export.memberships {
+        match events.get(&membership.event_id) {
+            Some(event) if event.event_type == EventType::RoomMember.to_string()
+                && event.state_key.as_ref() == Some(&membership.user_id.to_string()) => (),
+            _ => Err(ApiError::bad_json(format!(
+                "The membership of {} does not reference its m.room.member event",
+                membership.user_id
+            )))?,
+        }
+
+        if !members.insert(membership.user_id.clone()) {
+            Err(ApiError::bad_json(
+                format!("The membership of {} is exported more than once", membership.user_id)
+            ))?;
+        }
+    }
+
+    for data in &export.account_data {
+        if !members.contains(&data.user_id) {
+            Err(ApiError::bad_json(
+                format!("The room account data of {} belongs to a non-member", data.user_id)
+            ))?;
+        }
+    }
+
+    Ok(())
+}
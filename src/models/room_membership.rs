@@ -1,5 +1,6 @@
 //! Matrix room membership.
 
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::error::Error;
 
@@ -10,6 +11,7 @@ use diesel::{
     FilterDsl,
     FindDsl,
     LoadDsl,
+    OrderDsl,
     SaveChangesDsl,
     SelectDsl,
     insert,
@@ -18,6 +20,7 @@ use diesel::{
 use diesel::expression::dsl::*;
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
 use diesel::result::Error as DieselError;
 use ruma_events::EventType;
 use ruma_events::room::join_rules::JoinRule;
@@ -27,14 +30,16 @@ use ruma_events::room::member::{
     MemberEventContent,
 };
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{Value, from_value};
+use serde_json::{Value, from_str, from_value};
 
 use error::ApiError;
+use hooks;
 use models::event::{NewEvent, Event};
 use models::user::User;
 use models::profile::Profile;
 use models::room::Room;
-use schema::{events, room_memberships};
+use schema::{events, room_memberships, rooms};
+use server_acl::ServerAcl;
 
 /// Room membership update or create data.
 #[derive(Debug, Clone)]
@@ -47,6 +52,21 @@ pub struct RoomMembershipOptions {
     pub sender: UserId,
     /// The current membership state.
     pub membership: String,
+    /// Why the membership changed, e.g. why the user was kicked.
+    pub reason: Option<String>,
+}
+
+/// A change of a user's membership in a room, for inspection by administrators.
+#[derive(Debug, Serialize)]
+pub struct MembershipTransition {
+    /// The ID of the `m.room.member` event of the change.
+    pub event_id: EventId,
+    /// The membership after the change, e.g. *leave*.
+    pub membership: String,
+    /// Why the membership changed, if a reason was given and the event was not redacted.
+    pub reason: Option<String>,
+    /// The time of the change, in milliseconds since the Unix epoch.
+    pub origin_server_ts: i64,
 }
 
 /// A new Matrix room membership, not yet saved.
@@ -88,7 +108,7 @@ impl RoomMembership {
     /// Creates a new `RoomMembership` in the database.
     pub fn create(connection: &PgConnection, homeserver_domain: &str, options: RoomMembershipOptions)
     -> Result<RoomMembership, ApiError> {
-        RoomMembership::verify_creation_priviledges(connection, &options)?;
+        RoomMembership::verify_priviledges(connection, &options)?;
 
         let profile = Profile::find_by_uid(connection, &options.user_id)?;
 
@@ -122,7 +142,7 @@ impl RoomMembership {
         let mut new_memberships: Vec<NewRoomMembership> = Vec::new();
 
         for option in options {
-            RoomMembership::verify_creation_priviledges(connection, &option)?;
+            RoomMembership::verify_priviledges(connection, &option)?;
 
             let profile = Profile::find_by_uid(connection, &option.user_id)?;
 
@@ -160,12 +180,24 @@ impl RoomMembership {
                                                     .into(room_memberships::table)
                                                     .get_results(connection)
                                                     .map_err(ApiError::from)?;
+
+            for membership in &memberships {
+                hooks::membership_changed(
+                    connection,
+                    &membership.room_id,
+                    &membership.user_id,
+                    None,
+                    &membership.membership,
+                )?;
+            }
+
             Ok(memberships)
         }).map_err(ApiError::from)
     }
 
-    /// Check if a `User` has enough priviledges to create a `RoomMembership`.
-    fn verify_creation_priviledges(connection: &PgConnection, options: &RoomMembershipOptions)
+    /// Check if a `User` has enough priviledges to create a `RoomMembership`, or to change a
+    /// membership that does not grant any access to the room yet, e.g. a knock.
+    pub fn verify_priviledges(connection: &PgConnection, options: &RoomMembershipOptions)
     -> Result<(), ApiError> {
         let room = match Room::find(connection, &options.room_id)? {
             Some(room) => room,
@@ -173,16 +205,26 @@ impl RoomMembership {
         };
 
         let join_rules_event = Event::find_room_join_rules_by_room_id(connection, room.id.clone())?;
+        let join_rule = join_rules_event.content.join_rule;
 
-        // Only the creator of the room can join an invite-only room, without an invite.
+        // Only the creator of the room can join an invite-only or knockable room, without an invite.
         if options.membership == "join" {
-            if join_rules_event.content.join_rule == JoinRule::Invite && options.sender != room.user_id {
+            if (join_rule == JoinRule::Invite || join_rule == JoinRule::Knock)
+                && options.sender != room.user_id {
                 return Err(ApiError::unauthorized("You are not invited to this room".to_string()));
             }
 
             return Ok(());
         }
 
+        if options.membership == "knock" {
+            if join_rule != JoinRule::Knock {
+                return Err(ApiError::unauthorized("This room does not allow knocking".to_string()));
+            }
+
+            return Ok(());
+        }
+
         let power_levels = room.current_power_levels(connection)?;
         let user_power_level = power_levels
             .users
@@ -224,17 +266,132 @@ impl RoomMembership {
     }
 
     /// Update an existing `RoomMembership` entry or insert a new one.
+    ///
+    /// Concurrent upserts for the same user and room are serialized by locking the entry, so
+    /// retrying clients end up with a single entry. Requesting the membership the user already
+    /// has does not create a new `MemberEvent` and returns the existing entry.
     pub fn upsert(connection: &PgConnection, domain: &str, options: RoomMembershipOptions)
     -> Result<RoomMembership, ApiError> {
-        let room_membership = RoomMembership::find(
-            connection,
-            &options.room_id,
-            &options.user_id
+        // If another request creates the entry between our lookup and our insert, the second
+        // attempt finds and locks the entry it created.
+        for _ in 0..2 {
+            if let Some(room_membership) = RoomMembership::try_upsert(connection, domain, &options)? {
+                return Ok(room_membership);
+            }
+        }
+
+        Err(ApiError::unknown(None))
+    }
+
+    /// Upsert a `RoomMembership` in a single transaction.
+    ///
+    /// Returns `None` if the entry did not exist yet and another request created it first.
+    fn try_upsert(connection: &PgConnection, domain: &str, options: &RoomMembershipOptions)
+    -> Result<Option<RoomMembership>, ApiError> {
+        connection.transaction::<Option<RoomMembership>, ApiError, _>(|| {
+            let room_membership = RoomMembership::find_for_update(
+                connection,
+                &options.room_id,
+                &options.user_id
+            )?;
+
+            match room_membership {
+                Some(entry) if entry.membership == options.membership => Ok(Some(entry)),
+                Some(mut entry) => {
+                    RoomMembership::verify_transition(&entry.membership, &options.membership)?;
+
+                    // A knock does not grant any access to the room, so it is checked like a new membership.
+                    if entry.membership == "knock" {
+                        RoomMembership::verify_priviledges(connection, options)?;
+                    }
+
+                    entry.update(connection, domain, options.clone()).map(Some)
+                }
+                None => RoomMembership::create_if_absent(connection, domain, options.clone()),
+            }
+        }).map_err(ApiError::from)
+    }
+
+    /// Create a new `RoomMembership` unless an entry for the user and room exists already.
+    ///
+    /// The entry is inserted before its `MemberEvent`, so nothing is saved if the insert conflicts
+    /// with an entry created concurrently.
+    fn create_if_absent(connection: &PgConnection, homeserver_domain: &str, options: RoomMembershipOptions)
+    -> Result<Option<RoomMembership>, ApiError> {
+        RoomMembership::verify_priviledges(connection, &options)?;
+
+        let profile = Profile::find_by_uid(connection, &options.user_id)?;
+
+        let new_member_event = RoomMembership::create_new_room_member_event(
+            homeserver_domain,
+            &options,
+            profile,
         )?;
 
-        match room_membership {
-            Some(mut entry) => entry.update(connection, domain, options),
-            None => RoomMembership::create(connection, domain, options)
+        let new_membership = NewRoomMembership {
+            event_id: new_member_event.id.clone(),
+            room_id: options.room_id.clone(),
+            user_id: options.user_id.clone(),
+            sender: options.sender.clone(),
+            membership: options.membership.clone(),
+        };
+
+        let mut memberships: Vec<RoomMembership> = insert(&new_membership.on_conflict_do_nothing())
+            .into(room_memberships::table)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        match memberships.pop() {
+            Some(membership) => {
+                insert(&new_member_event)
+                    .into(events::table)
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                hooks::membership_changed(
+                    connection,
+                    &membership.room_id,
+                    &membership.user_id,
+                    None,
+                    &membership.membership,
+                )?;
+
+                Ok(Some(membership))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Return the `RoomMembership` for given `RoomId` and `UserId`, locking it until the end of the
+    /// current transaction.
+    fn find_for_update(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<Option<RoomMembership>, ApiError> {
+        // Diesel cannot express `SELECT ... FOR UPDATE`, but a no-op update takes the same row lock.
+        let membership = update(
+            room_memberships::table
+                .filter(room_memberships::room_id.eq(room_id))
+                .filter(room_memberships::user_id.eq(user_id))
+        )
+            .set(room_memberships::membership.eq(room_memberships::membership))
+            .get_result(connection);
+
+        match membership {
+            Ok(membership) => Ok(Some(membership)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Check whether a membership can change from one state to another.
+    ///
+    /// A banned user has to be unbanned, i.e. their membership set to *leave*, before they can
+    /// join, knock or be invited again.
+    pub fn verify_transition(current: &str, new: &str) -> Result<(), ApiError> {
+        match (current, new) {
+            ("ban", "join") | ("ban", "knock") | ("ban", "invite") => {
+                Err(ApiError::unauthorized("User is banned from the room".to_string()))
+            }
+            _ => Ok(()),
         }
     }
 
@@ -251,6 +408,7 @@ impl RoomMembership {
             profile,
         )?;
 
+        let old_membership = self.membership.clone();
         self.membership = options.membership.clone();
         self.sender = options.sender.clone();
 
@@ -264,10 +422,25 @@ impl RoomMembership {
                 .map_err(ApiError::from)?;
 
             // Use the new `EventId` as primary key.
-            update(room_memberships::table.find(self.event_id.clone()))
+            let membership: RoomMembership = update(
+                room_memberships::table.find(self.event_id.clone())
+            )
                 .set(room_memberships::event_id.eq(event.id.clone()))
                 .get_result(connection)
-                .map_err(ApiError::from)
+                .map_err(ApiError::from)?;
+
+            // Profile updates rewrite the member event without changing the membership.
+            if old_membership != membership.membership {
+                hooks::membership_changed(
+                    connection,
+                    &membership.room_id,
+                    &membership.user_id,
+                    Some(&old_membership),
+                    &membership.membership,
+                )?;
+            }
+
+            Ok(membership)
         }).map_err(ApiError::from)
     }
 
@@ -286,7 +459,7 @@ impl RoomMembership {
             None => (None, None),
         };
 
-        let new_member_event: NewEvent = MemberEvent {
+        let mut new_member_event: NewEvent = MemberEvent {
             content: MemberEventContent {
                 avatar_url: avatar_url,
                 displayname: displayname,
@@ -298,11 +471,19 @@ impl RoomMembership {
             invite_room_state: None,
             prev_content: None,
             room_id: options.room_id.clone(),
-            state_key: format!("@{}:{}", options.user_id.clone(), &homeserver_domain),
+            state_key: options.user_id.to_string(),
             unsigned: None,
             user_id: options.user_id.clone(),
         }.try_into()?;
 
+        // The content of member events in ruma-events has no reason.
+        if let Some(ref reason) = options.reason {
+            let mut content: Value = from_str(&new_member_event.content)?;
+
+            content["reason"] = Value::String(reason.clone());
+            new_member_event.set_content(&content)?;
+        }
+
         Ok(new_member_event)
     }
 
@@ -340,6 +521,7 @@ impl RoomMembership {
                 user_id: user_id.clone(),
                 sender: room.user_id.clone(),
                 membership: "invite".to_string(),
+                reason: None,
             }
         }).collect::<Vec<RoomMembershipOptions>>();
 
@@ -365,6 +547,34 @@ impl RoomMembership {
         events.into_iter().map(TryInto::try_into).collect()
     }
 
+    /// Return the changes of a user's membership in a room, oldest first, as recorded by the
+    /// `m.room.member` events whose state key is the user, no matter who sent them.
+    pub fn find_transitions(connection: &PgConnection, room_id: &RoomId, user_id: &UserId)
+    -> Result<Vec<MembershipTransition>, ApiError> {
+        let member_events: Vec<Event> = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(EventType::RoomMember.to_string()))
+            .filter(events::state_key.eq(user_id.to_string()))
+            .order(events::ordering.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        member_events.into_iter().map(|event| {
+            let content: Value = from_str(&event.content)?;
+            let origin_server_ts = event.origin_server_ts();
+
+            Ok(MembershipTransition {
+                event_id: event.id,
+                membership: content.get("membership")
+                    .and_then(Value::as_str)
+                    .unwrap_or("")
+                    .to_string(),
+                reason: content.get("reason").and_then(Value::as_str).map(str::to_string),
+                origin_server_ts: origin_server_ts,
+            })
+        }).collect()
+    }
+
     /// Return all `RoomMembership`'s for given `UserId`.
     pub fn find_all_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Vec<RoomMembership>, ApiError> {
         room_memberships::table
@@ -427,6 +637,112 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 
+    /// Return the `UserId`'s among `user_ids` who share a joined room with the given `UserId` that
+    /// is not among the `suppressed_room_ids`.
+    pub fn find_user_ids_sharing_rooms(
+        connection: &PgConnection,
+        user_id: &UserId,
+        user_ids: &[UserId],
+        suppressed_room_ids: &[RoomId],
+    ) -> Result<Vec<UserId>, ApiError> {
+        let room_ids: Vec<RoomId> = RoomMembership::find_room_ids_by_uid_and_state(
+            connection,
+            user_id,
+            "join"
+        )?.into_iter().filter(|room_id| !suppressed_room_ids.contains(room_id)).collect();
+
+        if room_ids.is_empty() || user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(any(room_ids)))
+            .filter(room_memberships::user_id.eq(any(user_ids)))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::user_id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the joined rooms the given users share that let them see each other's presence,
+    /// i.e. rooms with at most `max_room_size` joined members that are not among the
+    /// `suppressed_room_ids`.
+    pub fn find_presence_sharing_rooms(
+        connection: &PgConnection,
+        user_id: &UserId,
+        observed_user_id: &UserId,
+        max_room_size: Option<u64>,
+        suppressed_room_ids: &[RoomId],
+    ) -> Result<Vec<RoomId>, ApiError> {
+        let room_ids: Vec<RoomId> = RoomMembership::find_common_rooms(
+            connection,
+            user_id,
+            observed_user_id,
+            "join"
+        )?.into_iter().filter(|room_id| !suppressed_room_ids.contains(room_id)).collect();
+
+        let max_room_size = match max_room_size {
+            Some(max_room_size) if !room_ids.is_empty() => max_room_size,
+            _ => return Ok(room_ids),
+        };
+
+        rooms::table
+            .filter(rooms::id.eq(any(room_ids)))
+            .filter(rooms::joined_member_count.le(max_room_size as i64))
+            .select(rooms::id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the remote homeservers of all users who share a joined room with given `UserId`.
+    ///
+    /// A homeserver is only returned if the server ACL of at least one of the shared rooms allows
+    /// it.
+    pub fn find_remote_servers(
+        connection: &PgConnection,
+        user_id: &UserId,
+        homeserver_domain: &str
+    ) -> Result<Vec<String>, ApiError> {
+        let rooms = room_memberships::table
+            .filter(room_memberships::user_id.eq(user_id))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::room_id);
+
+        let members: Vec<(RoomId, UserId)> = room_memberships::table
+            .filter(room_memberships::membership.eq("join"))
+            .filter(room_memberships::room_id.eq(any(rooms)))
+            .select((room_memberships::room_id, room_memberships::user_id))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let mut servers_by_room: HashMap<RoomId, Vec<String>> = HashMap::new();
+
+        for (room_id, user_id) in members {
+            let server = user_id.hostname().to_string();
+
+            if server != homeserver_domain {
+                servers_by_room.entry(room_id).or_insert_with(Vec::new).push(server);
+            }
+        }
+
+        let mut servers = Vec::new();
+
+        for (room_id, room_servers) in servers_by_room {
+            match ServerAcl::find_by_room(connection, &room_id)? {
+                Some(acl) => {
+                    let allowed = room_servers.into_iter().filter(|server| acl.is_allowed(server));
+                    servers.extend(allowed);
+                }
+                None => servers.extend(room_servers),
+            }
+        }
+
+        servers.sort();
+        servers.dedup();
+
+        Ok(servers)
+    }
+
     /// Filter `RoomId`'s for `UserId` and membership state.
     pub fn filter_rooms_by_state(
         connection: &PgConnection,
@@ -442,4 +758,72 @@ impl RoomMembership {
             .get_results(connection)
             .map_err(ApiError::from)
     }
+
+    /// Return the IDs of the users with the given membership state in a room.
+    pub fn find_user_ids_by_room_and_state(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        membership: &str
+    ) -> Result<Vec<UserId>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq(membership))
+            .select(room_memberships::user_id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the number of users with the given membership state in a room.
+    pub fn count_by_room_and_state(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        membership: &str
+    ) -> Result<i64, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq(membership))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the number of rooms in which the user has the given membership state.
+    pub fn count_by_uid_and_state(
+        connection: &PgConnection,
+        user_id: &UserId,
+        membership: &str
+    ) -> Result<i64, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::user_id.eq(user_id))
+            .filter(room_memberships::membership.eq(membership))
+            .select(count_star())
+            .first(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Check that the user may join another room without exceeding `max_rooms_per_user`.
+    ///
+    /// Only joined rooms count, rooms the user has left or was invited to do not.
+    pub fn verify_room_limit(
+        connection: &PgConnection,
+        user_id: &UserId,
+        max_rooms_per_user: Option<u64>
+    ) -> Result<(), ApiError> {
+        let max_rooms_per_user = match max_rooms_per_user {
+            Some(max_rooms_per_user) => max_rooms_per_user,
+            None => return Ok(()),
+        };
+
+        let joined_rooms = RoomMembership::count_by_uid_and_state(connection, user_id, "join")?;
+
+        if joined_rooms as u64 >= max_rooms_per_user {
+            return Err(ApiError::list_limit_exceeded(format!(
+                "The user {} cannot be in more than {} rooms",
+                user_id,
+                max_rooms_per_user
+            )));
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,137 @@
+//! Third party identifiers bound to users.
+
+use diesel::{
+    delete,
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use error::ApiError;
+use models::threepid_session::{EMAIL_MEDIUM, ThreepidSession};
+use schema::threepids;
+
+/// A third party identifier bound to a user, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "threepids"]
+pub struct NewThreepid {
+    /// The medium of the third party identifier, e.g. *msisdn*.
+    pub medium: String,
+    /// The third party identifier.
+    pub address: String,
+    /// The user the third party identifier belongs to.
+    pub user_id: UserId,
+}
+
+/// A third party identifier bound to a user.
+#[derive(Clone, Debug, Queryable)]
+pub struct Threepid {
+    /// The medium of the third party identifier, e.g. *msisdn*.
+    pub medium: String,
+    /// The third party identifier.
+    pub address: String,
+    /// The user the third party identifier belongs to.
+    pub user_id: UserId,
+    /// The time the third party identifier was bound.
+    pub created_at: PgTimestamp,
+}
+
+impl Threepid {
+    /// Bind the third party identifier of a validated session to a user and delete the session,
+    /// so it cannot be used again.
+    ///
+    /// Email addresses must belong to one of `allowed_email_domains`, unless it is empty. It must
+    /// be called in a transaction, so that the session is kept if the binding fails.
+    pub fn bind(
+        connection: &PgConnection,
+        session: &ThreepidSession,
+        user_id: &UserId,
+        allowed_email_domains: &[String],
+    ) -> Result<Threepid, ApiError> {
+        Threepid::verify_allowed(&session.medium, &session.address, allowed_email_domains)?;
+
+        if Threepid::find_by_address(connection, &session.medium, &session.address)?.is_some() {
+            return Err(ApiError::threepid_in_use(None));
+        }
+
+        let new_threepid = NewThreepid {
+            medium: session.medium.clone(),
+            address: session.address.clone(),
+            user_id: user_id.clone(),
+        };
+
+        session.delete(connection)?;
+
+        // A concurrent binding of the same identifier may have been committed since the check.
+        let mut threepids: Vec<Threepid> = insert(&new_threepid.on_conflict_do_nothing())
+            .into(threepids::table)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        threepids.pop().ok_or_else(|| ApiError::threepid_in_use(None))
+    }
+
+    /// Check whether a third party identifier may be bound to users of this server.
+    ///
+    /// Only the domains of email addresses are restricted, compared case-insensitively.
+    pub fn verify_allowed(medium: &str, address: &str, allowed_email_domains: &[String])
+    -> Result<(), ApiError> {
+        if medium != EMAIL_MEDIUM || allowed_email_domains.is_empty() {
+            return Ok(());
+        }
+
+        let domain = match address.rfind('@') {
+            Some(index) => address[index + 1..].to_lowercase(),
+            None => return Err(ApiError::invalid_param("address", "Invalid email address.")),
+        };
+
+        if allowed_email_domains.iter().any(|allowed| allowed.to_lowercase() == domain) {
+            Ok(())
+        } else {
+            Err(ApiError::threepid_denied(format!(
+                "Email addresses of the domain {} are not allowed on this server.",
+                domain
+            )))
+        }
+    }
+
+    /// Return the third party identifier with the given medium and address, if it is bound.
+    pub fn find_by_address(connection: &PgConnection, medium: &str, address: &str)
+    -> Result<Option<Threepid>, ApiError> {
+        let threepid = threepids::table
+            .find((medium, address))
+            .first(connection);
+
+        match threepid {
+            Ok(threepid) => Ok(Some(threepid)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return all third party identifiers bound to a user.
+    pub fn find_by_user(connection: &PgConnection, user_id: &UserId)
+    -> Result<Vec<Threepid>, ApiError> {
+        threepids::table
+            .filter(threepids::user_id.eq(user_id))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Unbind all third party identifiers of a user.
+    pub fn delete_by_user(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        delete(threepids::table.filter(threepids::user_id.eq(user_id)))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+}
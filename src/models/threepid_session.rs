@@ -0,0 +1,262 @@
+//! Validation sessions for third party identifiers.
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LoadDsl,
+    SaveChangesDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::result::Error as DieselError;
+
+use crypto::{generate_nonce, generate_numeric_token};
+use error::ApiError;
+use models::presence_status::get_now;
+use schema::threepid_sessions;
+
+/// The medium of third party identifiers that are email addresses.
+pub const EMAIL_MEDIUM: &'static str = "email";
+
+/// The medium of third party identifiers that are phone numbers.
+pub const MSISDN_MEDIUM: &'static str = "msisdn";
+
+/// The purpose of sessions that validate a third party identifier to bind it to a user.
+pub const BINDING_PURPOSE: &'static str = "binding";
+
+/// The purpose of sessions that validate an email address to reset the password of the user it
+/// is bound to.
+pub const PASSWORD_RESET_PURPOSE: &'static str = "password_reset";
+
+/// The number of milliseconds a session can be validated and used for after it was created.
+const SESSION_LIFETIME: i64 = 3_600_000;
+
+/// The number of times a token can be submitted for a session. Phone numbers get a token of six
+/// digits, which could otherwise be guessed within the lifetime of the session.
+const MAX_TOKEN_ATTEMPTS: i32 = 5;
+
+/// The credentials of a validation session as submitted by clients.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ThreepidCredentials {
+    /// The session ID.
+    pub sid: String,
+    /// The client secret used when requesting the token.
+    pub client_secret: String,
+}
+
+/// A session for validating a third party identifier, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "threepid_sessions"]
+pub struct NewThreepidSession {
+    /// The session ID handed to the client.
+    pub id: String,
+    /// A secret chosen by the client to identify its validation attempt.
+    pub client_secret: String,
+    /// The medium of the third party identifier, e.g. *msisdn*.
+    pub medium: String,
+    /// The third party identifier being validated.
+    pub address: String,
+    /// The token sent to the third party identifier.
+    pub token: String,
+    /// The client's counter for requesting the token to be (re)sent.
+    pub send_attempt: i64,
+    /// The time the session was created.
+    pub created_at: PgTimestamp,
+    /// What the validated session can be used for, e.g. *password_reset*.
+    pub purpose: String,
+}
+
+/// A session for validating a third party identifier.
+#[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
+#[table_name = "threepid_sessions"]
+pub struct ThreepidSession {
+    /// The session ID handed to the client.
+    pub id: String,
+    /// A secret chosen by the client to identify its validation attempt.
+    pub client_secret: String,
+    /// The medium of the third party identifier, e.g. *msisdn*.
+    pub medium: String,
+    /// The third party identifier being validated.
+    pub address: String,
+    /// The token sent to the third party identifier.
+    pub token: String,
+    /// The client's counter for requesting the token to be (re)sent.
+    pub send_attempt: i64,
+    /// Whether the token has been submitted back successfully.
+    pub validated: bool,
+    /// The time the session was created.
+    pub created_at: PgTimestamp,
+    /// What the validated session can be used for, e.g. *password_reset*.
+    pub purpose: String,
+    /// The number of times a token was submitted for the session.
+    pub token_attempts: i32,
+}
+
+impl ThreepidSession {
+    /// Start validating a third party identifier for `purpose`, or continue an unexpired session
+    /// for the same client secret and purpose.
+    ///
+    /// Returns the session and whether the token needs to be sent. Following the spec, the token
+    /// is only sent again if `send_attempt` is higher than for any previous request. Sessions
+    /// without token attempts left are not continued, so the client gets a new token.
+    ///
+    /// Phone numbers get a short numeric token that is easy to type from a text message. Other
+    /// media get a long random token, since it also authorizes password resets.
+    pub fn request_token(
+        connection: &PgConnection,
+        medium: &str,
+        address: &str,
+        client_secret: &str,
+        send_attempt: i64,
+        purpose: &str,
+    ) -> Result<(ThreepidSession, bool), ApiError> {
+        connection.transaction::<(ThreepidSession, bool), ApiError, _>(|| {
+            let existing_session = threepid_sessions::table
+                .filter(threepid_sessions::client_secret.eq(client_secret))
+                .filter(threepid_sessions::medium.eq(medium))
+                .filter(threepid_sessions::address.eq(address))
+                .filter(threepid_sessions::purpose.eq(purpose))
+                .filter(threepid_sessions::created_at.gt(PgTimestamp(expired_before())))
+                .filter(threepid_sessions::token_attempts.lt(MAX_TOKEN_ATTEMPTS))
+                .first::<ThreepidSession>(connection);
+
+            match existing_session {
+                Ok(mut session) => {
+                    if send_attempt <= session.send_attempt {
+                        return Ok((session, false));
+                    }
+
+                    session.send_attempt = send_attempt;
+                    session.save_changes::<ThreepidSession>(connection).map_err(ApiError::from)?;
+
+                    Ok((session, true))
+                }
+                Err(DieselError::NotFound) => {
+                    let new_session = NewThreepidSession {
+                        id: generate_nonce()?,
+                        client_secret: client_secret.to_string(),
+                        medium: medium.to_string(),
+                        address: address.to_string(),
+                        token: if medium == MSISDN_MEDIUM {
+                            generate_numeric_token()?
+                        } else {
+                            generate_nonce()?
+                        },
+                        send_attempt: send_attempt,
+                        created_at: PgTimestamp(get_now()),
+                        purpose: purpose.to_string(),
+                    };
+
+                    let session: ThreepidSession = insert(&new_session)
+                        .into(threepid_sessions::table)
+                        .get_result(connection)
+                        .map_err(ApiError::from)?;
+
+                    Ok((session, true))
+                }
+                Err(err) => Err(ApiError::from(err)),
+            }
+        }).map_err(ApiError::from)
+    }
+
+    /// Mark a session as validated if the token matches.
+    ///
+    /// Every submission uses up one of the `MAX_TOKEN_ATTEMPTS` of the session, counted in the
+    /// database so that concurrent submissions cannot exceed them. Returns whether the session is
+    /// now validated.
+    pub fn submit_token(
+        connection: &PgConnection,
+        credentials: &ThreepidCredentials,
+        token: &str,
+    ) -> Result<bool, ApiError> {
+        let session = threepid_sessions::table
+            .find(&credentials.sid)
+            .filter(threepid_sessions::client_secret.eq(&credentials.client_secret))
+            .filter(threepid_sessions::created_at.gt(PgTimestamp(expired_before())))
+            .filter(threepid_sessions::token_attempts.lt(MAX_TOKEN_ATTEMPTS));
+
+        let session = update(session)
+            .set(threepid_sessions::token_attempts.eq(threepid_sessions::token_attempts + 1))
+            .get_result::<ThreepidSession>(connection);
+
+        let mut session = match session {
+            Ok(session) => session,
+            Err(DieselError::NotFound) => return Ok(false),
+            Err(err) => return Err(ApiError::from(err)),
+        };
+
+        if session.token != token.trim() {
+            return Ok(false);
+        }
+
+        if !session.validated {
+            session.validated = true;
+            session.save_changes::<ThreepidSession>(connection).map_err(ApiError::from)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Return the session for the given credentials if it was validated for `purpose`.
+    pub fn find_validated(
+        connection: &PgConnection,
+        credentials: &ThreepidCredentials,
+        purpose: &str,
+    ) -> Result<Option<ThreepidSession>, ApiError> {
+        let session = ThreepidSession::find(
+            connection,
+            &credentials.sid,
+            &credentials.client_secret,
+        )?;
+
+        Ok(session.and_then(|session| {
+            if session.validated && session.purpose == purpose { Some(session) } else { None }
+        }))
+    }
+
+    /// Delete the session, so it cannot be used again.
+    pub fn delete(&self, connection: &PgConnection) -> Result<(), ApiError> {
+        delete(threepid_sessions::table.find(&self.id))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Delete sessions that have expired. Returns the number of sessions deleted.
+    pub fn delete_expired(connection: &PgConnection) -> Result<usize, ApiError> {
+        delete(
+            threepid_sessions::table
+                .filter(threepid_sessions::created_at.le(PgTimestamp(expired_before())))
+        )
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the unexpired session with the given ID if the client secret matches.
+    fn find(connection: &PgConnection, id: &str, client_secret: &str)
+    -> Result<Option<ThreepidSession>, ApiError> {
+        let session = threepid_sessions::table
+            .find(id)
+            .filter(threepid_sessions::client_secret.eq(client_secret))
+            .filter(threepid_sessions::created_at.gt(PgTimestamp(expired_before())))
+            .first(connection);
+
+        match session {
+            Ok(session) => Ok(Some(session)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+}
+
+/// Sessions created at or before this time have expired.
+fn expired_before() -> i64 {
+    get_now() - SESSION_LIFETIME
+}
@@ -0,0 +1,140 @@
+//! Messages sent directly to a device of a user rather than to a room.
+//!
+//! Messages wait here until the device syncs, and are removed once the device acknowledged them by
+//! syncing with the next batch token.
+
+use diesel::{
+    delete,
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+};
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use ruma_identifiers::UserId;
+use serde_json::{Value, from_str, to_string};
+
+use error::ApiError;
+use schema::to_device_messages;
+
+/// The maximum number of messages delivered to a device in one sync.
+const MAX_MESSAGES_PER_SYNC: i64 = 100;
+
+/// A message for a device, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "to_device_messages"]
+pub struct NewToDeviceMessage {
+    /// The user owning the device.
+    pub user_id: UserId,
+    /// The ID of the device.
+    pub device_id: String,
+    /// The user who sent the message.
+    pub sender: UserId,
+    /// The type of the message, e.g. *m.ruma.session_expiry_warning*.
+    pub event_type: String,
+    /// The JSON content of the message.
+    pub content: String,
+}
+
+/// A message for a device.
+#[derive(Debug, Clone, Queryable)]
+pub struct ToDeviceMessage {
+    /// The ID of the message, increasing in the order the messages were sent.
+    pub id: i64,
+    /// The user owning the device.
+    pub user_id: UserId,
+    /// The ID of the device.
+    pub device_id: String,
+    /// The user who sent the message.
+    pub sender: UserId,
+    /// The type of the message, e.g. *m.ruma.session_expiry_warning*.
+    pub event_type: String,
+    /// The JSON content of the message.
+    pub content: String,
+    /// The time the message was sent.
+    pub created_at: PgTimestamp,
+}
+
+/// A message in the `to_device` section of a sync response.
+#[derive(Debug, Clone, Serialize)]
+pub struct ToDeviceEvent {
+    /// The content of the message.
+    pub content: Value,
+    /// The user who sent the message.
+    pub sender: UserId,
+    /// The type of the message.
+    #[serde(rename="type")]
+    pub event_type: String,
+}
+
+impl ToDeviceMessage {
+    /// Queue a message for a device of a user.
+    pub fn create(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        sender: &UserId,
+        event_type: &str,
+        content: &Value,
+    ) -> Result<(), ApiError> {
+        let new_message = NewToDeviceMessage {
+            user_id: user_id.clone(),
+            device_id: device_id.to_string(),
+            sender: sender.clone(),
+            event_type: event_type.to_string(),
+            content: to_string(content).map_err(ApiError::from)?,
+        };
+
+        insert(&new_message)
+            .into(to_device_messages::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return the oldest messages for a device of a user to be delivered, oldest first, with the
+    /// ID of the last one.
+    ///
+    /// The rest is left for the next sync if there are too many. The messages stay until the
+    /// device acknowledges them.
+    pub fn find_for_device(connection: &PgConnection, user_id: &UserId, device_id: &str)
+    -> Result<(Vec<ToDeviceEvent>, Option<i64>), ApiError> {
+        let messages: Vec<ToDeviceMessage> = to_device_messages::table
+            .filter(to_device_messages::user_id.eq(user_id))
+            .filter(to_device_messages::device_id.eq(device_id))
+            .order(to_device_messages::id.asc())
+            .limit(MAX_MESSAGES_PER_SYNC)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let last_id = messages.last().map(|message| message.id);
+
+        let events = messages.into_iter().map(|message| {
+            Ok(ToDeviceEvent {
+                content: from_str(&message.content).map_err(ApiError::from)?,
+                sender: message.sender,
+                event_type: message.event_type,
+            })
+        }).collect::<Result<Vec<ToDeviceEvent>, ApiError>>()?;
+
+        Ok((events, last_id))
+    }
+
+    /// Delete the messages for a device of a user up to and including the ID `up_to`, which the
+    /// device acknowledged by syncing with a batch containing it. Returns the number of messages
+    /// deleted.
+    pub fn acknowledge(connection: &PgConnection, user_id: &UserId, device_id: &str, up_to: i64)
+    -> Result<usize, ApiError> {
+        let delivered = to_device_messages::table
+            .filter(to_device_messages::user_id.eq(user_id))
+            .filter(to_device_messages::device_id.eq(device_id))
+            .filter(to_device_messages::id.le(up_to));
+
+        delete(delivered).execute(connection).map_err(ApiError::from)
+    }
+}
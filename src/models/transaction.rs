@@ -1,58 +1,108 @@
 //! Matrix transaction.
+//!
+//! A transaction saves the response of a request so that a retry of the request gets the same
+//! response instead of doing it again. Clients retry right after a failure, so transactions are
+//! deleted after a day.
 
 use diesel::{
+    delete,
+    insert,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
     FindDsl,
     LoadDsl,
-    insert,
 };
 use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
 use diesel::result::Error as DieselError;
+use iron::Request;
+use ruma_identifiers::UserId;
 
+use appservice::AppServiceRegistration;
+use crypto::sha256_hex;
 use error::ApiError;
+use models::access_token::AccessToken;
+use models::presence_status::get_now;
 use schema::transactions;
 
+/// The time, in milliseconds, after which transactions are deleted.
+const TRANSACTION_LIFETIME: i64 = 24 * 60 * 60 * 1000;
+
 /// A Transaction.
 #[derive(AsChangeset, Clone, Debug, Identifiable, Insertable, Queryable)]
-#[primary_key(path, access_token)]
+#[primary_key(path, access_token, user_id)]
 #[table_name = "transactions"]
 pub struct Transaction {
     /// The full path of the endpoint used for the transaction.
     pub path: String,
-    /// The access token used.
+    /// The SHA-256 hash of the access token used.
     pub access_token: String,
+    /// The user the request was made for. An application service makes requests for many users
+    /// with the same token.
+    pub user_id: UserId,
     /// The serialized response of the endpoint. It should be used
     /// as the response on future requests.
     pub response: String,
+    /// The time the transaction was created.
+    pub created_at: PgTimestamp,
 }
 
 impl Transaction {
-    /// Create a new transaction entry.
+    /// Create a new transaction entry, unless a transaction with the same path, access token and
+    /// user exists. Returns whether the transaction was created.
+    ///
+    /// A concurrent request with the same transaction waits for the database transaction that
+    /// created it to finish, so only one of them creates it.
     pub fn create(
         connection: &PgConnection,
         path: String,
         access_token: String,
+        user_id: UserId,
         response: String
-    ) -> Result<Transaction, ApiError> {
+    ) -> Result<bool, ApiError> {
         let new_transaction = Transaction {
             path: path,
             access_token: access_token,
+            user_id: user_id,
             response: response,
+            created_at: PgTimestamp(get_now()),
         };
 
-        insert(&new_transaction)
+        let rows = insert(&new_transaction.on_conflict_do_nothing())
             .into(transactions::table)
-            .get_result(connection)
-            .map_err(ApiError::from)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(rows == 1)
     }
 
-    /// Look up a transaction with the url path of the endpoint and the access token.
+    /// The hash of the token the transactions of an authenticated request are scoped to: the
+    /// access token, or the token of the application service that sent the request.
+    pub fn token_hash(request: &Request) -> String {
+        match request.extensions.get::<AccessToken>() {
+            Some(access_token) => access_token.token_hash.clone(),
+            None => {
+                let registration = request.extensions.get::<AppServiceRegistration>().expect(
+                    "AccessTokenAuth should ensure an access token or an application service"
+                );
+
+                sha256_hex(registration.as_token.as_bytes())
+            }
+        }
+    }
+
+    /// Look up a transaction with the url path of the endpoint, the hash of the access token and
+    /// the user the request was made for.
     pub fn find(
         connection: &PgConnection,
         path: &str,
-        access_token: &str
+        access_token: &str,
+        user_id: &UserId,
     ) -> Result<Option<Transaction>, ApiError> {
         let transaction = transactions::table
-            .find((path, access_token))
+            .find((path, access_token, user_id))
             .get_result(connection);
 
         match transaction {
@@ -61,4 +111,14 @@ impl Transaction {
             Err(err) => Err(ApiError::from(err)),
         }
     }
+
+    /// Delete the transactions older than `TRANSACTION_LIFETIME`. Returns the number of
+    /// transactions deleted.
+    pub fn delete_expired(connection: &PgConnection) -> Result<usize, ApiError> {
+        let expired_before = PgTimestamp(get_now() - TRANSACTION_LIFETIME);
+
+        delete(transactions::table.filter(transactions::created_at.le(expired_before)))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
 }
@@ -12,16 +12,21 @@ use diesel::{
     SaveChangesDsl,
     SelectDsl,
 };
-use diesel::expression::dsl::any;
+use diesel::expression::dsl::{any, sql};
 use diesel::pg::PgConnection;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::result::Error as DieselError;
+use diesel::types::Bool;
 use iron::typemap::Key;
 use ruma_identifiers::UserId;
 
-use crypto::verify_password;
+use config::Config;
+use crypto::{hash_password, verify_password};
 use error::ApiError;
 use models::access_token::AccessToken;
+use models::account_data::{AccountData, RoomAccountData};
+use models::profile::Profile;
+use models::user_erasure::UserErasure;
 use schema::users;
 
 /// A Matrix user.
@@ -38,6 +43,9 @@ pub struct User {
     pub created_at: PgTimestamp,
     /// The time the user was last modified.
     pub updated_at: PgTimestamp,
+    /// Whether the user is a server administrator, in addition to the `server_admins` of the
+    /// configuration.
+    pub admin: bool,
 }
 
 /// A new Matrix user, not yet saved.
@@ -48,27 +56,44 @@ pub struct NewUser {
     pub id: UserId,
     /// The user's hashed password.
     pub password_hash: String,
+    /// Whether the user is a server administrator.
+    pub admin: bool,
 }
 
 impl User {
-    /// Creates a new user in the database.
+    /// Creates a new user in the database with an empty profile and an access token, and returns
+    /// the plaintext value of the access token.
     pub fn create(
         connection: &PgConnection,
         new_user: &NewUser,
-        macaroon_secret_key: &[u8],
-    ) -> Result<(User, AccessToken), ApiError> {
-        connection.transaction::<(User, AccessToken), ApiError, _>(|| {
+        config: &Config,
+    ) -> Result<(User, String), ApiError> {
+        connection.transaction::<(User, String), ApiError, _>(|| {
             let user: User = insert(new_user)
                 .into(users::table)
                 .get_result(connection)
                 .map_err(ApiError::from)?;
 
-            let access_token = AccessToken::create(connection, &user.id, macaroon_secret_key)?;
+            let new_profile = Profile {
+                id: user.id.clone(),
+                avatar_url: None,
+                displayname: None,
+            };
+
+            Profile::create(connection, &new_profile)?;
+
+            let access_token = AccessToken::create(connection, &user.id, None, config)?;
 
             Ok((user, access_token))
         }).map_err(ApiError::from)
     }
 
+    /// Check whether the user is a server administrator, either registered as one or listed in
+    /// the `server_admins` of the configuration.
+    pub fn is_server_admin(&self, config: &Config) -> bool {
+        self.admin || config.server_admins.contains(&self.id)
+    }
+
     /// Verify that a `User` with the given `UserId` and plaintext password exists.
     pub fn verify(
         connection: &PgConnection,
@@ -114,9 +139,10 @@ impl User {
         }
     }
 
-    /// Remove the user's ability to login.
-    pub fn deactivate(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
-        self.active = false;
+    /// Replace the user's password with the given plaintext password.
+    pub fn set_password(&mut self, connection: &PgConnection, plaintext_password: &str)
+    -> Result<(), ApiError> {
+        self.password_hash = hash_password(plaintext_password)?;
 
         match self.save_changes::<User>(connection) {
             Ok(_) => Ok(()),
@@ -124,6 +150,29 @@ impl User {
         }
     }
 
+    /// Remove the user's ability to login, revoke all of the user's access tokens and delete the
+    /// user's account data.
+    ///
+    /// If `erase` is true, the erasure of the user's personal data is requested as well, see
+    /// `UserErasure`.
+    pub fn deactivate(&mut self, connection: &PgConnection, erase: bool) -> Result<(), ApiError> {
+        self.active = false;
+
+        connection.transaction::<(), ApiError, _>(|| {
+            self.save_changes::<User>(connection).map_err(ApiError::from)?;
+
+            AccessToken::revoke_all(connection, &self.id)?;
+            AccountData::delete_by_uid(connection, &self.id)?;
+            RoomAccountData::delete_by_uid(connection, &self.id)?;
+
+            if erase {
+                UserErasure::request(connection, &self.id)?;
+            }
+
+            Ok(())
+        })
+    }
+
     /// Return `UserId`s for given `user_ids` base on the existence of a single user.
     pub fn find_missing_users(
         connection: &PgConnection,
@@ -152,6 +201,18 @@ impl User {
 
         Ok(missing_user_ids)
     }
+
+    /// Return the IDs of users whose localpart is not lower-case.
+    ///
+    /// Such users were registered before user IDs were normalized and cannot be addressed by
+    /// clients anymore, since all user IDs received from clients are lower-cased.
+    pub fn find_non_normalized_ids(connection: &PgConnection) -> Result<Vec<UserId>, ApiError> {
+        users::table
+            .filter(sql::<Bool>("id <> lower(id)"))
+            .select(users::id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
 }
 
 impl Key for User {
@@ -0,0 +1,347 @@
+//! Erasure of the personal data of deactivated users.
+//!
+//! A user can ask for their data to be erased when deactivating their account. The erasure is
+//! recorded here and carried out by a background worker: the events the user sent are redacted
+//! in batches, oldest first, together with the transaction IDs they were sent with. Once all
+//! events are redacted, the rest of the user's personal data is deleted: their profile, third
+//! party identifiers and the validation sessions and queued emails for them, pushers, device
+//! names, email notifications, key backups and presence, as well as the to-device messages they
+//! sent or were sent and the queued push notifications and federation payloads that carry copies
+//! of their events or presence. The user ID itself stays, since events need a sender.
+
+use diesel::{
+    delete,
+    insert,
+    update,
+    Connection,
+    ExecuteDsl,
+    ExpressionMethods,
+    FilterDsl,
+    FindDsl,
+    LimitDsl,
+    LoadDsl,
+    OrderDsl,
+    SelectDsl,
+    TextExpressionMethods,
+};
+use diesel::expression::dsl::any;
+use diesel::pg::PgConnection;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::OnConflictExtension;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+use serde_json::{Value, from_str, to_string};
+
+use error::ApiError;
+use models::event::{Event, imported_content_hash};
+use models::presence_status::get_now;
+use models::profile::Profile;
+use models::threepid::Threepid;
+use schema::{
+    devices,
+    email_digests,
+    email_notifications,
+    event_transactions,
+    events,
+    federation_queue,
+    key_backup_keys,
+    key_backups,
+    mail_queue,
+    presence_status,
+    push_queue,
+    pushers,
+    threepid_sessions,
+    to_device_messages,
+    user_erasures,
+};
+use util::redaction::redact_content;
+
+/// The maximum number of events the erasure worker redacts in one go for each user.
+pub const ERASURE_BATCH_SIZE: i64 = 100;
+
+/// A requested erasure, not saved yet.
+#[derive(Debug, Clone, Insertable)]
+#[table_name = "user_erasures"]
+pub struct NewUserErasure {
+    /// The ID of the user whose data is erased.
+    pub user_id: UserId,
+    /// The time the erasure was requested.
+    pub created_at: PgTimestamp,
+}
+
+/// The erasure of the personal data of a user.
+#[derive(Debug, Clone, Queryable)]
+pub struct UserErasure {
+    /// The ID of the user whose data is erased.
+    pub user_id: UserId,
+    /// The ordering of the newest event that has been redacted so far.
+    pub redacted_up_to: i64,
+    /// The number of events that have been redacted so far.
+    pub redacted_events: i64,
+    /// The time the erasure was requested.
+    pub created_at: PgTimestamp,
+    /// The time the erasure was completed, if it is.
+    pub completed_at: Option<PgTimestamp>,
+}
+
+/// The progress of an erasure, for inspection by administrators.
+///
+/// Times are in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct ErasureStatus {
+    /// The ID of the user whose data is erased.
+    pub user_id: UserId,
+    /// Either *pending* or *done*.
+    pub status: &'static str,
+    /// The number of events that have been redacted so far.
+    pub redacted_events: i64,
+    /// When the erasure was requested.
+    pub requested_ts: i64,
+    /// When the erasure was completed.
+    pub completed_ts: Option<i64>,
+}
+
+impl UserErasure {
+    /// Request the erasure of the user's data, unless it was requested before.
+    pub fn request(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+        let new_erasure = NewUserErasure {
+            user_id: user_id.clone(),
+            created_at: PgTimestamp(get_now()),
+        };
+
+        insert(&new_erasure.on_conflict_do_nothing())
+            .into(user_erasures::table)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Look up the erasure of a user's data.
+    pub fn find(connection: &PgConnection, user_id: &UserId)
+    -> Result<Option<UserErasure>, ApiError> {
+        match user_erasures::table.find(user_id).first(connection) {
+            Ok(erasure) => Ok(Some(erasure)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return the erasures that are not completed yet, oldest first.
+    pub fn find_pending(connection: &PgConnection) -> Result<Vec<UserErasure>, ApiError> {
+        user_erasures::table
+            .filter(user_erasures::completed_at.is_null())
+            .order(user_erasures::created_at.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Redact the next `batch_size` events the user sent, or complete the erasure by deleting
+    /// the rest of the user's personal data if there are none left.
+    ///
+    /// Returns the number of redacted events.
+    pub fn erase_batch(&self, connection: &PgConnection, batch_size: i64)
+    -> Result<usize, ApiError> {
+        connection.transaction::<usize, ApiError, _>(|| {
+            let events: Vec<Event> = events::table
+                .filter(events::user_id.eq(&self.user_id))
+                .filter(events::ordering.gt(self.redacted_up_to))
+                .order(events::ordering.asc())
+                .limit(batch_size)
+                .get_results(connection)
+                .map_err(ApiError::from)?;
+
+            let last = match events.last() {
+                Some(event) => event.ordering,
+                None => {
+                    delete_personal_data(connection, &self.user_id)?;
+
+                    update(user_erasures::table.find(&self.user_id))
+                        .set(user_erasures::completed_at.eq(Some(PgTimestamp(get_now()))))
+                        .execute(connection)
+                        .map_err(ApiError::from)?;
+
+                    return Ok(0);
+                }
+            };
+
+            for event in &events {
+                redact_event(connection, event)?;
+            }
+
+            let event_ids: Vec<_> = events.iter().map(|event| event.id.clone()).collect();
+
+            let event_transactions = event_transactions::table
+                .filter(event_transactions::event_id.eq(any(event_ids)));
+
+            delete(event_transactions)
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            update(user_erasures::table.find(&self.user_id))
+                .set((
+                    user_erasures::redacted_up_to.eq(last),
+                    user_erasures::redacted_events.eq(self.redacted_events + events.len() as i64),
+                ))
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            Ok(events.len())
+        }).map_err(ApiError::from)
+    }
+
+    /// The progress of the erasure.
+    pub fn status(&self) -> ErasureStatus {
+        ErasureStatus {
+            user_id: self.user_id.clone(),
+            status: if self.completed_at.is_some() { "done" } else { "pending" },
+            redacted_events: self.redacted_events,
+            requested_ts: self.created_at.0,
+            completed_ts: self.completed_at.map(|timestamp| timestamp.0),
+        }
+    }
+}
+
+/// Work on the pending erasures, which is the work of the erasure worker.
+///
+/// Each pending erasure gets one batch of at most `batch_size` events redacted, or is completed.
+/// Returns the number of redacted events.
+pub fn erase_pending(connection: &PgConnection, batch_size: i64) -> Result<usize, ApiError> {
+    let mut redacted = 0;
+
+    for erasure in UserErasure::find_pending(connection)? {
+        redacted += erasure.erase_batch(connection, batch_size)?;
+    }
+
+    Ok(redacted)
+}
+
+/// Delete the personal data of a user that is not part of the events the user sent.
+fn delete_personal_data(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+    let addresses: Vec<String> = Threepid::find_by_user(connection, user_id)?
+        .into_iter()
+        .map(|threepid| threepid.address)
+        .collect();
+
+    delete(threepid_sessions::table.filter(threepid_sessions::address.eq(any(addresses.clone()))))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(mail_queue::table.filter(mail_queue::recipient.eq(any(addresses))))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+    Profile::delete(connection, user_id)?;
+    Threepid::delete_by_user(connection, user_id)?;
+
+    delete(pushers::table.filter(pushers::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    update(devices::table.filter(devices::user_id.eq(user_id)))
+        .set(devices::display_name.eq(""))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(email_notifications::table.filter(email_notifications::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(email_digests::table.filter(email_digests::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(key_backup_keys::table.filter(key_backup_keys::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(key_backups::table.filter(key_backups::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(presence_status::table.filter(presence_status::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(to_device_messages::table.filter(to_device_messages::sender.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(to_device_messages::table.filter(to_device_messages::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+    delete(push_queue::table.filter(push_queue::user_id.eq(user_id)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+    // Payloads mentioning the user ID anywhere are only candidates, e.g. a message may quote it.
+    let pattern = format!("%{}%", user_id);
+
+    let queued_pushes: Vec<(i64, String)> = push_queue::table
+        .filter(push_queue::body.like(pattern.clone()))
+        .select((push_queue::id, push_queue::body))
+        .get_results(connection)
+        .map_err(ApiError::from)?;
+    let push_ids = ids_of_payloads_from(queued_pushes, user_id);
+
+    delete(push_queue::table.filter(push_queue::id.eq(any(push_ids))))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+    let federation_entries: Vec<(i64, String)> = federation_queue::table
+        .filter(federation_queue::payload.like(pattern))
+        .select((federation_queue::id, federation_queue::payload))
+        .get_results(connection)
+        .map_err(ApiError::from)?;
+    let federation_ids = ids_of_payloads_from(federation_entries, user_id);
+
+    delete(federation_queue::table.filter(federation_queue::id.eq(any(federation_ids))))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+    Ok(())
+}
+
+/// Return the IDs of the queued JSON payloads that carry an event or EDU of the user: a push
+/// notification about an event the user sent, a PDU the user sent, or an EDU about the user.
+fn ids_of_payloads_from(payloads: Vec<(i64, String)>, user_id: &UserId) -> Vec<i64> {
+    let user_id = user_id.to_string();
+    let is_user = |value: Option<&Value>| value.and_then(Value::as_str) == Some(user_id.as_str());
+
+    payloads.into_iter()
+        .filter(|&(_, ref payload)| {
+            let payload: Value = match from_str(payload) {
+                Ok(payload) => payload,
+                Err(_) => return false,
+            };
+            let presence_pushes = payload.pointer("/content/push").and_then(Value::as_array);
+
+            is_user(payload.pointer("/notification/sender")) ||
+                is_user(payload.pointer("/sender")) ||
+                is_user(payload.pointer("/content/user_id")) ||
+                presence_pushes.map_or(false, |pushes| {
+                    pushes.iter().any(|push| is_user(push.get("user_id")))
+                })
+        })
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Redact the stored content of an event, and drop the copy of it that a state event replacing
+/// the event keeps as its previous content.
+fn redact_event(connection: &PgConnection, event: &Event) -> Result<(), ApiError> {
+    let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+    let redacted = to_string(&redact_content(&event.event_type, &content))
+        .map_err(ApiError::from)?;
+
+    // The event may have been imported with floats in its content, which redaction can keep.
+    update(events::table.find(&event.id))
+        .set((
+            events::content_hash.eq(imported_content_hash(&redacted)?),
+            events::content.eq(redacted),
+            events::extra_content.eq(None::<String>),
+            events::redacted.eq(true),
+        ))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+    if event.state_key.is_some() {
+        update(events::table.filter(events::replaces_state.eq(&event.id)))
+            .set(events::prev_content.eq(None::<String>))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,309 @@
+//! Exporting everything stored about a user, e.g. to answer a data subject access request.
+//!
+//! The sections about the account are collected into a `UserExport`. The events the user sent
+//! are not part of it, since prolific users may have sent millions of them, but are loaded in
+//! batches while the export is written, so only the written JSON has to fit into memory. Media
+//! uploads are not included, since there is no media repository to store them in yet.
+
+use std::io::{Error as IoError, ErrorKind, Write};
+
+use diesel::{ExpressionMethods, FilterDsl, LimitDsl, LoadDsl, OrderDsl};
+use diesel::pg::PgConnection;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::to_writer;
+
+use error::ApiError;
+use models::access_token::AccessToken;
+use models::account_data::{AccountData, RoomAccountData};
+use models::device::Device;
+use models::event::Event;
+use models::presence_status::PresenceStatus;
+use models::profile::Profile;
+use models::pusher::{Pusher, PusherOptions};
+use models::room_membership::RoomMembership;
+use models::threepid::Threepid;
+use models::user::User;
+use schema::{access_tokens, devices, events, room_account_data};
+
+/// The number of sent events loaded from the database at a time.
+const EVENT_BATCH_SIZE: i64 = 500;
+
+/// The account of a user, as produced by `User::export`.
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    /// The user's ID.
+    pub user_id: UserId,
+    /// The user's profile, if it was ever set.
+    pub profile: Option<ExportedProfile>,
+    /// The third party identifiers bound to the user.
+    pub threepids: Vec<ExportedThreepid>,
+    /// The user's devices.
+    pub devices: Vec<ExportedDevice>,
+    /// The user's access tokens, without the tokens themselves.
+    pub access_tokens: Vec<ExportedAccessToken>,
+    /// The user's global and room account data.
+    pub account_data: Vec<ExportedAccountData>,
+    /// The user's memberships in rooms.
+    pub room_memberships: Vec<ExportedMembership>,
+    /// The user's pushers.
+    pub pushers: Vec<PusherOptions>,
+    /// The user's last presence. Earlier presence is not stored.
+    pub presence: Option<ExportedPresence>,
+}
+
+/// A profile in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedProfile {
+    /// The display name.
+    pub displayname: Option<String>,
+    /// The URL of the avatar.
+    pub avatar_url: Option<String>,
+}
+
+/// A third party identifier in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedThreepid {
+    /// The medium, e.g. *email*.
+    pub medium: String,
+    /// The address.
+    pub address: String,
+    /// The time the identifier was bound.
+    pub created_at: i64,
+}
+
+/// A device in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedDevice {
+    /// The ID of the device.
+    pub device_id: String,
+    /// The display name of the device.
+    pub display_name: String,
+    /// The time the device was created.
+    pub created_at: i64,
+}
+
+/// An access token in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedAccessToken {
+    /// The device the token belongs to, if any.
+    pub device_id: Option<String>,
+    /// Whether the token was revoked.
+    pub revoked: bool,
+    /// The time the token was created.
+    pub created_at: i64,
+    /// The time the token was last used.
+    pub last_used_at: i64,
+    /// The IP address the token was last used from.
+    pub last_used_ip: Option<String>,
+}
+
+/// Account data in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedAccountData {
+    /// The room the data belongs to, or `None` for global account data.
+    pub room_id: Option<RoomId>,
+    /// The type of the data.
+    pub data_type: String,
+    /// The contents.
+    pub content: String,
+}
+
+/// A room membership in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedMembership {
+    /// The room.
+    pub room_id: RoomId,
+    /// The membership state, e.g. *join*.
+    pub membership: String,
+    /// The ID of the user who set the membership.
+    pub sender: UserId,
+    /// The time the membership was set.
+    pub created_at: i64,
+}
+
+/// A presence in a `UserExport`.
+#[derive(Debug, Serialize)]
+pub struct ExportedPresence {
+    /// The presence, e.g. *online*.
+    pub presence: String,
+    /// The status message.
+    pub status_msg: Option<String>,
+    /// The time the presence was set.
+    pub updated_at: i64,
+}
+
+/// An event sent by the user, in the export written by `UserExport::write`.
+#[derive(Debug, Serialize)]
+struct ExportedEvent {
+    /// The unique event ID.
+    id: EventId,
+    /// The room the event was sent in.
+    room_id: RoomId,
+    /// The type of the event, e.g. *m.room.message*.
+    event_type: String,
+    /// The time the event was sent.
+    created_at: i64,
+    /// JSON of the event's content, if contents are exported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+impl User {
+    /// Collect everything stored about the account of a user into a `UserExport`.
+    pub fn export(connection: &PgConnection, user_id: &UserId)
+    -> Result<UserExport, ApiError> {
+        let profile = Profile::find_by_uid(connection, user_id)?.map(|profile| ExportedProfile {
+            displayname: profile.displayname,
+            avatar_url: profile.avatar_url,
+        });
+
+        let threepids = Threepid::find_by_user(connection, user_id)?
+            .into_iter()
+            .map(|threepid| ExportedThreepid {
+                medium: threepid.medium,
+                address: threepid.address,
+                created_at: threepid.created_at.0,
+            })
+            .collect();
+
+        let devices = devices::table
+            .filter(devices::user_id.eq(user_id))
+            .order(devices::created_at.asc())
+            .get_results::<Device>(connection)
+            .map_err(ApiError::from)?
+            .into_iter()
+            .map(|device| ExportedDevice {
+                device_id: device.id,
+                display_name: device.display_name,
+                created_at: device.created_at.0,
+            })
+            .collect();
+
+        let access_tokens = access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .order(access_tokens::id.asc())
+            .get_results::<AccessToken>(connection)
+            .map_err(ApiError::from)?
+            .into_iter()
+            .map(|token| ExportedAccessToken {
+                device_id: token.device_id,
+                revoked: token.revoked,
+                created_at: token.created_at.0,
+                last_used_at: token.last_used_at.0,
+                last_used_ip: token.last_used_ip,
+            })
+            .collect();
+
+        let room_account_data: Vec<RoomAccountData> = room_account_data::table
+            .filter(room_account_data::user_id.eq(user_id))
+            .order(room_account_data::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let account_data = AccountData::get_by_uid(connection, user_id)?
+            .into_iter()
+            .map(|data| ExportedAccountData {
+                room_id: None,
+                data_type: data.data_type,
+                content: data.content,
+            })
+            .chain(room_account_data.into_iter().map(|data| ExportedAccountData {
+                room_id: Some(data.room_id),
+                data_type: data.data_type,
+                content: data.content,
+            }))
+            .collect();
+
+        let room_memberships = RoomMembership::find_all_by_uid(connection, user_id)?
+            .into_iter()
+            .map(|membership| ExportedMembership {
+                room_id: membership.room_id,
+                membership: membership.membership,
+                sender: membership.sender,
+                created_at: membership.created_at.0,
+            })
+            .collect();
+
+        let pushers = Pusher::find_by_uid(connection, user_id)?
+            .into_iter()
+            .map(PusherOptions::from)
+            .collect();
+
+        let presence = PresenceStatus::find_by_uid(connection, user_id)?.map(|status| {
+            ExportedPresence {
+                presence: status.presence,
+                status_msg: status.status_msg,
+                updated_at: status.updated_at.0,
+            }
+        });
+
+        Ok(UserExport {
+            user_id: user_id.clone(),
+            profile: profile,
+            threepids: threepids,
+            devices: devices,
+            access_tokens: access_tokens,
+            account_data: account_data,
+            room_memberships: room_memberships,
+            pushers: pushers,
+            presence: presence,
+        })
+    }
+}
+
+impl UserExport {
+    /// Write the export as a JSON document with the account under *account* and the events the
+    /// user sent, oldest first, under *sent_events*.
+    ///
+    /// The events are loaded in batches while they are written, so that only the written export
+    /// of a prolific user has to fit into memory, not the loaded events as well. Their contents
+    /// are only included if `include_contents` is true. Run it with `DB::with_snapshot` for an
+    /// export that is consistent with the account.
+    pub fn write(&self, connection: &PgConnection, include_contents: bool, writer: &mut Write)
+    -> Result<(), IoError> {
+        writer.write_all(br#"{"account":"#)?;
+        to_writer(&mut *writer, self).map_err(|error| IoError::new(ErrorKind::Other, error))?;
+        writer.write_all(br#","sent_events":["#)?;
+
+        let mut last_ordering = 0;
+        let mut is_first = true;
+
+        loop {
+            let batch: Vec<Event> = events::table
+                .filter(events::user_id.eq(&self.user_id))
+                .filter(events::ordering.gt(last_ordering))
+                .order(events::ordering.asc())
+                .limit(EVENT_BATCH_SIZE)
+                .get_results(connection)
+                .map_err(|error| IoError::new(ErrorKind::Other, ApiError::from(error)))?;
+
+            for event in &batch {
+                if !is_first {
+                    writer.write_all(b",")?;
+                }
+
+                is_first = false;
+
+                let exported_event = ExportedEvent {
+                    id: event.id.clone(),
+                    room_id: event.room_id.clone(),
+                    event_type: event.event_type.clone(),
+                    created_at: event.created_at.0,
+                    content: if include_contents { Some(event.content.clone()) } else { None },
+                };
+
+                to_writer(&mut *writer, &exported_event)
+                    .map_err(|error| IoError::new(ErrorKind::Other, error))?;
+            }
+
+            match batch.last() {
+                Some(event) if batch.len() as i64 == EVENT_BATCH_SIZE => {
+                    last_ordering = event.ordering;
+                }
+                _ => break,
+            }
+        }
+
+        writer.write_all(b"]}")
+    }
+}
@@ -0,0 +1,145 @@
+//! Phone numbers used as third party identifiers.
+
+use error::ApiError;
+
+/// International calling codes by ISO 3166-1 alpha-2 country code.
+static CALLING_CODES: [(&'static str, &'static str); 48] = [
+    ("AR", "54"),
+    ("AT", "43"),
+    ("AU", "61"),
+    ("BE", "32"),
+    ("BR", "55"),
+    ("CA", "1"),
+    ("CH", "41"),
+    ("CL", "56"),
+    ("CN", "86"),
+    ("CO", "57"),
+    ("CZ", "420"),
+    ("DE", "49"),
+    ("DK", "45"),
+    ("EG", "20"),
+    ("ES", "34"),
+    ("FI", "358"),
+    ("FR", "33"),
+    ("GB", "44"),
+    ("GR", "30"),
+    ("HK", "852"),
+    ("HU", "36"),
+    ("ID", "62"),
+    ("IE", "353"),
+    ("IL", "972"),
+    ("IN", "91"),
+    ("IT", "39"),
+    ("JP", "81"),
+    ("KR", "82"),
+    ("MX", "52"),
+    ("MY", "60"),
+    ("NG", "234"),
+    ("NL", "31"),
+    ("NO", "47"),
+    ("NZ", "64"),
+    ("PH", "63"),
+    ("PL", "48"),
+    ("PT", "351"),
+    ("RO", "40"),
+    ("RU", "7"),
+    ("SE", "46"),
+    ("SG", "65"),
+    ("TH", "66"),
+    ("TR", "90"),
+    ("TW", "886"),
+    ("UA", "380"),
+    ("US", "1"),
+    ("VN", "84"),
+    ("ZA", "27"),
+];
+
+/// Countries where the leading zero of a national number is part of the number rather than a
+/// trunk prefix.
+static KEEP_LEADING_ZERO: [&'static str; 1] = ["IT"];
+
+/// Normalize a phone number to the E.164 digits used as the address of msisdn third party
+/// identifiers, i.e. the international number without the leading "+".
+///
+/// `country` is the two-letter country code the number should be parsed as if it is not given
+/// in international format.
+pub fn normalize(country: &str, phone_number: &str) -> Result<String, ApiError> {
+    let invalid_number = || ApiError::invalid_param("phone_number", "Not a valid phone number.");
+
+    let mut digits = String::new();
+    let mut international = false;
+
+    for (index, character) in phone_number.trim().chars().enumerate() {
+        match character {
+            '0'...'9' => digits.push(character),
+            '+' if index == 0 => international = true,
+            ' ' | '-' | '.' | '(' | ')' => {}
+            _ => return Err(invalid_number()),
+        }
+    }
+
+    if !international && digits.starts_with("00") {
+        international = true;
+        digits = digits[2..].to_string();
+    }
+
+    let msisdn = if international {
+        digits
+    } else {
+        let country = country.trim().to_uppercase();
+
+        let calling_code = CALLING_CODES.iter()
+            .find(|&&(code, _)| code == country)
+            .map(|&(_, calling_code)| calling_code)
+            .ok_or_else(|| ApiError::invalid_param("country", "Unknown country code."))?;
+
+        let national_number = if digits.starts_with('0')
+            && !KEEP_LEADING_ZERO.contains(&country.as_str()) {
+            &digits[1..]
+        } else {
+            &digits[..]
+        };
+
+        format!("{}{}", calling_code, national_number)
+    };
+
+    if msisdn.len() < 8 || msisdn.len() > 15 || msisdn.starts_with('0') {
+        return Err(invalid_number());
+    }
+
+    Ok(msisdn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn national_number_with_trunk_prefix() {
+        assert_eq!(normalize("GB", "07700 900123").unwrap(), "447700900123");
+    }
+
+    #[test]
+    fn national_number_with_separators() {
+        assert_eq!(normalize("us", "(202) 555-0123").unwrap(), "12025550123");
+    }
+
+    #[test]
+    fn international_number_ignores_country() {
+        assert_eq!(normalize("US", "+49 30 1234567").unwrap(), "49301234567");
+        assert_eq!(normalize("US", "0049 30 1234567").unwrap(), "49301234567");
+    }
+
+    #[test]
+    fn leading_zero_kept_where_significant() {
+        assert_eq!(normalize("IT", "06 1234 5678").unwrap(), "390612345678");
+    }
+
+    #[test]
+    fn invalid_numbers_are_rejected() {
+        assert!(normalize("US", "555-CALL-NOW").is_err());
+        assert!(normalize("US", "123").is_err());
+        assert!(normalize("US", "+1234567890123456").is_err());
+        assert!(normalize("XX", "2025550123").is_err());
+    }
+}
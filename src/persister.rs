@@ -0,0 +1,517 @@
+//! Batched writing of new events.
+//!
+//! Writing every event in a transaction of its own spends most of the time of busy rooms on
+//! commits. Endpoints instead submit prepared events to the `EventPersister`, which queues them
+//! per room and writes each queue in batches, one transaction per batch. A room's events are
+//! written in the order they were submitted, so their orderings follow that order, while rooms
+//! are written in parallel by up to `event_writers` threads.
+//!
+//! If a batch fails, its events are written again one by one, so that an event that is rejected
+//! does not fail the others. The orderings the failed batch used up are skipped. A room is only
+//! ever written by one writer at a time, so these retries are serialized with the room's other
+//! events too: events submitted while they run wait in the queue until the writer is done.
+//! A write that panics fails like a rejected event.
+
+use std::collections::{HashMap, VecDeque};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+
+use diesel::Connection;
+use diesel::pg::PgConnection;
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+use ruma_identifiers::RoomId;
+
+use db::Database;
+use error::ApiError;
+use models::event::Event;
+
+/// The maximum number of events written in one transaction.
+const MAX_BATCH_SIZE: usize = 50;
+
+/// Writes a prepared event and everything that belongs to it, returning the saved event.
+///
+/// It may be called more than once, but only one of its writes is ever committed.
+pub type EventWrite = Box<Fn(&PgConnection) -> Result<Event, ApiError> + Send>;
+
+/// Writes the events submitted by the endpoints in batches.
+pub struct EventPersister {
+    /// The database the writers take their connections from.
+    database: Arc<Database>,
+    /// The maximum number of writer threads.
+    max_writers: usize,
+    /// The events waiting to be written and the writers writing them.
+    state: Arc<Mutex<WriterState>>,
+}
+
+/// The events waiting to be written and the writers writing them.
+///
+/// A room with a queue is either among the `ready` rooms or taken by exactly one writer, which
+/// puts it back once the batch and its retries are done, so no two writers write a room at once.
+#[derive(Default)]
+struct WriterState {
+    /// The queues of events waiting to be written, by room. A room has a queue as long as it has
+    /// events waiting or a writer writes its events.
+    queues: HashMap<RoomId, VecDeque<Submission>>,
+    /// The rooms with events waiting that no writer is writing, in the order they got ready.
+    ready: VecDeque<RoomId>,
+    /// The number of running writers.
+    writers: usize,
+}
+
+/// An event waiting to be written.
+struct Submission {
+    /// The write of the event.
+    write: EventWrite,
+    /// Where the result of the write is sent.
+    result: Sender<Result<Event, ApiError>>,
+}
+
+impl EventPersister {
+    /// Create a persister that writes with connections from `database`, with at most
+    /// `max_writers` threads.
+    pub fn new(database: Arc<Database>, max_writers: usize) -> Self {
+        EventPersister {
+            database: database,
+            max_writers: max_writers,
+            state: Arc::new(Mutex::new(WriterState::default())),
+        }
+    }
+
+    /// Extract the persister stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<EventPersister>, ApiError> {
+        request.get::<PersistentRead<EventPersister>>().map_err(ApiError::from)
+    }
+
+    /// Queue an event of `room_id` for writing and wait for the result.
+    ///
+    /// The write uses a connection of its own, so callers must not hold a connection from the
+    /// pool while waiting, or a pool of one connection would never get to the write.
+    pub fn persist(&self, room_id: &RoomId, write: EventWrite) -> Result<Event, ApiError> {
+        let (sender, receiver) = channel();
+
+        let needs_writer = {
+            let mut state = lock(&self.state);
+            let is_new = !state.queues.contains_key(room_id);
+
+            let submission = Submission {
+                write: write,
+                result: sender,
+            };
+
+            state.queues.entry(room_id.clone()).or_insert_with(VecDeque::new).push_back(submission);
+
+            if is_new {
+                state.ready.push_back(room_id.clone());
+            }
+
+            // Otherwise one of the running writers gets to the room once it is done.
+            let needs_writer = is_new && state.writers < self.max_writers;
+
+            if needs_writer {
+                state.writers += 1;
+            }
+
+            needs_writer
+        };
+
+        if needs_writer {
+            self.spawn_writer();
+        }
+
+        // The sender is only dropped without a result if the write panicked.
+        receiver.recv().unwrap_or_else(|_| {
+            Err(ApiError::unknown("Failed to write the event.".to_string()))
+        })
+    }
+
+    /// Write batches of the ready rooms until no room is ready.
+    ///
+    /// A room goes back to the end of the ready rooms after each batch, so that a busy room does
+    /// not keep the writer from the others.
+    fn spawn_writer(&self) {
+        let database = self.database.clone();
+        let shared_state = self.state.clone();
+
+        thread::spawn(move || loop {
+            let (room_id, batch) = {
+                let mut state = lock(&shared_state);
+                let next_room_id = state.ready.pop_front();
+
+                let room_id = match next_room_id {
+                    Some(room_id) => room_id,
+                    None => {
+                        state.writers -= 1;
+
+                        return;
+                    }
+                };
+
+                let batch: Vec<Submission> = {
+                    let queue = state.queues.get_mut(&room_id).expect("Ready rooms have a queue");
+                    let size = queue.len().min(MAX_BATCH_SIZE);
+
+                    queue.drain(..size).collect()
+                };
+
+                (room_id, batch)
+            };
+
+            // Panicking writes only fail their own event, but should anything else panic, the
+            // senders of the batch are dropped, which fails their requests, and the writer keeps
+            // going so that the room's queue is not stuck.
+            if catch_unwind(AssertUnwindSafe(|| write_batch(&database, batch))).is_err() {
+                error!("Writing a batch of events of {} panicked.", room_id);
+            }
+
+            let mut state = lock(&shared_state);
+            let is_empty = state.queues.get(&room_id).map_or(true, VecDeque::is_empty);
+
+            if is_empty {
+                state.queues.remove(&room_id);
+            } else {
+                state.ready.push_back(room_id);
+            }
+        });
+    }
+}
+
+impl Key for EventPersister {
+    type Value = EventPersister;
+}
+
+/// Lock the state of the writers.
+fn lock(state: &Mutex<WriterState>) -> MutexGuard<WriterState> {
+    // The state is only changed while no write runs, so it stays consistent when a write panics.
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Write a batch of events in one transaction, or one by one if that fails, and send the results.
+fn write_batch(database: &Database, batch: Vec<Submission>) {
+    let connection = match database.get() {
+        Ok(connection) => connection,
+        Err(error) => {
+            for submission in batch {
+                let _ = submission.result.send(Err(error.clone()));
+            }
+
+            return;
+        }
+    };
+
+    if batch.len() > 1 {
+        let events = connection.transaction::<Vec<Event>, ApiError, _>(|| {
+            batch.iter().map(|submission| call(&submission.write, &*connection)).collect()
+        });
+
+        match events {
+            Ok(events) => {
+                for (submission, event) in batch.into_iter().zip(events) {
+                    let _ = submission.result.send(Ok(event));
+                }
+
+                return;
+            }
+            Err(error) => {
+                debug!(
+                    "Writing a batch of {} events failed, writing them one by one: {}",
+                    batch.len(),
+                    error
+                );
+            }
+        }
+    }
+
+    for submission in batch {
+        let event = connection.transaction(|| call(&submission.write, &*connection));
+
+        let _ = submission.result.send(event);
+    }
+}
+
+/// Call a write, turning a panic into an error.
+///
+/// The panic is caught inside the transaction of the write, which is then rolled back like that
+/// of a rejected event, and the batch is written again one by one without failing the others.
+fn call(write: &EventWrite, connection: &PgConnection) -> Result<Event, ApiError> {
+    catch_unwind(AssertUnwindSafe(|| write(connection))).unwrap_or_else(|_| {
+        error!("Writing an event panicked.");
+
+        Err(ApiError::unknown("Failed to write the event.".to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use diesel::pg::PgConnection;
+    use diesel::pg::data_types::PgTimestamp;
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::{Value, from_str};
+
+    use error::ApiError;
+    use models::event::{Event, content_hash};
+    use test::Test;
+    use super::{EventPersister, EventWrite, lock};
+
+    /// Send a message "message <index>" with each of the access tokens, all at once, and return
+    /// the event IDs of the responses in the same order, or `None` for failed requests.
+    fn send_concurrently(test: &Arc<Test>, tokens: Vec<String>, room_id: &str)
+    -> Vec<Option<String>> {
+        let handles: Vec<_> = tokens.into_iter().enumerate().map(|(index, token)| {
+            let test = test.clone();
+            let room_id = room_id.to_string();
+
+            thread::spawn(move || {
+                let response =
+                    test.send_message(&token, &room_id, &format!("message {}", index), 1);
+
+                if response.status == Status::Ok {
+                    Some(response.json().get("event_id").unwrap().as_str().unwrap().to_string())
+                } else {
+                    None
+                }
+            })
+        }).collect();
+
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }
+
+    /// The messages of a room, oldest first.
+    fn messages(test: &Test, room_id: &str) -> Vec<Event> {
+        let room_id = RoomId::try_from(room_id).unwrap();
+
+        test.with_connection(|connection| {
+            Event::find_room_events(connection, &room_id, 0).unwrap()
+        }).into_iter().filter(|event| event.event_type == "m.room.message").collect()
+    }
+
+    fn body(event: &Event) -> String {
+        let content: Value = from_str(&event.content).unwrap();
+
+        content.get("body").unwrap().as_str().unwrap().to_string()
+    }
+
+    /// An event returned by the writes of the tests instead of one written to the database, with
+    /// `index` as its ordering.
+    fn stand_in_event(index: usize) -> Event {
+        Event {
+            id: EventId::try_from(&format!("$event{}:ruma.test", index)).unwrap(),
+            ordering: index as i64,
+            room_id: RoomId::try_from("!room:ruma.test").unwrap(),
+            user_id: UserId::try_from("@alice:ruma.test").unwrap(),
+            event_type: "m.room.message".to_string(),
+            state_key: None,
+            content: "{}".to_string(),
+            extra_content: None,
+            created_at: PgTimestamp(0),
+            replaces_state: None,
+            prev_content: None,
+            redacted: false,
+            content_hash: content_hash("{}").unwrap(),
+        }
+    }
+
+    /// A write that records its `index` in `calls` and returns a stand-in event, or fails if it is
+    /// `rejected`.
+    fn recorded_write(calls: &Arc<Mutex<Vec<usize>>>, index: usize, rejected: bool) -> EventWrite {
+        let calls = calls.clone();
+
+        Box::new(move |_| {
+            calls.lock().unwrap().push(index);
+
+            if rejected {
+                Err(ApiError::unauthorized(None))
+            } else {
+                Ok(stand_in_event(index))
+            }
+        })
+    }
+
+    /// Submit `writes` to a persister with a single writer while it is busy with another event of
+    /// the room, so that they are queued in the given order and written as one batch, and return
+    /// their results.
+    fn persist_behind_busy_writer(test: &Test, writes: Vec<EventWrite>)
+    -> Vec<Result<Event, ApiError>> {
+        let persister = Arc::new(EventPersister::new(test.database(), 1));
+        let room_id = RoomId::try_from("!room:ruma.test").unwrap();
+        let (started_sender, started) = channel();
+        let (release, released) = channel::<()>();
+
+        let busy = {
+            let persister = persister.clone();
+            let room_id = room_id.clone();
+            let write: EventWrite = Box::new(move |_| {
+                started_sender.send(()).unwrap();
+                released.recv().unwrap();
+
+                Ok(stand_in_event(0))
+            });
+
+            thread::spawn(move || persister.persist(&room_id, write))
+        };
+
+        started.recv().unwrap();
+
+        let handles: Vec<_> = writes.into_iter().enumerate().map(|(index, write)| {
+            let thread_persister = persister.clone();
+            let thread_room_id = room_id.clone();
+            let handle = thread::spawn(move || thread_persister.persist(&thread_room_id, write));
+
+            // Submit the next write only once this one is queued.
+            while lock(&persister.state).queues.get(&room_id).map_or(0, VecDeque::len) <= index {
+                thread::yield_now();
+            }
+
+            handle
+        }).collect();
+
+        release.send(()).unwrap();
+
+        assert_eq!(busy.join().unwrap().unwrap().ordering, 0);
+
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn queued_events_are_batched_and_retried_in_order() {
+        let test = Test::new();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let writes = (1..6).map(|index| recorded_write(&calls, index, index == 3)).collect();
+
+        let results = persist_behind_busy_writer(&test, writes);
+
+        for (result, index) in results.iter().zip(1..6) {
+            match *result {
+                Ok(ref event) => assert_eq!(event.ordering, index as i64),
+                Err(_) => assert_eq!(index, 3),
+            }
+        }
+
+        assert!(results[2].is_err());
+
+        // The queued events were written as one batch, which failed at the rejected event, and
+        // then one by one in the order they were submitted.
+        assert_eq!(*calls.lock().unwrap(), vec![1, 2, 3, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn panicking_writes_only_fail_their_own_event() {
+        let test = Test::new();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let panicking: EventWrite = Box::new(|_: &PgConnection| -> Result<Event, ApiError> {
+            panic!("The write of the event failed.")
+        });
+        let writes = vec![
+            recorded_write(&calls, 1, false),
+            panicking,
+            recorded_write(&calls, 3, false),
+        ];
+
+        let results = persist_behind_busy_writer(&test, writes);
+
+        assert_eq!(results[0].as_ref().unwrap().ordering, 1);
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().ordering, 3);
+        assert_eq!(*calls.lock().unwrap(), vec![1, 1, 3]);
+    }
+
+    #[test]
+    fn concurrent_messages_are_written_in_order() {
+        let test = Arc::new(Test::new());
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let mut tokens = vec![alice.token.clone()];
+
+        for _ in 0..7 {
+            let user = test.create_user();
+
+            assert_eq!(test.join_room(&user.token, &room_id).status, Status::Ok);
+
+            tokens.push(user.token);
+        }
+
+        let event_ids = send_concurrently(&test, tokens, &room_id);
+        let messages = messages(&test, &room_id);
+
+        assert_eq!(messages.len(), 8);
+
+        for pair in messages.windows(2) {
+            assert!(pair[0].ordering < pair[1].ordering);
+        }
+
+        let mut event_ids: Vec<String> = event_ids.into_iter()
+            .map(|event_id| event_id.expect("Every message should be sent"))
+            .collect();
+
+        for (index, event_id) in event_ids.iter().enumerate() {
+            let event = messages.iter()
+                .find(|event| event.id.opaque_id() == event_id)
+                .expect("The event ID should be one of a message");
+
+            assert_eq!(body(event), format!("message {}", index));
+        }
+
+        event_ids.sort();
+        event_ids.dedup();
+
+        assert_eq!(event_ids.len(), 8);
+    }
+
+    #[test]
+    fn rooms_wait_for_a_free_writer() {
+        let test = Arc::new(Test::with_config(|config| config.event_writers = 1));
+        let alice = test.create_user();
+        let room_ids: Vec<String> = (0..3).map(|_| test.create_public_room(&alice.token)).collect();
+
+        let handles: Vec<_> = room_ids.iter().cloned().map(|room_id| {
+            let test = test.clone();
+            let token = alice.token.clone();
+
+            thread::spawn(move || send_concurrently(&test, vec![token], &room_id))
+        }).collect();
+
+        for handle in handles {
+            assert!(handle.join().unwrap().iter().all(Option::is_some));
+        }
+
+        for room_id in &room_ids {
+            assert_eq!(messages(&test, room_id).len(), 1);
+        }
+    }
+
+    #[test]
+    fn rejected_messages_do_not_fail_the_others() {
+        let test = Arc::new(Test::new());
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        // Carl has not joined the room, so his message is rejected.
+        let tokens = vec![alice.token.clone(), carl.token.clone(), bob.token.clone()];
+        let event_ids = send_concurrently(&test, tokens, &room_id);
+
+        assert!(event_ids[0].is_some());
+        assert!(event_ids[1].is_none());
+        assert!(event_ids[2].is_some());
+
+        let bodies: Vec<String> = messages(&test, &room_id).iter().map(body).collect();
+
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies.contains(&"message 0".to_string()));
+        assert!(bodies.contains(&"message 2".to_string()));
+    }
+}
@@ -0,0 +1,468 @@
+//! Evaluation of push rules and delivery of push notifications to pushers.
+//!
+//! Users cannot configure push rules yet, so only the server-default underride rules are
+//! evaluated: calls ring, and messages, encrypted or not, notify silently except in rooms with two
+//! members.
+//!
+//! Only room events are ever pushed. Presence updates never reach pushers and never count as
+//! notifications, even if a rule would match them.
+//!
+//! Notifications are put into the push queue in the database and posted to the push gateways by
+//! a background worker started with the server, so that requests do not wait for slow gateways.
+//!
+//! Every delivery is recorded in the delivery statistics of the pusher. After a failure, no
+//! notifications are sent to the pusher until its backoff has passed. The notifications in
+//! between stay in the queue and are sent once it has, unless they are more than a day old by
+//! then. A notification whose delivery failed is not retried.
+
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+#[cfg(not(test))]
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+#[cfg(not(test))]
+use hyper::Client;
+#[cfg(not(test))]
+use hyper::header::ContentType;
+#[cfg(not(test))]
+use hyper::status::StatusClass;
+use ruma_events::EventType;
+use serde_json::{Value, from_str, to_string};
+
+use error::ApiError;
+use models::email_notification::EmailNotification;
+use models::event::Event;
+use models::presence_status::get_now;
+use models::push_queue::QueuedPush;
+use models::pusher::{EMAIL_PUSHER_KIND, Pusher};
+use models::room_membership::RoomMembership;
+
+/// The ID of the server-default rule that notifies users of incoming calls.
+pub const CALL_RULE_ID: &'static str = ".m.rule.call";
+
+/// The maximum number of queued notifications delivered in one run of the worker.
+pub const PUSH_DELIVERY_BATCH_SIZE: i64 = 50;
+
+/// How long, in seconds, a push gateway may take to accept the request and to respond.
+#[cfg(not(test))]
+const PUSH_GATEWAY_TIMEOUT: u64 = 10;
+
+/// The server-default underride rules, in the order they are evaluated.
+const DEFAULT_UNDERRIDE_RULES: [DefaultRule; 5] = [
+    DefaultRule {
+        rule_id: CALL_RULE_ID,
+        event_type: "m.call.invite",
+        room_member_count: None,
+        sound: Some("ring"),
+    },
+    DefaultRule {
+        rule_id: ".m.rule.encrypted_room_one_to_one",
+        event_type: "m.room.encrypted",
+        room_member_count: Some(2),
+        sound: Some("default"),
+    },
+    DefaultRule {
+        rule_id: ".m.rule.room_one_to_one",
+        event_type: "m.room.message",
+        room_member_count: Some(2),
+        sound: Some("default"),
+    },
+    DefaultRule {
+        rule_id: ".m.rule.message",
+        event_type: "m.room.message",
+        room_member_count: None,
+        sound: None,
+    },
+    DefaultRule {
+        rule_id: ".m.rule.encrypted",
+        event_type: "m.room.encrypted",
+        room_member_count: None,
+        sound: None,
+    },
+];
+
+#[cfg(test)]
+thread_local! {
+    /// The notifications "sent" to push gateways on the current thread, with their URL.
+    static SENT_NOTIFICATIONS: RefCell<Vec<(String, Value)>> = RefCell::new(Vec::new());
+    /// The HTTP status code push gateways "respond" with on the current thread.
+    static GATEWAY_STATUS: Cell<u16> = Cell::new(200);
+}
+
+/// Why a notification could not be delivered to a push gateway.
+#[derive(Clone, Debug)]
+struct DeliveryFailure {
+    /// The HTTP status code of the push gateway's response, if it responded at all.
+    status: Option<u16>,
+    /// A description of the failure.
+    reason: &'static str,
+}
+
+/// A server-default push rule that matches all events of one type, optionally only in rooms
+/// with a given number of joined members.
+struct DefaultRule {
+    /// The ID of the rule.
+    rule_id: &'static str,
+    /// The type of the events the rule matches.
+    event_type: &'static str,
+    /// The number of joined members of the rooms the rule matches events in, if it is limited.
+    room_member_count: Option<usize>,
+    /// The sound clients should play, set as the *sound* tweak. Notifications without a sound
+    /// are silent.
+    sound: Option<&'static str>,
+}
+
+/// The actions of the push rule that matched an event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Actions {
+    /// The ID of the matching rule.
+    pub rule_id: &'static str,
+    /// The tweaks set by the rule, e.g. the *sound* to play.
+    pub tweaks: BTreeMap<String, Value>,
+}
+
+/// The JSON body posted to a push gateway.
+#[derive(Debug, Serialize)]
+struct NotifyRequest<'a> {
+    /// The notification.
+    notification: Notification<'a>,
+}
+
+/// A notification about an event.
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    /// The ID of the event.
+    event_id: String,
+    /// The room the event was sent in.
+    room_id: String,
+    /// The type of the event.
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    /// The user who sent the event.
+    sender: String,
+    /// The content of the event.
+    content: &'a Value,
+    /// How urgently the notification should be delivered.
+    prio: &'static str,
+    /// The pushers the notification is for.
+    devices: Vec<NotificationDevice<'a>>,
+}
+
+/// A pusher a notification is for.
+#[derive(Debug, Serialize)]
+struct NotificationDevice<'a> {
+    /// The ID of the application of the pusher.
+    app_id: &'a str,
+    /// The push key of the pusher.
+    pushkey: &'a str,
+    /// The tweaks of the matching push rule.
+    tweaks: &'a BTreeMap<String, Value>,
+}
+
+/// Evaluate the push rules for an event at the time `now`, in milliseconds, in a room with
+/// `room_member_count` joined members.
+///
+/// Returns `None` if no rule matches, for presence events, or if the event, e.g. a call invite,
+/// has a `lifetime` that has passed already.
+pub fn evaluate(event: &Event, now: i64, room_member_count: usize) -> Option<Actions> {
+    // Presence is never pushed, no matter which rules exist.
+    if event.event_type == EventType::Presence.to_string() {
+        return None;
+    }
+
+    if is_expired(event, now) {
+        return None;
+    }
+
+    DEFAULT_UNDERRIDE_RULES.iter()
+        .find(|rule| {
+            rule.event_type == event.event_type &&
+                rule.room_member_count.map_or(true, |count| count == room_member_count)
+        })
+        .map(|rule| {
+            let mut tweaks = BTreeMap::new();
+
+            if let Some(sound) = rule.sound {
+                tweaks.insert("sound".to_string(), Value::String(sound.to_string()));
+            }
+
+            tweaks.insert("highlight".to_string(), Value::Bool(false));
+
+            Actions {
+                rule_id: rule.rule_id,
+                tweaks: tweaks,
+            }
+        })
+}
+
+/// Queue a notification about a new event for the pushers of the other users in the room.
+///
+/// Users with an email pusher get the notification in their next digest email instead.
+pub fn notify_room_members(connection: &PgConnection, event: &Event) -> Result<(), ApiError> {
+    let now = get_now();
+    let user_ids = RoomMembership::find_user_ids_by_room_and_state(
+        connection,
+        &event.room_id,
+        "join",
+    )?;
+
+    let actions = match evaluate(event, now, user_ids.len()) {
+        Some(actions) => actions,
+        None => return Ok(()),
+    };
+
+    let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+    for user_id in user_ids.iter().filter(|user_id| **user_id != event.user_id) {
+        let pushers = Pusher::find_by_uid(connection, user_id)?;
+
+        if pushers.iter().any(|pusher| pusher.kind == EMAIL_PUSHER_KIND) {
+            EmailNotification::create(connection, user_id, event)?;
+        }
+
+        let http_pushers = pushers.iter().filter(|pusher| {
+            pusher.kind == "http" && pusher.url.is_some()
+        });
+
+        for pusher in http_pushers {
+            let body = {
+                let request = NotifyRequest {
+                    notification: Notification {
+                        event_id: event.id.to_string(),
+                        room_id: event.room_id.to_string(),
+                        event_type: &event.event_type,
+                        sender: event.user_id.to_string(),
+                        content: &content,
+                        prio: "high",
+                        devices: vec![NotificationDevice {
+                            app_id: &pusher.app_id,
+                            pushkey: &pusher.pushkey,
+                            tweaks: &actions.tweaks,
+                        }],
+                    },
+                };
+
+                to_string(&request).map_err(ApiError::from)?
+            };
+
+            QueuedPush::enqueue(connection, &pusher.user_id, &pusher.app_id, &body)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Post the oldest queued notifications that are due to the push gateways, which is the work of
+/// the push worker.
+///
+/// Notifications for pushers that are backing off are postponed until the end of the backoff.
+/// Returns the number of notifications taken from the queue, whether they were sent, dropped or
+/// postponed.
+pub fn deliver_queued(connection: &PgConnection) -> Result<usize, ApiError> {
+    let queued_pushes = QueuedPush::find_due(connection, PUSH_DELIVERY_BATCH_SIZE)?;
+
+    for queued_push in &queued_pushes {
+        let pusher = Pusher::find(connection, &queued_push.user_id, &queued_push.app_id)?;
+
+        // The pusher may have been deleted or changed since.
+        let mut pusher = match pusher {
+            Some(pusher) => pusher,
+            None => {
+                queued_push.delete(connection)?;
+                continue;
+            }
+        };
+
+        let url = match pusher.url {
+            Some(ref url) if pusher.kind == "http" => url.clone(),
+            _ => {
+                queued_push.delete(connection)?;
+                continue;
+            }
+        };
+
+        let now = get_now();
+
+        if pusher.is_backing_off(now) {
+            debug!("Postponing push notifications to {} until its backoff has passed.", url);
+
+            let backoff_until = pusher.backoff_until.map_or(now, |backoff_until| backoff_until.0);
+
+            QueuedPush::postpone_for_pusher(
+                connection,
+                &queued_push.user_id,
+                &queued_push.app_id,
+                backoff_until,
+            )?;
+
+            continue;
+        }
+
+        queued_push.delete(connection)?;
+
+        match send(&url, &queued_push.body) {
+            Ok(()) => pusher.record_success(connection, now)?,
+            Err(failure) => {
+                warn!("Failed to send a push notification to {}: {}", url, failure.reason);
+
+                pusher.record_failure(connection, now, failure.status, failure.reason)?;
+            }
+        }
+    }
+
+    Ok(queued_pushes.len())
+}
+
+/// Check whether the `lifetime` in the content of an event has passed at the time `now`.
+fn is_expired(event: &Event, now: i64) -> bool {
+    let lifetime = from_str::<Value>(&event.content)
+        .ok()
+        .and_then(|content| content.get("lifetime").and_then(Value::as_i64));
+
+    match lifetime {
+        // Events store their creation time in microseconds.
+        Some(lifetime) => now - event.created_at.0 / 1000 > lifetime,
+        None => false,
+    }
+}
+
+/// Post a notification to a push gateway.
+///
+/// hyper has no timeout for connecting, but one for every write and read afterwards, so a gateway
+/// that accepts the connection cannot hold up the worker forever.
+#[cfg(not(test))]
+fn send(url: &str, body: &str) -> Result<(), DeliveryFailure> {
+    let mut client = Client::new();
+
+    client.set_read_timeout(Some(Duration::from_secs(PUSH_GATEWAY_TIMEOUT)));
+    client.set_write_timeout(Some(Duration::from_secs(PUSH_GATEWAY_TIMEOUT)));
+
+    let response = client
+        .post(url)
+        .header(ContentType::json())
+        .body(body)
+        .send()
+        .map_err(|_| DeliveryFailure {
+            status: None,
+            reason: "Failed to reach the push gateway.",
+        })?;
+
+    match response.status.class() {
+        StatusClass::Success => Ok(()),
+        _ => Err(DeliveryFailure {
+            status: Some(response.status.to_u16()),
+            reason: "The push gateway rejected the notification.",
+        }),
+    }
+}
+
+/// Remember a notification so tests can inspect it instead of posting it, and fail if the
+/// status set with `set_gateway_status` is not a success.
+#[cfg(test)]
+fn send(url: &str, body: &str) -> Result<(), DeliveryFailure> {
+    let body = from_str(body).expect("Notifications should always be JSON");
+
+    SENT_NOTIFICATIONS.with(|notifications| {
+        notifications.borrow_mut().push((url.to_string(), body));
+    });
+
+    match GATEWAY_STATUS.with(Cell::get) {
+        200...299 => Ok(()),
+        status => Err(DeliveryFailure {
+            status: Some(status),
+            reason: "The push gateway rejected the notification.",
+        }),
+    }
+}
+
+/// Make push gateways respond with the HTTP status code `status` on the current thread.
+#[cfg(test)]
+pub fn set_gateway_status(status: u16) {
+    GATEWAY_STATUS.with(|gateway_status| gateway_status.set(status));
+}
+
+/// The notifications sent to push gateways on the current thread, oldest first.
+#[cfg(test)]
+pub fn sent_notifications() -> Vec<(String, Value)> {
+    SENT_NOTIFICATIONS.with(|notifications| notifications.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::pg::data_types::PgTimestamp;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+    use serde_json::Value;
+
+    use models::event::{Event, content_hash};
+
+    use super::{Actions, CALL_RULE_ID, evaluate};
+
+    /// An event of the given type sent at the start of the Postgres epoch.
+    fn event(event_type: &str, content: &str) -> Event {
+        Event {
+            id: EventId::try_from("$event:ruma.test").unwrap(),
+            ordering: 1,
+            room_id: RoomId::try_from("!room:ruma.test").unwrap(),
+            user_id: UserId::try_from("@alice:ruma.test").unwrap(),
+            event_type: event_type.to_string(),
+            state_key: None,
+            content: content.to_string(),
+            extra_content: None,
+            created_at: PgTimestamp(0),
+            replaces_state: None,
+            prev_content: None,
+            redacted: false,
+            content_hash: content_hash(content).unwrap(),
+        }
+    }
+
+    #[test]
+    fn call_invites_are_pushed() {
+        let actions = evaluate(&event("m.call.invite", r#"{"lifetime": 60000}"#), 0, 2).unwrap();
+
+        assert_eq!(actions.rule_id, CALL_RULE_ID);
+    }
+
+    #[test]
+    fn messages_are_pushed_with_a_sound_only_in_one_to_one_rooms() {
+        let message = event("m.room.message", r#"{"body": "Hi", "msgtype": "m.text"}"#);
+        let encrypted = event("m.room.encrypted", r#"{"algorithm": "m.megolm.v1.aes-sha2"}"#);
+        let sound = |actions: &Actions| {
+            actions.tweaks.get("sound").and_then(Value::as_str).map(str::to_string)
+        };
+
+        let actions = evaluate(&message, 0, 2).unwrap();
+
+        assert_eq!(actions.rule_id, ".m.rule.room_one_to_one");
+        assert_eq!(sound(&actions), Some("default".to_string()));
+
+        let actions = evaluate(&message, 0, 3).unwrap();
+
+        assert_eq!(actions.rule_id, ".m.rule.message");
+        assert_eq!(sound(&actions), None);
+
+        let actions = evaluate(&encrypted, 0, 2).unwrap();
+
+        assert_eq!(actions.rule_id, ".m.rule.encrypted_room_one_to_one");
+        assert_eq!(sound(&actions), Some("default".to_string()));
+
+        let actions = evaluate(&encrypted, 0, 3).unwrap();
+
+        assert_eq!(actions.rule_id, ".m.rule.encrypted");
+        assert_eq!(sound(&actions), None);
+    }
+
+    #[test]
+    fn other_events_are_not_pushed() {
+        assert_eq!(evaluate(&event("m.room.topic", r#"{"topic": "Books"}"#), 0, 2), None);
+    }
+
+    #[test]
+    fn presence_is_never_pushed() {
+        assert_eq!(evaluate(&event("m.presence", r#"{"presence": "online"}"#), 0, 2), None);
+    }
+}
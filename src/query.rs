@@ -7,23 +7,45 @@ use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::i64;
 use std::iter::Iterator;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::usize;
 
 use diesel::pg::PgConnection;
 use ruma_events::EventType;
 use ruma_events::stripped::StrippedState;
-use ruma_events::collections::all::{RoomEvent, StateEvent};
 use ruma_events::presence::PresenceEvent;
 use ruma_events::presence::PresenceState;
-use ruma_identifiers::RoomId;
-use serde_json::Value;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Map, Value, from_str, to_value};
 
+use config::Config;
+use db::Database;
 use error::ApiError;
+use history_visibility::{HistoryTimeline, ReadableUntil};
+use models::account_data::{AccountData, RoomAccountData};
 use models::event::Event;
-use models::filter::{ContentFilter, RoomEventFilter, RoomFilter};
+use models::event_purge::stream_has_gap_since;
+use models::filter::{ContentFilter, EventFilter, EventFormat, RoomEventFilter, RoomFilter};
+use models::room::Room;
 use models::room_membership::RoomMembership;
 use models::presence_list::PresenceList;
 use models::presence_status::PresenceStatus;
+use models::to_device_message::ToDeviceEvent;
 use models::user::User;
+use util::event_fields::project_event;
+use util::pagination::{Stream, Token};
+use util::worker_pool::{Workers, map_concurrently};
+use views::event::{RenderOptions, render_events};
+
+/// The types of the state events shown to users who knocked on a room, besides their membership.
+const KNOCK_STATE_EVENTS: [EventType; 6] = [
+    EventType::RoomAvatar,
+    EventType::RoomCanonicalAlias,
+    EventType::RoomCreate,
+    EventType::RoomJoinRules,
+    EventType::RoomName,
+    EventType::RoomTopic,
+];
 
 /// Counts of unread notifications for a room.
 #[derive(Debug, Clone, Serialize)]
@@ -37,13 +59,27 @@ struct UnreadNotificationCounts {
 #[derive(Debug, Clone, Serialize)]
 struct Timeline {
     /// List of events.
-    events: Vec<RoomEvent>,
-    /// True if the number of events returned was limited by the limit on the filter.
+    events: Vec<Value>,
+    /// True if the number of events returned was limited by the limit on the filter, or if events
+    /// since the last sync were purged.
     limited: bool,
     /// A token that can be supplied to to the from parameter of the `rooms/{roomId}/messages` endpoint.
+    ///
+    /// It points before the oldest event of the timeline, and is empty if there are no events.
+    /// That endpoint is not implemented yet, so nothing accepts the token so far.
     prev_batch: String,
 }
 
+/// Account data of a user, in the format of an event.
+#[derive(Debug, Clone, Serialize)]
+struct AccountDataEvent {
+    /// The type of the data.
+    #[serde(rename = "type")]
+    event_type: String,
+    /// The contents.
+    content: Value,
+}
+
 /// Generic placeholder for the different event types.
 #[derive(Debug, Clone, Serialize)]
 struct Events<T> {
@@ -53,15 +89,21 @@ struct Events<T> {
 #[derive(Debug, Clone, Serialize)]
 struct LeftRoom {
     /// The state updates for the room up to the start of the timeline.
-    state: Events<StateEvent>,
+    state: Events<Value>,
     /// The timeline of messages and state changes in the room up to the point when the user left.
     timeline: Timeline,
 }
 
 #[derive(Debug, Clone, Serialize)]
 struct InvitedRoom {
-    /// The state of a room that the user has been invited to.
-    invite_state: Events<StrippedState>,
+    /// The state of a room that the user has been invited to, as stripped state events.
+    invite_state: Events<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct KnockedRoom {
+    /// The state of a room that the user has knocked on, as stripped state events.
+    knock_state: Events<Value>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -73,7 +115,7 @@ struct JoinedRoom {
     /// Updates to the state, between the time indicated by the since parameter,
     /// and the start of the timeline (or all state up to the start of the timeline,
     /// if since is not given, or full_state is true).
-    state: Events<StateEvent>,
+    state: Events<Value>,
     /// The private data that this user has attached to this room.
     account_data: Events<Value>,
     /// The ephemeral events in the room that aren't recorded in the timeline or
@@ -87,19 +129,114 @@ struct Rooms {
     invite: HashMap<RoomId, InvitedRoom>,
     /// The rooms that the user has joined.
     join: HashMap<RoomId, JoinedRoom>,
+    /// The rooms that the user has knocked on.
+    knock: HashMap<RoomId, KnockedRoom>,
     /// The rooms that the user has left or been banned from.
     leave: HashMap<RoomId, LeftRoom>,
 }
 
+/// The section of a room in a sync, depending on the membership of the user.
+#[derive(Debug, Clone)]
+enum RoomSection {
+    /// A room the user has been invited to.
+    Invite(RoomId, InvitedRoom),
+    /// A room the user has joined.
+    Join(RoomId, JoinedRoom),
+    /// A room the user has knocked on.
+    Knock(RoomId, KnockedRoom),
+    /// A room the user has left or been banned from.
+    Leave(RoomId, LeftRoom),
+}
+
+/// The events of a timeline that are visible to the user.
+#[derive(Debug, Clone)]
+struct TimelineEvents {
+    /// The events, oldest first.
+    events: Vec<Event>,
+    /// Whether older visible events were left out.
+    limited: bool,
+    /// The ordering the `prev_batch` token paginates backwards from.
+    prev_batch: i64,
+}
+
+/// What the sections of the rooms of a sync depend on, besides the memberships.
+#[derive(Debug, Clone)]
+struct RoomSectionOptions {
+    /// The ID of the syncing user.
+    user_id: UserId,
+    /// The hash of the token the user syncs with.
+    token_hash: String,
+    /// The users the syncing user ignores.
+    ignored_user_ids: Vec<UserId>,
+    /// The number of most recent events in the timelines, or `None` for all of them.
+    timeline_limit: Option<usize>,
+    /// Whether to include the rooms the user has left.
+    include_leave: bool,
+    /// Whether to include the full state of joined rooms instead of the changes since `since`.
+    is_full_state: bool,
+    /// The room key of the last sync, or -1 for an initial sync.
+    since: i64,
+}
+
+/// The threads that assemble the rooms of an initial sync concurrently.
+pub struct RoomWorkers {
+    /// The database the workers get their connections from.
+    pub database: Arc<Database>,
+    /// The threads reserved for the workers, each of which holds a connection while the rooms
+    /// are assembled.
+    pub workers: Workers,
+}
+
 /// A Sync response.
 #[derive(Debug, Clone, Serialize)]
 pub struct Sync {
+    /// The account data of the user that isn't associated with any room.
+    account_data: Events<AccountDataEvent>,
     /// The batch token to supply in the since param of the next /sync request.
     next_batch: String,
+    /// The batch `next_batch` was made from.
+    #[serde(skip_serializing)]
+    batch: Batch,
     /// The updates to the presence status of other users.
     presence: Events<PresenceEvent>,
     /// Updates to rooms.
     rooms: Rooms,
+    /// The messages sent directly to the syncing device.
+    to_device: Events<ToDeviceEvent>,
+}
+
+/// The number of messages returned by `/rooms/:room_id/initialSync` if the client does not
+/// specify a limit.
+pub const DEFAULT_ROOM_INITIAL_SYNC_LIMIT: usize = 10;
+
+/// The most recent messages of a room, in the format of the legacy pagination API.
+#[derive(Debug, Clone, Serialize)]
+struct MessagesChunk {
+    /// The messages, oldest first.
+    chunk: Vec<Value>,
+    /// A token of the messages stream pointing after the newest message.
+    end: String,
+    /// A token of the messages stream pointing before the oldest message, like the `prev_batch`
+    /// of a `/sync` timeline.
+    start: String,
+}
+
+/// The response of the deprecated `/rooms/:room_id/initialSync` endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoomInitialSync {
+    /// The private data that the user has attached to the room.
+    account_data: Vec<AccountDataEvent>,
+    /// The membership state of the user in the room, if they have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    membership: Option<String>,
+    /// The most recent messages of the room.
+    messages: MessagesChunk,
+    /// The ID of the room.
+    room_id: RoomId,
+    /// The state of the room at the end of `messages`.
+    state: Vec<Value>,
+    /// Whether the room is visible in the room directory, either "public" or "private".
+    visibility: &'static str,
 }
 
 /// A State Ordering.
@@ -109,14 +246,18 @@ pub struct Batch {
     pub room_key: i64,
     /// The presence ordering key.
     pub presence_key: i64,
+    /// The ID of the last message delivered to the syncing device. Syncing with the batch
+    /// acknowledges the messages up to it.
+    pub to_device_key: i64,
 }
 
 impl Batch {
     /// Create a new `Batch`.
-    pub fn new(room_key: i64, presence_key: i64) -> Batch {
+    pub fn new(room_key: i64, presence_key: i64, to_device_key: i64) -> Batch {
         Batch {
             room_key: room_key,
             presence_key: presence_key,
+            to_device_key: to_device_key,
         }
     }
 }
@@ -124,16 +265,19 @@ impl Batch {
 impl Display for Batch {
     /// Make a String from a `Batch`.
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        write!(f, "{}_{}", self.room_key, self.presence_key)
+        write!(f, "{}_{}_{}", self.room_key, self.presence_key, self.to_device_key)
     }
 }
 
 impl FromStr for Batch {
     type Err = String;
+
+    /// Parse a `Batch`. Batches handed out before to-device messages were acknowledged have no
+    /// to-device key, and acknowledge no messages.
     fn from_str(s: &str) -> Result<Batch, String> {
         let values: Vec<&str> = s.split('_').collect();
 
-        if values.len() != 2 {
+        if values.len() != 2 && values.len() != 3 {
             return Err(String::from("Wrong number of tokens"));
         }
 
@@ -143,7 +287,12 @@ impl FromStr for Batch {
         let presence_key = i64::from_str_radix(values[1], 10)
             .map_err(|err| err.to_string())?;
 
-        Ok(Batch::new(room_key, presence_key))
+        let to_device_key = match values.get(2) {
+            Some(value) => i64::from_str_radix(value, 10).map_err(|err| err.to_string())?,
+            None => 0,
+        };
+
+        Ok(Batch::new(room_key, presence_key, to_device_key))
     }
 }
 
@@ -174,12 +323,20 @@ pub enum Context<'a> {
 }
 
 impl Sync {
-    /// Query sync.
+    /// Query sync for `user`, who syncs with the token with the hash `token_hash`.
+    ///
+    /// If `presence_requires_consent` is set, presence is only included for users who have the
+    /// syncing user on their own presence list. The timelines of rooms have at most
+    /// `max_sync_timeline_limit` events, whatever the `timeline.limit` of the filter. The rooms
+    /// of an initial sync are assembled concurrently by the `room_workers`, or one after the other
+    /// on `connection` if there are none.
     pub fn sync(
         connection: &PgConnection,
-        homeserver_domain: &str,
+        config: &Config,
         user: &User,
-        options: SyncOptions
+        token_hash: &str,
+        options: SyncOptions,
+        room_workers: Option<RoomWorkers>,
     ) -> Result<Sync, ApiError> {
         let mut context = Context::Initial;
 
@@ -191,46 +348,157 @@ impl Sync {
             }
         }
 
-        let filter_room = match options.filter {
-            Some(filter) => filter.room,
-            None => None
+        let (filter_room, filter_account_data) = match options.filter {
+            Some(filter) => (filter.room, filter.account_data),
+            None => (None, None),
         };
 
+        let account_data = Sync::get_account_data_events(connection, user, &filter_account_data)?;
+
         let (presence_key, presence) = Sync::get_presence_events(
             connection,
-            homeserver_domain,
+            &config.domain,
             user,
             options.set_presence,
-            &context
+            &context,
+            &config.presence_suppressed_rooms,
+            config.presence_requires_consent
         )?;
 
-        let (room_key, rooms) = Sync::get_rooms_events(connection, user, filter_room, &context)?;
-        let batch = Batch::new(room_key, presence_key);
+        let (room_key, rooms) = Sync::get_rooms_events(
+            connection,
+            user,
+            token_hash,
+            filter_room,
+            config.max_sync_timeline_limit,
+            &context,
+            room_workers,
+        )?;
+        // Messages delivered to the device since are only acknowledged by the handler.
+        let to_device_key = options.since.as_ref().map_or(0, |since| since.to_device_key);
+        let batch = Batch::new(room_key, presence_key, to_device_key);
         let state = Sync {
+            account_data: Events {
+                events: account_data,
+            },
             next_batch: batch.to_string(),
+            batch: batch,
             presence: Events {
                 events: presence,
             },
             rooms: rooms,
+            to_device: Events {
+                events: Vec::new(),
+            },
         };
 
         Ok(state)
     }
 
+    /// Deliver messages sent directly to the syncing device with the response. `last_id` is the
+    /// ID of the last message, which the next sync acknowledges.
+    pub fn add_to_device_events(&mut self, events: Vec<ToDeviceEvent>, last_id: Option<i64>) {
+        self.to_device.events.extend(events);
+
+        if let Some(last_id) = last_id {
+            self.batch.to_device_key = last_id;
+            self.next_batch = self.batch.to_string();
+        }
+    }
+
+    /// Serialize the response with its room events in the `event_format` and with the
+    /// `event_fields` of `filter`.
+    ///
+    /// Federation format adds the `origin` of the timeline and state events. Events are not
+    /// signed yet, so there are no signatures or hashes to include.
+    pub fn to_filtered_value(&self, filter: &ContentFilter) -> Result<Value, ApiError> {
+        let mut value = to_value(self).map_err(ApiError::from)?;
+        let paths = filter.event_field_paths();
+        let is_federation_format = filter.event_format == Some(EventFormat::Federation);
+
+        if paths.is_none() && !is_federation_format {
+            return Ok(value);
+        }
+
+        for membership in &["join", "leave"] {
+            let rooms = value.get_mut("rooms")
+                .and_then(|rooms| rooms.get_mut(membership))
+                .and_then(Value::as_object_mut);
+
+            let rooms = match rooms {
+                Some(rooms) => rooms,
+                None => continue,
+            };
+
+            for (_, room) in rooms.iter_mut() {
+                for section in &["timeline", "state", "ephemeral"] {
+                    let events = room.get_mut(section)
+                        .and_then(|section| section.get_mut("events"))
+                        .and_then(Value::as_array_mut);
+
+                    for event in events.into_iter().flat_map(|events| events.iter_mut()) {
+                        if is_federation_format && *section != "ephemeral" {
+                            add_origin(event);
+                        }
+
+                        if let Some(ref paths) = paths {
+                            *event = project_event(event, paths);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// Return the account data of the user that passes the filter, at most `limit` events of it
+    /// in the order it was first stored. A limit of 0 does not limit the events.
+    ///
+    /// Changes to account data are not tracked, so all of it is included in every sync.
+    fn get_account_data_events(
+        connection: &PgConnection,
+        user: &User,
+        account_data_filter: &Option<EventFilter>,
+    ) -> Result<Vec<AccountDataEvent>, ApiError> {
+        let limit = match account_data_filter.as_ref().map_or(0, |filter| filter.limit) {
+            0 => usize::MAX,
+            limit => limit,
+        };
+
+        AccountData::get_by_uid(connection, &user.id)?
+            .into_iter()
+            .filter(|data| {
+                account_data_filter
+                    .as_ref()
+                    .map_or(true, |filter| filter.allows_type(&data.data_type))
+            })
+            .take(limit)
+            .map(|data| {
+                Ok(AccountDataEvent {
+                    content: from_str(&data.content).map_err(ApiError::from)?,
+                    event_type: data.data_type,
+                })
+            })
+            .collect()
+    }
+
     /// Return presence events for sync from database and options.
     fn get_presence_events(
         connection: &PgConnection,
         homeserver_domain: &str,
         user: &User,
         set_presence: Option<PresenceState>,
-        context: &Context
+        context: &Context,
+        presence_suppressed_rooms: &[RoomId],
+        presence_requires_consent: bool,
     ) -> Result<(i64, Vec<PresenceEvent>), ApiError> {
         let set_presence = match set_presence {
             Some(set_presence) => set_presence,
             None => PresenceState::Online,
         };
 
-        PresenceStatus::upsert(connection, homeserver_domain, &user.id, Some(set_presence), None)?;
+        PresenceStatus::upsert(connection, homeserver_domain, &user.id, Some(set_presence), None, None)?;
 
         let since = match *context {
             Context::Incremental(batch) | Context::FullState(batch)  => {
@@ -242,22 +510,32 @@ impl Sync {
         PresenceList::find_events_by_uid(
             connection,
             &user.id,
-            since
+            since,
+            presence_suppressed_rooms,
+            presence_requires_consent
         )
     }
 
     /// Return rooms for sync from database and options.
+    ///
+    /// The rooms of an initial sync are assembled by the `room_workers` if there are any, each
+    /// with its own connection. The rooms are the same either way.
     fn get_rooms_events(
         connection: &PgConnection,
         user: &User,
+        token_hash: &str,
         room_filter: Option<RoomFilter>,
+        max_timeline_limit: Option<usize>,
         context: &Context,
+        room_workers: Option<RoomWorkers>,
     ) -> Result<(i64, Rooms), ApiError> {
         let mut join = HashMap::new();
         let mut invite = HashMap::new();
+        let mut knock = HashMap::new();
         let mut leave = HashMap::new();
 
         let room_memberships = RoomMembership::find_all_by_uid(connection, &user.id)?;
+        let ignored_user_ids = AccountData::find_ignored_user_ids(connection, &user.id)?;
 
         let mut room_ordering = match *context {
             Context::Incremental(batch) | Context::FullState(batch) => batch.room_key,
@@ -275,182 +553,451 @@ impl Sync {
             None => (None, false),
         };
 
-        for room_membership in room_memberships {
-            match room_membership.membership.as_str() {
-                "join" => {
-                    let events: Vec<Event> = Event::find_room_events(connection, &room_membership.room_id, since)?;
-
-                    let room_state_events: Vec<Event> = if is_full_state {
-                        Event::get_room_full_state(connection, &room_membership.room_id)?
-                    } else {
-                        Event::get_room_state_events_since(connection, &room_membership.room_id, since)?
-                    };
+        let options = RoomSectionOptions {
+            user_id: user.id.clone(),
+            token_hash: token_hash.to_string(),
+            ignored_user_ids: ignored_user_ids,
+            timeline_limit: timeline_limit(&timeline_filter, max_timeline_limit),
+            include_leave: include_leave,
+            is_full_state: is_full_state,
+            since: since,
+        };
 
-                    if events.is_empty() && room_state_events.is_empty() {
-                        continue;
-                    }
+        let sections = match (context, room_workers) {
+            (&Context::Initial, Some(room_workers)) => {
+                let database = room_workers.database;
 
-                    let (ordering, timeline) = Sync::convert_events_to_timeline(events, &timeline_filter)?;
-                    room_ordering = cmp::max(ordering, room_ordering);
-
-                    let state_events: Vec<StateEvent> = room_state_events.iter().cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StateEvent>, ApiError>>()?;
-
-                    join.insert(room_membership.room_id, JoinedRoom {
-                        unread_notifications: UnreadNotificationCounts {
-                            highlight_count: 0,
-                            notification_count: 0,
-                        },
-                        timeline: timeline,
-                        state: Events {
-                            events: state_events,
-                        },
-                        account_data: Events {
-                            events: Vec::new(),
-                        },
-                        ephemeral: Events {
-                            events: Vec::new(),
-                        },
-                    });
-                },
-                "invite" => {
-                    let room_state_events = Event::get_room_full_state(connection, &room_membership.room_id)?;
-
-                    let state_events: Vec<StrippedState> = room_state_events.iter().cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StrippedState>, ApiError>>()?;
-
-                    invite.insert(room_membership.room_id, InvitedRoom {
-                        invite_state: Events {
-                            events: state_events,
-                        },
-                    });
-                },
-                "leave" | "ban" => {
-                    if !include_leave {
-                        continue;
-                    }
+                map_concurrently(
+                    room_memberships,
+                    room_workers.workers,
+                    move || database.get(),
+                    move |connection, room_membership| {
+                        Sync::get_room_section(connection, room_membership, &options)
+                    },
+                )?
+            }
+            _ => {
+                room_memberships.iter().map(|room_membership| {
+                    Sync::get_room_section(connection, room_membership, &options)
+                }).collect::<Result<Vec<_>, ApiError>>()?
+            }
+        };
 
-                    let last_event = Event::find(&connection, &room_membership.event_id)?
-                        .expect("A room membership should be associated with an event");
-
-                    let events = Event::find_room_events_until(
-                        connection,
-                        &room_membership.room_id,
-                        &last_event.ordering,
-                    )?;
-
-                    let (ordering, timeline) = Sync::convert_events_to_timeline(events, &timeline_filter)?;
-                    room_ordering = cmp::max(ordering, room_ordering);
-
-                    let room_state_events = Event::get_room_state_events_until(
-                        connection,
-                        &room_membership.room_id,
-                        &last_event,
-                    )?;
-                    let state_events: Vec<StateEvent> = room_state_events.iter().cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StateEvent>, ApiError>>()?;
-
-                    leave.insert(room_membership.room_id, LeftRoom {
-                        timeline: timeline,
-                        state: Events {
-                            events: state_events,
-                        },
-                    });
-                },
-                _ => (),
+        for (ordering, section) in sections.into_iter().filter_map(|section| section) {
+            match section {
+                RoomSection::Invite(room_id, room) => {
+                    invite.insert(room_id, room);
+                }
+                RoomSection::Join(room_id, room) => {
+                    join.insert(room_id, room);
+                }
+                RoomSection::Knock(room_id, room) => {
+                    knock.insert(room_id, room);
+                }
+                RoomSection::Leave(room_id, room) => {
+                    leave.insert(room_id, room);
+                }
             }
+
+            room_ordering = cmp::max(ordering, room_ordering);
         }
 
         Ok((room_ordering, Rooms {
             join: join,
+            knock: knock,
             leave: leave,
             invite: invite,
         }))
     }
 
-    /// Converting events in the correct format for timeline.
+    /// Assemble the section of the room of a membership, with the greatest ordering of its
+    /// timeline.
     ///
-    /// Also returns the max ordering from the given events that will be used
-    /// as the `next_batch` token.
-    fn convert_events_to_timeline(
-        events: Vec<Event>,
-        timeline_filter: &Option<RoomEventFilter>
-    ) -> Result<(i64, Timeline), ApiError> {
-        let mut room_ordering = 0;
-        let mut timeline_events = Vec::new();
-        let mut limited = false;
+    /// Returns `None` if the room has nothing to sync.
+    fn get_room_section(
+        connection: &PgConnection,
+        room_membership: &RoomMembership,
+        options: &RoomSectionOptions,
+    ) -> Result<Option<(i64, RoomSection)>, ApiError> {
+        let room_id = &room_membership.room_id;
+        let (since, timeline_limit) = (options.since, options.timeline_limit);
+        let render_options = RenderOptions {
+            user_id: Some(&options.user_id),
+            token_hash: Some(&options.token_hash),
+            ignored_user_ids: &options.ignored_user_ids,
+            bundle_relations: true,
+        };
 
-        let length = events.len();
+        match room_membership.membership.as_str() {
+            "join" => {
+                let history = HistoryTimeline::load(connection, room_id, &options.user_id)?;
+                let mut timeline_events = Sync::find_timeline_events(
+                    connection,
+                    room_id,
+                    &history,
+                    since,
+                    None,
+                    timeline_limit,
+                )?;
+
+                // Clients that may have missed purged events have to resync the room.
+                let has_gap = since >= 0 && stream_has_gap_since(connection, room_id, since)?;
+
+                let room_state_events: Vec<Event> = if options.is_full_state || has_gap {
+                    Event::get_room_full_state(connection, room_id)?
+                } else {
+                    Event::get_room_state_events_since(connection, room_id, since)?
+                };
+
+                if timeline_events.events.is_empty() && room_state_events.is_empty() {
+                    return Ok(None);
+                }
 
-        let count = match *timeline_filter {
-            None => 0,
-            Some(ref filter) => {
-                match filter.limit {
-                    0 => 0,
-                    x => {
-                        if length > x {
-                            limited = true;
-                            length - x
-                        } else {
-                            0
-                        }
+                timeline_events.limited = timeline_events.limited || has_gap;
+
+                let (ordering, timeline) = Sync::convert_events_to_timeline(
+                    connection,
+                    timeline_events,
+                    &render_options,
+                )?;
+
+                let state_events = render_events(connection, room_state_events, &render_options)?;
+
+                Ok(Some((ordering, RoomSection::Join(room_id.clone(), JoinedRoom {
+                    unread_notifications: UnreadNotificationCounts {
+                        highlight_count: 0,
+                        notification_count: 0,
+                    },
+                    timeline: timeline,
+                    state: Events {
+                        events: state_events,
+                    },
+                    account_data: Events {
+                        events: Vec::new(),
+                    },
+                    ephemeral: Events {
+                        events: Vec::new(),
+                    },
+                }))))
+            },
+            "invite" => {
+                let room_state_events = Event::get_room_full_state(connection, room_id)?;
+                let state_events = stripped_state(room_state_events)?;
+
+                Ok(Some((0, RoomSection::Invite(room_id.clone(), InvitedRoom {
+                    invite_state: Events {
+                        events: state_events,
                     },
+                }))))
+            },
+            "knock" => {
+                let user_id = options.user_id.to_string();
+                let room_state_events = Event::get_room_full_state(connection, room_id)?
+                    .into_iter()
+                    .filter(|event| is_knock_state(event, &user_id))
+                    .collect();
+                let state_events = stripped_state(room_state_events)?;
+
+                Ok(Some((0, RoomSection::Knock(room_id.clone(), KnockedRoom {
+                    knock_state: Events {
+                        events: state_events,
+                    },
+                }))))
+            },
+            "leave" | "ban" => {
+                if !options.include_leave {
+                    return Ok(None);
                 }
+
+                let history = HistoryTimeline::load(connection, room_id, &options.user_id)?;
+
+                let until = match history.readable_until() {
+                    Some(ReadableUntil::Before(until)) => until,
+                    _ => return Ok(None),
+                };
+
+                let timeline_events = Sync::find_timeline_events(
+                    connection,
+                    room_id,
+                    &history,
+                    -1,
+                    Some(until),
+                    timeline_limit,
+                )?;
+
+                let (ordering, timeline) = Sync::convert_events_to_timeline(
+                    connection,
+                    timeline_events,
+                    &render_options,
+                )?;
+
+                let room_state_events =
+                    Event::get_room_state_events_until(connection, room_id, until)?;
+                let state_events = render_events(connection, room_state_events, &render_options)?;
+
+                Ok(Some((ordering, RoomSection::Leave(room_id.clone(), LeftRoom {
+                    timeline: timeline,
+                    state: Events {
+                        events: state_events,
+                    },
+                }))))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Load the `limit` most recent events of a room's timeline with an ordering greater than
+    /// `after` and lower than `before` that are visible to the user of `history`.
+    ///
+    /// The events are loaded from the database in batches of one more than the limit, newest
+    /// first, until the limit is exceeded or the range is exhausted. That way the limit counts
+    /// only visible events, and long timelines are never loaded as a whole.
+    fn find_timeline_events(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        history: &HistoryTimeline,
+        after: i64,
+        before: Option<i64>,
+        limit: Option<usize>,
+    ) -> Result<TimelineEvents, ApiError> {
+        let batch_size = limit.map(|limit| limit + 1);
+        let mut events = Vec::new();
+        let mut before = before;
+        let mut prev_batch = before.unwrap_or(after + 1);
+
+        loop {
+            let batch =
+                Event::find_latest_room_events(connection, room_id, after, before, batch_size)?;
+            let is_exhausted = batch_size.map_or(true, |batch_size| batch.len() < batch_size);
+
+            if let Some(oldest) = batch.first() {
+                before = Some(oldest.ordering);
+                prev_batch = oldest.ordering;
             }
-        };
 
-        for event in events.into_iter().skip(count) {
-            room_ordering = cmp::max(room_ordering, event.ordering);
-
-            let value = match EventType::from(event.event_type.as_ref()) {
-                EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
-                EventType::CallCandidates => RoomEvent::CallCandidates(event.try_into()?),
-                EventType::CallHangup => RoomEvent::CallHangup(event.try_into()?),
-                EventType::CallInvite => RoomEvent::CallInvite(event.try_into()?),
-                EventType::RoomAliases => RoomEvent::RoomAliases(event.try_into()?),
-                EventType::RoomAvatar => RoomEvent::RoomAvatar(event.try_into()?),
-                EventType::RoomCanonicalAlias => RoomEvent::RoomCanonicalAlias(event.try_into()?),
-                EventType::RoomCreate => RoomEvent::RoomCreate(event.try_into()?),
-                EventType::RoomGuestAccess => RoomEvent::RoomGuestAccess(event.try_into()?),
-                EventType::RoomHistoryVisibility => RoomEvent::RoomHistoryVisibility(event.try_into()?),
-                EventType::RoomJoinRules => RoomEvent::RoomJoinRules(event.try_into()?),
-                EventType::RoomMember => RoomEvent::RoomMember(event.try_into()?),
-                EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
-                EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
-                EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
-                EventType::RoomThirdPartyInvite => RoomEvent::RoomThirdPartyInvite(event.try_into()?),
-                EventType::RoomTopic => RoomEvent::RoomTopic(event.try_into()?),
-                _ => {
-                    println!("unhandled {:?}", event.event_type);
-                    continue;
-                },
-            };
+            let mut visible = history.filter(batch);
+            visible.append(&mut events);
+            events = visible;
 
-            timeline_events.push(value);
+            if is_exhausted || limit.map_or(true, |limit| events.len() > limit) {
+                break;
+            }
         }
 
+        let excess = limit.map_or(0, |limit| events.len().saturating_sub(limit));
+        let limited = excess > 0;
+
+        events.drain(..excess);
+
+        if let Some(oldest) = events.first() {
+            prev_batch = oldest.ordering;
+        }
+
+        Ok(TimelineEvents {
+            events: events,
+            limited: limited,
+            prev_batch: prev_batch,
+        })
+    }
+
+    /// Converting events in the correct format for timeline.
+    ///
+    /// The events are rendered with `options`. Also returns the max ordering from the given events
+    /// that will be used as the `next_batch` token.
+    fn convert_events_to_timeline(
+        connection: &PgConnection,
+        timeline_events: TimelineEvents,
+        options: &RenderOptions,
+    ) -> Result<(i64, Timeline), ApiError> {
+        let TimelineEvents { events, limited, prev_batch } = timeline_events;
+        let room_ordering = events.iter().map(|event| event.ordering).max().unwrap_or(0);
+
         Ok((room_ordering, Timeline {
-            events: timeline_events,
+            events: render_events(connection, events, options)?,
             limited: limited,
-            prev_batch: String::from(""),
+            prev_batch: Token::new(Stream::Messages, prev_batch).to_string(),
         }))
     }
 }
 
+/// The number of most recent events in the timelines of a sync, or `None` for all of them.
+///
+/// The `limit` of the timeline filter is clamped to `max_timeline_limit`, which also applies if
+/// the filter does not limit the timeline, as with a limit of 0.
+fn timeline_limit(
+    timeline_filter: &Option<RoomEventFilter>,
+    max_timeline_limit: Option<usize>,
+) -> Option<usize> {
+    let limit = timeline_filter.as_ref()
+        .map(|filter| filter.limit)
+        .and_then(|limit| if limit == 0 { None } else { Some(limit) });
+
+    match (limit, max_timeline_limit) {
+        (Some(limit), Some(max)) => Some(cmp::min(limit, max)),
+        (limit, None) => limit,
+        (None, max) => max,
+    }
+}
+
+/// Whether a state event is shown to a user who knocked on the room: the events describing the
+/// room and the user's own membership, but not the memberships of the room's members.
+fn is_knock_state(event: &Event, user_id: &str) -> bool {
+    if event.event_type == EventType::RoomMember.to_string() {
+        return event.state_key.as_ref().map(String::as_str) == Some(user_id);
+    }
+
+    KNOCK_STATE_EVENTS.iter().any(|event_type| event.event_type == event_type.to_string())
+}
+
+/// Convert state events into serialized stripped state events.
+///
+/// The content of the m.room.create event is taken as it is stored, since ruma-events drops the
+/// keys it does not know about, like the room's *type*. So is the content of redacted events,
+/// which ruma-events has no stripped type for.
+fn stripped_state(events: Vec<Event>) -> Result<Vec<Value>, ApiError> {
+    events.into_iter().map(|event| {
+        let is_create_event = event.event_type == EventType::RoomCreate.to_string();
+        let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+        if event.is_served_as_custom() {
+            let mut value = Map::new();
+
+            value.insert("content".to_string(), content);
+            value.insert("state_key".to_string(), Value::from(event.state_key.unwrap_or_default()));
+            value.insert("type".to_string(), Value::from(event.event_type));
+
+            return Ok(Value::Object(value));
+        }
+
+        let stripped_event: StrippedState = event.try_into()?;
+        let mut value = to_value(&stripped_event).map_err(ApiError::from)?;
+
+        if is_create_event {
+            value["content"] = content;
+        }
+
+        Ok(value)
+    }).collect()
+}
+
+/// Add the server name of a serialized event's sender as its `origin`, like in the format of
+/// federation.
+fn add_origin(event: &mut Value) {
+    let origin = event.get("sender")
+        .and_then(Value::as_str)
+        .and_then(|sender| sender.splitn(2, ':').nth(1))
+        .map(str::to_string);
+
+    if let (Some(origin), Some(event)) = (origin, event.as_object_mut()) {
+        event.insert("origin".to_string(), Value::String(origin));
+    }
+}
+
+impl RoomInitialSync {
+    /// Query the state and the `limit` most recent messages of a room, as seen by `user`, or by
+    /// a user who is not logged in if `user` is `None`. Transaction IDs are echoed to the token
+    /// with the hash `token_hash`.
+    ///
+    /// Users who left or were banned see the room up to that point, like in `/sync`. Users who
+    /// are not members see its current state if the room is world-readable, and fail with
+    /// `M_FORBIDDEN` otherwise. Messages are filtered by the history visibility.
+    pub fn initial_sync(
+        connection: &PgConnection,
+        user: Option<&User>,
+        token_hash: Option<&str>,
+        room: &Room,
+        limit: usize,
+    ) -> Result<RoomInitialSync, ApiError> {
+        let (membership, ignored_user_ids, history) = match user {
+            Some(user) => (
+                RoomMembership::find(connection, &room.id, &user.id)?,
+                AccountData::find_ignored_user_ids(connection, &user.id)?,
+                HistoryTimeline::load(connection, &room.id, &user.id)?,
+            ),
+            None => (None, Vec::new(), HistoryTimeline::load_anonymous(connection, &room.id)?),
+        };
+
+        let (before, room_state_events) = match history.readable_until() {
+            Some(ReadableUntil::Now) => (None, Event::get_room_full_state(connection, &room.id)?),
+            Some(ReadableUntil::Before(until)) => (
+                Some(until),
+                Event::get_room_state_events_until(connection, &room.id, until)?,
+            ),
+            None => {
+                return Err(ApiError::unauthorized(
+                    "The user is not a member of the room".to_string()
+                ));
+            }
+        };
+
+        let timeline_events =
+            Sync::find_timeline_events(connection, &room.id, &history, -1, before, Some(limit))?;
+
+        // The tokens are the same as the `prev_batch` of the timeline in `/sync`, so clients can
+        // page through the rest of the room with them instead of loading it at once.
+        let start = timeline_events.prev_batch;
+        let end = timeline_events.events.last().map_or(start, |event| event.ordering);
+
+        let render_options = RenderOptions {
+            user_id: user.map(|user| &user.id),
+            token_hash: token_hash,
+            ignored_user_ids: &ignored_user_ids,
+            bundle_relations: true,
+        };
+
+        let (_, timeline) =
+            Sync::convert_events_to_timeline(connection, timeline_events, &render_options)?;
+
+        let state = render_events(connection, room_state_events, &render_options)?;
+
+        let room_account_data = match user {
+            Some(user) => RoomAccountData::find_by_uid_and_room(connection, &user.id, &room.id)?,
+            None => Vec::new(),
+        };
+
+        let account_data = room_account_data
+            .into_iter()
+            .map(|data| {
+                Ok(AccountDataEvent {
+                    content: from_str(&data.content).map_err(ApiError::from)?,
+                    event_type: data.data_type,
+                })
+            })
+            .collect::<Result<Vec<AccountDataEvent>, ApiError>>()?;
+
+        Ok(RoomInitialSync {
+            account_data: account_data,
+            membership: membership.map(|membership| membership.membership),
+            messages: MessagesChunk {
+                chunk: timeline.events,
+                end: Token::new(Stream::Messages, end).to_string(),
+                start: Token::new(Stream::Messages, start).to_string(),
+            },
+            room_id: room.id.clone(),
+            state: state,
+            visibility: if room.public { "public" } else { "private" },
+        })
+    }
+}
+
 #[test]
 fn batch_to_str() {
-    let batch = Batch::new(10, 10);
-    assert_eq!(batch.to_string(), String::from("10_10"));
+    let batch = Batch::new(10, 10, 3);
+    assert_eq!(batch.to_string(), String::from("10_10_3"));
 }
 
 #[test]
 fn batch_parse() {
-    let batch = Batch::from_str("10_12").unwrap();
+    let batch = Batch::from_str("10_12_3").unwrap();
     assert_eq!(batch.room_key, 10);
     assert_eq!(batch.presence_key, 12);
+    assert_eq!(batch.to_device_key, 3);
+}
+
+#[test]
+fn batch_parse_without_to_device_key() {
+    let batch = Batch::from_str("10_12").unwrap();
+    assert_eq!(batch, Batch::new(10, 12, 0));
 }
 
 #[test]
@@ -461,6 +1008,6 @@ fn batch_parse_non_number() {
 
 #[test]
 fn batch_parse_too_many() {
-    let batch = Batch::from_str("10_12_12");
+    let batch = Batch::from_str("10_12_12_12");
     assert!(batch.is_err());
 }
@@ -4,14 +4,27 @@
 
 #![allow(missing_docs)]
 
+// The table Diesel records the applied migrations in.
+table! {
+    __diesel_schema_migrations(version) {
+        version -> VarChar,
+        run_on -> Timestamp,
+    }
+}
+
 table! {
     access_tokens {
         id -> BigSerial,
         user_id -> Text,
-        value -> Text,
+        token_hash -> Text,
+        device_id -> Nullable<Text>,
         revoked -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        last_used_at -> Timestamp,
+        last_used_ip -> Nullable<Text>,
+        soft_logout -> Bool,
+        expiry_warned_at -> Nullable<Timestamp>,
     }
 }
 
@@ -24,6 +37,15 @@ table! {
     }
 }
 
+table! {
+    devices(user_id, id) {
+        id -> Text,
+        user_id -> Text,
+        display_name -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     events {
         id -> Text,
@@ -35,6 +57,10 @@ table! {
         content -> Text,
         extra_content -> Nullable<Text>,
         created_at -> Timestamp,
+        replaces_state -> Nullable<Text>,
+        prev_content -> Nullable<Text>,
+        redacted -> Bool,
+        content_hash -> Text,
     }
 }
 
@@ -74,6 +100,8 @@ table! {
         user_id -> Text,
         public -> Bool,
         created_at -> Timestamp,
+        room_type -> Nullable<Text>,
+        joined_member_count -> BigInt,
     }
 }
 
@@ -84,6 +112,7 @@ table! {
         active -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        admin -> Bool,
     }
 }
 
@@ -108,10 +137,24 @@ table! {
 }
 
 table! {
-    transactions (path, access_token) {
+    transactions (path, access_token, user_id) {
         path -> Text,
         access_token -> Text,
+        user_id -> Text,
         response -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    federation_queue {
+        id -> BigSerial,
+        destination -> Text,
+        kind -> Text,
+        edu_type -> Nullable<Text>,
+        coalesce_key -> Nullable<Text>,
+        payload -> Text,
+        created_at -> Timestamp,
     }
 }
 
@@ -123,12 +166,20 @@ table! {
     }
 }
 
+table! {
+    monthly_active_users(user_id) {
+        user_id -> Text,
+        last_active_at -> Timestamp,
+    }
+}
+
 table! {
     presence_status(user_id) {
         user_id -> Text,
         event_id -> Text,
         presence -> Text,
         status_msg -> Nullable<Text>,
+        sticky -> Bool,
         updated_at -> Timestamp,
     }
 }
@@ -140,6 +191,17 @@ table! {
     }
 }
 
+table! {
+    push_queue {
+        id -> BigSerial,
+        user_id -> Text,
+        app_id -> Text,
+        body -> Text,
+        next_attempt_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     pushers(user_id, app_id) {
         user_id -> Text,
@@ -151,5 +213,174 @@ table! {
         profile_tag -> Nullable<Text>,
         pushkey -> Text,
         app_display_name -> Text,
+        last_success_at -> Nullable<Timestamp>,
+        last_failure_at -> Nullable<Timestamp>,
+        last_failure_reason -> Nullable<Text>,
+        last_failure_status -> Nullable<Integer>,
+        consecutive_failures -> Integer,
+        backoff_until -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    registration_nonces(nonce) {
+        nonce -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    threepid_sessions {
+        id -> Text,
+        client_secret -> Text,
+        medium -> Text,
+        address -> Text,
+        token -> Text,
+        send_attempt -> BigInt,
+        validated -> Bool,
+        created_at -> Timestamp,
+        purpose -> Text,
+        token_attempts -> Integer,
+    }
+}
+
+table! {
+    threepids(medium, address) {
+        medium -> Text,
+        address -> Text,
+        user_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    event_relations(event_id) {
+        event_id -> Text,
+        room_id -> Text,
+        user_id -> Text,
+        relates_to_id -> Text,
+        rel_type -> Text,
+        aggregation_key -> Nullable<Text>,
+        ordering -> BigInt,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    key_backup_keys(user_id, version, room_id, session_id) {
+        user_id -> Text,
+        version -> BigInt,
+        room_id -> Text,
+        session_id -> Text,
+        first_message_index -> BigInt,
+        forwarded_count -> BigInt,
+        is_verified -> Bool,
+        session_data -> Text,
+    }
+}
+
+table! {
+    key_backups(version) {
+        version -> BigInt,
+        user_id -> Text,
+        algorithm -> Text,
+        auth_data -> Text,
+        etag -> BigInt,
+    }
+}
+
+table! {
+    mail_queue {
+        id -> BigSerial,
+        recipient -> Text,
+        subject -> Text,
+        html_body -> Text,
+        text_body -> Text,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    email_digests(user_id) {
+        user_id -> Text,
+        sent_at -> Timestamp,
+    }
+}
+
+table! {
+    email_notifications {
+        id -> BigSerial,
+        user_id -> Text,
+        room_id -> Text,
+        event_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    event_purges {
+        id -> BigSerial,
+        room_id -> Text,
+        purged_up_to -> BigInt,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    admin_audit_log {
+        id -> BigSerial,
+        action -> Text,
+        target -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    failed_logins(user_id) {
+        user_id -> Text,
+        attempts -> Integer,
+        first_failed_at -> Timestamp,
+    }
+}
+
+table! {
+    user_erasures(user_id) {
+        user_id -> Text,
+        redacted_up_to -> BigInt,
+        redacted_events -> BigInt,
+        created_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    event_lookup(event_id) {
+        event_id -> Text,
+        room_id -> Text,
+        ordering -> BigInt,
+        sender -> Text,
+    }
+}
+
+table! {
+    to_device_messages {
+        id -> BigSerial,
+        user_id -> Text,
+        device_id -> Text,
+        sender -> Text,
+        event_type -> Text,
+        content -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    event_transactions(event_id) {
+        event_id -> Text,
+        access_token -> Text,
+        transaction_id -> Text,
+        created_at -> Timestamp,
     }
 }
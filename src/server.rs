@@ -1,33 +1,68 @@
 //! Iron web server that serves the API.
-use diesel::migrations::setup_database;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
 use diesel::pg::PgConnection;
-use iron::{Chain, Iron, IronError, IronResult, Listening, Request, Response};
+use iron::{
+    Chain,
+    Iron,
+    IronError,
+    IronResult,
+    Listening,
+    Protocol,
+    Request,
+    Response,
+    Timeouts,
+};
 use iron::error::HttpResult;
 use mount::Mount;
-use persistent::{Read, Write};
+use persistent::Read;
 use r2d2::Config as R2D2Config;
 use r2d2_diesel::Error as R2D2DieselError;
 use router::Router;
 
+use api::media::PreviewUrl;
 use api::r0::{
     AccountPassword,
+    AddThreepid,
+    BatchSendEvents,
+    BulkResolveRoomAliases,
+    CreateKeyBackupVersion,
     CreateRoom,
     DeactivateAccount,
     DeleteRoomAlias,
+    DeleteRoomKeys,
     DeleteTag,
     GetAvatarUrl,
     GetDisplayName,
+    GetEventContext,
     GetFilter,
+    GetKeyBackupVersion,
+    GetMembershipTransitions,
+    GetMonthlyActiveUsers,
     GetPresenceList,
     GetPresenceStatus,
     GetPushers,
+    GetRegistrationNonce,
     GetRoomAlias,
+    GetRoomAliases,
+    GetRoomEvent,
+    GetRoomExport,
+    GetRoomKeys,
+    GetRoomVisibility,
+    GetServerVersion,
+    GetUserDataExport,
+    GetUserErasure,
     GetStateEvent,
     GetTags,
+    GetThreepids,
+    GetUserPushers,
     InviteToRoom,
     JoinRoom,
     JoinRoomWithIdOrAlias,
     KickFromRoom,
+    KnockOnRoom,
     LeaveRoom,
     Login,
     Logout,
@@ -41,26 +76,91 @@ use api::r0::{
     PutPresenceStatus,
     PutRoomAccountData,
     PutRoomAlias,
+    PutRoomKeys,
+    PutRoomVisibility,
     PutTag,
     Register,
+    RegisterAvailable,
+    RequestEmailAccountToken,
+    RequestEmailRegistrationToken,
+    RequestMsisdnAccountToken,
+    RequestMsisdnRegistrationToken,
+    RequestPasswordResetEmailToken,
+    RoomInitialSync,
     RoomState,
     SendMessageEvent,
     SetPushers,
+    SharedSecretRegister,
     StateMessageEvent,
+    SubmitThreepidToken,
     Sync,
+    UpdateKeyBackupVersion,
     Versions,
 };
+use api::v1::{GetHierarchy, GetRelations};
 use config::Config;
-use embedded_migrations::run as run_pending_migrations;
 use error::{ApiError, CliError};
-use db::DB;
-use middleware::{ResponseHeaders, MiddlewareChain};
+use db::{DB, Database};
+use health::GetHealth;
+use listener::{ConnectionLimit, DeadlineListener, RequestDeadlines};
+use mailer::{Mailer, deliver_queued, mailer, send_digests};
+use middleware::{
+    ClientIp,
+    Localization,
+    MiddlewareChain,
+    PanicRecovery,
+    RequestLogger,
+    ResponseCompression,
+    ResponseHeaders,
+    Routing,
+};
+use migrations;
+use models::device_expiry::{DEVICE_EXPIRY_BATCH_SIZE, DeviceExpiry, expire_idle_devices};
+use models::event_transaction::EventTransaction;
+use models::monthly_active_user::MonthlyActiveUser;
+use models::presence_status::PresenceStatus;
+use models::push_queue::QueuedPush;
+use models::registration_nonce::RegistrationNonce;
+use models::threepid_session::ThreepidSession;
+use models::transaction::Transaction;
+use models::user::User;
+use models::user_erasure::{ERASURE_BATCH_SIZE, erase_pending};
+use persister::EventPersister;
+use push::{PUSH_DELIVERY_BATCH_SIZE, deliver_queued as deliver_queued_pushes};
+use spam::{BlocklistSpamChecker, CompositeSpamChecker, SpamChecker};
 use swagger::Swagger;
+use util::worker_pool::WorkerLimit;
+
+/// How often, in seconds, users whose presence has been idle for too long are marked as offline.
+const PRESENCE_IDLE_CHECK_INTERVAL: u64 = 10;
+
+/// How often, in seconds, users who are no longer monthly active are removed from the count.
+const MAU_EXPIRY_INTERVAL: u64 = 60 * 60;
+
+/// How often, in seconds, the mail queue is checked for emails that are due for delivery.
+const MAIL_DELIVERY_INTERVAL: u64 = 5;
+
+/// How often, in seconds, the push queue is checked for notifications to deliver.
+const PUSH_DELIVERY_INTERVAL: u64 = 1;
+
+/// How often, in seconds, digests of missed notifications are sent to offline users.
+const EMAIL_DIGEST_INTERVAL: u64 = 60;
+
+/// How often, in seconds, the pending erasures of users' personal data are worked on.
+const USER_ERASURE_INTERVAL: u64 = 5;
+
+/// How often, in seconds, idle devices are warned and expired.
+const DEVICE_EXPIRY_INTERVAL: u64 = 60 * 60;
+
+/// How often, in seconds, expired rows are deleted from the database.
+const CLEANUP_INTERVAL: u64 = 10 * 60;
 
 /// Ruma's web server.
 pub struct Server<'a> {
     config: &'a Config,
+    database: Option<Arc<Database>>,
     mount: Mount,
+    spam_checkers: Vec<Box<SpamChecker>>,
 }
 
 impl<'a> Server<'a> {
@@ -68,10 +168,22 @@ impl<'a> Server<'a> {
     pub fn new(config: &'a Config) -> Self {
         Server {
             config,
+            database: None,
             mount: Mount::new(),
+            spam_checkers: Vec::new(),
         }
     }
 
+    /// Register a spam checker consulted by the client APIs.
+    ///
+    /// Spam checkers must be registered before the client APIs are mounted. They are consulted
+    /// after the checker for the configured `spam_blocklist`, in the order they were registered.
+    pub fn spam_checker(mut self, checker: Box<SpamChecker>) -> Self {
+        self.spam_checkers.push(checker);
+
+        self
+    }
+
     /// Mount all APIs.
     pub fn mount_all(self) -> Result<Self, CliError> {
         self.mount_extra().mount_client()
@@ -87,8 +199,12 @@ impl<'a> Server<'a> {
     }
 
     /// Mount the client APIs.
+    ///
+    /// The database connection pool is configured with the `db_*` options of the `Config`.
     pub fn mount_client(self) -> Result<Self, CliError> {
-        self.mount_client_with_options(R2D2Config::default(), true)
+        let r2d2_config = DB::pool_config(self.config);
+
+        self.mount_client_with_options(r2d2_config, true)
     }
 
     /// Mount the client APIs with some extra options.
@@ -100,7 +216,70 @@ impl<'a> Server<'a> {
         let mut r0_router = Router::new();
 
         r0_router.post("/account/password", AccountPassword::chain(), "account_password");
+        r0_router.post(
+            "/account/password/email/requestToken",
+            RequestPasswordResetEmailToken::chain(),
+            "request_password_reset_email_token",
+        );
+        r0_router.post(
+            "/account/password/email/submitToken",
+            SubmitThreepidToken::chain(),
+            "submit_password_reset_email_token",
+        );
         r0_router.post("/account/deactivate", DeactivateAccount::chain(), "deactivate_account");
+        r0_router.get("/account/3pid", GetThreepids::chain(), "get_threepids");
+        r0_router.post("/account/3pid", AddThreepid::chain(), "add_threepid");
+        r0_router.post(
+            "/account/3pid/email/requestToken",
+            RequestEmailAccountToken::chain(),
+            "request_email_account_token",
+        );
+        r0_router.post(
+            "/account/3pid/email/submitToken",
+            SubmitThreepidToken::chain(),
+            "submit_email_account_token",
+        );
+        r0_router.post(
+            "/account/3pid/msisdn/requestToken",
+            RequestMsisdnAccountToken::chain(),
+            "request_msisdn_account_token",
+        );
+        r0_router.post(
+            "/account/3pid/msisdn/submitToken",
+            SubmitThreepidToken::chain(),
+            "submit_msisdn_account_token",
+        );
+        r0_router.post(
+            "/admin/directory/bulk_resolve",
+            BulkResolveRoomAliases::chain(),
+            "bulk_resolve_room_aliases",
+        );
+        r0_router.get(
+            "/admin/monthly_active_users",
+            GetMonthlyActiveUsers::chain(),
+            "get_monthly_active_users",
+        );
+        r0_router.get("/admin/pushers/:user_id", GetUserPushers::chain(), "get_user_pushers");
+        r0_router.get("/admin/register", GetRegistrationNonce::chain(), "get_registration_nonce");
+        r0_router.post("/admin/register", SharedSecretRegister::chain(), "shared_secret_register");
+        r0_router.post(
+            "/admin/rooms/:room_id/batch_send",
+            BatchSendEvents::chain(),
+            "batch_send_events",
+        );
+        r0_router.get("/admin/rooms/:room_id/export", GetRoomExport::chain(), "get_room_export");
+        r0_router.get(
+            "/admin/rooms/:room_id/memberships/:user_id",
+            GetMembershipTransitions::chain(),
+            "get_membership_transitions",
+        );
+        r0_router.get("/admin/server_version", GetServerVersion::chain(), "get_server_version");
+        r0_router.get(
+            "/admin/users/:user_id/data_export",
+            GetUserDataExport::chain(),
+            "get_user_data_export",
+        );
+        r0_router.get("/admin/users/:user_id/erasure", GetUserErasure::chain(), "get_user_erasure");
         r0_router.post("/createRoom", CreateRoom::chain(), "create_room");
         r0_router.get("/directory/room/:room_alias", GetRoomAlias::chain(), "get_room_alias");
         r0_router.delete(
@@ -109,9 +288,40 @@ impl<'a> Server<'a> {
             "delete_room_alias",
         );
         r0_router.put("/directory/room/:room_alias", PutRoomAlias::chain(), "put_room_alias");
+        r0_router.get(
+            "/directory/list/room/:room_id",
+            GetRoomVisibility::chain(),
+            "get_room_visibility",
+        );
+        r0_router.put(
+            "/directory/list/room/:room_id",
+            PutRoomVisibility::chain(),
+            "put_room_visibility",
+        );
         r0_router.post("/login", Login::chain(), "login");
         r0_router.post("/logout", Logout::chain(), "logout");
         r0_router.post("/register", Register::chain(), "register");
+        r0_router.get("/register/available", RegisterAvailable::chain(), "register_available");
+        r0_router.post(
+            "/register/email/requestToken",
+            RequestEmailRegistrationToken::chain(),
+            "request_email_registration_token",
+        );
+        r0_router.post(
+            "/register/email/submitToken",
+            SubmitThreepidToken::chain(),
+            "submit_email_registration_token",
+        );
+        r0_router.post(
+            "/register/msisdn/requestToken",
+            RequestMsisdnRegistrationToken::chain(),
+            "request_msisdn_registration_token",
+        );
+        r0_router.post(
+            "/register/msisdn/submitToken",
+            SubmitThreepidToken::chain(),
+            "submit_msisdn_registration_token",
+        );
         r0_router.post("/tokenrefresh", deprecated, "token_refresh");
         r0_router.put(
             "/user/:user_id/account_data/:type",
@@ -141,9 +351,22 @@ impl<'a> Server<'a> {
         r0_router.post("/rooms/:room_id/join", JoinRoom::chain(), "join_room");
         r0_router.post("/rooms/:room_id/invite", InviteToRoom::chain(), "invite_to_room");
         r0_router.post("/join/:room_id_or_alias", JoinRoomWithIdOrAlias::chain(), "join_room_with_alias");
+        r0_router.post("/knock/:room_id_or_alias", KnockOnRoom::chain(), "knock_on_room");
         r0_router.post("rooms/:room_id/kick", KickFromRoom::chain(), "kick_from_room");
         r0_router.post("rooms/:room_id/leave", LeaveRoom::chain(), "leave_room");
         r0_router.get("/rooms/:room_id/members", Members::chain(), "members");
+        r0_router.get("/rooms/:room_id/aliases", GetRoomAliases::chain(), "get_room_aliases");
+        r0_router.get(
+            "/rooms/:room_id/initialSync",
+            RoomInitialSync::chain(),
+            "room_initial_sync",
+        );
+        r0_router.get("/rooms/:room_id/event/:event_id", GetRoomEvent::chain(), "get_room_event");
+        r0_router.get(
+            "/rooms/:room_id/context/:event_id",
+            GetEventContext::chain(),
+            "get_event_context",
+        );
         r0_router.get("/rooms/:room_id/state", RoomState::chain(), "get_room_state");
         r0_router.get("/rooms/:room_id/state/:event_type", GetStateEvent::chain(), "get_state_event");
         r0_router.get(
@@ -166,36 +389,141 @@ impl<'a> Server<'a> {
         r0_router.put("/presence/:user_id/status", PutPresenceStatus::chain(), "put_presence_status");
         r0_router.get("/presence/list/:user_id", GetPresenceList::chain(), "get_presence_list");
         r0_router.post("/presence/list/:user_id", PostPresenceList::chain(), "post_presence_list");
+        r0_router.get("/room_keys/keys", GetRoomKeys::chain(), "get_room_keys");
+        r0_router.put("/room_keys/keys", PutRoomKeys::chain(), "put_room_keys");
+        r0_router.delete("/room_keys/keys", DeleteRoomKeys::chain(), "delete_room_keys");
+        r0_router.get(
+            "/room_keys/version",
+            GetKeyBackupVersion::chain(),
+            "get_current_key_backup_version",
+        );
+        r0_router.post(
+            "/room_keys/version",
+            CreateKeyBackupVersion::chain(),
+            "create_key_backup_version",
+        );
+        r0_router.get(
+            "/room_keys/version/:version",
+            GetKeyBackupVersion::chain(),
+            "get_key_backup_version",
+        );
+        r0_router.put(
+            "/room_keys/version/:version",
+            UpdateKeyBackupVersion::chain(),
+            "update_key_backup_version",
+        );
         r0_router.get("/pushers", GetPushers::chain(), "pushers");
         r0_router.post("/pushers/set", SetPushers::chain(), "set_pushers");
 
         let mut r0 = Chain::new(r0_router);
 
         debug!("Connecting to PostgreSQL.");
+        let pool_size = r2d2_config.pool_size();
         let connection_pool = DB::create_connection_pool(r2d2_config, &self.config.postgres_url)?;
         let connection = connection_pool.get()?;
 
         if set_up_db {
-            debug!("Setting up database.");
-            setup_database(&*connection).map_err(CliError::from)?;
+            debug!("Checking the database schema.");
+            migrations::prepare(&*connection, self.config.auto_migrate)?;
+        }
+
+        let database = Arc::new(Database::new(connection_pool.clone(), pool_size));
 
-            debug!("Running pending database migrations.");
-            run_pending_migrations(&*connection).map_err(CliError::from)?;
+        for user_id in User::find_non_normalized_ids(&*connection).map_err(CliError::from)? {
+            warn!(
+                "The user {} has an upper-case localpart and cannot log in until it is renamed to \
+                lower-case.",
+                user_id
+            );
         }
 
+        let mut spam_checker = CompositeSpamChecker::new();
+
+        if !self.config.spam_blocklist.is_empty() {
+            spam_checker.push(Box::new(BlocklistSpamChecker::new(&self.config.spam_blocklist)?));
+        }
+
+        for checker in self.spam_checkers.drain(..) {
+            spam_checker.push(checker);
+        }
+
+        link_request_handling(&mut r0, &self.config);
         r0.link_before(Read::<Config>::one(self.config.clone()));
-        r0.link_before(Write::<DB>::one(connection_pool));
-        r0.link_after(ResponseHeaders);
+        r0.link_before(Read::<CompositeSpamChecker>::one(spam_checker));
+        r0.link_before(Read::<EventPersister>::one(
+            EventPersister::new(database.clone(), self.config.event_writers)
+        ));
+        r0.link_before(Read::<DB>::one(database.clone()));
+        r0.link_before(Read::<WorkerLimit>::one(
+            WorkerLimit::new(self.config.initial_sync_worker_count())
+        ));
+        r0.link_before(Localization::new(self.config.default_locale));
+        r0.link_after(Localization::new(self.config.default_locale));
+        r0.link_after(ResponseHeaders::new());
+        link_compression(&mut r0, &self.config);
+
+        let mut v1_router = Router::new();
+
+        v1_router.get("/rooms/:room_id/hierarchy", GetHierarchy::chain(), "get_hierarchy");
+        v1_router.get("/rooms/:room_id/relations/:event_id", GetRelations::chain(), "get_relations");
+        v1_router.get(
+            "/rooms/:room_id/relations/:event_id/:rel_type",
+            GetRelations::chain(),
+            "get_relations_by_type",
+        );
+
+        let mut v1 = Chain::new(v1_router);
+
+        link_request_handling(&mut v1, &self.config);
+        v1.link_before(Read::<Config>::one(self.config.clone()));
+        v1.link_before(Read::<DB>::one(database.clone()));
+        v1.link_before(Localization::new(self.config.default_locale));
+        v1.link_after(Localization::new(self.config.default_locale));
+        v1.link_after(ResponseHeaders::new());
+        link_compression(&mut v1, &self.config);
 
         let mut versions_router = Router::new();
 
         versions_router.get("/versions", Versions::chain(), "versions");
 
         let mut versions = Chain::new(versions_router);
-        versions.link_after(ResponseHeaders);
+        link_request_handling(&mut versions, &self.config);
+        versions.link_after(ResponseHeaders::new());
+
+        // Responses of the media API carry the security headers for attacker-controlled content.
+        let mut media_router = Router::new();
+
+        media_router.get("/preview_url", PreviewUrl::chain(), "preview_url");
+
+        let mut media = Chain::new(media_router);
+
+        link_request_handling(&mut media, &self.config);
+        media.link_before(Read::<Config>::one(self.config.clone()));
+        media.link_before(Read::<DB>::one(database.clone()));
+        media.link_before(Localization::new(self.config.default_locale));
+        media.link_after(Localization::new(self.config.default_locale));
+        media.link_after(ResponseHeaders::for_media(&self.config));
+
+        // Requests to the rest of /_matrix fail like requests to unknown endpoints of the APIs.
+        let mut unrecognized = Chain::new(Router::new());
+
+        link_request_handling(&mut unrecognized, &self.config);
+        unrecognized.link_before(Localization::new(self.config.default_locale));
+        unrecognized.link_after(Localization::new(self.config.default_locale));
+        unrecognized.link_after(ResponseHeaders::new());
+
+        let mut health = GetHealth::chain();
 
+        health.link_before(Read::<DB>::one(database.clone()));
+
+        self.mount.mount("/_matrix/", unrecognized);
         self.mount.mount("/_matrix/client/", versions);
         self.mount.mount("/_matrix/client/r0/", r0);
+        self.mount.mount("/_matrix/client/v1/", v1);
+        self.mount.mount("/_matrix/media/r0/", media);
+        self.mount.mount("/ruma/health", health);
+
+        self.database = Some(database);
 
         Ok(self)
     }
@@ -213,9 +541,41 @@ impl<'a> Server<'a> {
 
         info!("Starting Ruma server on {}.", address);
 
-        let iron = Iron::new(self.mount);
+        // The background jobs share the outage tracking of the client APIs, so that they do not
+        // wait for the connection timeout on every run while the database is unavailable.
+        if let Some(database) = self.database() {
+            spawn_presence_idle_check(
+                database.clone(),
+                self.config.domain.clone(),
+                self.config.presence_idle_timeout
+            );
+            spawn_mau_expiry(database.clone());
+            spawn_push_delivery(database.clone());
+            spawn_email_digests(database.clone(), self.config.clone());
+            spawn_user_erasures(database.clone());
+            spawn_cleanup(database.clone());
+
+            if self.config.device_idle_expiry_days.is_some() {
+                spawn_device_expiry(database.clone(), self.config.clone());
+            }
+
+            spawn_mail_delivery(database, mailer(&self.config));
+        }
 
-        iron.http(&address[..])
+        let connection_limit = self.config.max_connections_per_ip.map(|limit| {
+            ConnectionLimit::new(limit, self.config.trusted_proxies.clone())
+        });
+        let listener =
+            DeadlineListener::new(&address, request_deadlines(&self.config), connection_limit)?;
+        let mut iron = Iron::new(self.mount);
+        iron.timeouts = http_timeouts(&self.config);
+
+        iron.listen(listener, Protocol::http())
+    }
+
+    /// The database used by the client APIs, once they are mounted. Useful for testing.
+    pub fn database(&self) -> Option<Arc<Database>> {
+        self.database.clone()
     }
 
     /// Moves out the server's `Mount`. Useful for testing.
@@ -224,6 +584,270 @@ impl<'a> Server<'a> {
     }
 }
 
+/// Link the middleware that wraps the handling of every request to `chain`.
+///
+/// Routing directly wraps the router, and panics are recovered from inside the request logger, so
+/// failed requests show up in the access log. The client's address is determined before any of
+/// them, so that the handlers can record it.
+fn link_request_handling(chain: &mut Chain, config: &Config) {
+    chain.link_before(ClientIp::new(config.trusted_proxies.clone()));
+    chain.link_around(Routing);
+    chain.link_around(PanicRecovery);
+    chain.link_around(RequestLogger::new(config.log_format));
+}
+
+/// Link the compression of responses to `chain` if it is enabled.
+///
+/// It should be linked after all other after middleware, which may still change the body.
+fn link_compression(chain: &mut Chain, config: &Config) {
+    if config.compress_responses {
+        chain.link_after(ResponseCompression::new(config.compression_min_size));
+    }
+}
+
+/// The timeouts of connections to the HTTP server.
+///
+/// Both read and write timeouts apply to single reads and writes on the socket, so the time a
+/// handler takes to build its response does not count against them.
+fn http_timeouts(config: &Config) -> Timeouts {
+    Timeouts {
+        keep_alive: match config.http_keep_alive_timeout {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        read: Some(Duration::from_secs(config.http_read_timeout)),
+        write: Some(Duration::from_secs(config.http_write_timeout)),
+    }
+}
+
+/// The deadlines of the requests to the HTTP server, which limit how long a client may take to
+/// send a whole request, unlike the read timeout of `http_timeouts`.
+fn request_deadlines(config: &Config) -> RequestDeadlines {
+    RequestDeadlines {
+        headers: Duration::from_secs(config.http_header_timeout),
+        body: Duration::from_secs(config.http_body_timeout),
+    }
+}
+
+/// Periodically mark users as offline whose presence has been idle for `idle_timeout` seconds.
+fn spawn_presence_idle_check(
+    database: Arc<Database>,
+    homeserver_domain: String,
+    idle_timeout: u64,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(PRESENCE_IDLE_CHECK_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the presence idle check: {}", error);
+                continue;
+            }
+        };
+
+        match PresenceStatus::set_idle_offline(&*connection, &homeserver_domain, idle_timeout as i64 * 1000) {
+            Ok(0) => (),
+            Ok(count) => debug!("Marked {} idle users as offline.", count),
+            Err(error) => warn!("Failed to mark idle users as offline: {}", error),
+        }
+    });
+}
+
+/// Periodically remove users who have not made a request within the last 30 days from the
+/// monthly active users.
+fn spawn_mau_expiry(database: Arc<Database>) {
+    thread::spawn(move || loop {
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the MAU expiry: {}", error);
+                thread::sleep(Duration::from_secs(MAU_EXPIRY_INTERVAL));
+                continue;
+            }
+        };
+
+        match MonthlyActiveUser::expire(&*connection) {
+            Ok(0) => (),
+            Ok(count) => debug!("Expired {} monthly active users.", count),
+            Err(error) => warn!("Failed to expire monthly active users: {}", error),
+        }
+
+        drop(connection);
+        thread::sleep(Duration::from_secs(MAU_EXPIRY_INTERVAL));
+    });
+}
+
+/// Periodically deliver the emails in the mail queue that are due.
+fn spawn_mail_delivery(
+    database: Arc<Database>,
+    mailer: Box<Mailer + Send + Sync>,
+) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(MAIL_DELIVERY_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the mail delivery: {}", error);
+                continue;
+            }
+        };
+
+        match deliver_queued(&*connection, &*mailer) {
+            Ok(0) => (),
+            Ok(count) => debug!("Delivered {} queued emails.", count),
+            Err(error) => warn!("Failed to deliver queued emails: {}", error),
+        }
+    });
+}
+
+/// Deliver the queued push notifications, as long as there are any, and then check the queue
+/// again periodically.
+fn spawn_push_delivery(database: Arc<Database>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(PUSH_DELIVERY_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the push delivery: {}", error);
+                continue;
+            }
+        };
+
+        // A full batch means that more notifications may be waiting.
+        loop {
+            match deliver_queued_pushes(&*connection) {
+                Ok(0) => break,
+                Ok(count) => {
+                    debug!("Delivered {} queued push notifications.", count);
+
+                    if count < PUSH_DELIVERY_BATCH_SIZE as usize {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    warn!("Failed to deliver queued push notifications: {}", error);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn spawn_email_digests(database: Arc<Database>, config: Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(EMAIL_DIGEST_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the email digests: {}", error);
+                continue;
+            }
+        };
+
+        match send_digests(&*connection, &config) {
+            Ok(0) => (),
+            Ok(count) => debug!("Queued {} email digests.", count),
+            Err(error) => warn!("Failed to send email digests: {}", error),
+        }
+    });
+}
+
+/// Periodically redact the next batch of events of each user whose personal data is erased.
+fn spawn_user_erasures(database: Arc<Database>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(USER_ERASURE_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the user erasures: {}", error);
+                continue;
+            }
+        };
+
+        match erase_pending(&*connection, ERASURE_BATCH_SIZE) {
+            Ok(0) => (),
+            Ok(count) => debug!("Redacted {} events of erased users.", count),
+            Err(error) => warn!("Failed to erase users: {}", error),
+        }
+    });
+}
+
+/// Periodically warn the devices that will expire soon and revoke the access tokens of the ones
+/// that have been idle for too long.
+fn spawn_device_expiry(database: Arc<Database>, config: Config) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(DEVICE_EXPIRY_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the device expiry: {}", error);
+                continue;
+            }
+        };
+
+        match expire_idle_devices(&*connection, &config, DEVICE_EXPIRY_BATCH_SIZE) {
+            Ok(DeviceExpiry { warned: 0, expired: 0 }) => (),
+            Ok(expiry) => debug!(
+                "Warned {} idle devices and expired {} access tokens.",
+                expiry.warned,
+                expiry.expired
+            ),
+            Err(error) => warn!("Failed to expire idle devices: {}", error),
+        }
+    });
+}
+
+/// Periodically delete rows that have expired and can no longer be used.
+fn spawn_cleanup(database: Arc<Database>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(CLEANUP_INTERVAL));
+
+        let connection = match database.get() {
+            Ok(connection) => connection,
+            Err(error) => {
+                warn!("Failed to get a database connection for the cleanup: {}", error);
+                continue;
+            }
+        };
+
+        match ThreepidSession::delete_expired(&*connection) {
+            Ok(0) => (),
+            Ok(count) => debug!("Deleted {} expired 3PID validation sessions.", count),
+            Err(error) => warn!("Failed to delete expired 3PID validation sessions: {}", error),
+        }
+
+        match RegistrationNonce::delete_expired(&*connection) {
+            Ok(0) => (),
+            Ok(count) => debug!("Deleted {} expired registration nonces.", count),
+            Err(error) => warn!("Failed to delete expired registration nonces: {}", error),
+        }
+
+        match EventTransaction::delete_expired(&*connection) {
+            Ok(0) => (),
+            Ok(count) => debug!("Deleted {} expired transaction IDs of events.", count),
+            Err(error) => warn!("Failed to delete expired transaction IDs of events: {}", error),
+        }
+
+        match Transaction::delete_expired(&*connection) {
+            Ok(0) => (),
+            Ok(count) => debug!("Deleted {} expired transactions.", count),
+            Err(error) => warn!("Failed to delete expired transactions: {}", error),
+        }
+
+        match QueuedPush::delete_expired(&*connection) {
+            Ok(0) => (),
+            Ok(count) => debug!("Gave up on {} queued push notifications.", count),
+            Err(error) => warn!("Failed to delete expired push notifications: {}", error),
+        }
+    });
+}
+
 fn deprecated(_: &mut Request) -> IronResult<Response> {
     Err(IronError::from(ApiError::unauthorized("tokenrefresh is no longer supported".to_string())))
 }
@@ -0,0 +1,210 @@
+//! Server access control lists, set per room with *m.room.server_acl* state events.
+//!
+//! An ACL decides which homeservers may participate in a room. Servers are matched by their
+//! server name without the port, against globs in which `*` matches any sequence of characters
+//! and `?` matches exactly one character.
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_identifiers::RoomId;
+use serde_json::{Value, from_value};
+
+use error::{ApiError, MapApiError};
+use models::room::Room;
+use util::glob::glob_with_single_wildcard;
+use util::room_state::state_key;
+
+/// The type of the state event holding a room's server ACL.
+pub const SERVER_ACL_EVENT_TYPE: &'static str = "m.room.server_acl";
+
+/// The content of an *m.room.server_acl* event.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ServerAcl {
+    /// Globs of the servers that are allowed. Servers that do not match any are denied.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Whether servers whose name is an IP address literal are allowed. Defaults to true.
+    #[serde(default = "default_allow_ip_literals")]
+    pub allow_ip_literals: bool,
+    /// Globs of the servers that are denied, even if they are allowed by `allow`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+fn default_allow_ip_literals() -> bool {
+    true
+}
+
+impl ServerAcl {
+    /// Parse the content of an *m.room.server_acl* event.
+    pub fn from_content(content: Value) -> Result<ServerAcl, ApiError> {
+        from_value(content).map_api_err(|_| {
+            ApiError::bad_event(format!(
+                "Event content did not match expected structure for event of type {}.",
+                SERVER_ACL_EVENT_TYPE
+            ))
+        })
+    }
+
+    /// Look up the current server ACL of a room, if it has one.
+    pub fn find_by_room(connection: &PgConnection, room_id: &RoomId)
+    -> Result<Option<ServerAcl>, ApiError> {
+        let event_type = EventType::Custom(SERVER_ACL_EVENT_TYPE.to_string());
+        let keys = [(event_type.clone(), "")];
+        let mut contents = Room::get_state_contents(connection, room_id, &keys)?;
+
+        match contents.remove(&state_key(&event_type, "")) {
+            Some(content) => ServerAcl::from_content(content).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Check whether the ACL allows the server with the given server name, which may include a
+    /// port, e.g. *example.com:8448* or *[::1]:8448*.
+    pub fn is_allowed(&self, server_name: &str) -> bool {
+        let host = strip_port(server_name).to_lowercase();
+
+        if !self.allow_ip_literals && is_ip_literal(&host) {
+            return false;
+        }
+
+        let matches = |pattern: &String| glob_with_single_wildcard(&pattern.to_lowercase(), &host);
+
+        !self.deny.iter().any(&matches) && self.allow.iter().any(&matches)
+    }
+
+    /// Check that the ACL does not deny our own server, so that it cannot lock the server out of
+    /// the room.
+    pub fn verify_allows_own_server(&self, homeserver_domain: &str) -> Result<(), ApiError> {
+        if self.is_allowed(homeserver_domain) {
+            Ok(())
+        } else {
+            Err(ApiError::invalid_param(
+                "content",
+                &format!("The server ACL would deny this server, {}.", homeserver_domain),
+            ))
+        }
+    }
+}
+
+/// Remove the port from a server name, keeping the brackets around IPv6 literals.
+fn strip_port(server_name: &str) -> &str {
+    if server_name.starts_with('[') {
+        match server_name.find(']') {
+            Some(end) => &server_name[..end + 1],
+            None => server_name,
+        }
+    } else {
+        match server_name.rfind(':') {
+            Some(start) => &server_name[..start],
+            None => server_name,
+        }
+    }
+}
+
+/// Check whether a server name without port is an IPv4 address or a bracketed IPv6 address.
+fn is_ip_literal(host: &str) -> bool {
+    host.starts_with('[') || Ipv4Addr::from_str(host).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_str;
+
+    use super::ServerAcl;
+
+    /// Parse an ACL from its JSON content.
+    fn parse_acl(content: &str) -> ServerAcl {
+        ServerAcl::from_content(from_str(content).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn allow_and_deny_globs() {
+        let acl = parse_acl(r#"{"allow": ["*"], "deny": ["evil.test", "*.evil.test"]}"#);
+
+        assert!(acl.is_allowed("ruma.test"));
+        assert!(!acl.is_allowed("evil.test"));
+        assert!(!acl.is_allowed("chat.evil.test"));
+        assert!(acl.is_allowed("notevil.test"));
+    }
+
+    #[test]
+    fn missing_allow_denies_everything() {
+        let acl = parse_acl(r#"{"deny": []}"#);
+
+        assert!(!acl.is_allowed("ruma.test"));
+    }
+
+    #[test]
+    fn single_character_wildcard() {
+        let acl = parse_acl(r#"{"allow": ["server?.test"]}"#);
+
+        assert!(acl.is_allowed("server1.test"));
+        assert!(!acl.is_allowed("server.test"));
+        assert!(!acl.is_allowed("server12.test"));
+    }
+
+    #[test]
+    fn ports_are_ignored() {
+        let acl = parse_acl(r#"{"allow": ["*"], "deny": ["evil.test"]}"#);
+
+        assert!(!acl.is_allowed("evil.test:8448"));
+        assert!(acl.is_allowed("ruma.test:8448"));
+
+        let acl = ServerAcl {
+            allow: vec!["ruma.test".to_string()],
+            allow_ip_literals: true,
+            deny: Vec::new(),
+        };
+
+        assert!(acl.is_allowed("ruma.test:443"));
+        assert!(!acl.is_allowed("ruma.test.evil:443"));
+    }
+
+    #[test]
+    fn ip_literals() {
+        let acl = parse_acl(r#"{"allow": ["*"], "allow_ip_literals": false}"#);
+
+        assert!(!acl.is_allowed("1.2.3.4"));
+        assert!(!acl.is_allowed("1.2.3.4:8448"));
+        assert!(!acl.is_allowed("[::1]"));
+        assert!(!acl.is_allowed("[2001:db8::1]:8448"));
+        assert!(acl.is_allowed("1.2.3.4.example.test"));
+
+        let acl = parse_acl(r#"{"allow": ["*"]}"#);
+
+        assert!(acl.is_allowed("[2001:db8::1]:8448"));
+    }
+
+    #[test]
+    fn ipv6_literals_can_be_matched_by_globs() {
+        let acl = parse_acl(r#"{"allow": ["*"], "deny": ["[2001:db8::*]"]}"#);
+
+        assert!(!acl.is_allowed("[2001:db8::1]"));
+        assert!(!acl.is_allowed("[2001:db8::1]:8448"));
+        assert!(acl.is_allowed("[2001:db9::1]:8448"));
+    }
+
+    #[test]
+    fn matching_is_case_insensitive() {
+        let acl = parse_acl(r#"{"allow": ["*"], "deny": ["Evil.Test"]}"#);
+
+        assert!(!acl.is_allowed("EVIL.test"));
+    }
+
+    #[test]
+    fn own_server_must_be_allowed() {
+        assert!(parse_acl(r#"{"allow": ["*"], "deny": ["ruma.test"]}"#)
+            .verify_allows_own_server("ruma.test")
+            .is_err());
+        assert!(parse_acl(r#"{"allow": ["other.test"]}"#)
+            .verify_allows_own_server("ruma.test")
+            .is_err());
+        assert!(parse_acl(r#"{"allow": ["*.test"]}"#)
+            .verify_allows_own_server("ruma.test")
+            .is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+//! Delivery of text messages, e.g. for validating phone numbers.
+
+#[cfg(test)]
+use std::cell::RefCell;
+
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper::status::StatusClass;
+use serde_json::to_string;
+
+use config::Config;
+use error::{ApiError, MapApiError};
+
+#[cfg(test)]
+thread_local! {
+    /// The messages "sent" by `LoggingSmsSender` on the current thread.
+    static SENT_MESSAGES: RefCell<Vec<(String, String)>> = RefCell::new(Vec::new());
+}
+
+/// Something that can deliver a text message to a phone number.
+pub trait SmsSender {
+    /// Send `body` to the E.164 phone number `msisdn`.
+    fn send(&self, msisdn: &str, body: &str) -> Result<(), ApiError>;
+}
+
+/// An `SmsSender` that only writes messages to the log.
+///
+/// Used when no SMS gateway is configured.
+pub struct LoggingSmsSender;
+
+/// An `SmsSender` that posts messages to an HTTP SMS gateway.
+pub struct HttpSmsSender {
+    /// The URL messages are posted to.
+    url: String,
+}
+
+/// The JSON body posted to the SMS gateway.
+#[derive(Debug, Serialize)]
+struct SmsGatewayRequest<'a> {
+    /// The E.164 phone number of the recipient, without a leading "+".
+    to: &'a str,
+    /// The text of the message.
+    body: &'a str,
+}
+
+/// Create the `SmsSender` for the given configuration.
+pub fn sender(config: &Config) -> Box<SmsSender> {
+    match config.sms_gateway_url {
+        Some(ref url) => Box::new(HttpSmsSender::new(url.clone())),
+        None => Box::new(LoggingSmsSender),
+    }
+}
+
+impl SmsSender for LoggingSmsSender {
+    fn send(&self, msisdn: &str, body: &str) -> Result<(), ApiError> {
+        info!("SMS to {}: {}", msisdn, body);
+
+        record_sent_message(msisdn, body);
+
+        Ok(())
+    }
+}
+
+impl HttpSmsSender {
+    /// Create an `HttpSmsSender` posting to `url`.
+    pub fn new(url: String) -> Self {
+        HttpSmsSender {
+            url: url,
+        }
+    }
+}
+
+impl SmsSender for HttpSmsSender {
+    fn send(&self, msisdn: &str, body: &str) -> Result<(), ApiError> {
+        let request_body = to_string(&SmsGatewayRequest {
+            to: msisdn,
+            body: body,
+        }).map_err(ApiError::from)?;
+
+        let response = Client::new()
+            .post(self.url.as_str())
+            .header(ContentType::json())
+            .body(request_body.as_str())
+            .send()
+            .map_api_err(|_| ApiError::unknown("Failed to reach the SMS gateway.".to_string()))?;
+
+        match response.status.class() {
+            StatusClass::Success => Ok(()),
+            _ => Err(ApiError::unknown("The SMS gateway rejected the message.".to_string())),
+        }
+    }
+}
+
+/// Remember a message sent by `LoggingSmsSender` so tests can inspect it.
+#[cfg(test)]
+fn record_sent_message(msisdn: &str, body: &str) {
+    SENT_MESSAGES.with(|messages| {
+        messages.borrow_mut().push((msisdn.to_string(), body.to_string()));
+    });
+}
+
+/// Remember a message sent by `LoggingSmsSender` so tests can inspect it.
+#[cfg(not(test))]
+fn record_sent_message(_msisdn: &str, _body: &str) {}
+
+/// The messages sent by `LoggingSmsSender` on the current thread, oldest first.
+#[cfg(test)]
+pub fn sent_messages() -> Vec<(String, String)> {
+    SENT_MESSAGES.with(|messages| messages.borrow().clone())
+}
@@ -0,0 +1,183 @@
+//! Rejection of spam, e.g. unsolicited messages and invites.
+//!
+//! Spam checkers are registered with `Server::spam_checker` before the client APIs are mounted.
+//! The checker built from the `spam_blocklist` configuration is always registered first.
+
+use std::sync::Arc;
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+use regex::{RegexSet, RegexSetBuilder};
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{Value, from_str};
+
+use config::Config;
+use error::{ApiError, CliError};
+use models::event::Event;
+
+/// Whether a spam checker allows a request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// The request may proceed.
+    Allow,
+    /// The request is rejected as spam.
+    Deny,
+}
+
+/// Something that can decide whether events and actions of users are spam.
+///
+/// All hooks allow everything by default, so implementations only need to override the hooks
+/// they care about.
+pub trait SpamChecker: Send + Sync {
+    /// Decide whether a new event sent by a local user is spam.
+    fn check_event_for_spam(&self, _event: &Event) -> Decision {
+        Decision::Allow
+    }
+
+    /// Decide whether `inviter` may invite `invitee` to a room.
+    fn user_may_invite(&self, _inviter: &UserId, _invitee: &UserId, _room_id: &RoomId)
+    -> Decision {
+        Decision::Allow
+    }
+
+    /// Decide whether a user may create a room.
+    fn user_may_create_room(&self, _user_id: &UserId) -> Decision {
+        Decision::Allow
+    }
+
+    /// Decide whether a user may publish a room in the room directory.
+    fn user_may_publish_room(&self, _user_id: &UserId, _room_id: &RoomId) -> Decision {
+        Decision::Allow
+    }
+}
+
+/// A `SpamChecker` that allows everything.
+pub struct AllowAllSpamChecker;
+
+/// A `SpamChecker` that asks several spam checkers in turn and denies a request if any of them
+/// does.
+#[derive(Default)]
+pub struct CompositeSpamChecker {
+    /// The spam checkers, in the order they are consulted.
+    checkers: Vec<Box<SpamChecker>>,
+}
+
+/// A `SpamChecker` that rejects messages and invites matching a list of regular expressions.
+pub struct BlocklistSpamChecker {
+    /// The blocked patterns.
+    patterns: RegexSet,
+}
+
+impl Decision {
+    /// Turn a `Deny` into a 403 error with the configured rejection message.
+    pub fn ensure_allowed(self, config: &Config) -> Result<(), ApiError> {
+        match self {
+            Decision::Allow => Ok(()),
+            Decision::Deny => Err(ApiError::spam(config.spam_rejection_message.clone())),
+        }
+    }
+}
+
+impl SpamChecker for AllowAllSpamChecker {}
+
+impl CompositeSpamChecker {
+    /// Create a `CompositeSpamChecker` without any spam checkers, which allows everything.
+    pub fn new() -> Self {
+        CompositeSpamChecker {
+            checkers: Vec::new(),
+        }
+    }
+
+    /// Add a spam checker, consulted after the ones added before.
+    pub fn push(&mut self, checker: Box<SpamChecker>) {
+        self.checkers.push(checker);
+    }
+
+    /// Extract the `CompositeSpamChecker` stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<CompositeSpamChecker>, ApiError> {
+        request.get::<PersistentRead<CompositeSpamChecker>>().map_err(ApiError::from)
+    }
+
+    /// Return `Deny` if any of the spam checkers returns it for `check`.
+    fn decide<F>(&self, check: F) -> Decision where F: Fn(&SpamChecker) -> Decision {
+        if self.checkers.iter().any(|checker| check(&**checker) == Decision::Deny) {
+            Decision::Deny
+        } else {
+            Decision::Allow
+        }
+    }
+}
+
+impl SpamChecker for CompositeSpamChecker {
+    fn check_event_for_spam(&self, event: &Event) -> Decision {
+        self.decide(|checker| checker.check_event_for_spam(event))
+    }
+
+    fn user_may_invite(&self, inviter: &UserId, invitee: &UserId, room_id: &RoomId) -> Decision {
+        self.decide(|checker| checker.user_may_invite(inviter, invitee, room_id))
+    }
+
+    fn user_may_create_room(&self, user_id: &UserId) -> Decision {
+        self.decide(|checker| checker.user_may_create_room(user_id))
+    }
+
+    fn user_may_publish_room(&self, user_id: &UserId, room_id: &RoomId) -> Decision {
+        self.decide(|checker| checker.user_may_publish_room(user_id, room_id))
+    }
+}
+
+impl Key for CompositeSpamChecker {
+    type Value = CompositeSpamChecker;
+}
+
+impl BlocklistSpamChecker {
+    /// Create a `BlocklistSpamChecker` for the given regular expressions, matched
+    /// case-insensitively.
+    pub fn new(patterns: &[String]) -> Result<Self, CliError> {
+        let patterns = RegexSetBuilder::new(patterns)
+            .case_insensitive(true)
+            .build()
+            .map_err(|error| CliError::new(format!("Invalid spam_blocklist pattern: {}", error)))?;
+
+        Ok(BlocklistSpamChecker {
+            patterns: patterns,
+        })
+    }
+
+    /// Check whether any string in a JSON value matches one of the patterns.
+    fn matches_value(&self, value: &Value) -> bool {
+        match *value {
+            Value::String(ref string) => self.patterns.is_match(string),
+            Value::Array(ref values) => values.iter().any(|value| self.matches_value(value)),
+            Value::Object(ref map) => map.values().any(|value| self.matches_value(value)),
+            _ => false,
+        }
+    }
+}
+
+impl SpamChecker for BlocklistSpamChecker {
+    fn check_event_for_spam(&self, event: &Event) -> Decision {
+        let matches = match from_str::<Value>(&event.content) {
+            Ok(content) => self.matches_value(&content),
+            Err(_) => self.patterns.is_match(&event.content),
+        };
+
+        if matches {
+            Decision::Deny
+        } else {
+            Decision::Allow
+        }
+    }
+
+    fn user_may_invite(&self, inviter: &UserId, invitee: &UserId, _room_id: &RoomId) -> Decision {
+        let matches = self.patterns.is_match(&inviter.to_string())
+            || self.patterns.is_match(&invitee.to_string());
+
+        if matches {
+            Decision::Deny
+        } else {
+            Decision::Allow
+        }
+    }
+}
@@ -26,7 +26,7 @@ impl MiddlewareChain for Swagger {
     fn chain() -> Chain {
         let mut chain = Chain::new(Swagger);
 
-        chain.link_after(ResponseHeaders);
+        chain.link_after(ResponseHeaders::new());
 
         chain
     }
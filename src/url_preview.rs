@@ -0,0 +1,684 @@
+//! Previews of web pages linked in messages.
+//!
+//! The server fetches the page and extracts its OpenGraph metadata, so clients can show a title
+//! and description without revealing their users' IP addresses to the linked site. Only hosts
+//! permitted by the `url_preview` configuration are fetched. Hosts on or resolving to the
+//! loopback, private and link-local networks are never fetched: the host of the page and of every
+//! redirect is resolved first, and the server connects to the address that was checked.
+//!
+//! Preview images are not included, because there is no media repository to store them in yet.
+
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(not(test))]
+use std::io;
+#[cfg(test)]
+use std::io::Cursor;
+use std::io::Read;
+#[cfg(not(test))]
+use std::net::{TcpStream, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+#[cfg(not(test))]
+use hyper;
+#[cfg(not(test))]
+use hyper::Client;
+#[cfg(not(test))]
+use hyper::client::RedirectPolicy;
+#[cfg(not(test))]
+use hyper::header::{ContentType, Location};
+#[cfg(not(test))]
+use hyper::mime::{Mime, SubLevel, TopLevel};
+#[cfg(not(test))]
+use hyper::net::{HttpStream, HttpsStream, NetworkConnector, SslClient};
+#[cfg(not(test))]
+use hyper::status::StatusClass;
+#[cfg(not(test))]
+use hyper_openssl::OpensslClient;
+use regex::Regex;
+use serde_json::{Map, Value};
+use url::{Host, Url};
+
+use config::UrlPreviewConfig;
+use error::ApiError;
+#[cfg(not(test))]
+use error::MapApiError;
+
+/// The maximum number of redirects followed to reach the page.
+const MAX_REDIRECTS: usize = 5;
+
+/// The number of seconds to wait for a connection to the linked site.
+#[cfg(not(test))]
+const CONNECT_TIMEOUT: u64 = 5;
+
+/// The number of seconds fetching a page may take in total, including redirects.
+const FETCH_TIMEOUT: u64 = 10;
+
+/// The OpenGraph properties included in previews.
+const PREVIEW_PROPERTIES: [&'static str; 2] = ["og:title", "og:description"];
+
+/// The sites previews are fetched from.
+#[cfg(not(test))]
+const SITE: Internet = Internet;
+
+/// The sites previews are fetched from.
+#[cfg(test)]
+const SITE: TestSite = TestSite;
+
+lazy_static! {
+    /// A `<meta>` tag, with its attributes.
+    static ref META_TAG: Regex = Regex::new(r"(?is)<meta\s([^>]*)>").unwrap();
+    /// An attribute of an HTML tag with a quoted value.
+    static ref ATTRIBUTE: Regex =
+        Regex::new(r#"(?is)([a-z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+    /// The `<title>` of a page.
+    static ref TITLE: Regex = Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap();
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The pages "served" to `fetch` on the current thread, by URL.
+    static PAGES: RefCell<HashMap<String, TestPage>> = RefCell::new(HashMap::new());
+    /// The addresses host names "resolve" to on the current thread.
+    static HOSTS: RefCell<HashMap<String, Vec<IpAddr>>> = RefCell::new(HashMap::new());
+}
+
+/// Where previews are fetched from: the web, or the pages served by tests.
+trait Site {
+    /// Resolve `host` to the addresses of its servers.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, ApiError>;
+
+    /// Request `url` from the server at `address`, giving up at `deadline`.
+    fn get(&self, url: &Url, address: SocketAddr, deadline: Instant)
+    -> Result<SiteResponse, ApiError>;
+}
+
+/// The response of a site to a request for a page.
+enum SiteResponse {
+    /// A redirect to the given, possibly relative, URL.
+    Redirect(String),
+    /// An HTML page.
+    Html(Box<Read>),
+    /// A page that is not HTML.
+    NotHtml,
+    /// An error, or a redirect without a location.
+    Failure,
+}
+
+/// Fetch the page at `url` and return its preview, the OpenGraph properties by name.
+///
+/// Pages that are not HTML have an empty preview.
+pub fn preview(url: &Url, config: &UrlPreviewConfig) -> Result<Map<String, Value>, ApiError> {
+    if !is_allowed(url, config) {
+        return Err(ApiError::unauthorized("Previews of this URL are not allowed.".to_string()));
+    }
+
+    match fetch(&SITE, url, config)? {
+        Some(html) => Ok(parse_preview(&html)),
+        None => Ok(Map::new()),
+    }
+}
+
+/// Check whether the configuration allows fetching `url`.
+///
+/// Entries of `allowed_hosts` and `denied_hosts` include their subdomains. The addresses the host
+/// resolves to are checked when the page is fetched.
+pub fn is_allowed(url: &Url, config: &UrlPreviewConfig) -> bool {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return false;
+    }
+
+    let host = match url.host() {
+        Some(Host::Domain(domain)) => domain.trim_right_matches('.').to_lowercase(),
+        Some(Host::Ipv4(address)) if is_internal_ipv4(&address) => return false,
+        Some(Host::Ipv6(address)) if is_internal_ipv6(&address) => return false,
+        Some(Host::Ipv4(address)) => address.to_string(),
+        Some(Host::Ipv6(address)) => address.to_string(),
+        None => return false,
+    };
+
+    if host == "localhost" || host.ends_with(".localhost") {
+        return false;
+    }
+
+    if matches_any(&host, &config.denied_hosts) {
+        return false;
+    }
+
+    config.allowed_hosts.is_empty() || matches_any(&host, &config.allowed_hosts)
+}
+
+/// Extract the OpenGraph properties of a preview from an HTML page.
+///
+/// Pages without an *og:title* or *og:description* fall back to their `<title>` and their
+/// *description* meta tag.
+pub fn parse_preview(html: &str) -> Map<String, Value> {
+    let mut preview = Map::new();
+    let mut description = None;
+
+    for tag in META_TAG.captures_iter(html) {
+        let mut name = None;
+        let mut content = None;
+
+        for attribute in ATTRIBUTE.captures_iter(&tag[1]) {
+            let value = attribute.get(2).or_else(|| attribute.get(3)).map_or("", |value| {
+                value.as_str()
+            });
+
+            match attribute[1].to_lowercase().as_str() {
+                "property" | "name" => name = Some(value.to_lowercase()),
+                "content" => content = Some(decode_entities(value)),
+                _ => {}
+            }
+        }
+
+        if let (Some(name), Some(content)) = (name, content) {
+            if name == "description" {
+                description = description.or(Some(content));
+            } else if PREVIEW_PROPERTIES.contains(&name.as_str()) && !preview.contains_key(&name) {
+                preview.insert(name, Value::String(content));
+            }
+        }
+    }
+
+    if !preview.contains_key("og:title") {
+        if let Some(title) = TITLE.captures(html) {
+            let title = decode_entities(title[1].trim());
+
+            if !title.is_empty() {
+                preview.insert("og:title".to_string(), Value::String(title));
+            }
+        }
+    }
+
+    if !preview.contains_key("og:description") {
+        if let Some(description) = description {
+            preview.insert("og:description".to_string(), Value::String(description));
+        }
+    }
+
+    preview
+}
+
+/// Fetch up to `max_size` bytes of the page at `url` from `site`, following redirects to allowed
+/// hosts.
+///
+/// Returns `None` if the page is not HTML.
+fn fetch<S: Site>(site: &S, url: &Url, config: &UrlPreviewConfig)
+-> Result<Option<String>, ApiError> {
+    let deadline = Instant::now() + Duration::from_secs(FETCH_TIMEOUT);
+    let mut url = url.clone();
+
+    for _ in 0..MAX_REDIRECTS + 1 {
+        let address = resolve_external(site, &url)?;
+
+        if Instant::now() >= deadline {
+            return Err(timed_out());
+        }
+
+        match site.get(&url, address, deadline)? {
+            SiteResponse::Redirect(location) => {
+                url = url.join(&location).map_err(|_| {
+                    ApiError::unknown("The URL redirects to an invalid URL.".to_string())
+                })?;
+
+                if !is_allowed(&url, config) {
+                    return Err(ApiError::unauthorized(
+                        "The URL redirects to a URL whose preview is not allowed.".to_string()
+                    ));
+                }
+            }
+            SiteResponse::Html(body) => {
+                return read_page(body, config.max_size, deadline).map(Some);
+            }
+            SiteResponse::NotHtml => return Ok(None),
+            SiteResponse::Failure => break,
+        }
+    }
+
+    Err(ApiError::unknown("The URL could not be fetched.".to_string()))
+}
+
+/// Resolve the host of `url` with `site` and return the address to connect to.
+///
+/// Fails if the host resolves to any internal address, so a host cannot be made to reach
+/// internal services by resolving to both an external and an internal address.
+fn resolve_external<S: Site>(site: &S, url: &Url) -> Result<SocketAddr, ApiError> {
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let addresses = match url.host() {
+        Some(Host::Domain(domain)) => site.resolve(domain.trim_right_matches('.'), port)?,
+        Some(Host::Ipv4(address)) => vec![SocketAddr::new(IpAddr::V4(address), port)],
+        Some(Host::Ipv6(address)) => vec![SocketAddr::new(IpAddr::V6(address), port)],
+        None => Vec::new(),
+    };
+
+    let is_internal = |address: &SocketAddr| match address.ip() {
+        IpAddr::V4(address) => is_internal_ipv4(&address),
+        IpAddr::V6(address) => is_internal_ipv6(&address),
+    };
+
+    if addresses.iter().any(is_internal) {
+        return Err(ApiError::unauthorized(
+            "The host of the URL resolves to an internal address.".to_string()
+        ));
+    }
+
+    match addresses.first() {
+        Some(address) => Ok(*address),
+        None => Err(ApiError::unknown("The host of the URL could not be resolved.".to_string())),
+    }
+}
+
+/// Read up to `max_size` bytes of `body`, failing if that is not done by `deadline`.
+fn read_page(mut body: Box<Read>, max_size: u64, deadline: Instant)
+-> Result<String, ApiError> {
+    let mut page = Vec::new();
+    let mut buffer = [0; 8192];
+
+    while (page.len() as u64) < max_size {
+        if Instant::now() >= deadline {
+            return Err(timed_out());
+        }
+
+        let length = body.read(&mut buffer)
+            .map_err(|_| ApiError::unknown("Failed to fetch the URL.".to_string()))?;
+
+        if length == 0 {
+            break;
+        }
+
+        let remaining = (max_size - page.len() as u64) as usize;
+
+        page.extend_from_slice(&buffer[..length.min(remaining)]);
+    }
+
+    Ok(String::from_utf8_lossy(&page).into_owned())
+}
+
+/// The error for pages that took longer than `FETCH_TIMEOUT` to fetch.
+fn timed_out() -> ApiError {
+    ApiError::unknown("Fetching the URL timed out.".to_string())
+}
+
+/// The web, reached through the system's resolver.
+#[cfg(not(test))]
+struct Internet;
+
+#[cfg(not(test))]
+impl Site for Internet {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, ApiError> {
+        let addresses = (host, port).to_socket_addrs().map_err(|_| {
+            ApiError::unknown("The host of the URL could not be resolved.".to_string())
+        })?;
+
+        Ok(addresses.collect())
+    }
+
+    fn get(&self, url: &Url, address: SocketAddr, deadline: Instant)
+    -> Result<SiteResponse, ApiError> {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Err(timed_out());
+        }
+
+        let tls = OpensslClient::new().map_api_err(|_| {
+            ApiError::unknown("Failed to set up TLS for fetching the URL.".to_string())
+        })?;
+        let mut client = Client::with_connector(PinnedConnector { address: address, tls: tls });
+
+        // Redirects are followed by `fetch`, since their targets must be checked as well.
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        client.set_read_timeout(Some(deadline - now));
+        client.set_write_timeout(Some(deadline - now));
+
+        let response = client.get(url.as_str())
+            .send()
+            .map_api_err(|_| ApiError::unknown("Failed to fetch the URL.".to_string()))?;
+
+        if response.status.class() == StatusClass::Redirection {
+            return match response.headers.get::<Location>() {
+                Some(location) => Ok(SiteResponse::Redirect(location.0.clone())),
+                None => Ok(SiteResponse::Failure),
+            };
+        }
+
+        if response.status.class() != StatusClass::Success {
+            return Ok(SiteResponse::Failure);
+        }
+
+        match response.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Text, SubLevel::Html, _))) => {}
+            _ => return Ok(SiteResponse::NotHtml),
+        }
+
+        Ok(SiteResponse::Html(Box::new(response)))
+    }
+}
+
+/// Connects to a resolved and checked address, instead of resolving the host of the URL again.
+///
+/// For https URLs, the certificate is verified against the host of the URL, not the address.
+#[cfg(not(test))]
+struct PinnedConnector {
+    /// The address to connect to.
+    address: SocketAddr,
+    /// The TLS client for https URLs.
+    tls: OpensslClient,
+}
+
+#[cfg(not(test))]
+impl NetworkConnector for PinnedConnector {
+    type Stream = HttpsStream<<OpensslClient as SslClient>::Stream>;
+
+    fn connect(&self, host: &str, _port: u16, scheme: &str) -> hyper::Result<Self::Stream> {
+        if scheme != "http" && scheme != "https" {
+            return Err(hyper::Error::Io(
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid scheme for Http")
+            ));
+        }
+
+        let timeout = Duration::from_secs(CONNECT_TIMEOUT);
+        let stream = HttpStream(TcpStream::connect_timeout(&self.address, timeout)?);
+
+        if scheme == "https" {
+            self.tls.wrap_client(stream, host).map(HttpsStream::Https)
+        } else {
+            Ok(HttpsStream::Http(stream))
+        }
+    }
+}
+
+/// A page served by tests with `serve_page`, `serve_redirect` or `serve_non_html`.
+#[cfg(test)]
+#[derive(Clone)]
+enum TestPage {
+    /// An HTML page.
+    Html(String),
+    /// A redirect to the given URL.
+    Redirect(String),
+    /// A page that is not HTML.
+    NotHtml,
+}
+
+/// The pages served by tests on the current thread. Host names that were not given addresses with
+/// `resolve_host` resolve to an external address.
+#[cfg(test)]
+struct TestSite;
+
+#[cfg(test)]
+impl Site for TestSite {
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, ApiError> {
+        let addresses = HOSTS.with(|hosts| hosts.borrow().get(host).cloned())
+            .unwrap_or_else(|| vec![IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]);
+
+        Ok(addresses.into_iter().map(|address| SocketAddr::new(address, port)).collect())
+    }
+
+    fn get(&self, url: &Url, _address: SocketAddr, _deadline: Instant)
+    -> Result<SiteResponse, ApiError> {
+        let page = PAGES.with(|pages| pages.borrow().get(url.as_str()).cloned());
+
+        Ok(match page {
+            Some(TestPage::Html(html)) => {
+                SiteResponse::Html(Box::new(Cursor::new(html.into_bytes())))
+            }
+            Some(TestPage::Redirect(location)) => SiteResponse::Redirect(location),
+            Some(TestPage::NotHtml) => SiteResponse::NotHtml,
+            None => SiteResponse::Failure,
+        })
+    }
+}
+
+/// Serve `html` as the page at `url` to previews on the current thread.
+#[cfg(test)]
+pub fn serve_page(url: &str, html: &str) {
+    serve(url, TestPage::Html(html.to_string()));
+}
+
+/// Redirect requests for `url` to `location` for previews on the current thread.
+#[cfg(test)]
+pub fn serve_redirect(url: &str, location: &str) {
+    serve(url, TestPage::Redirect(location.to_string()));
+}
+
+/// Serve a page that is not HTML at `url` to previews on the current thread.
+#[cfg(test)]
+pub fn serve_non_html(url: &str) {
+    serve(url, TestPage::NotHtml);
+}
+
+/// Make `host` resolve to `addresses` for previews on the current thread.
+#[cfg(test)]
+pub fn resolve_host(host: &str, addresses: &[IpAddr]) {
+    HOSTS.with(|hosts| {
+        hosts.borrow_mut().insert(host.to_string(), addresses.to_vec());
+    });
+}
+
+#[cfg(test)]
+fn serve(url: &str, page: TestPage) {
+    PAGES.with(|pages| {
+        pages.borrow_mut().insert(url.to_string(), page);
+    });
+}
+
+/// Check whether `host` is one of `hosts` or a subdomain of one of them.
+fn matches_any(host: &str, hosts: &[String]) -> bool {
+    hosts.iter().any(|entry| {
+        let entry = entry.trim_left_matches("*.").trim_right_matches('.').to_lowercase();
+
+        host == entry || host.ends_with(&format!(".{}", entry))
+    })
+}
+
+/// Whether an IPv4 address belongs to this host, a private network or is not a unicast address.
+fn is_internal_ipv4(address: &Ipv4Addr) -> bool {
+    let octets = address.octets();
+
+    address.is_loopback() || address.is_private() || address.is_link_local() ||
+        address.is_broadcast() || address.is_multicast() ||
+        // "This network", 0.0.0.0/8.
+        octets[0] == 0 ||
+        // Shared address space for carrier-grade NAT, 100.64.0.0/10.
+        (octets[0] == 100 && octets[1] & 0xc0 == 64) ||
+        // Reserved for future use, 240.0.0.0/4.
+        octets[0] >= 240
+}
+
+/// Whether an IPv6 address belongs to this host or a private network.
+fn is_internal_ipv6(address: &Ipv6Addr) -> bool {
+    let first_segment = address.segments()[0];
+
+    address.is_loopback() || address.is_unspecified() ||
+        // Unique local addresses, fc00::/7.
+        first_segment & 0xfe00 == 0xfc00 ||
+        // Link-local addresses, fe80::/10.
+        first_segment & 0xffc0 == 0xfe80 ||
+        address.to_ipv4().map_or(false, |address| is_internal_ipv4(&address))
+}
+
+/// Replace the most common HTML character references with the characters they stand for.
+fn decode_entities(value: &str) -> String {
+    value.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use serde_json::{Map, Value};
+    use url::Url;
+
+    use config::UrlPreviewConfig;
+    use error::ApiError;
+    use super::{
+        SITE,
+        fetch,
+        is_allowed,
+        parse_preview,
+        preview,
+        resolve_host,
+        serve_non_html,
+        serve_page,
+        serve_redirect,
+    };
+
+    fn config(allowed_hosts: &[&str], denied_hosts: &[&str]) -> UrlPreviewConfig {
+        UrlPreviewConfig {
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            denied_hosts: denied_hosts.iter().map(|host| host.to_string()).collect(),
+            max_size: 1024,
+        }
+    }
+
+    fn allows(url: &str, allowed_hosts: &[&str], denied_hosts: &[&str]) -> bool {
+        is_allowed(&Url::parse(url).unwrap(), &config(allowed_hosts, denied_hosts))
+    }
+
+    fn preview_of(url: &str) -> Result<Map<String, Value>, ApiError> {
+        preview(&Url::parse(url).unwrap(), &config(&[], &["intranet.example.com"]))
+    }
+
+    #[test]
+    fn parse_open_graph_properties() {
+        let preview = parse_preview(r#"
+            <html><head>
+                <title>Fallback</title>
+                <meta property="og:title" content="Matrix &amp; Ruma">
+                <meta content='An open network' property='og:description' />
+                <meta property="og:image" content="https://example.com/logo.png">
+            </head></html>
+        "#);
+
+        assert_eq!(preview.len(), 2);
+        assert_eq!(preview["og:title"], "Matrix & Ruma");
+        assert_eq!(preview["og:description"], "An open network");
+    }
+
+    #[test]
+    fn parse_preview_falls_back_to_title_and_description() {
+        let preview = parse_preview(r#"
+            <title>
+                Ruma
+            </title>
+            <meta name="description" content="A Matrix homeserver">
+        "#);
+
+        assert_eq!(preview["og:title"], "Ruma");
+        assert_eq!(preview["og:description"], "A Matrix homeserver");
+    }
+
+    #[test]
+    fn hosts_are_allowed_by_the_configuration() {
+        assert!(allows("https://example.com/", &[], &[]));
+        assert!(allows("https://www.example.com/", &["example.com"], &[]));
+        assert!(!allows("https://example.org/", &["example.com"], &[]));
+        assert!(!allows("https://intranet.example.com/", &[], &["intranet.example.com"]));
+        assert!(!allows(
+            "https://intranet.example.com/",
+            &["example.com"],
+            &["intranet.example.com"],
+        ));
+        assert!(!allows("ftp://example.com/", &[], &[]));
+    }
+
+    #[test]
+    fn internal_addresses_are_never_allowed() {
+        assert!(!allows("http://localhost:8008/", &[], &[]));
+        assert!(!allows("http://127.0.0.1/", &[], &[]));
+        assert!(!allows("http://192.168.1.1/", &[], &[]));
+        assert!(!allows("http://0.1.2.3/", &[], &[]));
+        assert!(!allows("http://100.64.0.1/", &[], &[]));
+        assert!(!allows("http://100.127.255.254/", &[], &[]));
+        assert!(!allows("http://224.0.0.1/", &[], &[]));
+        assert!(!allows("http://240.0.0.1/", &[], &[]));
+        assert!(!allows("http://[::1]/", &[], &[]));
+        assert!(!allows("http://[fd00::1]/", &[], &[]));
+        assert!(allows("http://93.184.216.34/", &[], &[]));
+        assert!(allows("http://100.128.0.1/", &[], &[]));
+    }
+
+    #[test]
+    fn redirects_are_followed() {
+        serve_redirect("http://example.com/short", "/article");
+        serve_redirect("http://example.com/article", "http://www.example.com/article");
+        serve_page("http://www.example.com/article", "<title>Ruma</title>");
+
+        let preview = preview_of("http://example.com/short").unwrap();
+
+        assert_eq!(preview["og:title"], "Ruma");
+    }
+
+    #[test]
+    fn redirects_to_denied_hosts_are_not_followed() {
+        serve_redirect("http://example.com/", "http://intranet.example.com/");
+        serve_page("http://intranet.example.com/", "<title>Secrets</title>");
+
+        assert!(preview_of("http://example.com/").is_err());
+    }
+
+    #[test]
+    fn redirects_to_hosts_resolving_to_internal_addresses_are_not_followed() {
+        resolve_host("rebind.example.org", &[IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        serve_redirect("http://example.com/", "http://rebind.example.org/");
+        serve_page("http://rebind.example.org/", "<title>Secrets</title>");
+
+        assert!(preview_of("http://example.com/").is_err());
+    }
+
+    #[test]
+    fn redirects_are_limited() {
+        for hop in 0..6 {
+            serve_redirect(
+                &format!("http://example.com/{}", hop),
+                &format!("http://example.com/{}", hop + 1),
+            );
+        }
+
+        serve_page("http://example.com/6", "<title>Ruma</title>");
+
+        assert!(preview_of("http://example.com/0").is_err());
+        assert_eq!(preview_of("http://example.com/1").unwrap()["og:title"], "Ruma");
+    }
+
+    #[test]
+    fn hosts_resolving_to_any_internal_address_are_not_fetched() {
+        resolve_host(
+            "mixed.example.org",
+            &[IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
+        );
+        serve_page("http://mixed.example.org/", "<title>Secrets</title>");
+
+        assert!(preview_of("http://mixed.example.org/").is_err());
+    }
+
+    #[test]
+    fn pages_are_fetched_up_to_the_maximum_size() {
+        let page = format!("<title>Ruma</title>{}", "a".repeat(2048));
+
+        serve_page("http://example.com/long", &page);
+
+        let url = Url::parse("http://example.com/long").unwrap();
+        let fetched = fetch(&SITE, &url, &config(&[], &[])).unwrap().unwrap();
+
+        assert_eq!(fetched.len(), 1024);
+        assert!(fetched.starts_with("<title>Ruma</title>"));
+    }
+
+    #[test]
+    fn pages_that_are_not_html_have_an_empty_preview() {
+        serve_non_html("http://example.com/image.png");
+
+        assert!(preview_of("http://example.com/image.png").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+//! Matching of user IDs and room aliases against the namespaces of application services.
+//!
+//! IDs in an exclusive namespace are reserved for its application service: nobody else can
+//! register such a user or create such an alias. IDs in non-exclusive namespaces can be used by
+//! anyone. Room aliases matching the configured `reserved_alias_patterns` are reserved for the
+//! server administrators in the same way.
+
+use regex::{Error as RegexError, Regex};
+
+use appservice::{AppServiceRegistration, Namespace};
+use config::Config;
+use error::ApiError;
+use models::user::User;
+
+/// The kinds of IDs application services have namespaces for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NamespaceKind {
+    /// Room aliases, e.g. *#irc_#ruma:ruma.test*.
+    Aliases,
+    /// User IDs, e.g. *@irc_carl:ruma.test*.
+    Users,
+}
+
+/// Compile the regular expression of a namespace so that it only matches whole IDs.
+pub fn compile_namespace(regex: &str) -> Result<Regex, RegexError> {
+    Regex::new(&format!("^(?:{})$", regex))
+}
+
+/// Check whether an ID is in one of the namespaces.
+pub fn in_namespaces(namespaces: &[Namespace], id: &str) -> bool {
+    namespaces.iter().any(|namespace| matches(namespace, id))
+}
+
+/// The namespaces of the given kind of an application service.
+pub fn namespaces(registration: &AppServiceRegistration, kind: NamespaceKind) -> &[Namespace] {
+    match kind {
+        NamespaceKind::Aliases => &registration.namespaces.aliases,
+        NamespaceKind::Users => &registration.namespaces.users,
+    }
+}
+
+/// Find the application service that has an exclusive namespace containing the ID.
+pub fn exclusive_owner<'a>(
+    registrations: &'a [AppServiceRegistration],
+    kind: NamespaceKind,
+    id: &str,
+) -> Option<&'a AppServiceRegistration> {
+    registrations.iter().find(|registration| {
+        namespaces(registration, kind).iter().any(|namespace| {
+            namespace.exclusive && matches(namespace, id)
+        })
+    })
+}
+
+/// Check that an ID is not reserved for another application service than `requester`, which is
+/// `None` for requests of regular users.
+///
+/// Fails with `M_EXCLUSIVE` otherwise.
+pub fn verify_not_reserved(
+    registrations: &[AppServiceRegistration],
+    kind: NamespaceKind,
+    id: &str,
+    requester: Option<&AppServiceRegistration>,
+) -> Result<(), ApiError> {
+    match exclusive_owner(registrations, kind, id) {
+        Some(owner) if requester.map_or(true, |requester| requester.id != owner.id) => {
+            let what = match kind {
+                NamespaceKind::Aliases => "room alias",
+                NamespaceKind::Users => "user ID",
+            };
+
+            Err(ApiError::exclusive(format!(
+                "The {} {} is reserved for an application service.",
+                what,
+                id
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that the user may create the room alias.
+///
+/// The alias must not be reserved for an application service other than `requester`, and only
+/// server administrators may create aliases matching `reserved_alias_patterns`. Fails with
+/// `M_EXCLUSIVE` otherwise.
+pub fn verify_alias_allowed(
+    config: &Config,
+    alias: &str,
+    user: &User,
+    requester: Option<&AppServiceRegistration>,
+) -> Result<(), ApiError> {
+    verify_not_reserved(&config.app_services, NamespaceKind::Aliases, alias, requester)?;
+
+    let reserved = config.reserved_alias_patterns.iter().any(|pattern| is_match(pattern, alias));
+
+    if reserved && !user.is_server_admin(config) {
+        return Err(ApiError::exclusive(format!(
+            "The room alias {} is reserved for the server administrators.",
+            alias
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check whether an ID is in a namespace.
+fn matches(namespace: &Namespace, id: &str) -> bool {
+    is_match(&namespace.regex, id)
+}
+
+/// Check whether the regular expression matches the whole ID.
+///
+/// Invalid regular expressions match nothing. They are rejected when the configuration is loaded.
+fn is_match(regex: &str, id: &str) -> bool {
+    compile_namespace(regex).map_or(false, |regex| regex.is_match(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_str;
+
+    use appservice::AppServiceRegistration;
+    use super::{NamespaceKind, exclusive_owner, in_namespaces, verify_not_reserved};
+
+    fn registrations() -> Vec<AppServiceRegistration> {
+        from_str(r#"[
+            {
+                "id": "irc",
+                "as_token": "irc_secret",
+                "sender_localpart": "irc_bot",
+                "namespaces": {
+                    "users": [{"exclusive": true, "regex": "@irc_.*:ruma\\.test"}],
+                    "aliases": [{"exclusive": true, "regex": "#irc_.*:ruma\\.test"}]
+                }
+            },
+            {
+                "id": "logger",
+                "as_token": "logger_secret",
+                "sender_localpart": "logger",
+                "namespaces": {"users": [{"exclusive": false, "regex": "@.*_log:ruma\\.test"}]}
+            }
+        ]"#).unwrap()
+    }
+
+    #[test]
+    fn namespaces_match_whole_ids() {
+        let registrations = registrations();
+        let users = &registrations[0].namespaces.users;
+
+        assert!(in_namespaces(users, "@irc_carl:ruma.test"));
+        assert!(!in_namespaces(users, "@irc_carl:ruma.test.evil"));
+        assert!(!in_namespaces(users, "@carl_irc_x:ruma.test"));
+    }
+
+    #[test]
+    fn exclusive_namespaces_have_an_owner() {
+        let registrations = registrations();
+
+        let owner = exclusive_owner(&registrations, NamespaceKind::Users, "@irc_carl:ruma.test");
+        assert_eq!(owner.unwrap().id, "irc");
+
+        let owner = exclusive_owner(&registrations, NamespaceKind::Aliases, "#irc_ruma:ruma.test");
+        assert_eq!(owner.unwrap().id, "irc");
+
+        assert!(exclusive_owner(&registrations, NamespaceKind::Users, "@carl_log:ruma.test")
+            .is_none());
+        assert!(exclusive_owner(&registrations, NamespaceKind::Users, "@carl:ruma.test")
+            .is_none());
+    }
+
+    #[test]
+    fn only_the_owner_can_use_reserved_ids() {
+        let registrations = registrations();
+        let (irc, logger) = (&registrations[0], &registrations[1]);
+        let kind = NamespaceKind::Users;
+
+        assert!(verify_not_reserved(&registrations, kind, "@irc_carl:ruma.test", None).is_err());
+        assert!(
+            verify_not_reserved(&registrations, kind, "@irc_carl:ruma.test", Some(logger))
+                .is_err()
+        );
+        assert!(
+            verify_not_reserved(&registrations, kind, "@irc_carl:ruma.test", Some(irc)).is_ok()
+        );
+        assert!(verify_not_reserved(&registrations, kind, "@carl_log:ruma.test", None).is_ok());
+        assert!(verify_not_reserved(&registrations, kind, "@carl:ruma.test", None).is_ok());
+    }
+}
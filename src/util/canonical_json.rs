@@ -0,0 +1,199 @@
+//! Canonical JSON, the serialization Matrix hashes and signs.
+//!
+//! The keys of objects are sorted by their code points and no insignificant whitespace is
+//! written, so equal values always serialize to the same bytes. Strings are written as UTF-8,
+//! escaping only quotes, backslashes and control characters. Numbers must be integers in the range
+//! that is exact in IEEE 754 doubles, and are written without fraction or exponent.
+//!
+//! Events imported from elsewhere may predate that rule and have other floats in their content, so
+//! they are serialized with `to_canonical_allowing_floats`, which writes those as serde_json does.
+
+use serde_json::{Number, Value, to_string};
+
+use error::ApiError;
+
+/// The largest magnitude of an integer in canonical JSON, 2^53 - 1.
+const MAX_SAFE_INTEGER: i64 = 9_007_199_254_740_991;
+
+/// Serialize a value as canonical JSON.
+///
+/// Fails with `M_BAD_JSON` if the value contains a number that is not an integer, or an integer
+/// outside of [-(2^53 - 1), 2^53 - 1].
+pub fn to_canonical(value: &Value) -> Result<Vec<u8>, ApiError> {
+    let mut json = String::new();
+
+    write_value(&mut json, value, false)?;
+
+    Ok(json.into_bytes())
+}
+
+/// Serialize a value as canonical JSON, except that floats are allowed.
+///
+/// Integers are still limited to [-(2^53 - 1), 2^53 - 1] and floats with an integral value in that
+/// range are still written as integers, so values `to_canonical` accepts serialize the same either
+/// way.
+pub fn to_canonical_allowing_floats(value: &Value) -> Result<Vec<u8>, ApiError> {
+    let mut json = String::new();
+
+    write_value(&mut json, value, true)?;
+
+    Ok(json.into_bytes())
+}
+
+/// Append the canonical JSON of a value to `json`.
+fn write_value(json: &mut String, value: &Value, allow_floats: bool) -> Result<(), ApiError> {
+    match *value {
+        Value::Array(ref values) => {
+            json.push('[');
+
+            for (index, value) in values.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+
+                write_value(json, value, allow_floats)?;
+            }
+
+            json.push(']');
+        }
+        Value::Object(ref map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+
+            keys.sort();
+            json.push('{');
+
+            for (index, key) in keys.into_iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+
+                write_string(json, key);
+                json.push(':');
+                write_value(json, &map[key], allow_floats)?;
+            }
+
+            json.push('}');
+        }
+        Value::Number(ref number) => match integer(number) {
+            Ok(integer) => json.push_str(&integer.to_string()),
+            Err(_) if allow_floats && number.is_f64() => json.push_str(&number.to_string()),
+            Err(error) => return Err(error),
+        },
+        Value::String(ref string) => write_string(json, string),
+        Value::Bool(_) | Value::Null => json.push_str(&value.to_string()),
+    }
+
+    Ok(())
+}
+
+/// Append a string as a JSON string literal to `json`.
+fn write_string(json: &mut String, string: &str) {
+    // Serializing a string cannot fail.
+    json.push_str(&to_string(string).unwrap_or_default());
+}
+
+/// The value of a number that is allowed in canonical JSON.
+///
+/// Floats with an integral value, e.g. `1e10` or `-0`, are taken as the integer.
+fn integer(number: &Number) -> Result<i64, ApiError> {
+    let integer = match (number.as_i64(), number.as_f64()) {
+        (Some(integer), _) => Some(integer),
+        (None, Some(float)) if float.fract() == 0.0 && float.abs() <= MAX_SAFE_INTEGER as f64 => {
+            Some(float as i64)
+        }
+        _ => None,
+    };
+
+    match integer {
+        Some(integer) if -MAX_SAFE_INTEGER <= integer && integer <= MAX_SAFE_INTEGER => {
+            Ok(integer)
+        }
+        _ => Err(ApiError::bad_json(format!(
+            "{} is not an integer between -(2^53 - 1) and 2^53 - 1.",
+            number
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_str;
+
+    use super::{to_canonical, to_canonical_allowing_floats};
+
+    fn canonical(json: &str) -> String {
+        String::from_utf8(to_canonical(&from_str(json).unwrap()).unwrap()).unwrap()
+    }
+
+    fn is_canonical(json: &str) -> bool {
+        to_canonical(&from_str(json).unwrap()).is_ok()
+    }
+
+    fn canonical_allowing_floats(json: &str) -> String {
+        String::from_utf8(to_canonical_allowing_floats(&from_str(json).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn whitespace_is_removed() {
+        assert_eq!(canonical("{}"), "{}");
+        assert_eq!(canonical(r#"{ "one": 1, "two": "Two" }"#), r#"{"one":1,"two":"Two"}"#);
+        assert_eq!(canonical(r#"[ 1, [ true, null ], { } ]"#), r#"[1,[true,null],{}]"#);
+        assert_eq!(canonical(r#"{"a": null}"#), r#"{"a":null}"#);
+    }
+
+    #[test]
+    fn keys_are_sorted() {
+        assert_eq!(canonical(r#"{"b": "2", "a": "1"}"#), r#"{"a":"1","b":"2"}"#);
+        assert_eq!(canonical(r#"{"本": 2, "日": 1}"#), r#"{"日":1,"本":2}"#);
+        assert_eq!(
+            canonical(r#"{
+                "auth": {
+                    "success": true,
+                    "mxid": "@john.doe:example.com",
+                    "profile": {"display_name": "John Doe", "three_pids": ["email", "msisdn"]}
+                }
+            }"#),
+            concat!(
+                r#"{"auth":{"mxid":"@john.doe:example.com","#,
+                r#""profile":{"display_name":"John Doe","three_pids":["email","msisdn"]},"#,
+                r#""success":true}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn strings_are_written_as_utf8() {
+        assert_eq!(canonical(r#"{"a": "日本語"}"#), r#"{"a":"日本語"}"#);
+        assert_eq!(canonical(r#"{"a": "\u65E5"}"#), r#"{"a":"日"}"#);
+        assert_eq!(canonical(r#"{"a": "\"\\\n"}"#), r#"{"a":"\"\\\n"}"#);
+    }
+
+    #[test]
+    fn numbers_are_integers() {
+        assert_eq!(canonical(r#"{"a": -0, "b": 1e10}"#), r#"{"a":0,"b":10000000000}"#);
+        assert_eq!(canonical("[1.0, 1E2]"), "[1,100]");
+        assert_eq!(
+            canonical("[9007199254740991, -9007199254740991]"),
+            "[9007199254740991,-9007199254740991]"
+        );
+
+        assert!(!is_canonical(r#"{"a": 1.5}"#));
+        assert!(!is_canonical("[0.1]"));
+        assert!(!is_canonical("[1e20]"));
+        assert!(!is_canonical("[9007199254740992]"));
+        assert!(!is_canonical("[-9007199254740992]"));
+        assert!(!is_canonical("[-9223372036854775808]"));
+        assert!(!is_canonical("[18446744073709551615]"));
+    }
+
+    #[test]
+    fn floats_are_allowed_for_imported_events() {
+        assert_eq!(
+            canonical_allowing_floats(r#"{"b": 1.5, "a": 1}"#),
+            r#"{"a":1,"b":1.5}"#
+        );
+        assert_eq!(canonical_allowing_floats("[0.25, -2, 1e10]"), "[0.25,-2,10000000000]");
+
+        assert!(to_canonical_allowing_floats(&from_str("[9007199254740992]").unwrap()).is_err());
+    }
+}
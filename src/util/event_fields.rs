@@ -0,0 +1,137 @@
+//! The `event_fields` of filters, which strip events down to the fields a client asked for.
+//!
+//! A field is a dot-separated path like *content.body*. Dots that are part of a key are escaped
+//! with a backslash, as in *content.m\.relates_to*, and so are backslashes.
+
+use serde_json::{Map, Value};
+
+/// Parse a field of `event_fields` into the keys along its path.
+///
+/// Returns `None` for malformed fields: empty keys, and backslashes that escape neither a dot nor
+/// a backslash.
+pub fn parse_field_path(field: &str) -> Option<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut key = String::new();
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) if escaped == '.' || escaped == '\\' => key.push(escaped),
+                _ => return None,
+            },
+            '.' => {
+                if key.is_empty() {
+                    return None;
+                }
+
+                keys.push(key);
+                key = String::new();
+            }
+            c => key.push(c),
+        }
+    }
+
+    if key.is_empty() {
+        return None;
+    }
+
+    keys.push(key);
+
+    Some(keys)
+}
+
+/// Copy the fields at `paths` of a serialized event into a new object, leaving out everything
+/// else. Paths that do not exist in the event are skipped.
+pub fn project_event(event: &Value, paths: &[Vec<String>]) -> Value {
+    let mut projection = Value::Object(Map::new());
+
+    for path in paths {
+        if let Some(value) = lookup(event, path) {
+            insert(&mut projection, path, value.clone());
+        }
+    }
+
+    projection
+}
+
+/// The value at `path` of an object.
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().fold(Some(value), |value, key| value.and_then(|value| value.get(key)))
+}
+
+/// Insert `value` at `path` into an object, creating the objects along the path.
+fn insert(object: &mut Value, path: &[String], value: Value) {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    let map = match *object {
+        Value::Object(ref mut map) => map,
+        _ => return,
+    };
+
+    if rest.is_empty() {
+        map.insert(key.clone(), value);
+    } else {
+        if !map.contains_key(key) {
+            map.insert(key.clone(), Value::Object(Map::new()));
+        }
+
+        if let Some(child) = map.get_mut(key) {
+            insert(child, rest, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, from_str};
+
+    use super::{parse_field_path, project_event};
+
+    fn paths(fields: &[&str]) -> Vec<Vec<String>> {
+        fields.iter().map(|field| parse_field_path(field).unwrap()).collect()
+    }
+
+    #[test]
+    fn field_paths() {
+        assert_eq!(parse_field_path("sender").unwrap(), vec!["sender"]);
+        assert_eq!(parse_field_path("content.body").unwrap(), vec!["content", "body"]);
+        assert_eq!(
+            parse_field_path(r"content.m\.relates_to").unwrap(),
+            vec!["content", "m.relates_to"]
+        );
+        assert_eq!(parse_field_path(r"back\\slash").unwrap(), vec![r"back\slash"]);
+    }
+
+    #[test]
+    fn malformed_field_paths() {
+        for field in &["", ".", "content.", ".content", "content..body", r"content\body", r"end\"] {
+            assert!(parse_field_path(field).is_none(), "{} should be malformed", field);
+        }
+    }
+
+    #[test]
+    fn events_are_projected_onto_the_fields() {
+        let event: Value = from_str(r#"{
+            "content": {"body": "Hello", "msgtype": "m.text", "m.relates_to": {"rel_type": "x"}},
+            "event_id": "$1:ruma.test",
+            "sender": "@carl:ruma.test",
+            "type": "m.room.message"
+        }"#).unwrap();
+
+        let projection = project_event(
+            &event,
+            &paths(&["type", "content.body", r"content.m\.relates_to", "unsigned.age", "sender.x"]),
+        );
+
+        let expected: Value = from_str(r#"{
+            "content": {"body": "Hello", "m.relates_to": {"rel_type": "x"}},
+            "type": "m.room.message"
+        }"#).unwrap();
+
+        assert_eq!(projection, expected);
+    }
+}
@@ -0,0 +1,107 @@
+//! Glob patterns as used by filters, e.g. `m.*` for all types in the *m.* namespace, and by
+//! server ACLs, e.g. `*.example.com`.
+
+/// Check whether `value` matches the glob `pattern`.
+///
+/// `*` matches any sequence of characters, including none. All other characters, including
+/// dots, only match themselves.
+pub fn glob(pattern: &str, value: &str) -> bool {
+    matches(pattern, value, false)
+}
+
+/// Check whether `value` matches the glob `pattern`, in which `?` matches exactly one character.
+///
+/// `*` matches any sequence of characters, including none, like in `glob`.
+pub fn glob_with_single_wildcard(pattern: &str, value: &str) -> bool {
+    matches(pattern, value, true)
+}
+
+/// Match `value` against `pattern`, treating `?` as a wildcard if `single_wildcard` is true.
+fn matches(pattern: &str, value: &str, single_wildcard: bool) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let mut pattern_index = 0;
+    let mut value_index = 0;
+    // The position of the last `*` in the pattern and of the value when it was reached, to
+    // backtrack to if the rest of the pattern does not match.
+    let mut backtrack = None;
+
+    while value_index < value.len() {
+        match pattern.get(pattern_index) {
+            Some(&'*') => {
+                backtrack = Some((pattern_index, value_index));
+                pattern_index += 1;
+            }
+            Some(&'?') if single_wildcard => {
+                pattern_index += 1;
+                value_index += 1;
+            }
+            Some(character) if *character == value[value_index] => {
+                pattern_index += 1;
+                value_index += 1;
+            }
+            _ => match backtrack {
+                Some((star_index, star_value_index)) => {
+                    // Let the `*` match one more character and try again.
+                    backtrack = Some((star_index, star_value_index + 1));
+                    pattern_index = star_index + 1;
+                    value_index = star_value_index + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[pattern_index..].iter().all(|character| *character == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob, glob_with_single_wildcard};
+
+    #[test]
+    fn literal_patterns() {
+        assert!(glob("m.direct", "m.direct"));
+        assert!(!glob("m.direct", "m.directs"));
+        assert!(!glob("m.direct", "m.dir"));
+        assert!(!glob("m.direct", "m_direct"));
+        assert!(glob("", ""));
+        assert!(!glob("", "m.direct"));
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        assert!(glob("*", ""));
+        assert!(glob("*", "m.direct"));
+        assert!(glob("m.*", "m.direct"));
+        assert!(glob("m.*", "m."));
+        assert!(!glob("m.*", "m"));
+        assert!(!glob("m.*", "org.example.m.direct"));
+        assert!(glob("org.example.*", "org.example.settings.large"));
+        assert!(!glob("org.example.*", "org.examples.settings"));
+    }
+
+    #[test]
+    fn wildcards_in_the_middle() {
+        assert!(glob("org.*.settings", "org.example.settings"));
+        assert!(glob("org.*.settings", "org.a.b.settings"));
+        assert!(!glob("org.*.settings", "org.example.settings.old"));
+        assert!(glob("*.settings*", "org.example.settings.old"));
+        assert!(glob("m.**.list", "m.ignored_user.list"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(!glob("m.?", "m.a"));
+        assert!(glob("m.?", "m.?"));
+        assert!(glob_with_single_wildcard("m.?", "m.a"));
+        assert!(glob_with_single_wildcard("m.?", "m.?"));
+        assert!(!glob_with_single_wildcard("m.?", "m."));
+        assert!(!glob_with_single_wildcard("m.?", "m.ab"));
+        assert!(glob_with_single_wildcard("?*.example.com", "a.example.com"));
+        assert!(!glob_with_single_wildcard("?*.example.com", ".example.com"));
+        assert!(glob_with_single_wildcard("*?", "a"));
+        assert!(!glob_with_single_wildcard("*?", ""));
+    }
+}
@@ -0,0 +1,147 @@
+//! IP networks in CIDR notation, e.g. *10.0.0.0/8* or *2001:db8::/32*.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A range of IP addresses sharing a prefix.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpNetwork {
+    /// The address the prefix is taken from.
+    address: IpAddr,
+    /// The number of leading bits addresses must share with `address`.
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Parse a network like *10.0.0.0/8*. A plain address is a network of only that address.
+    pub fn parse(network: &str) -> Option<IpNetwork> {
+        let (address, prefix_len) = match network.find('/') {
+            Some(slash) => (&network[..slash], Some(&network[slash + 1..])),
+            None => (network, None),
+        };
+
+        let address = match IpAddr::from_str(address) {
+            Ok(address) => canonical(address),
+            Err(_) => return None,
+        };
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => match u8::from_str(prefix_len) {
+                Ok(prefix_len) => prefix_len,
+                Err(_) => return None,
+            },
+            None => max_prefix_len,
+        };
+
+        if prefix_len > max_prefix_len {
+            return None;
+        }
+
+        Some(IpNetwork {
+            address: address,
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// Check whether `ip` is part of the network.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                has_prefix(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                has_prefix(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Turn IPv4-mapped IPv6 addresses like *::ffff:10.0.0.1*, which dual-stack sockets report for
+/// IPv4 peers, into the IPv4 addresses they stand for.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    if let IpAddr::V6(ipv6) = ip {
+        let segments = ipv6.segments();
+
+        if segments[..5].iter().all(|&segment| segment == 0) && segments[5] == 0xffff {
+            if let Some(ipv4) = ipv6.to_ipv4() {
+                return IpAddr::V4(ipv4);
+            }
+        }
+    }
+
+    ip
+}
+
+/// Check whether the first `prefix_len` bits of `network` and `address` are the same.
+fn has_prefix(network: &[u8], address: &[u8], prefix_len: u8) -> bool {
+    let whole_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+
+    if network[..whole_bytes] != address[..whole_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xff << (8 - remaining_bits);
+
+    network[whole_bytes] & mask == address[whole_bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use super::IpNetwork;
+
+    fn ip(address: &str) -> IpAddr {
+        IpAddr::from_str(address).unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let network = IpNetwork::parse("10.0.0.0/8").unwrap();
+
+        assert!(network.contains(ip("10.0.0.1")));
+        assert!(network.contains(ip("10.255.255.255")));
+        assert!(!network.contains(ip("11.0.0.1")));
+        assert!(network.contains(ip("::ffff:10.1.2.3")));
+
+        let network = IpNetwork::parse("192.168.0.0/23").unwrap();
+
+        assert!(network.contains(ip("192.168.1.7")));
+        assert!(!network.contains(ip("192.168.2.7")));
+
+        let network = IpNetwork::parse("2001:db8::/32").unwrap();
+
+        assert!(network.contains(ip("2001:db8::1")));
+        assert!(!network.contains(ip("2001:db9::1")));
+        assert!(!network.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn plain_addresses_are_single_address_networks() {
+        let network = IpNetwork::parse("127.0.0.1").unwrap();
+
+        assert!(network.contains(ip("127.0.0.1")));
+        assert!(!network.contains(ip("127.0.0.2")));
+        assert!(IpNetwork::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.1")));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        assert!(IpNetwork::parse("10.0.0.0/33").is_none());
+        assert!(IpNetwork::parse("::/129").is_none());
+        assert!(IpNetwork::parse("10.0.0/8").is_none());
+        assert!(IpNetwork::parse("proxy.example.com").is_none());
+        assert!(IpNetwork::parse("10.0.0.0/").is_none());
+    }
+}
@@ -0,0 +1,15 @@
+//! Helpers shared by the API endpoints.
+
+pub mod appservice;
+pub mod canonical_json;
+pub mod event_fields;
+pub mod glob;
+pub mod ip_network;
+pub mod pagination;
+pub mod redaction;
+pub mod room_alias;
+pub mod room_state;
+pub mod server_name;
+pub mod user_agent;
+pub mod user_id;
+pub mod worker_pool;
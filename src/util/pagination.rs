@@ -0,0 +1,275 @@
+//! Opaque tokens and page sizes for paginating through streams of results.
+
+use std::cmp::min;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+
+use base64::{URL_SAFE, decode_config, encode_config};
+use iron::Request;
+use ruma_identifiers::{RoomId, UserId};
+use serde_json::{from_slice, to_vec};
+use url::Url;
+
+use error::ApiError;
+
+/// A stream of results that can be paginated.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Stream {
+    /// The rooms of a space hierarchy, in the order they are traversed.
+    #[serde(rename="hierarchy")]
+    Hierarchy,
+    /// The events of a room, e.g. for `/rooms/:room_id/messages`.
+    #[serde(rename="messages")]
+    Messages,
+    /// The notifications of a user.
+    #[serde(rename="notifications")]
+    Notifications,
+    /// The rooms published in the room directory.
+    #[serde(rename="public_rooms")]
+    PublicRooms,
+    /// The relations to an event, newest first.
+    #[serde(rename="relations")]
+    Relations,
+    /// The results of a search.
+    #[serde(rename="search")]
+    Search,
+}
+
+/// A position in a `Stream`, encoded into an opaque string for clients.
+///
+/// Since the stream is part of the token, a token handed out for one endpoint cannot be mistaken
+/// for a position in another stream. Positions in streams that differ per user and room, like
+/// the rooms of a space hierarchy, also carry the user and room they were handed out for.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Token {
+    /// The stream the position belongs to.
+    stream: Stream,
+    /// The position in the stream.
+    position: i64,
+    /// The user the token was handed out to, for streams of a user and a room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_id: Option<UserId>,
+    /// The room the token was handed out for, for streams of a user and a room.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room_id: Option<RoomId>,
+}
+
+impl Token {
+    /// Create a new `Token` for a position in a stream.
+    pub fn new(stream: Stream, position: i64) -> Token {
+        Token {
+            stream: stream,
+            position: position,
+            user_id: None,
+            room_id: None,
+        }
+    }
+
+    /// Create a new `Token` for a position in a stream that differs per user and room.
+    pub fn for_user_and_room(stream: Stream, position: i64, user_id: &UserId, room_id: &RoomId)
+    -> Token {
+        Token {
+            stream: stream,
+            position: position,
+            user_id: Some(user_id.clone()),
+            room_id: Some(room_id.clone()),
+        }
+    }
+
+    /// Decode a token a client supplied in the parameter `param_name`.
+    ///
+    /// Fails if the token is malformed or belongs to a stream other than `stream`.
+    pub fn decode(stream: Stream, param_name: &str, token: &str) -> Result<Token, ApiError> {
+        let invalid_token = || ApiError::invalid_param(param_name, "Not a valid pagination token.");
+
+        let bytes = decode_config(token, URL_SAFE).map_err(|_| invalid_token())?;
+        let token: Token = from_slice(&bytes).map_err(|_| invalid_token())?;
+
+        if token.stream != stream {
+            return Err(ApiError::invalid_param(
+                param_name,
+                "The pagination token belongs to a different stream.",
+            ));
+        }
+
+        Ok(token)
+    }
+
+    /// Decode a token created with `Token::for_user_and_room` that a client supplied in the
+    /// parameter `param_name`.
+    ///
+    /// Fails like `Token::decode`, and if the token was handed out to another user or for another
+    /// room.
+    pub fn decode_for_user_and_room(
+        stream: Stream,
+        param_name: &str,
+        token: &str,
+        user_id: &UserId,
+        room_id: &RoomId,
+    ) -> Result<Token, ApiError> {
+        let token = Token::decode(stream, param_name, token)?;
+
+        if token.user_id.as_ref() != Some(user_id) || token.room_id.as_ref() != Some(room_id) {
+            return Err(ApiError::invalid_param(
+                param_name,
+                "The pagination token was handed out for a different user or room.",
+            ));
+        }
+
+        Ok(token)
+    }
+
+    /// Encode the token into an opaque string.
+    pub fn encode(&self) -> String {
+        let json = to_vec(self).expect("Token should always serialize");
+
+        encode_config(&json, URL_SAFE)
+    }
+
+    /// The stream the position belongs to.
+    pub fn stream(&self) -> Stream {
+        self.stream
+    }
+
+    /// The position in the stream.
+    pub fn position(&self) -> i64 {
+        self.position
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}", self.encode())
+    }
+}
+
+/// Read the page size from the `limit` query parameter of a request.
+///
+/// Returns `default` if the parameter is missing and clamps larger values to `max`, the maximum
+/// the endpoint allows. Fails if the value is not a positive integer.
+pub fn parse_limit(request: &Request, default: usize, max: usize) -> Result<usize, ApiError> {
+    let url: Url = request.url.clone().into();
+    let value = url.query_pairs()
+        .find(|&(ref key, _)| key == "limit")
+        .map(|(_, value)| value.into_owned());
+
+    clamp_limit(value.as_ref().map(String::as_str), default, max)
+}
+
+/// Parse the value of a `limit` parameter, see `parse_limit`.
+fn clamp_limit(value: Option<&str>, default: usize, max: usize) -> Result<usize, ApiError> {
+    let invalid_limit = || ApiError::invalid_param("limit", "Must be a positive integer.");
+
+    let limit = match value {
+        Some(value) => match usize::from_str(value) {
+            Ok(limit) => limit,
+            // Numbers too large for a `usize` are clamped like any other large number.
+            Err(_) if !value.is_empty() && value.chars().all(|c| c.is_digit(10)) => max,
+            Err(_) => return Err(invalid_limit()),
+        },
+        None => default,
+    };
+
+    if limit == 0 {
+        return Err(invalid_limit());
+    }
+
+    Ok(min(limit, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::Response;
+    use iron::modifier::Modifier;
+    use iron::status::Status;
+    use ruma_identifiers::{RoomId, UserId};
+
+    use super::{Stream, Token, clamp_limit};
+
+    #[test]
+    fn encode_and_decode() {
+        let token = Token::new(Stream::Messages, 42);
+        let encoded = token.encode();
+
+        assert_eq!(Token::decode(Stream::Messages, "from", &encoded).unwrap(), token);
+        assert_eq!(Token::decode(Stream::Messages, "from", &encoded).unwrap().position(), 42);
+    }
+
+    #[test]
+    fn token_from_another_stream_is_rejected() {
+        let encoded = Token::new(Stream::PublicRooms, 42).encode();
+
+        let error = Token::decode(Stream::Messages, "from", &encoded).unwrap_err();
+        let mut response = Response::new();
+        error.modify(&mut response);
+
+        assert_eq!(response.status.unwrap(), Status::BadRequest);
+    }
+
+    #[test]
+    fn token_for_another_user_or_room_is_rejected() {
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+        let bob = UserId::try_from("@bob:ruma.test").unwrap();
+        let space = RoomId::try_from("!space:ruma.test").unwrap();
+        let room = RoomId::try_from("!room:ruma.test").unwrap();
+        let encoded = Token::for_user_and_room(Stream::Hierarchy, 42, &alice, &space).encode();
+
+        let token =
+            Token::decode_for_user_and_room(Stream::Hierarchy, "from", &encoded, &alice, &space);
+
+        assert_eq!(token.unwrap().position(), 42);
+        assert!(
+            Token::decode_for_user_and_room(Stream::Hierarchy, "from", &encoded, &bob, &space)
+                .is_err()
+        );
+        assert!(
+            Token::decode_for_user_and_room(Stream::Hierarchy, "from", &encoded, &alice, &room)
+                .is_err()
+        );
+
+        let unbound = Token::new(Stream::Hierarchy, 42).encode();
+
+        assert!(
+            Token::decode_for_user_and_room(Stream::Hierarchy, "from", &unbound, &alice, &space)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn malformed_token_is_rejected() {
+        assert!(Token::decode(Stream::Messages, "from", "42").is_err());
+        assert!(Token::decode(Stream::Messages, "from", "not base64!").is_err());
+        assert!(Token::decode(Stream::Messages, "from", "12_34").is_err());
+    }
+
+    #[test]
+    fn missing_limit_is_the_default() {
+        assert_eq!(clamp_limit(None, 10, 50).unwrap(), 10);
+        assert_eq!(clamp_limit(None, 100, 50).unwrap(), 50);
+    }
+
+    #[test]
+    fn valid_limit_is_used() {
+        assert_eq!(clamp_limit(Some("1"), 10, 50).unwrap(), 1);
+        assert_eq!(clamp_limit(Some("50"), 10, 50).unwrap(), 50);
+    }
+
+    #[test]
+    fn oversized_limit_is_clamped() {
+        assert_eq!(clamp_limit(Some("51"), 10, 50).unwrap(), 50);
+        assert_eq!(clamp_limit(Some("99999999999999999999"), 10, 50).unwrap(), 50);
+    }
+
+    #[test]
+    fn garbage_limit_is_rejected() {
+        for value in &["", "ten", "-1", "0", "1.5", " 5"] {
+            let error = clamp_limit(Some(value), 10, 50).unwrap_err();
+            let mut response = Response::new();
+            error.modify(&mut response);
+
+            assert_eq!(response.status.unwrap(), Status::BadRequest, "limit {:?}", value);
+        }
+    }
+}
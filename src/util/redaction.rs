@@ -0,0 +1,90 @@
+//! The redaction algorithm, which strips events down to the keys needed to authorize them.
+//!
+//! Only the content of an event is stored apart from its IDs and type, so only the content is
+//! redacted here. State events keep the keys the authorization rules depend on, like the
+//! *membership* of *m.room.member* events, as in the latest room versions. Everything else, e.g.
+//! the body of a message or the display name of a member, is removed.
+
+use serde_json::{Map, Value};
+
+/// Return the content of an event of the given type with everything removed that the redaction
+/// algorithm does not keep.
+pub fn redact_content(event_type: &str, content: &Value) -> Value {
+    let kept: &[&str] = match event_type {
+        "m.room.create" => return content.clone(),
+        "m.room.history_visibility" => &["history_visibility"],
+        "m.room.join_rules" => &["join_rule", "allow"],
+        "m.room.member" => &["membership", "join_authorised_via_users_server"],
+        "m.room.power_levels" => &[
+            "ban",
+            "events",
+            "events_default",
+            "invite",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        "m.room.redaction" => &["redacts"],
+        _ => &[],
+    };
+
+    let mut redacted = Map::new();
+
+    if let Value::Object(ref map) = *content {
+        for key in kept {
+            if let Some(value) = map.get(*key) {
+                redacted.insert(key.to_string(), value.clone());
+            }
+        }
+    }
+
+    Value::Object(redacted)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, from_str};
+
+    use super::redact_content;
+
+    fn redact(event_type: &str, content: &str) -> Value {
+        redact_content(event_type, &from_str(content).unwrap())
+    }
+
+    #[test]
+    fn messages_lose_their_whole_content() {
+        assert_eq!(
+            redact("m.room.message", r#"{"msgtype": "m.text", "body": "Secret"}"#),
+            from_str::<Value>("{}").unwrap()
+        );
+    }
+
+    #[test]
+    fn state_events_keep_the_keys_needed_for_authorization() {
+        assert_eq!(
+            redact(
+                "m.room.member",
+                r#"{"membership": "join", "displayname": "Carl", "avatar_url": "mxc://a/b"}"#
+            ),
+            from_str::<Value>(r#"{"membership": "join"}"#).unwrap()
+        );
+        assert_eq!(
+            redact("m.room.member", r#"{"membership": "leave", "reason": "Spamming"}"#),
+            from_str::<Value>(r#"{"membership": "leave"}"#).unwrap()
+        );
+        assert_eq!(
+            redact("m.room.power_levels", r#"{"ban": 50, "users": {}, "notifications": {}}"#),
+            from_str::<Value>(r#"{"ban": 50, "users": {}}"#).unwrap()
+        );
+        assert_eq!(
+            redact("m.room.create", r#"{"creator": "@carl:ruma.test", "type": "m.space"}"#),
+            from_str::<Value>(r#"{"creator": "@carl:ruma.test", "type": "m.space"}"#).unwrap()
+        );
+        assert_eq!(
+            redact("m.room.topic", r#"{"topic": "Carl's room"}"#),
+            from_str::<Value>("{}").unwrap()
+        );
+    }
+}
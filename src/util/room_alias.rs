@@ -0,0 +1,73 @@
+//! Validation of room aliases received from clients.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use ruma_identifiers::RoomAliasId;
+
+use error::{ApiError, MapApiError};
+use util::user_id::check_localpart;
+
+/// Parse a room alias of this server sent by a client.
+///
+/// The alias must have the form *#localpart:domain*, where the domain is the domain of this
+/// server and the localpart only contains the characters allowed in localparts of user IDs.
+/// `param` is the name of the request parameter reported if the alias is invalid.
+pub fn parse_local_room_alias(value: &str, domain: &str, param: &str)
+-> Result<RoomAliasId, ApiError> {
+    if !value.starts_with('#') {
+        return Err(ApiError::invalid_param(param, "A room alias must start with \"#\"."));
+    }
+
+    let mut parts = value[1..].splitn(2, ':');
+    let localpart = parts.next().unwrap_or("");
+
+    let server_name = match parts.next() {
+        Some(server_name) => server_name,
+        None => {
+            return Err(ApiError::invalid_param(
+                param,
+                "A room alias must have the form #localpart:domain.",
+            ));
+        }
+    };
+
+    if server_name != domain {
+        return Err(ApiError::invalid_param(
+            param,
+            &format!("Only room aliases on {} can be created on this server.", domain),
+        ));
+    }
+
+    check_localpart(localpart, param)?;
+
+    RoomAliasId::try_from(value).map_api_err(|err| {
+        ApiError::invalid_param(param, err.description())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_local_room_alias;
+
+    #[test]
+    fn local_room_aliases() {
+        assert_eq!(
+            parse_local_room_alias("#my_room:ruma.test", "ruma.test", "room_alias")
+                .unwrap()
+                .to_string(),
+            "#my_room:ruma.test"
+        );
+        assert!(parse_local_room_alias("#c.a_r=l-/1:ruma.test", "ruma.test", "room_alias").is_ok());
+    }
+
+    #[test]
+    fn malformed_room_aliases_are_rejected() {
+        assert!(parse_local_room_alias("my_room:ruma.test", "ruma.test", "room_alias").is_err());
+        assert!(parse_local_room_alias("#my_room", "ruma.test", "room_alias").is_err());
+        assert!(parse_local_room_alias("#:ruma.test", "ruma.test", "room_alias").is_err());
+        assert!(parse_local_room_alias("#my_room:example.com", "ruma.test", "room_alias").is_err());
+        assert!(parse_local_room_alias("#my room:ruma.test", "ruma.test", "room_alias").is_err());
+        assert!(parse_local_room_alias("#my#room:ruma.test", "ruma.test", "room_alias").is_err());
+    }
+}
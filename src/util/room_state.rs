@@ -0,0 +1,11 @@
+//! Keys of room state.
+
+use ruma_events::EventType;
+
+/// The type and state key of a piece of room state.
+pub type StateKey = (String, String);
+
+/// The key of a piece of state.
+pub fn state_key(event_type: &EventType, state_key: &str) -> StateKey {
+    (event_type.to_string(), state_key.to_string())
+}
@@ -0,0 +1,95 @@
+//! Validation of server names, the part of Matrix IDs after the colon.
+//!
+//! A server name is a hostname, an IPv4 address, or an IPv6 address in brackets, optionally
+//! followed by a port, e.g. *example.com*, *1.2.3.4:8448*, or *[::1]:8448*.
+
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+/// The maximum length of the hostname of a server name.
+const MAX_HOSTNAME_LENGTH: usize = 255;
+
+/// Check whether `server_name` is a valid server name.
+pub fn is_valid_server_name(server_name: &str) -> bool {
+    let (host, port) = if server_name.starts_with('[') {
+        match server_name.find(']') {
+            Some(end) => server_name.split_at(end + 1),
+            None => return false,
+        }
+    } else {
+        match server_name.find(':') {
+            Some(start) => server_name.split_at(start),
+            None => (server_name, ""),
+        }
+    };
+
+    is_valid_host(host) && is_valid_port(port)
+}
+
+/// Check whether `host` is an IPv6 address in brackets or a DNS name, which includes IPv4
+/// addresses.
+fn is_valid_host(host: &str) -> bool {
+    if host.starts_with('[') {
+        return host.ends_with(']') && Ipv6Addr::from_str(&host[1..host.len() - 1]).is_ok();
+    }
+
+    let is_allowed = |c: char| {
+        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') ||
+            c == '-' || c == '.'
+    };
+
+    !host.is_empty() && host.len() <= MAX_HOSTNAME_LENGTH && host.chars().all(is_allowed)
+}
+
+/// Check whether `port` is empty or a colon followed by a port number.
+fn is_valid_port(port: &str) -> bool {
+    if port.is_empty() {
+        return true;
+    }
+
+    if !port.starts_with(':') {
+        return false;
+    }
+
+    let digits = &port[1..];
+
+    !digits.is_empty() &&
+        digits.chars().all(|c| c.is_digit(10)) &&
+        u16::from_str(digits).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_valid_server_name;
+
+    #[test]
+    fn valid_server_names() {
+        assert!(is_valid_server_name("example.com"));
+        assert!(is_valid_server_name("matrix-1.example.com:8448"));
+        assert!(is_valid_server_name("localhost"));
+        assert!(is_valid_server_name("1.2.3.4"));
+        assert!(is_valid_server_name("1.2.3.4:443"));
+        assert!(is_valid_server_name("[::1]"));
+        assert!(is_valid_server_name("[2001:db8::1]:8448"));
+    }
+
+    #[test]
+    fn invalid_server_names() {
+        assert!(!is_valid_server_name(""));
+        assert!(!is_valid_server_name(":8448"));
+        assert!(!is_valid_server_name("example.com:"));
+        assert!(!is_valid_server_name("example.com:port"));
+        assert!(!is_valid_server_name("example.com:65536"));
+        assert!(!is_valid_server_name("example.com:+80"));
+        assert!(!is_valid_server_name("example.com:80:80"));
+        assert!(!is_valid_server_name("https://example.com"));
+        assert!(!is_valid_server_name("example.com/matrix"));
+        assert!(!is_valid_server_name("exa mple.com"));
+        assert!(!is_valid_server_name("ex_ample.com"));
+        assert!(!is_valid_server_name("::1"));
+        assert!(!is_valid_server_name("[::1"));
+        assert!(!is_valid_server_name("[not-an-ip]"));
+        assert!(!is_valid_server_name("[::1]é"));
+        assert!(!is_valid_server_name(&"a".repeat(256)));
+    }
+}
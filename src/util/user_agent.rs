@@ -0,0 +1,125 @@
+//! Best-effort parsing of `User-Agent` headers.
+
+/// The display name of devices whose client did not send a usable `User-Agent` header.
+pub const UNKNOWN_DEVICE: &'static str = "Unknown device";
+
+/// The maximum number of characters of a raw `User-Agent` used as a device display name.
+const MAX_RAW_USER_AGENT_LENGTH: usize = 100;
+
+/// Browsers and the product tokens identifying them, most specific first, since most browsers
+/// also claim to be the browsers they are derived from.
+const BROWSERS: [(&'static str, &'static str); 8] = [
+    ("Edg/", "Edge"),
+    ("Edge/", "Edge"),
+    ("OPR/", "Opera"),
+    ("Firefox/", "Firefox"),
+    ("FxiOS/", "Firefox"),
+    ("CriOS/", "Chrome"),
+    ("Chrome/", "Chrome"),
+    ("Safari/", "Safari"),
+];
+
+/// Operating systems and the tokens identifying them, most specific first.
+const OPERATING_SYSTEMS: [(&'static str, &'static str); 8] = [
+    ("Windows", "Windows"),
+    ("Android", "Android"),
+    ("iPhone", "iOS"),
+    ("iPad", "iOS"),
+    ("CrOS", "Chrome OS"),
+    ("Mac OS X", "macOS"),
+    ("Macintosh", "macOS"),
+    ("Linux", "Linux"),
+];
+
+/// Derive a human-readable device name like *Firefox on Linux* from a `User-Agent` header.
+///
+/// Falls back to the (shortened) header itself if neither the browser nor the operating system
+/// is recognized, and to *Unknown device* if there is no header.
+pub fn device_display_name(user_agent: Option<&str>) -> String {
+    let user_agent = match user_agent.map(str::trim) {
+        Some(user_agent) if !user_agent.is_empty() => user_agent,
+        _ => return UNKNOWN_DEVICE.to_string(),
+    };
+
+    let browser = find_token(user_agent, &BROWSERS);
+    let operating_system = find_token(user_agent, &OPERATING_SYSTEMS);
+
+    match (browser, operating_system) {
+        (Some(browser), Some(operating_system)) => format!("{} on {}", browser, operating_system),
+        (Some(browser), None) => browser.to_string(),
+        (None, Some(operating_system)) => match product(user_agent) {
+            Some(product) => format!("{} on {}", product, operating_system),
+            None => operating_system.to_string(),
+        },
+        (None, None) => user_agent.chars().take(MAX_RAW_USER_AGENT_LENGTH).collect(),
+    }
+}
+
+/// Return the name belonging to the first token contained in the `User-Agent`.
+fn find_token(user_agent: &str, tokens: &[(&'static str, &'static str)]) -> Option<&'static str> {
+    tokens.iter()
+        .find(|&&(token, _)| user_agent.contains(token))
+        .map(|&(_, name)| name)
+}
+
+/// Return the name of the first product in the `User-Agent`, unless it is the meaningless
+/// *Mozilla* every browser starts with.
+fn product(user_agent: &str) -> Option<&str> {
+    match user_agent.split('/').next().map(str::trim) {
+        Some(product) if !product.is_empty() && product != "Mozilla" && !product.contains(' ') => {
+            Some(product)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::device_display_name;
+
+    #[test]
+    fn common_browsers() {
+        assert_eq!(
+            device_display_name(Some(
+                "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0"
+            )),
+            "Firefox on Linux"
+        );
+        assert_eq!(
+            device_display_name(Some(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+                Chrome/120.0.0.0 Safari/537.36"
+            )),
+            "Chrome on Windows"
+        );
+        assert_eq!(
+            device_display_name(Some(
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+                (KHTML, like Gecko) Version/17.1 Safari/605.1.15"
+            )),
+            "Safari on macOS"
+        );
+        assert_eq!(
+            device_display_name(Some(
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1 like Mac OS X) AppleWebKit/605.1.15 \
+                (KHTML, like Gecko) CriOS/120.0.6099.119 Mobile/15E148 Safari/604.1"
+            )),
+            "Chrome on iOS"
+        );
+    }
+
+    #[test]
+    fn native_clients() {
+        assert_eq!(
+            device_display_name(Some("Element/1.11.40 (Android 14; Pixel 8)")),
+            "Element on Android"
+        );
+        assert_eq!(device_display_name(Some("curl/8.4.0")), "curl/8.4.0");
+    }
+
+    #[test]
+    fn missing_user_agent() {
+        assert_eq!(device_display_name(None), "Unknown device");
+        assert_eq!(device_display_name(Some("  ")), "Unknown device");
+    }
+}
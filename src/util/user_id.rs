@@ -0,0 +1,130 @@
+//! Normalization of user IDs received from clients.
+//!
+//! Localparts of users on this server are compared case-insensitively by lower-casing them
+//! wherever a user ID enters the server, as the specification recommends.
+
+use std::convert::TryFrom;
+use std::error::Error;
+
+use ruma_identifiers::UserId;
+
+use error::{ApiError, MapApiError};
+
+/// The characters allowed in localparts besides lower-case ASCII letters and digits.
+const LOCALPART_SYMBOLS: &'static str = "._=-/";
+
+/// Lower-case a localpart of this server and check that it only contains allowed characters.
+///
+/// `param` is the name of the request parameter reported if the localpart is invalid.
+pub fn normalize_localpart(localpart: &str, param: &str) -> Result<String, ApiError> {
+    let localpart = localpart.to_lowercase();
+
+    check_localpart(&localpart, param)?;
+
+    Ok(localpart)
+}
+
+/// Check that a localpart is not empty and only contains allowed characters.
+///
+/// `param` is the name of the request parameter reported if the localpart is invalid.
+pub fn check_localpart(localpart: &str, param: &str) -> Result<(), ApiError> {
+    if localpart.is_empty() {
+        return Err(ApiError::invalid_param(param, "The localpart must not be empty."));
+    }
+
+    let is_allowed = |c: char| {
+        (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9') || LOCALPART_SYMBOLS.contains(c)
+    };
+
+    if !localpart.chars().all(is_allowed) {
+        return Err(ApiError::invalid_param(
+            param,
+            "The localpart may only contain a-z, 0-9, \".\", \"_\", \"=\", \"-\" and \"/\".",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Build the normalized ID of a user on this server from a localpart.
+pub fn local_user_id(localpart: &str, domain: &str, param: &str) -> Result<UserId, ApiError> {
+    let localpart = normalize_localpart(localpart, param)?;
+
+    UserId::try_from(&format!("@{}:{}", localpart, domain)).map_api_err(|err| {
+        ApiError::invalid_param(param, err.description())
+    })
+}
+
+/// Generate a random user ID on this server with a normalized localpart.
+pub fn generate_user_id(domain: &str) -> Result<UserId, ApiError> {
+    let user_id = UserId::new(domain).map_err(ApiError::from)?;
+
+    UserId::try_from(&format!("@{}:{}", user_id.localpart().to_lowercase(), domain))
+        .map_err(ApiError::from)
+}
+
+/// Parse a user ID sent by a client, normalizing it if the user belongs to this server.
+///
+/// IDs of users on other servers are left as they are. If `accept_localpart` is true, a bare
+/// localpart is treated as the localpart of a user on this server.
+pub fn parse_user_id(value: &str, domain: &str, accept_localpart: bool, param: &str)
+-> Result<UserId, ApiError> {
+    if !value.starts_with('@') && accept_localpart {
+        return local_user_id(value, domain, param);
+    }
+
+    let user_id = UserId::try_from(value).map_api_err(|err| {
+        ApiError::invalid_param(param, err.description())
+    })?;
+
+    // Server names are case-insensitive, so `@Carl:RUMA.TEST` is a user of this server, too.
+    let server_name = value.splitn(2, ':').nth(1).map(str::to_lowercase);
+
+    if server_name == Some(domain.to_lowercase()) {
+        local_user_id(user_id.localpart(), domain, param)
+    } else {
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_localpart, parse_user_id};
+
+    #[test]
+    fn local_user_ids_are_lower_cased() {
+        assert_eq!(
+            parse_user_id("@Carl:ruma.test", "ruma.test", false, "user_id").unwrap().to_string(),
+            "@carl:ruma.test"
+        );
+        assert_eq!(
+            parse_user_id("@Carl:example.com", "ruma.test", false, "user_id").unwrap().to_string(),
+            "@Carl:example.com"
+        );
+    }
+
+    #[test]
+    fn server_names_of_local_user_ids_are_compared_case_insensitively() {
+        assert_eq!(
+            parse_user_id("@Carl:RUMA.TEST", "ruma.test", false, "user_id").unwrap().to_string(),
+            "@carl:ruma.test"
+        );
+    }
+
+    #[test]
+    fn bare_localparts() {
+        assert_eq!(
+            parse_user_id("Carl", "ruma.test", true, "user_id").unwrap().to_string(),
+            "@carl:ruma.test"
+        );
+        assert!(parse_user_id("carl", "ruma.test", false, "user_id").is_err());
+    }
+
+    #[test]
+    fn forbidden_characters_are_rejected() {
+        assert!(normalize_localpart("car l", "username").is_err());
+        assert!(normalize_localpart("carl:", "username").is_err());
+        assert!(normalize_localpart("", "username").is_err());
+        assert_eq!(normalize_localpart("c.a_r=l-/1", "username").unwrap(), "c.a_r=l-/1");
+    }
+}
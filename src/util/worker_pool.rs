@@ -0,0 +1,268 @@
+//! Running independent jobs concurrently on a bounded number of threads.
+//!
+//! The threads are reserved from a `WorkerLimit` shared by all requests of the server, so that
+//! concurrent requests together never run more than its limit of them.
+
+use std::cmp;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+
+use iron::{Plugin, Request};
+use iron::typemap::Key;
+use persistent::Read as PersistentRead;
+
+use error::ApiError;
+
+/// The number of worker threads that may run at the same time, shared by all requests.
+///
+/// Clones share their count, like those of `ConnectionLimit`.
+#[derive(Clone, Debug)]
+pub struct WorkerLimit {
+    /// The maximum number of worker threads running at the same time.
+    limit: usize,
+    /// The number of worker threads currently reserved, shared by all clones.
+    reserved: Arc<Mutex<usize>>,
+}
+
+/// Worker threads reserved from a `WorkerLimit` until this is dropped.
+#[derive(Debug)]
+pub struct Workers {
+    /// The number of reserved threads.
+    count: usize,
+    /// The count of the `WorkerLimit` the threads were reserved from.
+    reserved: Arc<Mutex<usize>>,
+}
+
+impl WorkerLimit {
+    /// Create a `WorkerLimit` allowing `limit` worker threads at the same time.
+    pub fn new(limit: usize) -> Self {
+        WorkerLimit {
+            limit: limit,
+            reserved: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// Extract the limit stored in the request.
+    pub fn from_request(request: &mut Request) -> Result<Arc<WorkerLimit>, ApiError> {
+        request.get::<PersistentRead<WorkerLimit>>().map_err(ApiError::from)
+    }
+
+    /// Reserve up to `wanted` threads, as many as are not reserved by other requests.
+    ///
+    /// Never waits, so the reservation may have fewer threads than wanted, or none at all.
+    pub fn reserve(&self, wanted: usize) -> Workers {
+        let mut reserved = lock_reserved(&self.reserved);
+        let count = cmp::min(wanted, self.limit.saturating_sub(*reserved));
+
+        *reserved += count;
+
+        Workers {
+            count: count,
+            reserved: self.reserved.clone(),
+        }
+    }
+}
+
+impl Workers {
+    /// The number of reserved threads.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        *lock_reserved(&self.reserved) -= self.count;
+    }
+}
+
+impl Key for WorkerLimit {
+    type Value = WorkerLimit;
+}
+
+/// Lock the count of reserved threads.
+///
+/// The count is only changed while the lock is held, so it stays consistent even if a thread
+/// panicked while holding it.
+fn lock_reserved(reserved: &Mutex<usize>) -> MutexGuard<usize> {
+    reserved.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Apply `job` to every item on the threads reserved by `workers`, returning the results in the
+/// order of the items.
+///
+/// Each thread calls `init` once for the resource its jobs share, e.g. a database connection, so
+/// there are never more than `workers.count()` of them. Once a job fails, the remaining items are
+/// skipped and the first error that is received is returned. A thread that panics fails the whole
+/// run instead of leaving it waiting. The threads stay reserved until the last of them has
+/// stopped, even if the run already failed.
+pub fn map_concurrently<T, C, R, I, F>(items: Vec<T>, workers: Workers, init: I, job: F)
+-> Result<Vec<R>, ApiError>
+where T: Send + Sync + 'static,
+      R: Send + 'static,
+      I: Fn() -> Result<C, ApiError> + Send + Sync + 'static,
+      F: Fn(&C, &T) -> Result<R, ApiError> + Send + Sync + 'static {
+    let count = items.len();
+    let items = Arc::new(items);
+    let init = Arc::new(init);
+    let job = Arc::new(job);
+    let next = Arc::new(AtomicUsize::new(0));
+    let failed = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = channel();
+    let threads = cmp::min(workers.count, count);
+    let workers = Arc::new(workers);
+
+    for _ in 0..threads {
+        let workers = workers.clone();
+        let items = items.clone();
+        let init = init.clone();
+        let job = job.clone();
+        let next = next.clone();
+        let failed = failed.clone();
+        let sender = sender.clone();
+
+        thread::spawn(move || {
+            // Released once every thread has stopped.
+            let _workers = workers;
+
+            let resource = match (*init)() {
+                Ok(resource) => resource,
+                Err(error) => {
+                    failed.store(true, Ordering::SeqCst);
+                    sender.send(Err(error)).ok();
+
+                    return;
+                }
+            };
+
+            while !failed.load(Ordering::SeqCst) {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+
+                let item = match items.get(index) {
+                    Some(item) => item,
+                    None => break,
+                };
+
+                let result = (*job)(&resource, item);
+
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+
+                // The receiver is gone once another job failed.
+                if sender.send(result.map(|result| (index, result))).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Only the threads hold senders and the reservation now, so the loop ends once all of them
+    // have stopped.
+    drop(sender);
+    drop(workers);
+
+    let mut results: Vec<Option<R>> = (0..count).map(|_| None).collect();
+
+    for result in receiver {
+        let (index, result) = result?;
+
+        results[index] = Some(result);
+    }
+
+    results.into_iter().map(|result| {
+        result.ok_or_else(|| ApiError::unknown("A worker stopped before finishing.".to_string()))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use error::ApiError;
+    use super::{WorkerLimit, Workers, map_concurrently};
+
+    fn workers(count: usize) -> Workers {
+        WorkerLimit::new(count).reserve(count)
+    }
+
+    fn double_slowly(_: &(), item: &u64) -> Result<u64, ApiError> {
+        sleep(Duration::from_millis(5));
+
+        Ok(item * 2)
+    }
+
+    #[test]
+    fn results_are_in_the_order_of_the_items() {
+        let items: Vec<u64> = (0..40).collect();
+        let expected: Vec<u64> = items.iter().map(|item| item * 2).collect();
+
+        let concurrent = map_concurrently(items.clone(), workers(4), || Ok(()), double_slowly);
+        let serial = map_concurrently(items, workers(1), || Ok(()), double_slowly);
+
+        assert_eq!(concurrent.unwrap(), expected);
+        assert_eq!(serial.unwrap(), expected);
+    }
+
+    #[test]
+    fn concurrent_jobs_are_not_slower() {
+        let items: Vec<u64> = (0..40).collect();
+
+        let start = Instant::now();
+        map_concurrently(items.clone(), workers(1), || Ok(()), double_slowly).unwrap();
+        let serial = start.elapsed();
+
+        let start = Instant::now();
+        map_concurrently(items, workers(8), || Ok(()), double_slowly).unwrap();
+        let concurrent = start.elapsed();
+
+        assert!(concurrent <= serial, "{:?} is slower than {:?}", concurrent, serial);
+    }
+
+    #[test]
+    fn a_failing_job_fails_the_run() {
+        let items: Vec<u64> = (0..40).collect();
+
+        assert!(map_concurrently(items.clone(), workers(4), || Ok(()), |_, item| {
+            if *item == 7 {
+                Err(ApiError::unknown("Seven".to_string()))
+            } else {
+                Ok(*item)
+            }
+        }).is_err());
+
+        assert!(map_concurrently(items.clone(), workers(4), || -> Result<(), ApiError> {
+            Err(ApiError::unknown("No connection".to_string()))
+        }, double_slowly).is_err());
+
+        assert!(map_concurrently(items, workers(4), || Ok(()), |_, item| -> Result<u64, ApiError> {
+            if *item == 7 {
+                panic!("Seven");
+            }
+
+            Ok(*item)
+        }).is_err());
+    }
+
+    #[test]
+    fn requests_share_the_limit() {
+        let limit = WorkerLimit::new(4);
+
+        let first = limit.reserve(3);
+        assert_eq!(first.count(), 3);
+
+        let second = limit.reserve(3);
+        assert_eq!(second.count(), 1);
+        assert_eq!(limit.clone().reserve(3).count(), 0);
+
+        drop(first);
+        assert_eq!(limit.reserve(3).count(), 3);
+
+        let items: Vec<u64> = (0..40).collect();
+        map_concurrently(items, second, || Ok(()), double_slowly).unwrap();
+        assert_eq!(limit.reserve(5).count(), 4);
+    }
+}
@@ -0,0 +1,507 @@
+//! Events in the format of the client-server API.
+//!
+//! Every endpoint returning room events renders them here, so an event looks the same no matter
+//! where a client reads it: `origin_server_ts` is an integer, `unsigned.age` is the time since
+//! the event was created as of the request, `unsigned.transaction_id` is only shown to the token
+//! that sent the event, and state events carry the content they replaced as `prev_content`.
+//! Events of types ruma-events does not know about are rendered as custom events.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use diesel::pg::PgConnection;
+use ruma_events::EventType;
+use ruma_events::collections::all::RoomEvent;
+use ruma_identifiers::{EventId, UserId};
+use serde_json::{Map, Value, to_value};
+
+use error::ApiError;
+use models::event::Event;
+use models::event_relation::{EventRelation, bundle_relations};
+use models::event_transaction::EventTransaction;
+
+/// Who events are rendered for, and what to render besides the events themselves.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderOptions<'a> {
+    /// The user the events are shown to, or `None` for a user who is not logged in.
+    pub user_id: Option<&'a UserId>,
+    /// The hash of the token the events are shown to, whose transaction IDs are echoed.
+    pub token_hash: Option<&'a str>,
+    /// The users whose annotations are left out of the bundled counts.
+    pub ignored_user_ids: &'a [UserId],
+    /// Whether messages carry their annotation counts and latest edit by their sender.
+    pub bundle_relations: bool,
+}
+
+/// Render events in the client format, in the order they are given.
+pub fn render_events(connection: &PgConnection, events: Vec<Event>, options: &RenderOptions)
+-> Result<Vec<Value>, ApiError> {
+    let event_ids: Vec<EventId> = events.iter().map(|event| event.id.clone()).collect();
+
+    let mut transaction_ids = match options.token_hash {
+        Some(token_hash) => {
+            EventTransaction::find_transaction_ids(connection, token_hash, &event_ids)?
+        }
+        None => HashMap::new(),
+    };
+
+    let (mut annotation_counts, mut latest_edits) = if options.bundle_relations {
+        let message_event_ids: Vec<EventId> = events.iter()
+            .filter(|event| event.event_type == EventType::RoomMessage.to_string())
+            .map(|event| event.id.clone())
+            .collect();
+
+        (
+            EventRelation::annotation_counts(
+                connection,
+                &message_event_ids,
+                options.user_id,
+                options.ignored_user_ids,
+            )?,
+            EventRelation::latest_edits(connection, &message_event_ids)?,
+        )
+    } else {
+        (HashMap::new(), HashMap::new())
+    };
+
+    let mut rendered = Vec::with_capacity(events.len());
+
+    for event in events {
+        let (origin_server_ts, age) = (event.origin_server_ts(), event.age());
+        let counts = annotation_counts.remove(&event.id);
+        let edit = latest_edits.remove(&event.id);
+        let transaction_id = transaction_ids.remove(&event.id);
+
+        let mut value = to_value(&room_event(event)?).map_err(ApiError::from)?;
+
+        if let Some(object) = value.as_object_mut() {
+            // ruma-events knows neither the timestamps nor the transaction ID, and the state
+            // events already have `replaces_state` in their `unsigned` data.
+            let mut unsigned = match object.remove("unsigned") {
+                Some(Value::Object(unsigned)) => unsigned,
+                _ => Map::new(),
+            };
+
+            bundle_relations(&mut unsigned, counts, edit)?;
+
+            unsigned.insert("age".to_string(), Value::from(age));
+
+            if let Some(transaction_id) = transaction_id {
+                unsigned.insert("transaction_id".to_string(), Value::String(transaction_id));
+            }
+
+            object.insert("origin_server_ts".to_string(), Value::from(origin_server_ts));
+            object.insert("unsigned".to_string(), Value::Object(unsigned));
+        }
+
+        rendered.push(value);
+    }
+
+    Ok(rendered)
+}
+
+/// Convert an event to its type in ruma-events.
+///
+/// Events of unknown types and events whose content does not fit their type are served as custom
+/// events.
+fn room_event(event: Event) -> Result<RoomEvent, ApiError> {
+    if event.is_served_as_custom() {
+        return custom_event(event);
+    }
+
+    let room_event = match EventType::from(event.event_type.as_ref()) {
+        EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
+        EventType::CallCandidates => RoomEvent::CallCandidates(event.try_into()?),
+        EventType::CallHangup => RoomEvent::CallHangup(event.try_into()?),
+        EventType::CallInvite => RoomEvent::CallInvite(event.try_into()?),
+        EventType::RoomAliases => RoomEvent::RoomAliases(event.try_into()?),
+        EventType::RoomAvatar => RoomEvent::RoomAvatar(event.try_into()?),
+        EventType::RoomCanonicalAlias => RoomEvent::RoomCanonicalAlias(event.try_into()?),
+        EventType::RoomCreate => RoomEvent::RoomCreate(event.try_into()?),
+        EventType::RoomGuestAccess => RoomEvent::RoomGuestAccess(event.try_into()?),
+        EventType::RoomHistoryVisibility => RoomEvent::RoomHistoryVisibility(event.try_into()?),
+        EventType::RoomJoinRules => RoomEvent::RoomJoinRules(event.try_into()?),
+        EventType::RoomMember => RoomEvent::RoomMember(event.try_into()?),
+        EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
+        EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
+        EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
+        EventType::RoomThirdPartyInvite => RoomEvent::RoomThirdPartyInvite(event.try_into()?),
+        EventType::RoomTopic => RoomEvent::RoomTopic(event.try_into()?),
+        _ => custom_event(event)?,
+    };
+
+    Ok(room_event)
+}
+
+/// Convert an event to a custom state or room event, depending on whether it has a state key.
+fn custom_event(event: Event) -> Result<RoomEvent, ApiError> {
+    if event.state_key.is_some() {
+        Ok(RoomEvent::CustomState(event.try_into()?))
+    } else {
+        Ok(RoomEvent::CustomRoom(event.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId};
+    use serde_json::{Value, from_str};
+
+    use models::event::Event;
+    use models::event_purge::EventPurge;
+    use models::event_transaction::EventTransaction;
+    use models::presence_status::advance_clock;
+    use query::SyncOptions;
+    use test::{Test, TestUser};
+
+    /// The room of the tests, with a message, a reaction to it and a topic that replaced an
+    /// older one.
+    struct Fixture {
+        test: Test,
+        alice: TestUser,
+        bob: TestUser,
+        room_id: String,
+        message: Event,
+        reaction: Event,
+        topic: Event,
+        old_topic: Event,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let test = Test::new();
+            let alice = test.create_user();
+            let bob = test.create_user();
+            let room_id = test.create_public_room(&alice.token);
+
+            assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+            for topic in &["Old", "Golden"] {
+                let content = format!(r#"{{"topic": "{}"}}"#, topic);
+                let response =
+                    test.send_state_event(&alice.token, &room_id, "m.room.topic", &content, None);
+
+                assert_eq!(response.status, Status::Ok);
+            }
+
+            let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+            let message_id = sent_event_id(response.json());
+
+            let path = format!(
+                "/_matrix/client/r0/rooms/{}/send/m.reaction/1?access_token={}",
+                room_id,
+                bob.token
+            );
+            let body = format!(
+                r#"{{
+                    "m.relates_to": {{"rel_type": "m.annotation", "event_id": "{}", "key": "+1"}}
+                }}"#,
+                message_id
+            );
+            let response = test.put(&path, &body);
+            let reaction_id = sent_event_id(response.json());
+
+            let (message, reaction, old_topic, topic) = test.with_connection(|connection| {
+                let room_id = RoomId::try_from(room_id.as_str()).unwrap();
+                let mut topics: Vec<Event> = Event::find_room_events(connection, &room_id, -1)
+                    .unwrap()
+                    .into_iter()
+                    .filter(|event| event.event_type == "m.room.topic")
+                    .collect();
+                let topic = topics.pop().unwrap();
+                let old_topic = topics.pop().unwrap();
+
+                (
+                    Event::find(connection, &message_id).unwrap().unwrap(),
+                    Event::find(connection, &reaction_id).unwrap().unwrap(),
+                    old_topic,
+                    topic,
+                )
+            });
+
+            Fixture {
+                test: test,
+                alice: alice,
+                bob: bob,
+                room_id: room_id,
+                message: message,
+                reaction: reaction,
+                topic: topic,
+                old_topic: old_topic,
+            }
+        }
+
+        /// The message as it is rendered for alice, who sent it.
+        fn golden_message(&self) -> Value {
+            let message = &self.message;
+
+            golden(&format!(
+                r#"{{
+                    "content": {{"body": "Hi", "msgtype": "m.text"}},
+                    "event_id": "{}",
+                    "origin_server_ts": {},
+                    "room_id": "{}",
+                    "sender": "{}",
+                    "type": "m.room.message",
+                    "unsigned": {{
+                        "age": 0,
+                        "m.relations": {{
+                            "m.annotation": {{
+                                "chunk": [
+                                    {{"type": "m.reaction", "key": "+1", "count": 1, "me": false}}
+                                ]
+                            }}
+                        }},
+                        "transaction_id": "1"
+                    }}
+                }}"#,
+                message.id,
+                message.origin_server_ts(),
+                message.room_id,
+                message.user_id
+            ))
+        }
+
+        /// The reaction as it is rendered for alice, who did not send it.
+        fn golden_reaction(&self) -> Value {
+            let reaction = &self.reaction;
+
+            golden(&format!(
+                r#"{{
+                    "content": {{
+                        "m.relates_to": {{
+                            "rel_type": "m.annotation",
+                            "event_id": "{}",
+                            "key": "+1"
+                        }}
+                    }},
+                    "event_id": "{}",
+                    "origin_server_ts": {},
+                    "room_id": "{}",
+                    "sender": "{}",
+                    "type": "m.reaction",
+                    "unsigned": {{"age": 0}}
+                }}"#,
+                self.message.id,
+                reaction.id,
+                reaction.origin_server_ts(),
+                reaction.room_id,
+                reaction.user_id
+            ))
+        }
+
+        /// The current topic as it is rendered for anyone.
+        fn golden_topic(&self) -> Value {
+            let topic = &self.topic;
+
+            golden(&format!(
+                r#"{{
+                    "content": {{"topic": "Golden"}},
+                    "event_id": "{}",
+                    "origin_server_ts": {},
+                    "prev_content": {{"topic": "Old"}},
+                    "room_id": "{}",
+                    "sender": "{}",
+                    "state_key": "",
+                    "type": "m.room.topic",
+                    "unsigned": {{
+                        "age": 0,
+                        "prev_content": {{"topic": "Old"}},
+                        "replaces_state": "{}"
+                    }}
+                }}"#,
+                topic.id,
+                topic.origin_server_ts(),
+                topic.room_id,
+                topic.user_id,
+                self.old_topic.id
+            ))
+        }
+
+        fn sync(&self, user: &TestUser) -> Value {
+            let options = SyncOptions {
+                filter: None,
+                since: None,
+                full_state: false,
+                set_presence: None,
+                timeout: 0,
+            };
+            let response = self.test.sync(&user.token, options);
+
+            assert_eq!(response.status, Status::Ok);
+
+            response.json().pointer(&format!("/rooms/join/{}", self.room_id)).unwrap().clone()
+        }
+
+        /// Get a room endpoint of the client-server API `version` as `user`.
+        fn get(&self, version: &str, path: &str, user: &TestUser) -> Value {
+            let path = format!(
+                "/_matrix/client/{}/rooms/{}/{}?access_token={}",
+                version,
+                self.room_id,
+                path,
+                user.token
+            );
+            let response = self.test.get(&path);
+
+            assert_eq!(response.status, Status::Ok);
+
+            response.json().clone()
+        }
+    }
+
+    /// The event ID in the response of sending an event, which only has the opaque part.
+    fn sent_event_id(response: &Value) -> EventId {
+        let opaque_id = response.get("event_id").unwrap().as_str().unwrap();
+
+        EventId::try_from(&format!("${}:ruma.test", opaque_id)).unwrap()
+    }
+
+    fn golden(json: &str) -> Value {
+        from_str(json).unwrap()
+    }
+
+    /// Find an event in a list of rendered events, with the age it was rendered with set to 0.
+    ///
+    /// The age depends on when the request was handled, so it can only be checked for sanity.
+    fn rendered(events: &Value, event: &Event) -> Value {
+        let event_id = event.id.to_string();
+        let mut rendered = events.as_array()
+            .unwrap()
+            .iter()
+            .find(|rendered| rendered.get("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap()
+            .clone();
+
+        assert!(rendered.pointer("/unsigned/age").unwrap().as_i64().unwrap() >= 0);
+
+        rendered["unsigned"]["age"] = Value::from(0);
+
+        rendered
+    }
+
+    #[test]
+    fn message_is_rendered_the_same_by_every_endpoint() {
+        let fixture = Fixture::new();
+        let message = &fixture.message;
+        let golden = fixture.golden_message();
+
+        let sync = fixture.sync(&fixture.alice);
+
+        assert_eq!(rendered(&sync["timeline"]["events"], message), golden);
+
+        let initial_sync = fixture.get("r0", "initialSync", &fixture.alice);
+
+        assert_eq!(rendered(&initial_sync["messages"]["chunk"], message), golden);
+    }
+
+    #[test]
+    fn state_event_is_rendered_the_same_by_every_endpoint() {
+        let fixture = Fixture::new();
+        let topic = &fixture.topic;
+        let golden = fixture.golden_topic();
+
+        let sync = fixture.sync(&fixture.alice);
+
+        assert_eq!(rendered(&sync["timeline"]["events"], topic), golden);
+        assert_eq!(rendered(&sync["state"]["events"], topic), golden);
+
+        let initial_sync = fixture.get("r0", "initialSync", &fixture.alice);
+
+        assert_eq!(rendered(&initial_sync["messages"]["chunk"], topic), golden);
+        assert_eq!(rendered(&initial_sync["state"], topic), golden);
+
+        let state = fixture.get("r0", "state", &fixture.alice);
+
+        assert_eq!(rendered(&state, topic), golden);
+    }
+
+    #[test]
+    fn relating_event_is_rendered_with_its_relation() {
+        let fixture = Fixture::new();
+        let path = format!("relations/{}", fixture.message.id);
+        let relations = fixture.get("v1", &path, &fixture.alice);
+
+        assert_eq!(rendered(&relations["chunk"], &fixture.reaction), fixture.golden_reaction());
+    }
+
+    #[test]
+    fn custom_event_is_rendered_the_same_by_every_endpoint() {
+        let fixture = Fixture::new();
+        let reaction = &fixture.reaction;
+        let golden = fixture.golden_reaction();
+
+        let sync = fixture.sync(&fixture.alice);
+
+        assert_eq!(rendered(&sync["timeline"]["events"], reaction), golden);
+
+        let initial_sync = fixture.get("r0", "initialSync", &fixture.alice);
+
+        assert_eq!(rendered(&initial_sync["messages"]["chunk"], reaction), golden);
+    }
+
+    #[test]
+    fn transaction_ids_expire_after_a_day() {
+        let fixture = Fixture::new();
+
+        advance_clock(24 * 60 * 60 * 1000 + 1);
+
+        fixture.test.with_connection(|connection| {
+            assert_eq!(EventTransaction::delete_expired(connection).unwrap(), 2);
+        });
+
+        let sync = fixture.sync(&fixture.alice);
+        let message = rendered(&sync["timeline"]["events"], &fixture.message);
+
+        assert!(message.pointer("/unsigned/transaction_id").is_none());
+    }
+
+    #[test]
+    fn purges_delete_transaction_ids() {
+        let fixture = Fixture::new();
+
+        fixture.test.with_connection(|connection| {
+            EventPurge::record(connection, &fixture.message.room_id, fixture.message.ordering)
+                .unwrap();
+        });
+
+        let sync = fixture.sync(&fixture.alice);
+        let message = rendered(&sync["timeline"]["events"], &fixture.message);
+
+        assert!(message.pointer("/unsigned/transaction_id").is_none());
+
+        let path = format!("relations/{}", fixture.message.id);
+        let relations = fixture.get("v1", &path, &fixture.bob);
+        let reaction = rendered(&relations["chunk"], &fixture.reaction);
+
+        assert!(reaction.pointer("/unsigned/transaction_id").is_some());
+    }
+
+    #[test]
+    fn transaction_ids_are_only_shown_to_the_sender() {
+        let fixture = Fixture::new();
+
+        let sync = fixture.sync(&fixture.bob);
+        let message = rendered(&sync["timeline"]["events"], &fixture.message);
+
+        assert!(message.pointer("/unsigned/transaction_id").is_none());
+        assert!(
+            message.pointer("/unsigned/m.relations/m.annotation/chunk/0/me")
+                .unwrap()
+                .as_bool()
+                .unwrap()
+        );
+
+        let path = format!("relations/{}", fixture.message.id);
+        let relations = fixture.get("v1", &path, &fixture.bob);
+        let reaction = rendered(&relations["chunk"], &fixture.reaction);
+
+        assert_eq!(
+            reaction.pointer("/unsigned/transaction_id").unwrap().as_str().unwrap(),
+            "1"
+        );
+    }
+}
@@ -0,0 +1,3 @@
+//! The client format of the data the API endpoints return.
+
+pub mod event;